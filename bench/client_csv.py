@@ -0,0 +1,132 @@
+"""
+client_csv.py — Read load-client `<id>_data.csv` files in the current layout.
+
+Schema 6 (client/src/metrics.rs, described column by column in the
+`<id>_data.schema.json` written next to each file) names every column by
+what it holds: `_total` for counters since start, `_per_s` for rates over
+the last interval, and milliseconds in `timestamp_ms`. Files from older
+clients (or written with `--legacy-csv`) are converted as they are read:
+counters are renamed and the per-interval differences divided by the time
+between a target's rows. Columns a schema added are left empty in rows
+from before it.
+
+Usage:
+    python bench/client_csv.py <old_data.csv>  > <new_data.csv>
+"""
+
+import csv
+import sys
+
+SCHEMA = 6
+
+# Schema 6 column order, as written by the client.
+COLUMNS = [
+    "schema", "timestamp_ms", "target", "active",
+    "connect_attempts_total", "connect_success_total", "connect_failed_total",
+    "disconnects_total", "connects_per_s", "disconnects_per_s",
+    "tx_pixels_total", "tx_pixels_per_s", "rx_datagrams_per_s",
+    "rx_megabits_per_s", "accepted_pixels_total", "accepted_pixels_per_s",
+    "rejected_pixels_total", "rejected_pixels_per_s", "rle_divergences_total", "tracer_p99_ms",
+    "connecting", "syncing", "steady", "degraded",
+]
+
+# Counters, renamed. `failed` is schema 1's only connect counter.
+RENAMED = {
+    "connect_attempts": "connect_attempts_total",
+    "connect_success": "connect_success_total",
+    "connect_failed": "connect_failed_total",
+    "failed": "connect_failed_total",
+    "disconnects": "disconnects_total",
+    "tx_pixels": "tx_pixels_total",
+    "accepted_pixels": "accepted_pixels_total",
+    "rle_divergences": "rle_divergences_total",
+}
+
+# Per-interval differences, now rates: (new name, decimals).
+RATES = {
+    "connects_s": ("connects_per_s", 2),
+    "disconnects_s": ("disconnects_per_s", 2),
+    "tx_pps": ("tx_pixels_per_s", 2),
+    "rx_dgram_s": ("rx_datagrams_per_s", 2),
+    "rx_mbps": ("rx_megabits_per_s", 3),
+    "accepted_pps": ("accepted_pixels_per_s", 2),
+}
+
+# Columns each schema from 6 on added, which older rows leave empty.
+ADDED = {
+    6: ["rejected_pixels_total", "rejected_pixels_per_s"],
+}
+
+
+def convert(rows):
+    """Schema <= 4 rows (dicts of strings) as schema 5 rows. The first row of
+    each target is taken to cover one second, as the client samples."""
+    last_seen = {}
+    out = []
+    for row in rows:
+        ts = int(row["timestamp"])
+        target = row.get("target", "")
+        prev = last_seen.get(target)
+        interval = ts - prev if prev is not None and ts > prev else 1
+        last_seen[target] = ts
+
+        new = {"schema": "5", "timestamp_ms": str(ts * 1000)}
+        for name, value in row.items():
+            if name in ("schema", "timestamp"):
+                continue
+            if name in RENAMED:
+                new[RENAMED[name]] = value
+            elif name in RATES:
+                renamed, decimals = RATES[name]
+                new[renamed] = value and f"{float(value) / interval:.{decimals}f}"
+            else:
+                new[name] = value
+        out.append(new)
+    return out
+
+
+def upgrade(rows):
+    """Schema >= 5 rows as current ones, with the columns added since left
+    empty."""
+    for row in rows:
+        schema = int(row["schema"])
+        if schema >= SCHEMA:
+            continue
+        for version, names in ADDED.items():
+            if version > schema:
+                for name in names:
+                    row.setdefault(name, "")
+        row["schema"] = str(SCHEMA)
+    return rows
+
+
+def fieldnames(rows):
+    """Current columns present in `rows`, in the client's order, then any
+    the client no longer writes."""
+    present = {name for row in rows for name in row}
+    known = [c for c in COLUMNS if c in present]
+    return known + sorted(present.difference(COLUMNS))
+
+
+def read(path):
+    """The rows of a client data CSV as current dicts of strings."""
+    with open(path, newline="") as f:
+        rows = list(csv.DictReader(f))
+    if rows and "timestamp_ms" not in rows[0]:
+        rows = convert(rows)
+    return upgrade(rows)
+
+
+def main(argv):
+    if len(argv) != 2:
+        print(__doc__.strip().splitlines()[-1].strip(), file=sys.stderr)
+        return 2
+    rows = read(argv[1])
+    writer = csv.DictWriter(sys.stdout, fieldnames=fieldnames(rows), lineterminator="\n")
+    writer.writeheader()
+    writer.writerows(rows)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main(sys.argv))
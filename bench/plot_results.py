@@ -15,6 +15,11 @@ a 6-panel figure with seaborn dark theme.
 Usage:
     python bench/plot_results.py <results_dir>
     python bench/plot_results.py bench_results/20260228_013000/
+    python bench/plot_results.py <results_dir> --baseline <other_results_dir> [--force]
+
+With --baseline, the environment fingerprints in both runs' run-info.json
+(written by `server --metrics-dir`) must match, or the report is refused
+unless --force is given.
 
 Output:
     <results_dir>/benchmark_report.png  (300 DPI)
@@ -23,7 +28,9 @@ Output:
 
 import argparse
 import glob
+import json
 import os
+import struct
 import sys
 
 import matplotlib.pyplot as plt
@@ -31,6 +38,8 @@ import matplotlib.ticker as mticker
 import pandas as pd
 import seaborn as sns
 
+import client_csv
+
 # ── Theme & Palette ──────────────────────────────────────────────────
 
 # Dark theme with custom colors
@@ -125,13 +134,18 @@ def load_client_data(results_dir):
     dfs = []
     for f in files:
         try:
-            df = pd.read_csv(f)
-            if "timestamp" not in df.columns:
+            # Older layouts come back renamed and as per-second rates.
+            df = pd.DataFrame(client_csv.read(f))
+            if "timestamp_ms" not in df.columns:
                 continue
-            df = df.sort_values("timestamp")
-            # Calculate per-second rates from cumulative counters
-            if "tx_pixels" in df.columns:
-                df["tx_pixels_s"] = df["tx_pixels"].diff().fillna(0).clip(lower=0)
+            df = df.drop(columns=["schema", "target"], errors="ignore")
+            df = df.apply(pd.to_numeric, errors="coerce")
+            # Workers sample on their own clocks; line them up by second.
+            df["timestamp"] = df["timestamp_ms"] // 1000
+            df = df.drop(columns=["timestamp_ms"]).sort_values("timestamp")
+            # Schema 1 wrote the TX counter only.
+            if "tx_pixels_per_s" not in df.columns and "tx_pixels_total" in df.columns:
+                df["tx_pixels_per_s"] = df["tx_pixels_total"].diff().fillna(0).clip(lower=0)
             dfs.append(df)
         except Exception as e:
             print(f"  ⚠ Error reading {f}: {e}")
@@ -140,7 +154,7 @@ def load_client_data(results_dir):
         return None
 
     combined = pd.concat(dfs, ignore_index=True)
-    # Aggregate per timestamp across all workers
+    # Aggregate per second across all workers
     numeric_cols = combined.select_dtypes(include="number").columns
     cols_to_sum = numeric_cols.drop("timestamp", errors="ignore")
     agg = combined.groupby("timestamp")[cols_to_sum].sum().reset_index()
@@ -153,6 +167,160 @@ def load_client_data(results_dir):
     return agg
 
 
+def load_client_closes(results_dir):
+    """Sum server-initiated closes by reason across client summary files."""
+    patterns = [
+        os.path.join(results_dir, "*_summary.json"),
+        os.path.join(results_dir, "canvas-client*", "*_summary.json"),
+    ]
+    closes = {}
+    for p in patterns:
+        for f in glob.glob(p):
+            try:
+                with open(f) as fh:
+                    summary = json.load(fh)
+            except Exception as e:
+                print(f"  ⚠ Error reading {f}: {e}")
+                continue
+            for target in summary.get("targets", []):
+                for reason, count in target.get("closes", {}).items():
+                    closes[reason] = closes.get(reason, 0) + count
+    return closes or None
+
+
+def load_client_drops(results_dir):
+    """Kernel receive drops on the clients' own sockets, summed over every
+    pool endpoint (last row of each `*_endpoints.csv`). Loss counted here
+    happened on the load machines, not on the server or the network."""
+    patterns = [
+        os.path.join(results_dir, "*_endpoints.csv"),
+        os.path.join(results_dir, "canvas-client*", "*_endpoints.csv"),
+    ]
+    total = None
+    for p in patterns:
+        for f in glob.glob(p):
+            try:
+                df = pd.read_csv(f)
+            except Exception as e:
+                print(f"  ⚠ Error reading {f}: {e}")
+                continue
+            if df.empty or "client_side_drops" not in df.columns:
+                continue
+            last = df[df["timestamp"] == df["timestamp"].max()]
+            total = (total or 0) + int(last["client_side_drops"].sum())
+    return total
+
+
+def histogram_quantile(le, cumulative, q):
+    """Prometheus-style quantile estimate from cumulative buckets: linear
+    within the bucket, the last finite bound for the overflow bucket."""
+    total = cumulative[-1]
+    if total == 0:
+        return None
+    rank = q * total
+    lower, below = 0, 0
+    for bound, count in zip(le, cumulative):
+        if count >= rank:
+            inside = count - below
+            return lower + (bound - lower) * ((rank - below) / inside if inside else 1)
+        lower, below = bound, count
+    return le[-1]
+
+
+def load_client_first_broadcast(results_dir):
+    """Connect-to-first-datagram histogram summed over client summaries,
+    as (le, cumulative)."""
+    patterns = [
+        os.path.join(results_dir, "*_summary.json"),
+        os.path.join(results_dir, "canvas-client*", "*_summary.json"),
+    ]
+    le, cumulative = None, None
+    for p in patterns:
+        for f in glob.glob(p):
+            try:
+                with open(f) as fh:
+                    summary = json.load(fh)
+            except Exception as e:
+                print(f"  ⚠ Error reading {f}: {e}")
+                continue
+            for target in summary.get("targets", []):
+                h = target.get("first_broadcast_ms")
+                if not h:
+                    continue
+                if cumulative is None:
+                    le, cumulative = h["le"], [0] * len(h["cumulative"])
+                cumulative = [a + b for a, b in zip(cumulative, h["cumulative"])]
+    return (le, cumulative) if cumulative else None
+
+
+def load_server_first_broadcast(results_dir):
+    """Handshake-to-first-broadcast histogram summed over workers, from the
+    final /metrics scrape (`server_metrics.prom`), as (le, cumulative)."""
+    for path in [
+        os.path.join(results_dir, "server_metrics.prom"),
+        os.path.join(results_dir, "server", "server_metrics.prom"),
+    ]:
+        if not os.path.exists(path):
+            continue
+        buckets = {}
+        with open(path) as fh:
+            for line in fh:
+                if not line.startswith("canvas_worker_first_broadcast_ms_bucket{"):
+                    continue
+                labels, value = line.rsplit(" ", 1)
+                bound = labels.split('le="', 1)[1].split('"', 1)[0]
+                bound = float("inf") if bound == "+Inf" else float(bound)
+                buckets[bound] = buckets.get(bound, 0) + int(value)
+        if buckets:
+            bounds = sorted(buckets)
+            return [b for b in bounds if b != float("inf")], [buckets[b] for b in bounds]
+    return None
+
+
+def read_observer_log(path):
+    """Parse one client observer log into a list of (arrival_us, length).
+
+    Layout: b"CVOB" | u16 version, then per datagram
+    u64 arrival_unix_us | u32 len | payload (little-endian).
+    """
+    with open(path, "rb") as fh:
+        buf = fh.read()
+    # Version 2 datagrams start with their type byte; lengths read the same.
+    if buf[:4] != b"CVOB" or struct.unpack_from("<H", buf, 4)[0] not in (1, 2):
+        raise ValueError("not a version 1 or 2 observer log")
+    records = []
+    pos = 6
+    while pos + 12 <= len(buf):
+        arrival_us, length = struct.unpack_from("<QI", buf, pos)
+        pos += 12 + length
+        if pos > len(buf):
+            break  # truncated tail (client killed mid-write)
+        records.append((arrival_us, length))
+    return records
+
+
+def load_observer_gaps(results_dir):
+    """Broadcast inter-arrival gaps (ms) seen by observers, pooled."""
+    patterns = [
+        os.path.join(results_dir, "observer_*.bin"),
+        os.path.join(results_dir, "canvas-client*", "observer_*.bin"),
+    ]
+    gaps = []
+    for p in patterns:
+        for f in glob.glob(p):
+            try:
+                records = read_observer_log(f)
+            except Exception as e:
+                print(f"  ⚠ Error reading {f}: {e}")
+                continue
+            arrivals = [a for a, _ in records]
+            gaps.extend((b - a) / 1000.0 for a, b in zip(arrivals, arrivals[1:]))
+    if not gaps:
+        return None
+    print(f"  ✓ Loaded observer logs ({len(gaps) + 1} broadcasts)")
+    return pd.Series(gaps)
+
+
 def load_server_data(results_dir):
     """Load server metrics CSV."""
     patterns = [
@@ -177,6 +345,124 @@ def load_server_data(results_dir):
     return None
 
 
+# ── Run environment ──────────────────────────────────────────────────
+
+# Parts of the server's environment fingerprint that make two runs
+# incomparable when they differ. Hostnames and kernel build strings do not.
+MATERIAL_ENVIRONMENT = [
+    ("kernel", "release"),
+    ("kernel", "machine"),
+    ("cpu", "model"),
+    ("cpu", "logical_cpus"),
+    ("cpu", "flags"),
+    ("cpu", "governor"),
+    ("mitigations", "cmdline"),
+    ("mitigations", "vulnerabilities"),
+    ("sysctls",),
+    ("nics",),
+    ("io_uring",),
+]
+
+
+def load_run_info(results_dir):
+    """The server's run-info.json (environment, build and config), or None."""
+    for path in [
+        os.path.join(results_dir, "run-info.json"),
+        os.path.join(results_dir, "server", "run-info.json"),
+    ]:
+        if not os.path.exists(path):
+            continue
+        try:
+            with open(path) as fh:
+                info = json.load(fh)
+            print(f"  ✓ Loaded {path}")
+            return info
+        except Exception as e:
+            print(f"  ⚠ Error reading {path}: {e}")
+    return None
+
+
+def _lookup(tree, keys):
+    for key in keys:
+        if not isinstance(tree, dict):
+            return None
+        tree = tree.get(key)
+    return tree
+
+
+def environment_differences(run_info, baseline_info):
+    """Material environment fields that differ, as (name, run, baseline).
+    A field either side could not read (null) is unknown, not different."""
+    run_env = _lookup(run_info, ("server", "environment")) or {}
+    base_env = _lookup(baseline_info, ("server", "environment")) or {}
+    differences = []
+    for keys in MATERIAL_ENVIRONMENT:
+        ours, theirs = _lookup(run_env, keys), _lookup(base_env, keys)
+        if ours is not None and theirs is not None and ours != theirs:
+            differences.append((".".join(keys), ours, theirs))
+    return differences
+
+
+def config_differences(run_info, baseline_info):
+    """Server flags that differ between the runs: expected when comparing
+    configurations, so they are annotated rather than refused."""
+    ours = _lookup(run_info, ("server", "config")) or {}
+    theirs = _lookup(baseline_info, ("server", "config")) or {}
+    return [
+        (key, ours.get(key), theirs.get(key))
+        for key in sorted(set(ours) | set(theirs))
+        if ours.get(key) != theirs.get(key)
+    ]
+
+
+def run_info_lines(run_info):
+    """One line describing where the run happened."""
+    env = _lookup(run_info, ("server", "environment"))
+    if not env:
+        return []
+    parts = [
+        _lookup(env, ("kernel", "release")),
+        _lookup(env, ("cpu", "model")),
+        _lookup(env, ("cpu", "governor")),
+    ]
+    mitigations = _lookup(env, ("mitigations", "cmdline"))
+    if mitigations is not None:
+        parts.append(f"mitigations={mitigations}")
+    return ["Env: " + " · ".join(str(p) for p in parts if p is not None)]
+
+
+def baseline_lines(client_df, baseline_df, env_diffs, cfg_diffs):
+    """Headline numbers against the baseline run, and what differed.
+    `env_diffs` is None when either run has no run-info.json."""
+    lines = ["vs baseline:"]
+    if client_df is not None and baseline_df is not None:
+        def steady_tx(df):
+            ss = df.iloc[int(len(df) * 0.4):]
+            col = "tx_pixels_per_s"
+            return ss[col].mean() if col in ss.columns else None
+
+        for label, ours, theirs in [
+            ("peak conns", client_df["active"].max(), baseline_df["active"].max()),
+            ("avg TX/s", steady_tx(client_df), steady_tx(baseline_df)),
+        ]:
+            if ours is None or theirs is None or not theirs:
+                continue
+            change = (ours - theirs) / theirs * 100
+            lines.append(
+                f"  {label}: {human_format(ours)} vs {human_format(theirs)} ({change:+.1f}%)"
+            )
+    if env_diffs is None:
+        lines.append("  ? Environment not recorded")
+    elif env_diffs:
+        lines.append(f"  ⚠ FORCED: environment differs ({len(env_diffs)} field(s))")
+        lines.extend(f"    {name}" for name, _, _ in env_diffs)
+    else:
+        lines.append("  ✓ Same environment")
+    for key, ours, theirs in cfg_diffs:
+        lines.append(f"  --{key.replace('_', '-')}: {ours} (was {theirs})")
+    return lines
+
+
 # ── Plotting ─────────────────────────────────────────────────────────
 
 def plot_panel_connections(ax, client_df):
@@ -189,9 +475,9 @@ def plot_panel_connections(ax, client_df):
         client_df["elapsed_s"], client_df["active"],
         color=PALETTE["active"], linewidth=1.5, label="Active",
     )
-    if "failed" in client_df.columns and client_df["failed"].max() > 0:
+    if "connect_failed_total" in client_df.columns and client_df["connect_failed_total"].max() > 0:
         ax.plot(
-            client_df["elapsed_s"], client_df["failed"],
+            client_df["elapsed_s"], client_df["connect_failed_total"],
             color=PALETTE["failed"], linewidth=1.5, linestyle="--", label="Failed",
         )
     ax.set_title("Client Connections")
@@ -213,20 +499,23 @@ def plot_panel_connections(ax, client_df):
 
 def plot_panel_throughput(ax, client_df):
     """Panel 2: TX/RX throughput."""
-    if "tx_pps" in client_df.columns:
+    if "tx_pixels_per_s" in client_df.columns:
         ax.plot(
-            client_df["elapsed_s"], client_df["tx_pps"],
+            client_df["elapsed_s"], client_df["tx_pixels_per_s"],
             color=PALETTE["tx_pixels"], linewidth=1.5, label="TX Pixels/s",
         )
-    elif "tx_pixels_s" in client_df.columns:
+
+    # Only non-zero with --respect-cooldown: pixels the server will keep.
+    if "accepted_pixels_per_s" in client_df.columns and client_df["accepted_pixels_per_s"].any():
         ax.plot(
-            client_df["elapsed_s"], client_df["tx_pixels_s"],
-            color=PALETTE["tx_pixels"], linewidth=1.5, label="TX Pixels/s",
+            client_df["elapsed_s"], client_df["accepted_pixels_per_s"],
+            color=PALETTE["tx_pixels"], linewidth=1.5, linestyle="--",
+            label="Accepted Pixels/s",
         )
 
-    if "rx_dgram_s" in client_df.columns:
+    if "rx_datagrams_per_s" in client_df.columns:
         ax.plot(
-            client_df["elapsed_s"], client_df["rx_dgram_s"],
+            client_df["elapsed_s"], client_df["rx_datagrams_per_s"],
             color=PALETTE["rx_dgram"], linewidth=1.5, label="RX Datagrams/s",
         )
 
@@ -236,11 +525,8 @@ def plot_panel_throughput(ax, client_df):
     ax.legend(loc="upper left")
 
     # Annotate average steady-state throughput
-    if "tx_pps" in client_df.columns:
-        col = "tx_pps"
-    elif "tx_pixels_s" in client_df.columns:
-        col = "tx_pixels_s"
-    else:
+    col = "tx_pixels_per_s"
+    if col not in client_df.columns:
         return
     # Use last 60% of data as "steady state"
     ss = client_df.iloc[int(len(client_df) * 0.4):]
@@ -376,7 +662,30 @@ def plot_panel_network(ax, server_df):
     ax2.legend(loc="upper right", fontsize=8)
 
 
-def add_summary_box(fig, client_df=None, server_df=None):
+def first_broadcast_lines(server_hist, client_hist):
+    """p50/p99 time to first broadcast on each side. The server measures
+    handshake done to first datagram queued, the client connect done to
+    first datagram read: the difference is network plus client delay."""
+    quantiles = {}
+    for side, hist in (("server", server_hist), ("client", client_hist)):
+        if hist is None:
+            continue
+        p50, p99 = (histogram_quantile(*hist, q) for q in (0.5, 0.99))
+        if p50 is not None:
+            quantiles[side] = (p50, p99)
+    if not quantiles:
+        return []
+    lines = ["First broadcast p50/p99:"]
+    for side, (p50, p99) in quantiles.items():
+        lines.append(f"  {side}: {p50:.0f}/{p99:.0f} ms")
+    if len(quantiles) == 2:
+        (s50, s99), (c50, c99) = quantiles["server"], quantiles["client"]
+        lines.append(f"  net+client: {c50 - s50:.0f}/{c99 - s99:.0f} ms")
+    return lines
+
+
+def add_summary_box(fig, client_df=None, server_df=None, closes=None, gaps=None,
+                    client_drops=None, first_broadcast=(), run_lines=()):
     """Add a summary stats text box to the figure."""
     lines = []
 
@@ -386,22 +695,43 @@ def add_summary_box(fig, client_df=None, server_df=None):
 
         # Average steady-state throughput (last 60%)
         ss = client_df.iloc[int(len(client_df) * 0.4):]
-        for col in ["tx_pps", "tx_pixels_s"]:
-            if col in ss.columns:
-                avg_tx = ss[col].mean()
-                lines.append(f"Avg TX: {human_format(avg_tx)}/s")
-                break
-        if "rx_dgram_s" in ss.columns:
-            avg_rx = ss["rx_dgram_s"].mean()
+        if "tx_pixels_per_s" in ss.columns:
+            avg_tx = ss["tx_pixels_per_s"].mean()
+            lines.append(f"Avg TX: {human_format(avg_tx)}/s")
+        if "accepted_pixels_per_s" in ss.columns and client_df["accepted_pixels_per_s"].any():
+            avg_acc = ss["accepted_pixels_per_s"].mean()
+            lines.append(f"Avg accepted: {human_format(avg_acc)}/s")
+        if "rx_datagrams_per_s" in ss.columns:
+            avg_rx = ss["rx_datagrams_per_s"].mean()
             lines.append(f"Avg RX: {human_format(avg_rx)}/s")
 
-        if "failed" in client_df.columns:
-            total_failed = client_df["failed"].max()
+        if "connect_failed_total" in client_df.columns:
+            total_failed = client_df["connect_failed_total"].max()
             lines.append(f"Failed: {int(total_failed)}")
 
         duration = client_df["elapsed_s"].max()
         lines.append(f"Duration: {int(duration)}s")
 
+    if client_drops is not None:
+        if client_drops > 0:
+            lines.append(f"⚠ Client-side drops: {human_format(client_drops)}")
+        else:
+            lines.append("✓ Zero client-side drops")
+
+    lines.extend(first_broadcast)
+
+    if gaps is not None:
+        lines.append(
+            f"Broadcast gap p50/p99/max: {gaps.quantile(0.5):.0f}/"
+            f"{gaps.quantile(0.99):.0f}/{gaps.max():.0f} ms"
+        )
+
+    if closes:
+        lines.append("Closes by reason:")
+        for reason, count in closes.items():
+            if count > 0:
+                lines.append(f"  {reason}: {human_format(count)}")
+
     if server_df is not None:
         total_rcvbuf_err = server_df["udp_rcvbuf_errors"].sum()
         if total_rcvbuf_err > 0:
@@ -412,6 +742,8 @@ def add_summary_box(fig, client_df=None, server_df=None):
         peak_rss = server_df["server_rss_kb"].max() / 1024
         lines.append(f"Peak RSS: {peak_rss:.0f} MB")
 
+    lines.extend(run_lines)
+
     text = "\n".join(lines)
     fig.text(
         0.98, 0.98, text,
@@ -432,20 +764,51 @@ def add_summary_box(fig, client_df=None, server_df=None):
 
 # ── Main ─────────────────────────────────────────────────────────────
 
-def plot(results_dir):
-    """Generate the full benchmark report."""
+def plot(results_dir, baseline_dir=None, force=False):
+    """Generate the full benchmark report, compared against `baseline_dir`
+    if given. Exits with status 2 when the two runs' environments differ,
+    unless `force`."""
     print(f"\n{'═' * 60}")
     print(f"  Canvas Benchmark Report Generator")
     print(f"  Results: {results_dir}")
     print(f"{'═' * 60}\n")
 
     client_df = load_client_data(results_dir)
+    client_closes = load_client_closes(results_dir)
+    client_drops = load_client_drops(results_dir)
+    observer_gaps = load_observer_gaps(results_dir)
     server_df = load_server_data(results_dir)
+    first_broadcast = first_broadcast_lines(
+        load_server_first_broadcast(results_dir),
+        load_client_first_broadcast(results_dir),
+    )
 
     if client_df is None and server_df is None:
         print("\n✗ No data found. Nothing to plot.")
         sys.exit(1)
 
+    run_info = load_run_info(results_dir)
+    run_lines = run_info_lines(run_info)
+    if baseline_dir is not None:
+        baseline_info = load_run_info(baseline_dir)
+        env_diffs = None
+        if run_info is None or baseline_info is None:
+            print("  ⚠ run-info.json missing: cannot check the runs are comparable")
+        else:
+            env_diffs = environment_differences(run_info, baseline_info)
+        if env_diffs:
+            print("\n  Environment differs from the baseline:")
+            for name, ours, theirs in env_diffs:
+                print(f"    {name}: {ours} (baseline {theirs})")
+            if not force:
+                print("\n✗ Refusing to compare runs from different environments"
+                      " (--force to compare anyway).")
+                sys.exit(2)
+        run_lines = run_lines + baseline_lines(
+            client_df, load_client_data(baseline_dir), env_diffs,
+            config_differences(run_info, baseline_info),
+        )
+
     setup_style()
 
     png_paths = []
@@ -458,7 +821,7 @@ def plot(results_dir):
         plot_panel_memory(axes_flat_s[2], server_df)
         plot_panel_network(axes_flat_s[3], server_df)
 
-        add_summary_box(fig_s, server_df=server_df)
+        add_summary_box(fig_s, server_df=server_df, run_lines=run_lines)
 
         fig_s.suptitle(
             "Canvas Server — Benchmark Report",
@@ -482,7 +845,11 @@ def plot(results_dir):
         plot_panel_connections(axes_c[0], client_df)
         plot_panel_throughput(axes_c[1], client_df)
 
-        add_summary_box(fig_c, client_df=client_df)
+        add_summary_box(
+            fig_c, client_df=client_df, closes=client_closes, gaps=observer_gaps,
+            client_drops=client_drops, first_broadcast=first_broadcast,
+            run_lines=run_lines,
+        )
 
         fig_c.suptitle(
             "Canvas Client — Benchmark Report",
@@ -514,10 +881,20 @@ if __name__ == "__main__":
         "results_dir",
         help="Path to the results directory (e.g. bench_results/20260228_013000/)",
     )
+    parser.add_argument(
+        "--baseline",
+        help="Results directory of an earlier run to compare against",
+    )
+    parser.add_argument(
+        "--force",
+        action="store_true",
+        help="Compare even if the runs' environments (run-info.json) differ",
+    )
     args = parser.parse_args()
 
-    if not os.path.isdir(args.results_dir):
-        print(f"Error: '{args.results_dir}' is not a directory")
-        sys.exit(1)
+    for d in [args.results_dir, args.baseline]:
+        if d is not None and not os.path.isdir(d):
+            print(f"Error: '{d}' is not a directory")
+            sys.exit(1)
 
-    plot(args.results_dir)
+    plot(args.results_dir, args.baseline, args.force)
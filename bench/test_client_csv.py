@@ -0,0 +1,46 @@
+"""Run from bench/: python3 -m unittest test_client_csv"""
+
+import csv
+import os
+import unittest
+
+import client_csv
+
+TESTDATA = os.path.join(os.path.dirname(__file__), "..", "client", "testdata")
+
+
+class ConvertTest(unittest.TestCase):
+    def current(self):
+        with open(os.path.join(TESTDATA, "data_v6.csv"), newline="") as f:
+            reader = csv.DictReader(f)
+            self.assertEqual(reader.fieldnames, client_csv.COLUMNS)
+            return list(reader)
+
+    def blanked(self, rows, since):
+        """`rows` with the columns added from schema `since` on emptied."""
+        for version, names in client_csv.ADDED.items():
+            if version >= since:
+                for row in rows:
+                    row.update(dict.fromkeys(names, ""))
+        return rows
+
+    def test_legacy_file_reads_as_current(self):
+        """The golden files hold the same samples in every layout, but for
+        the columns a layout did not have yet."""
+        legacy = client_csv.read(os.path.join(TESTDATA, "data_v4.csv"))
+        self.assertEqual(client_csv.fieldnames(legacy), client_csv.COLUMNS)
+        self.assertEqual(legacy, self.blanked(self.current(), 6))
+
+    def test_schema_5_file_reads_as_current(self):
+        v5 = client_csv.read(os.path.join(TESTDATA, "data_v5.csv"))
+        self.assertEqual(client_csv.fieldnames(v5), client_csv.COLUMNS)
+        self.assertEqual(v5, self.blanked(self.current(), 6))
+
+    def test_current_file_is_unchanged(self):
+        path = os.path.join(TESTDATA, "data_v6.csv")
+        with open(path, newline="") as f:
+            self.assertEqual(client_csv.read(path), list(csv.DictReader(f)))
+
+
+if __name__ == "__main__":
+    unittest.main()
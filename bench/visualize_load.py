@@ -2,6 +2,8 @@ import pandas as pd
 import glob
 import matplotlib.pyplot as plt
 
+import client_csv
+
 def merge_and_plot():
     files = glob.glob("test_results/*_data.csv")
     if not files:
@@ -14,12 +16,11 @@ def merge_and_plot():
     dfs = []
     for f in files:
         try:
-            df = pd.read_csv(f)
+            df = pd.DataFrame(client_csv.read(f))
+            df = df.drop(columns=['schema', 'target'], errors='ignore')
+            df = df.apply(pd.to_numeric, errors='coerce')
+            df['timestamp'] = df.pop('timestamp_ms') // 1000
             df = df.sort_values('timestamp')
-            # Calculate per-worker rate from cumulative counter
-            df['tx_pixels_s'] = df['tx_pixels'].diff().fillna(0)
-            # Handle potential counter resets
-            df.loc[df['tx_pixels_s'] < 0, 'tx_pixels_s'] = 0
             dfs.append(df)
         except Exception as e:
             print(f"Error reading {f}: {e}")
@@ -36,22 +37,22 @@ def merge_and_plot():
     
     # 1. Active Clients
     axes[0].plot(agg['timestamp'], agg['active'], label='Active Clients', color='blue')
-    axes[0].plot(agg['timestamp'], agg['failed'], label='Failed Connects', color='red')
+    axes[0].plot(agg['timestamp'], agg['connect_failed_total'], label='Failed Connects', color='red')
     axes[0].set_title('Client Connections')
     axes[0].set_ylabel('Count')
     axes[0].legend()
     axes[0].grid(True)
     
     # 2. Datagrams per second & Pixels per second
-    axes[1].plot(agg['timestamp'], agg['rx_dgram_s'], label='RX Datagrams/s', color='green')
-    axes[1].plot(agg['timestamp'], agg['tx_pixels_s'], label='TX Pixels/s', color='orange')
+    axes[1].plot(agg['timestamp'], agg['rx_datagrams_per_s'], label='RX Datagrams/s', color='green')
+    axes[1].plot(agg['timestamp'], agg['tx_pixels_per_s'], label='TX Pixels/s', color='orange')
     axes[1].set_title('Throughput (Messages)')
     axes[1].set_ylabel('Messages / second')
     axes[1].legend()
     axes[1].grid(True)
     
     # 3. Bandwidth
-    axes[2].plot(agg['timestamp'], agg['rx_mbps'], label='RX Bandwidth (Mbps)', color='purple')
+    axes[2].plot(agg['timestamp'], agg['rx_megabits_per_s'], label='RX Bandwidth (Mbps)', color='purple')
     axes[2].set_title('Bandwidth (Mbps)')
     axes[2].set_xlabel('Timestamp (Unix epoch)')
     axes[2].set_ylabel('Mbps')
@@ -0,0 +1,262 @@
+//! Client side of dictionary-compressed diffs (see `protocol::dict`):
+//! training a dictionary from observer logs (`--mode train-dict`) and
+//! decoding compressed diffs in load mode (`--zstd-diffs`).
+
+use crate::observer::decode_observer_log;
+use protocol::broadcast::{self, Message};
+use protocol::dict::{
+    DIFF_DICTS, DIFF_ZSTD_LEVEL, ZSTD_DIFF_TAG, dict_id, frame_dict_id, is_raw_diff, pack_diff,
+    set_dict_version, unpack_diff, zstd_frame,
+};
+use protocol::version::Features;
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+
+/// Every n-th sample is held out of training to measure the dictionary on.
+const HOLDOUT_EVERY: usize = 10;
+
+/// Largest diff a compressed datagram may expand to; far above the
+/// server's chunk size.
+const MAX_DECODED_LEN: usize = 64 * 1024;
+
+/// Raw diff chunks found in a directory of observer logs, without their
+/// type.
+#[derive(Debug, Default)]
+pub struct Samples {
+    pub logs: usize,
+    pub diffs: Vec<Vec<u8>>,
+    /// Full snapshot chunks and already compressed diffs.
+    pub skipped: usize,
+}
+
+/// Reads every `observer_*.bin` in `dir`.
+pub fn collect_samples(dir: &Path) -> io::Result<Samples> {
+    let mut samples = Samples::default();
+    let mut paths: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("observer_") && name.ends_with(".bin"))
+        })
+        .collect();
+    paths.sort();
+    for path in paths {
+        let messages = decode_observer_log(&std::fs::read(&path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        samples.logs += 1;
+        for message in messages {
+            match broadcast::decode(&message.datagram, Features::LEGACY) {
+                Message::Diff(chunk) if is_raw_diff(chunk) => samples.diffs.push(chunk.to_vec()),
+                _ => samples.skipped += 1,
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Wire bytes of a set of diff datagrams, sent three ways.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WireBytes {
+    pub raw: usize,
+    /// Packed, then zstd without a dictionary.
+    pub plain: usize,
+    pub dict: usize,
+}
+
+/// Trains diff dictionary `version` of at most `max_size` bytes on all but
+/// the held-out samples (packed, as they will be compressed), and measures
+/// it on those.
+pub fn train(
+    samples: &[Vec<u8>],
+    max_size: usize,
+    version: u16,
+) -> io::Result<(Vec<u8>, WireBytes)> {
+    let (training, holdout): (Vec<_>, Vec<_>) = samples
+        .iter()
+        .enumerate()
+        .partition(|(n, _)| n % HOLDOUT_EVERY != HOLDOUT_EVERY - 1);
+    let training: Vec<Vec<u8>> = training
+        .into_iter()
+        .map(|(_, raw)| {
+            let mut packed = Vec::with_capacity(raw.len());
+            pack_diff(raw, &mut packed);
+            packed
+        })
+        .collect();
+    let holdout: Vec<&Vec<u8>> = holdout.into_iter().map(|(_, s)| s).collect();
+
+    let mut dict = zstd::dict::from_samples(&training, max_size)?;
+    if !set_dict_version(&mut dict, version) {
+        return Err(io::Error::other(
+            "zstd produced something that is not a dictionary",
+        ));
+    }
+    let wire = measure(&holdout, &dict)?;
+    Ok((dict, wire))
+}
+
+/// Sends each raw diff datagram the way the server does: packed and
+/// compressed, unless that is no smaller.
+pub fn measure(datagrams: &[impl AsRef<[u8]>], dict: &[u8]) -> io::Result<WireBytes> {
+    let mut with_dict = zstd::bulk::Compressor::with_dictionary(DIFF_ZSTD_LEVEL, dict)?;
+    let mut plain = zstd::bulk::Compressor::new(DIFF_ZSTD_LEVEL)?;
+    let mut wire = WireBytes::default();
+    let mut packed = Vec::new();
+    for datagram in datagrams {
+        let raw = datagram.as_ref();
+        packed.clear();
+        pack_diff(raw, &mut packed);
+        wire.raw += raw.len();
+        wire.plain += raw.len().min(1 + plain.compress(&packed)?.len());
+        wire.dict += raw.len().min(1 + with_dict.compress(&packed)?.len());
+    }
+    Ok(wire)
+}
+
+/// What a received broadcast datagram turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    /// Not compressed; nothing to do.
+    Uncompressed,
+    /// A compressed diff that expands to this many bytes of raw records.
+    Diff(usize),
+    /// Compressed with a dictionary this build does not have, or corrupt.
+    Undecodable,
+}
+
+thread_local! {
+    /// One decompressor per shipped dictionary, keyed by dictionary id. The
+    /// runtime is single-threaded, so this is one set per process rather
+    /// than one per simulated user.
+    static DECODERS: RefCell<Vec<(u32, zstd::bulk::Decompressor<'static>)>> = RefCell::new(
+        DIFF_DICTS
+            .iter()
+            .map(|&(version, dict)| {
+                let decompressor = zstd::bulk::Decompressor::with_dictionary(dict)
+                    .expect("Failed to load a shipped diff dictionary");
+                (dict_id(version), decompressor)
+            })
+            .collect(),
+    );
+    /// Packed records, then the raw records they unpack to.
+    static DECODED: RefCell<(Vec<u8>, Vec<u8>)> = RefCell::new((
+        Vec::with_capacity(MAX_DECODED_LEN),
+        Vec::with_capacity(MAX_DECODED_LEN),
+    ));
+}
+
+/// Decompresses `datagram` if it is a compressed diff.
+pub fn decode(datagram: &[u8]) -> Decoded {
+    if datagram.first() != Some(&ZSTD_DIFF_TAG) {
+        return Decoded::Uncompressed;
+    }
+    let Some(frame) = zstd_frame(datagram) else {
+        return Decoded::Uncompressed;
+    };
+    let Some(id) = frame_dict_id(frame) else {
+        return Decoded::Undecodable;
+    };
+    DECODERS.with_borrow_mut(|decoders| {
+        let Some((_, decompressor)) = decoders.iter_mut().find(|(d, _)| *d == id) else {
+            return Decoded::Undecodable;
+        };
+        DECODED.with_borrow_mut(|(packed, raw)| {
+            packed.clear();
+            raw.clear();
+            if decompressor.decompress_to_buffer(frame, packed).is_err()
+                || unpack_diff(packed, raw).is_err()
+            {
+                return Decoded::Undecodable;
+            }
+            Decoded::Diff(raw.len())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::{OBSERVER_QUEUE_LEN, ObserverLog};
+    use bytes::Bytes;
+    use protocol::datagram;
+    use rand::{Rng, SeedableRng};
+
+    /// One tick of diff traffic: bots filling a template image row by row,
+    /// plus scattered users, in the server's chunking.
+    fn tick(rng: &mut impl Rng, cursor: &mut u32, out: &mut Vec<Vec<u8>>) {
+        let mut indices = Vec::new();
+        for _ in 0..rng.gen_range(20..200) {
+            *cursor = (*cursor + rng.gen_range(1..4)) % 200_000;
+            indices.push(300_000 + (*cursor / 200) * 1000 + *cursor % 200);
+        }
+        for _ in 0..rng.gen_range(0..20) {
+            indices.push(rng.gen_range(0..1_000_000));
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        let mut diff = Vec::new();
+        for i in indices {
+            diff.extend_from_slice(&i.to_le_bytes());
+            diff.push(if i % 1000 < 200 {
+                (i % 7) as u8
+            } else {
+                rng.gen_range(0..16)
+            });
+        }
+        out.extend(diff.chunks(1200).map(|c| c.to_vec()));
+    }
+
+    #[test]
+    fn test_train_from_observer_logs_and_decode() {
+        let dir = std::env::temp_dir().join(format!("canvas-train-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut cursor = 0;
+        let mut datagrams = Vec::new();
+        for _ in 0..2_000 {
+            tick(&mut rng, &mut cursor, &mut datagrams);
+        }
+        let log = ObserverLog::create(&dir.join("observer_t_0.bin"), OBSERVER_QUEUE_LEN).unwrap();
+        for d in &datagrams {
+            let mut typed = Vec::new();
+            datagram::push_typed(datagram::DIFF, d, &mut typed);
+            log.record(Bytes::from(typed));
+        }
+        // A full snapshot chunk, skipped.
+        let mut full = vec![datagram::FULL_RLE];
+        full.extend_from_slice(&[255u8, 3].repeat(600));
+        log.record(Bytes::from(full));
+        log.finish();
+        std::fs::write(dir.join("unrelated.bin"), b"not a log").unwrap();
+
+        let samples = collect_samples(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!((samples.logs, samples.skipped), (1, 1));
+        assert_eq!(samples.diffs, datagrams);
+
+        let (dict, wire) = train(&samples.diffs, 16 * 1024, 42).unwrap();
+        assert_eq!(protocol::dict::dict_version(&dict), Some(42));
+        assert!(wire.dict < wire.plain && wire.plain <= wire.raw);
+
+        let mut packed = Vec::new();
+        pack_diff(&datagrams[0], &mut packed);
+        // A frame made with a dictionary this build does not ship.
+        let mut datagram = vec![ZSTD_DIFF_TAG];
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(DIFF_ZSTD_LEVEL, &dict).unwrap();
+        datagram.extend_from_slice(&compressor.compress(&packed).unwrap());
+        assert_eq!(decode(&datagram), Decoded::Undecodable);
+        assert_eq!(decode(&datagrams[0]), Decoded::Uncompressed);
+
+        let (_, shipped) = DIFF_DICTS[DIFF_DICTS.len() - 1];
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(DIFF_ZSTD_LEVEL, shipped).unwrap();
+        let mut datagram = vec![ZSTD_DIFF_TAG];
+        datagram.extend_from_slice(&compressor.compress(&packed).unwrap());
+        assert_eq!(decode(&datagram), Decoded::Diff(datagrams[0].len()));
+        datagram.truncate(datagram.len() - 1);
+        assert_eq!(decode(&datagram), Decoded::Undecodable);
+    }
+}
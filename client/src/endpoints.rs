@@ -0,0 +1,84 @@
+//! Per-endpoint counters for the source-port pool. The pool exists to spread
+//! connections over the server's SO_REUSEPORT workers; these show whether it
+//! does, and whether the kernel dropped broadcasts on our side before quinn
+//! ever saw them (which would otherwise read as server-side loss).
+
+use crate::metrics::AlignedAtomic;
+use std::collections::HashMap;
+
+pub struct EndpointStats {
+    /// Local UDP port of the endpoint's socket; how `/proc/net/udp` names it.
+    pub port: u16,
+    pub established: AlignedAtomic,
+    pub rx_datagrams: AlignedAtomic,
+}
+
+impl EndpointStats {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            established: AlignedAtomic::new(0),
+            rx_datagrams: AlignedAtomic::new(0),
+        }
+    }
+}
+
+/// Adds the `drops` column of a `/proc/net/udp` (or `udp6`) table to `out`,
+/// keyed by local port. Malformed lines are skipped.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn parse_udp_drops(table: &str, out: &mut HashMap<u16, u64>) {
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 13 {
+            continue;
+        }
+        let port = fields[1]
+            .rsplit_once(':')
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+        if let (Some(port), Ok(drops)) = (port, fields[fields.len() - 1].parse::<u64>()) {
+            *out.entry(port).or_default() += drops;
+        }
+    }
+}
+
+/// Kernel receive drops per local UDP port, for every socket in our network
+/// namespace; each pool endpoint has a port of its own to look up.
+#[cfg(target_os = "linux")]
+pub fn socket_drops() -> HashMap<u16, u64> {
+    let mut drops = HashMap::new();
+    for table in ["/proc/net/udp", "/proc/net/udp6"] {
+        if let Ok(contents) = std::fs::read_to_string(table) {
+            parse_udp_drops(&contents, &mut drops);
+        }
+    }
+    drops
+}
+
+/// No per-socket drop counters to read; the column stays at zero.
+#[cfg(not(target_os = "linux"))]
+pub fn socket_drops() -> HashMap<u16, u64> {
+    HashMap::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_udp_drops() {
+        let udp = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  112: 00000000:E2A5 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 81234 2 0000000000000000 17
+  113: 0100007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 12345 2 0000000000000000 0
+  114: garbage
+";
+        let udp6 = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  40: 00000000000000000000000000000000:E2A5 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 81235 2 0000000000000000 3
+";
+        let mut drops = HashMap::new();
+        parse_udp_drops(udp, &mut drops);
+        parse_udp_drops(udp6, &mut drops);
+        assert_eq!(drops.get(&0xE2A5), Some(&20));
+        assert_eq!(drops.get(&53), Some(&0));
+        assert_eq!(drops.len(), 2);
+    }
+}
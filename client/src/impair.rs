@@ -0,0 +1,108 @@
+//! Network faults the load client injects on purpose (`--impair`), to check
+//! the server copes with them.
+//!
+//! `dup=p` sends each pixel datagram a second time with probability `p`,
+//! byte for byte (token included with `--pixel-tokens`). QUIC already drops
+//! UDP packets a middlebox duplicated, so a duplicate the application sees
+//! is always a resend by the peer; that is what this simulates. The server
+//! must take a tokened duplicate as a retransmit (its
+//! `canvas_worker_duplicate_pixels_total`); an untokened one is charged as a
+//! cooldown rejection. Duplicates are counted in the summary's
+//! `tx_duplicates` and never in `tx_pixels` or the cooldown pacing.
+//!
+//! `rx_loss=p` throws away each received datagram with probability `p`
+//! before the client looks at it, as if the path to the client lost it;
+//! QUIC does not retransmit datagrams, so the effect is the same. Dropped
+//! datagrams are counted in `rx_dropped`, not in `rx_datagrams`.
+
+use rand::Rng;
+
+/// Faults to inject; the default injects none.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Impairment {
+    /// Probability of sending a pixel datagram twice.
+    pub dup: f64,
+    /// Probability of losing a received datagram.
+    pub rx_loss: f64,
+}
+
+impl Impairment {
+    /// Whether to send the datagram just sent once more.
+    pub fn duplicate(&self, rng: &mut impl Rng) -> bool {
+        self.dup > 0.0 && rng.gen_bool(self.dup)
+    }
+
+    /// Whether to act as if the datagram just received never arrived.
+    pub fn lose_rx(&self, rng: &mut impl Rng) -> bool {
+        self.rx_loss > 0.0 && rng.gen_bool(self.rx_loss)
+    }
+}
+
+/// Parses `key=value[,key=value...]`, e.g. `dup=0.05,rx_loss=0.02`.
+pub fn parse_impairment(spec: &str) -> Result<Impairment, String> {
+    let mut impairment = Impairment::default();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("impairment '{}' must be key=value", part))?;
+        let probability = |key: &str| {
+            value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("{} must be a probability in 0..=1, not '{}'", key, value))
+        };
+        match key.trim() {
+            "dup" => impairment.dup = probability("dup")?,
+            "rx_loss" => impairment.rx_loss = probability("rx_loss")?,
+            other => {
+                return Err(format!(
+                    "unknown impairment '{}' (known: dup, rx_loss)",
+                    other
+                ));
+            }
+        }
+    }
+    Ok(impairment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_parse_and_rate() {
+        assert_eq!(parse_impairment("dup=0.05").unwrap().dup, 0.05);
+        assert_eq!(parse_impairment(" dup = 1 ,").unwrap().dup, 1.0);
+        assert_eq!(parse_impairment("").unwrap(), Impairment::default());
+        assert_eq!(
+            parse_impairment("dup=0.1,rx_loss=0.02").unwrap(),
+            Impairment {
+                dup: 0.1,
+                rx_loss: 0.02
+            }
+        );
+        for bad in [
+            "dup",
+            "dup=1.5",
+            "dup=-0.1",
+            "dup=x",
+            "loss=0.1",
+            "rx_loss=2",
+        ] {
+            assert!(parse_impairment(bad).is_err(), "{}", bad);
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let dup = parse_impairment("dup=0.05").unwrap();
+        let duplicated = (0..20_000).filter(|_| dup.duplicate(&mut rng)).count();
+        assert!((800..1_200).contains(&duplicated), "{}", duplicated);
+        assert!(!(0..1_000).any(|_| Impairment::default().duplicate(&mut rng)));
+        let loss = parse_impairment("rx_loss=0.02").unwrap();
+        let lost = (0..20_000).filter(|_| loss.lose_rx(&mut rng)).count();
+        assert!((300..500).contains(&lost), "{}", lost);
+        assert!(!(0..1_000).any(|_| Impairment::default().lose_rx(&mut rng)));
+    }
+}
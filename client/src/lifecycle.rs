@@ -0,0 +1,494 @@
+//! The life of one simulated user's session as explicit phases, so a
+//! failure can be pinned on the phase it happened in:
+//!
+//! - `connecting`: handshake in flight, failover attempts included;
+//! - `syncing`: connected, waiting for a whole snapshot of the canvas,
+//!   again after the server starts a new epoch (`--epochs`);
+//! - `steady`: synced and receiving broadcasts;
+//! - `degraded`: synced, but nothing arrived for `--stall-ms`; back to
+//!   steady with the next broadcast.
+//!
+//! A session ends closed, for a [`CloseReason`]. Each phase counts the users
+//! that entered and left it, so its population at any moment is the
+//! difference, exported every second (`<id>_data.csv`: "5k users stuck in
+//! syncing"). A full snapshot is only recognizable as such on connections
+//! that negotiated FEC (`--fec`); elsewhere a snapshot chunk looks like any
+//! diff, and the first broadcast stands in for the first full sync.
+//!
+//! [`Reconnect`] decides, across sessions, whether and when a user comes
+//! back.
+
+use crate::metrics::{AlignedAtomic, LatencyHistogram, LoadMetrics};
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connecting = 0,
+    Syncing = 1,
+    Steady = 2,
+    Degraded = 3,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [
+        Phase::Connecting,
+        Phase::Syncing,
+        Phase::Steady,
+        Phase::Degraded,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Connecting => "connecting",
+            Phase::Syncing => "syncing",
+            Phase::Steady => "steady",
+            Phase::Degraded => "degraded",
+        }
+    }
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// No target accepted the connection.
+    ConnectFailed = 0,
+    /// The connection went away: closed by the server or timed out.
+    Lost = 1,
+    /// A pixel could not be sent.
+    SendFailed = 2,
+    /// The persona's session length was up.
+    SessionEnd = 3,
+    /// The run is over.
+    Shutdown = 4,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 5] = [
+        CloseReason::ConnectFailed,
+        CloseReason::Lost,
+        CloseReason::SendFailed,
+        CloseReason::SessionEnd,
+        CloseReason::Shutdown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::Lost => "lost",
+            CloseReason::SendFailed => "send_failed",
+            CloseReason::SessionEnd => "session_end",
+            CloseReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// One target's phase bookkeeping, in `LoadMetrics::lifecycle`.
+pub struct LifecycleMetrics {
+    /// Entries into and exits from each phase, as two monotonic counters
+    /// (see `AlignedAtomic::sub`).
+    entered: [AlignedAtomic; Phase::ALL.len()],
+    left: [AlignedAtomic; Phase::ALL.len()],
+    pub full_syncs: AlignedAtomic,
+    /// New epochs the server announced, each one back to syncing.
+    pub epoch_changes: AlignedAtomic,
+    closed: [AlignedAtomic; CloseReason::ALL.len()],
+    /// Connect start to established, and established (or a new epoch) to
+    /// the full sync that follows.
+    pub connect_ms: LatencyHistogram,
+    pub sync_ms: LatencyHistogram,
+}
+
+impl LifecycleMetrics {
+    pub fn new() -> Self {
+        Self {
+            entered: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            left: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            full_syncs: AlignedAtomic::new(0),
+            epoch_changes: AlignedAtomic::new(0),
+            closed: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            connect_ms: LatencyHistogram::new(),
+            sync_ms: LatencyHistogram::new(),
+        }
+    }
+
+    /// Users in `phase` right now. Exits are read first, as in
+    /// `LoadMetrics::active`.
+    pub fn population(&self, phase: Phase) -> usize {
+        let left = self.left[phase as usize].get();
+        self.entered[phase as usize].get().saturating_sub(left)
+    }
+
+    pub fn entered(&self, phase: Phase) -> usize {
+        self.entered[phase as usize].get()
+    }
+
+    pub fn closed(&self, reason: CloseReason) -> usize {
+        self.closed[reason as usize].get()
+    }
+
+    /// `{"entered":{"connecting":n,...},"full_syncs":n,"epoch_changes":n,
+    /// "closed":{"connect_failed":n,...},"connect_ms":{..},"sync_ms":{..}}`
+    pub fn json(&self) -> String {
+        let entered: Vec<String> = Phase::ALL
+            .iter()
+            .map(|&p| format!("\"{}\":{}", p.name(), self.entered(p)))
+            .collect();
+        let closed: Vec<String> = CloseReason::ALL
+            .iter()
+            .map(|&r| format!("\"{}\":{}", r.name(), self.closed(r)))
+            .collect();
+        format!(
+            "{{\"entered\":{{{}}},\"full_syncs\":{},\"epoch_changes\":{},\"closed\":{{{}}},\"connect_ms\":{},\"sync_ms\":{}}}",
+            entered.join(","),
+            self.full_syncs.get(),
+            self.epoch_changes.get(),
+            closed.join(","),
+            self.connect_ms.json(),
+            self.sync_ms.json()
+        )
+    }
+}
+
+impl Default for LifecycleMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One session's phase, kept in step with the counters of the target it
+/// belongs to.
+pub struct Lifecycle<'a> {
+    metrics: &'a LoadMetrics,
+    phase: Phase,
+    stall: Duration,
+    started: Instant,
+    established: Option<Instant>,
+    broadcast_seen: bool,
+    synced: bool,
+    /// What the next full sync is timed from: establishment, or the start
+    /// of the epoch it resyncs to.
+    sync_from: Instant,
+    last_rx: Instant,
+}
+
+impl<'a> Lifecycle<'a> {
+    /// A session starts connecting, counted against `metrics`, its assigned
+    /// target's.
+    pub fn connect_start(metrics: &'a LoadMetrics, stall: Duration, now: Instant) -> Self {
+        metrics.lifecycle.entered[Phase::Connecting as usize].add(1);
+        Self {
+            metrics,
+            phase: Phase::Connecting,
+            stall,
+            started: now,
+            established: None,
+            broadcast_seen: false,
+            synced: false,
+            sync_from: now,
+            last_rx: now,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Connected, to the target `metrics` belongs to (another one after a
+    /// failover).
+    pub fn established(&mut self, metrics: &'a LoadMetrics, now: Instant) {
+        debug_assert_eq!(self.phase, Phase::Connecting);
+        self.leave();
+        self.metrics = metrics;
+        self.metrics.lifecycle.connect_ms.record(now - self.started);
+        self.established = Some(now);
+        self.sync_from = now;
+        self.last_rx = now;
+        self.enter(Phase::Syncing);
+    }
+
+    /// A broadcast arrived. The first one is timed from establishment; one
+    /// ends a stall.
+    pub fn broadcast(&mut self, now: Instant) {
+        let Some(established) = self.established else {
+            return;
+        };
+        self.last_rx = now;
+        if !self.broadcast_seen {
+            self.broadcast_seen = true;
+            self.metrics.first_broadcast_ms.record(now - established);
+        }
+        if self.phase == Phase::Degraded {
+            self.switch(Phase::Steady);
+        }
+    }
+
+    /// A whole snapshot arrived; the first one ends syncing.
+    pub fn full_sync(&mut self, now: Instant) {
+        if self.established.is_none() || self.synced {
+            return;
+        }
+        self.synced = true;
+        let lifecycle = &self.metrics.lifecycle;
+        lifecycle.full_syncs.add(1);
+        lifecycle.sync_ms.record(now - self.sync_from);
+        if self.phase == Phase::Syncing {
+            self.switch(Phase::Steady);
+        }
+    }
+
+    /// The server started a new epoch: the canvas synced so far is gone.
+    /// Back to syncing until the next whole snapshot.
+    pub fn new_epoch(&mut self, now: Instant) {
+        if self.established.is_none() {
+            return;
+        }
+        self.metrics.lifecycle.epoch_changes.add(1);
+        self.synced = false;
+        self.sync_from = now;
+        if self.phase != Phase::Syncing {
+            self.switch(Phase::Syncing);
+        }
+    }
+
+    /// Degrades a steady session that has heard nothing for the stall
+    /// time. Returns when to check again.
+    pub fn check_stall(&mut self, now: Instant) -> Instant {
+        let deadline = self.last_rx + self.stall;
+        if self.phase == Phase::Steady && now >= deadline {
+            self.switch(Phase::Degraded);
+        }
+        deadline
+    }
+
+    /// Ends the session.
+    pub fn close(mut self, reason: CloseReason) -> CloseReason {
+        self.leave();
+        self.metrics.lifecycle.closed[reason as usize].add(1);
+        reason
+    }
+
+    fn switch(&mut self, to: Phase) {
+        self.leave();
+        self.enter(to);
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.metrics.lifecycle.entered[phase as usize].add(1);
+    }
+
+    fn leave(&mut self) {
+        self.metrics.lifecycle.left[self.phase as usize].add(1);
+    }
+}
+
+/// Shortest and longest wait after failed connects, doubling per failure
+/// in a row.
+pub const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Whether and when a user starts another session.
+#[derive(Default)]
+pub struct Reconnect {
+    /// Failed connects since the last established one.
+    failures: u32,
+}
+
+impl Reconnect {
+    /// Wait before the next session, on top of the persona's arrival delay;
+    /// None when the user is done: on shutdown, and after the one session
+    /// of a persona that does not churn. Failed connects back off.
+    pub fn after(&mut self, reason: CloseReason, churns: bool) -> Option<Duration> {
+        if reason == CloseReason::Shutdown || !churns {
+            return None;
+        }
+        if reason != CloseReason::ConnectFailed {
+            self.failures = 0;
+            return Some(Duration::ZERO);
+        }
+        self.failures += 1;
+        let backoff = RECONNECT_BACKOFF_MIN.saturating_mul(1 << (self.failures - 1).min(16));
+        Some(backoff.min(RECONNECT_BACKOFF_MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::targets::Target;
+    use std::sync::Arc;
+
+    fn metrics(port: u16) -> Arc<LoadMetrics> {
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        LoadMetrics::new("test".to_string(), Target { addr, weight: 1 })
+    }
+
+    fn populations(m: &LoadMetrics) -> [usize; 4] {
+        Phase::ALL.map(|p| m.lifecycle.population(p))
+    }
+
+    const STALL: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_session_walks_through_every_phase() {
+        let ms = Duration::from_millis;
+        let m = metrics(4433);
+        let t0 = Instant::now();
+        let mut life = Lifecycle::connect_start(&m, STALL, t0);
+        assert_eq!(populations(&m), [1, 0, 0, 0]);
+
+        // Nothing counts before the handshake is done.
+        life.broadcast(t0 + ms(1));
+        life.full_sync(t0 + ms(1));
+        assert_eq!(life.phase(), Phase::Connecting);
+        assert_eq!(
+            m.first_broadcast_ms.json(),
+            metrics(1).first_broadcast_ms.json()
+        );
+
+        life.established(&m, t0 + ms(30));
+        assert_eq!(populations(&m), [0, 1, 0, 0]);
+        // Diffs before the first snapshot keep it syncing, and it never
+        // degrades there: it is stuck, which is what `syncing` says.
+        life.broadcast(t0 + ms(70));
+        assert_eq!(life.check_stall(t0 + ms(70) + STALL), t0 + ms(70) + STALL);
+        assert_eq!(life.phase(), Phase::Syncing);
+        // The datagram that completes a snapshot is a broadcast too.
+        life.broadcast(t0 + ms(300));
+        life.full_sync(t0 + ms(300));
+        assert_eq!(populations(&m), [0, 0, 1, 0]);
+
+        // Quiet for less than the stall time, then for all of it.
+        assert_eq!(life.check_stall(t0 + ms(5_000)), t0 + ms(300) + STALL);
+        assert_eq!(life.phase(), Phase::Steady);
+        life.check_stall(t0 + ms(300) + STALL);
+        assert_eq!(populations(&m), [0, 0, 0, 1]);
+        life.broadcast(t0 + ms(20_000));
+        life.full_sync(t0 + ms(20_000));
+        assert_eq!(populations(&m), [0, 0, 1, 0]);
+
+        assert_eq!(life.close(CloseReason::Lost), CloseReason::Lost);
+        assert_eq!(populations(&m), [0; 4]);
+        let lifecycle = &m.lifecycle;
+        assert_eq!(Phase::ALL.map(|p| lifecycle.entered(p)), [1, 1, 2, 1]);
+        assert_eq!(lifecycle.full_syncs.get(), 1);
+        assert_eq!(lifecycle.closed(CloseReason::Lost), 1);
+        assert!(
+            lifecycle
+                .connect_ms
+                .json()
+                .ends_with("\"sum_ms\":30,\"count\":1}")
+        );
+        assert!(
+            lifecycle
+                .sync_ms
+                .json()
+                .ends_with("\"sum_ms\":270,\"count\":1}")
+        );
+        assert!(
+            m.first_broadcast_ms
+                .json()
+                .ends_with("\"sum_ms\":40,\"count\":1}")
+        );
+        assert!(m.lifecycle.json().starts_with(
+            "{\"entered\":{\"connecting\":1,\"syncing\":1,\"steady\":2,\"degraded\":1},\"full_syncs\":1,\"epoch_changes\":0,\"closed\":{\"connect_failed\":0,\"lost\":1,"
+        ));
+    }
+
+    #[test]
+    fn test_new_epoch_resyncs() {
+        let ms = Duration::from_millis;
+        let m = metrics(4433);
+        let t0 = Instant::now();
+        let mut life = Lifecycle::connect_start(&m, STALL, t0);
+        life.new_epoch(t0);
+        life.established(&m, t0 + ms(10));
+        life.full_sync(t0 + ms(50));
+        assert_eq!(populations(&m), [0, 0, 1, 0]);
+
+        // Timed from the new epoch, not from establishment.
+        life.new_epoch(t0 + ms(1_000));
+        assert_eq!(populations(&m), [0, 1, 0, 0]);
+        life.full_sync(t0 + ms(1_020));
+        assert_eq!(populations(&m), [0, 0, 1, 0]);
+        let lifecycle = &m.lifecycle;
+        assert_eq!(lifecycle.epoch_changes.get(), 1);
+        assert_eq!(lifecycle.full_syncs.get(), 2);
+        assert!(
+            lifecycle
+                .sync_ms
+                .json()
+                .ends_with("\"sum_ms\":60,\"count\":2}")
+        );
+    }
+
+    #[test]
+    fn test_failover_and_failed_connects_balance_per_target() {
+        let (assigned, other) = (metrics(4433), metrics(4434));
+        let t0 = Instant::now();
+
+        // Connecting counts against the assigned target, the rest against
+        // the one that took the connection.
+        let mut life = Lifecycle::connect_start(&assigned, STALL, t0);
+        life.established(&other, t0 + Duration::from_millis(5));
+        assert_eq!(populations(&assigned), [0; 4]);
+        assert_eq!(populations(&other), [0, 1, 0, 0]);
+        life.close(CloseReason::Shutdown);
+        assert_eq!(populations(&other), [0; 4]);
+        assert_eq!(other.lifecycle.closed(CloseReason::Shutdown), 1);
+
+        let life = Lifecycle::connect_start(&assigned, STALL, t0);
+        life.close(CloseReason::ConnectFailed);
+        assert_eq!(populations(&assigned), [0; 4]);
+        assert_eq!(assigned.lifecycle.entered(Phase::Connecting), 2);
+        assert_eq!(assigned.lifecycle.closed(CloseReason::ConnectFailed), 1);
+        assert_eq!(
+            assigned.lifecycle.connect_ms.json(),
+            metrics(1).lifecycle.connect_ms.json()
+        );
+    }
+
+    #[test]
+    fn test_reconnect_backs_off_failed_connects() {
+        let secs = Duration::from_secs;
+        let mut reconnect = Reconnect::default();
+        // Users that do not churn have one session, however it ended.
+        assert_eq!(reconnect.after(CloseReason::SessionEnd, false), None);
+        assert_eq!(reconnect.after(CloseReason::ConnectFailed, false), None);
+
+        let backoffs: Vec<_> = (0..8)
+            .map(|_| reconnect.after(CloseReason::ConnectFailed, true).unwrap())
+            .collect();
+        assert_eq!(
+            backoffs,
+            [
+                RECONNECT_BACKOFF_MIN,
+                secs(1),
+                secs(2),
+                secs(4),
+                secs(8),
+                secs(16),
+                RECONNECT_BACKOFF_MAX,
+                RECONNECT_BACKOFF_MAX
+            ]
+        );
+        for _ in 0..100 {
+            reconnect.after(CloseReason::ConnectFailed, true);
+        }
+        assert_eq!(
+            reconnect.after(CloseReason::ConnectFailed, true),
+            Some(RECONNECT_BACKOFF_MAX)
+        );
+
+        // A session that got anywhere starts the count over.
+        assert_eq!(
+            reconnect.after(CloseReason::Lost, true),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            reconnect.after(CloseReason::ConnectFailed, true),
+            Some(RECONNECT_BACKOFF_MIN)
+        );
+        assert_eq!(reconnect.after(CloseReason::Shutdown, true), None);
+    }
+}
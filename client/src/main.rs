@@ -5,23 +5,95 @@
 // clap = { version = "4.4", features = ["derive"] }
 
 use bytes::Bytes;
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
+use diff_dict::Decoded;
+use endpoints::EndpointStats;
+use impair::Impairment;
+use lifecycle::{CloseReason, Lifecycle, Phase, Reconnect};
+use observer::ObserverLog;
+use pacing::{CooldownPacer, CooldownSpec};
+use protocol::batch::{self, MAX_BATCH_PIXELS};
+use protocol::broadcast::{self, Message};
+use protocol::close::AppCloseCode;
+use protocol::control;
+use protocol::fec::FecAssembler;
+use protocol::pixel::{self, PIXEL_LEN};
+use protocol::receipt;
+use protocol::stats::{self, STATS_MIN_INTERVAL_MS, STATS_TAG};
+use protocol::version::{self, BuildInfo, Features};
 use quinn::Endpoint;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rle_check::RleVerifier;
+use scenario::{Arrival, Pattern, Persona, Role, Scenario};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
+use targets::Target;
+use tiers::TracerBoard;
+use tokio::sync::watch;
 use tokio::time::sleep;
+use trace::{EventKind, TraceRecorder};
 
+mod diff_dict;
+mod endpoints;
+mod impair;
+mod lifecycle;
 mod metrics;
+mod observer;
+mod pacing;
+mod reconstruct;
+mod replay;
+mod rle_check;
+mod scenario;
+mod soak;
+mod targets;
+mod tiers;
 mod tls;
+mod trace;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Ramp up simulated users sending random-interval pixels.
+    Load,
+    /// Re-execute a trace recorded with --record.
+    Replay,
+    /// Fetch the per-minute activity timeline from the first target and print it.
+    Timeline,
+    /// Fetch the first target's effective configuration (JSON) and print it.
+    Config,
+    /// Follow the --viewport tiles of the first target with catch-ups and
+    /// check them against its tile checksums, refreshing any that fail.
+    VerifyTiles,
+    /// Train a diff dictionary on the observer logs in --observer-dir and
+    /// write it to --dict-out (see protocol::dict). Needs no server.
+    TrainDict,
+    /// Watch a running soak: follow the client CSVs in --metrics-dir and the
+    /// server dashboard named in --soak, write a diagnostic bundle whenever
+    /// an anomaly rule fires and exit 1 if any did (see soak.rs). Needs no
+    /// target.
+    Soak,
+}
 
 #[derive(Parser, Debug, Clone)]
 struct Args {
+    #[arg(long, value_enum, default_value_t = Mode::Load)]
+    mode: Mode,
+    /// Server to drive, as `[https://]ip:port[=weight]`. Repeat to split
+    /// users across instances by weight (default weight 1). Required in
+    /// every mode but train-dict and soak.
+    #[arg(long = "target", value_parser = targets::parse_target_spec)]
+    targets: Vec<Target>,
+    /// On connection failure, try the other targets instead of giving up.
     #[arg(long)]
-    target: String,
-    #[arg(long)]
-    clients: usize,
+    failover: bool,
+    /// Number of simulated users (load mode).
     #[arg(long)]
+    clients: Option<usize>,
+    /// Names this run's metrics files. Required in every mode but train-dict
+    /// and soak.
+    #[arg(long, default_value = "")]
     id: String,
     #[arg(long, default_value_t = 10000)]
     max_conn_jitter: u64,
@@ -29,115 +101,1300 @@ struct Args {
     min_pixel_wait: u64,
     #[arg(long, default_value_t = 10000)]
     max_pixel_wait: u64,
-    #[arg(long, default_value = "/metrics")]
+    #[arg(long, default_value_t = metrics::default_metrics_dir())]
     metrics_dir: String,
+    /// Write `<id>_data.csv` in the schema 4 layout (second timestamps,
+    /// `tx_pps`, `rx_mbps`, ...) instead of the current one. Goes away in
+    /// the next release; bench/client_csv.py converts old files.
+    #[arg(long)]
+    legacy_csv: bool,
+    /// Record every connection event and sent datagram into a binary trace.
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Trace to re-execute in replay mode.
+    #[arg(long, required_if_eq("mode", "replay"))]
+    trace: Option<PathBuf>,
+    /// Replay speed multiplier (2.0 = twice as fast as recorded).
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Persona mix for load mode (see client/scenarios/). Keys a persona
+    /// omits fall back to the flags above.
+    #[arg(long)]
+    scenario: Option<PathBuf>,
+    /// Extra non-painting connections that archive every broadcast they
+    /// receive (load mode), spread over the targets in order.
+    #[arg(long, default_value_t = 0)]
+    observers: usize,
+    /// Where observer logs go: `observer_<id>_<n>.bin`. Also where
+    /// train-dict reads them from.
+    #[arg(long, default_value = ".")]
+    observer_dir: PathBuf,
+    /// Observers negotiate FEC and decode each complete full snapshot with
+    /// a reference RLE decoder as well as the real ones; the summary's
+    /// `rle` section counts divergences (see rle_check.rs).
+    #[arg(long)]
+    verify_rle: bool,
+    /// Move sampled users to a fresh local port every N seconds, like a NAT
+    /// rebinding or a Wi-Fi to cellular switch (load mode).
+    #[arg(long)]
+    rebind_interval: Option<u64>,
+    /// Fraction of users that rebind; each gets its own endpoint so the
+    /// others are not moved along.
+    #[arg(long, default_value_t = 0.1)]
+    rebind_fraction: f64,
+    /// Only receive diffs for the tiles under this pixel rectangle,
+    /// `x0,y0,x1,y1` (half-open). Repeat for disjoint regions; every user
+    /// subscribes to their union (load mode).
+    #[arg(long = "viewport", value_parser = parse_viewport)]
+    viewports: Vec<control::PixelRect>,
+    /// Server cooldown in seconds, or `server` to ask the first target.
+    /// Writers then send their next pixel just after it expires, and the
+    /// summary reports accepted pixel throughput (load mode).
+    #[arg(long, value_parser = pacing::parse_cooldown_spec)]
+    respect_cooldown: Option<CooldownSpec>,
+    /// Percentage of users that ignore the cooldown, to keep the rejection
+    /// path exercised under --respect-cooldown.
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    defiance: u8,
+    /// Append a fresh idempotency token to every pixel (9-byte datagrams),
+    /// so the server's retransmit filter is on the measured path.
+    #[arg(long)]
+    pixel_tokens: bool,
+    /// Follow the token with a nonce that counts up across the user's
+    /// reconnects (13-byte datagrams), as clients that send pixels in 0-RTT
+    /// do for the server's replay filter.
+    #[arg(long)]
+    pixel_nonces: bool,
+    /// Pixels per datagram. Above 1, each send is one batch of that many
+    /// (up to 32) if the server offers batching in the VERSION handshake,
+    /// and a single pixel if not. Batches carry no tokens or nonces.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=MAX_BATCH_PIXELS as i64))]
+    pixels_per_dgram: u8,
+    /// Offer RECEIPTS in the VERSION handshake and number each single
+    /// pixel (protocol::receipt); the summary's `receipts` section counts
+    /// the pixels the server acknowledged and their share of those sent
+    /// (load mode).
+    #[arg(long)]
+    pixel_receipts: bool,
+    /// Offer EPOCHS in the VERSION handshake: a new epoch on the server
+    /// sends the connection back to syncing, counted in the summary's
+    /// `lifecycle.epoch_changes` (load mode).
+    #[arg(long)]
+    epochs: bool,
+    /// Faults to inject, e.g. `dup=0.05` to send 5% of pixel datagrams
+    /// twice (see impair.rs; load mode).
+    #[arg(long, value_parser = impair::parse_impairment, default_value = "")]
+    impair: Impairment,
+    /// Offer dictionary-compressed diffs in the VERSION handshake and
+    /// decompress them; the summary's `zstd` section counts them (load mode).
+    #[arg(long)]
+    zstd_diffs: bool,
+    /// Offer FEC in the VERSION handshake and put full snapshots back
+    /// together, rebuilding lost chunks from parity; the summary's `fec`
+    /// section counts complete snapshots with and without it (load mode).
+    #[arg(long)]
+    fec: bool,
+    /// Tier-1 token the server accepts (`--tier1-tokens`): paint tracer
+    /// pixels on the first target and time them on a tier-0 and a tier-1
+    /// observer; the summary's `tiers` section compares them (load mode).
+    #[arg(long)]
+    tier_token: Option<String>,
+    /// Time between tracer pixels with --tier-token.
+    #[arg(long, default_value_t = 1000)]
+    tracer_interval_ms: u64,
+    /// Checks verify-tiles runs before exiting, and the time between them.
+    #[arg(long, default_value_t = 10)]
+    verify_rounds: u32,
+    #[arg(long, default_value_t = 1000)]
+    verify_interval_ms: u64,
+    /// Readers and observers ask for a STATS datagram (protocol::stats) as
+    /// often as the server allows; the summary's `live_stats` section holds
+    /// the newest figures (load mode).
+    #[arg(long)]
+    live_stats: bool,
+    /// A synced user that receives nothing for this long counts as
+    /// `degraded` until the next broadcast (see lifecycle.rs).
+    #[arg(long, default_value_t = 10_000)]
+    stall_ms: u64,
+    /// Where train-dict writes the dictionary.
+    #[arg(long, default_value = "diff.zdict")]
+    dict_out: PathBuf,
+    /// Version train-dict gives the dictionary; default: one past the newest
+    /// shipped in protocol/dicts/.
+    #[arg(long)]
+    dict_version: Option<u16>,
+    /// Largest dictionary train-dict may produce, in bytes.
+    #[arg(long, default_value_t = 8 * 1024)]
+    dict_size: usize,
+    /// Seeds each simulated user's randomness (arrival, pixels, waits,
+    /// tokens, impairments) with this plus the user's index, so a failing
+    /// run can be replayed, network timing aside (load mode). Default: the
+    /// scenario's `seed`, else a random one; the summary records it.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Soak watcher settings and anomaly rules (see client/scenarios/soak.toml);
+    /// default: every rule at its default, no dashboard.
+    #[arg(long)]
+    soak: Option<PathBuf>,
 }
 
-pub fn rle_decompress(src: &[u8], dst: &mut [u8]) -> usize {
-    let mut src_idx = 0;
-    let mut dst_idx = 0;
-    while src_idx + 1 < src.len() {
-        let count = src[src_idx] as usize;
-        let color = src[src_idx + 1];
-        src_idx += 2;
-        for _ in 0..count {
-            if dst_idx < dst.len() {
-                dst[dst_idx] = color;
-                dst_idx += 1;
-            }
-        }
+fn parse_viewport(spec: &str) -> Result<control::PixelRect, String> {
+    let v: Vec<u16> = spec
+        .split(',')
+        .map(|p| p.trim().parse::<u16>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid viewport '{}': {}", spec, e))?;
+    match v[..] {
+        [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(control::PixelRect { x0, y0, x1, y1 }),
+        _ => Err(format!(
+            "viewport '{}' must be x0,y0,x1,y1 with x0 < x1 and y0 < y1",
+            spec
+        )),
     }
-    dst_idx
 }
 
-async fn simulate_user(endpoint: Endpoint, metrics: Arc<metrics::LoadMetrics>, args: Args) {
-    let target_cleaned = args.target.replace("https://", "").replace("http://", "");
-    let addr = target_cleaned
-        .parse::<std::net::SocketAddr>()
-        .expect("Invalid target format");
+impl Args {
+    /// Features to offer in the VERSION handshake.
+    fn features(&self) -> Features {
+        let mut features = CLIENT_FEATURES;
+        if self.zstd_diffs {
+            features = features | Features::ZSTD;
+        }
+        if self.fec {
+            features = features | Features::FEC;
+        }
+        if self.pixels_per_dgram > 1 {
+            features = features | Features::BATCHING;
+        }
+        if self.live_stats {
+            features = features | Features::STATS;
+        }
+        if self.respect_cooldown.is_some() {
+            features = features | Features::CONTRACTS | Features::COOLDOWNS;
+        }
+        if self.pixel_receipts {
+            features = features | Features::RECEIPTS;
+        }
+        if self.epochs {
+            features = features | Features::EPOCHS;
+        }
+        features
+    }
+
+    /// Cooldown pacing works with, once `server` has been resolved.
+    fn cooldown_secs(&self) -> Option<u64> {
+        match self.respect_cooldown {
+            Some(CooldownSpec::Secs(secs)) => Some(secs),
+            _ => None,
+        }
+    }
 
-    #[cfg(feature = "debug-logs")]
-    println!("Client {} connecting to {}...", metrics.id, addr);
+    /// The single persona a run without `--scenario` simulates.
+    fn base_persona(&self) -> Persona {
+        Persona {
+            name: "default".to_string(),
+            share: 100,
+            role: Role::Writer,
+            arrival: Arrival::Uniform,
+            max_conn_jitter: self.max_conn_jitter,
+            min_pixel_wait: self.min_pixel_wait,
+            max_pixel_wait: self.max_pixel_wait,
+            session_ms: 0,
+            pattern: Pattern::Fixed,
+        }
+    }
+}
 
-    let conn: quinn::Connection = match endpoint.connect(addr, "localhost") {
-        Ok(connecting) => match connecting.await {
-            Ok(c) => {
-                #[cfg(feature = "debug-logs")]
-                println!("Client {} connected successfully!", metrics.id);
-                metrics.active.add(1);
-                c
-            }
-            Err(e) => {
-                #[cfg(feature = "debug-logs")]
-                println!("Client {} failed to connect: {:?}", metrics.id, e);
-                metrics.failed.add(1);
-                return;
+async fn connect(
+    endpoint: &Endpoint,
+    addr: std::net::SocketAddr,
+    metrics: &metrics::LoadMetrics,
+) -> Option<quinn::Connection> {
+    metrics.connect_attempts.add(1);
+    match endpoint.connect(addr, "localhost") {
+        // With a ticket from an earlier connection to this server the
+        // connection is usable right away. Should the server turn the 0-RTT
+        // down, quinn sends it again once the handshake completes; should
+        // the handshake fail, the connection closes like any other.
+        Ok(connecting) => match connecting.into_0rtt() {
+            Ok((c, _accepted)) => {
+                metrics.connect_0rtt.add(1);
+                metrics.connect_success.add(1);
+                Some(c)
             }
+            Err(connecting) => match connecting.await {
+                Ok(c) => {
+                    #[cfg(feature = "debug-logs")]
+                    println!("Client {} connected successfully!", metrics.id);
+                    metrics.connect_success.add(1);
+                    Some(c)
+                }
+                Err(e) => {
+                    #[cfg(feature = "debug-logs")]
+                    println!("Client {} failed to connect: {:?}", metrics.id, e);
+                    observe_close(&e, metrics);
+                    metrics.connect_failed.add(1);
+                    None
+                }
+            },
         },
-        Err(e) => {
+        Err(_e) => {
             #[cfg(feature = "debug-logs")]
-            println!("Client {} endpoint connect error: {:?}", metrics.id, e);
-            metrics.failed.add(1);
-            return;
+            println!("Client {} endpoint connect error: {:?}", metrics.id, _e);
+            metrics.connect_failed.add(1);
+            None
+        }
+    }
+}
+
+/// Ends a connection `connect` established: tells the server we are leaving
+/// (a no-op if the connection already failed) and counts the disconnect.
+fn leave(conn: &quinn::Connection, metrics: &metrics::LoadMetrics) {
+    close_with(conn, AppCloseCode::ClientExit);
+    metrics.disconnects.add(1);
+}
+
+/// Closes the connection with a registered application code.
+fn close_with(conn: &quinn::Connection, code: AppCloseCode) {
+    conn.close(quinn::VarInt::from_u64(code.code()).unwrap(), code.reason());
+}
+
+/// QUIC transport error CONNECTION_REFUSED (RFC 9000, section 20.1).
+const CONNECTION_REFUSED: u64 = 0x2;
+
+/// Counts closes initiated by the server by their application code; other
+/// transport errors and timeouts are not closes and are ignored here. A
+/// full server refuses before the handshake completes, where only transport
+/// codes can be sent, so CONNECTION_REFUSED counts as `capacity`.
+fn observe_close(err: &quinn::ConnectionError, metrics: &metrics::LoadMetrics) {
+    match err {
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            metrics.record_close(close.error_code.into_inner());
+        }
+        quinn::ConnectionError::ConnectionClosed(close)
+            if u64::from(close.error_code) == CONNECTION_REFUSED =>
+        {
+            metrics.record_close(AppCloseCode::Capacity.code());
+        }
+        _ => {}
+    }
+}
+
+/// How long to wait for CONNECTION_CLOSE frames to go out before exiting.
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Waits (bounded) until every endpoint has flushed its closes, so the server
+/// sees the CONNECTION_CLOSE instead of waiting for the idle timeout.
+async fn drain_endpoints(endpoints: &[Endpoint]) {
+    let idle = async {
+        for ep in endpoints {
+            ep.wait_idle().await;
         }
     };
+    if tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, idle)
+        .await
+        .is_err()
+    {
+        eprintln!("Timed out waiting for connections to close");
+    }
+}
 
-    // TX payload prep
-    let mut payload = [0u8; 5];
-    payload[0..2].copy_from_slice(&100u16.to_ne_bytes());
-    payload[2..4].copy_from_slice(&200u16.to_ne_bytes());
-    payload[4] = 255;
-    let payload_bytes = Bytes::copy_from_slice(&payload);
+/// Whether `user` is one of the `fraction` of users that rebind. Depends only
+/// on the id, so reruns move the same users.
+fn rebind_sampled(user: u32, fraction: f64) -> bool {
+    (user.wrapping_mul(0x9E37_79B9) as f64) < fraction * u32::MAX as f64
+}
 
-    // Optimized Sleep: Pin the future once to avoid reallocation churn in tokio::select!
-    let sleep_duration = if args.min_pixel_wait >= args.max_pixel_wait {
-        args.min_pixel_wait
+/// Randomness of simulated user `user` in a run seeded with `seed`.
+fn user_rng(seed: u64, user: u32) -> StdRng {
+    StdRng::seed_from_u64(seed.wrapping_add(user as u64))
+}
+
+/// Moves every connection of `endpoint` to a fresh local port.
+fn rebind_endpoint(endpoint: &Endpoint) -> std::io::Result<()> {
+    let ip = endpoint.local_addr()?.ip();
+    endpoint.rebind(std::net::UdpSocket::bind((ip, 0))?)
+}
+
+/// One session of a simulated user: connect, paint and watch until the
+/// connection drops, the persona's session ends or the run shuts down, and
+/// say which. `endpoint_stats` belongs to `endpoint`; rebinding users leave
+/// the pool and are not counted there. Every random draw comes from `rng`
+/// (see `--seed`); `nonce` is the next `--pixel-nonces` nonce.
+#[allow(clippy::too_many_arguments)]
+async fn simulate_user(
+    user: u32,
+    persona: &Persona,
+    rng: &mut StdRng,
+    nonce: &mut u32,
+    endpoint: &Endpoint,
+    endpoint_stats: &EndpointStats,
+    metrics: &[Arc<metrics::LoadMetrics>],
+    args: &Args,
+    recorder: Option<&TraceRecorder>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> CloseReason {
+    let rebind_every = args
+        .rebind_interval
+        .filter(|_| rebind_sampled(user, args.rebind_fraction))
+        .map(Duration::from_secs);
+    let own_endpoint;
+    let (endpoint, endpoint_stats) = if rebind_every.is_some() {
+        let mut ep = Endpoint::client("0.0.0.0:0".parse().unwrap()).unwrap();
+        ep.set_default_client_config(tls::build_optimized_config());
+        own_endpoint = ep;
+        (&own_endpoint, None)
     } else {
-        rand::thread_rng().gen_range(args.min_pixel_wait..args.max_pixel_wait)
+        (endpoint, Some(endpoint_stats))
     };
+
+    let assigned = targets::assign(&args.targets, user as usize);
+    let mut life = Lifecycle::connect_start(
+        &metrics[assigned],
+        Duration::from_millis(args.stall_ms),
+        tokio::time::Instant::now(),
+    );
+    let mut established = None;
+    for idx in targets::attempt_order(&args.targets, assigned, args.failover) {
+        let addr = args.targets[idx].addr;
+
+        #[cfg(feature = "debug-logs")]
+        println!("Client {} connecting to {}...", metrics[idx].id, addr);
+
+        if let Some(conn) = connect(endpoint, addr, &metrics[idx]).await {
+            if idx != assigned {
+                metrics[idx].failovers.add(1);
+            }
+            established = Some((conn, idx));
+            break;
+        }
+    }
+    let Some((conn, idx)) = established else {
+        if let Some(r) = recorder {
+            r.record(user, EventKind::ConnectFailed, &[]);
+        }
+        return life.close(CloseReason::ConnectFailed);
+    };
+    if let Some(r) = recorder {
+        r.record(user, EventKind::Connect, &[]);
+    }
+    if let Some(e) = endpoint_stats {
+        e.established.add(1);
+    }
+    let metrics = &metrics[idx];
+    life.established(metrics, tokio::time::Instant::now());
+    // What broadcasts may use: datagrams of features not negotiated are
+    // skipped like unknown ones.
+    let mut negotiated = Features::LEGACY;
+    let mut fec = None;
+    // Pixels per send: a batch only once the server has said it reads them.
+    let mut per_dgram = 1;
+    if !args.viewports.is_empty()
+        || args.zstd_diffs
+        || args.fec
+        || args.pixels_per_dgram > 1
+        || args.live_stats
+        || args.respect_cooldown.is_some()
+        || args.pixel_receipts
+        || args.epochs
+    {
+        let subscribed = match exchange_version(&conn, args.features()).await {
+            Ok(features) => {
+                negotiated = features;
+                if features.contains(Features::BATCHING) {
+                    per_dgram = args.pixels_per_dgram as usize;
+                }
+                if features.contains(Features::FEC) {
+                    fec = Some(FecAssembler::default());
+                }
+                if args.viewports.is_empty() {
+                    Ok(())
+                } else if features.contains(Features::SUBSCRIPTIONS) {
+                    subscribe(&conn, &args.viewports).await
+                } else {
+                    Err("server does not offer subscriptions; receiving every tile".to_string())
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = subscribed {
+            eprintln!("Client {} user {}: {}", metrics.id, user, e);
+        }
+    }
+
+    // Received datagrams arrive as the network has it; losing them draws
+    // from a stream of its own so they do not shift the send schedule.
+    let mut rx_rng = StdRng::seed_from_u64(rng.r#gen());
+
+    // TX payload prep
+    let fixed_pixel = persona.pixel(rng);
+    let fixed_payload = Bytes::copy_from_slice(&pixel::datagram(&fixed_pixel));
+    let writer = persona.role == Role::Writer;
+
+    // Optimized Sleep: Pin the future once to avoid reallocation churn in tokio::select!
+    let mut pacer = args.cooldown_secs().map(|secs| {
+        CooldownPacer::new(
+            Duration::from_secs(secs),
+            pacing::defiant(user, args.defiance),
+        )
+    });
+    if let Some(pacer) = pacer.as_mut() {
+        // A server without COOLDOWN_CONFIG refuses it: the configured
+        // cooldown stands.
+        if let Ok(config) = fetch_cooldown_config(&conn).await {
+            pacer.adopt(tokio::time::Instant::now(), &config);
+        }
+    }
+    let mut sleep_duration = persona.pixel_wait(rng);
+    if let Some(p) = pacer.as_ref() {
+        sleep_duration = p.next_wait(tokio::time::Instant::now(), sleep_duration, rng);
+    }
     let sleep = sleep(Duration::from_millis(sleep_duration));
     tokio::pin!(sleep);
+    let session_end = tokio::time::sleep(Duration::from_millis(persona.session_ms));
+    tokio::pin!(session_end);
+    let rebinding = rebind_every.is_some();
+    let rebind_every = rebind_every.unwrap_or(Duration::MAX);
+    let rebind = tokio::time::sleep(rebind_every);
+    tokio::pin!(rebind);
+    // Set on rebind, cleared by the first broadcast on the new address.
+    let mut rebound_at: Option<tokio::time::Instant> = None;
+    let live_stats = args.live_stats && !writer;
+    let stats_tick = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(stats_tick);
+    let stall_check = tokio::time::sleep(Duration::from_millis(args.stall_ms));
+    tokio::pin!(stall_check);
+    // Rate contracts and cooldowns come on streams the server opens; paced
+    // or not, the pacer keeps track of what the server accepts.
+    let mut pushes = pacer.is_some()
+        && (negotiated.contains(Features::CONTRACTS) || negotiated.contains(Features::COOLDOWNS));
+    // Single pixels go numbered once the server has said it acknowledges
+    // them; batches are acknowledged pixel by pixel, unnumbered.
+    let receipts = negotiated.contains(Features::RECEIPTS);
+    let mut seq: u16 = 0;
+    // The epoch the broadcasts belong to, once the server has said.
+    let mut epoch = None;
 
     // Single loop for both RX and TX to save task overhead
-    loop {
+    let reason = loop {
         tokio::select! {
             // RX: Read incoming datagrams
             res = conn.read_datagram() => {
                 match res {
+                    Ok(_) if args.impair.lose_rx(&mut rx_rng) => {
+                        metrics.rx_dropped.add(1);
+                    }
                     Ok(dgram) => {
                         metrics.rx_datagrams.add(1);
                         metrics.rx_bytes.add(dgram.len());
+                        if let Some(e) = endpoint_stats {
+                            e.rx_datagrams.add(1);
+                        }
+                        let now = tokio::time::Instant::now();
+                        let synced = match broadcast::decode(&dgram, negotiated) {
+                            Message::Stats(live) => {
+                                metrics.record_live_stats(&live);
+                                continue;
+                            }
+                            Message::Skipped(_) => {
+                                metrics.rx_skipped.add(1);
+                                continue;
+                            }
+                            // A pixel landed in the cooldown. Paced, the
+                            // prediction already counted it; the pacer
+                            // waits out what the server says is left.
+                            Message::CooldownNack(secs) => {
+                                metrics.cooldown_nacks.add(1);
+                                match pacer.as_mut() {
+                                    Some(p) => p.nacked(now, secs),
+                                    None => metrics.rejected_pixels.add(1),
+                                }
+                                continue;
+                            }
+                            Message::PixelAck(_) => {
+                                metrics.acked_pixels.add(1);
+                                continue;
+                            }
+                            // A new round: the canvas so far is gone, and
+                            // so is any snapshot half rebuilt.
+                            Message::Epoch(e) => {
+                                if epoch.replace(e).is_some_and(|prev| prev != e) {
+                                    life.new_epoch(now);
+                                    if fec.is_some() {
+                                        fec = Some(FecAssembler::default());
+                                    }
+                                }
+                                continue;
+                            }
+                            Message::ZstdDiff(dgram) => {
+                                match diff_dict::decode(dgram) {
+                                    Decoded::Diff(len) => {
+                                        metrics.zstd_diffs.add(1);
+                                        metrics.zstd_wire_bytes.add(dgram.len());
+                                        metrics.zstd_raw_bytes.add(len);
+                                    }
+                                    Decoded::Uncompressed | Decoded::Undecodable => {
+                                        metrics.zstd_errors.add(1)
+                                    }
+                                }
+                                fec.is_none() && life.phase() == Phase::Syncing
+                            }
+                            // Full snapshots come as FEC chunks once negotiated.
+                            Message::Fec(header, payload) => fec
+                                .as_mut()
+                                .is_some_and(|assembler| assembler.push(&header, payload).is_some()),
+                            // Without FEC the first broadcast stands in for a
+                            // whole snapshot.
+                            Message::Full(_) | Message::Diff(_) => {
+                                fec.is_none() && life.phase() == Phase::Syncing
+                            }
+                        };
+                        life.broadcast(now);
+                        if synced {
+                            life.full_sync(now);
+                        }
+                        if let Some(at) = rebound_at.take() {
+                            metrics.record_rebind_recovery(at.elapsed());
+                        }
                     }
-                    Err(_) => {
+                    Err(e) => {
                         // Connection closed
-                        break;
+                        observe_close(&e, metrics);
+                        if rebound_at.is_some() {
+                            metrics.rebind_losses.add(1);
+                        }
+                        break CloseReason::Lost;
                     }
                 }
             }
             // TX: Periodic pixel update
-            _ = &mut sleep => {
-                if conn.send_datagram(payload_bytes.clone()).is_err() {
-                    break;
+            _ = &mut sleep, if writer => {
+                let payload = match persona.pattern {
+                    _ if per_dgram > 1 => pixel_batch(persona, &fixed_pixel, per_dgram, rng),
+                    pattern if receipts => {
+                        let record = match pattern {
+                            Pattern::Fixed => fixed_pixel,
+                            Pattern::Random => persona.pixel(rng),
+                        };
+                        seq = seq.wrapping_add(1);
+                        Bytes::copy_from_slice(&receipt::seq_datagram(seq, &record))
+                    }
+                    Pattern::Fixed => fixed_payload.clone(),
+                    Pattern::Random => Bytes::copy_from_slice(&pixel::datagram(&persona.pixel(rng))),
+                };
+                let payload = if args.pixel_tokens || args.pixel_nonces {
+                    let mut tokened = payload.to_vec();
+                    pixel::push_trailer(rng.r#gen(), &mut tokened);
+                    if args.pixel_nonces {
+                        pixel::push_trailer(*nonce, &mut tokened);
+                        *nonce = nonce.wrapping_add(1);
+                    }
+                    Bytes::from(tokened)
+                } else {
+                    payload
+                };
+                if conn.send_datagram(payload.clone()).is_err() {
+                    break CloseReason::SendFailed;
+                }
+                metrics.tx_pixels.add(per_dgram);
+                if receipts {
+                    metrics.receipted_pixels.add(per_dgram);
+                }
+                if let Some(r) = recorder {
+                    r.record(user, EventKind::Datagram, &payload);
+                }
+                if args.impair.duplicate(rng)
+                    && conn.send_datagram(payload.clone()).is_ok()
+                {
+                    metrics.tx_duplicates.add(1);
+                    if let Some(r) = recorder {
+                        r.record(user, EventKind::Datagram, &payload);
+                    }
                 }
-                metrics.tx_pixels.add(1);
 
                 // Reset rather than re-create sleep future
-                let next_wait = if args.min_pixel_wait >= args.max_pixel_wait {
-                    args.min_pixel_wait
-                } else {
-                    rand::thread_rng().gen_range(args.min_pixel_wait..args.max_pixel_wait)
+                let now = tokio::time::Instant::now();
+                let mut next_wait = persona.pixel_wait(rng);
+                if let Some(p) = pacer.as_mut() {
+                    if p.record_send(now) {
+                        metrics.accepted_pixels.add(1);
+                    } else {
+                        metrics.rejected_pixels.add(1);
+                    }
+                    // The rest of a batch meets the cooldown the first took.
+                    metrics.rejected_pixels.add(per_dgram - 1);
+                    next_wait = p.next_wait(now, next_wait, rng);
+                }
+                sleep.as_mut().reset(now + Duration::from_millis(next_wait));
+            }
+            // Contract push: pace to the new rate from the next pixel on.
+            // Cooldown push: the same, and wait out what is left of one.
+            stream = conn.accept_uni(), if pushes => {
+                let push = match stream {
+                    Ok(mut recv) => recv
+                        .read_to_end(
+                            1 + control::RATE_CONTRACT_LEN.max(control::COOLDOWN_CONFIG_LEN),
+                        )
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|push| control::decode_server_push(&push)),
+                    // Lost: the datagram arm reports it.
+                    Err(e) => Err(e.to_string()),
                 };
-                sleep.as_mut().reset(tokio::time::Instant::now() + Duration::from_millis(next_wait));
+                match push {
+                    Ok(control::ServerPush::Contract(contract)) => {
+                        if let Some(p) = pacer.as_mut()
+                            && p.contract(&contract)
+                        {
+                            metrics.contract_updates.add(1);
+                        }
+                    }
+                    Ok(control::ServerPush::Cooldown(config)) => {
+                        if let Some(p) = pacer.as_mut() {
+                            p.adopt(tokio::time::Instant::now(), &config);
+                        }
+                    }
+                    // Not asked for: STATS replies carry the interval.
+                    Ok(control::ServerPush::Interval(_)) => {}
+                    Err(_) => pushes = false,
+                }
+            }
+            // Live stats: one request per server rate-limit interval
+            _ = &mut stats_tick, if live_stats => {
+                let _ = conn.send_datagram(Bytes::from_static(&STATS_TAG));
+                stats_tick.as_mut().reset(tokio::time::Instant::now() + STATS_REQUEST_EVERY);
+            }
+            // Rebinding: same connection, new source address
+            _ = &mut rebind, if rebinding => {
+                if rebind_endpoint(endpoint).is_ok() {
+                    metrics.rebinds.add(1);
+                    rebound_at.get_or_insert_with(tokio::time::Instant::now);
+                }
+                rebind.as_mut().reset(tokio::time::Instant::now() + rebind_every);
+            }
+            // Stall: a synced user that hears nothing is degraded
+            _ = &mut stall_check, if life.phase() == Phase::Steady => {
+                let next = life.check_stall(tokio::time::Instant::now());
+                stall_check.as_mut().reset(next);
+            }
+            // Churn: this persona only stays for a while
+            _ = &mut session_end, if persona.session_ms > 0 => {
+                break CloseReason::SessionEnd;
+            }
+            // Test end: close explicitly so the server frees the slot right away
+            _ = shutdown.changed() => {
+                break CloseReason::Shutdown;
             }
         }
+    };
+
+    if let Some(mut assembler) = fec {
+        assembler.finish();
+        metrics.record_fec(&assembler.stats);
+    }
+
+    // No-op if the connection already failed; otherwise tells the server we
+    // are leaving instead of letting it discover that at idle timeout.
+    leave(&conn, metrics);
+
+    if let Some(r) = recorder {
+        r.record(user, EventKind::Disconnect, &[]);
     }
+    life.close(reason)
+}
 
-    metrics.active.add(usize::MAX); // Subtract 1 (wrapping) to indicate disconnection
+/// Time between STATS_REQUESTs with `--live-stats`: the server's limit plus
+/// some slack, so jitter never lands a request just inside it.
+const STATS_REQUEST_EVERY: Duration = Duration::from_millis(STATS_MIN_INTERVAL_MS + 250);
+
+/// Archives every broadcast received on one connection until it drops or the
+/// run shuts down. Returns the log so the caller can flush it.
+async fn run_observer(
+    endpoint: Endpoint,
+    addr: std::net::SocketAddr,
+    metrics: Arc<metrics::LoadMetrics>,
+    log: ObserverLog,
+    mut verifier: Option<RleVerifier>,
+    live_stats: bool,
+    mut shutdown: watch::Receiver<bool>,
+) -> ObserverLog {
+    let Some(conn) = connect(&endpoint, addr, &metrics).await else {
+        return log;
+    };
+    if verifier.is_some() {
+        match exchange_version(&conn, Features::FEC).await {
+            Ok(features) if features.contains(Features::FEC) => {}
+            Ok(_) => {
+                eprintln!("Observer: server does not offer FEC; full snapshots go unchecked");
+                verifier = None;
+            }
+            Err(e) => {
+                eprintln!("Observer: {}; full snapshots go unchecked", e);
+                verifier = None;
+            }
+        }
+    }
+    let stats_tick = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(stats_tick);
+    loop {
+        tokio::select! {
+            res = conn.read_datagram() => match res {
+                // Replies to our own requests are not broadcasts; keep them
+                // out of the archive.
+                Ok(dgram) => match stats::decode_stats(&dgram) {
+                    Some(live) => metrics.record_live_stats(&live),
+                    None => {
+                        if let Some(verifier) = verifier.as_mut() {
+                            verifier.push(&dgram, &metrics);
+                        }
+                        log.record(dgram);
+                    }
+                },
+                Err(e) => {
+                    observe_close(&e, &metrics);
+                    break;
+                }
+            },
+            _ = &mut stats_tick, if live_stats => {
+                let _ = conn.send_datagram(Bytes::from_static(&STATS_TAG));
+                stats_tick.as_mut().reset(tokio::time::Instant::now() + STATS_REQUEST_EVERY);
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+    leave(&conn, &metrics);
+    log
+}
+
+async fn run_replay(
+    endpoints: Vec<Endpoint>,
+    metrics: Arc<[Arc<metrics::LoadMetrics>]>,
+    args: Args,
+) {
+    let path = args.trace.as_ref().unwrap();
+    let events = trace::read_trace(path)
+        .unwrap_or_else(|e| panic!("Failed to read trace {}: {}", path.display(), e));
+    let users = replay::group_by_user(events);
+
+    println!(
+        "Replaying {} users from {} at {}x speed...",
+        users.len(),
+        path.display(),
+        args.speed
+    );
+
+    let start = tokio::time::Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, (user, timeline)) in users.into_iter().enumerate() {
+        let ep = endpoints[i % endpoints.len()].clone();
+        // Traces don't store targets; the same --target list reproduces the
+        // recorded split because assignment only depends on the user id.
+        let target = targets::assign(&args.targets, user as usize);
+        let addr = args.targets[target].addr;
+        let m = metrics[target].clone();
+        m.users.add(1);
+        let speed = args.speed;
+        tasks.spawn(async move {
+            let report = replay::replay_user(&timeline, start, speed, || {
+                let (ep, m) = (ep.clone(), m.clone());
+                async move { connect(&ep, addr, &m).await }
+            })
+            .await;
+            m.tx_pixels.add(report.sent_datagrams);
+            report
+        });
+    }
+
+    let mut report = replay::ReplayReport::default();
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(user_report) = res {
+            report.merge(&user_report);
+        }
+    }
+    drain_endpoints(&endpoints).await;
+    println!("Replay finished: {:#?}", report);
+    metrics::write_summary(&args.id, &args.metrics_dir, None, None, None, &metrics);
+}
+
+/// Largest STATS_TIMELINE reply accepted: a status byte, the count, and a
+/// week of minutes.
+const TIMELINE_REPLY_LIMIT: usize = 1 + 4 + 7 * 24 * 60 * control::TIMELINE_ENTRY_LEN;
+
+async fn fetch_timeline(conn: &quinn::Connection) -> Result<Vec<control::TimelineEntry>, String> {
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(&[control::ControlOp::StatsTimeline as u8])
+        .await
+        .map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let reply = recv
+        .read_to_end(TIMELINE_REPLY_LIMIT)
+        .await
+        .map_err(|e| e.to_string())?;
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok)) => control::decode_timeline(&reply[1..]),
+        Some(status) => Err(format!("server refused STATS_TIMELINE: {:?}", status)),
+        None => Err("empty STATS_TIMELINE reply".to_string()),
+    }
+}
+
+/// Optional protocol features this client always offers; ZSTD only with
+/// `--zstd-diffs`.
+const CLIENT_FEATURES: Features = Features::SUBSCRIPTIONS;
+
+/// One batch datagram of `n` pixels: the persona's fixed pixel `n` times,
+/// or `n` random ones.
+fn pixel_batch(persona: &Persona, fixed: &[u8; PIXEL_LEN], n: usize, rng: &mut impl Rng) -> Bytes {
+    let records: Vec<[u8; PIXEL_LEN]> = (0..n)
+        .map(|_| match persona.pattern {
+            Pattern::Fixed => *fixed,
+            Pattern::Random => persona.pixel(rng),
+        })
+        .collect();
+    let mut wire = Vec::with_capacity(batch::batch_len(n));
+    batch::encode_batch(&records, &mut wire);
+    Bytes::from(wire)
+}
+
+/// Paints a tracer every `--tracer-interval-ms` on a connection of its own,
+/// so the cooldown never gets in the way (see tiers.rs).
+async fn run_tracer_writer(
+    endpoint: Endpoint,
+    addr: std::net::SocketAddr,
+    metrics: Arc<metrics::LoadMetrics>,
+    board: Arc<TracerBoard>,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = shutdown.changed() => return,
+        }
+        let Some(conn) = connect(&endpoint, addr, &metrics).await else {
+            continue;
+        };
+        let payload = board.next_payload();
+        if conn.send_datagram(Bytes::copy_from_slice(&payload)).is_ok() {
+            board.sent(&payload, &metrics);
+        }
+        // Closing right away could discard the datagram before it is sent.
+        sleep(Duration::from_millis(50)).await;
+        leave(&conn, &metrics);
+    }
+}
+
+/// Watches broadcasts for tracers as a `tier` observer; tier 1 first
+/// presents `token`. Gives up if the server does not grant the tier.
+async fn run_tier_observer(
+    endpoint: Endpoint,
+    addr: std::net::SocketAddr,
+    metrics: Arc<metrics::LoadMetrics>,
+    board: Arc<TracerBoard>,
+    tier: usize,
+    token: String,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let Some(conn) = connect(&endpoint, addr, &metrics).await else {
+        return;
+    };
+    if tier > 0 {
+        match request_tier(&conn, token.as_bytes()).await {
+            Ok(granted) if granted as usize == tier => {}
+            Ok(granted) => {
+                eprintln!("Tier observer: asked for tier {}, got {}", tier, granted);
+                leave(&conn, &metrics);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Tier observer: {}", e);
+                leave(&conn, &metrics);
+                return;
+            }
+        }
+    }
+    loop {
+        tokio::select! {
+            res = conn.read_datagram() => match res {
+                Ok(dgram) => board.observe(tier, &dgram, &metrics),
+                Err(e) => {
+                    observe_close(&e, &metrics);
+                    break;
+                }
+            },
+            _ = shutdown.changed() => break,
+        }
+    }
+    leave(&conn, &metrics);
+}
+
+fn client_build_info(features: Features) -> BuildInfo {
+    let git = option_env!("CANVAS_GIT_HASH").unwrap_or("unknown");
+    let build = format!("client {}+{}", env!("CARGO_PKG_VERSION"), git);
+    BuildInfo::current(features, &build)
+}
+
+/// VERSION handshake offering `features`: the features both sides support.
+/// A server that predates the handshake answers UNKNOWN_OP and gets
+/// `Features::LEGACY`.
+async fn exchange_version(
+    conn: &quinn::Connection,
+    features: Features,
+) -> Result<Features, String> {
+    let ours = client_build_info(features);
+    let mut request = Vec::new();
+    version::encode_version_request(&ours, &mut request);
+
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(&request).await.map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let reply = recv
+        .read_to_end(1 + version::VERSION_FIXED_LEN + version::MAX_BUILD_LEN)
+        .await
+        .map_err(|e| e.to_string())?;
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok | control::ControlStatus::VersionMismatch)) => {
+            let server = version::decode_version(&reply[1..])?;
+            version::negotiate(&ours, &server).map_err(|m| {
+                format!(
+                    "protocol mismatch: client {}.{}, server {}.{} ({})",
+                    m.ours, ours.minor, m.theirs, server.minor, server.build
+                )
+            })
+        }
+        Some(Some(control::ControlStatus::UnknownOp)) => Ok(Features::LEGACY & features),
+        Some(status) => Err(format!("server refused VERSION: {:?}", status)),
+        None => Err("empty VERSION reply".to_string()),
+    }
+}
+
+/// Sends SUBSCRIBE for the union of `viewports` as a tile bitmap.
+async fn subscribe(
+    conn: &quinn::Connection,
+    viewports: &[control::PixelRect],
+) -> Result<(), String> {
+    let mut bitmap = [0u8; control::SUBSCRIBE_BITMAP_LEN];
+    for rect in viewports {
+        rect.add_tiles(&mut bitmap);
+    }
+    let mut request = Vec::new();
+    control::encode_subscribe(&control::Subscription::Tiles(bitmap), &mut request);
+
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(&request).await.map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let reply = recv.read_to_end(1).await.map_err(|e| e.to_string())?;
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok)) => Ok(()),
+        Some(status) => Err(format!("server refused SUBSCRIBE: {:?}", status)),
+        None => Err("empty SUBSCRIBE reply".to_string()),
+    }
+}
+
+/// Sends TIER with `token`: the tier granted.
+async fn request_tier(conn: &quinn::Connection, token: &[u8]) -> Result<u8, String> {
+    let mut request = Vec::new();
+    control::encode_tier_request(token, &mut request);
+
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(&request).await.map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let reply = recv.read_to_end(2).await.map_err(|e| e.to_string())?;
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok)) if reply.len() == 2 => Ok(reply[1]),
+        Some(status) => Err(format!("server refused TIER: {:?}", status)),
+        None => Err("empty TIER reply".to_string()),
+    }
+}
+
+/// Largest CONFIG reply accepted.
+const CONFIG_REPLY_LIMIT: usize = 1 << 20;
+
+async fn fetch_config(conn: &quinn::Connection) -> Result<String, String> {
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(&[control::ControlOp::Config as u8])
+        .await
+        .map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let reply = recv
+        .read_to_end(CONFIG_REPLY_LIMIT)
+        .await
+        .map_err(|e| e.to_string())?;
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok)) => {
+            String::from_utf8(reply[1..].to_vec()).map_err(|e| e.to_string())
+        }
+        Some(status) => Err(format!("server refused CONFIG: {:?}", status)),
+        None => Err("empty CONFIG reply".to_string()),
+    }
+}
+
+/// Resolves `--respect-cooldown server` from the first target's CONFIG.
+async fn resolve_cooldown(endpoint: &Endpoint, metrics: &metrics::LoadMetrics, args: &Args) -> u64 {
+    let addr = args.targets[0].addr;
+    let Some(conn) = connect(endpoint, addr, metrics).await else {
+        panic!("--respect-cooldown server: cannot connect to {}", addr);
+    };
+    let secs = fetch_config(&conn)
+        .await
+        .and_then(|json| {
+            pacing::cooldown_from_config(&json).ok_or("no cooldown in CONFIG".to_string())
+        })
+        .unwrap_or_else(|e| panic!("--respect-cooldown server: {}: {}", addr, e));
+    leave(&conn, metrics);
+    secs
+}
+
+/// `--mode train-dict`: trains on the raw diffs in the observer logs, then
+/// reports how the dictionary does on the diffs it was not trained on.
+fn run_train_dict(args: &Args) {
+    let samples = diff_dict::collect_samples(&args.observer_dir).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read observer logs in {}: {}",
+            args.observer_dir.display(),
+            e
+        )
+    });
+    println!(
+        "{} diff datagrams from {} observer logs ({} other datagrams skipped)",
+        samples.diffs.len(),
+        samples.logs,
+        samples.skipped
+    );
+    let version = args
+        .dict_version
+        .unwrap_or(protocol::dict::DIFF_DICTS[protocol::dict::DIFF_DICTS.len() - 1].0 + 1);
+    let (dict, wire) = diff_dict::train(&samples.diffs, args.dict_size, version)
+        .unwrap_or_else(|e| panic!("Training failed (too few diffs?): {}", e));
+    std::fs::write(&args.dict_out, &dict)
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", args.dict_out.display(), e));
+    println!(
+        "Dictionary v{} ({} bytes, id {:#x}) written to {}",
+        version,
+        dict.len(),
+        protocol::dict::dict_id(version),
+        args.dict_out.display()
+    );
+    let ratio = |bytes: usize| wire.raw as f64 / bytes.max(1) as f64;
+    println!(
+        "Held-out diffs: {} bytes raw, {} packed with plain zstd ({:.2}x), {} packed with the dictionary ({:.2}x)",
+        wire.raw,
+        wire.plain,
+        ratio(wire.plain),
+        wire.dict,
+        ratio(wire.dict)
+    );
+}
+
+async fn run_soak(args: &Args) -> usize {
+    let config = match &args.soak {
+        Some(path) => soak::SoakConfig::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => soak::SoakConfig::default(),
+    };
+    soak::run(&config, std::path::Path::new(&args.metrics_dir)).await
+}
+
+async fn run_config(endpoint: Endpoint, metrics: &metrics::LoadMetrics, args: &Args) {
+    let addr = args.targets[0].addr;
+    let Some(conn) = connect(&endpoint, addr, metrics).await else {
+        eprintln!("Failed to connect to {}", addr);
+        return;
+    };
+    match fetch_config(&conn).await {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Config query to {} failed: {}", addr, e),
+    }
+    leave(&conn, metrics);
+    drain_endpoints(&[endpoint]).await;
+}
+
+async fn run_timeline(endpoint: Endpoint, metrics: &metrics::LoadMetrics, args: &Args) {
+    let addr = args.targets[0].addr;
+    let Some(conn) = connect(&endpoint, addr, metrics).await else {
+        eprintln!("Failed to connect to {}", addr);
+        return;
+    };
+    match fetch_timeline(&conn).await {
+        Ok(entries) => {
+            println!(
+                "{:>10} {:>8} {:>12} {:>14}",
+                "minute", "writes", "unique_tiles", "active_writers"
+            );
+            for e in entries {
+                println!(
+                    "{:>10} {:>8} {:>12} {:>14}",
+                    e.minute, e.writes, e.unique_tiles, e.active_writers
+                );
+            }
+        }
+        Err(e) => eprintln!("Timeline query to {} failed: {}", addr, e),
+    }
+    leave(&conn, metrics);
+    drain_endpoints(&[endpoint]).await;
+}
+
+/// Largest CATCHUP reply accepted: every pixel of a million-pixel canvas.
+const CATCHUP_REPLY_LIMIT: usize = 1 + 12 + (1 << 20) * control::CATCHUP_ENTRY_LEN;
+
+/// Largest TILE_REFRESH reply accepted: every tile, incompressible.
+const TILE_REFRESH_REPLY_LIMIT: usize = 1
+    + control::TILE_REFRESH_HEADER_LEN
+    + protocol::rle::rle_max_len(control::TILE_CHECKSUM_COUNT * 64 * 64);
+
+/// Sends one control request and returns the reply payload, or
+/// `Ok(None)` when the server answered RESYNC_REQUIRED.
+async fn control_request(
+    conn: &quinn::Connection,
+    request: &[u8],
+    reply_limit: usize,
+) -> Result<Option<Vec<u8>>, String> {
+    let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+    send.write_all(request).await.map_err(|e| e.to_string())?;
+    send.finish().await.map_err(|e| e.to_string())?;
+    let mut reply = recv
+        .read_to_end(reply_limit)
+        .await
+        .map_err(|e| e.to_string())?;
+    let op = control::ControlOp::from_u8(request[0]);
+    match reply.first().copied().map(control::ControlStatus::from_u8) {
+        Some(Some(control::ControlStatus::Ok)) => {
+            reply.remove(0);
+            Ok(Some(reply))
+        }
+        Some(Some(control::ControlStatus::ResyncRequired)) => Ok(None),
+        Some(status) => Err(format!("server refused {:?}: {:?}", op, status)),
+        None => Err(format!("empty {:?} reply", op)),
+    }
+}
+
+async fn fetch_cooldown_config(
+    conn: &quinn::Connection,
+) -> Result<control::CooldownConfig, String> {
+    let request = [control::ControlOp::CooldownConfig as u8];
+    let reply = control_request(conn, &request, 1 + control::COOLDOWN_CONFIG_LEN)
+        .await?
+        .ok_or("COOLDOWN_CONFIG answered RESYNC_REQUIRED")?;
+    control::decode_cooldown_config(&reply)
+}
+
+async fn fetch_tile_refresh(
+    conn: &quinn::Connection,
+    seq: u64,
+    tiles: &reconstruct::TileBitmap,
+) -> Result<Option<control::TileRefresh>, String> {
+    let mut request = Vec::new();
+    control::encode_tile_refresh_request(seq, tiles, &mut request);
+    match control_request(conn, &request, TILE_REFRESH_REPLY_LIMIT).await? {
+        Some(reply) => control::decode_tile_refresh(&reply).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// `--mode verify-tiles`: see reconstruct.rs. Prints one line per check.
+async fn run_verify_tiles(endpoint: Endpoint, metrics: &metrics::LoadMetrics, args: &Args) {
+    let addr = args.targets[0].addr;
+    let Some(conn) = connect(&endpoint, addr, metrics).await else {
+        eprintln!("Failed to connect to {}", addr);
+        return;
+    };
+    let tracked = reconstruct::tracked_tiles(&args.viewports);
+    let result = async {
+        let refresh = fetch_tile_refresh(&conn, control::NEWEST_SNAPSHOT, &tracked)
+            .await?
+            .ok_or("no snapshot to start from")?;
+        let mut mirror = reconstruct::TileMirror::new(&tracked, &refresh);
+        println!(
+            "Tracking {} tiles from snapshot {}",
+            reconstruct::tiles(mirror.tracked()).count(),
+            mirror.seq()
+        );
+        let (mut checked, mut refreshed) = (0u64, 0u64);
+        for _ in 0..args.verify_rounds {
+            sleep(Duration::from_millis(args.verify_interval_ms)).await;
+
+            let mut request = Vec::new();
+            control::encode_catchup_request(mirror.seq(), &mut request);
+            match control_request(&conn, &request, CATCHUP_REPLY_LIMIT).await? {
+                Some(reply) => mirror.apply_catchup(&control::decode_catchup(&reply)?),
+                None => {
+                    // Fell out of the server's history: start over.
+                    let refresh = fetch_tile_refresh(&conn, control::NEWEST_SNAPSHOT, &tracked)
+                        .await?
+                        .ok_or("no snapshot to start over from")?;
+                    mirror = reconstruct::TileMirror::new(&tracked, &refresh);
+                    println!("Resynced at snapshot {}", mirror.seq());
+                    continue;
+                }
+            }
+
+            request.clear();
+            control::encode_tile_checksums_request(mirror.seq(), &mut request);
+            let Some(reply) =
+                control_request(&conn, &request, 1 + 8 + 4 * control::TILE_CHECKSUM_COUNT).await?
+            else {
+                println!(
+                    "Snapshot {} recycled before its checksums were read",
+                    mirror.seq()
+                );
+                continue;
+            };
+            let bad = mirror.mismatches(&control::decode_tile_checksums(&reply)?)?;
+            let bad_tiles: Vec<usize> = reconstruct::tiles(&bad).collect();
+            checked += 1;
+            if bad_tiles.is_empty() {
+                println!("Snapshot {}: all tracked tiles match", mirror.seq());
+                continue;
+            }
+            println!(
+                "Snapshot {}: tiles {:?} do not match",
+                mirror.seq(),
+                bad_tiles
+            );
+            match fetch_tile_refresh(&conn, mirror.seq(), &bad).await? {
+                Some(refresh) => {
+                    mirror.apply_refresh(&refresh);
+                    refreshed += bad_tiles.len() as u64;
+                }
+                None => println!("Snapshot {} recycled before the refresh", mirror.seq()),
+            }
+        }
+        println!("{} checks, {} tiles refreshed", checked, refreshed);
+        Ok::<(), String>(())
+    }
+    .await;
+    if let Err(e) = result {
+        eprintln!("Tile verification against {} failed: {}", addr, e);
+    }
+    leave(&conn, metrics);
+    drain_endpoints(&[endpoint]).await;
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if args.mode == Mode::TrainDict {
+        run_train_dict(&args);
+        return;
+    }
+    if args.mode == Mode::Soak {
+        let anomalies = run_soak(&args).await;
+        std::process::exit(if anomalies > 0 { 1 } else { 0 });
+    }
+    if args.targets.is_empty() || args.id.is_empty() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--target and --id are required outside train-dict and soak modes",
+            )
+            .exit();
+    }
+    if args.pixels_per_dgram > 1 && (args.pixel_tokens || args.pixel_nonces) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--pixels-per-dgram above 1 cannot carry --pixel-tokens or --pixel-nonces",
+            )
+            .exit();
+    }
     let config = tls::build_optimized_config();
 
     // Use a pool of endpoints to rotate source ports.
@@ -151,31 +1408,1136 @@ async fn main() {
         endpoints.push(endpoint);
     }
 
-    let metrics = metrics::LoadMetrics::new(args.id.clone());
-    metrics::spawn_csv_exporter(metrics.clone(), args.id.clone(), args.metrics_dir.clone());
+    let metrics: Arc<[Arc<metrics::LoadMetrics>]> = args
+        .targets
+        .iter()
+        .map(|t| metrics::LoadMetrics::new(args.id.clone(), t.clone()))
+        .collect();
+    let endpoint_stats: Arc<[EndpointStats]> = endpoints
+        .iter()
+        .map(|ep| EndpointStats::new(ep.local_addr().map_or(0, |a| a.port())))
+        .collect();
+    metrics::spawn_csv_exporter(
+        metrics.to_vec(),
+        endpoint_stats.clone(),
+        args.id.clone(),
+        args.metrics_dir.clone(),
+        args.legacy_csv,
+    );
 
+    match args.mode {
+        Mode::Replay => {
+            run_replay(endpoints, metrics, args).await;
+            return;
+        }
+        Mode::Timeline => {
+            run_timeline(endpoints[0].clone(), &metrics[0], &args).await;
+            return;
+        }
+        Mode::Config => {
+            run_config(endpoints[0].clone(), &metrics[0], &args).await;
+            return;
+        }
+        Mode::VerifyTiles => {
+            run_verify_tiles(endpoints[0].clone(), &metrics[0], &args).await;
+            return;
+        }
+        Mode::TrainDict | Mode::Soak => unreachable!("{:?} returns before connecting", args.mode),
+        Mode::Load => {}
+    }
+
+    if args.respect_cooldown == Some(CooldownSpec::Server) {
+        let secs = resolve_cooldown(&endpoints[0], &metrics[0], &args).await;
+        args.respect_cooldown = Some(CooldownSpec::Secs(secs));
+    }
+    if let Some(secs) = args.cooldown_secs() {
+        println!(
+            "Pacing writers to a {} s cooldown, {}% of users defiant",
+            secs, args.defiance
+        );
+    }
+
+    let recorder = args.record.as_ref().map(|path| {
+        TraceRecorder::create(path)
+            .unwrap_or_else(|e| panic!("Failed to create trace {}: {}", path.display(), e))
+    });
+
+    let Some(clients) = args.clients else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--clients is required in load mode",
+            )
+            .exit();
+    };
+    let scenario = match &args.scenario {
+        Some(path) => Scenario::load(path, &args.base_persona()).unwrap_or_else(|e| {
+            Args::command()
+                .error(clap::error::ErrorKind::ValueValidation, e)
+                .exit()
+        }),
+        None => Scenario::single(args.base_persona()),
+    };
     println!(
-        "Starting worker {} ramping up {} clients using {} source ports...",
-        args.id, args.clients, num_endpoints
+        "Starting worker {} ramping up {} clients across {} target(s) using {} source ports...",
+        args.id,
+        clients,
+        args.targets.len(),
+        num_endpoints
     );
+    for (persona, count) in scenario.personas.iter().zip(scenario.allocate(clients)) {
+        println!(
+            "  persona {}: {} users ({:?})",
+            persona.name, count, persona.role
+        );
+    }
+
+    let seed = args.seed.or(scenario.seed).unwrap_or_else(rand::random);
+    println!("  seed {} (--seed to replay)", seed);
 
-    for i in 0..args.clients {
+    let scenario_hash = scenario.hash();
+    let assignments = scenario.assignments(clients);
+    let scenario = Arc::new(scenario);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    if args.verify_rle && args.observers == 0 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--verify-rle checks what observers receive; add --observers",
+            )
+            .exit();
+    }
+    let mut observers = tokio::task::JoinSet::new();
+    for n in 0..args.observers {
+        let path = args
+            .observer_dir
+            .join(format!("observer_{}_{}.bin", args.id, n));
+        let log = ObserverLog::create(&path, observer::OBSERVER_QUEUE_LEN)
+            .unwrap_or_else(|e| panic!("Failed to create {}: {}", path.display(), e));
+        let target = n % args.targets.len();
+        observers.spawn(run_observer(
+            endpoints[n % num_endpoints].clone(),
+            args.targets[target].addr,
+            metrics[target].clone(),
+            log,
+            args.verify_rle
+                .then(|| RleVerifier::new(args.observer_dir.clone(), format!("{}_{}", args.id, n))),
+            args.live_stats,
+            shutdown_rx.clone(),
+        ));
+    }
+
+    if let Some(token) = &args.tier_token {
+        if token.is_empty() || token.len() > control::MAX_TIER_TOKEN_LEN {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "--tier-token must be 1 to {} bytes",
+                        control::MAX_TIER_TOKEN_LEN
+                    ),
+                )
+                .exit();
+        }
+        let board = Arc::new(TracerBoard::default());
+        for tier in 0..tiers::TIERS {
+            tokio::spawn(run_tier_observer(
+                endpoints[tier % num_endpoints].clone(),
+                args.targets[0].addr,
+                metrics[0].clone(),
+                board.clone(),
+                tier,
+                token.clone(),
+                shutdown_rx.clone(),
+            ));
+        }
+        tokio::spawn(run_tracer_writer(
+            endpoints[0].clone(),
+            args.targets[0].addr,
+            metrics[0].clone(),
+            board,
+            Duration::from_millis(args.tracer_interval_ms),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    for (i, persona) in assignments.into_iter().enumerate() {
         let ep = endpoints[i % num_endpoints].clone();
+        let es = endpoint_stats.clone();
         let m = metrics.clone();
         let a = args.clone();
+        let r = recorder.clone();
+        let s = scenario.clone();
+        let mut shutdown = shutdown_rx.clone();
 
         tokio::spawn(async move {
-            let jitter = if a.max_conn_jitter == 0 {
-                0
-            } else {
-                rand::thread_rng().gen_range(0..a.max_conn_jitter)
+            let persona = &s.personas[persona];
+            m[targets::assign(&a.targets, i)].users.add(1);
+            let mut rng = user_rng(seed, i as u32);
+            let mut nonce = 0;
+
+            // Churning personas arrive again after each session.
+            let mut reconnect = Reconnect::default();
+            let mut backoff = Duration::ZERO;
+            loop {
+                let jitter = Duration::from_millis(persona.connect_delay(&mut rng));
+                if !(jitter + backoff).is_zero() {
+                    tokio::select! {
+                        _ = sleep(jitter + backoff) => {}
+                        _ = shutdown.changed() => return,
+                    }
+                }
+                if *shutdown.borrow() {
+                    return;
+                }
+                let stats = &es[i % num_endpoints];
+                let reason = simulate_user(
+                    i as u32,
+                    persona,
+                    &mut rng,
+                    &mut nonce,
+                    &ep,
+                    stats,
+                    &m,
+                    &a,
+                    r.as_ref(),
+                    &mut shutdown,
+                )
+                .await;
+                match reconnect.after(reason, persona.session_ms > 0) {
+                    Some(wait) => backoff = wait,
+                    None => return,
+                }
+            }
+        });
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+    let active: usize = metrics.iter().map(|m| m.active()).sum();
+    println!("Shutting down, closing {} connections...", active);
+    let _ = shutdown_tx.send(true);
+    let mut n = 0;
+    while let Some(Ok(log)) = observers.join_next().await {
+        let stats = log.stats.clone();
+        log.finish();
+        println!(
+            "Observer {}: {} broadcasts archived, {} dropped (disk too slow)",
+            n,
+            stats.received.load(Ordering::Relaxed) - stats.dropped.load(Ordering::Relaxed),
+            stats.dropped.load(Ordering::Relaxed)
+        );
+        n += 1;
+    }
+    drain_endpoints(&endpoints).await;
+    metrics::write_summary(
+        &args.id,
+        &args.metrics_dir,
+        Some(scenario_hash),
+        Some(seed),
+        args.cooldown_secs(),
+        &metrics,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::datagram::{self, FULL_RLE};
+    use protocol::epoch;
+    use protocol::nack;
+
+    fn loopback_server() -> Endpoint {
+        loopback_server_with(|_| {})
+    }
+
+    fn loopback_server_with(tune: impl FnOnce(&mut quinn::ServerConfig)) -> Endpoint {
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(loopback_crypto()));
+        tune(&mut config);
+        Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn loopback_crypto() -> rustls::ServerConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.cert.der().to_vec())],
+                rustls::PrivateKey(cert.key_pair.serialize_der()),
+            )
+            .unwrap();
+        crypto.alpn_protocols = vec![protocol::webtransport::RAW_ALPN.to_vec()];
+        crypto
+    }
+
+    #[test]
+    fn test_default_scenario_matches_cli_defaults() {
+        let args = Args::parse_from(["client", "--target", "127.0.0.1:4433", "--id", "0"]);
+        let file = Scenario::parse(
+            include_str!("../scenarios/default.toml"),
+            &args.base_persona(),
+        )
+        .unwrap();
+        let flags = Scenario::single(args.base_persona());
+        assert_eq!(file, flags);
+        assert_eq!(file.hash(), flags.hash());
+    }
+
+    #[tokio::test]
+    async fn test_close_codes_are_counted_by_reason() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        // Every registered code, then one this client has never heard of.
+        let codes = AppCloseCode::ALL.map(Some).into_iter().chain([None]);
+        for code in codes {
+            let accept = async { server.accept().await.unwrap().await.unwrap() };
+            let (server_conn, client_conn) = tokio::join!(accept, connect(&client, addr, &metrics));
+            match code {
+                Some(code) => close_with(&server_conn, code),
+                None => server_conn.close(999u32.into(), b"???"),
+            }
+            let err = client_conn.unwrap().read_datagram().await.unwrap_err();
+            observe_close(&err, &metrics);
+        }
+
+        for code in AppCloseCode::ALL {
+            assert_eq!(metrics.closes[code as usize].get(), 1, "{:?}", code);
+        }
+        assert_eq!(metrics.closes_unknown.get(), 1);
+        assert!(
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics))
+                .contains("\"closes\":{\"client_exit\":1,")
+        );
+    }
+
+    #[test]
+    fn test_first_broadcast_histogram_in_summary() {
+        let addr = "127.0.0.1:4433".parse().unwrap();
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+        for ms in [3, 10, 40, 7_000, 60_000] {
+            metrics.first_broadcast_ms.record(Duration::from_millis(ms));
+        }
+        let summary =
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics));
+        assert!(summary.contains(
+            "\"first_broadcast_ms\":{\"le\":[10,25,50,100,250,500,1000,2500,5000,10000],\"cumulative\":[2,2,3,3,3,3,3,3,3,4,5],\"sum_ms\":67053,\"count\":5}"
+        ));
+    }
+
+    #[test]
+    fn test_bucket_quantile() {
+        let mut counts = [0; metrics::FIRST_BROADCAST_BUCKETS_MS.len() + 1];
+        assert_eq!(metrics::bucket_quantile(&counts, 0.99), None);
+        counts[0] = 99;
+        counts[3] = 1;
+        assert_eq!(metrics::bucket_quantile(&counts, 0.99), Some(10));
+        counts[5] = 1;
+        assert_eq!(metrics::bucket_quantile(&counts, 0.99), Some(100));
+        // Past the last bound, the last bound is all there is to say.
+        counts[10] = 5;
+        assert_eq!(metrics::bucket_quantile(&counts, 0.99), Some(10000));
+    }
+
+    #[test]
+    fn test_live_stats_in_summary() {
+        let addr = "127.0.0.1:4433".parse().unwrap();
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+        let summary =
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics));
+        assert!(summary.contains("\"live_stats\":{\"replies\":0,\"online\":null,"));
+
+        for (online, next_full_ms) in [(10, 500), (12, stats::NO_FULL_SCHEDULED)] {
+            let live = stats::LiveStats {
+                online,
+                pixels_per_sec: 90,
+                cooldown_secs: 300,
+                broadcast_interval_ms: 100,
+                next_full_ms,
+                snapshot: 7,
             };
-            if jitter > 0 {
-                sleep(Duration::from_millis(jitter)).await;
+            let reply = stats::encode_stats(&live);
+            metrics.record_live_stats(&stats::decode_stats(&reply).unwrap());
+        }
+        let summary =
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics));
+        assert!(summary.contains(
+            "\"live_stats\":{\"replies\":2,\"online\":12,\"pixels_per_sec\":90,\"cooldown_s\":300,\"broadcast_interval_ms\":100,\"next_full_ms\":null}"
+        ));
+    }
+
+    #[test]
+    fn test_parse_viewport() {
+        let rect = parse_viewport("0, 10,64,100").unwrap();
+        assert_eq!(
+            rect,
+            control::PixelRect {
+                x0: 0,
+                y0: 10,
+                x1: 64,
+                y1: 100
             }
-            simulate_user(ep, m, a).await;
+        );
+        assert!(parse_viewport("0,0,0,10").is_err());
+        assert!(parse_viewport("0,0,10").is_err());
+        assert!(parse_viewport("0,0,10,70000").is_err());
+    }
+
+    #[test]
+    fn test_rebind_sampling() {
+        assert!((0..10_000).all(|u| !rebind_sampled(u, 0.0)));
+        assert!((0..10_000).all(|u| rebind_sampled(u, 1.0)));
+        let sampled = (0..10_000).filter(|&u| rebind_sampled(u, 0.25)).count();
+        assert!((2_300..2_700).contains(&sampled), "{}", sampled);
+    }
+
+    #[tokio::test]
+    async fn test_connection_survives_rebind() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        let accept = async { server.accept().await.unwrap().await.unwrap() };
+        let (server_conn, client_conn) = tokio::join!(accept, connect(&client, addr, &metrics));
+        let client_conn = client_conn.unwrap();
+        let before = server_conn.remote_address();
+
+        rebind_endpoint(&client).unwrap();
+        client_conn
+            .send_datagram(Bytes::from_static(b"px"))
+            .unwrap();
+        assert_eq!(&server_conn.read_datagram().await.unwrap()[..], b"px");
+        assert_ne!(server_conn.remote_address(), before);
+
+        server_conn
+            .send_datagram(Bytes::from_static(b"diff"))
+            .unwrap();
+        assert_eq!(&client_conn.read_datagram().await.unwrap()[..], b"diff");
+    }
+
+    #[tokio::test]
+    async fn test_version_exchange() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        let accept = async { server.accept().await.unwrap().await.unwrap() };
+        let (server_conn, client_conn) = tokio::join!(accept, connect(&client, addr, &metrics));
+        let client_conn = client_conn.unwrap();
+
+        // Newer minor with extra features, an older build without VERSION,
+        // then a new major.
+        let replies = [
+            (
+                control::ControlStatus::Ok,
+                2,
+                Features::SUBSCRIPTIONS | Features::ZSTD,
+            ),
+            (control::ControlStatus::UnknownOp, 0, Features::NONE),
+            (
+                control::ControlStatus::VersionMismatch,
+                3,
+                Features::SUBSCRIPTIONS,
+            ),
+        ];
+        let serve = async {
+            for (status, major, features) in replies {
+                let (mut send, mut recv) = server_conn.accept_bi().await.unwrap();
+                let request = recv.read_to_end(256).await.unwrap();
+                let client_info = version::decode_version(&request[1..]).unwrap();
+                assert_eq!(client_info.features, CLIENT_FEATURES);
+                assert!(client_info.build.starts_with("client 0.1.0+"));
+
+                let mut reply = vec![status as u8];
+                if status != control::ControlStatus::UnknownOp {
+                    let info = BuildInfo {
+                        major,
+                        minor: 7,
+                        features,
+                        build: "server test".to_string(),
+                    };
+                    version::encode_version(&info, &mut reply);
+                }
+                send.write_all(&reply).await.unwrap();
+                send.finish().await.unwrap();
+            }
+        };
+        let negotiate = async {
+            let mut results = Vec::new();
+            for _ in 0..replies.len() {
+                results.push(exchange_version(&client_conn, CLIENT_FEATURES).await);
+            }
+            results
+        };
+        let ((), results) = tokio::join!(serve, negotiate);
+
+        assert_eq!(results[0], Ok(Features::SUBSCRIPTIONS));
+        assert_eq!(results[1], Ok(Features::SUBSCRIPTIONS));
+        let err = results[2].clone().unwrap_err();
+        assert!(
+            err.contains("client 2.4, server 3.7 (server test)"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_active_is_connects_minus_disconnects() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        let mut conns = Vec::new();
+        for expected_active in 1..=3 {
+            let accept = async { server.accept().await.unwrap().await.unwrap() };
+            let (server_conn, client_conn) = tokio::join!(accept, connect(&client, addr, &metrics));
+            conns.push((server_conn, client_conn.unwrap()));
+            assert_eq!(metrics.active(), expected_active);
+        }
+
+        // One user leaves, one is dropped by the server.
+        leave(&conns[0].1, &metrics);
+        assert_eq!(metrics.active(), 2);
+        close_with(&conns[1].0, AppCloseCode::Capacity);
+        let err = conns[1].1.read_datagram().await.unwrap_err();
+        observe_close(&err, &metrics);
+        leave(&conns[1].1, &metrics);
+        assert_eq!(metrics.active(), 1);
+
+        // A failed handshake never counts as active.
+        let full = loopback_server_with(|c| {
+            c.concurrent_connections(0);
+        });
+        assert!(
+            connect(&client, full.local_addr().unwrap(), &metrics)
+                .await
+                .is_none()
+        );
+        assert_eq!(metrics.connect_attempts.get(), 4);
+        assert_eq!(metrics.connect_success.get(), 3);
+        assert_eq!(metrics.connect_failed.get(), 1);
+        assert_eq!(metrics.disconnects.get(), 2);
+        assert_eq!(metrics.active(), 1);
+        assert!(
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics)).contains(
+                "\"active\":1,\"connect_attempts\":4,\"connect_success\":3,\"connect_failed\":1,\"disconnects\":2,"
+            )
+        );
+
+        let gauge = metrics::AlignedAtomic::new(2);
+        gauge.sub(1);
+        assert_eq!(gauge.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resumes_in_0rtt() {
+        let mut crypto = loopback_crypto();
+        // QUIC servers take 0-RTT all or nothing.
+        crypto.max_early_data_size = u32::MAX;
+        let server = Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        // The ticket follows the handshake; a datagram sent after it means
+        // the client has it.
+        let accept = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            conn.send_datagram(vec![0].into()).unwrap();
+            conn
+        };
+        let (_server_conn, first) = tokio::join!(accept, connect(&client, addr, &metrics));
+        let first = first.unwrap();
+        first.read_datagram().await.unwrap();
+        leave(&first, &metrics);
+        assert_eq!(metrics.connect_0rtt.get(), 0);
+
+        let accept = async { server.accept().await.unwrap().await.unwrap() };
+        let (_server_conn, second) = tokio::join!(accept, connect(&client, addr, &metrics));
+        assert!(second.is_some());
+        assert_eq!(metrics.connect_success.get(), 2);
+        assert_eq!(metrics.connect_0rtt.get(), 1);
+        assert!(
+            metrics::summary_json("test", None, None, None, std::slice::from_ref(&metrics))
+                .contains("\"disconnects\":1,\"connect_0rtt\":1,")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refused_handshake_counts_as_capacity() {
+        let server = loopback_server_with(|c| {
+            c.concurrent_connections(0);
         });
+        let addr = server.local_addr().unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let metrics = metrics::LoadMetrics::new("test".to_string(), Target { addr, weight: 1 });
+
+        assert!(connect(&client, addr, &metrics).await.is_none());
+        assert_eq!(metrics.connect_failed.get(), 1);
+        assert_eq!(metrics.closes[AppCloseCode::Capacity as usize].get(), 1);
+        assert_eq!(metrics.closes_unknown.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_impaired_user_sends_exact_duplicates() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let args = Args::parse_from([
+            "client",
+            "--target",
+            &addr.to_string(),
+            "--id",
+            "dup",
+            "--pixel-tokens",
+            "--impair",
+            "dup=1",
+            "--min-pixel-wait",
+            "1",
+            "--max-pixel-wait",
+            "2",
+        ]);
+        let persona = args.base_persona();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "dup".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let receive = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let mut seen = std::collections::HashMap::new();
+            for _ in 0..20 {
+                let dgram = conn.read_datagram().await.unwrap();
+                *seen.entry(dgram).or_insert(0) += 1;
+            }
+            stop.send(true).unwrap();
+            seen
+        };
+        let (reason, seen) = tokio::join!(user, receive);
+        assert_eq!(reason, CloseReason::Shutdown);
+
+        // Every pixel arrived twice, token and all; tokens tell pixels apart.
+        assert_eq!(seen.len(), 10);
+        assert!(seen.iter().all(|(dgram, &n)| dgram.len() == 10 && n == 2));
+        let m = &metrics[0];
+        assert_eq!(m.tx_duplicates.get(), m.tx_pixels.get());
+        assert!(
+            metrics::summary_json("dup", None, None, None, &metrics)
+                .contains(&format!("\"tx_duplicates\":{},", m.tx_pixels.get()))
+        );
+    }
+
+    /// Pixels a random-pattern writer seeded with `seed` sends first.
+    async fn first_pixels(seed: u64, flags: &[&str]) -> Vec<Bytes> {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap();
+        let addr = addr.to_string();
+        let args = Args::parse_from(
+            [
+                "client",
+                "--target",
+                &addr,
+                "--id",
+                "seed",
+                "--pixel-tokens",
+                "--min-pixel-wait",
+                "1",
+                "--max-pixel-wait",
+                "5",
+            ]
+            .iter()
+            .chain(flags),
+        );
+        let persona = Persona {
+            pattern: Pattern::Random,
+            ..args.base_persona()
+        };
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "seed".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(seed, 3);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            3,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let receive = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let mut pixels = Vec::new();
+            for _ in 0..10 {
+                pixels.push(conn.read_datagram().await.unwrap());
+            }
+            stop.send(true).unwrap();
+            pixels
+        };
+        let (_, pixels) = tokio::join!(user, receive);
+        pixels
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_replays_the_send_schedule() {
+        // Positions, colors, tokens and the waits between them come from
+        // one stream per user.
+        let first = first_pixels(42, &[]).await;
+        assert_eq!(first.len(), 10);
+        assert_eq!(first, first_pixels(42, &[]).await);
+        assert_ne!(first, first_pixels(43, &[]).await);
+
+        // Neighbouring users draw differently.
+        let mut a = user_rng(42, 0);
+        let mut b = user_rng(42, 1);
+        assert_ne!(a.r#gen::<u64>(), b.r#gen::<u64>());
+        assert!(
+            metrics::summary_json("seed", Some(1), Some(42), None, &[])
+                .contains("\"scenario\":\"0000000000000001\",\"seed\":42,")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pixel_nonces_count_up() {
+        let pixels = first_pixels(1, &["--pixel-nonces"]).await;
+        let nonces: Vec<u32> = pixels
+            .iter()
+            .map(|p| {
+                assert_eq!(p.len(), 14);
+                pixel::trailer_at(p, 10)
+            })
+            .collect();
+        assert_eq!(nonces, (0..10).collect::<Vec<_>>());
+    }
+
+    /// The first 5 datagrams of a user sending `--pixels-per-dgram 4` to a
+    /// server whose VERSION reply offers `offered`.
+    async fn first_batches(offered: Features) -> (Vec<Bytes>, usize) {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap().to_string();
+        let args = Args::parse_from([
+            "client",
+            "--target",
+            &addr,
+            "--id",
+            "batch",
+            "--pixels-per-dgram",
+            "4",
+            "--min-pixel-wait",
+            "1",
+            "--max-pixel-wait",
+            "2",
+        ]);
+        assert!(args.features().contains(Features::BATCHING));
+        let persona = Persona {
+            pattern: Pattern::Random,
+            ..args.base_persona()
+        };
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "batch".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let receive = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            recv.read_to_end(256).await.unwrap();
+            let mut reply = vec![control::ControlStatus::Ok as u8];
+            version::encode_version(&BuildInfo::current(offered, "server test"), &mut reply);
+            send.write_all(&reply).await.unwrap();
+            send.finish().await.unwrap();
+            let mut dgrams = Vec::new();
+            for _ in 0..5 {
+                dgrams.push(conn.read_datagram().await.unwrap());
+            }
+            stop.send(true).unwrap();
+            dgrams
+        };
+        let (_, dgrams) = tokio::join!(user, receive);
+        (dgrams, metrics[0].tx_pixels.get())
+    }
+
+    #[tokio::test]
+    async fn test_pixels_are_batched_once_the_server_offers_it() {
+        let (dgrams, sent) = first_batches(Features::SUBSCRIPTIONS | Features::BATCHING).await;
+        for dgram in &dgrams {
+            assert_eq!(dgram.len(), batch::batch_len(4));
+            let records: Vec<&[u8]> = batch::decode_batch(dgram).unwrap().collect();
+            // Random pixels: four different placements per batch.
+            assert!(records.windows(2).all(|w| w[0] != w[1]));
+        }
+        assert!(sent >= 4 * 5);
+
+        // A server without batching gets single pixels.
+        let (dgrams, _) = first_batches(Features::SUBSCRIPTIONS).await;
+        assert!(dgrams.iter().all(|d| d.len() == 6));
     }
 
-    std::future::pending::<()>().await;
+    #[tokio::test]
+    async fn test_paced_user_follows_a_contract_tightened_mid_session() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap().to_string();
+        let args = Args::parse_from([
+            "client",
+            "--target",
+            &addr,
+            "--id",
+            "contract",
+            "--respect-cooldown",
+            "1",
+            "--min-pixel-wait",
+            "1",
+            "--max-pixel-wait",
+            "2",
+        ]);
+        assert!(args.features().contains(Features::CONTRACTS));
+        let persona = args.base_persona();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "contract".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let serve = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            recv.read_to_end(256).await.unwrap();
+            let mut reply = vec![control::ControlStatus::Ok as u8];
+            let offered = Features::SUBSCRIPTIONS | Features::CONTRACTS;
+            version::encode_version(&BuildInfo::current(offered, "server test"), &mut reply);
+            send.write_all(&reply).await.unwrap();
+            send.finish().await.unwrap();
+            // No COOLDOWN_CONFIG: the configured second stands.
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            recv.read_to_end(256).await.unwrap();
+            send.write_all(&[control::ControlStatus::UnknownOp as u8])
+                .await
+                .unwrap();
+            send.finish().await.unwrap();
+
+            let mut sent_at = vec![];
+            conn.read_datagram().await.unwrap();
+            sent_at.push(tokio::time::Instant::now());
+            // Tightened to 2 s after the first pixel.
+            let mut push = Vec::new();
+            let contract = control::RateContract {
+                seq: 2,
+                interval_ms: 2_000,
+                effective_in_ms: 5_000,
+            };
+            control::encode_rate_contract_push(&contract, &mut push);
+            let mut uni = conn.open_uni().await.unwrap();
+            uni.write_all(&push).await.unwrap();
+            uni.finish().await.unwrap();
+            for _ in 0..2 {
+                conn.read_datagram().await.unwrap();
+                sent_at.push(tokio::time::Instant::now());
+            }
+            stop.send(true).unwrap();
+            sent_at
+        };
+        let (_, sent_at) = tokio::join!(user, serve);
+
+        // The cooldown running when it arrived keeps its 1 s; the next one
+        // is 2 s.
+        let first = sent_at[1] - sent_at[0];
+        let second = sent_at[2] - sent_at[1];
+        assert!(first < Duration::from_millis(2_000), "{:?}", first);
+        assert!(second >= Duration::from_millis(2_000), "{:?}", second);
+        assert_eq!(metrics[0].contract_updates.get(), 1);
+        assert_eq!(metrics[0].rejected_pixels.get(), 0);
+    }
+
+    #[test]
+    fn test_fixed_pattern_batches_repeat_its_pixel() {
+        let args = Args::parse_from(["client", "--pixels-per-dgram", "32"]);
+        let persona = args.base_persona();
+        let fixed = [1, 0, 2, 0, 3];
+        let wire = pixel_batch(&persona, &fixed, 32, &mut user_rng(0, 0));
+        let records: Vec<&[u8]> = batch::decode_batch(&wire).unwrap().collect();
+        assert_eq!(records.len(), 32);
+        assert!(records.iter().all(|r| *r == fixed));
+        assert!(Args::try_parse_from(["client", "--pixels-per-dgram", "33"]).is_err());
+        assert!(Args::try_parse_from(["client", "--pixels-per-dgram", "0"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_receipted_pixels_are_numbered_and_acked() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap().to_string();
+        let args = Args::parse_from([
+            "client",
+            "--target",
+            &addr,
+            "--id",
+            "receipts",
+            "--pixel-receipts",
+            "--min-pixel-wait",
+            "1",
+            "--max-pixel-wait",
+            "2",
+        ]);
+        assert!(args.features().contains(Features::RECEIPTS));
+        let persona = args.base_persona();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "receipts".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let m = &metrics[0];
+        let serve = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            recv.read_to_end(256).await.unwrap();
+            let mut reply = vec![control::ControlStatus::Ok as u8];
+            let offered = Features::SUBSCRIPTIONS | Features::RECEIPTS;
+            version::encode_version(&BuildInfo::current(offered, "server test"), &mut reply);
+            send.write_all(&reply).await.unwrap();
+            send.finish().await.unwrap();
+            let mut seqs = Vec::new();
+            for _ in 0..3 {
+                let dgram = conn.read_datagram().await.unwrap();
+                assert_eq!(dgram.len(), receipt::SEQ_DATAGRAM_LEN);
+                let (kind, body) = datagram::split(&dgram).unwrap();
+                assert_eq!(kind, datagram::PIXEL_SEQ);
+                seqs.push(receipt::split_seq(body).unwrap().0);
+            }
+            // The second was dropped, say for cooldown.
+            let mut ack = [0; receipt::MAX_ACK_LEN];
+            for seq in [1, 3] {
+                let ack = receipt::encode_ack(Some(seq), &mut ack);
+                conn.send_datagram(Bytes::copy_from_slice(ack)).unwrap();
+            }
+            while m.acked_pixels.get() < 2 {
+                sleep(Duration::from_millis(5)).await;
+            }
+            stop.send(true).unwrap();
+            seqs
+        };
+        let (reason, seqs) = tokio::join!(user, serve);
+        assert_eq!(reason, CloseReason::Shutdown);
+        assert_eq!(seqs, [1, 2, 3]);
+        assert!(m.receipted_pixels.get() >= 3);
+        assert_eq!(m.receipted_pixels.get(), m.tx_pixels.get());
+    }
+
+    #[tokio::test]
+    async fn test_new_epoch_resyncs() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap().to_string();
+        let args = Args::parse_from(["client", "--target", &addr, "--id", "epochs", "--epochs"]);
+        assert!(args.features().contains(Features::EPOCHS));
+        let persona = args.base_persona();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "epochs".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let m = &metrics[0];
+        let serve = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            recv.read_to_end(256).await.unwrap();
+            let mut reply = vec![control::ControlStatus::Ok as u8];
+            let offered = Features::SUBSCRIPTIONS | Features::EPOCHS;
+            version::encode_version(&BuildInfo::current(offered, "server test"), &mut reply);
+            send.write_all(&reply).await.unwrap();
+            send.finish().await.unwrap();
+            // Two full broadcasts in epoch 0, then one in epoch 1.
+            let full = vec![FULL_RLE, 3, 5, 250, 0];
+            for e in [0, 0, 1] {
+                conn.send_datagram(Bytes::copy_from_slice(&epoch::encode_epoch(e)))
+                    .unwrap();
+                conn.send_datagram(Bytes::from(full.clone())).unwrap();
+            }
+            while m.rx_datagrams.get() < 6 {
+                sleep(Duration::from_millis(5)).await;
+            }
+            stop.send(true).unwrap();
+            conn
+        };
+        let (reason, _conn) = tokio::join!(user, serve);
+        assert_eq!(reason, CloseReason::Shutdown);
+        assert_eq!(m.rx_skipped.get(), 0);
+        assert_eq!(m.lifecycle.epoch_changes.get(), 1);
+        assert_eq!(m.lifecycle.full_syncs.get(), 2);
+        assert_eq!(m.lifecycle.entered(Phase::Syncing), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_broadcasts_are_skipped() {
+        let server = loopback_server();
+        let addr = server.local_addr().unwrap().to_string();
+        let args = Args::parse_from(["client", "--target", &addr, "--id", "skip"]);
+        let persona = args.base_persona();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(tls::build_optimized_config());
+        let stats = EndpointStats::new(client.local_addr().unwrap().port());
+        let metrics = [metrics::LoadMetrics::new(
+            "skip".to_string(),
+            args.targets[0].clone(),
+        )];
+        let (stop, mut shutdown) = watch::channel(false);
+        let mut rng = user_rng(0, 0);
+        let mut nonce = 0;
+
+        let user = simulate_user(
+            0,
+            &persona,
+            &mut rng,
+            &mut nonce,
+            &client,
+            &stats,
+            &metrics,
+            &args,
+            None,
+            &mut shutdown,
+        );
+        let m = &metrics[0];
+        let send = async {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            // A format from a newer server, then STATS, which this
+            // connection never negotiated, a cooldown NACK, which is no
+            // broadcast, then a snapshot chunk.
+            let mut future = Vec::new();
+            broadcast::push_tag(*b"NEW", &mut future);
+            future.extend_from_slice(&[1; 20]);
+            let live = stats::encode_stats(&stats::LiveStats::default());
+            let nack = nack::encode_cooldown_nack(120);
+            for dgram in [
+                future,
+                live.to_vec(),
+                nack.to_vec(),
+                vec![FULL_RLE, 3, 5, 250, 0],
+            ] {
+                conn.send_datagram(Bytes::from(dgram)).unwrap();
+            }
+            while m.rx_datagrams.get() < 4 {
+                sleep(Duration::from_millis(5)).await;
+            }
+            stop.send(true).unwrap();
+            conn
+        };
+        let (reason, _conn) = tokio::join!(user, send);
+        assert_eq!(reason, CloseReason::Shutdown);
+        assert_eq!(m.rx_skipped.get(), 2);
+        assert_eq!(m.live_stats_replies.get(), 0);
+        assert_eq!(m.lifecycle.full_syncs.get(), 1);
+        // Unpaced, the NACK is all there is to count rejections by.
+        assert_eq!(m.cooldown_nacks.get(), 1);
+        assert_eq!(m.rejected_pixels.get(), 1);
+    }
 }
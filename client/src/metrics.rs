@@ -1,6 +1,14 @@
+use crate::endpoints::{self, EndpointStats};
+use crate::lifecycle::{LifecycleMetrics, Phase};
+use crate::targets::Target;
+use crate::tiers::TIERS;
+use protocol::close::AppCloseCode;
+use protocol::fec::FecStats;
+use protocol::stats::{LiveStats, NO_FULL_SCHEDULED};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use tokio::time::{Duration, sleep};
@@ -16,109 +24,1053 @@ impl AlignedAtomic {
     pub fn add(&self, val: usize) {
         self.0.fetch_add(val, Ordering::Relaxed);
     }
+    /// For gauges. Counters that several tasks move both ways are better
+    /// kept as two monotonic counters (see `LoadMetrics::active`): a reader
+    /// then never sees a value that was not true at some point.
+    #[cfg_attr(not(test), allow(dead_code))]
+    #[inline(always)]
+    pub fn sub(&self, val: usize) {
+        self.0.fetch_sub(val, Ordering::Relaxed);
+    }
+    /// For last-seen values, where the newest writer wins.
+    #[inline(always)]
+    pub fn set(&self, val: usize) {
+        self.0.store(val, Ordering::Relaxed);
+    }
     pub fn get(&self) -> usize {
         self.0.load(Ordering::Relaxed)
     }
 }
 
+/// Upper bounds (ms) of the time-to-first-broadcast buckets, the same as the
+/// server's `canvas_worker_first_broadcast_ms` so the two line up.
+pub const FIRST_BROADCAST_BUCKETS_MS: [u64; 10] =
+    [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+pub struct LatencyHistogram {
+    /// One per bound, then everything above the last one.
+    buckets: [AlignedAtomic; FIRST_BROADCAST_BUCKETS_MS.len() + 1],
+    sum_ms: AlignedAtomic,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            sum_ms: AlignedAtomic::new(0),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = FIRST_BROADCAST_BUCKETS_MS.partition_point(|&bound| bound < ms);
+        self.buckets[bucket].add(1);
+        self.sum_ms.add(ms as usize);
+    }
+
+    /// Samples per bucket, the overflow bucket last.
+    fn counts(&self) -> [usize; FIRST_BROADCAST_BUCKETS_MS.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].get())
+    }
+
+    /// `{"le":[..],"cumulative":[..],"sum_ms":n,"count":n}`; `cumulative`
+    /// has one more entry than `le`, the total.
+    pub fn json(&self) -> String {
+        let mut total = 0;
+        let cumulative: Vec<String> = self
+            .buckets
+            .iter()
+            .map(|b| {
+                total += b.get();
+                total.to_string()
+            })
+            .collect();
+        let le: Vec<String> = FIRST_BROADCAST_BUCKETS_MS
+            .iter()
+            .map(|b| b.to_string())
+            .collect();
+        format!(
+            "{{\"le\":[{}],\"cumulative\":[{}],\"sum_ms\":{},\"count\":{}}}",
+            le.join(","),
+            cumulative.join(","),
+            self.sum_ms.get(),
+            total
+        )
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct LoadMetrics {
+    #[cfg_attr(not(feature = "debug-logs"), allow(dead_code))]
     pub id: String,
-    pub active: AlignedAtomic,
-    pub failed: AlignedAtomic,
+    /// Server these counters belong to; one LoadMetrics exists per `--target`.
+    pub target: Target,
+    /// Simulated users assigned to this target by weight.
+    pub users: AlignedAtomic,
+    /// Users that connected here after their assigned target failed (`--failover`).
+    pub failovers: AlignedAtomic,
+    /// Handshakes started, and how each ended: every attempt ends up in
+    /// exactly one of `connect_success` and `connect_failed`. A connection
+    /// that succeeded and later went away, for whatever reason, also counts
+    /// in `disconnects`.
+    pub connect_attempts: AlignedAtomic,
+    pub connect_success: AlignedAtomic,
+    pub connect_failed: AlignedAtomic,
+    pub disconnects: AlignedAtomic,
+    /// Of `connect_success`, connections resumed from a session ticket and
+    /// used at once in 0-RTT, without waiting for the handshake. Should
+    /// that handshake fail after all, it shows up as a disconnect.
+    pub connect_0rtt: AlignedAtomic,
     pub tx_pixels: AlignedAtomic,
+    /// Pixel datagrams sent a second time by `--impair dup=p`, not counted
+    /// in `tx_pixels`.
+    pub tx_duplicates: AlignedAtomic,
+    /// Sent pixels the server will accept or reject for cooldown, as
+    /// predicted by `--respect-cooldown` pacing. Without pacing, rejected
+    /// pixels are counted from the server's cooldown NACKs instead: a lower
+    /// bound, as it sends at most one a second per connection.
+    pub accepted_pixels: AlignedAtomic,
+    pub rejected_pixels: AlignedAtomic,
+    /// Cooldown NACKs (COOLDOWN_NACK) received.
+    pub cooldown_nacks: AlignedAtomic,
+    /// With `--pixel-receipts`: pixels sent on connections that negotiated
+    /// RECEIPTS, and the receipts (PIXEL_ACK) the server sent for those it
+    /// queued for the canvas.
+    pub receipted_pixels: AlignedAtomic,
+    pub acked_pixels: AlignedAtomic,
+    /// Pixel-rate contracts (RATE_CONTRACT) pacing took up: the one pushed
+    /// at handshake, then one per change of the server's load.
+    pub contract_updates: AlignedAtomic,
     pub rx_datagrams: AlignedAtomic,
     pub rx_bytes: AlignedAtomic,
+    /// Received datagrams thrown away by `--impair rx_loss=p`.
+    pub rx_dropped: AlignedAtomic,
+    /// Received datagrams of formats this build does not know or features
+    /// the connection did not negotiate, skipped (see protocol::broadcast).
+    pub rx_skipped: AlignedAtomic,
+    /// Server-initiated closes, indexed by `AppCloseCode` value.
+    pub closes: [AlignedAtomic; AppCloseCode::ALL.len()],
+    /// Closes whose code this client does not know.
+    pub closes_unknown: AlignedAtomic,
+    /// Local address changes forced by `--rebind-interval`.
+    pub rebinds: AlignedAtomic,
+    /// Rebinds followed by a broadcast on the new address, and the summed
+    /// time until that first broadcast arrived.
+    pub rebind_recoveries: AlignedAtomic,
+    pub rebind_recovery_ms: AlignedAtomic,
+    /// Connections that died after a rebind before any broadcast came back.
+    pub rebind_losses: AlignedAtomic,
+    /// Dictionary-compressed diffs received (`--zstd-diffs`), their size on
+    /// the wire and decompressed, and those that would not decompress.
+    pub zstd_diffs: AlignedAtomic,
+    pub zstd_wire_bytes: AlignedAtomic,
+    pub zstd_raw_bytes: AlignedAtomic,
+    pub zstd_errors: AlignedAtomic,
+    /// Full snapshots seen on connections that negotiated FEC (`--fec`):
+    /// complete as received, complete after parity repair, and chunks
+    /// rebuilt from parity.
+    pub fec_snapshots: AlignedAtomic,
+    pub fec_complete_without_parity: AlignedAtomic,
+    pub fec_complete: AlignedAtomic,
+    pub fec_rebuilt_chunks: AlignedAtomic,
+    /// Full snapshots observers decoded alike with every RLE decoder, and
+    /// those where one diverged (`--verify-rle`, see rle_check.rs).
+    pub rle_verified: AlignedAtomic,
+    pub rle_divergences: AlignedAtomic,
+    /// STATS replies received (`--live-stats`), and the figures in the
+    /// newest one from any connection.
+    pub live_stats_replies: AlignedAtomic,
+    pub live_online: AlignedAtomic,
+    pub live_pixels_per_sec: AlignedAtomic,
+    pub live_cooldown_secs: AlignedAtomic,
+    pub live_broadcast_interval_ms: AlignedAtomic,
+    pub live_next_full_ms: AlignedAtomic,
+    /// Connect complete to the first datagram received: with the server's
+    /// histogram, the gap is network plus client-side delay.
+    pub first_broadcast_ms: LatencyHistogram,
+    /// Tracer pixels painted (`--tier-token`), and per tier (0, 1) the time
+    /// until its observer saw one and the tracers it never saw.
+    pub tracers: AlignedAtomic,
+    pub tier_latency_ms: [LatencyHistogram; TIERS],
+    pub tracer_misses: [AlignedAtomic; TIERS],
+    /// Simulated users per session phase (see lifecycle.rs).
+    pub lifecycle: LifecycleMetrics,
+    pub started: Instant,
 }
 
 impl LoadMetrics {
-    pub fn new(id: String) -> Arc<Self> {
+    pub fn new(id: String, target: Target) -> Arc<Self> {
         Arc::new(Self {
             id,
-            active: AlignedAtomic::new(0),
-            failed: AlignedAtomic::new(0),
+            target,
+            users: AlignedAtomic::new(0),
+            failovers: AlignedAtomic::new(0),
+            connect_attempts: AlignedAtomic::new(0),
+            connect_success: AlignedAtomic::new(0),
+            connect_failed: AlignedAtomic::new(0),
+            disconnects: AlignedAtomic::new(0),
+            connect_0rtt: AlignedAtomic::new(0),
             tx_pixels: AlignedAtomic::new(0),
+            tx_duplicates: AlignedAtomic::new(0),
+            accepted_pixels: AlignedAtomic::new(0),
+            rejected_pixels: AlignedAtomic::new(0),
+            cooldown_nacks: AlignedAtomic::new(0),
+            receipted_pixels: AlignedAtomic::new(0),
+            acked_pixels: AlignedAtomic::new(0),
+            contract_updates: AlignedAtomic::new(0),
             rx_datagrams: AlignedAtomic::new(0),
             rx_bytes: AlignedAtomic::new(0),
+            rx_dropped: AlignedAtomic::new(0),
+            rx_skipped: AlignedAtomic::new(0),
+            closes: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            closes_unknown: AlignedAtomic::new(0),
+            rebinds: AlignedAtomic::new(0),
+            rebind_recoveries: AlignedAtomic::new(0),
+            rebind_recovery_ms: AlignedAtomic::new(0),
+            rebind_losses: AlignedAtomic::new(0),
+            zstd_diffs: AlignedAtomic::new(0),
+            zstd_wire_bytes: AlignedAtomic::new(0),
+            zstd_raw_bytes: AlignedAtomic::new(0),
+            zstd_errors: AlignedAtomic::new(0),
+            fec_snapshots: AlignedAtomic::new(0),
+            fec_complete_without_parity: AlignedAtomic::new(0),
+            fec_complete: AlignedAtomic::new(0),
+            fec_rebuilt_chunks: AlignedAtomic::new(0),
+            rle_verified: AlignedAtomic::new(0),
+            rle_divergences: AlignedAtomic::new(0),
+            live_stats_replies: AlignedAtomic::new(0),
+            live_online: AlignedAtomic::new(0),
+            live_pixels_per_sec: AlignedAtomic::new(0),
+            live_cooldown_secs: AlignedAtomic::new(0),
+            live_broadcast_interval_ms: AlignedAtomic::new(0),
+            live_next_full_ms: AlignedAtomic::new(0),
+            first_broadcast_ms: LatencyHistogram::new(),
+            tracers: AlignedAtomic::new(0),
+            tier_latency_ms: std::array::from_fn(|_| LatencyHistogram::new()),
+            tracer_misses: std::array::from_fn(|_| AlignedAtomic::new(0)),
+            lifecycle: LifecycleMetrics::new(),
+            started: Instant::now(),
         })
     }
+
+    /// Connections currently up. Disconnects are read first, so a
+    /// disconnect racing the read can only make this too high by one, never
+    /// wrap below zero.
+    pub fn active(&self) -> usize {
+        let disconnects = self.disconnects.get();
+        self.connect_success.get().saturating_sub(disconnects)
+    }
+
+    pub fn record_close(&self, code: u64) {
+        match AppCloseCode::from_u64(code) {
+            Some(code) => self.closes[code as usize].add(1),
+            None => self.closes_unknown.add(1),
+        }
+    }
+
+    pub fn record_rebind_recovery(&self, elapsed: Duration) {
+        self.rebind_recoveries.add(1);
+        self.rebind_recovery_ms.add(elapsed.as_millis() as usize);
+    }
+
+    /// `{"count":n,"recovered":n,"mean_recovery_ms":x,"lost":n}`
+    fn rebinds_json(&self) -> String {
+        let recovered = self.rebind_recoveries.get();
+        let mean_ms = if recovered == 0 {
+            0.0
+        } else {
+            self.rebind_recovery_ms.get() as f64 / recovered as f64
+        };
+        format!(
+            "{{\"count\":{},\"recovered\":{},\"mean_recovery_ms\":{:.1},\"lost\":{}}}",
+            self.rebinds.get(),
+            recovered,
+            mean_ms,
+            self.rebind_losses.get()
+        )
+    }
+
+    /// `{"diffs":n,"wire_bytes":n,"raw_bytes":n,"errors":n}`
+    fn zstd_json(&self) -> String {
+        format!(
+            "{{\"diffs\":{},\"wire_bytes\":{},\"raw_bytes\":{},\"errors\":{}}}",
+            self.zstd_diffs.get(),
+            self.zstd_wire_bytes.get(),
+            self.zstd_raw_bytes.get(),
+            self.zstd_errors.get()
+        )
+    }
+
+    /// Adds what one connection's FEC assembler saw.
+    pub fn record_fec(&self, stats: &FecStats) {
+        self.fec_snapshots.add(stats.snapshots as usize);
+        self.fec_complete_without_parity
+            .add(stats.complete_without_parity as usize);
+        self.fec_complete.add(stats.complete as usize);
+        self.fec_rebuilt_chunks.add(stats.rebuilt_chunks as usize);
+    }
+
+    /// `{"snapshots":n,"complete_without_parity":n,"complete":n,"rebuilt_chunks":n}`
+    fn fec_json(&self) -> String {
+        format!(
+            "{{\"snapshots\":{},\"complete_without_parity\":{},\"complete\":{},\"rebuilt_chunks\":{}}}",
+            self.fec_snapshots.get(),
+            self.fec_complete_without_parity.get(),
+            self.fec_complete.get(),
+            self.fec_rebuilt_chunks.get()
+        )
+    }
+
+    /// `{"verified":n,"divergences":n}`
+    fn rle_json(&self) -> String {
+        format!(
+            "{{\"verified\":{},\"divergences\":{}}}",
+            self.rle_verified.get(),
+            self.rle_divergences.get()
+        )
+    }
+
+    pub fn record_live_stats(&self, stats: &LiveStats) {
+        self.live_stats_replies.add(1);
+        self.live_online.set(stats.online as usize);
+        self.live_pixels_per_sec.set(stats.pixels_per_sec as usize);
+        self.live_cooldown_secs.set(stats.cooldown_secs as usize);
+        self.live_broadcast_interval_ms
+            .set(stats.broadcast_interval_ms as usize);
+        self.live_next_full_ms.set(stats.next_full_ms as usize);
+    }
+
+    /// `{"replies":n,"online":n,"pixels_per_sec":n,"cooldown_s":n,
+    /// "broadcast_interval_ms":n,"next_full_ms":n}`, the figures null until
+    /// a reply came in; `next_full_ms` also when the server had no full
+    /// broadcast scheduled.
+    fn live_stats_json(&self) -> String {
+        let replies = self.live_stats_replies.get();
+        if replies == 0 {
+            return "{\"replies\":0,\"online\":null,\"pixels_per_sec\":null,\"cooldown_s\":null,\"broadcast_interval_ms\":null,\"next_full_ms\":null}".to_string();
+        }
+        let next_full_ms = match self.live_next_full_ms.get() {
+            n if n == NO_FULL_SCHEDULED as usize => "null".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{{\"replies\":{},\"online\":{},\"pixels_per_sec\":{},\"cooldown_s\":{},\"broadcast_interval_ms\":{},\"next_full_ms\":{}}}",
+            replies,
+            self.live_online.get(),
+            self.live_pixels_per_sec.get(),
+            self.live_cooldown_secs.get(),
+            self.live_broadcast_interval_ms.get(),
+            next_full_ms
+        )
+    }
+
+    /// `{"tracers":n,"latency_ms":[tier0,tier1],"misses":[n,n]}`, with
+    /// histograms like `first_broadcast_ms`.
+    fn tiers_json(&self) -> String {
+        format!(
+            "{{\"tracers\":{},\"latency_ms\":[{},{}],\"misses\":[{},{}]}}",
+            self.tracers.get(),
+            self.tier_latency_ms[0].json(),
+            self.tier_latency_ms[1].json(),
+            self.tracer_misses[0].get(),
+            self.tracer_misses[1].get()
+        )
+    }
+
+    /// `"accepted_pixels":n,"rejected_pixels":n,"accepted_pps":x,
+    /// "contract_updates":n,"cooldown_nacks":n`; without a cooldown to
+    /// predict acceptance from, only the NACK-fed counts are not null.
+    fn acceptance_json(&self, paced: bool) -> String {
+        if !paced {
+            return format!(
+                "\"accepted_pixels\":null,\"rejected_pixels\":{},\"accepted_pps\":null,\"contract_updates\":null,\"cooldown_nacks\":{}",
+                self.rejected_pixels.get(),
+                self.cooldown_nacks.get()
+            );
+        }
+        let secs = self.started.elapsed().as_secs_f64().max(1.0);
+        format!(
+            "\"accepted_pixels\":{},\"rejected_pixels\":{},\"accepted_pps\":{:.1},\"contract_updates\":{},\"cooldown_nacks\":{}",
+            self.accepted_pixels.get(),
+            self.rejected_pixels.get(),
+            self.accepted_pixels.get() as f64 / secs,
+            self.contract_updates.get(),
+            self.cooldown_nacks.get()
+        )
+    }
+
+    /// `{"pixels":n,"acked":n,"accept_ratio":x}`, the ratio null when no
+    /// pixel asked for a receipt.
+    fn receipts_json(&self) -> String {
+        let pixels = self.receipted_pixels.get();
+        let acked = self.acked_pixels.get();
+        let ratio = match pixels {
+            0 => "null".to_string(),
+            n => format!("{:.4}", acked as f64 / n as f64),
+        };
+        format!(
+            "{{\"pixels\":{},\"acked\":{},\"accept_ratio\":{}}}",
+            pixels, acked, ratio
+        )
+    }
+
+    /// `{"client_exit":n,...,"unknown":n}`
+    fn closes_json(&self) -> String {
+        let mut fields: Vec<String> = AppCloseCode::ALL
+            .iter()
+            .map(|c| format!("\"{}\":{}", c.name(), self.closes[*c as usize].get()))
+            .collect();
+        fields.push(format!("\"unknown\":{}", self.closes_unknown.get()));
+        format!("{{{}}}", fields.join(","))
+    }
 }
 
-pub fn spawn_csv_exporter(metrics: Arc<LoadMetrics>, worker_id: String, metrics_dir: String) {
-    tokio::spawn(async move {
-        // Ansible playbook expects metrics in /opt/canvas/metrics/
-        let path = format!("{}/{}_data.csv", metrics_dir, worker_id);
-        // Fallback for non-docker runs to `./test_results/` might be nice, but to match blueprint, we stick to /metrics
-        let file_res = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await;
-
-        let mut file = match file_res {
-            Ok(f) => Some(f),
-            Err(_e) => {
-                // Fallback for local testing maybe?
-                let fallback = format!("{}_data.csv", worker_id);
-                if let Ok(f) = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(&fallback)
-                    .await
-                {
-                    Some(f)
-                } else {
-                    eprintln!(
-                        "Could not open metrics file at {} or fallback {}, ignoring metrics reporting.",
-                        path, fallback
-                    );
-                    None
-                }
+/// `--metrics-dir` default: `/metrics`, the volume the containers and the
+/// Ansible playbook mount, on Linux; the temp directory on developer
+/// machines that have no such path (Windows, macOS).
+pub fn default_metrics_dir() -> String {
+    if cfg!(target_os = "linux") {
+        "/metrics".to_string()
+    } else {
+        std::env::temp_dir().to_string_lossy().into_owned()
+    }
+}
+
+/// Writes `<metrics_dir>/<worker_id>_data.schema.json`, falling back to
+/// the working directory like `create_csv`.
+fn write_csv_schema(metrics_dir: &str, worker_id: &str) {
+    let name = format!("{}_data.schema.json", worker_id);
+    let json = csv_schema_json();
+    if std::fs::write(Path::new(metrics_dir).join(&name), &json).is_err() {
+        let _ = std::fs::write(&name, &json);
+    }
+}
+
+/// Opens `<metrics_dir>/<worker_id>_<name>.csv` and writes its header.
+async fn create_csv(
+    metrics_dir: &str,
+    worker_id: &str,
+    name: &str,
+    header: &[u8],
+) -> Option<tokio::fs::File> {
+    // Ansible playbook expects metrics in /opt/canvas/metrics/
+    let path = Path::new(metrics_dir).join(format!("{}_{}.csv", worker_id, name));
+    let file_res = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&path)
+        .await;
+
+    let mut file = match file_res {
+        Ok(f) => f,
+        Err(_e) => {
+            // Fallback for local testing maybe?
+            let fallback = format!("{}_{}.csv", worker_id, name);
+            if let Ok(f) = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&fallback)
+                .await
+            {
+                f
+            } else {
+                eprintln!(
+                    "Could not open metrics file at {} or fallback {}, ignoring metrics reporting.",
+                    path.display(),
+                    fallback
+                );
+                return None;
             }
-        };
+        }
+    };
+    let _ = file.write_all(header).await;
+    Some(file)
+}
 
-        if let Some(ref mut f) = file {
-            let _ = f
-                .write_all(b"timestamp,active,failed,tx_pixels,tx_pps,rx_dgram_s,rx_mbps\n")
-                .await;
+/// Version of the `<id>_data.csv` layout, in its first column. 2 split
+/// `active`/`failed` into the connect counters; 3 added `rle_divergences`
+/// and `tracer_p99_ms`; 4 the session phase populations; 5 named every
+/// column by what it holds (CSV_COLUMNS) and moved to milliseconds; 6
+/// added `rejected_pixels_*`.
+pub const CSV_SCHEMA_VERSION: u32 = 6;
+
+/// What `--legacy-csv` writes: schema 4, for one release. bench/client_csv.py
+/// converts such files to the current schema.
+pub const LEGACY_CSV_SCHEMA_VERSION: u32 = 4;
+
+const LEGACY_CSV_HEADER: &str = "schema,timestamp,target,active,connect_attempts,connect_success,connect_failed,disconnects,connects_s,disconnects_s,tx_pixels,tx_pps,rx_dgram_s,rx_mbps,accepted_pixels,accepted_pps,rle_divergences,tracer_p99_ms,connecting,syncing,steady,degraded\n";
+
+/// How a `<id>_data.csv` column relates to time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnKind {
+    /// Identifies the row.
+    Key,
+    /// Value at the sample.
+    Gauge,
+    /// Since the client started; the name ends in `_total`.
+    Total,
+    /// Over the interval since the previous sample, per second; the name
+    /// ends in `_per_s`.
+    PerSecond,
+    /// A statistic of the interval since the previous sample.
+    Interval,
+}
+
+impl ColumnKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Gauge => "gauge",
+            Self::Total => "total",
+            Self::PerSecond => "per_second",
+            Self::Interval => "interval",
+        }
+    }
+}
+
+pub struct CsvColumn {
+    pub name: &'static str,
+    pub kind: ColumnKind,
+    pub unit: &'static str,
+    pub doc: &'static str,
+}
+
+const fn column(
+    name: &'static str,
+    kind: ColumnKind,
+    unit: &'static str,
+    doc: &'static str,
+) -> CsvColumn {
+    CsvColumn {
+        name,
+        kind,
+        unit,
+        doc,
+    }
+}
+
+/// The `<id>_data.csv` columns, in order. `<id>_data.schema.json` next to
+/// the CSV lists the same.
+pub const CSV_COLUMNS: [CsvColumn; 24] = {
+    use ColumnKind::*;
+    [
+        column("schema", Key, "", "layout version"),
+        column(
+            "timestamp_ms",
+            Key,
+            "ms",
+            "sample time, since the Unix epoch",
+        ),
+        column("target", Key, "", "server address"),
+        column(
+            "active",
+            Gauge,
+            "connections",
+            "established, not yet closed",
+        ),
+        column(
+            "connect_attempts_total",
+            Total,
+            "connections",
+            "connects started",
+        ),
+        column(
+            "connect_success_total",
+            Total,
+            "connections",
+            "handshakes completed",
+        ),
+        column(
+            "connect_failed_total",
+            Total,
+            "connections",
+            "connects that failed",
+        ),
+        column(
+            "disconnects_total",
+            Total,
+            "connections",
+            "established connections lost",
+        ),
+        column(
+            "connects_per_s",
+            PerSecond,
+            "connections/s",
+            "handshakes completed",
+        ),
+        column(
+            "disconnects_per_s",
+            PerSecond,
+            "connections/s",
+            "established connections lost",
+        ),
+        column("tx_pixels_total", Total, "pixels", "pixel datagrams sent"),
+        column(
+            "tx_pixels_per_s",
+            PerSecond,
+            "pixels/s",
+            "pixel datagrams sent",
+        ),
+        column(
+            "rx_datagrams_per_s",
+            PerSecond,
+            "datagrams/s",
+            "datagrams received",
+        ),
+        column(
+            "rx_megabits_per_s",
+            PerSecond,
+            "Mbit/s",
+            "datagram payload received",
+        ),
+        column(
+            "accepted_pixels_total",
+            Total,
+            "pixels",
+            "pixels sent outside the cooldown (--respect-cooldown)",
+        ),
+        column(
+            "accepted_pixels_per_s",
+            PerSecond,
+            "pixels/s",
+            "pixels sent outside the cooldown (--respect-cooldown)",
+        ),
+        column(
+            "rejected_pixels_total",
+            Total,
+            "pixels",
+            "pixels sent in the cooldown (--respect-cooldown), else cooldown NACKs",
+        ),
+        column(
+            "rejected_pixels_per_s",
+            PerSecond,
+            "pixels/s",
+            "pixels sent in the cooldown (--respect-cooldown), else cooldown NACKs",
+        ),
+        column(
+            "rle_divergences_total",
+            Total,
+            "snapshots",
+            "reconstructed canvases that did not match the server's",
+        ),
+        column(
+            "tracer_p99_ms",
+            Interval,
+            "ms",
+            "p99 tracer pixel latency, bucket bound; empty without tracers",
+        ),
+        column(
+            "connecting",
+            Gauge,
+            "users",
+            "users in the connecting phase",
+        ),
+        column("syncing", Gauge, "users", "users in the syncing phase"),
+        column("steady", Gauge, "users", "users in the steady phase"),
+        column("degraded", Gauge, "users", "users in the degraded phase"),
+    ]
+};
+
+fn csv_header() -> String {
+    let names: Vec<&str> = CSV_COLUMNS.iter().map(|c| c.name).collect();
+    format!("{}\n", names.join(","))
+}
+
+/// `<id>_data.schema.json`: the schema version and every column's kind,
+/// unit and meaning.
+pub fn csv_schema_json() -> String {
+    let columns: Vec<String> = CSV_COLUMNS
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"name\":\"{}\",\"kind\":\"{}\",\"unit\":\"{}\",\"doc\":\"{}\"}}",
+                c.name,
+                c.kind.as_str(),
+                c.unit,
+                c.doc
+            )
+        })
+        .collect();
+    format!(
+        "{{\"schema\":{},\"columns\":[{}]}}\n",
+        CSV_SCHEMA_VERSION,
+        columns.join(",")
+    )
+}
+
+type BucketCounts = [usize; FIRST_BROADCAST_BUCKETS_MS.len() + 1];
+
+/// Upper bound (ms) of the bucket holding the `q` quantile of `counts`, or
+/// None without samples. The overflow bucket reports the last bound.
+pub fn bucket_quantile(counts: &BucketCounts, q: f64) -> Option<u64> {
+    let total: usize = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = ((q * total as f64).ceil() as usize).max(1);
+    let mut seen = 0;
+    let bucket = counts.iter().position(|&c| {
+        seen += c;
+        seen >= rank
+    })?;
+    Some(FIRST_BROADCAST_BUCKETS_MS[bucket.min(FIRST_BROADCAST_BUCKETS_MS.len() - 1)])
+}
+
+/// The cumulative counters the per-second rates are computed from.
+#[derive(Default, Clone, Copy)]
+struct RateSample {
+    /// When it was taken (ms since the Unix epoch).
+    at_ms: u64,
+    connect_success: usize,
+    disconnects: usize,
+    tx_pixels: usize,
+    rx_datagrams: usize,
+    rx_bytes: usize,
+    accepted_pixels: usize,
+    rejected_pixels: usize,
+    /// Tracer latencies of both tiers together.
+    tracer_ms: BucketCounts,
+}
+
+impl RateSample {
+    fn take(m: &LoadMetrics, at_ms: u64) -> Self {
+        Self {
+            at_ms,
+            // Disconnects first, as in `LoadMetrics::active`.
+            disconnects: m.disconnects.get(),
+            connect_success: m.connect_success.get(),
+            tx_pixels: m.tx_pixels.get(),
+            rx_datagrams: m.rx_datagrams.get(),
+            rx_bytes: m.rx_bytes.get(),
+            accepted_pixels: m.accepted_pixels.get(),
+            rejected_pixels: m.rejected_pixels.get(),
+            tracer_ms: m
+                .tier_latency_ms
+                .iter()
+                .fold(BucketCounts::default(), |mut sum, h| {
+                    for (s, c) in sum.iter_mut().zip(h.counts()) {
+                        *s += c;
+                    }
+                    sum
+                }),
         }
+    }
+
+    /// p99 of the tracers seen since `last`, empty if there were none.
+    fn tracer_p99_ms(&self, last: &Self) -> String {
+        let tracer_ms: BucketCounts =
+            std::array::from_fn(|i| self.tracer_ms[i] - last.tracer_ms[i]);
+        bucket_quantile(&tracer_ms, 0.99).map_or(String::new(), |ms| ms.to_string())
+    }
+}
 
-        let (mut last_dgrams, mut last_bytes, mut last_tx) = (0, 0, 0);
+/// One `<id>_data.csv` row (CSV_COLUMNS): `current` counters, with rates
+/// against `last`. The phase populations are as of now.
+fn csv_row(m: &LoadMetrics, current: &RateSample, last: &RateSample) -> String {
+    let elapsed_s = current.at_ms.saturating_sub(last.at_ms).max(1) as f64 / 1000.0;
+    let per_s = |now: usize, then: usize| (now - then) as f64 / elapsed_s;
+    format!(
+        "{},{},{},{},{},{},{},{},{:.2},{:.2},{},{:.2},{:.2},{:.3},{},{:.2},{},{:.2},{},{},{}\n",
+        CSV_SCHEMA_VERSION,
+        current.at_ms,
+        m.target.addr,
+        current.connect_success.saturating_sub(current.disconnects),
+        m.connect_attempts.get(),
+        current.connect_success,
+        m.connect_failed.get(),
+        current.disconnects,
+        per_s(current.connect_success, last.connect_success),
+        per_s(current.disconnects, last.disconnects),
+        current.tx_pixels,
+        per_s(current.tx_pixels, last.tx_pixels),
+        per_s(current.rx_datagrams, last.rx_datagrams),
+        per_s(current.rx_bytes, last.rx_bytes) * 8.0 / 1_000_000.0,
+        current.accepted_pixels,
+        per_s(current.accepted_pixels, last.accepted_pixels),
+        current.rejected_pixels,
+        per_s(current.rejected_pixels, last.rejected_pixels),
+        m.rle_divergences.get(),
+        current.tracer_p99_ms(last),
+        Phase::ALL
+            .map(|p| m.lifecycle.population(p).to_string())
+            .join(",")
+    )
+}
+
+/// Formats one target's row from its samples now and at the last export.
+type CsvRow = fn(&LoadMetrics, &RateSample, &RateSample) -> String;
+
+/// A schema 4 row (`--legacy-csv`): seconds, and the differences to
+/// `last` as they are, whatever the interval was.
+fn legacy_csv_row(m: &LoadMetrics, current: &RateSample, last: &RateSample) -> String {
+    let rx_mbps = (current.rx_bytes - last.rx_bytes) as f64 * 8.0 / 1_000_000.0;
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},{:.3},{},{},{},{},{}\n",
+        LEGACY_CSV_SCHEMA_VERSION,
+        current.at_ms / 1000,
+        m.target.addr,
+        current.connect_success.saturating_sub(current.disconnects),
+        m.connect_attempts.get(),
+        current.connect_success,
+        m.connect_failed.get(),
+        current.disconnects,
+        current.connect_success - last.connect_success,
+        current.disconnects - last.disconnects,
+        current.tx_pixels,
+        current.tx_pixels - last.tx_pixels,
+        current.rx_datagrams - last.rx_datagrams,
+        rx_mbps,
+        current.accepted_pixels,
+        current.accepted_pixels - last.accepted_pixels,
+        m.rle_divergences.get(),
+        current.tracer_p99_ms(last),
+        Phase::ALL
+            .map(|p| m.lifecycle.population(p).to_string())
+            .join(",")
+    )
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Writes one CSV row per target per second, so runs against several
+/// instances can be compared column-for-column (`accepted_pixels_*` are
+/// only filled in with `--respect-cooldown`), plus the schema sidecar
+/// (schema 4 and no sidecar with `legacy`), and one row per pool
+/// endpoint to `<id>_endpoints.csv`. `client_side_drops` there is the
+/// kernel's receive-buffer drop count for the endpoint's socket: loss that
+/// happened on this machine, not on the server or the network.
+pub fn spawn_csv_exporter(
+    metrics: Vec<Arc<LoadMetrics>>,
+    endpoint_stats: Arc<[EndpointStats]>,
+    worker_id: String,
+    metrics_dir: String,
+    legacy: bool,
+) {
+    tokio::spawn(async move {
+        let (header, row): (String, CsvRow) = if legacy {
+            (LEGACY_CSV_HEADER.to_string(), legacy_csv_row)
+        } else {
+            write_csv_schema(&metrics_dir, &worker_id);
+            (csv_header(), csv_row)
+        };
+        let mut file = create_csv(&metrics_dir, &worker_id, "data", header.as_bytes()).await;
+        let mut endpoint_file = create_csv(
+            &metrics_dir,
+            &worker_id,
+            "endpoints",
+            b"timestamp,endpoint,local_port,established,rx_datagrams,client_side_drops\n",
+        )
+        .await;
+
+        // Counters at the previous sample, per target.
+        let start = RateSample {
+            at_ms: unix_ms(),
+            ..Default::default()
+        };
+        let mut last: Vec<RateSample> = metrics.iter().map(|_| start).collect();
 
         loop {
             sleep(Duration::from_secs(1)).await;
-            let ts = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-
-            let current_dgrams = metrics.rx_datagrams.get();
-            let current_bytes = metrics.rx_bytes.get();
-            let current_tx = metrics.tx_pixels.get();
-
-            let dps = current_dgrams - last_dgrams;
-            let tx_pps = current_tx - last_tx;
-            let mbps = ((current_bytes - last_bytes) as f64 * 8.0) / 1_000_000.0;
-
-            let row = format!(
-                "{},{},{},{},{},{},{:.3}\n",
-                ts,
-                metrics.active.get(),
-                metrics.failed.get(),
-                metrics.tx_pixels.get(),
-                tx_pps,
-                dps,
-                mbps
-            );
+            let now_ms = unix_ms();
+            let ts = now_ms / 1000;
+
+            let mut rows = String::new();
+            for (m, last) in metrics.iter().zip(last.iter_mut()) {
+                let current = RateSample::take(m, now_ms);
+                rows.push_str(&row(m, &current, last));
+                *last = current;
+            }
 
             if let Some(ref mut f) = file {
-                let _ = f.write_all(row.as_bytes()).await;
+                let _ = f.write_all(rows.as_bytes()).await;
             }
 
-            last_dgrams = current_dgrams;
-            last_bytes = current_bytes;
-            last_tx = current_tx;
+            if let Some(ref mut f) = endpoint_file {
+                let drops = endpoints::socket_drops();
+                let mut rows = String::new();
+                for (n, e) in endpoint_stats.iter().enumerate() {
+                    rows.push_str(&format!(
+                        "{},{},{},{},{},{}\n",
+                        ts,
+                        n,
+                        e.port,
+                        e.established.get(),
+                        e.rx_datagrams.get(),
+                        drops.get(&e.port).copied().unwrap_or(0)
+                    ));
+                }
+                let _ = f.write_all(rows.as_bytes()).await;
+            }
         }
     });
 }
+
+/// End-of-run comparison table, one entry per target. `scenario` is the hash
+/// of the persona mix that drove a load run and `seed` what its users drew
+/// their randomness from (both null for replays). With a
+/// `cooldown_s`, `accepted_pps` is the run's capacity figure: pixels the
+/// server took, rather than pixels thrown at it.
+pub fn summary_json(
+    worker_id: &str,
+    scenario: Option<u64>,
+    seed: Option<u64>,
+    cooldown_s: Option<u64>,
+    metrics: &[Arc<LoadMetrics>],
+) -> String {
+    let rows: Vec<String> = metrics
+        .iter()
+        .map(|m| {
+            format!(
+                "{{\"target\":\"{}\",\"weight\":{},\"users\":{},\"active\":{},\"connect_attempts\":{},\"connect_success\":{},\"connect_failed\":{},\"disconnects\":{},\"connect_0rtt\":{},\"failovers\":{},\"tx_pixels\":{},\"tx_duplicates\":{},{},\"receipts\":{},\"rx_datagrams\":{},\"rx_bytes\":{},\"rx_dropped\":{},\"rx_skipped\":{},\"closes\":{},\"rebinds\":{},\"zstd\":{},\"fec\":{},\"rle\":{},\"live_stats\":{},\"first_broadcast_ms\":{},\"tiers\":{},\"lifecycle\":{}}}",
+                m.target.addr,
+                m.target.weight,
+                m.users.get(),
+                m.active(),
+                m.connect_attempts.get(),
+                m.connect_success.get(),
+                m.connect_failed.get(),
+                m.disconnects.get(),
+                m.connect_0rtt.get(),
+                m.failovers.get(),
+                m.tx_pixels.get(),
+                m.tx_duplicates.get(),
+                m.acceptance_json(cooldown_s.is_some()),
+                m.receipts_json(),
+                m.rx_datagrams.get(),
+                m.rx_bytes.get(),
+                m.rx_dropped.get(),
+                m.rx_skipped.get(),
+                m.closes_json(),
+                m.rebinds_json(),
+                m.zstd_json(),
+                m.fec_json(),
+                m.rle_json(),
+                m.live_stats_json(),
+                m.first_broadcast_ms.json(),
+                m.tiers_json(),
+                m.lifecycle.json()
+            )
+        })
+        .collect();
+    let scenario = match scenario {
+        Some(hash) => format!("\"{:016x}\"", hash),
+        None => "null".to_string(),
+    };
+    let seed = seed.map_or("null".to_string(), |seed| seed.to_string());
+    let cooldown_s = cooldown_s.map_or("null".to_string(), |secs| secs.to_string());
+    format!(
+        "{{\"id\":\"{}\",\"scenario\":{},\"seed\":{},\"cooldown_s\":{},\"targets\":[{}]}}",
+        worker_id,
+        scenario,
+        seed,
+        cooldown_s,
+        rows.join(",")
+    )
+}
+
+/// Prints the summary and stores it next to the CSV (`<id>_summary.json`),
+/// falling back to the working directory like the exporter does.
+pub fn write_summary(
+    worker_id: &str,
+    metrics_dir: &str,
+    scenario: Option<u64>,
+    seed: Option<u64>,
+    cooldown_s: Option<u64>,
+    metrics: &[Arc<LoadMetrics>],
+) {
+    let json = summary_json(worker_id, scenario, seed, cooldown_s, metrics);
+    println!("{}", json);
+    let path = Path::new(metrics_dir).join(format!("{}_summary.json", worker_id));
+    if std::fs::write(&path, &json).is_err() {
+        let fallback = format!("{}_summary.json", worker_id);
+        if let Err(e) = std::fs::write(&fallback, &json) {
+            eprintln!(
+                "Could not write summary to {} or {}: {}",
+                path.display(),
+                fallback,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples one and three seconds after the start, written both ways.
+    fn export(row: CsvRow) -> String {
+        let addr = "127.0.0.1:4433".parse().unwrap();
+        let m = LoadMetrics::new("golden".to_string(), Target { addr, weight: 1 });
+        let mut last = RateSample {
+            at_ms: 1_700_000_000_000,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        for (at_ms, attempts, success, tx, rx, rx_bytes) in [
+            (1_700_000_001_000, 12, 10, 500, 40, 250_000),
+            (1_700_000_003_000, 4, 4, 400, 60, 250_000),
+        ] {
+            m.connect_attempts.add(attempts);
+            m.connect_success.add(success);
+            m.tx_pixels.add(tx);
+            m.rx_datagrams.add(rx);
+            m.rx_bytes.add(rx_bytes);
+            if at_ms == 1_700_000_001_000 {
+                m.connect_failed.add(2);
+                m.disconnects.add(3);
+                m.rle_divergences.add(1);
+                m.rejected_pixels.add(4);
+                m.tier_latency_ms[0].record(Duration::from_millis(30));
+                m.tier_latency_ms[0].record(Duration::from_millis(40));
+            }
+            let current = RateSample::take(&m, at_ms);
+            out.push_str(&row(&m, &current, &last));
+            last = current;
+        }
+        out
+    }
+
+    #[test]
+    fn test_csv_matches_golden_files() {
+        assert_eq!(
+            csv_header() + &export(csv_row),
+            include_str!("../testdata/data_v6.csv")
+        );
+        assert_eq!(
+            LEGACY_CSV_HEADER.to_string() + &export(legacy_csv_row),
+            include_str!("../testdata/data_v4.csv")
+        );
+        assert_eq!(
+            csv_schema_json(),
+            include_str!("../testdata/data_v6.schema.json")
+        );
+    }
+
+    #[test]
+    fn test_columns_are_named_by_kind() {
+        for c in &CSV_COLUMNS {
+            match c.kind {
+                ColumnKind::Total => assert!(c.name.ends_with("_total"), "{}", c.name),
+                ColumnKind::PerSecond => assert!(c.name.ends_with("_per_s"), "{}", c.name),
+                _ => assert!(
+                    !c.name.ends_with("_total") && !c.name.ends_with("_per_s"),
+                    "{}",
+                    c.name
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Observer connections (`--observers N`): connections that never paint and
+//! archive every broadcast datagram they receive, for post-run analysis.
+//!
+//! One log per observer, `<observer-dir>/observer_<id>_<n>.bin`. Layout (all
+//! integers little-endian):
+//!
+//! ```text
+//! header: b"CVOB" | u16 version
+//! record: u64 arrival_unix_us | u32 len | [u8; len] datagram
+//! ```
+//!
+//! Datagrams are stored verbatim, type byte included (see
+//! `protocol::datagram`); version 1 logs predate types. Arrival times are
+//! wall-clock so logs from several observers and machines line up.
+
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const OBSERVER_MAGIC: &[u8; 4] = b"CVOB";
+pub const OBSERVER_VERSION: u16 = 2;
+
+/// Datagrams buffered between the receive loop and the writer thread. Past
+/// this, the disk is not keeping up and datagrams are dropped (and counted)
+/// rather than stalling the connection.
+pub const OBSERVER_QUEUE_LEN: usize = 16 * 1024;
+
+const HEADER_LEN: usize = 6;
+const RECORD_HEADER_LEN: usize = 8 + 4;
+
+pub fn encode_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(OBSERVER_MAGIC);
+    out.extend_from_slice(&OBSERVER_VERSION.to_le_bytes());
+}
+
+pub fn encode_message(arrival_us: u64, datagram: &[u8], out: &mut Vec<u8>) {
+    out.reserve(RECORD_HEADER_LEN + datagram.len());
+    out.extend_from_slice(&arrival_us.to_le_bytes());
+    out.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+    out.extend_from_slice(datagram);
+}
+
+#[derive(Default)]
+pub struct ObserverStats {
+    pub received: AtomicU64,
+    /// Datagrams not logged because the writer queue was full.
+    pub dropped: AtomicU64,
+}
+
+/// Receive-side handle of one observer log. `record` never blocks: file I/O
+/// happens on a writer thread behind a bounded queue.
+pub struct ObserverLog {
+    tx: mpsc::SyncSender<(u64, Bytes)>,
+    writer: JoinHandle<()>,
+    pub stats: Arc<ObserverStats>,
+}
+
+impl ObserverLog {
+    pub fn create(path: &Path, queue_len: usize) -> io::Result<Self> {
+        let mut file = BufWriter::with_capacity(1 << 16, File::create(path)?);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        encode_header(&mut header);
+        file.write_all(&header)?;
+
+        let (tx, rx) = mpsc::sync_channel::<(u64, Bytes)>(queue_len);
+        let writer = std::thread::spawn(move || {
+            let mut scratch = Vec::with_capacity(2048);
+            loop {
+                match rx.recv_timeout(Duration::from_millis(500)) {
+                    Ok((arrival_us, datagram)) => {
+                        scratch.clear();
+                        encode_message(arrival_us, &datagram, &mut scratch);
+                        if file.write_all(&scratch).is_err() {
+                            return;
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        let _ = file.flush();
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        let _ = file.flush();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            tx,
+            writer,
+            stats: Arc::new(ObserverStats::default()),
+        })
+    }
+
+    pub fn record(&self, datagram: Bytes) {
+        let arrival_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        if self.tx.try_send((arrival_us, datagram)).is_err() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Flushes everything queued so far and closes the file.
+    pub fn finish(self) {
+        drop(self.tx);
+        let _ = self.writer.join();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedMessage {
+    pub arrival_us: u64,
+    pub datagram: Vec<u8>,
+}
+
+/// Reads back a whole log (see the layout above); further analysis lives in
+/// bench/plot_results.py.
+pub fn decode_observer_log(buf: &[u8]) -> io::Result<Vec<ObservedMessage>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    if buf.len() < HEADER_LEN || &buf[..4] != OBSERVER_MAGIC {
+        return Err(invalid("not an observer log".into()));
+    }
+    let version = u16::from_le_bytes([buf[4], buf[5]]);
+    if version != OBSERVER_VERSION {
+        return Err(invalid(format!(
+            "unsupported observer log version {} (expected {})",
+            version, OBSERVER_VERSION
+        )));
+    }
+
+    let mut messages = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < buf.len() {
+        if pos + RECORD_HEADER_LEN > buf.len() {
+            return Err(invalid(format!("truncated record at byte {}", pos)));
+        }
+        let rec = &buf[pos..];
+        let arrival_us = u64::from_le_bytes(rec[0..8].try_into().unwrap());
+        let len = u32::from_le_bytes(rec[8..12].try_into().unwrap()) as usize;
+        pos += RECORD_HEADER_LEN;
+        if pos + len > buf.len() {
+            return Err(invalid(format!("truncated datagram at byte {}", pos)));
+        }
+        messages.push(ObservedMessage {
+            arrival_us,
+            datagram: buf[pos..pos + len].to_vec(),
+        });
+        pos += len;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observer_log_round_trip() {
+        let path = std::env::temp_dir().join(format!("canvas-observer-{}.bin", std::process::id()));
+        let datagrams: Vec<&[u8]> = vec![b"\x07\x00\x00\x00\x05", b"", &[0xff; 1200]];
+
+        let log = ObserverLog::create(&path, OBSERVER_QUEUE_LEN).unwrap();
+        for d in &datagrams {
+            log.record(Bytes::copy_from_slice(d));
+        }
+        let stats = log.stats.clone();
+        log.finish();
+
+        let buf = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(&buf[..6], b"CVOB\x02\x00");
+        let messages = decode_observer_log(&buf).unwrap();
+        let got: Vec<&[u8]> = messages.iter().map(|m| &m.datagram[..]).collect();
+        assert_eq!(got, datagrams);
+        assert!(
+            messages
+                .windows(2)
+                .all(|w| w[0].arrival_us <= w[1].arrival_us)
+        );
+        assert_eq!(stats.received.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+
+        assert!(decode_observer_log(&buf[..buf.len() - 1]).is_err());
+        assert!(decode_observer_log(b"CVTR\x01\x00").is_err());
+    }
+
+    #[test]
+    fn test_full_queue_drops_instead_of_blocking() {
+        // No writer thread behind the queue: once its two slots are full,
+        // every further datagram must be counted as dropped.
+        let (tx, _rx) = mpsc::sync_channel(2);
+        let log = ObserverLog {
+            tx,
+            writer: std::thread::spawn(|| {}),
+            stats: Arc::new(ObserverStats::default()),
+        };
+        for _ in 0..5 {
+            log.record(Bytes::from_static(b"x"));
+        }
+        assert_eq!(log.stats.received.load(Ordering::Relaxed), 5);
+        assert_eq!(log.stats.dropped.load(Ordering::Relaxed), 3);
+    }
+}
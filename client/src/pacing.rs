@@ -0,0 +1,288 @@
+//! Cooldown-aware pacing (`--respect-cooldown`). With a 300 s cooldown and
+//! the default 1-10 s pixel wait, nearly every pixel a user sends is
+//! rejected, so the raw send rate says little about capacity. Paced users
+//! send their next pixel just after their cooldown expires instead; a
+//! `--defiance` share keeps ignoring it so the rejection path stays busy.
+//!
+//! Receipts are opt-in (`--pixel-receipts`) and cooldown NACKs come at most
+//! once a second, so whether a pixel was accepted is predicted from the
+//! same rule the server applies: the first pixel of a connection is
+//! accepted, and so is every pixel sent at least one cooldown after the
+//! last accepted one. Each connection asks COOLDOWN_CONFIG first,
+//! since a reconnect may take back a parked id still on cooldown, and then
+//! follows the rate contracts the server pushes (RATE_CONTRACT) as its load
+//! changes.
+
+use protocol::control::{CooldownConfig, RateContract};
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+/// Slack after the predicted expiry. The server expires cooldowns on a 1 s
+/// tick, and the pixel still has to cross the network.
+pub const COOLDOWN_MARGIN_MS: u64 = 100;
+
+/// Spreads paced users that connected together over this window, so they
+/// do not all come back in the same tick.
+pub const COOLDOWN_JITTER_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CooldownSpec {
+    Secs(u64),
+    /// Ask the first target (CONFIG control op) before ramping up.
+    Server,
+}
+
+pub fn parse_cooldown_spec(spec: &str) -> Result<CooldownSpec, String> {
+    if spec == "server" {
+        return Ok(CooldownSpec::Server);
+    }
+    match spec.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(CooldownSpec::Secs(secs)),
+        _ => Err(format!(
+            "invalid cooldown '{}': expected seconds (> 0) or `server`",
+            spec
+        )),
+    }
+}
+
+/// Cooldown in seconds from a CONFIG reply: `cooldown_secs`, or on servers
+/// without it the timing wheel's `timing_wheel_ticks` ticks of one second.
+pub fn cooldown_from_config(json: &str) -> Option<u64> {
+    ["\"cooldown_secs\":", "\"timing_wheel_ticks\":"]
+        .iter()
+        .find_map(|key| config_number(json, key))
+}
+
+fn config_number(json: &str, key: &str) -> Option<u64> {
+    let rest = &json[json.find(key)? + key.len()..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok().filter(|&secs| secs > 0)
+}
+
+/// Whether `user` is one of the `percent` of users that ignore the
+/// cooldown. Depends only on the id, and is independent of the rebind
+/// sample, so reruns defy with the same users.
+pub fn defiant(user: u32, percent: u8) -> bool {
+    let h = (user ^ 0x5BD1_E995).wrapping_mul(0x85EB_CA6B) as u64;
+    h * 100 < percent as u64 * u32::MAX as u64
+}
+
+/// One connection's view of its own cooldown.
+pub struct CooldownPacer {
+    cooldown: Duration,
+    defiant: bool,
+    /// When the last predicted-accepted pixel's cooldown ends.
+    ready_at: Option<Instant>,
+    /// Sequence number of the last contract taken up.
+    contract_seq: u32,
+}
+
+impl CooldownPacer {
+    pub fn new(cooldown: Duration, defiant: bool) -> Self {
+        Self {
+            cooldown,
+            defiant,
+            ready_at: None,
+            contract_seq: 0,
+        }
+    }
+
+    /// Takes the server's word for this connection's cooldown
+    /// (COOLDOWN_CONFIG): its length, and what is left of one the
+    /// connection carried over.
+    pub fn adopt(&mut self, now: Instant, config: &CooldownConfig) {
+        if config.cooldown_ms > 0 {
+            self.cooldown = Duration::from_millis(config.cooldown_ms as u64);
+        }
+        self.ready_at = (config.remaining_ms > 0)
+            .then(|| now + Duration::from_millis(config.remaining_ms as u64));
+    }
+
+    /// Takes up a pushed contract; returns false for one older than the
+    /// last. Its cooldown applies from the next accepted pixel, even while
+    /// the server gives notice of a tighter one: waiting longer early is
+    /// never rejected.
+    pub fn contract(&mut self, contract: &RateContract) -> bool {
+        if contract.seq <= self.contract_seq {
+            return false;
+        }
+        self.contract_seq = contract.seq;
+        if contract.interval_ms > 0 {
+            self.cooldown = Duration::from_millis(contract.interval_ms as u64);
+        }
+        true
+    }
+
+    /// Takes the server's word that a pixel was rejected with `secs` of
+    /// the cooldown left (COOLDOWN_NACK), should it end later than
+    /// predicted.
+    pub fn nacked(&mut self, now: Instant, secs: u16) {
+        let ready_at = now + Duration::from_secs(secs as u64);
+        self.ready_at = Some(self.ready_at.map_or(ready_at, |at| at.max(ready_at)));
+    }
+
+    /// Delay before the next pixel. `persona_wait` is what the persona would
+    /// wait anyway; paced users wait at least until the cooldown is over.
+    pub fn next_wait(&self, now: Instant, persona_wait: u64, rng: &mut impl Rng) -> u64 {
+        match self.ready_at {
+            Some(ready_at) if !self.defiant => {
+                let remaining = ready_at.saturating_duration_since(now).as_millis() as u64;
+                let ready_in =
+                    remaining + COOLDOWN_MARGIN_MS + rng.gen_range(0..COOLDOWN_JITTER_MS);
+                persona_wait.max(ready_in)
+            }
+            _ => persona_wait,
+        }
+    }
+
+    /// Records a pixel sent at `now`; returns whether the server will
+    /// accept it.
+    pub fn record_send(&mut self, now: Instant) -> bool {
+        if self.ready_at.is_some_and(|ready_at| now < ready_at) {
+            return false;
+        }
+        self.ready_at = Some(now + self.cooldown);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cooldown_spec() {
+        assert_eq!(parse_cooldown_spec("300"), Ok(CooldownSpec::Secs(300)));
+        assert_eq!(parse_cooldown_spec("server"), Ok(CooldownSpec::Server));
+        assert!(parse_cooldown_spec("0").is_err());
+        assert!(parse_cooldown_spec("5m").is_err());
+
+        let json = "{\"settings\":{\"tile_size\":64,\"timing_wheel_ticks\":300,\"socket_recv_buf_size\":1}}";
+        assert_eq!(cooldown_from_config(json), Some(300));
+        let json = "{\"config\":{\"cooldown_secs\":60},\"settings\":{\"timing_wheel_ticks\":300}}";
+        assert_eq!(cooldown_from_config(json), Some(60));
+        assert_eq!(cooldown_from_config("{\"settings\":{}}"), None);
+        assert_eq!(cooldown_from_config("null"), None);
+    }
+
+    #[test]
+    fn test_paced_user_waits_out_its_cooldown() {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pacer = CooldownPacer::new(Duration::from_secs(300), false);
+
+        assert_eq!(pacer.next_wait(start, 1_000, &mut rng), 1_000);
+        assert!(pacer.record_send(at(1_000)));
+
+        let wait = pacer.next_wait(at(1_000), 1_000, &mut rng);
+        assert!(
+            (300_000 + COOLDOWN_MARGIN_MS..300_000 + COOLDOWN_MARGIN_MS + COOLDOWN_JITTER_MS)
+                .contains(&wait),
+            "{}",
+            wait
+        );
+        assert!(pacer.record_send(at(1_000 + wait)));
+        // A persona slower than the cooldown keeps its own pace.
+        assert_eq!(
+            pacer.next_wait(at(1_000 + wait), 400_000, &mut rng),
+            400_000
+        );
+    }
+
+    #[test]
+    fn test_defiant_user_is_predicted_rejected() {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pacer = CooldownPacer::new(Duration::from_secs(10), true);
+
+        assert!(pacer.record_send(start));
+        assert_eq!(pacer.next_wait(at(0), 1_000, &mut rng), 1_000);
+        let accepted: Vec<bool> = (1..=12).map(|s| pacer.record_send(at(s * 1_000))).collect();
+        // Rejected until the cooldown that started at 0 s ends at 10 s.
+        assert_eq!(accepted.iter().filter(|&&a| a).count(), 1);
+        assert!(accepted[9]);
+    }
+
+    #[test]
+    fn test_adopted_cooldown_delays_the_first_pixel() {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pacer = CooldownPacer::new(Duration::from_secs(300), false);
+        pacer.adopt(
+            start,
+            &CooldownConfig {
+                cooldown_ms: 60_000,
+                multiplier_permille: protocol::control::COOLDOWN_MULTIPLIER_ONE,
+                remaining_ms: 20_000,
+            },
+        );
+
+        // A parked id's cooldown: the first pixel waits it out.
+        assert!(pacer.next_wait(start, 1_000, &mut rng) >= 20_000 + COOLDOWN_MARGIN_MS);
+        assert!(!pacer.record_send(at(19_000)));
+        assert!(pacer.record_send(at(20_000)));
+        // Then the server's length, not the configured one.
+        assert!(!pacer.record_send(at(79_000)));
+        assert!(pacer.record_send(at(80_000)));
+    }
+
+    #[test]
+    fn test_pushed_contract_paces_the_next_pixel() {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pacer = CooldownPacer::new(Duration::from_secs(60), false);
+        let contract = |seq, interval_ms| RateContract {
+            seq,
+            interval_ms,
+            effective_in_ms: 5_000,
+        };
+
+        assert!(pacer.record_send(start));
+        // Tightened mid-cooldown: the one running keeps its end.
+        assert!(pacer.contract(&contract(2, 90_000)));
+        assert!(pacer.next_wait(at(1_000), 1_000, &mut rng) < 60_000);
+        assert!(pacer.record_send(at(60_000)));
+        assert!(!pacer.record_send(at(149_000)));
+        assert!(pacer.record_send(at(150_000)));
+        // A stale push arriving late changes nothing.
+        assert!(!pacer.contract(&contract(1, 60_000)));
+        assert!(!pacer.record_send(at(239_000)));
+
+        // Defiant users track it too, but do not wait for it.
+        let mut defiant = CooldownPacer::new(Duration::from_secs(60), true);
+        defiant.contract(&contract(2, 90_000));
+        assert!(defiant.record_send(start));
+        assert_eq!(defiant.next_wait(at(1_000), 1_000, &mut rng), 1_000);
+        assert!(!defiant.record_send(at(60_000)));
+    }
+
+    #[test]
+    fn test_nack_only_ever_extends_the_cooldown() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pacer = CooldownPacer::new(Duration::from_secs(60), false);
+
+        // A cooldown carried over from a connection the pacer never saw.
+        assert!(pacer.record_send(start));
+        pacer.nacked(at(1_000), 100);
+        assert!(!pacer.record_send(at(100_000)));
+        assert!(pacer.record_send(at(101_000)));
+        // A late NACK for an earlier pixel does not shorten it.
+        pacer.nacked(at(102_000), 10);
+        assert!(!pacer.record_send(at(160_000)));
+    }
+
+    #[test]
+    fn test_defiance_sampling() {
+        assert!((0..10_000).all(|u| !defiant(u, 0)));
+        assert!((0..10_000).all(|u| defiant(u, 100)));
+        let sampled = (0..10_000).filter(|&u| defiant(u, 20)).count();
+        assert!((1_800..2_200).contains(&sampled), "{}", sampled);
+    }
+}
@@ -0,0 +1,277 @@
+//! Partial canvas reconstruction for a client that tracks only some tiles
+//! (`--mode verify-tiles`), and its check against the server's tile
+//! checksums (TILE_CHECKSUMS, see `protocol::control`).
+//!
+//! The mirror starts from a TILE_REFRESH of its tiles and follows the canvas
+//! with CATCHUP, keeping only the pixels that land in its tiles. Both carry
+//! a snapshot number, so after each catch-up the mirror asks for the
+//! checksums of exactly the snapshot it is at. A tile that does not match
+//! was corrupted or missed an update; only those tiles are refreshed, from
+//! the same snapshot, so the mirror stays consistent.
+
+use protocol::control::{
+    Catchup, PixelRect, SUBSCRIBE_BITMAP_LEN, SUBSCRIBE_TILE_SIZE, SUBSCRIBE_TILES_X,
+    TILE_CHECKSUM_COUNT, TileChecksums, TileRefresh, canvas_tile_checksum,
+};
+
+pub type TileBitmap = [u8; SUBSCRIBE_BITMAP_LEN];
+
+pub fn has_tile(bitmap: &TileBitmap, tile: usize) -> bool {
+    bitmap[tile / 8] & (1 << (tile % 8)) != 0
+}
+
+/// Tiles set in `bitmap`, in index order.
+pub fn tiles(bitmap: &TileBitmap) -> impl Iterator<Item = usize> + '_ {
+    (0..TILE_CHECKSUM_COUNT).filter(|&t| has_tile(bitmap, t))
+}
+
+/// Every tile under the viewports, or all tiles without any.
+pub fn tracked_tiles(viewports: &[PixelRect]) -> TileBitmap {
+    if viewports.is_empty() {
+        return [0xff; SUBSCRIBE_BITMAP_LEN];
+    }
+    let mut bitmap = [0u8; SUBSCRIBE_BITMAP_LEN];
+    for rect in viewports {
+        rect.add_tiles(&mut bitmap);
+    }
+    bitmap
+}
+
+/// The tracked tiles of the canvas as of snapshot `seq`. Pixels outside them
+/// are kept at 0 and never looked at.
+pub struct TileMirror {
+    tracked: TileBitmap,
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+    seq: u64,
+}
+
+impl TileMirror {
+    /// A mirror of `refresh`, which should carry every tile of `tracked`;
+    /// tiles it leaves out are tracked no more (past the canvas edge).
+    pub fn new(tracked: &TileBitmap, refresh: &TileRefresh) -> Self {
+        let mut mirror = Self {
+            tracked: [0; SUBSCRIBE_BITMAP_LEN],
+            width: refresh.width,
+            height: refresh.height,
+            pixels: vec![0; refresh.width as usize * refresh.height as usize],
+            seq: refresh.seq,
+        };
+        for (byte, (&wanted, &carried)) in mirror
+            .tracked
+            .iter_mut()
+            .zip(tracked.iter().zip(&refresh.tiles))
+        {
+            *byte = wanted & carried;
+        }
+        mirror.apply_refresh(refresh);
+        mirror
+    }
+
+    /// Snapshot the mirror is at.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn tracked(&self) -> &TileBitmap {
+        &self.tracked
+    }
+
+    /// Applies a CATCHUP reply taken from the mirror's own snapshot.
+    pub fn apply_catchup(&mut self, catchup: &Catchup) {
+        let (size, tiles_x) = (SUBSCRIBE_TILE_SIZE as usize, SUBSCRIBE_TILES_X as usize);
+        for &(index, color) in &catchup.pixels {
+            let (x, y) = (
+                index as usize % self.width as usize,
+                index as usize / self.width as usize,
+            );
+            if has_tile(&self.tracked, (y / size) * tiles_x + x / size)
+                && let Some(pixel) = self.pixels.get_mut(index as usize)
+            {
+                *pixel = color;
+            }
+        }
+        self.seq = catchup.seq;
+    }
+
+    /// Copies in the tiles a TILE_REFRESH reply carries. A reply from
+    /// another snapshot than the mirror's must carry every tracked tile.
+    pub fn apply_refresh(&mut self, refresh: &TileRefresh) {
+        for (tile, pixels) in refresh.tiles() {
+            let rect = PixelRect::of_tile(tile, self.width, self.height);
+            let row_len = (rect.x1 - rect.x0) as usize;
+            if row_len == 0 {
+                continue;
+            }
+            for (y, row) in (rect.y0..rect.y1).zip(pixels.chunks_exact(row_len)) {
+                let start = y as usize * self.width as usize + rect.x0 as usize;
+                self.pixels[start..start + row_len].copy_from_slice(row);
+            }
+        }
+        self.seq = refresh.seq;
+    }
+
+    /// Tracked tiles whose pixels do not match `checksums`, which must be
+    /// those of the mirror's snapshot.
+    pub fn mismatches(&self, checksums: &TileChecksums) -> Result<TileBitmap, String> {
+        if checksums.seq != self.seq {
+            return Err(format!(
+                "checksums of snapshot {} against a mirror at {}",
+                checksums.seq, self.seq
+            ));
+        }
+        let mut bad = [0u8; SUBSCRIBE_BITMAP_LEN];
+        for tile in tiles(&self.tracked) {
+            if canvas_tile_checksum(&self.pixels, self.width, self.height, tile)
+                != checksums.checksums[tile]
+            {
+                bad[tile / 8] |= 1 << (tile % 8);
+            }
+        }
+        Ok(bad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::control::{
+        decode_tile_checksums, decode_tile_refresh, encode_tile_checksums, encode_tile_refresh,
+    };
+    use protocol::rle::{rle_encode, rle_max_len};
+
+    const WIDTH: u16 = 200;
+    const HEIGHT: u16 = 130;
+
+    /// What the server would answer for `canvas` at `seq`.
+    struct Server {
+        canvas: Vec<u8>,
+        seq: u64,
+    }
+
+    impl Server {
+        fn checksums(&self) -> TileChecksums {
+            let mut checksums = [0u32; TILE_CHECKSUM_COUNT];
+            for (tile, checksum) in checksums.iter_mut().enumerate() {
+                *checksum = canvas_tile_checksum(&self.canvas, WIDTH, HEIGHT, tile);
+            }
+            let mut wire = Vec::new();
+            encode_tile_checksums(self.seq, &checksums, &mut wire);
+            decode_tile_checksums(&wire).unwrap()
+        }
+
+        fn refresh(&self, wanted: &TileBitmap) -> TileRefresh {
+            let mut carried = [0u8; SUBSCRIBE_BITMAP_LEN];
+            let mut pixels = Vec::new();
+            for tile in tiles(wanted) {
+                let rect = PixelRect::of_tile(tile, WIDTH, HEIGHT);
+                if rect.area() == 0 {
+                    continue;
+                }
+                carried[tile / 8] |= 1 << (tile % 8);
+                for y in rect.y0..rect.y1 {
+                    let start = y as usize * WIDTH as usize;
+                    pixels.extend_from_slice(
+                        &self.canvas[start + rect.x0 as usize..start + rect.x1 as usize],
+                    );
+                }
+            }
+            let mut rle = vec![0; rle_max_len(pixels.len())];
+            let len = rle_encode(&pixels, &mut rle);
+            let mut wire = Vec::new();
+            encode_tile_refresh(self.seq, WIDTH, HEIGHT, &carried, &rle[..len], &mut wire);
+            decode_tile_refresh(&wire).unwrap()
+        }
+
+        fn paint(&mut self, writes: &[(u16, u16, u8)]) -> Catchup {
+            self.seq += 1;
+            let mut pixels = Vec::new();
+            for &(x, y, color) in writes {
+                let index = y as u32 * WIDTH as u32 + x as u32;
+                self.canvas[index as usize] = color;
+                pixels.push((index, color));
+            }
+            Catchup {
+                seq: self.seq,
+                pixels,
+            }
+        }
+    }
+
+    fn pixel(mirror: &mut TileMirror, x: u16, y: u16) -> &mut u8 {
+        &mut mirror.pixels[y as usize * WIDTH as usize + x as usize]
+    }
+
+    fn server() -> Server {
+        Server {
+            canvas: (0..WIDTH as usize * HEIGHT as usize)
+                .map(|i| (i / 7 % 5) as u8)
+                .collect(),
+            seq: 40,
+        }
+    }
+
+    #[test]
+    fn test_tracked_tiles() {
+        let viewport = PixelRect {
+            x0: 60,
+            y0: 0,
+            x1: 70,
+            y1: 70,
+        };
+        let tracked = tracked_tiles(&[viewport]);
+        assert_eq!(tiles(&tracked).collect::<Vec<_>>(), vec![0, 1, 16, 17]);
+        assert_eq!(tiles(&tracked_tiles(&[])).count(), TILE_CHECKSUM_COUNT);
+    }
+
+    #[test]
+    fn test_corrupted_tile_is_found_and_refreshed() {
+        let mut server = server();
+        // Tiles 1 and 2 of row 0, and tile 3, clipped to 8 columns.
+        let tracked = tracked_tiles(&[PixelRect {
+            x0: 64,
+            y0: 0,
+            x1: 200,
+            y1: 64,
+        }]);
+        let mut mirror = TileMirror::new(&tracked, &server.refresh(&tracked));
+        assert_eq!(tiles(mirror.tracked()).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(
+            mirror.mismatches(&server.checksums()),
+            Ok([0; SUBSCRIBE_BITMAP_LEN])
+        );
+
+        // Writes inside and outside the tracked tiles; the mirror follows.
+        let catchup = server.paint(&[(70, 3, 9), (5, 5, 9), (199, 63, 8), (100, 100, 7)]);
+        mirror.apply_catchup(&catchup);
+        assert_eq!(mirror.seq(), 41);
+        assert_eq!(*pixel(&mut mirror, 199, 63), 8);
+        assert_eq!(
+            mirror.mismatches(&server.checksums()),
+            Ok([0; SUBSCRIBE_BITMAP_LEN])
+        );
+
+        // A pixel of tile 2 goes bad, and an update to tile 3 is lost.
+        *pixel(&mut mirror, 150, 20) = 4;
+        server.paint(&[(195, 10, 6)]);
+        mirror.apply_catchup(&Catchup {
+            seq: 42,
+            pixels: Vec::new(),
+        });
+        let bad = mirror.mismatches(&server.checksums()).unwrap();
+        assert_eq!(tiles(&bad).collect::<Vec<_>>(), vec![2, 3]);
+
+        // Refreshing just those tiles repairs the mirror.
+        mirror.apply_refresh(&server.refresh(&bad));
+        assert_eq!(*pixel(&mut mirror, 195, 10), 6);
+        assert_eq!(
+            mirror.mismatches(&server.checksums()),
+            Ok([0; SUBSCRIBE_BITMAP_LEN])
+        );
+
+        // Checksums of another snapshot prove nothing.
+        server.paint(&[]);
+        assert!(mirror.mismatches(&server.checksums()).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::trace::{EventKind, TraceEvent};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What replay needs from a connection; implemented by quinn and by test mocks.
+pub trait ReplayConn {
+    fn send(&self, payload: &[u8]) -> bool;
+    fn close(&self);
+}
+
+impl ReplayConn for quinn::Connection {
+    fn send(&self, payload: &[u8]) -> bool {
+        self.send_datagram(bytes::Bytes::copy_from_slice(payload))
+            .is_ok()
+    }
+
+    fn close(&self) {
+        crate::close_with(self, protocol::close::AppCloseCode::ClientExit);
+    }
+}
+
+/// Recorded vs replayed outcomes. Pixel receipts are not recorded, so
+/// divergence is tracked at the connection level only.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub users: usize,
+    pub recorded_connects: usize,
+    pub recorded_connect_failures: usize,
+    pub replayed_connects: usize,
+    pub replayed_connect_failures: usize,
+    /// Attempts whose replayed outcome differs from the recorded one.
+    pub connect_divergence: usize,
+    pub recorded_datagrams: usize,
+    pub sent_datagrams: usize,
+    pub send_failures: usize,
+    /// Datagrams not sent because the replayed connection was not open.
+    pub skipped_datagrams: usize,
+    /// Worst delay between a datagram's scheduled and actual send time.
+    pub max_lateness_us: u64,
+}
+
+impl ReplayReport {
+    pub fn merge(&mut self, other: &ReplayReport) {
+        self.users += other.users;
+        self.recorded_connects += other.recorded_connects;
+        self.recorded_connect_failures += other.recorded_connect_failures;
+        self.replayed_connects += other.replayed_connects;
+        self.replayed_connect_failures += other.replayed_connect_failures;
+        self.connect_divergence += other.connect_divergence;
+        self.recorded_datagrams += other.recorded_datagrams;
+        self.sent_datagrams += other.sent_datagrams;
+        self.send_failures += other.send_failures;
+        self.skipped_datagrams += other.skipped_datagrams;
+        self.max_lateness_us = self.max_lateness_us.max(other.max_lateness_us);
+    }
+}
+
+pub fn group_by_user(events: Vec<TraceEvent>) -> BTreeMap<u32, Vec<TraceEvent>> {
+    let mut users: BTreeMap<u32, Vec<TraceEvent>> = BTreeMap::new();
+    for ev in events {
+        users.entry(ev.user).or_default().push(ev);
+    }
+    for timeline in users.values_mut() {
+        timeline.sort_by_key(|ev| ev.offset_us);
+    }
+    users
+}
+
+pub fn scheduled_at(start: Instant, offset_us: u64, speed: f64) -> Instant {
+    start + Duration::from_secs_f64(offset_us as f64 / 1e6 / speed)
+}
+
+/// Re-executes one simulated user's timeline against a fresh connection,
+/// keeping the recorded relative timing scaled by `speed`.
+pub async fn replay_user<C, F, Fut>(
+    events: &[TraceEvent],
+    start: Instant,
+    speed: f64,
+    mut connect: F,
+) -> ReplayReport
+where
+    C: ReplayConn,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<C>>,
+{
+    let mut report = ReplayReport {
+        users: 1,
+        ..Default::default()
+    };
+    let mut conn: Option<C> = None;
+
+    for ev in events {
+        let at = scheduled_at(start, ev.offset_us, speed);
+        tokio::time::sleep_until(at).await;
+
+        match ev.kind {
+            EventKind::Connect | EventKind::ConnectFailed => {
+                let recorded_ok = ev.kind == EventKind::Connect;
+                if recorded_ok {
+                    report.recorded_connects += 1;
+                } else {
+                    report.recorded_connect_failures += 1;
+                }
+                conn = connect().await;
+                if conn.is_some() {
+                    report.replayed_connects += 1;
+                } else {
+                    report.replayed_connect_failures += 1;
+                }
+                if conn.is_some() != recorded_ok {
+                    report.connect_divergence += 1;
+                }
+            }
+            EventKind::Datagram => {
+                report.recorded_datagrams += 1;
+                match &conn {
+                    Some(c) => {
+                        let lateness = Instant::now().saturating_duration_since(at);
+                        report.max_lateness_us =
+                            report.max_lateness_us.max(lateness.as_micros() as u64);
+                        if c.send(&ev.payload) {
+                            report.sent_datagrams += 1;
+                        } else {
+                            report.send_failures += 1;
+                        }
+                    }
+                    None => report.skipped_datagrams += 1,
+                }
+            }
+            EventKind::Disconnect => {
+                if let Some(c) = conn.take() {
+                    c.close();
+                }
+            }
+        }
+    }
+
+    if let Some(c) = conn.take() {
+        c.close();
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{TraceRecorder, read_trace};
+    use std::sync::{Arc, Mutex};
+
+    type SendLog = Arc<Mutex<Vec<(Duration, Vec<u8>)>>>;
+
+    struct MockConn {
+        start: Instant,
+        sends: SendLog,
+    }
+
+    impl ReplayConn for MockConn {
+        fn send(&self, payload: &[u8]) -> bool {
+            self.sends
+                .lock()
+                .unwrap()
+                .push((self.start.elapsed(), payload.to_vec()));
+            true
+        }
+
+        fn close(&self) {}
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay_preserves_timing() {
+        let path = std::env::temp_dir().join(format!("canvas-trace-{}.bin", std::process::id()));
+
+        // Record a tiny run: one user connects, sends three pixels 40 ms apart, leaves.
+        {
+            let recorder = TraceRecorder::create(&path).unwrap();
+            recorder.record(7, EventKind::Connect, &[]);
+            for i in 0..3u8 {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                recorder.record(7, EventKind::Datagram, &[i; 5]);
+            }
+            recorder.record(7, EventKind::Disconnect, &[]);
+            recorder.record(8, EventKind::ConnectFailed, &[]);
+        }
+        // Dropping the recorder lets the writer thread flush and exit.
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            if let Ok(read) = read_trace(&path)
+                && read.len() == 6
+            {
+                events = read;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(events.len(), 6);
+
+        let users = group_by_user(events);
+        let recorded: Vec<u64> = users[&7]
+            .iter()
+            .filter(|e| e.kind == EventKind::Datagram)
+            .map(|e| e.offset_us)
+            .collect();
+
+        for speed in [1.0, 2.0] {
+            let start = Instant::now();
+            let sends = Arc::new(Mutex::new(Vec::new()));
+            let mut report = ReplayReport::default();
+            for timeline in users.values() {
+                let sends = sends.clone();
+                let user_report = replay_user(timeline, start, speed, || {
+                    let sends = sends.clone();
+                    async move { Some(MockConn { start, sends }) }
+                })
+                .await;
+                report.merge(&user_report);
+            }
+
+            let sends = sends.lock().unwrap();
+            assert_eq!(sends.len(), 3);
+            for (i, ((at, payload), offset_us)) in sends.iter().zip(&recorded).enumerate() {
+                assert_eq!(payload, &vec![i as u8; 5]);
+                let expected = Duration::from_secs_f64(*offset_us as f64 / 1e6 / speed);
+                let error = at.abs_diff(expected);
+                assert!(
+                    error < Duration::from_millis(25),
+                    "speed {} send {} off by {:?}",
+                    speed,
+                    i,
+                    error
+                );
+            }
+            assert_eq!(report.users, 2);
+            assert_eq!(report.recorded_datagrams, 3);
+            assert_eq!(report.sent_datagrams, 3);
+            // User 8 failed to connect when recorded but the mock always succeeds.
+            assert_eq!(report.connect_divergence, 1);
+        }
+    }
+}
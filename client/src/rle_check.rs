@@ -0,0 +1,243 @@
+//! Full snapshot check for observers (`--verify-rle`). A canvas that decodes
+//! shifted could be the server's encoder, the chunking or the decoder; this
+//! rules out the decoder. Observers negotiate FEC, so each full snapshot
+//! arrives framed with its length and is put back together whole (see
+//! `protocol::fec`). Every complete snapshot is then decoded three times:
+//!
+//! - by `protocol::rle::rle_decode_reference`, the format taken literally;
+//! - by `rle_decode`, which rejects what the encoder could not produce;
+//! - by `rle_decode_lenient`, which renders whatever arrived.
+//!
+//! Agreement counts in the summary's `"rle"` object as `verified`. A
+//! snapshot the strict decoder rejects, or where either output differs
+//! from the reference, counts as a divergence: the first divergent pixel
+//! is logged with the bytes around it in the stream and in each output,
+//! and the stream is written to `<observer-dir>/rle_<id>_<n>_<snapshot>.rle`
+//! (the first [`MAX_REPRODUCERS`] per observer) to replay offline.
+
+use crate::metrics::LoadMetrics;
+use protocol::fec::{self, FecAssembler};
+use protocol::rle::{RleError, rle_decode, rle_decode_lenient, rle_decode_reference};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Reproducer files one observer writes at most.
+pub const MAX_REPRODUCERS: usize = 8;
+
+/// Bytes shown on each side of a divergence.
+const CONTEXT: usize = 8;
+
+/// Decodes `rle` with all three decoders. The number of pixels if they
+/// agree, or else a report of where they first part.
+pub fn check(rle: &[u8]) -> Result<usize, String> {
+    let reference = rle_decode_reference(rle);
+    // One pixel of room, so a decoder that writes too much shows it.
+    let mut strict = vec![0; reference.len() + 1];
+    match rle_decode(rle, &mut strict) {
+        Ok(n) => strict.truncate(n),
+        Err(e) => {
+            let (RleError::Truncated { offset }
+            | RleError::ZeroRun { offset }
+            | RleError::Overflow { offset, .. }) = e;
+            return Err(format!(
+                "rle_decode rejected a {}-byte snapshot: {}\n  rle {}",
+                rle.len(),
+                e,
+                window(rle, offset)
+            ));
+        }
+    }
+    let mut lenient = vec![0; reference.len() + 1];
+    let n = rle_decode_lenient(rle, &mut lenient);
+    lenient.truncate(n);
+
+    for (decoder, pixels) in [("rle_decode", &strict), ("rle_decode_lenient", &lenient)] {
+        if let Some(at) = first_divergence(&reference, pixels) {
+            let mut report = format!(
+                "{} diverges from the reference at pixel {} ({} vs {} pixels)",
+                decoder,
+                at,
+                pixels.len(),
+                reference.len()
+            );
+            let _ = write!(
+                report,
+                "\n  rle       {}\n  reference {}\n  {:<9} {}",
+                window(rle, pair_for_pixel(rle, at)),
+                window(&reference, at),
+                decoder.trim_start_matches("rle_decode_"),
+                window(pixels, at)
+            );
+            return Err(report);
+        }
+    }
+    Ok(reference.len())
+}
+
+/// First index where `a` and `b` differ, counting a length mismatch as a
+/// difference at the end of the shorter one.
+pub fn first_divergence(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(at) => Some(at),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Byte offset of the pair that produces pixel `pixel`; the last whole
+/// pair if the stream ends first.
+pub fn pair_for_pixel(rle: &[u8], pixel: usize) -> usize {
+    let mut produced = 0;
+    for (offset, pair) in (0..).step_by(2).zip(rle.chunks_exact(2)) {
+        produced += pair[0] as usize;
+        if produced > pixel {
+            return offset;
+        }
+    }
+    (rle.len() / 2).saturating_sub(1) * 2
+}
+
+/// `@start: xx xx [xx] xx`, the bytes of `bytes` around `at` in hex.
+fn window(bytes: &[u8], at: usize) -> String {
+    let start = at.saturating_sub(CONTEXT);
+    let end = (at + CONTEXT + 1).min(bytes.len());
+    let mut out = format!("@{}:", start);
+    for (i, byte) in bytes.iter().enumerate().take(end).skip(start) {
+        if i == at {
+            let _ = write!(out, " [{:02x}]", byte);
+        } else {
+            let _ = write!(out, " {:02x}", byte);
+        }
+    }
+    if at >= bytes.len() {
+        out.push_str(" [end]");
+    }
+    out
+}
+
+/// One observer's check: assembles its FEC datagrams and checks each
+/// complete snapshot.
+pub struct RleVerifier {
+    assembler: FecAssembler,
+    dir: PathBuf,
+    /// `<id>_<n>`, for log lines and reproducer names.
+    label: String,
+    reproducers: usize,
+}
+
+impl RleVerifier {
+    pub fn new(dir: PathBuf, label: String) -> Self {
+        Self {
+            assembler: FecAssembler::default(),
+            dir,
+            label,
+            reproducers: 0,
+        }
+    }
+
+    /// Takes any broadcast datagram; only FEC ones are looked at.
+    pub fn push(&mut self, datagram: &[u8], metrics: &LoadMetrics) {
+        let Some((header, payload)) = fec::decode_fec(datagram) else {
+            return;
+        };
+        let Some(snapshot) = self.assembler.push(&header, payload) else {
+            return;
+        };
+        let report = match check(snapshot) {
+            Ok(_) => {
+                metrics.rle_verified.add(1);
+                return;
+            }
+            Err(report) => report,
+        };
+        metrics.rle_divergences.add(1);
+        eprintln!(
+            "Observer {}: snapshot {}: {}",
+            self.label, header.snapshot, report
+        );
+        if self.reproducers < MAX_REPRODUCERS {
+            self.reproducers += 1;
+            let path = self
+                .dir
+                .join(format!("rle_{}_{}.rle", self.label, header.snapshot));
+            match std::fs::write(&path, snapshot) {
+                Ok(()) => eprintln!(
+                    "Observer {}: stream written to {}",
+                    self.label,
+                    path.display()
+                ),
+                Err(e) => eprintln!("Could not write {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::rle::{rle_encode, rle_max_len};
+
+    fn encode(pixels: &[u8]) -> Vec<u8> {
+        let mut rle = vec![0; rle_max_len(pixels.len())];
+        let len = rle_encode(pixels, &mut rle);
+        rle.truncate(len);
+        rle
+    }
+
+    #[test]
+    fn test_agreeing_decoders_pass() {
+        let pixels: Vec<u8> = (0..5_000).map(|i| (i / 300 % 7) as u8).collect();
+        assert_eq!(check(&encode(&pixels)), Ok(5_000));
+        assert_eq!(check(&[]), Ok(0));
+        assert_eq!(first_divergence(b"abc", b"abd"), Some(2));
+        assert_eq!(first_divergence(b"abc", b"ab"), Some(2));
+        assert_eq!(first_divergence(b"abc", b"abc"), None);
+    }
+
+    #[test]
+    fn test_corrupt_streams_are_reported_where_they_break() {
+        // 10 × 1, 10 × 2, 10 × 3: pixel 15 comes from the pair at byte 2.
+        let rle = [10, 1, 10, 2, 10, 3];
+        assert_eq!(pair_for_pixel(&rle, 15), 2);
+        assert_eq!(pair_for_pixel(&rle, 99), 4);
+
+        // An empty run: rle_decode refuses it, the reference skips it.
+        let report = check(&[10, 1, 0, 2, 10, 3]).unwrap_err();
+        assert!(report.starts_with("rle_decode rejected"), "{}", report);
+        assert!(report.contains("[00]"), "{}", report);
+
+        // A chunk cut mid-pair: the reference and lenient decoders agree,
+        // the strict one does not.
+        let report = check(&[10, 1, 10]).unwrap_err();
+        assert!(report.contains("@0: 0a 01 [0a]"), "{}", report);
+    }
+
+    #[test]
+    fn test_reproducer_is_written_on_divergence() {
+        let dir = std::env::temp_dir().join(format!("canvas-rle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = crate::targets::parse_target_spec("127.0.0.1:4433").unwrap();
+        let metrics = LoadMetrics::new("rle".to_string(), target);
+        let mut verifier = RleVerifier::new(dir.clone(), "rle_0".to_string());
+
+        let good = encode(&[4; 3_000]);
+        let mut bad = good.clone();
+        bad.push(7);
+        for (snapshot, stream) in [(1, &good), (2, &bad)] {
+            let (mut wire, mut ends) = (Vec::new(), Vec::new());
+            fec::encode_fec(snapshot, stream, 8, &mut wire, &mut ends);
+            let mut start = 0;
+            // Plain chunks of other broadcasts in between are ignored.
+            verifier.push(&[3, 9, 0, 0], &metrics);
+            for end in ends {
+                verifier.push(&wire[start..end], &metrics);
+                start = end;
+            }
+        }
+        assert_eq!(metrics.rle_verified.get(), 1);
+        assert_eq!(metrics.rle_divergences.get(), 1);
+        let path = dir.join("rle_rle_0_2.rle");
+        assert_eq!(std::fs::read(&path).unwrap(), bad);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,432 @@
+//! Load scenarios (`--scenario`): a population mix of user personas.
+//!
+//! Only the TOML subset scenario files need is understood: `[[persona]]`
+//! tables of `key = value` lines with string or integer values and
+//! `#` comments. Keys a persona leaves out take the value of the matching
+//! CLI flag, so a file only has to spell out what differs. A `seed` line
+//! before the first persona fixes the run's randomness like `--seed`,
+//! which takes precedence.
+//!
+//! ```toml
+//! seed = 42             # optional
+//!
+//! [[persona]]
+//! name = "lurker"
+//! share = 70            # percent of --clients; shares must add up to 100
+//! role = "reader"       # writer | reader
+//! arrival = "uniform"   # uniform | exponential
+//! max_conn_jitter = 10000
+//! min_pixel_wait = 1000
+//! max_pixel_wait = 10000
+//! session_ms = 0        # > 0: leave after this long, then arrive again
+//! pattern = "fixed"     # fixed | random
+//! ```
+
+use protocol::pixel::{CANVAS_HEIGHT, CANVAS_WIDTH, PIXEL_LEN, Pixel};
+use rand::Rng;
+use std::path::Path;
+
+/// Pixel every `fixed` writer paints, as (x, y, color).
+pub const FIXED_PIXEL: (u16, u16, u8) = (100, 200, 255);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Sends pixels and receives broadcasts.
+    Writer,
+    /// Only receives broadcasts.
+    Reader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// Connect after a delay drawn uniformly from `[0, max_conn_jitter)`.
+    Uniform,
+    /// Front-loaded: exponential delay with mean `max_conn_jitter / 4`,
+    /// capped at `max_conn_jitter`.
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Always [`FIXED_PIXEL`].
+    Fixed,
+    /// Uniform position on the canvas (protocol::pixel), uniform color.
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Persona {
+    pub name: String,
+    /// Percentage of the simulated users.
+    pub share: u32,
+    pub role: Role,
+    pub arrival: Arrival,
+    pub max_conn_jitter: u64,
+    pub min_pixel_wait: u64,
+    pub max_pixel_wait: u64,
+    /// Churn: leave after this many ms and arrive again; 0 stays until shutdown.
+    pub session_ms: u64,
+    pub pattern: Pattern,
+}
+
+impl Persona {
+    pub fn connect_delay(&self, rng: &mut impl Rng) -> u64 {
+        if self.max_conn_jitter == 0 {
+            return 0;
+        }
+        match self.arrival {
+            Arrival::Uniform => rng.gen_range(0..self.max_conn_jitter),
+            Arrival::Exponential => {
+                let mean = self.max_conn_jitter as f64 / 4.0;
+                let delay = -mean * (1.0 - rng.r#gen::<f64>()).ln();
+                (delay as u64).min(self.max_conn_jitter)
+            }
+        }
+    }
+
+    pub fn pixel_wait(&self, rng: &mut impl Rng) -> u64 {
+        if self.min_pixel_wait >= self.max_pixel_wait {
+            self.min_pixel_wait
+        } else {
+            rng.gen_range(self.min_pixel_wait..self.max_pixel_wait)
+        }
+    }
+
+    /// Wire payload of the next pixel (see protocol::pixel).
+    pub fn pixel(&self, rng: &mut impl Rng) -> [u8; PIXEL_LEN] {
+        let (x, y, color) = match self.pattern {
+            Pattern::Fixed => FIXED_PIXEL,
+            Pattern::Random => (
+                rng.gen_range(0..CANVAS_WIDTH),
+                rng.gen_range(0..CANVAS_HEIGHT),
+                rng.r#gen(),
+            ),
+        };
+        let mut payload = [0u8; PIXEL_LEN];
+        Pixel { x, y, color }.to_wire(&mut payload);
+        payload
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    pub personas: Vec<Persona>,
+    /// Seed the file asks for; not part of the hash, the summary records
+    /// the seed a run used on its own.
+    pub seed: Option<u64>,
+}
+
+impl Scenario {
+    /// The behavior without `--scenario`: everyone is `base`.
+    pub fn single(base: Persona) -> Self {
+        Self {
+            personas: vec![Persona { share: 100, ..base }],
+            seed: None,
+        }
+    }
+
+    pub fn load(path: &Path, base: &Persona) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read scenario {}: {}", path.display(), e))?;
+        Self::parse(&text, base).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str, base: &Persona) -> Result<Self, String> {
+        let mut personas: Vec<Persona> = Vec::new();
+        let mut seed = None;
+        let mut seen: Vec<&str> = Vec::new();
+        let mut has_share = true;
+
+        for (n, raw) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[persona]]" {
+                if !has_share {
+                    return Err(format!("line {}: previous persona has no share", line_no));
+                }
+                personas.push(Persona {
+                    name: format!("persona{}", personas.len()),
+                    share: 0,
+                    ..base.clone()
+                });
+                seen.clear();
+                has_share = false;
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| format!("line {}: expected `key = value`", line_no))?;
+            let Some(persona) = personas.last_mut() else {
+                if key != "seed" {
+                    return Err(format!("line {}: `{}` outside [[persona]]", line_no, key));
+                }
+                if seed.is_some() {
+                    return Err(format!("line {}: duplicate key `seed`", line_no));
+                }
+                seed = Some(parse_int(value).map_err(|e| format!("line {}: {}", line_no, e))?);
+                continue;
+            };
+            if seen.contains(&key) {
+                return Err(format!("line {}: duplicate key `{}`", line_no, key));
+            }
+            seen.push(key);
+            set_key(persona, key, value).map_err(|e| format!("line {}: {}", line_no, e))?;
+            has_share |= key == "share";
+        }
+
+        if personas.is_empty() {
+            return Err("scenario defines no [[persona]]".to_string());
+        }
+        if !has_share {
+            return Err("last persona has no share".to_string());
+        }
+        let total: u32 = personas.iter().map(|p| p.share).sum();
+        if total != 100 {
+            return Err(format!("persona shares add up to {}, not 100", total));
+        }
+        for (i, p) in personas.iter().enumerate() {
+            if p.share == 0 {
+                return Err(format!("persona `{}` has a zero share", p.name));
+            }
+            if personas[..i].iter().any(|q| q.name == p.name) {
+                return Err(format!("persona name `{}` is used twice", p.name));
+            }
+        }
+        Ok(Self { personas, seed })
+    }
+
+    /// Every resolved persona, one per line; independent of the file's
+    /// layout, comments and of which keys were left to the CLI defaults.
+    fn canonical(&self) -> String {
+        self.personas.iter().map(|p| format!("{:?}\n", p)).collect()
+    }
+
+    /// FNV-1a of the resolved scenario. Two runs with the same hash drove
+    /// the same population, whether it came from a file or from flags.
+    pub fn hash(&self) -> u64 {
+        self.canonical()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |h, b| {
+                (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    /// Users per persona for a population of `total`, by largest remainder:
+    /// counts add up to `total` and each is within one of its exact share.
+    pub fn allocate(&self, total: usize) -> Vec<usize> {
+        let exact: Vec<u64> = self
+            .personas
+            .iter()
+            .map(|p| total as u64 * p.share as u64)
+            .collect();
+        let mut counts: Vec<usize> = exact.iter().map(|e| (e / 100) as usize).collect();
+        let mut by_remainder: Vec<usize> = (0..counts.len()).collect();
+        // Stable: ties go to the persona declared first.
+        by_remainder.sort_by_key(|&i| std::cmp::Reverse(exact[i] % 100));
+        let missing = total - counts.iter().sum::<usize>();
+        for &i in &by_remainder[..missing] {
+            counts[i] += 1;
+        }
+        counts
+    }
+
+    /// Persona index of every user, interleaved so that any prefix of the
+    /// ramp-up already follows the mix.
+    pub fn assignments(&self, total: usize) -> Vec<usize> {
+        let counts = self.allocate(total);
+        let mut given = vec![0usize; counts.len()];
+        (0..total)
+            .map(|user| {
+                // Persona furthest behind its pro-rata count at this point.
+                let pick = (0..counts.len())
+                    .filter(|&p| given[p] < counts[p])
+                    .max_by_key(|&p| {
+                        let due = (counts[p] * (user + 1)) as i128;
+                        let had = (given[p] * total) as i128;
+                        (due - had, std::cmp::Reverse(p))
+                    })
+                    .unwrap();
+                given[pick] += 1;
+                pick
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+pub(crate) fn parse_string(value: &str) -> Result<&str, String> {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, got `{}`", value))
+}
+
+pub(crate) fn parse_int(value: &str) -> Result<u64, String> {
+    value
+        .replace('_', "")
+        .parse()
+        .map_err(|_| format!("expected a non-negative integer, got `{}`", value))
+}
+
+fn set_key(p: &mut Persona, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "name" => p.name = parse_string(value)?.to_string(),
+        "share" => {
+            p.share = parse_int(value)?
+                .try_into()
+                .map_err(|_| format!("share `{}` is out of range", value))?
+        }
+        "role" => {
+            p.role = match parse_string(value)? {
+                "writer" => Role::Writer,
+                "reader" => Role::Reader,
+                other => return Err(format!("unknown role `{}`", other)),
+            }
+        }
+        "arrival" => {
+            p.arrival = match parse_string(value)? {
+                "uniform" => Arrival::Uniform,
+                "exponential" => Arrival::Exponential,
+                other => return Err(format!("unknown arrival `{}`", other)),
+            }
+        }
+        "pattern" => {
+            p.pattern = match parse_string(value)? {
+                "fixed" => Pattern::Fixed,
+                "random" => Pattern::Random,
+                other => return Err(format!("unknown pattern `{}`", other)),
+            }
+        }
+        "max_conn_jitter" => p.max_conn_jitter = parse_int(value)?,
+        "min_pixel_wait" => p.min_pixel_wait = parse_int(value)?,
+        "max_pixel_wait" => p.max_pixel_wait = parse_int(value)?,
+        "session_ms" => p.session_ms = parse_int(value)?,
+        _ => return Err(format!("unknown persona key `{}`", key)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Persona {
+        Persona {
+            name: "default".to_string(),
+            share: 100,
+            role: Role::Writer,
+            arrival: Arrival::Uniform,
+            max_conn_jitter: 10000,
+            min_pixel_wait: 1000,
+            max_pixel_wait: 10000,
+            session_ms: 0,
+            pattern: Pattern::Fixed,
+        }
+    }
+
+    #[test]
+    fn test_parse_scenario() {
+        let default = Scenario::parse(include_str!("../scenarios/default.toml"), &base()).unwrap();
+        assert_eq!(default, Scenario::single(base()));
+        assert_eq!(default.hash(), Scenario::single(base()).hash());
+        Scenario::parse(include_str!("../scenarios/mixed.toml"), &base()).unwrap();
+
+        let mixed = Scenario::parse(
+            r#"
+            # comment
+            [[persona]]
+            name = "lurker"   # trailing comment
+            share = 70
+            role = "reader"
+
+            [[persona]]
+            name = "bot #1"
+            share = 30
+            arrival = "exponential"
+            session_ms = 60_000
+            pattern = "random"
+            "#,
+            &base(),
+        )
+        .unwrap();
+        assert_eq!(mixed.personas.len(), 2);
+        assert_eq!(mixed.personas[0].role, Role::Reader);
+        assert_eq!(mixed.personas[0].min_pixel_wait, 1000);
+        assert_eq!(mixed.personas[1].name, "bot #1");
+        assert_eq!(mixed.personas[1].session_ms, 60_000);
+        assert_ne!(mixed.hash(), default.hash());
+        assert_eq!(mixed.seed, None);
+
+        let seeded = Scenario::parse(
+            "seed = 1_234\n[[persona]]\nname = \"default\"\nshare = 100",
+            &base(),
+        )
+        .unwrap();
+        assert_eq!(seeded.seed, Some(1234));
+        assert_eq!(seeded.hash(), default.hash());
+
+        let err = |text: &str| Scenario::parse(text, &base()).unwrap_err();
+        assert!(err("").contains("no [[persona]]"));
+        assert!(err("share = 100").contains("outside"));
+        assert!(err("seed = 1\nseed = 2\n[[persona]]\nshare = 100").contains("duplicate"));
+        assert!(err("seed = -1\n[[persona]]\nshare = 100").contains("non-negative"));
+        assert!(err("[[persona]]\nshare = 100\nseed = 1").contains("unknown persona key"));
+        assert!(err("[[persona]]\nshare = 60").contains("not 100"));
+        assert!(err("[[persona]]\nname = \"a\"").contains("no share"));
+        assert!(err("[[persona]]\nshare = 100\nshare = 100").contains("duplicate"));
+        assert!(err("[[persona]]\nshare = 100\nviewport = \"pan\"").contains("unknown"));
+        assert!(err("[[persona]]\nshare = 100\nrole = \"admin\"").contains("unknown role"));
+        assert!(err("[[persona]]\nshare = 100\nname = lurker").contains("quoted"));
+        assert!(
+            err("[[persona]]\nname = \"a\"\nshare = 50\n[[persona]]\nname = \"a\"\nshare = 50")
+                .contains("used twice")
+        );
+    }
+
+    #[test]
+    fn test_mix_allocation() {
+        let mut scenario = Scenario::single(base());
+        scenario.personas = [50, 30, 20]
+            .iter()
+            .enumerate()
+            .map(|(i, &share)| Persona {
+                name: format!("p{}", i),
+                share,
+                ..base()
+            })
+            .collect();
+
+        // 3.5 / 2.1 / 1.4: the largest remainder (0.5) takes the extra user.
+        assert_eq!(scenario.allocate(7), vec![4, 2, 1]);
+        assert_eq!(scenario.allocate(100), vec![50, 30, 20]);
+        assert_eq!(scenario.allocate(0), vec![0, 0, 0]);
+        for total in [1, 3, 99, 1001] {
+            assert_eq!(scenario.allocate(total).iter().sum::<usize>(), total);
+        }
+
+        let assigned = scenario.assignments(1000);
+        for (p, &want) in scenario.allocate(1000).iter().enumerate() {
+            assert_eq!(assigned.iter().filter(|&&a| a == p).count(), want);
+        }
+        // Every ramp-up prefix stays within one user of the mix.
+        for prefix in 1..=1000 {
+            let p0 = assigned[..prefix].iter().filter(|&&a| a == 0).count() as f64;
+            assert!((p0 - prefix as f64 * 0.5).abs() <= 1.0, "prefix {}", prefix);
+        }
+    }
+}
@@ -0,0 +1,965 @@
+//! Soak watcher (`--mode soak`): keeps an eye on a long load run so nobody
+//! has to read its CSVs the next morning.
+//!
+//! Every `interval_ms` it reads the rows the load clients appended to
+//! `<--metrics-dir>/*_data.csv` since its last look and, with `dashboard`
+//! set, scrapes the server's `/metrics`, then applies the [`Rules`]. When
+//! any fires it writes a diagnostic bundle to `<out_dir>/anomaly_<n>/`:
+//!
+//! - `anomalies.txt`: the rules that fired and why
+//! - each client CSV's header and last `bundle_rows` rows
+//! - `samples.csv`: the watcher's own last `bundle_rows` looks
+//! - `metrics.prom`: the newest scrape
+//! - `config.json`, `stats.json`, `thumb.png`: the server's CONFIG and
+//!   STATS dumps and a snapshot of the canvas, from the dashboard
+//!
+//! The run ends after `duration_s` (or on Ctrl-C) and the process exits
+//! with status 1 if any rule fired, 0 otherwise.
+//!
+//! `--soak <file>` uses the same TOML subset as scenarios, in one `[soak]`
+//! table; keys left out keep the defaults shown:
+//!
+//! ```toml
+//! [soak]
+//! dashboard = "10.0.0.1:8080"  # server --dashboard; none: client rules only
+//! interval_ms = 10000
+//! duration_s = 0               # 0: until Ctrl-C
+//! bundle_rows = 60
+//! out_dir = "/metrics/soak"    # default: <--metrics-dir>/soak
+//! active_sag_pct = 20          # 0 turns a rule off
+//! divergence = 1
+//! p99_slo_ms = 1000
+//! p99_intervals = 3
+//! capacity_rejections = 1
+//! worker_restarts = 1
+//! ```
+
+use crate::metrics::FIRST_BROADCAST_BUCKETS_MS;
+use crate::scenario::{parse_int, parse_string, strip_comment};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest a dashboard request may take before the look goes on without it.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Server counter whose increase means a connection was turned away
+/// because the server was full.
+const CAPACITY_REJECTIONS: &str = "canvas_worker_accept_capacity_rejections_total";
+
+/// When the watcher raises an anomaly. Integer thresholds of 0 and flags
+/// of 0 turn a rule off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rules {
+    /// Connections up, over every client and target, fell this many percent
+    /// below their peak so far. A client whose CSV stopped growing counts
+    /// as having none.
+    pub active_sag_pct: u64,
+    /// An observer's reference RLE decoder disagreed (`--verify-rle`).
+    pub divergence: bool,
+    /// The worst per-second tracer p99 (`--tier-token`) was above this for
+    /// `p99_intervals` looks in a row. Looks without tracers do not count
+    /// either way.
+    pub p99_slo_ms: u64,
+    pub p99_intervals: u64,
+    /// The server turned connections away at capacity since the last look.
+    pub capacity_rejections: bool,
+    /// A server counter went backwards: the server, or one of its workers,
+    /// started over.
+    pub worker_restarts: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            active_sag_pct: 20,
+            divergence: true,
+            p99_slo_ms: 1000,
+            p99_intervals: 3,
+            capacity_rejections: true,
+            worker_restarts: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakConfig {
+    /// `host:port` of the server's dashboard.
+    pub dashboard: Option<String>,
+    pub interval_ms: u64,
+    /// 0: until interrupted.
+    pub duration_s: u64,
+    /// Rows of each CSV kept for a bundle.
+    pub bundle_rows: usize,
+    pub out_dir: Option<PathBuf>,
+    pub rules: Rules,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            dashboard: None,
+            interval_ms: 10_000,
+            duration_s: 0,
+            bundle_rows: 60,
+            out_dir: None,
+            rules: Rules::default(),
+        }
+    }
+}
+
+impl SoakConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read soak config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut in_soak = false;
+        let mut seen: Vec<&str> = Vec::new();
+        for (n, raw) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                if line != "[soak]" || in_soak {
+                    return Err(format!("line {}: expected one [soak] table", line_no));
+                }
+                in_soak = true;
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| format!("line {}: expected `key = value`", line_no))?;
+            if !in_soak {
+                return Err(format!("line {}: `{}` outside [soak]", line_no, key));
+            }
+            if seen.contains(&key) {
+                return Err(format!("line {}: duplicate key `{}`", line_no, key));
+            }
+            seen.push(key);
+            config
+                .set_key(key, value)
+                .map_err(|e| format!("line {}: {}", line_no, e))?;
+        }
+
+        let rules = &config.rules;
+        if config.interval_ms == 0 {
+            return Err("interval_ms must be positive".to_string());
+        }
+        if rules.active_sag_pct > 100 {
+            return Err(format!(
+                "active_sag_pct {} is over 100",
+                rules.active_sag_pct
+            ));
+        }
+        // Tracer p99s are bucket bounds: the last one is all they can say.
+        let top = FIRST_BROADCAST_BUCKETS_MS[FIRST_BROADCAST_BUCKETS_MS.len() - 1];
+        if rules.p99_slo_ms >= top {
+            return Err(format!(
+                "p99_slo_ms {} must be below {} ms, the top latency bucket",
+                rules.p99_slo_ms, top
+            ));
+        }
+        if rules.p99_slo_ms > 0 && rules.p99_intervals == 0 {
+            return Err("p99_intervals must be positive".to_string());
+        }
+        Ok(config)
+    }
+
+    fn set_key(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let flag = |value: &str| match parse_int(value)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(format!("`{}` takes 0 or 1", key)),
+        };
+        let rules = &mut self.rules;
+        match key {
+            "dashboard" => self.dashboard = Some(parse_string(value)?.to_string()),
+            "interval_ms" => self.interval_ms = parse_int(value)?,
+            "duration_s" => self.duration_s = parse_int(value)?,
+            "bundle_rows" => self.bundle_rows = parse_int(value)? as usize,
+            "out_dir" => self.out_dir = Some(PathBuf::from(parse_string(value)?)),
+            "active_sag_pct" => rules.active_sag_pct = parse_int(value)?,
+            "divergence" => rules.divergence = flag(value)?,
+            "p99_slo_ms" => rules.p99_slo_ms = parse_int(value)?,
+            "p99_intervals" => rules.p99_intervals = parse_int(value)?,
+            "capacity_rejections" => rules.capacity_rejections = flag(value)?,
+            "worker_restarts" => rules.worker_restarts = flag(value)?,
+            _ => return Err(format!("unknown soak key `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+/// What one look at the run found; None where a source had nothing to say.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub active: Option<u64>,
+    pub divergences: Option<u64>,
+    /// Worst per-second tracer p99 since the previous look.
+    pub tracer_p99_ms: Option<u64>,
+    /// Cumulative server counters, by series (see [`parse_counters`]).
+    pub server: Option<BTreeMap<String, u64>>,
+}
+
+fn capacity_rejections(series: &BTreeMap<String, u64>) -> u64 {
+    series
+        .iter()
+        .filter(|(name, _)| name.starts_with(CAPACITY_REJECTIONS))
+        .map(|(_, &value)| value)
+        .sum()
+}
+
+impl Sample {
+    fn capacity_rejections(&self) -> Option<u64> {
+        self.server.as_ref().map(capacity_rejections)
+    }
+
+    /// `samples.csv` row.
+    fn csv_row(&self, ts: u64) -> String {
+        let cell = |v: Option<u64>| v.map_or(String::new(), |v| v.to_string());
+        format!(
+            "{},{},{},{},{}\n",
+            ts,
+            cell(self.active),
+            cell(self.divergences),
+            cell(self.tracer_p99_ms),
+            cell(self.capacity_rejections())
+        )
+    }
+}
+
+const SAMPLES_HEADER: &str = "timestamp,active,rle_divergences,tracer_p99_ms,capacity_rejections\n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub rule: &'static str,
+    pub detail: String,
+}
+
+/// The rule engine: state carried from look to look. A sag or a slow
+/// streak fires once and re-arms when the run recovers, so one long
+/// incident makes one bundle.
+pub struct Watch {
+    rules: Rules,
+    peak_active: u64,
+    sagging: bool,
+    slow_streak: u64,
+    divergences: u64,
+    server: Option<BTreeMap<String, u64>>,
+}
+
+impl Watch {
+    pub fn new(rules: Rules) -> Self {
+        Self {
+            rules,
+            peak_active: 0,
+            sagging: false,
+            slow_streak: 0,
+            divergences: 0,
+            server: None,
+        }
+    }
+
+    pub fn observe(&mut self, sample: &Sample) -> Vec<Anomaly> {
+        let rules = self.rules;
+        let mut fired = Vec::new();
+
+        if let Some(active) = sample.active {
+            self.peak_active = self.peak_active.max(active);
+            let floor = self.peak_active * (100 - rules.active_sag_pct) / 100;
+            if rules.active_sag_pct > 0 && active < floor {
+                if !self.sagging {
+                    fired.push(Anomaly {
+                        rule: "active-sag",
+                        detail: format!(
+                            "{} connections up, {}% below the peak of {}",
+                            active,
+                            100 - active * 100 / self.peak_active,
+                            self.peak_active
+                        ),
+                    });
+                }
+                self.sagging = true;
+            } else {
+                self.sagging = false;
+            }
+        }
+
+        if let Some(divergences) = sample.divergences {
+            if rules.divergence && divergences > self.divergences {
+                fired.push(Anomaly {
+                    rule: "divergence",
+                    detail: format!(
+                        "{} new RLE divergences ({} in all)",
+                        divergences - self.divergences,
+                        divergences
+                    ),
+                });
+            }
+            self.divergences = divergences;
+        }
+
+        match sample.tracer_p99_ms {
+            Some(p99) if rules.p99_slo_ms > 0 && p99 > rules.p99_slo_ms => {
+                self.slow_streak += 1;
+                if self.slow_streak == rules.p99_intervals {
+                    fired.push(Anomaly {
+                        rule: "p99-slo",
+                        detail: format!(
+                            "tracer p99 above {} ms for {} intervals in a row (now {} ms)",
+                            rules.p99_slo_ms, self.slow_streak, p99
+                        ),
+                    });
+                }
+            }
+            Some(_) => self.slow_streak = 0,
+            None => {}
+        }
+
+        if let Some(now) = &sample.server {
+            if let Some(before) = &self.server {
+                let went_back: Vec<&str> = before
+                    .iter()
+                    .filter(|&(name, &was)| now.get(name).is_none_or(|&is| is < was))
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                if rules.worker_restarts && !went_back.is_empty() {
+                    fired.push(Anomaly {
+                        rule: "worker-restart",
+                        detail: format!(
+                            "{} server counters went backwards or vanished, e.g. {}",
+                            went_back.len(),
+                            went_back[0]
+                        ),
+                    });
+                }
+                let (rejected, was) = (capacity_rejections(now), capacity_rejections(before));
+                if rules.capacity_rejections && rejected > was {
+                    fired.push(Anomaly {
+                        rule: "capacity",
+                        detail: format!(
+                            "{} connections rejected at capacity since the last look",
+                            rejected - was
+                        ),
+                    });
+                }
+            }
+            self.server = Some(now.clone());
+        }
+        fired
+    }
+}
+
+/// `_total` series of a Prometheus text exposition, by name and labels.
+pub fn parse_counters(text: &str) -> BTreeMap<String, u64> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.rsplit_once(' '))
+        .filter(|(series, _)| {
+            series
+                .split('{')
+                .next()
+                .is_some_and(|name| name.ends_with("_total"))
+        })
+        .filter_map(|(series, value)| Some((series.to_string(), value.parse().ok()?)))
+        .collect()
+}
+
+/// Columns of a client CSV the rules read, by their schema 5 names or the
+/// older ones; the last two are absent before schema 3.
+struct Columns {
+    timestamp: usize,
+    active: usize,
+    divergences: Option<usize>,
+    tracer_p99_ms: Option<usize>,
+}
+
+impl Columns {
+    fn from_header(header: &str) -> Option<Self> {
+        let names: Vec<&str> = header.split(',').collect();
+        let find = |name: &str| names.iter().position(|&n| n == name);
+        Some(Self {
+            timestamp: find("timestamp_ms").or_else(|| find("timestamp"))?,
+            active: find("active")?,
+            divergences: find("rle_divergences_total").or_else(|| find("rle_divergences")),
+            tracer_p99_ms: find("tracer_p99_ms"),
+        })
+    }
+}
+
+/// What one client CSV added since the previous look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Seen {
+    /// At the newest timestamp, over its targets.
+    active: u64,
+    tracer_p99_ms: Option<u64>,
+}
+
+/// One client's `<id>_data.csv`, read as it grows.
+struct ClientCsv {
+    path: PathBuf,
+    offset: u64,
+    /// Text after the last complete line.
+    partial: String,
+    header: Option<String>,
+    columns: Option<Columns>,
+    recent: VecDeque<String>,
+    keep: usize,
+    /// Newest timestamp and the sums over its rows so far.
+    ts: u64,
+    active: u64,
+    divergences: u64,
+    /// `divergences` at the newest complete timestamp.
+    settled_divergences: u64,
+}
+
+impl ClientCsv {
+    fn new(path: PathBuf, keep: usize) -> Self {
+        Self {
+            path,
+            offset: 0,
+            partial: String::new(),
+            header: None,
+            columns: None,
+            recent: VecDeque::with_capacity(keep),
+            keep,
+            ts: 0,
+            active: 0,
+            divergences: 0,
+            settled_divergences: 0,
+        }
+    }
+
+    fn read_new(&mut self) -> io::Result<String> {
+        let mut file = std::fs::File::open(&self.path)?;
+        if file.metadata()?.len() < self.offset {
+            // Truncated: the client was started again under the same id.
+            *self = Self::new(std::mem::take(&mut self.path), self.keep);
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut text = String::new();
+        self.offset += file.read_to_string(&mut text)? as u64;
+        Ok(text)
+    }
+
+    /// Takes in appended text; None if it held no complete row.
+    fn feed(&mut self, text: &str) -> Option<Seen> {
+        self.partial.push_str(text);
+        let end = self.partial.rfind('\n')?;
+        let complete: String = self.partial.drain(..=end).collect();
+        let mut seen = None;
+        for line in complete.lines().filter(|l| !l.is_empty()) {
+            let Some(columns) = &self.columns else {
+                self.columns = Columns::from_header(line);
+                self.header = Some(line.to_string());
+                continue;
+            };
+            let fields: Vec<&str> = line.split(',').collect();
+            let int = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+            let Some(ts) = int(columns.timestamp) else {
+                continue;
+            };
+            if ts != self.ts {
+                self.settled_divergences = self.divergences;
+                (self.ts, self.active, self.divergences) = (ts, 0, 0);
+            }
+            self.active += int(columns.active).unwrap_or(0);
+            self.divergences += columns.divergences.and_then(int).unwrap_or(0);
+            let p99 = columns.tracer_p99_ms.and_then(int);
+            let seen = seen.get_or_insert(Seen {
+                active: 0,
+                tracer_p99_ms: None,
+            });
+            seen.active = self.active;
+            seen.tracer_p99_ms = seen.tracer_p99_ms.max(p99);
+
+            if self.recent.len() == self.keep {
+                self.recent.pop_front();
+            }
+            if self.keep > 0 {
+                self.recent.push_back(line.to_string());
+            }
+        }
+        seen
+    }
+
+    fn divergences(&self) -> u64 {
+        // Rows of one second arrive together, so the newest sum is whole
+        // unless a read split them; never report less than the last one.
+        self.divergences.max(self.settled_divergences)
+    }
+
+    fn tail(&self) -> String {
+        let mut out = self.header.clone().unwrap_or_default();
+        out.push('\n');
+        for row in &self.recent {
+            out.push_str(row);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Body of `GET <path>` on the dashboard; anything but 200 is an error.
+async fn http_get(addr: &str, path: &str) -> io::Result<Vec<u8>> {
+    let fetch = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = tokio::time::timeout(HTTP_TIMEOUT, fetch)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "dashboard did not answer"))??;
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::other(format!("{} answered `{}`", path, status)));
+    }
+    Ok(response[split + 4..].to_vec())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Client CSVs in `dir`, including ones that appeared since the last look.
+fn discover(dir: &Path, csvs: &mut Vec<ClientCsv>, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        let is_data = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with("_data.csv"));
+        if is_data && !csvs.iter().any(|c| c.path == path) {
+            csvs.push(ClientCsv::new(path, keep));
+        }
+    }
+}
+
+/// Writes `anomaly_<n>/` under `out_dir`; what could not be captured is
+/// noted in its `anomalies.txt` rather than failing the bundle.
+async fn capture(
+    dir: &Path,
+    anomalies: &[Anomaly],
+    csvs: &[ClientCsv],
+    samples: &VecDeque<String>,
+    scrape: Option<&str>,
+    dashboard: Option<&str>,
+) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let mut notes: String = anomalies
+        .iter()
+        .map(|a| format!("[{}] {}\n", a.rule, a.detail))
+        .collect();
+    for csv in csvs {
+        if let Some(name) = csv.path.file_name() {
+            std::fs::write(dir.join(name), csv.tail())?;
+        }
+    }
+    let mut rows = SAMPLES_HEADER.to_string();
+    rows.extend(samples.iter().map(String::as_str));
+    std::fs::write(dir.join("samples.csv"), rows)?;
+    if let Some(text) = scrape {
+        std::fs::write(dir.join("metrics.prom"), text)?;
+    }
+    for (path, file) in [
+        ("/config.json", "config.json"),
+        ("/stats.json", "stats.json"),
+        ("/thumb.png", "thumb.png"),
+    ] {
+        let Some(addr) = dashboard else {
+            notes.push_str(&format!(
+                "{}: not captured, no dashboard configured\n",
+                file
+            ));
+            continue;
+        };
+        match http_get(addr, path).await {
+            Ok(body) => std::fs::write(dir.join(file), body)?,
+            Err(e) => notes.push_str(&format!("{}: not captured: {}\n", file, e)),
+        }
+    }
+    std::fs::write(dir.join("anomalies.txt"), notes)
+}
+
+/// Watches until the soak ends; returns how many anomalies fired.
+pub async fn run(config: &SoakConfig, metrics_dir: &Path) -> usize {
+    let out_dir = config
+        .out_dir
+        .clone()
+        .unwrap_or_else(|| metrics_dir.join("soak"));
+    let dashboard = config.dashboard.as_deref();
+    println!(
+        "Soak: watching {} and {} every {} ms, bundles to {}",
+        metrics_dir.display(),
+        dashboard.unwrap_or("no dashboard"),
+        config.interval_ms,
+        out_dir.display()
+    );
+
+    let mut watch = Watch::new(config.rules);
+    let mut csvs: Vec<ClientCsv> = Vec::new();
+    let mut samples: VecDeque<String> = VecDeque::with_capacity(config.bundle_rows);
+    let mut fired = 0;
+    let mut bundles = 0;
+    let deadline = (config.duration_s > 0)
+        .then(|| tokio::time::Instant::now() + Duration::from_secs(config.duration_s));
+    let mut ticker = tokio::time::interval(Duration::from_millis(config.interval_ms));
+    // The first tick is immediate: give the run one interval to write rows.
+    ticker.tick().await;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        let until_deadline = async {
+            match deadline {
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = until_deadline => break,
+            _ = &mut ctrl_c => break,
+        }
+
+        discover(metrics_dir, &mut csvs, config.bundle_rows);
+        let mut sample = Sample::default();
+        for csv in &mut csvs {
+            let seen = match csv.read_new() {
+                Ok(text) => csv.feed(&text),
+                Err(e) => {
+                    eprintln!("Soak: cannot read {}: {}", csv.path.display(), e);
+                    None
+                }
+            };
+            // A client that stopped writing has no connections left.
+            *sample.active.get_or_insert(0) += seen.map_or(0, |s| s.active);
+            *sample.divergences.get_or_insert(0) += csv.divergences();
+            sample.tracer_p99_ms = sample.tracer_p99_ms.max(seen.and_then(|s| s.tracer_p99_ms));
+        }
+        let scrape = match dashboard {
+            Some(addr) => match http_get(addr, "/metrics").await {
+                Ok(body) => Some(String::from_utf8_lossy(&body).into_owned()),
+                Err(e) => {
+                    eprintln!("Soak: scraping {} failed: {}", addr, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        sample.server = scrape.as_deref().map(parse_counters);
+
+        if samples.len() == config.bundle_rows {
+            samples.pop_front();
+        }
+        if config.bundle_rows > 0 {
+            samples.push_back(sample.csv_row(unix_now()));
+        }
+        let anomalies = watch.observe(&sample);
+        if anomalies.is_empty() {
+            continue;
+        }
+        fired += anomalies.len();
+        bundles += 1;
+        let dir = out_dir.join(format!("anomaly_{}", bundles));
+        for a in &anomalies {
+            println!("Soak: [{}] {}", a.rule, a.detail);
+        }
+        match capture(
+            &dir,
+            &anomalies,
+            &csvs,
+            &samples,
+            scrape.as_deref(),
+            dashboard,
+        )
+        .await
+        {
+            Ok(()) => println!("Soak: diagnostic bundle in {}", dir.display()),
+            Err(e) => eprintln!("Soak: writing bundle {} failed: {}", dir.display(), e),
+        }
+    }
+    println!("Soak: finished, {} anomalies in {} bundles", fired, bundles);
+    fired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(active: u64, divergences: u64, p99: Option<u64>) -> Sample {
+        Sample {
+            active: Some(active),
+            divergences: Some(divergences),
+            tracer_p99_ms: p99,
+            server: None,
+        }
+    }
+
+    fn server(capacity: [u64; 2], accepted: [u64; 2]) -> Sample {
+        let mut series = BTreeMap::new();
+        for w in 0..2 {
+            series.insert(
+                format!("{}{{worker=\"{}\"}}", CAPACITY_REJECTIONS, w),
+                capacity[w],
+            );
+            series.insert(
+                format!(
+                    "canvas_worker_connections_accepted_total{{worker=\"{}\"}}",
+                    w
+                ),
+                accepted[w],
+            );
+        }
+        Sample {
+            server: Some(series),
+            ..Default::default()
+        }
+    }
+
+    fn rules_fired(watch: &mut Watch, stream: &[Sample]) -> Vec<Vec<&'static str>> {
+        stream
+            .iter()
+            .map(|s| watch.observe(s).iter().map(|a| a.rule).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_active_sag_fires_once_per_incident() {
+        let mut watch = Watch::new(Rules::default());
+        let stream: Vec<Sample> = [0, 500, 1000, 900, 790, 700, 850, 1000, 600]
+            .iter()
+            .map(|&active| client(active, 0, None))
+            .collect();
+        assert_eq!(
+            rules_fired(&mut watch, &stream),
+            [
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec!["active-sag"],
+                vec![],
+                vec![],
+                vec![],
+                vec!["active-sag"],
+            ]
+        );
+
+        let mut off = Watch::new(Rules {
+            active_sag_pct: 0,
+            ..Rules::default()
+        });
+        assert!(rules_fired(&mut off, &stream).iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn test_divergences_fire_when_they_grow() {
+        let mut watch = Watch::new(Rules::default());
+        let stream = [
+            client(10, 0, None),
+            client(10, 0, None),
+            client(10, 2, None),
+            client(10, 2, None),
+            client(10, 3, None),
+        ];
+        let fired = rules_fired(&mut watch, &stream);
+        assert_eq!(
+            fired.iter().filter(|r| r.contains(&"divergence")).count(),
+            2
+        );
+        assert!(fired[2].contains(&"divergence") && fired[4].contains(&"divergence"));
+    }
+
+    #[test]
+    fn test_p99_needs_consecutive_slow_intervals() {
+        let rules = Rules {
+            p99_slo_ms: 500,
+            p99_intervals: 3,
+            ..Rules::default()
+        };
+        let mut watch = Watch::new(rules);
+        // Slow, slow, fast resets; looks without tracers neither extend
+        // nor break a streak.
+        let p99s = [
+            Some(1000),
+            Some(1000),
+            Some(250),
+            Some(1000),
+            None,
+            Some(2500),
+            Some(1000),
+            Some(1000),
+        ];
+        let stream: Vec<Sample> = p99s.iter().map(|&p| client(10, 0, p)).collect();
+        let fired = rules_fired(&mut watch, &stream);
+        let slow: Vec<usize> = (0..fired.len())
+            .filter(|&i| fired[i].contains(&"p99-slo"))
+            .collect();
+        assert_eq!(slow, [6]);
+    }
+
+    #[test]
+    fn test_server_rules() {
+        let mut watch = Watch::new(Rules::default());
+        let stream = [
+            server([0, 0], [10, 10]),
+            server([0, 0], [20, 15]),
+            server([3, 0], [25, 15]),
+            server([3, 0], [30, 20]),
+            // Worker 1 started over.
+            server([3, 0], [35, 2]),
+            // Counters kept across the restart no longer hide rejections.
+            server([3, 1], [40, 4]),
+        ];
+        assert_eq!(
+            rules_fired(&mut watch, &stream),
+            [
+                vec![],
+                vec![],
+                vec!["capacity"],
+                vec![],
+                vec!["worker-restart"],
+                vec!["capacity"],
+            ]
+        );
+
+        // A look without a scrape compares the next one to the last good.
+        let mut watch = Watch::new(Rules::default());
+        let gap = [
+            server([0, 0], [5, 5]),
+            client(1, 0, None),
+            server([1, 0], [6, 6]),
+        ];
+        assert_eq!(rules_fired(&mut watch, &gap)[2], ["capacity"]);
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let text = "# TYPE canvas_worker_connections gauge\n\
+                    canvas_worker_connections{worker=\"0\"} 12\n\
+                    # TYPE canvas_worker_accept_capacity_rejections_total counter\n\
+                    canvas_worker_accept_capacity_rejections_total{worker=\"0\"} 4\n\
+                    canvas_worker_accept_capacity_rejections_total{worker=\"1\"} 5\n\
+                    canvas_worker_handshake_ms_bucket{worker=\"0\",le=\"10\"} 7\n\
+                    canvas_master_pixels_applied_total 99\n";
+        let counters = parse_counters(text);
+        assert_eq!(counters.len(), 3);
+        assert_eq!(counters["canvas_master_pixels_applied_total"], 99);
+        let sample = Sample {
+            server: Some(counters),
+            ..Default::default()
+        };
+        assert_eq!(sample.capacity_rejections(), Some(9));
+    }
+
+    #[test]
+    fn test_client_csv_rows_split_across_reads() {
+        let mut csv = ClientCsv::new(PathBuf::from("x_data.csv"), 2);
+        let header = "schema,timestamp,target,active,rle_divergences,tracer_p99_ms\n";
+        assert_eq!(csv.feed(header), None);
+        assert_eq!(
+            csv.feed("3,100,a,5,0,\n3,100,b,7,1,250\n3,101,a,6,0,"),
+            Some(Seen {
+                active: 12,
+                tracer_p99_ms: Some(250)
+            })
+        );
+        assert_eq!(csv.divergences(), 1);
+        // The rest of the 101 row, then the other target's.
+        assert_eq!(
+            csv.feed("50\n3,101,b,4,1,\n"),
+            Some(Seen {
+                active: 10,
+                tracer_p99_ms: Some(50)
+            })
+        );
+        assert_eq!(csv.divergences(), 1);
+        assert_eq!(csv.feed(""), None);
+        assert_eq!(
+            csv.tail(),
+            "schema,timestamp,target,active,rle_divergences,tracer_p99_ms\n3,101,a,6,0,50\n3,101,b,4,1,\n"
+        );
+
+        // Schema 2 files have neither column.
+        let mut old = ClientCsv::new(PathBuf::from("y_data.csv"), 0);
+        old.feed("schema,timestamp,target,active\n2,5,a,3\n");
+        assert_eq!(
+            old.feed("2,6,a,4\n"),
+            Some(Seen {
+                active: 4,
+                tracer_p99_ms: None
+            })
+        );
+        assert_eq!(old.tail(), "schema,timestamp,target,active\n");
+
+        // Schema 5 names, millisecond timestamps.
+        let mut new = ClientCsv::new(PathBuf::from("z_data.csv"), 0);
+        new.feed("schema,timestamp_ms,target,active,rle_divergences_total,tracer_p99_ms\n");
+        new.feed("5,100250,a,5,2,\n5,100250,b,7,0,250\n");
+        assert_eq!(new.divergences(), 2);
+        assert_eq!(
+            new.feed("5,101250,a,6,3,50\n"),
+            Some(Seen {
+                active: 6,
+                tracer_p99_ms: Some(50)
+            })
+        );
+        assert_eq!(new.divergences(), 3);
+    }
+
+    #[test]
+    fn test_parse_soak_config() {
+        let config = SoakConfig::parse(include_str!("../scenarios/soak.toml")).unwrap();
+        assert_eq!(config.dashboard.as_deref(), Some("127.0.0.1:8080"));
+        assert_eq!(config.rules, Rules::default());
+        assert_eq!(SoakConfig::parse("").unwrap(), SoakConfig::default());
+
+        let config = SoakConfig::parse(
+            "[soak]\nduration_s = 28_800 # 8 h\np99_slo_ms = 250\ndivergence = 0\nout_dir = \"/tmp/s\"",
+        )
+        .unwrap();
+        assert_eq!(config.duration_s, 28_800);
+        assert_eq!(config.rules.p99_slo_ms, 250);
+        assert!(!config.rules.divergence);
+        assert_eq!(config.out_dir, Some(PathBuf::from("/tmp/s")));
+
+        let err = |text: &str| SoakConfig::parse(text).unwrap_err();
+        assert!(err("interval_ms = 5").contains("outside [soak]"));
+        assert!(err("[persona]").contains("[soak]"));
+        assert!(err("[soak]\n[soak]").contains("one [soak]"));
+        assert!(err("[soak]\nsag = 5").contains("unknown"));
+        assert!(err("[soak]\ndivergence = 2").contains("0 or 1"));
+        assert!(err("[soak]\ninterval_ms = 0").contains("positive"));
+        assert!(err("[soak]\nactive_sag_pct = 101").contains("over 100"));
+        assert!(err("[soak]\np99_slo_ms = 10000").contains("top latency bucket"));
+        assert!(err("[soak]\np99_intervals = 0").contains("positive"));
+        assert!(err("[soak]\nbundle_rows = 1\nbundle_rows = 2").contains("duplicate"));
+    }
+}
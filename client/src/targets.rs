@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+
+/// One `--target` entry: `[https://]ip:port[=weight]`. Weight defaults to 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub addr: SocketAddr,
+    pub weight: u32,
+}
+
+pub fn parse_target_spec(spec: &str) -> Result<Target, String> {
+    let (addr, weight) = match spec.rsplit_once('=') {
+        Some((addr, weight)) => {
+            let weight = weight
+                .parse::<u32>()
+                .map_err(|_| format!("invalid weight '{}' in target '{}'", weight, spec))?;
+            (addr, weight)
+        }
+        None => (spec, 1),
+    };
+    if weight == 0 {
+        return Err(format!("target '{}' has zero weight", spec));
+    }
+    let addr = addr
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .parse::<SocketAddr>()
+        .map_err(|e| format!("invalid target address in '{}': {}", spec, e))?;
+    Ok(Target { addr, weight })
+}
+
+/// Deterministic weighted assignment of users to targets: user `i` lands on
+/// the target owning slot `i % total_weight`, so every window of
+/// `total_weight` consecutive users splits exactly by weight.
+pub fn assign(targets: &[Target], user: usize) -> usize {
+    let total: u64 = targets.iter().map(|t| t.weight as u64).sum();
+    let mut slot = user as u64 % total;
+    for (i, t) in targets.iter().enumerate() {
+        if slot < t.weight as u64 {
+            return i;
+        }
+        slot -= t.weight as u64;
+    }
+    unreachable!("slot is always below the total weight")
+}
+
+/// Order in which a user tries targets: its assigned one first, then (with
+/// `--failover`) every other target in declaration order.
+pub fn attempt_order(targets: &[Target], assigned: usize, failover: bool) -> Vec<usize> {
+    let tries = if failover { targets.len() } else { 1 };
+    (0..tries).map(|k| (assigned + k) % targets.len()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_assign_by_weight() {
+        let a = parse_target_spec("https://1.2.3.4:4433=70").unwrap();
+        let b = parse_target_spec("1.2.3.4:4434=30").unwrap();
+        assert_eq!(a.addr, "1.2.3.4:4433".parse().unwrap());
+        assert_eq!((a.weight, b.weight), (70, 30));
+        assert_eq!(parse_target_spec("127.0.0.1:4433").unwrap().weight, 1);
+        assert!(parse_target_spec("1.2.3.4:4433=0").is_err());
+        assert!(parse_target_spec("1.2.3.4:4433=x").is_err());
+        assert!(parse_target_spec("nope").is_err());
+
+        let targets = [a, b];
+        let mut counts = [0usize; 2];
+        for user in 0..1000 {
+            counts[assign(&targets, user)] += 1;
+        }
+        assert_eq!(counts, [700, 300]);
+
+        assert_eq!(attempt_order(&targets, 1, false), vec![1]);
+        assert_eq!(attempt_order(&targets, 1, true), vec![1, 0]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Per-tier broadcast latency (`--tier-token`): checks that tier-1
+//! connections really see pixels sooner than everyone else.
+//!
+//! A tracer writer paints one tracer pixel every `--tracer-interval-ms`,
+//! always at TRACER_PIXEL, in a color different from the last one. Two
+//! non-painting observers on the first target watch for it: one stays tier 0
+//! (snapshot diffs every broadcast tick), the other presents the token in a
+//! TIER request and gets micro-diffs too. Each records send-to-seen time in
+//! its tier's histogram; a tracer a tier has not seen by the time the next
+//! one is painted counts as that tier's miss. Only raw diffs are searched:
+//! a tracer that reaches an observer inside a full snapshot is a miss.
+//!
+//! The writer connects afresh for every tracer, since one connection may
+//! only paint once per cooldown and the server releases the cooldown of a
+//! connection that closes.
+
+use crate::metrics::LoadMetrics;
+use protocol::broadcast::{self, Message};
+use protocol::dict::{DIFF_RECORD_LEN, is_raw_diff};
+use protocol::pixel::{self, CANVAS_WIDTH, PIXEL_DATAGRAM_LEN, PIXEL_LEN, Pixel};
+use protocol::version::Features;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Where tracers are painted: the last pixel the random-pattern writers
+/// can hit, so they rarely paint over one.
+pub const TRACER_PIXEL: (u16, u16) = (999, 999);
+
+/// Tier 0 (snapshot diffs only) and tier 1 (micro-diffs too).
+pub const TIERS: usize = 2;
+
+struct Tracer {
+    color: u8,
+    sent: Instant,
+    seen: [bool; TIERS],
+}
+
+/// The tracer in flight, shared by the writer and the observers.
+#[derive(Default)]
+pub struct TracerBoard {
+    current: Mutex<Option<Tracer>>,
+    painted: Mutex<u8>,
+}
+
+impl TracerBoard {
+    /// Pixel datagram for the next tracer: a new color each time, so the
+    /// pixel changes and shows up in diffs.
+    pub fn next_payload(&self) -> [u8; PIXEL_DATAGRAM_LEN] {
+        let mut painted = self.painted.lock().unwrap();
+        *painted = painted.wrapping_add(1);
+        let (x, y) = TRACER_PIXEL;
+        let mut payload = [0u8; PIXEL_LEN];
+        Pixel {
+            x,
+            y,
+            color: *painted,
+        }
+        .to_wire(&mut payload);
+        pixel::datagram(&payload)
+    }
+
+    /// The writer sent `payload` (from `next_payload`): tiers that never saw
+    /// the previous tracer miss it.
+    pub fn sent(&self, payload: &[u8; PIXEL_DATAGRAM_LEN], metrics: &LoadMetrics) {
+        let mut current = self.current.lock().unwrap();
+        if let Some(previous) = current.as_ref() {
+            for tier in (0..TIERS).filter(|&t| !previous.seen[t]) {
+                metrics.tracer_misses[tier].add(1);
+            }
+        }
+        metrics.tracers.add(1);
+        *current = Some(Tracer {
+            color: payload[PIXEL_DATAGRAM_LEN - 1],
+            sent: Instant::now(),
+            seen: [false; TIERS],
+        });
+    }
+
+    /// An observer of `tier` received `datagram`.
+    pub fn observe(&self, tier: usize, datagram: &[u8], metrics: &LoadMetrics) {
+        let mut current = self.current.lock().unwrap();
+        let Some(tracer) = current.as_mut().filter(|t| !t.seen[tier]) else {
+            return;
+        };
+        let (x, y) = TRACER_PIXEL;
+        let index = y as u32 * CANVAS_WIDTH as u32 + x as u32;
+        if find_pixel(datagram, index) == Some(tracer.color) {
+            tracer.seen[tier] = true;
+            metrics.tier_latency_ms[tier].record(tracer.sent.elapsed());
+        }
+    }
+}
+
+/// Color a raw diff datagram gives pixel `index`, if it has it.
+pub fn find_pixel(datagram: &[u8], index: u32) -> Option<u8> {
+    let Message::Diff(chunk) = broadcast::decode(datagram, Features::LEGACY) else {
+        return None;
+    };
+    if !is_raw_diff(chunk) {
+        return None;
+    }
+    let records: Vec<&[u8]> = chunk.chunks_exact(DIFF_RECORD_LEN).collect();
+    let i = records
+        .binary_search_by_key(&index, |r| u32::from_le_bytes(r[..4].try_into().unwrap()))
+        .ok()?;
+    Some(records[i][4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::targets::parse_target_spec;
+
+    fn diff(pixels: &[(u32, u8)]) -> Vec<u8> {
+        let mut out = vec![protocol::datagram::DIFF];
+        for &(index, color) in pixels {
+            out.extend_from_slice(&index.to_le_bytes());
+            out.push(color);
+        }
+        out
+    }
+
+    #[test]
+    fn test_tracers_are_timed_per_tier() {
+        let metrics = LoadMetrics::new("t".into(), parse_target_spec("127.0.0.1:4433").unwrap());
+        let board = TracerBoard::default();
+        let index = 999 * CANVAS_WIDTH as u32 + 999;
+
+        assert_eq!(find_pixel(&diff(&[(3, 1), (index, 7)]), index), Some(7));
+        assert_eq!(find_pixel(&diff(&[(3, 1), (4, 7)]), index), None);
+        // Anything that is not a DIFF of whole, sorted records is not
+        // searched.
+        assert_eq!(find_pixel(&diff(&[(5, 1)])[1..], 5), None);
+        assert_eq!(find_pixel(&[0x11, 5, 0, 0, 0, 1, 0], 5), None);
+        assert_eq!(find_pixel(&diff(&[(index, 7), (3, 1)]), index), None);
+
+        let first = board.next_payload();
+        let second = board.next_payload();
+        assert_ne!(first[5], second[5]);
+
+        board.sent(&first, &metrics);
+        // Seen by tier 1 (twice, counted once); the wrong color is not it.
+        board.observe(1, &diff(&[(index, first[5])]), &metrics);
+        board.observe(1, &diff(&[(index, first[5])]), &metrics);
+        board.observe(0, &diff(&[(index, first[5].wrapping_add(9))]), &metrics);
+        board.sent(&second, &metrics);
+        board.observe(0, &diff(&[(index, second[5])]), &metrics);
+
+        assert_eq!(metrics.tracers.get(), 2);
+        assert_eq!(metrics.tracer_misses[0].get(), 1);
+        assert_eq!(metrics.tracer_misses[1].get(), 0);
+        let summary = crate::metrics::summary_json("t", None, None, None, &[metrics]);
+        assert!(summary.contains("\"misses\":[1,0]"), "{}", summary);
+        assert_eq!(summary.matches("\"count\":1}").count(), 2, "{}", summary);
+    }
+}
@@ -1,3 +1,4 @@
+use protocol::webtransport::RAW_ALPN;
 use quinn::ClientConfig;
 use rustls::client::{ServerCertVerified, ServerCertVerifier};
 use rustls::{Certificate, Error, ServerName};
@@ -26,7 +27,13 @@ pub fn build_optimized_config() -> ClientConfig {
         .with_safe_defaults()
         .with_custom_certificate_verifier(Arc::new(RecklessVerifier))
         .with_no_client_auth();
-    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    crypto.alpn_protocols = vec![RAW_ALPN.to_vec()];
+    // Reconnects resume from the server's session ticket and send their
+    // first pixels in 0-RTT (`connect` in main.rs).
+    crypto.enable_early_data = true;
+    // With SSLKEYLOGFILE set, the secrets of every connection are appended
+    // there for Wireshark; without it this logs nothing.
+    crypto.key_log = Arc::new(rustls::KeyLogFile::new());
 
     let mut config = ClientConfig::new(Arc::new(crypto));
 
@@ -40,11 +47,14 @@ pub fn build_optimized_config() -> ClientConfig {
     transport.receive_window(8192u32.into());
     transport.send_window(4096);
 
-    // Stream windows — we don't use streams at all, just datagrams.
-    // Set to minimum to save memory.
-    transport.stream_receive_window(0u32.into());
+    // Streams only carry the occasional control request (see
+    // protocol::control); replies are read as they arrive, so one small
+    // window is enough. With 0 no reply could ever arrive. The server
+    // opens a unidirectional stream only to push a rate contract, read as
+    // soon as it arrives.
+    transport.stream_receive_window(8192u32.into());
     transport.max_concurrent_bidi_streams(0u32.into());
-    transport.max_concurrent_uni_streams(0u32.into());
+    transport.max_concurrent_uni_streams(4u32.into());
 
     // Datagram buffers — enough for a few broadcast chunks.
     transport.datagram_receive_buffer_size(Some(8192));
@@ -0,0 +1,137 @@
+//! Batched pixel datagrams (the [`Features::BATCHING`](crate::version::Features)
+//! feature): up to [`MAX_BATCH_PIXELS`] placements in one datagram, so a
+//! busy client pays QUIC's per-packet overhead once per batch instead of
+//! once per 5-byte pixel.
+//!
+//! ```text
+//! PIXEL_BATCH u8 | count u8 | count × (x u16 | y u16 | color u8)
+//! ```
+//!
+//! Each record is a [`crate::pixel`] placement, little-endian like a
+//! single pixel datagram. Records carry no idempotency token or nonce;
+//! clients that need those send single pixels.
+//!
+//! Batching only saves bytes: the server admits each pixel on its own, so
+//! a batch from one user still gets one pixel per cooldown.
+
+use crate::datagram::{PIXEL_BATCH, TYPE_LEN};
+
+/// Bytes of one pixel record.
+pub const PIXEL_RECORD_LEN: usize = crate::pixel::PIXEL_LEN;
+
+/// Most pixels one batch may carry.
+pub const MAX_BATCH_PIXELS: usize = 32;
+
+/// Wire length of a batch of `count` pixels.
+pub const fn batch_len(count: usize) -> usize {
+    TYPE_LEN + 1 + count * PIXEL_RECORD_LEN
+}
+
+/// Appends a batch of `records` to `out`.
+///
+/// # Panics
+/// If `records` is empty or holds more than [`MAX_BATCH_PIXELS`].
+pub fn encode_batch(records: &[[u8; PIXEL_RECORD_LEN]], out: &mut Vec<u8>) {
+    assert!(
+        (1..=MAX_BATCH_PIXELS).contains(&records.len()),
+        "a batch carries 1 to {} pixels, not {}",
+        MAX_BATCH_PIXELS,
+        records.len()
+    );
+    out.reserve(batch_len(records.len()));
+    out.push(PIXEL_BATCH);
+    out.push(records.len() as u8);
+    for record in records {
+        out.extend_from_slice(record);
+    }
+}
+
+/// The pixel records of a batch, None unless `datagram` is one: the type,
+/// a count from 1 to [`MAX_BATCH_PIXELS`] and exactly that many records.
+pub fn decode_batch(datagram: &[u8]) -> Option<std::slice::ChunksExact<'_, u8>> {
+    let [PIXEL_BATCH, count, records @ ..] = datagram else {
+        return None;
+    };
+    let count = *count as usize;
+    if count == 0 || count > MAX_BATCH_PIXELS || datagram.len() != batch_len(count) {
+        return None;
+    }
+    Some(records.chunks_exact(PIXEL_RECORD_LEN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel::Pixel;
+
+    fn record(x: u16, y: u16, color: u8) -> [u8; PIXEL_RECORD_LEN] {
+        let mut r = [0; PIXEL_RECORD_LEN];
+        Pixel { x, y, color }.to_wire(&mut r);
+        r
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let records: Vec<_> = (0..MAX_BATCH_PIXELS as u16)
+            .map(|i| record(i, 999 - i, i as u8 % 32))
+            .collect();
+        for n in [1, 2, MAX_BATCH_PIXELS] {
+            let mut wire = Vec::new();
+            encode_batch(&records[..n], &mut wire);
+            assert_eq!(wire.len(), batch_len(n));
+            assert_eq!(wire[1] as usize, n);
+            let decoded: Vec<&[u8]> = decode_batch(&wire).unwrap().collect();
+            assert_eq!(decoded.len(), n);
+            for (got, want) in decoded.iter().zip(&records) {
+                assert_eq!(*got, &want[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_wire_layout() {
+        let mut wire = Vec::new();
+        encode_batch(&[record(1, 0x0200, 3), record(999, 4, 31)], &mut wire);
+        assert_eq!(wire, [0x02, 2, 1, 0, 0, 2, 3, 0xe7, 3, 4, 0, 31]);
+    }
+
+    #[test]
+    fn test_malformed_batches_are_refused() {
+        let mut wire = Vec::new();
+        encode_batch(&[record(1, 2, 3), record(4, 5, 6)], &mut wire);
+        // Truncated, padded, or a count that disagrees with the length.
+        assert!(decode_batch(&wire[..wire.len() - 1]).is_none());
+        let mut padded = wire.clone();
+        padded.push(0);
+        assert!(decode_batch(&padded).is_none());
+        let mut miscounted = wire.clone();
+        miscounted[1] = 3;
+        assert!(decode_batch(&miscounted).is_none());
+        // Empty, a count of 0, and more than MAX_BATCH_PIXELS.
+        assert!(decode_batch(&[]).is_none());
+        assert!(decode_batch(&[PIXEL_BATCH, 0]).is_none());
+        let mut oversized = vec![MAX_BATCH_PIXELS as u8 + 1; batch_len(MAX_BATCH_PIXELS + 1)];
+        oversized[0] = PIXEL_BATCH;
+        assert!(decode_batch(&oversized).is_none());
+    }
+
+    #[test]
+    fn test_only_batch_datagrams_are_batches() {
+        let mut wire = Vec::new();
+        encode_batch(&[record(1, 2, 3)], &mut wire);
+        // The same bytes as a single pixel, and without a type at all.
+        let mut single = wire.clone();
+        single[0] = crate::datagram::PIXEL;
+        assert!(decode_batch(&single).is_none());
+        assert!(decode_batch(&wire[TYPE_LEN..]).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "a batch carries 1 to 32 pixels, not 33")]
+    fn test_oversized_batches_are_not_encoded() {
+        encode_batch(
+            &[[0; PIXEL_RECORD_LEN]; MAX_BATCH_PIXELS + 1],
+            &mut Vec::new(),
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! What a client can receive as a datagram, and what it does with kinds it
+//! does not know: the forward-compatibility rule for broadcast formats.
+//!
+//! A datagram from the server is one message; its length is the message's.
+//! Its first byte is its type ([`crate::datagram`]):
+//!
+//! - [`FULL_RLE`] and [`DIFF`]: a full snapshot (RLE) chunk or a raw diff
+//!   chunk, the formats that predate tags.
+//! - [`TAGGED`]: `0x00 | tag [u8; 3] | body`. FEC chunks ([`crate::fec`]),
+//!   STATS replies ([`crate::stats`]) and compressed diffs
+//!   ([`crate::dict`], whose tag is the zstd magic) are.
+//! - [`COOLDOWN_NACK`]: a pixel of the connection's was rejected for
+//!   cooldown ([`crate::nack`]).
+//! - [`PIXEL_ACK`]: one was accepted, for connections that negotiated
+//!   RECEIPTS ([`crate::receipt`]).
+//! - [`EPOCH`]: the canvas round the next full snapshot belongs to, for
+//!   connections that negotiated EPOCHS ([`crate::epoch`]).
+//!
+//! New datagram formats get a type or a tag of their own. A client skips,
+//! and counts, datagrams of types and tags it does not know, and those of
+//! features it did not negotiate, so a newer server's additions reach an
+//! older client as noise rather than as garbled pixels.
+
+use crate::datagram::{self, COOLDOWN_NACK, DIFF, EPOCH, FULL_RLE, PIXEL_ACK, TAGGED};
+use crate::dict::zstd_frame;
+use crate::epoch::decode_epoch;
+use crate::fec::{FecHeader, decode_fec};
+use crate::nack::decode_cooldown_nack;
+use crate::receipt::decode_ack;
+use crate::stats::{LiveStats, decode_stats};
+use crate::version::Features;
+
+/// Bytes before a tagged message's body: the 0 and the tag.
+pub const TAG_HEADER_LEN: usize = 4;
+
+pub const FEC: [u8; 3] = [b'F', b'E', b'C'];
+pub const STATS: [u8; 3] = [b'S', b'T', b'S'];
+pub const ZSTD: [u8; 3] = [0x28, 0xB5, 0x2F];
+
+/// One received datagram, decoded as far as telling it apart.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// A chunk of a full snapshot, without its type.
+    Full(&'a [u8]),
+    /// A chunk of a raw diff, without its type.
+    Diff(&'a [u8]),
+    /// A compressed diff: the whole datagram, for the dictionary decoder.
+    ZstdDiff(&'a [u8]),
+    Fec(FecHeader, &'a [u8]),
+    Stats(LiveStats),
+    /// Seconds of cooldown left after a rejected pixel.
+    CooldownNack(u16),
+    /// A pixel was accepted: the number it was sent with, if any.
+    PixelAck(Option<u16>),
+    /// The canvas round broadcasts belong to from here on.
+    Epoch(u32),
+    Skipped(Skip),
+}
+
+/// Why a datagram was skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skip {
+    /// A type this build does not know, from a newer peer.
+    UnknownType(u8),
+    /// A tag this build does not know, from a newer peer.
+    Unknown([u8; 3]),
+    /// A known format of a feature the connection did not negotiate.
+    NotNegotiated(Features),
+    /// Empty, or a known tag whose body does not parse.
+    Malformed,
+}
+
+/// The tag of a tagged datagram, None for any other.
+pub fn tag(datagram: &[u8]) -> Option<[u8; 3]> {
+    match datagram {
+        [TAGGED, a, b, c, ..] => Some([*a, *b, *c]),
+        _ => None,
+    }
+}
+
+/// Appends the header of a tagged message; the body follows.
+pub fn push_tag(tag: [u8; 3], out: &mut Vec<u8>) {
+    out.push(TAGGED);
+    out.extend_from_slice(&tag);
+}
+
+/// Decodes `datagram` for a connection that negotiated `negotiated`.
+pub fn decode(datagram: &[u8], negotiated: Features) -> Message<'_> {
+    let tag = match datagram::split(datagram) {
+        Some((FULL_RLE, chunk)) if !chunk.is_empty() => return Message::Full(chunk),
+        Some((DIFF, chunk)) if !chunk.is_empty() => return Message::Diff(chunk),
+        Some((COOLDOWN_NACK, body)) => {
+            return decode_cooldown_nack(body)
+                .map_or(Message::Skipped(Skip::Malformed), Message::CooldownNack);
+        }
+        Some((PIXEL_ACK, _)) if !negotiated.contains(Features::RECEIPTS) => {
+            return Message::Skipped(Skip::NotNegotiated(Features::RECEIPTS));
+        }
+        Some((PIXEL_ACK, body)) => {
+            return decode_ack(body).map_or(Message::Skipped(Skip::Malformed), Message::PixelAck);
+        }
+        Some((EPOCH, _)) if !negotiated.contains(Features::EPOCHS) => {
+            return Message::Skipped(Skip::NotNegotiated(Features::EPOCHS));
+        }
+        Some((EPOCH, body)) => {
+            return decode_epoch(body).map_or(Message::Skipped(Skip::Malformed), Message::Epoch);
+        }
+        Some((TAGGED | FULL_RLE | DIFF, _)) | None => match tag(datagram) {
+            Some(tag) => tag,
+            None => return Message::Skipped(Skip::Malformed),
+        },
+        Some((kind, _)) => return Message::Skipped(Skip::UnknownType(kind)),
+    };
+    let feature = match tag {
+        FEC => Features::FEC,
+        STATS => Features::STATS,
+        ZSTD => Features::ZSTD,
+        _ => return Message::Skipped(Skip::Unknown(tag)),
+    };
+    if !negotiated.contains(feature) {
+        return Message::Skipped(Skip::NotNegotiated(feature));
+    }
+    let message = match tag {
+        FEC => decode_fec(datagram).map(|(header, payload)| Message::Fec(header, payload)),
+        STATS => decode_stats(datagram).map(Message::Stats),
+        _ => zstd_frame(datagram).map(|_| Message::ZstdDiff(datagram)),
+    };
+    message.unwrap_or(Message::Skipped(Skip::Malformed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dict::{ZSTD_DIFF_TAG, ZSTD_MAGIC};
+    use crate::epoch::encode_epoch;
+    use crate::fec::{FEC_DATA, FEC_TAG, encode_fec};
+    use crate::nack::encode_cooldown_nack;
+    use crate::receipt::{MAX_ACK_LEN, encode_ack};
+    use crate::stats::{STATS_TAG, encode_stats};
+
+    /// One datagram of each format this build knows, and what it decodes to
+    /// with every feature negotiated.
+    fn known() -> Vec<(Vec<u8>, &'static str)> {
+        let (mut fec, mut ends) = (Vec::new(), Vec::new());
+        encode_fec(7, &[1, 2, 3], 4, &mut fec, &mut ends);
+        let mut zstd = vec![ZSTD_DIFF_TAG];
+        zstd.extend_from_slice(&ZSTD_MAGIC);
+        zstd.extend_from_slice(&[0x23, 0, 0, 0, 0]);
+        vec![
+            // Run of 3 in color 5, then of 250 in color 0.
+            (vec![FULL_RLE, 3, 5, 250, 0], "full"),
+            // Pixels 0 and 1_000.
+            (vec![DIFF, 0, 0, 0, 0, 9, 0xe8, 3, 0, 0, 1], "diff"),
+            (fec[..ends[0]].to_vec(), "fec"),
+            (encode_stats(&LiveStats::default()).to_vec(), "stats"),
+            (zstd, "zstd"),
+            (encode_cooldown_nack(12).to_vec(), "nack"),
+            (encode_ack(Some(7), &mut [0; MAX_ACK_LEN]).to_vec(), "ack"),
+            (encode_epoch(2).to_vec(), "epoch"),
+        ]
+    }
+
+    fn kind(message: &Message) -> &'static str {
+        match message {
+            Message::Full(_) => "full",
+            Message::Diff(_) => "diff",
+            Message::ZstdDiff(_) => "zstd",
+            Message::Fec(..) => "fec",
+            Message::Stats(_) => "stats",
+            Message::CooldownNack(_) => "nack",
+            Message::PixelAck(_) => "ack",
+            Message::Epoch(_) => "epoch",
+            Message::Skipped(_) => "skipped",
+        }
+    }
+
+    #[test]
+    fn test_known_tags_match_their_formats() {
+        assert_eq!(tag(&FEC_TAG), Some(FEC));
+        assert_eq!(tag(&STATS_TAG), Some(STATS));
+        let mut zstd = vec![ZSTD_DIFF_TAG];
+        zstd.extend_from_slice(&ZSTD_MAGIC);
+        assert_eq!(tag(&zstd), Some(ZSTD));
+        let mut pushed = Vec::new();
+        push_tag(FEC, &mut pushed);
+        assert_eq!(pushed, FEC_TAG);
+    }
+
+    #[test]
+    fn test_typed_chunks_lose_their_type() {
+        let every = Features::FEC | Features::STATS | Features::ZSTD;
+        assert_eq!(
+            decode(&[FULL_RLE, 3, 5], Features::LEGACY),
+            Message::Full(&[3, 5])
+        );
+        assert_eq!(
+            decode(&[DIFF, 7, 0, 0, 0, 1], every),
+            Message::Diff(&[7, 0, 0, 0, 1])
+        );
+    }
+
+    // The compatibility matrix: every known format, with and without its
+    // feature, among datagrams of types and tags from the future.
+    #[test]
+    fn test_unknown_formats_are_skipped_between_known_ones() {
+        let mut future: Vec<Vec<u8>> = [*b"TIL", *b"RL2", [0xff, 0xff, 0xff]]
+            .iter()
+            .map(|&t| {
+                let mut dgram = Vec::new();
+                push_tag(t, &mut dgram);
+                dgram.extend_from_slice(&[0xaa; 40]);
+                dgram
+            })
+            .collect();
+        future.push(vec![0x1f; 40]);
+        future.push(vec![0xff]);
+        let every = Features::FEC
+            | Features::STATS
+            | Features::ZSTD
+            | Features::RECEIPTS
+            | Features::EPOCHS;
+        for negotiated in [every, Features::LEGACY] {
+            let mut seen = Vec::new();
+            let mut unknown = 0;
+            for (i, (dgram, _)) in known().iter().enumerate() {
+                let noise = &future[i % future.len()];
+                for d in [noise, dgram, noise] {
+                    match decode(d, negotiated) {
+                        Message::Skipped(Skip::Unknown(_) | Skip::UnknownType(_)) => unknown += 1,
+                        message => seen.push(kind(&message)),
+                    }
+                }
+            }
+            assert_eq!(unknown, 2 * known().len());
+            let expected: Vec<_> = known()
+                .iter()
+                .map(|&(_, k)| match k {
+                    "full" | "diff" | "nack" => k,
+                    _ if negotiated == every => k,
+                    _ => "skipped",
+                })
+                .collect();
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[test]
+    fn test_malformed_known_formats_are_skipped() {
+        let every = Features::FEC
+            | Features::STATS
+            | Features::ZSTD
+            | Features::RECEIPTS
+            | Features::EPOCHS;
+        for empty in [
+            &[][..],
+            &[FULL_RLE],
+            &[DIFF],
+            &[TAGGED, b'F'],
+            &[COOLDOWN_NACK, 1],
+            &[PIXEL_ACK, 1],
+            &[EPOCH, 1, 0],
+        ] {
+            assert_eq!(decode(empty, every), Message::Skipped(Skip::Malformed));
+        }
+        for (dgram, k) in known() {
+            if matches!(k, "full" | "diff" | "nack" | "ack" | "epoch") {
+                continue;
+            }
+            // Cut short: the tag is there, the body is not.
+            assert_eq!(
+                decode(&dgram[..TAG_HEADER_LEN], every),
+                Message::Skipped(Skip::Malformed),
+                "{}",
+                k
+            );
+        }
+        let mut fec = FEC_TAG.to_vec();
+        fec.push(FEC_DATA);
+        assert_eq!(
+            decode(&fec, Features::LEGACY),
+            Message::Skipped(Skip::NotNegotiated(Features::FEC))
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Application error codes carried in QUIC CONNECTION_CLOSE frames.
+//!
+//! Values are part of the wire contract: never renumber a variant, only
+//! append new ones.
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppCloseCode {
+    /// The client is leaving on its own (end of a load run or replay).
+    ClientExit = 0,
+    /// The server is shutting down.
+    Shutdown = 1,
+    /// The worker has no free connection slot.
+    Capacity = 2,
+    /// The peer sent more than its rate allows.
+    Flood = 3,
+    /// The peer is banned.
+    Banned = 4,
+    /// The peer should reconnect to another instance.
+    Steered = 5,
+    /// The peers' major protocol versions differ (see `version`).
+    VersionMismatch = 6,
+}
+
+impl AppCloseCode {
+    /// Every code in numeric order; index `i` holds the code with value `i`.
+    pub const ALL: [Self; 7] = [
+        Self::ClientExit,
+        Self::Shutdown,
+        Self::Capacity,
+        Self::Flood,
+        Self::Banned,
+        Self::Steered,
+        Self::VersionMismatch,
+    ];
+
+    pub fn from_u64(code: u64) -> Option<Self> {
+        Self::ALL.get(usize::try_from(code).ok()?).copied()
+    }
+
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    /// Reason phrase sent alongside the code.
+    pub fn reason(self) -> &'static [u8] {
+        self.name().as_bytes()
+    }
+
+    /// Stable snake_case name, used for counters and reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ClientExit => "client_exit",
+            Self::Shutdown => "shutdown",
+            Self::Capacity => "capacity",
+            Self::Flood => "flood",
+            Self::Banned => "banned",
+            Self::Steered => "steered",
+            Self::VersionMismatch => "version_mismatch",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_round_trip() {
+        for (i, code) in AppCloseCode::ALL.iter().enumerate() {
+            assert_eq!(code.code(), i as u64);
+            assert_eq!(AppCloseCode::from_u64(code.code()), Some(*code));
+            assert!(!code.reason().is_empty());
+        }
+        assert_eq!(AppCloseCode::from_u64(AppCloseCode::ALL.len() as u64), None);
+        assert_eq!(AppCloseCode::from_u64(u64::MAX), None);
+    }
+}
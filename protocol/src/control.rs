@@ -0,0 +1,996 @@
+//! Control stream: out-of-band queries on a client-opened bidirectional QUIC
+//! stream, next to the pixel datagrams.
+//!
+//! The client writes one opcode byte, followed by the op's arguments if it
+//! has any, and finishes its side. The server answers with a status byte
+//! followed by an op-specific payload and finishes the stream. One request
+//! per stream; all integers little-endian.
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlOp {
+    /// Per-minute canvas activity ring; payload is a [`TimelineEntry`] array
+    /// (see [`encode_timeline`]).
+    StatsTimeline = 0x01,
+    /// Pixels changed since a published snapshot sequence number, for a
+    /// client that missed a few broadcasts. Argument: `u64` last applied
+    /// sequence; payload: see [`encode_catchup`]. Answered with
+    /// [`ControlStatus::ResyncRequired`] once that snapshot has left the
+    /// server's history.
+    Catchup = 0x02,
+    /// Restricts diff broadcasts on this connection to a set of canvas
+    /// tiles. Argument: a [`Subscription`] (see [`encode_subscribe`]); the
+    /// reply carries no payload. Full broadcasts are not filtered.
+    Subscribe = 0x03,
+    /// The server's effective configuration as JSON (UTF-8), secrets
+    /// redacted. No argument.
+    Config = 0x04,
+    /// Build-info handshake. Argument and reply payload: see
+    /// [`crate::version::encode_version`]. Answered with
+    /// [`ControlStatus::VersionMismatch`] (and the server's own version)
+    /// when the major versions differ; the server then closes the
+    /// connection.
+    Version = 0x05,
+    /// Asks for a latency tier. Argument: a tier token (see
+    /// [`encode_tier_request`]); reply payload: `u8` tier granted, 1 or 0
+    /// when the server has no tier-1 seat left. Answered with
+    /// [`ControlStatus::Forbidden`] for a token the server does not know.
+    /// Tier-1 connections also receive micro-diffs: raw diff datagrams sent
+    /// as pixels are applied, ahead of the snapshot broadcasts.
+    Tier = 0x06,
+    /// Takes back the user id (and cooldown) a previous connection left
+    /// parked under a resumption token. Argument: the token (see
+    /// [`encode_resume_request`]); reply payload: `u8` 1 if a parked id was
+    /// taken over, 0 if there was none, in which case the token is kept for
+    /// this connection to park under when it closes. A server that does not
+    /// park ids always answers 0.
+    Resume = 0x07,
+    /// A checksum of every tile of a published snapshot, for clients that
+    /// only track some tiles and so cannot check the whole canvas.
+    /// Argument: `u64` snapshot sequence number, or [`NEWEST_SNAPSHOT`];
+    /// payload: see [`encode_tile_checksums`]. Answered with
+    /// [`ControlStatus::ResyncRequired`] once that snapshot has left the
+    /// server's history.
+    TileChecksums = 0x08,
+    /// The pixels of some tiles of a published snapshot, for a client whose
+    /// copy failed its checksum. Argument: see
+    /// [`encode_tile_refresh_request`]; payload: see
+    /// [`encode_tile_refresh`]. Answered like TILE_CHECKSUMS when the
+    /// snapshot is gone.
+    TileRefresh = 0x09,
+    /// The cooldown as it applies to this connection. No argument; payload:
+    /// see [`encode_cooldown_config`]. The remaining time covers a cooldown
+    /// the connection took over (a parked id, see RESUME) as well as one it
+    /// earned itself. Connections that negotiated
+    /// [`Features::COOLDOWNS`](crate::version::Features) are also told
+    /// unasked, on a stream the server opens (see [`decode_server_push`]):
+    /// once VERSION is done, after a RESUME or TIER, and whenever the
+    /// cooldown in force changes (load, a new epoch).
+    CooldownConfig = 0x0A,
+    /// The pixel rate this connection is held to. No argument; payload: see
+    /// [`encode_rate_contract`]. Connections that negotiated
+    /// [`Features::CONTRACTS`](crate::version::Features) are also told
+    /// unasked: the server opens a unidirectional stream carrying the
+    /// opcode and the payload (see [`decode_rate_contract_push`]) once
+    /// VERSION is done, and another whenever the contract changes.
+    RateContract = 0x0B,
+    /// The broadcast interval in force, stretched from the base while the
+    /// server is overloaded. No argument; payload: `u32` milliseconds (see
+    /// [`encode_broadcast_interval`]). Connections that negotiated
+    /// [`Features::INTERVALS`](crate::version::Features) are also told
+    /// unasked, on a stream the server opens (see [`decode_server_push`]):
+    /// once VERSION is done, and whenever the interval changes.
+    BroadcastInterval = 0x0C,
+}
+
+impl ControlOp {
+    pub fn from_u8(op: u8) -> Option<Self> {
+        match op {
+            0x01 => Some(Self::StatsTimeline),
+            0x02 => Some(Self::Catchup),
+            0x03 => Some(Self::Subscribe),
+            0x04 => Some(Self::Config),
+            0x05 => Some(Self::Version),
+            0x06 => Some(Self::Tier),
+            0x07 => Some(Self::Resume),
+            0x08 => Some(Self::TileChecksums),
+            0x09 => Some(Self::TileRefresh),
+            0x0A => Some(Self::CooldownConfig),
+            0x0B => Some(Self::RateContract),
+            0x0C => Some(Self::BroadcastInterval),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlStatus {
+    Ok = 0,
+    UnknownOp = 1,
+    /// The requested history is gone; wait for the next full broadcast.
+    ResyncRequired = 2,
+    /// The arguments did not match the op.
+    BadRequest = 3,
+    /// The peers' major protocol versions differ.
+    VersionMismatch = 4,
+    /// The credentials presented were not accepted.
+    Forbidden = 5,
+}
+
+impl ControlStatus {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        match status {
+            0 => Some(Self::Ok),
+            1 => Some(Self::UnknownOp),
+            2 => Some(Self::ResyncRequired),
+            3 => Some(Self::BadRequest),
+            4 => Some(Self::VersionMismatch),
+            5 => Some(Self::Forbidden),
+            _ => None,
+        }
+    }
+}
+
+/// Activity aggregated over one wall-clock minute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimelineEntry {
+    /// Minutes since the Unix epoch.
+    pub minute: u32,
+    /// Pixel writes applied during the minute.
+    pub writes: u32,
+    /// Distinct canvas tiles written during the minute.
+    pub unique_tiles: u16,
+    /// Writers active within one cooldown period ending at this minute.
+    pub active_writers: u32,
+}
+
+/// Encoded size of one entry: minute u32 | writes u32 | unique_tiles u16 |
+/// active_writers u32.
+pub const TIMELINE_ENTRY_LEN: usize = 14;
+
+/// Appends `u32 count` followed by `count` entries, oldest first.
+pub fn encode_timeline<'a>(
+    entries: impl ExactSizeIterator<Item = &'a TimelineEntry>,
+    out: &mut Vec<u8>,
+) {
+    out.reserve(4 + entries.len() * TIMELINE_ENTRY_LEN);
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for e in entries {
+        out.extend_from_slice(&e.minute.to_le_bytes());
+        out.extend_from_slice(&e.writes.to_le_bytes());
+        out.extend_from_slice(&e.unique_tiles.to_le_bytes());
+        out.extend_from_slice(&e.active_writers.to_le_bytes());
+    }
+}
+
+pub fn decode_timeline(buf: &[u8]) -> Result<Vec<TimelineEntry>, String> {
+    let count = buf
+        .get(..4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        .ok_or("timeline truncated before count")?;
+    let body = &buf[4..];
+    if body.len() != count * TIMELINE_ENTRY_LEN {
+        return Err(format!(
+            "timeline declares {} entries but carries {} bytes",
+            count,
+            body.len()
+        ));
+    }
+    Ok(body
+        .chunks_exact(TIMELINE_ENTRY_LEN)
+        .map(|c| TimelineEntry {
+            minute: u32::from_le_bytes(c[0..4].try_into().unwrap()),
+            writes: u32::from_le_bytes(c[4..8].try_into().unwrap()),
+            unique_tiles: u16::from_le_bytes(c[8..10].try_into().unwrap()),
+            active_writers: u32::from_le_bytes(c[10..14].try_into().unwrap()),
+        })
+        .collect())
+}
+
+/// Size of one changed pixel in a catch-up payload: index u32 | color u8
+/// (the broadcast diff format).
+pub const CATCHUP_ENTRY_LEN: usize = 5;
+
+/// Pixels a client needs to go from its last applied snapshot to `seq`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catchup {
+    /// Sequence number of the snapshot the pixels bring the client to.
+    pub seq: u64,
+    /// `(index, color)` of every pixel that differs, in canvas order.
+    pub pixels: Vec<(u32, u8)>,
+}
+
+pub fn encode_catchup_request(last_applied_seq: u64, out: &mut Vec<u8>) {
+    out.push(ControlOp::Catchup as u8);
+    out.extend_from_slice(&last_applied_seq.to_le_bytes());
+}
+
+/// Argument of a CATCHUP request, given the bytes after the opcode.
+pub fn decode_catchup_request(args: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(args.try_into().ok()?))
+}
+
+/// Appends `u64 seq | u32 count` and the `count` entries of `diff`, which
+/// is already in `[u32 index, u8 color]` form.
+pub fn encode_catchup(seq: u64, diff: &[u8], out: &mut Vec<u8>) {
+    out.reserve(12 + diff.len());
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&((diff.len() / CATCHUP_ENTRY_LEN) as u32).to_le_bytes());
+    out.extend_from_slice(diff);
+}
+
+pub fn decode_catchup(buf: &[u8]) -> Result<Catchup, String> {
+    if buf.len() < 12 {
+        return Err("catch-up truncated before count".into());
+    }
+    let seq = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+    let count = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize;
+    let body = &buf[12..];
+    if body.len() != count * CATCHUP_ENTRY_LEN {
+        return Err(format!(
+            "catch-up declares {} pixels but carries {} bytes",
+            count,
+            body.len()
+        ));
+    }
+    Ok(Catchup {
+        seq,
+        pixels: body
+            .chunks_exact(CATCHUP_ENTRY_LEN)
+            .map(|c| (u32::from_le_bytes(c[0..4].try_into().unwrap()), c[4]))
+            .collect(),
+    })
+}
+
+/// Side of the square tiles a subscription bitmap refers to, in pixels.
+pub const SUBSCRIBE_TILE_SIZE: u16 = 64;
+/// Tiles per canvas row: tile `t` covers column `t % 16`, row `t / 16`.
+pub const SUBSCRIBE_TILES_X: u16 = 16;
+/// One bit per tile, tile `t` at byte `t / 8`, bit `t % 8`.
+pub const SUBSCRIBE_BITMAP_LEN: usize = 32;
+
+/// Half-open pixel rectangle `[x0, x1) × [y0, y1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelRect {
+    pub x0: u16,
+    pub y0: u16,
+    pub x1: u16,
+    pub y1: u16,
+}
+
+impl PixelRect {
+    /// Pixels of subscription tile `tile` on a `width` × `height` canvas;
+    /// empty for tiles past the edge.
+    pub fn of_tile(tile: usize, width: u16, height: u16) -> PixelRect {
+        let size = SUBSCRIBE_TILE_SIZE as usize;
+        let x0 = ((tile % SUBSCRIBE_TILES_X as usize) * size).min(width as usize) as u16;
+        let y0 = ((tile / SUBSCRIBE_TILES_X as usize) * size).min(height as usize) as u16;
+        PixelRect {
+            x0,
+            y0,
+            x1: (x0 as usize + size).min(width as usize) as u16,
+            y1: (y0 as usize + size).min(height as usize) as u16,
+        }
+    }
+
+    /// Pixels covered; 0 for an empty rectangle.
+    pub fn area(&self) -> usize {
+        self.x1.saturating_sub(self.x0) as usize * self.y1.saturating_sub(self.y0) as usize
+    }
+
+    /// Sets the bit of every tile the rectangle touches.
+    pub fn add_tiles(&self, bitmap: &mut [u8; SUBSCRIBE_BITMAP_LEN]) {
+        if self.x1 <= self.x0 || self.y1 <= self.y0 {
+            return;
+        }
+        let tiles_y = (SUBSCRIBE_BITMAP_LEN * 8) as u16 / SUBSCRIBE_TILES_X;
+        let (tx0, ty0) = (self.x0 / SUBSCRIBE_TILE_SIZE, self.y0 / SUBSCRIBE_TILE_SIZE);
+        let tx1 = ((self.x1 - 1) / SUBSCRIBE_TILE_SIZE).min(SUBSCRIBE_TILES_X - 1);
+        let ty1 = ((self.y1 - 1) / SUBSCRIBE_TILE_SIZE).min(tiles_y - 1);
+        for ty in ty0..=ty1 {
+            for tx in tx0..=tx1 {
+                let tile = (ty * SUBSCRIBE_TILES_X + tx) as usize;
+                bitmap[tile / 8] |= 1 << (tile % 8);
+            }
+        }
+    }
+}
+
+/// Which tiles a connection wants diffs for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subscription {
+    /// Every tile (the default).
+    All,
+    /// Exactly these tiles, e.g. several disjoint viewports.
+    Tiles([u8; SUBSCRIBE_BITMAP_LEN]),
+    /// Every tile a pixel rectangle touches; the server converts it.
+    Rect(PixelRect),
+}
+
+/// Appends a complete SUBSCRIBE request: opcode, then `u8 form` (0 = all,
+/// 1 = tile bitmap, 2 = rectangle) and the form's data: the bitmap, or
+/// `u16 x0 | y0 | x1 | y1`.
+pub fn encode_subscribe(subscription: &Subscription, out: &mut Vec<u8>) {
+    out.push(ControlOp::Subscribe as u8);
+    match subscription {
+        Subscription::All => out.push(0),
+        Subscription::Tiles(bitmap) => {
+            out.push(1);
+            out.extend_from_slice(bitmap);
+        }
+        Subscription::Rect(r) => {
+            out.push(2);
+            for v in [r.x0, r.y0, r.x1, r.y1] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Argument of a SUBSCRIBE request, given the bytes after the opcode.
+pub fn decode_subscribe(args: &[u8]) -> Option<Subscription> {
+    let (&form, data) = args.split_first()?;
+    match (form, data.len()) {
+        (0, 0) => Some(Subscription::All),
+        (1, SUBSCRIBE_BITMAP_LEN) => Some(Subscription::Tiles(data.try_into().unwrap())),
+        (2, 8) => {
+            let v = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+            Some(Subscription::Rect(PixelRect {
+                x0: v(0),
+                y0: v(2),
+                x1: v(4),
+                y1: v(6),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Longest tier token accepted.
+pub const MAX_TIER_TOKEN_LEN: usize = 64;
+
+/// Appends a complete TIER request: opcode, then the token bytes.
+pub fn encode_tier_request(token: &[u8], out: &mut Vec<u8>) {
+    out.push(ControlOp::Tier as u8);
+    out.extend_from_slice(token);
+}
+
+/// Token of a TIER request, given the bytes after the opcode.
+pub fn decode_tier_request(args: &[u8]) -> Option<&[u8]> {
+    (!args.is_empty() && args.len() <= MAX_TIER_TOKEN_LEN).then_some(args)
+}
+
+/// Length of a resumption token. Clients pick it at random and reuse it
+/// across reconnects; anyone who knows it can take over the parked id.
+pub const RESUME_TOKEN_LEN: usize = 16;
+
+/// Appends a complete RESUME request: opcode, then the token.
+pub fn encode_resume_request(token: &[u8; RESUME_TOKEN_LEN], out: &mut Vec<u8>) {
+    out.push(ControlOp::Resume as u8);
+    out.extend_from_slice(token);
+}
+
+/// Token of a RESUME request, given the bytes after the opcode.
+pub fn decode_resume_request(args: &[u8]) -> Option<&[u8; RESUME_TOKEN_LEN]> {
+    args.try_into().ok()
+}
+
+/// Length of a COOLDOWN_CONFIG reply payload.
+pub const COOLDOWN_CONFIG_LEN: usize = 10;
+
+/// The multiplier of a cooldown that is not scaled.
+pub const COOLDOWN_MULTIPLIER_ONE: u16 = 1000;
+
+/// A connection's cooldown as the server applies it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CooldownConfig {
+    /// Time between two accepted pixels, multiplier included.
+    pub cooldown_ms: u32,
+    /// Scaling applied to the configured cooldown, in thousandths
+    /// ([`COOLDOWN_MULTIPLIER_ONE`] = unscaled).
+    pub multiplier_permille: u16,
+    /// Until the next pixel is accepted; 0 when the connection may paint.
+    pub remaining_ms: u32,
+}
+
+/// Appends `u32 cooldown_ms`, `u16 multiplier_permille`, `u32 remaining_ms`.
+pub fn encode_cooldown_config(config: &CooldownConfig, out: &mut Vec<u8>) {
+    out.extend_from_slice(&config.cooldown_ms.to_le_bytes());
+    out.extend_from_slice(&config.multiplier_permille.to_le_bytes());
+    out.extend_from_slice(&config.remaining_ms.to_le_bytes());
+}
+
+pub fn decode_cooldown_config(buf: &[u8]) -> Result<CooldownConfig, String> {
+    if buf.len() != COOLDOWN_CONFIG_LEN {
+        return Err(format!(
+            "cooldown config takes {} bytes, got {}",
+            COOLDOWN_CONFIG_LEN,
+            buf.len()
+        ));
+    }
+    Ok(CooldownConfig {
+        cooldown_ms: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+        multiplier_permille: u16::from_le_bytes(buf[4..6].try_into().unwrap()),
+        remaining_ms: u32::from_le_bytes(buf[6..10].try_into().unwrap()),
+    })
+}
+
+/// Length of a RATE_CONTRACT payload.
+pub const RATE_CONTRACT_LEN: usize = 12;
+
+/// The pixel rate a connection is held to: at most one accepted pixel per
+/// `interval_ms`. The server enforces exactly this; pixels sent sooner are
+/// rejected like any other pixel on cooldown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateContract {
+    /// Counts up with every change on the connection. Pushes travel on
+    /// streams of their own and may arrive out of order: the highest wins.
+    pub seq: u32,
+    pub interval_ms: u32,
+    /// Until the server enforces a tighter contract; 0 when it already
+    /// does. Looser contracts always apply at once.
+    pub effective_in_ms: u32,
+}
+
+impl RateContract {
+    pub fn pixels_per_sec(&self) -> f64 {
+        1000.0 / self.interval_ms.max(1) as f64
+    }
+}
+
+/// Appends `u32 seq`, `u32 interval_ms`, `u32 effective_in_ms`.
+pub fn encode_rate_contract(contract: &RateContract, out: &mut Vec<u8>) {
+    out.extend_from_slice(&contract.seq.to_le_bytes());
+    out.extend_from_slice(&contract.interval_ms.to_le_bytes());
+    out.extend_from_slice(&contract.effective_in_ms.to_le_bytes());
+}
+
+pub fn decode_rate_contract(buf: &[u8]) -> Result<RateContract, String> {
+    if buf.len() != RATE_CONTRACT_LEN {
+        return Err(format!(
+            "rate contract takes {} bytes, got {}",
+            RATE_CONTRACT_LEN,
+            buf.len()
+        ));
+    }
+    Ok(RateContract {
+        seq: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+        interval_ms: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+        effective_in_ms: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+    })
+}
+
+/// Appends a pushed contract: the opcode, then [`encode_rate_contract`].
+pub fn encode_rate_contract_push(contract: &RateContract, out: &mut Vec<u8>) {
+    out.push(ControlOp::RateContract as u8);
+    encode_rate_contract(contract, out);
+}
+
+/// The contract on a stream the server opened.
+pub fn decode_rate_contract_push(buf: &[u8]) -> Result<RateContract, String> {
+    match buf.split_first() {
+        Some((&op, contract)) if op == ControlOp::RateContract as u8 => {
+            decode_rate_contract(contract)
+        }
+        _ => Err("server stream does not carry a rate contract".to_string()),
+    }
+}
+
+/// Appends a pushed cooldown: the opcode, then [`encode_cooldown_config`].
+pub fn encode_cooldown_config_push(config: &CooldownConfig, out: &mut Vec<u8>) {
+    out.push(ControlOp::CooldownConfig as u8);
+    encode_cooldown_config(config, out);
+}
+
+/// Length of a BROADCAST_INTERVAL payload.
+pub const BROADCAST_INTERVAL_LEN: usize = 4;
+
+/// Appends `u32 interval_ms`.
+pub fn encode_broadcast_interval(interval_ms: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&interval_ms.to_le_bytes());
+}
+
+pub fn decode_broadcast_interval(buf: &[u8]) -> Result<u32, String> {
+    let bytes: [u8; BROADCAST_INTERVAL_LEN] = buf.try_into().map_err(|_| {
+        format!(
+            "broadcast interval takes {} bytes, got {}",
+            BROADCAST_INTERVAL_LEN,
+            buf.len()
+        )
+    })?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Appends a pushed interval: the opcode, then
+/// [`encode_broadcast_interval`].
+pub fn encode_broadcast_interval_push(interval_ms: u32, out: &mut Vec<u8>) {
+    out.push(ControlOp::BroadcastInterval as u8);
+    encode_broadcast_interval(interval_ms, out);
+}
+
+/// What a stream the server opened carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerPush {
+    Contract(RateContract),
+    Cooldown(CooldownConfig),
+    /// The broadcast interval, in ms.
+    Interval(u32),
+}
+
+/// Decodes a stream the server opened, by its opcode.
+pub fn decode_server_push(buf: &[u8]) -> Result<ServerPush, String> {
+    match buf.split_first() {
+        Some((&op, contract)) if op == ControlOp::RateContract as u8 => {
+            decode_rate_contract(contract).map(ServerPush::Contract)
+        }
+        Some((&op, config)) if op == ControlOp::CooldownConfig as u8 => {
+            decode_cooldown_config(config).map(ServerPush::Cooldown)
+        }
+        Some((&op, interval)) if op == ControlOp::BroadcastInterval as u8 => {
+            decode_broadcast_interval(interval).map(ServerPush::Interval)
+        }
+        _ => Err("server stream carries no known push".to_string()),
+    }
+}
+
+/// Snapshot number meaning "the newest one" in TILE_CHECKSUMS and
+/// TILE_REFRESH requests; replies always carry the actual number.
+pub const NEWEST_SNAPSHOT: u64 = u64::MAX;
+
+/// Checksums in a TILE_CHECKSUMS reply: one per tile of the subscription
+/// grid, tiles past the canvas edge included.
+pub const TILE_CHECKSUM_COUNT: usize = SUBSCRIBE_BITMAP_LEN * 8;
+
+/// FNV-1a of a tile's pixels, top row first. Meant to catch corruption and
+/// missed updates, not tampering; an empty tile hashes to the FNV offset
+/// basis.
+pub fn tile_checksum<'a>(rows: impl IntoIterator<Item = &'a [u8]>) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    for row in rows {
+        for &pixel in row {
+            hash = (hash ^ pixel as u32).wrapping_mul(0x0100_0193);
+        }
+    }
+    hash
+}
+
+/// Checksum of tile `tile` of a row-major `width` × `height` canvas.
+pub fn canvas_tile_checksum(canvas: &[u8], width: u16, height: u16, tile: usize) -> u32 {
+    let rect = PixelRect::of_tile(tile, width, height);
+    let (x0, x1) = (rect.x0 as usize, rect.x1 as usize);
+    tile_checksum((rect.y0..rect.y1).map(|y| {
+        let row = y as usize * width as usize;
+        &canvas[row + x0..row + x1]
+    }))
+}
+
+/// Tile checksums of one published snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileChecksums {
+    pub seq: u64,
+    pub checksums: [u32; TILE_CHECKSUM_COUNT],
+}
+
+/// Appends a complete TILE_CHECKSUMS request: opcode, then `u64 seq`.
+pub fn encode_tile_checksums_request(seq: u64, out: &mut Vec<u8>) {
+    out.push(ControlOp::TileChecksums as u8);
+    out.extend_from_slice(&seq.to_le_bytes());
+}
+
+/// Argument of a TILE_CHECKSUMS request, given the bytes after the opcode.
+pub fn decode_tile_checksums_request(args: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(args.try_into().ok()?))
+}
+
+/// Appends `u64 seq` and the `u32` checksums, tile 0 first.
+pub fn encode_tile_checksums(seq: u64, checksums: &[u32; TILE_CHECKSUM_COUNT], out: &mut Vec<u8>) {
+    out.reserve(8 + 4 * TILE_CHECKSUM_COUNT);
+    out.extend_from_slice(&seq.to_le_bytes());
+    for checksum in checksums {
+        out.extend_from_slice(&checksum.to_le_bytes());
+    }
+}
+
+pub fn decode_tile_checksums(buf: &[u8]) -> Result<TileChecksums, String> {
+    if buf.len() != 8 + 4 * TILE_CHECKSUM_COUNT {
+        return Err(format!(
+            "tile checksums take {} bytes, got {}",
+            8 + 4 * TILE_CHECKSUM_COUNT,
+            buf.len()
+        ));
+    }
+    let mut checksums = [0u32; TILE_CHECKSUM_COUNT];
+    for (checksum, c) in checksums.iter_mut().zip(buf[8..].chunks_exact(4)) {
+        *checksum = u32::from_le_bytes(c.try_into().unwrap());
+    }
+    Ok(TileChecksums {
+        seq: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        checksums,
+    })
+}
+
+/// Appends a complete TILE_REFRESH request: opcode, `u64 seq`, then a tile
+/// bitmap laid out like SUBSCRIBE's.
+pub fn encode_tile_refresh_request(
+    seq: u64,
+    tiles: &[u8; SUBSCRIBE_BITMAP_LEN],
+    out: &mut Vec<u8>,
+) {
+    out.push(ControlOp::TileRefresh as u8);
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(tiles);
+}
+
+/// Arguments of a TILE_REFRESH request, given the bytes after the opcode.
+pub fn decode_tile_refresh_request(args: &[u8]) -> Option<(u64, [u8; SUBSCRIBE_BITMAP_LEN])> {
+    if args.len() != 8 + SUBSCRIBE_BITMAP_LEN {
+        return None;
+    }
+    let seq = u64::from_le_bytes(args[0..8].try_into().unwrap());
+    Some((seq, args[8..].try_into().unwrap()))
+}
+
+/// Length of a TILE_REFRESH reply before the pixels.
+pub const TILE_REFRESH_HEADER_LEN: usize = 8 + 2 + 2 + SUBSCRIBE_BITMAP_LEN;
+
+/// Tiles of one published snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileRefresh {
+    pub seq: u64,
+    /// Canvas size, which the tiles' shapes follow.
+    pub width: u16,
+    pub height: u16,
+    /// The tiles carried; tiles past the canvas edge never are.
+    pub tiles: [u8; SUBSCRIBE_BITMAP_LEN],
+    /// Their pixels, tile by tile in index order, each top row first.
+    pub pixels: Vec<u8>,
+}
+
+impl TileRefresh {
+    /// Each tile carried, with its pixels.
+    pub fn tiles(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        let mut offset = 0;
+        (0..TILE_CHECKSUM_COUNT)
+            .filter(|t| self.tiles[t / 8] & (1 << (t % 8)) != 0)
+            .map(move |tile| {
+                let area = PixelRect::of_tile(tile, self.width, self.height).area();
+                let pixels = &self.pixels[offset..offset + area];
+                offset += area;
+                (tile, pixels)
+            })
+    }
+}
+
+/// Appends `u64 seq | u16 width | u16 height | tile bitmap` and `rle`, the
+/// tiles' pixels in [`TileRefresh::pixels`] order, run-length encoded like a
+/// full snapshot ([`crate::rle`]).
+pub fn encode_tile_refresh(
+    seq: u64,
+    width: u16,
+    height: u16,
+    tiles: &[u8; SUBSCRIBE_BITMAP_LEN],
+    rle: &[u8],
+    out: &mut Vec<u8>,
+) {
+    out.reserve(TILE_REFRESH_HEADER_LEN + rle.len());
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&width.to_le_bytes());
+    out.extend_from_slice(&height.to_le_bytes());
+    out.extend_from_slice(tiles);
+    out.extend_from_slice(rle);
+}
+
+/// Rejects a reply whose pixels do not exactly fill the tiles it names.
+pub fn decode_tile_refresh(buf: &[u8]) -> Result<TileRefresh, String> {
+    if buf.len() < TILE_REFRESH_HEADER_LEN {
+        return Err("tile refresh truncated before the pixels".into());
+    }
+    let width = u16::from_le_bytes([buf[8], buf[9]]);
+    let height = u16::from_le_bytes([buf[10], buf[11]]);
+    let tiles: [u8; SUBSCRIBE_BITMAP_LEN] = buf[12..TILE_REFRESH_HEADER_LEN].try_into().unwrap();
+    let area: usize = (0..TILE_CHECKSUM_COUNT)
+        .filter(|t| tiles[t / 8] & (1 << (t % 8)) != 0)
+        .map(|t| PixelRect::of_tile(t, width, height).area())
+        .sum();
+    let mut pixels = vec![0; area];
+    let written = crate::rle::rle_decode(&buf[TILE_REFRESH_HEADER_LEN..], &mut pixels)
+        .map_err(|e| format!("tile refresh pixels: {}", e))?;
+    if written != area {
+        return Err(format!(
+            "tile refresh carries {} of {} pixels",
+            written, area
+        ));
+    }
+    Ok(TileRefresh {
+        seq: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        width,
+        height,
+        tiles,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_round_trip() {
+        let entries = [
+            TimelineEntry {
+                minute: 29_000_000,
+                writes: 12,
+                unique_tiles: 3,
+                active_writers: 12,
+            },
+            TimelineEntry {
+                minute: 29_000_001,
+                writes: 0,
+                unique_tiles: 0,
+                active_writers: 12,
+            },
+        ];
+        let mut buf = Vec::new();
+        encode_timeline(entries.iter(), &mut buf);
+        assert_eq!(buf.len(), 4 + 2 * TIMELINE_ENTRY_LEN);
+        assert_eq!(decode_timeline(&buf).unwrap(), entries);
+
+        assert!(decode_timeline(&buf[..buf.len() - 1]).is_err());
+        assert!(decode_timeline(&buf[..2]).is_err());
+
+        assert_eq!(ControlOp::from_u8(1), Some(ControlOp::StatsTimeline));
+        assert_eq!(ControlOp::from_u8(0xff), None);
+    }
+
+    #[test]
+    fn test_catchup_round_trip() {
+        let mut request = Vec::new();
+        encode_catchup_request(41, &mut request);
+        assert_eq!(request.len(), 9);
+        assert_eq!(ControlOp::from_u8(request[0]), Some(ControlOp::Catchup));
+        assert_eq!(decode_catchup_request(&request[1..]), Some(41));
+        assert_eq!(decode_catchup_request(&request[1..8]), None);
+
+        let diff = [7, 0, 0, 0, 5, 0x3f, 0x42, 0x0f, 0, 31];
+        let mut buf = Vec::new();
+        encode_catchup(44, &diff, &mut buf);
+        let catchup = decode_catchup(&buf).unwrap();
+        assert_eq!(catchup.seq, 44);
+        assert_eq!(catchup.pixels, vec![(7, 5), (999_999, 31)]);
+
+        assert!(decode_catchup(&buf[..buf.len() - 1]).is_err());
+        assert!(decode_catchup(&buf[..11]).is_err());
+        assert_eq!(
+            ControlStatus::from_u8(2),
+            Some(ControlStatus::ResyncRequired)
+        );
+    }
+
+    #[test]
+    fn test_subscribe_round_trip() {
+        // Two disjoint viewports: the top-left corner and a strip at the
+        // bottom right crossing a tile boundary.
+        let mut bitmap = [0u8; SUBSCRIBE_BITMAP_LEN];
+        PixelRect {
+            x0: 0,
+            y0: 0,
+            x1: 10,
+            y1: 10,
+        }
+        .add_tiles(&mut bitmap);
+        PixelRect {
+            x0: 900,
+            y0: 990,
+            x1: 1000,
+            y1: 1000,
+        }
+        .add_tiles(&mut bitmap);
+        let tiles: Vec<usize> = (0..256)
+            .filter(|t| bitmap[t / 8] & (1 << (t % 8)) != 0)
+            .collect();
+        assert_eq!(tiles, vec![0, 15 * 16 + 14, 15 * 16 + 15]);
+
+        // Empty and out-of-canvas rectangles.
+        let mut untouched = [0u8; SUBSCRIBE_BITMAP_LEN];
+        PixelRect {
+            x0: 5,
+            y0: 5,
+            x1: 5,
+            y1: 50,
+        }
+        .add_tiles(&mut untouched);
+        assert_eq!(untouched, [0; SUBSCRIBE_BITMAP_LEN]);
+        PixelRect {
+            x0: 0,
+            y0: 0,
+            x1: u16::MAX,
+            y1: u16::MAX,
+        }
+        .add_tiles(&mut untouched);
+        assert_eq!(untouched, [0xff; SUBSCRIBE_BITMAP_LEN]);
+
+        let rect = PixelRect {
+            x0: 1,
+            y0: 2,
+            x1: 300,
+            y1: 400,
+        };
+        for sub in [
+            Subscription::All,
+            Subscription::Tiles(bitmap),
+            Subscription::Rect(rect),
+        ] {
+            let mut buf = Vec::new();
+            encode_subscribe(&sub, &mut buf);
+            assert_eq!(ControlOp::from_u8(buf[0]), Some(ControlOp::Subscribe));
+            assert_eq!(decode_subscribe(&buf[1..]), Some(sub));
+            assert_eq!(decode_subscribe(&buf[1..buf.len() - 1]), None);
+        }
+        assert_eq!(decode_subscribe(&[]), None);
+        assert_eq!(decode_subscribe(&[9]), None);
+    }
+
+    #[test]
+    fn test_tier_request() {
+        let mut request = Vec::new();
+        encode_tier_request(b"streamer-7", &mut request);
+        assert_eq!(ControlOp::from_u8(request[0]), Some(ControlOp::Tier));
+        assert_eq!(decode_tier_request(&request[1..]), Some(&b"streamer-7"[..]));
+        assert_eq!(decode_tier_request(&[]), None);
+        assert_eq!(decode_tier_request(&[b'x'; MAX_TIER_TOKEN_LEN + 1]), None);
+        assert_eq!(ControlStatus::from_u8(5), Some(ControlStatus::Forbidden));
+    }
+
+    #[test]
+    fn test_resume_request() {
+        let token = [7u8; RESUME_TOKEN_LEN];
+        let mut request = Vec::new();
+        encode_resume_request(&token, &mut request);
+        assert_eq!(ControlOp::from_u8(request[0]), Some(ControlOp::Resume));
+        assert_eq!(decode_resume_request(&request[1..]), Some(&token));
+        assert_eq!(decode_resume_request(&request[2..]), None);
+        assert_eq!(decode_resume_request(&[0; RESUME_TOKEN_LEN + 1]), None);
+    }
+
+    #[test]
+    fn test_cooldown_config_round_trip() {
+        assert_eq!(ControlOp::from_u8(0x0A), Some(ControlOp::CooldownConfig));
+        let config = CooldownConfig {
+            cooldown_ms: 300_000,
+            multiplier_permille: COOLDOWN_MULTIPLIER_ONE,
+            remaining_ms: 41_250,
+        };
+        let mut buf = Vec::new();
+        encode_cooldown_config(&config, &mut buf);
+        assert_eq!(buf.len(), COOLDOWN_CONFIG_LEN);
+        assert_eq!(decode_cooldown_config(&buf), Ok(config));
+        assert!(decode_cooldown_config(&buf[..COOLDOWN_CONFIG_LEN - 1]).is_err());
+
+        let mut push = Vec::new();
+        encode_cooldown_config_push(&config, &mut push);
+        assert_eq!(push[0], 0x0A);
+        assert_eq!(push[1..], buf[..]);
+        assert_eq!(decode_server_push(&push), Ok(ServerPush::Cooldown(config)));
+        assert!(decode_server_push(&push[..COOLDOWN_CONFIG_LEN]).is_err());
+        assert!(decode_rate_contract_push(&push).is_err());
+    }
+
+    #[test]
+    fn test_rate_contract_round_trip() {
+        assert_eq!(ControlOp::from_u8(0x0B), Some(ControlOp::RateContract));
+        let contract = RateContract {
+            seq: 2,
+            interval_ms: 450_000,
+            effective_in_ms: 5_000,
+        };
+        let mut push = Vec::new();
+        encode_rate_contract_push(&contract, &mut push);
+        assert_eq!(
+            push,
+            [0x0B, 2, 0, 0, 0, 0xd0, 0xdd, 0x06, 0, 0x88, 0x13, 0, 0]
+        );
+        assert_eq!(decode_rate_contract_push(&push), Ok(contract));
+        assert_eq!(
+            decode_server_push(&push),
+            Ok(ServerPush::Contract(contract))
+        );
+        assert_eq!(decode_rate_contract(&push[1..]), Ok(contract));
+        assert!(decode_rate_contract_push(&push[..RATE_CONTRACT_LEN]).is_err());
+        push[0] = ControlOp::CooldownConfig as u8;
+        assert!(decode_rate_contract_push(&push).is_err());
+        assert!(decode_rate_contract_push(&[]).is_err());
+        assert!(decode_server_push(&[]).is_err());
+        let per_minute = RateContract {
+            interval_ms: 60_000,
+            ..contract
+        };
+        assert!((per_minute.pixels_per_sec() * 60.0 - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_broadcast_interval_round_trip() {
+        assert_eq!(ControlOp::from_u8(0x0C), Some(ControlOp::BroadcastInterval));
+        let mut push = Vec::new();
+        encode_broadcast_interval_push(150, &mut push);
+        assert_eq!(push, [0x0C, 150, 0, 0, 0]);
+        assert_eq!(decode_server_push(&push), Ok(ServerPush::Interval(150)));
+        assert_eq!(decode_broadcast_interval(&push[1..]), Ok(150));
+        assert!(decode_broadcast_interval(&push[2..]).is_err());
+        assert!(decode_server_push(&push[..BROADCAST_INTERVAL_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_tile_checksums_round_trip() {
+        let mut request = Vec::new();
+        encode_tile_checksums_request(NEWEST_SNAPSHOT, &mut request);
+        assert_eq!(
+            ControlOp::from_u8(request[0]),
+            Some(ControlOp::TileChecksums)
+        );
+        assert_eq!(
+            decode_tile_checksums_request(&request[1..]),
+            Some(NEWEST_SNAPSHOT)
+        );
+        assert_eq!(decode_tile_checksums_request(&request[2..]), None);
+
+        // A 1000×1000 canvas: the last column and row of tiles are 40 pixels
+        // wide, and changing one pixel changes only its tile's checksum.
+        let (width, height) = (1000u16, 1000u16);
+        let mut canvas = vec![3u8; width as usize * height as usize];
+        assert_eq!(PixelRect::of_tile(15, width, height).area(), 40 * 64);
+        assert_eq!(PixelRect::of_tile(255, width, height).area(), 40 * 40);
+        let before: Vec<u32> = (0..TILE_CHECKSUM_COUNT)
+            .map(|t| canvas_tile_checksum(&canvas, width, height, t))
+            .collect();
+        canvas[70 * width as usize + 999] = 4;
+        let after: Vec<u32> = (0..TILE_CHECKSUM_COUNT)
+            .map(|t| canvas_tile_checksum(&canvas, width, height, t))
+            .collect();
+        let changed: Vec<usize> = (0..TILE_CHECKSUM_COUNT)
+            .filter(|&t| before[t] != after[t])
+            .collect();
+        assert_eq!(changed, vec![16 + 15]);
+        assert_eq!(tile_checksum([]), 0x811c_9dc5);
+
+        let mut buf = Vec::new();
+        encode_tile_checksums(12, &after.clone().try_into().unwrap(), &mut buf);
+        let decoded = decode_tile_checksums(&buf).unwrap();
+        assert_eq!(decoded.seq, 12);
+        assert_eq!(decoded.checksums.to_vec(), after);
+        assert!(decode_tile_checksums(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_tile_refresh_round_trip() {
+        let mut tiles = [0u8; SUBSCRIBE_BITMAP_LEN];
+        tiles[0] = 0b11;
+        tiles[31] = 0x80;
+        let mut request = Vec::new();
+        encode_tile_refresh_request(9, &tiles, &mut request);
+        assert_eq!(ControlOp::from_u8(request[0]), Some(ControlOp::TileRefresh));
+        assert_eq!(decode_tile_refresh_request(&request[1..]), Some((9, tiles)));
+        assert_eq!(decode_tile_refresh_request(&request[1..9]), None);
+
+        // Tiles 0, 1 and 255 of a 100×70 canvas: 64×64, 36×64 and nothing.
+        let pixels: Vec<u8> = (0..64 * 64)
+            .map(|_| 1)
+            .chain((0..36 * 64).map(|i| (i / 36) as u8))
+            .collect();
+        let mut rle = vec![0; crate::rle::rle_max_len(pixels.len())];
+        let rle_len = crate::rle::rle_encode(&pixels, &mut rle);
+        let mut buf = Vec::new();
+        encode_tile_refresh(9, 100, 70, &tiles, &rle[..rle_len], &mut buf);
+        let refresh = decode_tile_refresh(&buf).unwrap();
+        assert_eq!((refresh.seq, refresh.width, refresh.height), (9, 100, 70));
+        let carried: Vec<(usize, usize)> = refresh.tiles().map(|(t, p)| (t, p.len())).collect();
+        assert_eq!(carried, vec![(0, 64 * 64), (1, 36 * 64), (255, 0)]);
+        assert_eq!(refresh.pixels, pixels);
+
+        // Short by a run, or a run too many.
+        assert!(decode_tile_refresh(&buf[..buf.len() - 2]).is_err());
+        buf.extend_from_slice(&[1, 1]);
+        assert!(decode_tile_refresh(&buf).is_err());
+        assert!(decode_tile_refresh(&buf[..TILE_REFRESH_HEADER_LEN - 1]).is_err());
+    }
+}
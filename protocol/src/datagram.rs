@@ -0,0 +1,68 @@
+//! The type byte every application datagram starts with, in both
+//! directions. A receiver dispatches on it rather than on lengths and
+//! contents, so a new kind of datagram is told apart from the others by
+//! construction, and one a peer does not know is dropped and counted
+//! instead of being parsed as something else.
+//!
+//! ```text
+//! type u8 | body
+//! ```
+//!
+//! | type | direction | body |
+//! |------|-----------|------|
+//! | [`TAGGED`] | both | `tag [u8; 3] \| ...`, see [`crate::broadcast`] |
+//! | [`PIXEL`] | client to server | one placement, see [`crate::pixel`] |
+//! | [`PIXEL_BATCH`] | client to server | several, see [`crate::batch`] |
+//! | [`PIXEL_SEQ`] | client to server | one numbered placement, see [`crate::receipt`] |
+//! | [`FULL_RLE`] | server to client | a chunk of a full RLE snapshot |
+//! | [`DIFF`] | server to client | a chunk of a raw snapshot diff |
+//! | [`EPOCH`] | server to client | the canvas round, see [`crate::epoch`] |
+//! | [`COOLDOWN_NACK`] | server to client | a pixel rejected for cooldown, see [`crate::nack`] |
+//! | [`PIXEL_ACK`] | server to client | a pixel accepted, see [`crate::receipt`] |
+//!
+//! Types are part of the wire contract: never reuse one. Tagged datagrams
+//! (FEC chunks, STATS requests and replies, compressed diffs) keep the
+//! layout they had before types: their leading 0 is their type.
+
+/// Bytes of the type.
+pub const TYPE_LEN: usize = 1;
+
+/// Followed by a 3-byte tag naming the format.
+pub const TAGGED: u8 = 0x00;
+pub const PIXEL: u8 = 0x01;
+pub const PIXEL_BATCH: u8 = 0x02;
+pub const PIXEL_SEQ: u8 = 0x03;
+pub const FULL_RLE: u8 = 0x10;
+pub const DIFF: u8 = 0x11;
+pub const EPOCH: u8 = 0x12;
+pub const COOLDOWN_NACK: u8 = 0x20;
+pub const PIXEL_ACK: u8 = 0x21;
+
+/// Type and body of `datagram`, None if it is empty.
+#[inline]
+pub fn split(datagram: &[u8]) -> Option<(u8, &[u8])> {
+    let (&kind, body) = datagram.split_first()?;
+    Some((kind, body))
+}
+
+/// Appends a datagram of type `kind` carrying `body`.
+pub fn push_typed(kind: u8, body: &[u8], out: &mut Vec<u8>) {
+    out.reserve(TYPE_LEN + body.len());
+    out.push(kind);
+    out.extend_from_slice(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_leads_the_body() {
+        let mut wire = Vec::new();
+        push_typed(DIFF, &[9, 0, 0, 0, 3], &mut wire);
+        assert_eq!(wire, [0x11, 9, 0, 0, 0, 3]);
+        assert_eq!(split(&wire), Some((DIFF, &wire[1..])));
+        assert_eq!(split(&[PIXEL]), Some((PIXEL, &[][..])));
+        assert_eq!(split(&[]), None);
+    }
+}
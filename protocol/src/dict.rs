@@ -0,0 +1,278 @@
+//! Dictionary-compressed diff broadcasts (the
+//! [`Features::ZSTD`](crate::version::Features) feature).
+//!
+//! Diff datagrams are runs of `u32 LE index | u8 color` records, often a few
+//! hundred bytes: too little for plain zstd to find repetitions in. Indices
+//! are sorted, so the records are first packed as gaps ([`pack_diff`]), then
+//! compressed with a dictionary trained on recorded diff traffic
+//! (`client --mode train-dict`) that already knows what packed records look
+//! like. Each datagram is compressed on its own, so losing one still costs
+//! only its own pixels.
+//!
+//! A compressed datagram is [`ZSTD_DIFF_TAG`] followed by one zstd frame,
+//! which names its dictionary and holds the packed records: a tagged
+//! datagram (see [`crate::datagram`]), whose tag is the start of the zstd
+//! magic.
+//!
+//! Dictionaries are plain zstd dictionaries, as `zstd --train` writes them,
+//! whose id encodes a dictionary version ([`dict_id`]). A peer offering ZSTD
+//! decodes every version in [`DIFF_DICTS`]; a retrained dictionary is added
+//! as the next version rather than replacing one, so older servers keep
+//! working with newer clients.
+
+/// Precedes the zstd frame of a compressed diff datagram.
+pub const ZSTD_DIFF_TAG: u8 = 0x00;
+
+/// Every zstd frame starts with it.
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Every zstd dictionary starts with it, followed by its `u32 LE` id.
+pub const DICT_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+/// Canvas indices must fit in 24 bits for raw diff chunks to stay
+/// distinguishable from compressed ones.
+pub const MAX_CANVAS_PIXELS: usize = 1 << 24;
+
+/// Size of one `u32 LE index | u8 color` diff record.
+pub const DIFF_RECORD_LEN: usize = 5;
+
+/// zstd level servers compress diffs at; `train-dict` measures at it too.
+pub const DIFF_ZSTD_LEVEL: i32 = 3;
+
+/// High half of every diff dictionary id ("CV"); the low half is the
+/// version. Clear of the ranges zstd reserves (below 32768, 2^31 and up).
+const DICT_ID_BASE: u32 = 0x4356_0000;
+
+/// Dictionary versions clients decode, oldest first.
+pub static DIFF_DICTS: [(u16, &[u8]); 1] = [(1, include_bytes!("../dicts/diff-v1.zdict"))];
+
+/// The id a version-`version` diff dictionary carries.
+pub const fn dict_id(version: u16) -> u32 {
+    DICT_ID_BASE | version as u32
+}
+
+/// Version of a diff dictionary, or None for anything else (including zstd
+/// dictionaries with a foreign id).
+pub fn dict_version(dict: &[u8]) -> Option<u16> {
+    if dict.len() < 8 || dict[..4] != DICT_MAGIC {
+        return None;
+    }
+    let id = u32::from_le_bytes(dict[4..8].try_into().unwrap());
+    (id & 0xFFFF_0000 == DICT_ID_BASE && id != DICT_ID_BASE).then_some(id as u16)
+}
+
+/// Rewrites the id of a freshly trained zstd dictionary so it becomes diff
+/// dictionary `version`. False if `dict` is not a zstd dictionary.
+pub fn set_dict_version(dict: &mut [u8], version: u16) -> bool {
+    if version == 0 || dict.len() < 8 || dict[..4] != DICT_MAGIC {
+        return false;
+    }
+    dict[4..8].copy_from_slice(&dict_id(version).to_le_bytes());
+    true
+}
+
+/// The shipped dictionary with that version.
+pub fn diff_dict(version: u16) -> Option<&'static [u8]> {
+    DIFF_DICTS
+        .iter()
+        .find(|(v, _)| *v == version)
+        .map(|&(_, dict)| dict)
+}
+
+/// The zstd frame of a compressed diff datagram; None for uncompressed
+/// broadcasts.
+pub fn zstd_frame(datagram: &[u8]) -> Option<&[u8]> {
+    match datagram.split_first() {
+        Some((&ZSTD_DIFF_TAG, frame)) if frame.starts_with(&ZSTD_MAGIC) => Some(frame),
+        _ => None,
+    }
+}
+
+/// Dictionary id in a zstd frame header; None if the frame names none.
+pub fn frame_dict_id(frame: &[u8]) -> Option<u32> {
+    if !frame.starts_with(&ZSTD_MAGIC) || frame.len() < 5 {
+        return None;
+    }
+    let descriptor = frame[4];
+    let single_segment = descriptor & 0x20 != 0;
+    let start = 5 + usize::from(!single_segment);
+    let len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let field = frame.get(start..start + len)?;
+    let mut bytes = [0u8; 4];
+    bytes[..len].copy_from_slice(field);
+    Some(u32::from_le_bytes(bytes)).filter(|&id| id != 0)
+}
+
+/// Packs raw diff records as `varint gap | u8 color`, the gap being the
+/// distance to the previous index minus one (the first is its index). The
+/// varint is LEB128: 7 bits per byte, low bits first. `raw` must pass
+/// [`is_raw_diff`].
+pub fn pack_diff(raw: &[u8], out: &mut Vec<u8>) {
+    let mut next = 0;
+    for record in raw.chunks_exact(DIFF_RECORD_LEN) {
+        let index = u32::from_le_bytes(record[..4].try_into().unwrap());
+        let mut gap = index - next;
+        while gap >= 0x80 {
+            out.push(gap as u8 | 0x80);
+            gap >>= 7;
+        }
+        out.push(gap as u8);
+        out.push(record[4]);
+        next = index + 1;
+    }
+}
+
+/// Inverse of [`pack_diff`].
+pub fn unpack_diff(packed: &[u8], out: &mut Vec<u8>) -> Result<(), String> {
+    let mut next: u32 = 0;
+    let mut pos = 0;
+    while pos < packed.len() {
+        let mut gap: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let Some(&byte) = packed.get(pos) else {
+                return Err(format!("packed diff truncated at byte {}", pos));
+            };
+            pos += 1;
+            if shift > 21 {
+                return Err(format!("gap too long at byte {}", pos));
+            }
+            gap |= u32::from(byte & 0x7F) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let Some(&color) = packed.get(pos) else {
+            return Err(format!("packed diff truncated at byte {}", pos));
+        };
+        pos += 1;
+        let index = next.saturating_add(gap);
+        if index as usize >= MAX_CANVAS_PIXELS {
+            return Err(format!("index {} off any canvas", index));
+        }
+        out.extend_from_slice(&index.to_le_bytes());
+        out.push(color);
+        next = index + 1;
+    }
+    Ok(())
+}
+
+/// Whether `chunk`, the body of a DIFF datagram, is whole records with
+/// strictly increasing in-range indices, as the server writes them; used
+/// to check training samples out of observer logs.
+pub fn is_raw_diff(chunk: &[u8]) -> bool {
+    if chunk.is_empty() || !chunk.len().is_multiple_of(DIFF_RECORD_LEN) {
+        return false;
+    }
+    let mut last = None;
+    for record in chunk.chunks_exact(DIFF_RECORD_LEN) {
+        let index = u32::from_le_bytes(record[..4].try_into().unwrap());
+        if index as usize >= MAX_CANVAS_PIXELS || last.is_some_and(|last| index <= last) {
+            return false;
+        }
+        last = Some(index);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(indices: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (n, &i) in indices.iter().enumerate() {
+            out.extend_from_slice(&i.to_le_bytes());
+            out.push(n as u8 % 16);
+        }
+        out
+    }
+
+    #[test]
+    fn test_shipped_dictionaries_round_trip() {
+        for (n, &(version, dict)) in DIFF_DICTS.iter().enumerate() {
+            assert_eq!(dict_version(dict), Some(version));
+            assert_eq!(diff_dict(version), Some(dict));
+            assert!(n == 0 || DIFF_DICTS[n - 1].0 < version);
+        }
+        let (version, dict) = DIFF_DICTS[DIFF_DICTS.len() - 1];
+
+        let raw = diff(&(0..240).map(|i| 500_000 + i * 3).collect::<Vec<_>>());
+        assert!(is_raw_diff(&raw));
+        let mut packed = Vec::new();
+        pack_diff(&raw, &mut packed);
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(DIFF_ZSTD_LEVEL, dict).unwrap();
+        let mut datagram = vec![ZSTD_DIFF_TAG];
+        datagram.extend_from_slice(&compressor.compress(&packed).unwrap());
+        assert!(datagram.len() < raw.len() / 2);
+        assert!(!is_raw_diff(&datagram));
+
+        let frame = zstd_frame(&datagram).unwrap();
+        assert_eq!(frame_dict_id(frame), Some(dict_id(version)));
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(dict).unwrap();
+        let mut unpacked = Vec::new();
+        unpack_diff(
+            &decompressor.decompress(frame, 4096).unwrap(),
+            &mut unpacked,
+        )
+        .unwrap();
+        assert_eq!(unpacked, raw);
+
+        // Without a dictionary the frame names none.
+        let plain = zstd::bulk::compress(&raw, DIFF_ZSTD_LEVEL).unwrap();
+        assert_eq!(frame_dict_id(&plain), None);
+    }
+
+    #[test]
+    fn test_pack_round_trip() {
+        let raw = diff(&[0, 1, 2, 130, 16_513, 999_999, (1 << 24) - 1]);
+        let mut packed = Vec::new();
+        pack_diff(&raw, &mut packed);
+        // Gaps 0, 0, 0, 127 and 16382 take 1, 1, 1, 1 and 2 bytes.
+        assert_eq!(&packed[..10], &[0, 0, 0, 1, 0, 2, 127, 3, 0xFE, 0x7F]);
+        let mut unpacked = Vec::new();
+        unpack_diff(&packed, &mut unpacked).unwrap();
+        assert_eq!(unpacked, raw);
+
+        for bad in [
+            &[0x80][..],
+            &[5],
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 1],
+            &[0xFF, 0xFF, 0xFF, 0x7F, 1],
+        ] {
+            assert!(unpack_diff(bad, &mut Vec::new()).is_err(), "{:?}", bad);
+        }
+        let mut empty = Vec::new();
+        pack_diff(&[], &mut empty);
+        assert!(empty.is_empty() && unpack_diff(&[], &mut empty).is_ok());
+    }
+
+    #[test]
+    fn test_broadcast_kinds_are_told_apart() {
+        // Raw diffs: the fourth byte is always 0, the first one may be too.
+        let raw = diff(&[0, 7, 999_999]);
+        assert!(is_raw_diff(&raw) && zstd_frame(&raw).is_none());
+        assert!(!is_raw_diff(&diff(&[7, 7])));
+        assert!(!is_raw_diff(&diff(&[1 << 24])));
+        assert!(!is_raw_diff(&raw[..raw.len() - 1]));
+
+        // Full snapshot chunks: (run length >= 1, color) pairs.
+        let rle: Vec<u8> = (0..600).flat_map(|i| [255, i as u8]).collect();
+        assert!(!is_raw_diff(&rle) && zstd_frame(&rle).is_none());
+
+        let mut tagged = vec![ZSTD_DIFF_TAG];
+        tagged.extend_from_slice(&ZSTD_MAGIC);
+        assert!(zstd_frame(&tagged).is_some());
+        assert!(zstd_frame(&tagged[..4]).is_none());
+
+        let mut dict = DICT_MAGIC.to_vec();
+        dict.extend_from_slice(&[0; 60]);
+        assert_eq!(dict_version(&dict), None);
+        assert!(set_dict_version(&mut dict, 9));
+        assert_eq!(dict_version(&dict), Some(9));
+        assert!(!set_dict_version(&mut dict, 0));
+        assert!(!set_dict_version(&mut raw.clone(), 1));
+    }
+}
@@ -0,0 +1,46 @@
+//! EPOCH datagrams (`Features::EPOCHS`): which round of the canvas the
+//! broadcasts after it belong to. An operator can start a new round without
+//! restarting the server; the canvas goes back to a background and every
+//! cooldown is lifted. Every integer is little-endian:
+//!
+//! ```text
+//! type u8 (EPOCH) | epoch u32
+//! ```
+//!
+//! The server sends one ahead of every full snapshot, so a client that lost
+//! the one announcing a change learns of it with the next full broadcast.
+//! A client seeing a new number drops what it rebuilt of the old canvas and
+//! resyncs from the snapshot that follows.
+
+use crate::datagram::{EPOCH, TYPE_LEN};
+
+/// Bytes of an EPOCH datagram.
+pub const EPOCH_LEN: usize = TYPE_LEN + 4;
+
+pub fn encode_epoch(epoch: u32) -> [u8; EPOCH_LEN] {
+    let mut out = [EPOCH; EPOCH_LEN];
+    out[TYPE_LEN..].copy_from_slice(&epoch.to_le_bytes());
+    out
+}
+
+/// The epoch in the body of an EPOCH datagram (after its type).
+pub fn decode_epoch(body: &[u8]) -> Option<u32> {
+    let epoch: [u8; 4] = body.try_into().ok()?;
+    Some(u32::from_le_bytes(epoch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram;
+
+    #[test]
+    fn test_epoch_round_trip() {
+        let wire = encode_epoch(0x0102_0304);
+        assert_eq!(wire, [0x12, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(datagram::split(&wire), Some((EPOCH, &wire[1..])));
+        assert_eq!(decode_epoch(&wire[1..]), Some(0x0102_0304));
+        assert_eq!(decode_epoch(&wire[1..4]), None);
+        assert_eq!(decode_epoch(&[0; 5]), None);
+    }
+}
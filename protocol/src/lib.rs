@@ -0,0 +1,17 @@
+//! Wire definitions shared by the server and the load client.
+
+pub mod batch;
+pub mod broadcast;
+pub mod close;
+pub mod control;
+pub mod datagram;
+pub mod dict;
+pub mod epoch;
+pub mod fec;
+pub mod nack;
+pub mod pixel;
+pub mod receipt;
+pub mod rle;
+pub mod stats;
+pub mod version;
+pub mod webtransport;
@@ -0,0 +1,53 @@
+//! COOLDOWN_NACK datagrams: the server telling a connection that a pixel it
+//! sent was rejected because the connection is on cooldown, and how long
+//! the cooldown has left, rounded up to the second. Every integer is
+//! little-endian:
+//!
+//! ```text
+//! type u8 (COOLDOWN_NACK) | remaining_secs u16
+//! ```
+//!
+//! A connection gets at most one per second, however many pixels it sends
+//! meanwhile, so a flood cannot be turned into as many replies. Counting
+//! NACKs undercounts rejected pixels for a client sending faster than that.
+
+use crate::datagram::{self, COOLDOWN_NACK};
+
+pub const COOLDOWN_NACK_LEN: usize = datagram::TYPE_LEN + 2;
+
+pub fn encode_cooldown_nack(remaining_secs: u16) -> [u8; COOLDOWN_NACK_LEN] {
+    let secs = remaining_secs.to_le_bytes();
+    [COOLDOWN_NACK, secs[0], secs[1]]
+}
+
+/// The seconds left in the body of a COOLDOWN_NACK (after its type).
+pub fn decode_cooldown_nack(body: &[u8]) -> Option<u16> {
+    let secs: [u8; 2] = body.try_into().ok()?;
+    Some(u16::from_le_bytes(secs))
+}
+
+/// `remaining_ms` of cooldown as a NACK carries it: whole seconds, rounded
+/// up so a client that waits them out is past the cooldown.
+pub fn remaining_secs(remaining_ms: u32) -> u16 {
+    remaining_ms.div_ceil(1000).min(u16::MAX as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown_nack_round_trip() {
+        let wire = encode_cooldown_nack(299);
+        assert_eq!(wire, [0x20, 0x2b, 0x01]);
+        assert_eq!(datagram::split(&wire), Some((COOLDOWN_NACK, &wire[1..])));
+        assert_eq!(decode_cooldown_nack(&wire[1..]), Some(299));
+        assert_eq!(decode_cooldown_nack(&wire[1..2]), None);
+        assert_eq!(decode_cooldown_nack(&[1, 0, 0]), None);
+
+        assert_eq!(remaining_secs(0), 0);
+        assert_eq!(remaining_secs(1), 1);
+        assert_eq!(remaining_secs(299_001), 300);
+        assert_eq!(remaining_secs(u32::MAX), u16::MAX);
+    }
+}
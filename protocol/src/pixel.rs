@@ -0,0 +1,107 @@
+//! The pixel placement on the wire, the one layout the server parses and
+//! every client builds. All integers are little-endian, whatever the
+//! sender's architecture. A PIXEL datagram (see [`crate::datagram`]) is
+//! one placement record:
+//!
+//! ```text
+//! PIXEL u8 | x u16 | y u16 | color u8 [| token u32 [| nonce u32]]
+//! ```
+//!
+//! The token and nonce are optional trailers (see the server's
+//! admission.rs); a batch (see [`crate::batch`]) carries bare records, and
+//! a PIXEL_SEQ (see [`crate::receipt`]) numbers the placement.
+
+use crate::datagram::{PIXEL, TYPE_LEN};
+
+/// Bytes of a placement without trailers.
+pub const PIXEL_LEN: usize = 5;
+
+/// Bytes of a PIXEL datagram without trailers.
+pub const PIXEL_DATAGRAM_LEN: usize = TYPE_LEN + PIXEL_LEN;
+
+/// Bytes of each optional trailer.
+pub const TRAILER_LEN: usize = 4;
+
+/// The canvas placements land on, in pixels. The server drops placements
+/// outside it; diff indices are `y * CANVAS_WIDTH + x`.
+pub const CANVAS_WIDTH: u16 = 1000;
+pub const CANVAS_HEIGHT: u16 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pixel {
+    pub x: u16,
+    pub y: u16,
+    pub color: u8,
+}
+
+impl Pixel {
+    pub fn to_wire(&self, out: &mut [u8; PIXEL_LEN]) {
+        out[0..2].copy_from_slice(&self.x.to_le_bytes());
+        out[2..4].copy_from_slice(&self.y.to_le_bytes());
+        out[4] = self.color;
+    }
+
+    /// The placement at the start of `wire`, None if it is shorter than
+    /// [`PIXEL_LEN`]. Trailers, if any, are left to the caller.
+    #[inline]
+    pub fn from_wire(wire: &[u8]) -> Option<Self> {
+        let record: &[u8; PIXEL_LEN] = wire.get(..PIXEL_LEN)?.try_into().ok()?;
+        Some(Self {
+            x: u16::from_le_bytes([record[0], record[1]]),
+            y: u16::from_le_bytes([record[2], record[3]]),
+            color: record[4],
+        })
+    }
+}
+
+/// The PIXEL datagram placing `record`; trailers, if any, go after it.
+pub fn datagram(record: &[u8; PIXEL_LEN]) -> [u8; PIXEL_DATAGRAM_LEN] {
+    let mut out = [PIXEL; PIXEL_DATAGRAM_LEN];
+    out[TYPE_LEN..].copy_from_slice(record);
+    out
+}
+
+/// Appends a token or nonce trailer.
+pub fn push_trailer(value: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// The trailer at `wire[at..at + 4]`.
+#[inline]
+pub fn trailer_at(wire: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([wire[at], wire[at + 1], wire[at + 2], wire[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Golden bytes: a layout change has to change these on purpose.
+    #[test]
+    fn test_pixel_wire_layout() {
+        let pixel = Pixel {
+            x: 0x0102,
+            y: 999,
+            color: 31,
+        };
+        let mut wire = [0; PIXEL_LEN];
+        pixel.to_wire(&mut wire);
+        assert_eq!(wire, [0x02, 0x01, 0xe7, 0x03, 0x1f]);
+        assert_eq!(Pixel::from_wire(&wire), Some(pixel));
+
+        let mut nonced = datagram(&wire).to_vec();
+        push_trailer(0xdead_beef, &mut nonced);
+        push_trailer(7, &mut nonced);
+        assert_eq!(
+            nonced,
+            [
+                0x01, 0x02, 0x01, 0xe7, 0x03, 0x1f, 0xef, 0xbe, 0xad, 0xde, 7, 0, 0, 0
+            ]
+        );
+        assert_eq!(Pixel::from_wire(&nonced[TYPE_LEN..]), Some(pixel));
+        assert_eq!(trailer_at(&nonced, PIXEL_DATAGRAM_LEN), 0xdead_beef);
+        assert_eq!(trailer_at(&nonced, PIXEL_DATAGRAM_LEN + TRAILER_LEN), 7);
+
+        assert_eq!(Pixel::from_wire(&wire[..4]), None);
+    }
+}
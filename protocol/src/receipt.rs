@@ -0,0 +1,91 @@
+//! Pixel receipts (`Features::RECEIPTS`): the server acknowledging each
+//! pixel it queued for the canvas, so a client can tell an accepted write
+//! from one dropped for cooldown, a full queue or a bad position. Only
+//! connections that negotiated RECEIPTS get them; the load path does not
+//! pay for the extra datagrams. Every integer is little-endian:
+//!
+//! ```text
+//! PIXEL_SEQ u8 | seq u16 | x u16 | y u16 | color u8 [| token u32 [| nonce u32]]
+//! PIXEL_ACK u8 [| seq u16]
+//! ```
+//!
+//! PIXEL_SEQ is a PIXEL (see [`crate::pixel`]) with a sequence number of
+//! the client's choosing, which the ACK echoes. A pixel sent without one,
+//! alone or in a batch, is acknowledged by the bare type.
+
+use crate::datagram::{PIXEL_ACK, PIXEL_SEQ, TYPE_LEN};
+use crate::pixel::PIXEL_LEN;
+
+/// Bytes of the sequence number.
+pub const SEQ_LEN: usize = 2;
+
+/// Bytes of a PIXEL_SEQ datagram without trailers.
+pub const SEQ_DATAGRAM_LEN: usize = TYPE_LEN + SEQ_LEN + PIXEL_LEN;
+
+/// Bytes of the longest PIXEL_ACK.
+pub const MAX_ACK_LEN: usize = TYPE_LEN + SEQ_LEN;
+
+/// The PIXEL_SEQ datagram placing `record` as number `seq`; trailers, if
+/// any, go after it.
+pub fn seq_datagram(seq: u16, record: &[u8; PIXEL_LEN]) -> [u8; SEQ_DATAGRAM_LEN] {
+    let mut out = [PIXEL_SEQ; SEQ_DATAGRAM_LEN];
+    out[TYPE_LEN..TYPE_LEN + SEQ_LEN].copy_from_slice(&seq.to_le_bytes());
+    out[TYPE_LEN + SEQ_LEN..].copy_from_slice(record);
+    out
+}
+
+/// The sequence number of a PIXEL_SEQ body (after its type) and the
+/// placement after it, trailers included; None if it is too short to hold
+/// the number.
+#[inline]
+pub fn split_seq(body: &[u8]) -> Option<(u16, &[u8])> {
+    let (seq, rest) = body.split_first_chunk::<SEQ_LEN>()?;
+    Some((u16::from_le_bytes(*seq), rest))
+}
+
+/// The ACK for a pixel sent as number `seq`, or without one.
+pub fn encode_ack(seq: Option<u16>, out: &mut [u8; MAX_ACK_LEN]) -> &[u8] {
+    out[0] = PIXEL_ACK;
+    match seq {
+        Some(seq) => {
+            out[TYPE_LEN..].copy_from_slice(&seq.to_le_bytes());
+            &out[..]
+        }
+        None => &out[..TYPE_LEN],
+    }
+}
+
+/// The echoed number in the body of a PIXEL_ACK (after its type): Some(None)
+/// for a bare ACK, None if the body is neither empty nor a number.
+pub fn decode_ack(body: &[u8]) -> Option<Option<u16>> {
+    match body {
+        [] => Some(None),
+        &[lo, hi] => Some(Some(u16::from_le_bytes([lo, hi]))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram;
+
+    // Golden bytes: a layout change has to change these on purpose.
+    #[test]
+    fn test_receipt_wire_layout() {
+        let wire = seq_datagram(0x0102, &[0x02, 0x01, 0xe7, 0x03, 0x1f]);
+        assert_eq!(wire, [0x03, 0x02, 0x01, 0x02, 0x01, 0xe7, 0x03, 0x1f]);
+        let (kind, body) = datagram::split(&wire).unwrap();
+        assert_eq!(kind, PIXEL_SEQ);
+        assert_eq!(split_seq(body), Some((0x0102, &wire[3..])));
+        assert_eq!(split_seq(&body[..1]), None);
+
+        let mut buf = [0; MAX_ACK_LEN];
+        assert_eq!(encode_ack(Some(0x0102), &mut buf), [0x21, 0x02, 0x01]);
+        assert_eq!(decode_ack(&buf[1..]), Some(Some(0x0102)));
+        assert_eq!(encode_ack(None, &mut buf), [0x21]);
+        assert_eq!(decode_ack(&[]), Some(None));
+        assert_eq!(decode_ack(&[1]), None);
+        assert_eq!(decode_ack(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,506 @@
+//! Run-length encoding of full canvas snapshots.
+//!
+//! A snapshot is a sequence of `count u8 | color u8` pairs, each standing
+//! for `count` pixels of `color`. The encoder never writes a count of 0 and
+//! caps runs at 255, so the encoded size is at most twice the input
+//! ([`rle_max_len`]). The server encodes each published snapshot once
+//! ([`rle_encode`]) and sends it as 1200-byte chunks; a chunk boundary may
+//! fall between the two bytes of a pair, so only the whole snapshot is
+//! decoded.
+//!
+//! [`rle_decode`] rejects anything the encoder could not have produced.
+//! [`rle_decode_lenient`] is for rendering whatever arrived: it decodes what
+//! it can and ignores the rest. [`rle_decode_reference`] is the format above
+//! and nothing more, to check the other two against.
+//!
+//! Encoding spends its time finding where runs end, so there is one
+//! [`RleEncoder`] per way of comparing pixels: 32 at a time with AVX2, 16
+//! with SSE2, one at a time otherwise. They all write the same bytes. The
+//! fastest one the CPU has is picked on first use and kept
+//! ([`rle_encoder`]); other targets run the scalar one until they get a
+//! vector path of their own (NEON once the aarch64 port lands).
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Worst case encoded size of `len` pixels: no two neighbors alike.
+pub const fn rle_max_len(len: usize) -> usize {
+    2 * len
+}
+
+/// Why [`rle_decode`] gave up. Offsets are into the encoded input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RleError {
+    /// The input ends in the middle of a pair.
+    Truncated { offset: usize },
+    /// A pair with a count of 0.
+    ZeroRun { offset: usize },
+    /// The pair at `offset` runs past the end of the `capacity`-pixel
+    /// output.
+    Overflow { offset: usize, capacity: usize },
+}
+
+impl fmt::Display for RleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RleError::Truncated { offset } => {
+                write!(f, "input ends inside the pair at byte {}", offset)
+            }
+            RleError::ZeroRun { offset } => write!(f, "empty run at byte {}", offset),
+            RleError::Overflow { offset, capacity } => write!(
+                f,
+                "run at byte {} overflows the {}-pixel output",
+                offset, capacity
+            ),
+        }
+    }
+}
+
+/// One implementation of [`rle_encode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RleEncoder {
+    /// 32 pixels per comparison; x86_64 with AVX2.
+    Avx2,
+    /// 16 pixels per comparison; any x86_64.
+    Sse2,
+    /// One pixel per comparison; anything.
+    Scalar,
+}
+
+type EncodeFn = fn(&[u8], &mut [u8]) -> usize;
+
+static SELECTED: OnceLock<(RleEncoder, EncodeFn)> = OnceLock::new();
+
+impl RleEncoder {
+    /// Fastest first.
+    pub const ALL: [RleEncoder; 3] = [RleEncoder::Avx2, RleEncoder::Sse2, RleEncoder::Scalar];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RleEncoder::Avx2 => "avx2",
+            RleEncoder::Sse2 => "sse2",
+            RleEncoder::Scalar => "scalar",
+        }
+    }
+
+    /// Whether this CPU can run it.
+    pub fn is_available(self) -> bool {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            RleEncoder::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            RleEncoder::Sse2 => true,
+            RleEncoder::Scalar => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// The encoders this CPU can run, fastest first.
+    pub fn available() -> impl Iterator<Item = RleEncoder> {
+        Self::ALL.into_iter().filter(|e| e.is_available())
+    }
+
+    /// Same as [`rle_encode`], with this encoder. Panics if the CPU cannot
+    /// run it.
+    pub fn encode(self, src: &[u8], dst: &mut [u8]) -> usize {
+        self.entry()(src, dst)
+    }
+
+    fn entry(self) -> EncodeFn {
+        assert!(self.is_available(), "{} encoder unavailable", self.name());
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            RleEncoder::Avx2 => |src, dst| {
+                // SAFETY: handed out only once AVX2 was detected.
+                unsafe { x86::encode_avx2(src, dst) }
+            },
+            #[cfg(target_arch = "x86_64")]
+            RleEncoder::Sse2 => |src, dst| {
+                // SAFETY: SSE2 is part of x86_64.
+                unsafe { x86::encode_sse2(src, dst) }
+            },
+            _ => encode_scalar,
+        }
+    }
+}
+
+/// The encoder [`rle_encode`] uses: the first available of
+/// [`RleEncoder::ALL`], picked on the first call.
+pub fn rle_encoder() -> RleEncoder {
+    selected().0
+}
+
+#[inline(always)]
+fn selected() -> &'static (RleEncoder, EncodeFn) {
+    SELECTED.get_or_init(|| {
+        let encoder = RleEncoder::available().next().unwrap();
+        (encoder, encoder.entry())
+    })
+}
+
+/// Encodes `src` into `dst`, which must hold `rle_max_len(src.len())`
+/// bytes. Returns the encoded length.
+#[inline(always)]
+pub fn rle_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    (selected().1)(src, dst)
+}
+
+/// The encoder proper. `span(at, color, room)` counts how many pixels from
+/// `at` on are `color`, up to about `room`, in whole vectors; the scalar
+/// loop takes the run from there to its end.
+#[inline(always)]
+fn encode_runs(src: &[u8], dst: &mut [u8], span: impl Fn(usize, u8, usize) -> usize) -> usize {
+    let len = src.len();
+    let mut src_idx = 0;
+    let mut dst_idx = 0;
+
+    while src_idx < len {
+        let color = src[src_idx];
+        src_idx += 1;
+        let matching = span(src_idx, color, 254);
+        let mut count = 1 + matching;
+        src_idx += matching;
+
+        while src_idx < len && src[src_idx] == color && count < 255 {
+            count += 1;
+            src_idx += 1;
+        }
+
+        dst[dst_idx] = count as u8;
+        dst[dst_idx + 1] = color;
+        dst_idx += 2;
+    }
+    dst_idx
+}
+
+fn encode_scalar(src: &[u8], dst: &mut [u8]) -> usize {
+    encode_runs(src, dst, |_, _, _| 0)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub fn encode_avx2(src: &[u8], dst: &mut [u8]) -> usize {
+        super::encode_runs(src, dst, |at, color, room| {
+            let color_vec = _mm256_set1_epi8(color as i8);
+            let mut n = 0;
+            while at + n + 32 <= src.len() && n + 32 <= room {
+                // SAFETY: the 32 bytes from at + n are in src.
+                let chunk = unsafe { _mm256_loadu_si256(src.as_ptr().add(at + n).cast()) };
+                let mask = _mm256_movemask_epi8(_mm256_cmpeq_epi8(chunk, color_vec)) as u32;
+                if mask != u32::MAX {
+                    return n + (!mask).trailing_zeros() as usize;
+                }
+                n += 32;
+            }
+            n
+        })
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub fn encode_sse2(src: &[u8], dst: &mut [u8]) -> usize {
+        super::encode_runs(src, dst, |at, color, room| {
+            let color_vec = _mm_set1_epi8(color as i8);
+            let mut n = 0;
+            while at + n + 16 <= src.len() && n + 16 <= room {
+                // SAFETY: the 16 bytes from at + n are in src.
+                let chunk = unsafe { _mm_loadu_si128(src.as_ptr().add(at + n).cast()) };
+                let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, color_vec)) as u32;
+                if mask != 0xFFFF {
+                    return n + (!mask).trailing_zeros() as usize;
+                }
+                n += 16;
+            }
+            n
+        })
+    }
+}
+
+/// Decodes `src` into `dst`. Returns the number of pixels written, which
+/// may be fewer than `dst` holds.
+pub fn rle_decode(src: &[u8], dst: &mut [u8]) -> Result<usize, RleError> {
+    let mut written = 0;
+    for (pair, offset) in src.chunks(2).zip((0..).step_by(2)) {
+        let &[count, color] = pair else {
+            return Err(RleError::Truncated { offset });
+        };
+        if count == 0 {
+            return Err(RleError::ZeroRun { offset });
+        }
+        let capacity = dst.len();
+        let run = dst
+            .get_mut(written..written + count as usize)
+            .ok_or(RleError::Overflow { offset, capacity })?;
+        run.fill(color);
+        written += count as usize;
+    }
+    Ok(written)
+}
+
+/// Best-effort [`rle_decode`]: skips empty runs, drops a trailing half
+/// pair and stops once `dst` is full. Returns the number of pixels written.
+pub fn rle_decode_lenient(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut written = 0;
+    for pair in src.chunks_exact(2) {
+        let run = (pair[0] as usize).min(dst.len() - written);
+        dst[written..written + run].fill(pair[1]);
+        written += run;
+        if written == dst.len() {
+            break;
+        }
+    }
+    written
+}
+
+/// The format taken literally: each pair appends `count` pixels of `color`,
+/// one at a time, and a trailing half pair appends nothing. No output size,
+/// no validation, no shortcuts; a stream the other decoders accept must
+/// decode to the same pixels here (client `--verify-rle`).
+pub fn rle_decode_reference(src: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i + 1 < src.len() {
+        let count = src[i];
+        let color = src[i + 1];
+        let mut n = 0;
+        while n < count {
+            out.push(color);
+            n += 1;
+        }
+        i += 2;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64, so failures reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> usize {
+            (self.next() % n) as usize
+        }
+    }
+
+    /// Pixels made of runs whose lengths straddle the 255 cap and the
+    /// 16/32-byte SIMD strides, in a palette small enough that neighbor
+    /// runs often share a color.
+    fn canvas(rng: &mut Rng, len: usize, colors: u64) -> Vec<u8> {
+        let lengths = [1, 2, 15, 16, 17, 31, 32, 33, 254, 255, 256, 511, 600];
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let run = lengths[rng.below(lengths.len() as u64)].min(len - out.len());
+            let color = rng.below(colors) as u8;
+            out.extend(std::iter::repeat_n(color, run));
+        }
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for case in 0..500 {
+            let len = rng.below(5_000);
+            let src = canvas(&mut rng, len, 1 + case % 40);
+            let mut encoded = vec![0; rle_max_len(len)];
+            let encoded_len = rle_encode(&src, &mut encoded);
+            let encoded = &encoded[..encoded_len];
+
+            // What the decoders rely on.
+            assert_eq!(encoded_len % 2, 0);
+            assert!(encoded.chunks(2).all(|pair| pair[0] != 0));
+
+            let mut decoded = vec![0xAA; len];
+            assert_eq!(rle_decode(encoded, &mut decoded), Ok(len));
+            assert_eq!(decoded, src);
+            let mut lenient = vec![0xAA; len];
+            assert_eq!(rle_decode_lenient(encoded, &mut lenient), len);
+            assert_eq!(lenient, src);
+            assert_eq!(rle_decode_reference(encoded), src);
+        }
+
+        // Worst case: every pixel differs from the next.
+        let src: Vec<u8> = (0..1_000).map(|i| (i % 2) as u8).collect();
+        let mut encoded = vec![0; rle_max_len(src.len())];
+        assert_eq!(rle_encode(&src, &mut encoded), rle_max_len(src.len()));
+        assert_eq!(rle_encode(&[], &mut []), 0);
+    }
+
+    #[test]
+    fn test_encoders_agree_byte_for_byte() {
+        let available: Vec<RleEncoder> = RleEncoder::available().collect();
+        assert_eq!(available.last(), Some(&RleEncoder::Scalar));
+        assert_eq!(Some(&rle_encoder()), available.first());
+        #[cfg(target_arch = "x86_64")]
+        assert!(available.contains(&RleEncoder::Sse2));
+
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        for case in 0..500 {
+            let len = rng.below(5_000);
+            let src: Vec<u8> = if case % 5 == 0 {
+                (0..len).map(|_| rng.next() as u8).collect()
+            } else {
+                canvas(&mut rng, len, 1 + case % 40)
+            };
+            // Unaligned starts too: the vector loads must not care.
+            let src = &src[rng.below(src.len() as u64 + 1).min(31)..];
+            let mut expected = vec![0; rle_max_len(src.len())];
+            let expected_len = RleEncoder::Scalar.encode(src, &mut expected);
+            for &encoder in &available {
+                let mut encoded = vec![0; rle_max_len(src.len())];
+                let encoded_len = encoder.encode(src, &mut encoded);
+                assert_eq!(
+                    encoded[..encoded_len],
+                    expected[..expected_len],
+                    "{} on case {}",
+                    encoder.name(),
+                    case
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_input_is_reported() {
+        let mut dst = [0u8; 8];
+        assert_eq!(rle_decode(&[], &mut dst), Ok(0));
+        assert_eq!(rle_decode(&[3, 7, 5, 9], &mut dst), Ok(8));
+        assert_eq!(dst, [7, 7, 7, 9, 9, 9, 9, 9]);
+
+        // A count byte without its color.
+        assert_eq!(
+            rle_decode(&[3, 7, 2], &mut dst),
+            Err(RleError::Truncated { offset: 2 })
+        );
+        assert_eq!(
+            rle_decode(&[0, 7, 2, 1], &mut dst),
+            Err(RleError::ZeroRun { offset: 0 })
+        );
+        // One pixel too many, at the end of a run or as its own run.
+        assert_eq!(
+            rle_decode(&[8, 1, 1, 2], &mut dst),
+            Err(RleError::Overflow {
+                offset: 2,
+                capacity: 8
+            })
+        );
+        assert_eq!(
+            rle_decode(&[5, 1, 4, 2], &mut dst),
+            Err(RleError::Overflow {
+                offset: 2,
+                capacity: 8
+            })
+        );
+        assert_eq!(
+            rle_decode(&[255, 1], &mut []),
+            Err(RleError::Overflow {
+                offset: 0,
+                capacity: 0
+            })
+        );
+        assert_eq!(
+            RleError::Overflow {
+                offset: 2,
+                capacity: 8
+            }
+            .to_string(),
+            "run at byte 2 overflows the 8-pixel output"
+        );
+
+        // The same inputs, rendered as far as they go.
+        let mut dst = [0u8; 8];
+        assert_eq!(rle_decode_lenient(&[3, 7, 2], &mut dst), 3);
+        assert_eq!(rle_decode_lenient(&[0, 7, 2, 1], &mut dst), 2);
+        assert_eq!(dst[..2], [1, 1]);
+        assert_eq!(rle_decode_lenient(&[5, 1, 4, 2], &mut dst), 8);
+        assert_eq!(dst, [1, 1, 1, 1, 1, 2, 2, 2]);
+        assert_eq!(rle_decode_lenient(&[255, 1], &mut []), 0);
+
+        // The reference decoder stops only at the end of the input.
+        assert_eq!(rle_decode_reference(&[3, 7, 2]), [7, 7, 7]);
+        assert_eq!(rle_decode_reference(&[0, 7, 2, 1]), [1, 1]);
+        assert_eq!(rle_decode_reference(&[255, 1]).len(), 255);
+    }
+
+    #[test]
+    fn test_arbitrary_input_never_panics() {
+        // Random bytes, and valid encodings cut or corrupted at random: the
+        // strict decoder must agree with the other two whenever it accepts.
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..20_000 {
+            let mut src: Vec<u8> = if rng.below(2) == 0 {
+                (0..rng.below(64)).map(|_| rng.next() as u8).collect()
+            } else {
+                let len = rng.below(600);
+                let pixels = canvas(&mut rng, len, 4);
+                let mut encoded = vec![0; rle_max_len(len)];
+                let encoded_len = rle_encode(&pixels, &mut encoded);
+                encoded.truncate(encoded_len);
+                encoded
+            };
+            if !src.is_empty() && rng.below(2) == 0 {
+                let at = rng.below(src.len() as u64);
+                match rng.below(3) {
+                    0 => src.truncate(at),
+                    1 => src[at] = 0,
+                    _ => src[at] = rng.next() as u8,
+                }
+            }
+            let capacity = rng.below(700);
+            let (mut strict, mut lenient) = (vec![0; capacity], vec![0; capacity]);
+            let written = rle_decode_lenient(&src, &mut lenient);
+            assert!(written <= capacity);
+            if let Ok(n) = rle_decode(&src, &mut strict) {
+                assert_eq!((n, &strict), (written, &lenient));
+                assert_eq!(rle_decode_reference(&src), strict[..n]);
+            }
+        }
+    }
+
+    /// Encoding throughput of every encoder this CPU runs, on a canvas of
+    /// long runs and on noise.
+    /// `cargo test -p protocol --release bench_encoders -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_encoders() {
+        const PIXELS: usize = 1_000_000;
+        const ROUNDS: u32 = 50;
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let inputs = [
+            ("canvas", canvas(&mut rng, PIXELS, 8)),
+            ("noise", (0..PIXELS).map(|_| rng.next() as u8).collect()),
+        ];
+        let mut dst = vec![0; rle_max_len(PIXELS)];
+        for (label, src) in &inputs {
+            for encoder in RleEncoder::available() {
+                encoder.encode(src, &mut dst);
+                let start = std::time::Instant::now();
+                for _ in 0..ROUNDS {
+                    std::hint::black_box(encoder.encode(std::hint::black_box(src), &mut dst));
+                }
+                let per_round = start.elapsed() / ROUNDS;
+                println!(
+                    "{:>6} {:>6}: {:?} per {} pixels, {:.0} MB/s",
+                    label,
+                    encoder.name(),
+                    per_round,
+                    PIXELS,
+                    PIXELS as f64 / per_round.as_secs_f64() / 1e6
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Live server stats for ordinary clients (the
+//! [`Features::STATS`](crate::version::Features) feature): what a web page
+//! shows next to the canvas (users online, pixels per second, time to the
+//! next full sync) without polling an HTTP endpoint from every browser.
+//!
+//! The client sends a STATS_REQUEST datagram, which is [`STATS_TAG`] alone,
+//! a tagged datagram (see [`crate::datagram`]). The server answers on the same connection with a [`STATS_LEN`]-byte STATS
+//! datagram (all integers little-endian):
+//!
+//! ```text
+//! tag [u8; 4] | online u32 | pixels_per_sec u32 | cooldown_secs u16 |
+//! broadcast_interval_ms u16 | next_full_ms u32 | snapshot u64 | reserved [u8; 4]
+//! ```
+//!
+//! A connection gets at most one reply per [`STATS_MIN_INTERVAL_MS`];
+//! requests in between are dropped.
+
+/// Starts every STATS datagram, and is the whole STATS_REQUEST.
+pub const STATS_TAG: [u8; 4] = [0x00, b'S', b'T', b'S'];
+
+pub const STATS_LEN: usize = 32;
+
+/// Shortest gap between two replies on one connection.
+pub const STATS_MIN_INTERVAL_MS: u64 = 10_000;
+
+/// `next_full_ms` when the server has no full broadcast scheduled.
+pub const NO_FULL_SCHEDULED: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LiveStats {
+    /// Connections across all workers.
+    pub online: u32,
+    /// Accepted pixels per second, smoothed over several seconds.
+    pub pixels_per_sec: u32,
+    /// Wait between two pixels of one user.
+    pub cooldown_secs: u16,
+    /// How often snapshots are broadcast right now.
+    pub broadcast_interval_ms: u16,
+    /// Until the connection's next full broadcast, or
+    /// [`NO_FULL_SCHEDULED`].
+    pub next_full_ms: u32,
+    /// Newest published snapshot.
+    pub snapshot: u64,
+}
+
+pub fn is_stats_request(datagram: &[u8]) -> bool {
+    datagram == STATS_TAG
+}
+
+pub fn encode_stats(stats: &LiveStats) -> [u8; STATS_LEN] {
+    let mut out = [0; STATS_LEN];
+    out[..4].copy_from_slice(&STATS_TAG);
+    out[4..8].copy_from_slice(&stats.online.to_le_bytes());
+    out[8..12].copy_from_slice(&stats.pixels_per_sec.to_le_bytes());
+    out[12..14].copy_from_slice(&stats.cooldown_secs.to_le_bytes());
+    out[14..16].copy_from_slice(&stats.broadcast_interval_ms.to_le_bytes());
+    out[16..20].copy_from_slice(&stats.next_full_ms.to_le_bytes());
+    out[20..28].copy_from_slice(&stats.snapshot.to_le_bytes());
+    out
+}
+
+/// The stats a STATS datagram carries, None for any other datagram.
+pub fn decode_stats(datagram: &[u8]) -> Option<LiveStats> {
+    if datagram.len() != STATS_LEN || datagram[..4] != STATS_TAG {
+        return None;
+    }
+    Some(LiveStats {
+        online: u32::from_le_bytes(datagram[4..8].try_into().unwrap()),
+        pixels_per_sec: u32::from_le_bytes(datagram[8..12].try_into().unwrap()),
+        cooldown_secs: u16::from_le_bytes(datagram[12..14].try_into().unwrap()),
+        broadcast_interval_ms: u16::from_le_bytes(datagram[14..16].try_into().unwrap()),
+        next_full_ms: u32::from_le_bytes(datagram[16..20].try_into().unwrap()),
+        snapshot: u64::from_le_bytes(datagram[20..28].try_into().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_round_trip() {
+        let stats = LiveStats {
+            online: 41_234,
+            pixels_per_sec: 1_870,
+            cooldown_secs: 300,
+            broadcast_interval_ms: 100,
+            next_full_ms: 4_321,
+            snapshot: 0x0102_0304_0506_0708,
+        };
+        let wire = encode_stats(&stats);
+        assert_eq!(wire.len(), STATS_LEN);
+        assert_eq!(&wire[4..8], &41_234u32.to_le_bytes());
+        assert_eq!(&wire[20..28], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&wire[28..], &[0; 4]);
+        assert_eq!(decode_stats(&wire), Some(stats));
+
+        let idle = LiveStats {
+            next_full_ms: NO_FULL_SCHEDULED,
+            ..Default::default()
+        };
+        assert_eq!(decode_stats(&encode_stats(&idle)), Some(idle));
+
+        // Wrong length or tag is not a STATS datagram.
+        assert_eq!(decode_stats(&wire[..31]), None);
+        let mut other = wire;
+        other[3] = b'C';
+        assert_eq!(decode_stats(&other), None);
+    }
+
+    #[test]
+    fn test_stats_datagrams_stand_apart() {
+        assert!(is_stats_request(&STATS_TAG));
+        // A pixel, with and without token, and a STATS reply are not requests.
+        assert!(!is_stats_request(&[0, b'S', b'T', b'S', 1]));
+        assert!(!is_stats_request(&[0; 9]));
+        let wire = encode_stats(&LiveStats::default());
+        assert!(!is_stats_request(&wire));
+
+        // Nothing on the broadcast path takes a reply for its own.
+        assert!(crate::fec::decode_fec(&wire).is_none());
+        assert!(crate::dict::zstd_frame(&wire).is_none());
+        assert!(!crate::dict::is_raw_diff(&wire));
+    }
+}
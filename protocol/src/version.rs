@@ -0,0 +1,291 @@
+//! Build-info handshake (the VERSION control op): each side states its
+//! protocol version, build and the optional features it has, so mismatched
+//! builds fail with a clear close instead of garbled broadcasts.
+//!
+//! Peers with different major versions cannot talk: the server closes with
+//! [`AppCloseCode::VersionMismatch`](crate::close::AppCloseCode). Within a
+//! major version, both sides use only the features they have in common.
+//! A client that never sends VERSION gets [`Features::LEGACY`].
+
+use crate::control::ControlOp;
+use std::ops::{BitAnd, BitOr};
+
+/// Bumped for changes an older peer cannot parse.
+pub const PROTOCOL_MAJOR: u16 = 2;
+/// Bumped for compatible additions.
+pub const PROTOCOL_MINOR: u16 = 4;
+
+/// Optional protocol features, as a bit set. Bits are part of the wire
+/// contract: never reuse one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Several pixels per datagram (see [`crate::batch`]).
+    pub const BATCHING: Self = Self(1 << 0);
+    /// Per-pixel acceptance receipts (see [`crate::receipt`]).
+    pub const RECEIPTS: Self = Self(1 << 1);
+    /// Tile subscriptions (SUBSCRIBE).
+    pub const SUBSCRIPTIONS: Self = Self(1 << 2);
+    /// Dictionary-compressed diff broadcasts (see [`crate::dict`]).
+    pub const ZSTD: Self = Self(1 << 3);
+    /// Parity-protected full broadcasts (see [`crate::fec`]).
+    pub const FEC: Self = Self(1 << 4);
+    /// Live stats datagrams on request (see [`crate::stats`]).
+    pub const STATS: Self = Self(1 << 5);
+    /// Pixel-rate contracts pushed by the server (RATE_CONTRACT).
+    pub const CONTRACTS: Self = Self(1 << 6);
+    /// Canvas rounds announced ahead of full snapshots (see [`crate::epoch`]).
+    pub const EPOCHS: Self = Self(1 << 7);
+    /// COOLDOWN_CONFIG pushed by the server whenever it changes.
+    pub const COOLDOWNS: Self = Self(1 << 8);
+    /// BROADCAST_INTERVAL pushed by the server whenever it changes.
+    pub const INTERVALS: Self = Self(1 << 9);
+
+    /// What peers that predate the handshake do.
+    pub const LEGACY: Self = Self::SUBSCRIPTIONS;
+
+    /// Every named feature with its name, in bit order.
+    pub const NAMED: [(Self, &'static str); 10] = [
+        (Self::BATCHING, "batching"),
+        (Self::RECEIPTS, "receipts"),
+        (Self::SUBSCRIPTIONS, "subscriptions"),
+        (Self::ZSTD, "zstd"),
+        (Self::FEC, "fec"),
+        (Self::STATS, "stats"),
+        (Self::CONTRACTS, "contracts"),
+        (Self::EPOCHS, "epochs"),
+        (Self::COOLDOWNS, "cooldowns"),
+        (Self::INTERVALS, "intervals"),
+    ];
+
+    /// Keeps bits this build does not know: they come from a newer peer and
+    /// drop out of any intersection with our own features.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// `|` for constants.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Names of the known features in the set, e.g. for logs.
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMED
+            .iter()
+            .filter(|(f, _)| self.contains(*f))
+            .map(|&(_, name)| name)
+            .collect()
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// Longest build string carried; longer ones are cut at a char boundary.
+pub const MAX_BUILD_LEN: usize = 64;
+
+/// Encoded size without the build string: major u16 | minor u16 |
+/// features u32 | build_len u8.
+pub const VERSION_FIXED_LEN: usize = 9;
+
+/// One side of the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub major: u16,
+    pub minor: u16,
+    pub features: Features,
+    /// Free-form build identification (version and commit).
+    pub build: String,
+}
+
+impl BuildInfo {
+    /// This build's protocol version with the given features and build.
+    pub fn current(features: Features, build: &str) -> Self {
+        Self {
+            major: PROTOCOL_MAJOR,
+            minor: PROTOCOL_MINOR,
+            features,
+            build: build.to_string(),
+        }
+    }
+}
+
+/// Appends a complete VERSION request: opcode, then the client's
+/// [`encode_version`].
+pub fn encode_version_request(info: &BuildInfo, out: &mut Vec<u8>) {
+    out.push(ControlOp::Version as u8);
+    encode_version(info, out);
+}
+
+/// Appends `u16 major | u16 minor | u32 features | u8 len | build`. The
+/// server's reply payload has the same form.
+pub fn encode_version(info: &BuildInfo, out: &mut Vec<u8>) {
+    let mut len = info.build.len().min(MAX_BUILD_LEN);
+    while !info.build.is_char_boundary(len) {
+        len -= 1;
+    }
+    out.reserve(VERSION_FIXED_LEN + len);
+    out.extend_from_slice(&info.major.to_le_bytes());
+    out.extend_from_slice(&info.minor.to_le_bytes());
+    out.extend_from_slice(&info.features.bits().to_le_bytes());
+    out.push(len as u8);
+    out.extend_from_slice(&info.build.as_bytes()[..len]);
+}
+
+pub fn decode_version(buf: &[u8]) -> Result<BuildInfo, String> {
+    if buf.len() < VERSION_FIXED_LEN {
+        return Err("version truncated before build".into());
+    }
+    let len = buf[8] as usize;
+    let build = &buf[VERSION_FIXED_LEN..];
+    if build.len() != len {
+        return Err(format!(
+            "version declares a {}-byte build but carries {} bytes",
+            len,
+            build.len()
+        ));
+    }
+    Ok(BuildInfo {
+        major: u16::from_le_bytes([buf[0], buf[1]]),
+        minor: u16::from_le_bytes([buf[2], buf[3]]),
+        features: Features::from_bits(u32::from_le_bytes(buf[4..8].try_into().unwrap())),
+        build: String::from_utf8_lossy(build).into_owned(),
+    })
+}
+
+/// The peers cannot talk at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MajorMismatch {
+    pub ours: u16,
+    pub theirs: u16,
+}
+
+/// Features both sides may use, or the mismatch that rules out talking.
+/// Symmetric, so both sides reach the same answer.
+pub fn negotiate(ours: &BuildInfo, theirs: &BuildInfo) -> Result<Features, MajorMismatch> {
+    if ours.major != theirs.major {
+        return Err(MajorMismatch {
+            ours: ours.major,
+            theirs: theirs.major,
+        });
+    }
+    Ok(ours.features & theirs.features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(major: u16, minor: u16, features: Features) -> BuildInfo {
+        BuildInfo {
+            major,
+            minor,
+            features,
+            build: format!("{}.{}", major, minor),
+        }
+    }
+
+    #[test]
+    fn test_negotiation_matrix() {
+        let all = Features::NAMED
+            .iter()
+            .fold(Features::NONE, |acc, &(f, _)| acc | f);
+        let sets = [
+            Features::NONE,
+            Features::LEGACY,
+            Features::BATCHING | Features::ZSTD,
+            all,
+            // From a newer peer with a feature this build has never heard of.
+            Features::from_bits(1 << 31) | Features::RECEIPTS,
+        ];
+        for a in sets {
+            for b in sets {
+                for (major_a, major_b) in [(1, 1), (1, 2), (2, 1)] {
+                    for (minor_a, minor_b) in [(0, 0), (0, 3)] {
+                        let x = info(major_a, minor_a, a);
+                        let y = info(major_b, minor_b, b);
+                        let result = negotiate(&x, &y);
+                        if major_a != major_b {
+                            let mismatch = MajorMismatch {
+                                ours: major_a,
+                                theirs: major_b,
+                            };
+                            assert_eq!(result, Err(mismatch));
+                            assert!(negotiate(&y, &x).is_err());
+                        } else {
+                            // Minor versions never matter, only shared features.
+                            assert_eq!(result, Ok(a & b));
+                            assert_eq!(negotiate(&y, &x), result);
+                        }
+                    }
+                }
+            }
+        }
+
+        let unknown = Features::from_bits(1 << 31);
+        assert_eq!(
+            negotiate(&info(1, 0, all), &info(1, 9, unknown)),
+            Ok(Features::NONE)
+        );
+        assert_eq!(
+            all.names(),
+            [
+                "batching",
+                "receipts",
+                "subscriptions",
+                "zstd",
+                "fec",
+                "stats",
+                "contracts",
+                "epochs",
+                "cooldowns",
+                "intervals"
+            ]
+        );
+        assert_eq!(Features::LEGACY.names(), ["subscriptions"]);
+    }
+
+    #[test]
+    fn test_version_round_trip() {
+        let ours = BuildInfo::current(Features::SUBSCRIPTIONS, "0.1.0+abc123");
+        let mut request = Vec::new();
+        encode_version_request(&ours, &mut request);
+        assert_eq!(ControlOp::from_u8(request[0]), Some(ControlOp::Version));
+        assert_eq!(request.len(), 1 + VERSION_FIXED_LEN + 12);
+        assert_eq!(decode_version(&request[1..]), Ok(ours));
+
+        assert!(decode_version(&request[1..request.len() - 1]).is_err());
+        assert!(decode_version(&request[1..VERSION_FIXED_LEN]).is_err());
+
+        // Long builds are cut, never in the middle of a character.
+        let long = BuildInfo::current(Features::NONE, &"é".repeat(40));
+        let mut buf = Vec::new();
+        encode_version(&long, &mut buf);
+        assert_eq!(buf.len(), VERSION_FIXED_LEN + MAX_BUILD_LEN);
+        assert_eq!(decode_version(&buf).unwrap().build, "é".repeat(32));
+    }
+}
@@ -0,0 +1,119 @@
+//! How a connection carries the canvas protocol: raw, or inside a
+//! WebTransport session.
+//!
+//! Clients that speak the protocol directly (the load client, the server's
+//! demo bots) negotiate [`RAW_ALPN`]. Pixels, broadcasts and STATS are
+//! plain QUIC datagrams; control requests are plain bidirectional streams
+//! (see [`crate::control`]).
+//!
+//! Browsers negotiate `h3` and open a WebTransport session with an extended
+//! CONNECT (`:protocol = webtransport`) to [`SESSION_PATH`]. Every datagram
+//! of the session, both ways, is an HTTP/3 datagram: the session's quarter
+//! stream id (the CONNECT stream id / 4) as a QUIC varint, then the same
+//! bytes a raw connection would send ([`SessionPrefix`]). Control streams
+//! are not available to browsers yet.
+
+/// ALPN of raw connections.
+pub const RAW_ALPN: &[u8] = b"canvas-raw";
+
+/// Where browsers open their WebTransport session.
+pub const SESSION_PATH: &str = "/canvas";
+
+/// Longest QUIC varint.
+pub const MAX_SESSION_PREFIX_LEN: usize = 8;
+
+/// The bytes in front of every datagram of one WebTransport session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPrefix {
+    session_id: u64,
+    buf: [u8; MAX_SESSION_PREFIX_LEN],
+    len: u8,
+}
+
+impl SessionPrefix {
+    /// The prefix of the session opened on stream `session_id`, a
+    /// client-initiated bidirectional stream.
+    pub fn new(session_id: u64) -> Self {
+        let quarter = session_id / 4;
+        let mut buf = [0; MAX_SESSION_PREFIX_LEN];
+        let len = match quarter {
+            0..=0x3f => {
+                buf[0] = quarter as u8;
+                1
+            }
+            0x40..=0x3fff => {
+                buf[..2].copy_from_slice(&(quarter as u16 | 0x4000).to_be_bytes());
+                2
+            }
+            0x4000..=0x3fff_ffff => {
+                buf[..4].copy_from_slice(&(quarter as u32 | 0x8000_0000).to_be_bytes());
+                4
+            }
+            _ => {
+                buf.copy_from_slice(&(quarter | 0xc000_0000_0000_0000).to_be_bytes());
+                8
+            }
+        };
+        Self {
+            session_id,
+            buf,
+            len,
+        }
+    }
+
+    /// The CONNECT stream of the session.
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+
+    /// The payload of `datagram` if it belongs to this session.
+    #[inline(always)]
+    pub fn strip<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        datagram.strip_prefix(self.as_bytes())
+    }
+
+    /// `payload` behind the prefix, in `out` (cleared first).
+    #[inline(always)]
+    pub fn frame(&self, payload: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        out.extend_from_slice(self.as_bytes());
+        out.extend_from_slice(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_is_the_quarter_stream_id_varint() {
+        // RFC 9000 appendix A.1 samples, as quarter stream ids.
+        for (quarter, wire) in [
+            (0u64, &[0x00][..]),
+            (37, &[0x25]),
+            (15_293, &[0x7b, 0xbd]),
+            (494_878_333, &[0x9d, 0x7f, 0x3e, 0x7d]),
+            (
+                151_288_809_941_952_652,
+                &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c],
+            ),
+        ] {
+            let prefix = SessionPrefix::new(quarter * 4);
+            assert_eq!(prefix.as_bytes(), wire);
+            assert_eq!(prefix.session_id(), quarter * 4);
+        }
+
+        let prefix = SessionPrefix::new(4 * 64);
+        let mut framed = vec![0xff; 3];
+        prefix.frame(&[1, 2, 3], &mut framed);
+        assert_eq!(framed, [0x40, 0x40, 1, 2, 3]);
+        assert_eq!(prefix.strip(&framed), Some(&[1, 2, 3][..]));
+        // Another session's datagram, or a bare one.
+        assert_eq!(SessionPrefix::new(0).strip(&framed), None);
+        assert_eq!(prefix.strip(&[0x40]), None);
+    }
+}
@@ -0,0 +1,76 @@
+#!/bin/bash
+
+# bandwidth_cap_check.sh - Acceptance check for the broadcast bandwidth caps.
+#
+# Runs one server worker with a tiny worker cap against the load client with
+# many observers, then checks that:
+#   1. the worker's broadcast payload rate stayed under worker + full-sync cap
+#   2. the cap actually engaged (canvas_worker_global_throttle_windows_total)
+#   3. every observer still received broadcasts
+#
+# Usage: ./scripts/bandwidth_cap_check.sh [worker_kbps] [full_sync_kbps] [observers] [seconds]
+
+set -e
+
+WORKER_KBPS=${1:-256}
+FULL_SYNC_KBPS=${2:-256}
+OBSERVERS=${3:-200}
+SECONDS_TO_RUN=${4:-30}
+PORT=4533
+DASHBOARD=127.0.0.1:8533
+OUT=$(mktemp -d)
+
+cargo build --release -p server -p client
+
+./target/release/server --workers 1 --port $PORT --dashboard-bind $DASHBOARD \
+    --worker-kbps "$WORKER_KBPS" --full-sync-kbps "$FULL_SYNC_KBPS" \
+    > "$OUT/server.log" 2>&1 &
+SERVER=$!
+trap 'kill $SERVER 2>/dev/null || true' EXIT
+sleep 2
+
+./target/release/client --target 127.0.0.1:$PORT --id bwcheck --clients 50 \
+    --observers "$OBSERVERS" --observer-dir "$OUT" --metrics-dir "$OUT" \
+    --max-conn-jitter 1000 --min-pixel-wait 100 --max-pixel-wait 500 \
+    > "$OUT/client.log" 2>&1 &
+CLIENT=$!
+
+metric() {
+    curl -s "http://$DASHBOARD/metrics" | awk -v name="$1" '$1 ~ "^"name"{" { sum += $2 } END { print sum + 0 }'
+}
+
+# Let the observers connect and take their first full sync before measuring.
+sleep 5
+START_BYTES=$(metric canvas_worker_broadcast_bytes_total)
+sleep "$SECONDS_TO_RUN"
+END_BYTES=$(metric canvas_worker_broadcast_bytes_total)
+THROTTLES=$(metric canvas_worker_global_throttle_windows_total)
+
+kill -INT $CLIENT
+wait $CLIENT || true
+
+# Caps hold per 1 s window; a sample of N seconds overlaps at most N + 1.
+SENT_KBPS=$(( (END_BYTES - START_BYTES) * 8 / 1000 / SECONDS_TO_RUN ))
+LIMIT_KBPS=$(( (WORKER_KBPS + FULL_SYNC_KBPS) * (SECONDS_TO_RUN + 1) / SECONDS_TO_RUN ))
+echo "Broadcast payload: ${SENT_KBPS} kbps (limit ${LIMIT_KBPS} kbps), throttled windows: ${THROTTLES}"
+
+STATUS=0
+if [ "$SENT_KBPS" -gt "$LIMIT_KBPS" ]; then
+    echo "FAIL: broadcast rate above the cap"
+    STATUS=1
+fi
+if [ "$THROTTLES" -eq 0 ]; then
+    echo "FAIL: the worker cap never engaged; lower worker_kbps or add observers"
+    STATUS=1
+fi
+
+STARVED=$(grep -E "^Observer [0-9]+: 0 broadcasts archived" "$OUT/client.log" | wc -l)
+SEEN=$(grep -cE "^Observer [0-9]+: " "$OUT/client.log" || true)
+echo "Observers reporting: ${SEEN}/${OBSERVERS}, with no broadcast at all: ${STARVED}"
+if [ "$SEEN" -ne "$OBSERVERS" ] || [ "$STARVED" -ne 0 ]; then
+    echo "FAIL: some observers never received a broadcast"
+    STATUS=1
+fi
+
+[ $STATUS -eq 0 ] && echo "OK" && rm -rf "$OUT" || echo "Logs kept in $OUT"
+exit $STATUS
@@ -0,0 +1,23 @@
+#!/bin/bash
+
+# check_windows_client.sh - Checks that the load client still builds for
+# Windows (the server is Linux-only and not checked).
+#
+# On Linux, ring's C sources need clang-cl and llvm-lib on the PATH; see
+# docs/windows_client.md.
+#
+# Usage: ./scripts/check_windows_client.sh [extra cargo args]
+
+set -e
+
+TARGET=x86_64-pc-windows-msvc
+
+rustup target add "$TARGET"
+
+if [ "$(uname -s)" = "Linux" ]; then
+    export CC_x86_64_pc_windows_msvc=${CC_x86_64_pc_windows_msvc:-clang-cl}
+    export AR_x86_64_pc_windows_msvc=${AR_x86_64_pc_windows_msvc:-llvm-lib}
+fi
+
+cargo check -p client --all-targets --target "$TARGET" "$@"
+cargo clippy -p client --all-targets --target "$TARGET" "$@" -- -D warnings
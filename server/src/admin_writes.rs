@@ -0,0 +1,143 @@
+//! Writes made by operators rather than users: clearing a region of the
+//! canvas (`POST /admin/clear` on the dashboard). A clear can cover the
+//! whole canvas, a million pixels, so it does not go through the worker
+//! rings: it would push user pixels out of them and count as drops.
+//!
+//! Instead the dashboard queues one [`AdminWrite`] per region on a ring of
+//! its own, and the master expands it a row span at a time between drains
+//! of the worker rings. Per loop iteration it applies at most
+//! `--admin-drain-ratio` times what it drains from one worker ring
+//! (MASTER_BATCH_DRAIN), so user pixels wait at most that much longer
+//! behind a clear.
+//!
+//! Admin pixels skip everything user pixels are checked against (cooldown,
+//! duplicates): the master applies them as given. They are counted apart
+//! (`canvas_admin_pixels_applied_total`) and stay out of the per-tile
+//! write counts and the timeline, which describe user activity.
+
+use crate::const_settings::{CANVAS_HEIGHT, CANVAS_WIDTH};
+use crate::spsc::SharedRing;
+
+/// Paints the rectangle `[x0, x1) × [y0, y1)` in `color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdminWrite {
+    pub x0: u16,
+    pub y0: u16,
+    pub x1: u16,
+    pub y1: u16,
+    pub color: u8,
+}
+
+impl AdminWrite {
+    /// A clear of `[x0, x1) × [y0, y1)`, or None if it is empty or does not
+    /// fit on the canvas.
+    pub fn clear(x0: u16, y0: u16, x1: u16, y1: u16, color: u8) -> Option<Self> {
+        (x0 < x1 && y0 < y1 && x1 as usize <= CANVAS_WIDTH && y1 as usize <= CANVAS_HEIGHT)
+            .then_some(Self {
+                x0,
+                y0,
+                x1,
+                y1,
+                color,
+            })
+    }
+
+    pub fn pixels(&self) -> usize {
+        (self.x1 - self.x0) as usize * (self.y1 - self.y0) as usize
+    }
+}
+
+/// Pixels `x0..x1` of row `y`, all in `color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub y: u16,
+    pub x0: u16,
+    pub x1: u16,
+    pub color: u8,
+}
+
+impl Span {
+    pub fn pixels(&self) -> usize {
+        (self.x1 - self.x0) as usize
+    }
+}
+
+/// The master's end of the admin ring, with the write being expanded.
+pub struct AdminQueue {
+    ring: SharedRing<AdminWrite>,
+    /// The write in progress and its next pixel, row by row.
+    current: Option<(AdminWrite, u16, u16)>,
+}
+
+impl AdminQueue {
+    pub fn new(ring: SharedRing<AdminWrite>) -> Self {
+        Self {
+            ring,
+            current: None,
+        }
+    }
+
+    /// The ring to queue writes on. It is single-producer: one thread
+    /// (the dashboard's) pushes.
+    pub fn ring(&self) -> SharedRing<AdminWrite> {
+        self.ring.clone()
+    }
+
+    /// Drops the write in progress and every queued one: a new epoch
+    /// (epoch.rs) starts from a background of its own.
+    pub fn discard(&mut self) {
+        self.current = None;
+        while self.ring.pop().is_some() {}
+    }
+
+    /// The next at most `budget` pixels to apply, all on one row; None once
+    /// every queued write is applied.
+    pub fn next_span(&mut self, budget: usize) -> Option<Span> {
+        if self.current.is_none() {
+            let write = self.ring.pop()?;
+            self.current = Some((write, write.x0, write.y0));
+        }
+        let (write, x, y) = self.current.as_mut()?;
+        let x1 = (*x as usize + budget).min(write.x1 as usize) as u16;
+        let span = Span {
+            y: *y,
+            x0: *x,
+            x1,
+            color: write.color,
+        };
+        if x1 < write.x1 {
+            *x = x1;
+        } else if *y + 1 < write.y1 {
+            (*x, *y) = (write.x0, *y + 1);
+        } else {
+            self.current = None;
+        }
+        Some(span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spsc::SpscRingBuffer;
+
+    #[test]
+    fn test_writes_are_expanded_in_budgeted_row_spans() {
+        let ring = SpscRingBuffer::shared();
+        let mut queue = AdminQueue::new(ring.clone());
+        assert!(AdminWrite::clear(5, 0, 5, 1, 0).is_none());
+        assert!(AdminWrite::clear(0, 0, CANVAS_WIDTH as u16 + 1, 1, 0).is_none());
+        let write = AdminWrite::clear(10, 20, 15, 22, 3).unwrap();
+        assert_eq!(write.pixels(), 10);
+        ring.push(write).unwrap();
+        ring.push(AdminWrite::clear(0, 0, 1, 1, 4).unwrap())
+            .unwrap();
+
+        let span = |y, x0, x1, color| Some(Span { y, x0, x1, color });
+        assert_eq!(queue.next_span(3), span(20, 10, 13, 3));
+        assert_eq!(queue.next_span(3), span(20, 13, 15, 3));
+        assert_eq!(queue.next_span(100), span(21, 10, 15, 3));
+        assert_eq!(queue.next_span(100), span(0, 0, 1, 4));
+        assert_eq!(queue.next_span(100), None);
+    }
+}
@@ -0,0 +1,357 @@
+//! What a worker does with each received pixel before the master sees it:
+//! drop replays and retransmits, then charge the cooldown, then queue. A
+//! client that resends a placement it got no answer for (same idempotency
+//! token) must not pay the cooldown twice, so duplicates are dropped first.
+//!
+//! Replays come from 0-RTT: anyone who captured a client's resumption
+//! flight can send it again, and the server takes it for a new connection.
+//! Clients that resume that way number their pixels (the nonce), counting
+//! up across reconnects. The nonces a user id has seen are kept with its
+//! tokens, so they stay with the id while it is parked for a reconnect
+//! (reservation.rs) and a replay that lands on it is dropped even after the
+//! cooldown has run out. A replay that gets a fresh id instead is no better
+//! off than any new connection: it starts with a fresh cooldown anyway.
+//! Behind one NAT the IP fallback may hand a parked id to another client,
+//! whose pixels are then dropped until its nonces pass the previous
+//! owner's; a RESUME token avoids that as it does for the cooldown.
+
+use crate::const_settings::{PIXEL_NONCE_WINDOW, RECENT_PIXEL_TOKENS};
+use crate::cooldown::CooldownArray;
+use crate::master::PixelWrite;
+use crate::pixel_trace::Fate;
+use crate::spsc::SpscRingBuffer;
+use crate::timing_wheel::TimingWheel;
+use crate::transport::PixelDatagram;
+use rustc_hash::FxHashMap;
+
+/// The last RECENT_PIXEL_TOKENS tokens of one connection, oldest
+/// overwritten first.
+struct RecentTokens {
+    tokens: [u32; RECENT_PIXEL_TOKENS],
+    len: usize,
+    next: usize,
+}
+
+impl RecentTokens {
+    fn new() -> Self {
+        Self {
+            tokens: [0; RECENT_PIXEL_TOKENS],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    /// Remembers `token`; false if it was already there.
+    fn insert(&mut self, token: u32) -> bool {
+        if self.tokens[..self.len].contains(&token) {
+            return false;
+        }
+        self.tokens[self.next] = token;
+        self.next = (self.next + 1) % RECENT_PIXEL_TOKENS;
+        self.len = (self.len + 1).min(RECENT_PIXEL_TOKENS);
+        true
+    }
+}
+
+/// The nonces of one user id: the highest seen, and which of the
+/// PIXEL_NONCE_WINDOW below it were seen too (bit n: highest - n).
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u32>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Remembers `nonce`; false if it was seen before or is too far behind
+    /// to tell.
+    fn insert(&mut self, nonce: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(nonce);
+            self.seen = 1;
+            return true;
+        };
+        if nonce > highest {
+            let ahead = nonce - highest;
+            self.seen = if ahead < PIXEL_NONCE_WINDOW {
+                (self.seen << ahead) | 1
+            } else {
+                1
+            };
+            self.highest = Some(nonce);
+            return true;
+        }
+        let behind = highest - nonce;
+        if behind >= PIXEL_NONCE_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// Recent idempotency tokens and nonces, by user id. Only connections that
+/// send them get an entry, so plain datagram clients cost nothing.
+#[derive(Default)]
+pub struct TokenFilter {
+    recent: FxHashMap<u32, Box<RecentTokens>>,
+    nonces: FxHashMap<u32, ReplayWindow>,
+}
+
+impl TokenFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// False for a placement this connection already sent. Untokened pixels
+    /// always pass.
+    #[inline]
+    pub fn admit(&mut self, user_id: u32, token: Option<u32>) -> bool {
+        match token {
+            None => true,
+            Some(token) => self
+                .recent
+                .entry(user_id)
+                .or_insert_with(|| Box::new(RecentTokens::new()))
+                .insert(token),
+        }
+    }
+
+    /// False for a nonce this user id already sent: a replay. Pixels without
+    /// one always pass.
+    #[inline]
+    pub fn fresh(&mut self, user_id: u32, nonce: Option<u32>) -> bool {
+        match nonce {
+            None => true,
+            Some(nonce) => self.nonces.entry(user_id).or_default().insert(nonce),
+        }
+    }
+
+    /// Drops the tokens and nonces of a connection that went away; its user
+    /// id is about to be handed to someone else.
+    pub fn forget(&mut self, user_id: u32) {
+        self.recent.remove(&user_id);
+        self.nonces.remove(&user_id);
+    }
+}
+
+/// Decides the fate of `pixel`, received from `user_id` as `datagram`, and
+/// hands it to the master if it passes. The cooldown, `cooldown_ticks`
+/// long (the connection's contract, rate_contract.rs), is charged only for
+/// pixels that are neither replays nor retransmits.
+#[inline(always)]
+pub fn admit_pixel(
+    tokens: &mut TokenFilter,
+    cooldown: &mut CooldownArray,
+    wheel: &mut TimingWheel,
+    master_queue: &SpscRingBuffer<PixelWrite>,
+    user_id: u32,
+    datagram: &PixelDatagram,
+    pixel: PixelWrite,
+    cooldown_ticks: usize,
+) -> Fate {
+    if !tokens.fresh(user_id, datagram.nonce) {
+        return Fate::Replay;
+    }
+    if !tokens.admit(user_id, datagram.token) {
+        return Fate::Duplicate;
+    }
+    if cooldown.is_on_cooldown(user_id) {
+        return Fate::Cooldown;
+    }
+    cooldown.set_cooldown(user_id);
+    wheel.add_cooldown_for(user_id, cooldown_ticks);
+    match master_queue.push(pixel) {
+        Ok(()) => Fate::Queued,
+        Err(_) => Fate::SpscDrop,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::TIMING_WHEEL_TICKS;
+
+    fn pixel(color: u8) -> PixelWrite {
+        PixelWrite {
+            x: 10,
+            y: 20,
+            color,
+            traced: false,
+            user_id: 0,
+            ipv4: 0,
+            epoch: 0,
+        }
+    }
+
+    #[test]
+    fn test_retransmit_is_applied_and_charged_once() {
+        let mut tokens = TokenFilter::new();
+        let mut cooldown = CooldownArray::new();
+        let mut wheel = TimingWheel::new();
+        let queue = SpscRingBuffer::<PixelWrite>::shared();
+        let mut admit = |user, token, color| {
+            let datagram = PixelDatagram {
+                x: 10,
+                y: 20,
+                color,
+                token,
+                nonce: None,
+                seq: None,
+            };
+            admit_pixel(
+                &mut tokens,
+                &mut cooldown,
+                &mut wheel,
+                &queue,
+                user,
+                &datagram,
+                pixel(color),
+                TIMING_WHEEL_TICKS,
+            )
+        };
+
+        assert_eq!(admit(3, Some(77), 1), Fate::Queued);
+        assert_eq!(admit(3, Some(77), 1), Fate::Duplicate);
+        // A new placement is still subject to the cooldown.
+        assert_eq!(admit(3, Some(78), 2), Fate::Cooldown);
+        // Tokens are per connection.
+        assert_eq!(admit(4, Some(77), 5), Fate::Queued);
+
+        assert_eq!(queue.pop().map(|p| p.color), Some(1));
+        assert_eq!(queue.pop().map(|p| p.color), Some(5));
+        assert!(queue.pop().is_none());
+        // One cooldown, in one wheel bucket, for user 3.
+        assert!(cooldown.is_on_cooldown(3));
+        let charged = wheel
+            .wheel
+            .iter()
+            .filter(|bucket| bucket.is_on_cooldown(3))
+            .count();
+        assert_eq!(charged, 1);
+    }
+
+    #[test]
+    fn test_token_window_and_forget() {
+        let mut tokens = TokenFilter::new();
+        assert!(tokens.admit(1, None));
+        assert!(tokens.admit(1, None));
+
+        for token in 0..RECENT_PIXEL_TOKENS as u32 {
+            assert!(tokens.admit(1, Some(token)));
+        }
+        assert!(!tokens.admit(1, Some(0)));
+        // One more pushes the oldest token out of the window.
+        assert!(tokens.admit(1, Some(1_000)));
+        assert!(tokens.admit(1, Some(0)));
+        assert!(!tokens.admit(1, Some(1_000)));
+
+        tokens.forget(1);
+        assert!(tokens.admit(1, Some(1_000)));
+    }
+
+    #[test]
+    fn test_replayed_nonce_reaches_the_master_once() {
+        let mut tokens = TokenFilter::new();
+        let mut cooldown = CooldownArray::new();
+        let mut wheel = TimingWheel::new();
+        let queue = SpscRingBuffer::<PixelWrite>::shared();
+        // x=10, y=20, color 9, token, nonce 5: as a 0-RTT flight carries it.
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&10u16.to_le_bytes());
+        bytes.extend_from_slice(&20u16.to_le_bytes());
+        bytes.push(9);
+        bytes.extend_from_slice(&0x5eed_u32.to_le_bytes());
+        bytes.extend_from_slice(&5u32.to_le_bytes());
+
+        let mut fates = Vec::new();
+        for _ in 0..2 {
+            let datagram = PixelDatagram::from_wire(&bytes).unwrap();
+            fates.push(admit_pixel(
+                &mut tokens,
+                &mut cooldown,
+                &mut wheel,
+                &queue,
+                3,
+                &datagram,
+                pixel(datagram.color),
+                TIMING_WHEEL_TICKS,
+            ));
+            // Long enough for the cooldown to be over.
+            wheel.release(&mut cooldown, 3);
+        }
+        assert_eq!(fates, [Fate::Queued, Fate::Replay]);
+        assert_eq!(queue.pop().map(|p| p.color), Some(9));
+        assert!(queue.pop().is_none());
+
+        // A fresh token does not make an old nonce new.
+        bytes[5..9].copy_from_slice(&1u32.to_le_bytes());
+        let datagram = PixelDatagram::from_wire(&bytes).unwrap();
+        assert!(!tokens.fresh(3, datagram.nonce));
+        tokens.forget(3);
+        assert!(tokens.fresh(3, datagram.nonce));
+    }
+
+    #[test]
+    fn test_a_batch_gets_one_pixel_per_cooldown() {
+        let mut tokens = TokenFilter::new();
+        let mut cooldown = CooldownArray::new();
+        let mut wheel = TimingWheel::new();
+        let queue = SpscRingBuffer::<PixelWrite>::shared();
+        let records: Vec<_> = (1..=3u8)
+            .map(|color| {
+                let mut r = [0; protocol::batch::PIXEL_RECORD_LEN];
+                r[..2].copy_from_slice(&10u16.to_le_bytes());
+                r[2..4].copy_from_slice(&20u16.to_le_bytes());
+                r[4] = color;
+                r
+            })
+            .collect();
+        let mut batch = Vec::new();
+        protocol::batch::encode_batch(&records, &mut batch);
+
+        let mut fates = Vec::new();
+        for _ in 0..2 {
+            for record in protocol::batch::decode_batch(&batch).unwrap() {
+                let datagram = PixelDatagram::from_wire(record).unwrap();
+                fates.push(admit_pixel(
+                    &mut tokens,
+                    &mut cooldown,
+                    &mut wheel,
+                    &queue,
+                    3,
+                    &datagram,
+                    pixel(datagram.color),
+                    TIMING_WHEEL_TICKS,
+                ));
+            }
+            wheel.release(&mut cooldown, 3);
+        }
+        // The first pixel takes the cooldown; the rest of its batch is
+        // dropped, and the next batch after the cooldown gets one more.
+        use Fate::*;
+        assert_eq!(
+            fates,
+            [Queued, Cooldown, Cooldown, Queued, Cooldown, Cooldown]
+        );
+        assert_eq!(queue.pop().map(|p| p.color), Some(1));
+        assert_eq!(queue.pop().map(|p| p.color), Some(1));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_replay_window_takes_reordered_nonces_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.insert(10));
+        assert!(!window.insert(10));
+        // Behind the highest, within the window: once each.
+        assert!(window.insert(8));
+        assert!(!window.insert(8));
+        assert!(window.insert(100));
+        assert!(window.insert(100 - PIXEL_NONCE_WINDOW + 1));
+        assert!(!window.insert(100 - PIXEL_NONCE_WINDOW));
+        // A jump past the window forgets everything behind it.
+        assert!(window.insert(100 + 2 * PIXEL_NONCE_WINDOW));
+        assert!(!window.insert(100));
+        assert!(window.insert(101 + PIXEL_NONCE_WINDOW));
+    }
+}
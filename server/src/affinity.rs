@@ -0,0 +1,123 @@
+//! Core pinning. A cgroup or taskset restriction makes pins fail, which
+//! silently undoes the whole one-thread-per-core layout; these helpers make
+//! that visible (or fatal with `--strict-affinity`).
+
+/// CPUs the calling thread may run on, or None where that cannot be read.
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> Option<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some(
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> Option<Vec<usize>> {
+    None
+}
+
+/// Fails naming every requested core outside `allowed`.
+pub fn check_cores(requested: &[usize], allowed: &[usize]) -> Result<(), String> {
+    let mut outside: Vec<usize> = requested
+        .iter()
+        .copied()
+        .filter(|core| !allowed.contains(core))
+        .collect();
+    if outside.is_empty() {
+        return Ok(());
+    }
+    outside.sort_unstable();
+    outside.dedup();
+    Err(format!(
+        "requested core{} {} not in the allowed CPU set {} (cgroup or taskset restriction?)",
+        if outside.len() == 1 { "" } else { "s" },
+        format_cpu_list(&outside),
+        format_cpu_list(allowed)
+    ))
+}
+
+/// Inverse of `instance::parse_cpu_list` for sorted input: "0-3,6".
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let start = cpus[i];
+        while i + 1 < cpus.len() && cpus[i + 1] == cpus[i] + 1 {
+            i += 1;
+        }
+        if cpus[i] == start {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}-{}", start, cpus[i]));
+        }
+        i += 1;
+    }
+    if parts.is_empty() {
+        return "(empty)".to_string();
+    }
+    parts.join(",")
+}
+
+/// Pins the calling thread to `core_id` and reports the outcome. A failed
+/// pin is a loud warning, or exits the process in `strict` mode (a panic
+/// would only take down this thread). Returns whether the thread is pinned.
+pub fn pin_current(role: &str, core_id: usize, strict: bool) -> bool {
+    let allowed = allowed_cpus();
+    if core_affinity::set_for_current(core_affinity::CoreId { id: core_id }) {
+        println!("{} pinned to core {}", role, core_id);
+        return true;
+    }
+    let allowed = allowed.map_or("unknown".to_string(), |cpus| format_cpu_list(&cpus));
+    println!(
+        "WARNING: {} could not be pinned to core {} (allowed CPUs: {}); it runs unpinned",
+        role, core_id, allowed
+    );
+    if strict {
+        eprintln!("Refusing to run unpinned (--strict-affinity)");
+        std::process::exit(1);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_cores_against_synthetic_masks() {
+        let allowed = [0, 1, 2, 3, 8];
+        assert_eq!(check_cores(&[0, 1, 2, 3], &allowed), Ok(()));
+        assert_eq!(check_cores(&[8, 8], &allowed), Ok(()));
+
+        let err = check_cores(&[2, 5], &allowed).unwrap_err();
+        assert!(
+            err.starts_with("requested core 5 not in the allowed CPU set 0-3,8"),
+            "{}",
+            err
+        );
+
+        // Workers wrap around the core list, so cores can repeat.
+        let err = check_cores(&[7, 4, 5, 7, 0], &allowed).unwrap_err();
+        assert!(err.starts_with("requested cores 4-5,7 not in"), "{}", err);
+
+        assert!(check_cores(&[0], &[]).unwrap_err().contains("set (empty)"));
+    }
+
+    #[test]
+    fn test_format_cpu_list() {
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3]), "0-3");
+        assert_eq!(format_cpu_list(&[0, 2, 3, 4, 9]), "0,2-4,9");
+        assert_eq!(format_cpu_list(&[5]), "5");
+        assert_eq!(
+            crate::instance::parse_cpu_list(&format_cpu_list(&[0, 2, 3, 4, 9])),
+            Ok(vec![0, 2, 3, 4, 9])
+        );
+    }
+}
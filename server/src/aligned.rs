@@ -0,0 +1,122 @@
+//! Layout for state that one thread writes and another reads: the SPSC
+//! rings and the per-worker counter blocks. 64-byte padding is not enough on
+//! x86: the spatial prefetcher pulls cache lines in adjacent pairs, so two
+//! hot values 64 bytes apart still bounce between cores. Everything shared
+//! per worker gets 128 bytes to itself, as crossbeam's `CachePadded` does.
+
+use std::alloc::{self, Layout};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// Alignment (and size granularity) of shared per-worker state.
+pub const SHARED_ALIGN: usize = 128;
+
+// https://docs.rs/crossbeam-utils/latest/src/crossbeam_utils/cache_padded.rs.html#148-150
+#[repr(align(128))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// A heap value on its own 128-byte aligned lines, with its size rounded up
+/// so nothing else the allocator hands out can share its last line. Use it
+/// for any per-worker state behind an `Arc`: `Arc`'s reference counts are
+/// then in a separate allocation from the hot data.
+pub struct AlignedBox<T> {
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: Send> Send for AlignedBox<T> {}
+unsafe impl<T: Sync> Sync for AlignedBox<T> {}
+
+impl<T> AlignedBox<T> {
+    fn layout() -> Layout {
+        Layout::new::<T>()
+            .align_to(SHARED_ALIGN)
+            .unwrap()
+            .pad_to_align()
+    }
+
+    pub fn new(value: T) -> Self {
+        let boxed = Self::allocate(false);
+        // SAFETY: freshly allocated for a T and not yet initialized.
+        unsafe { boxed.ptr.as_ptr().write(value) };
+        boxed
+    }
+
+    /// Allocates zeroed memory and never builds the value on the stack,
+    /// for values too large to move around.
+    ///
+    /// # Safety
+    /// All-zero bytes must be a valid `T`.
+    pub unsafe fn new_zeroed() -> Self {
+        Self::allocate(true)
+    }
+
+    fn allocate(zeroed: bool) -> Self {
+        let layout = Self::layout();
+        // SAFETY: the layout is never zero-sized (padded to SHARED_ALIGN).
+        let raw = unsafe {
+            if zeroed {
+                alloc::alloc_zeroed(layout)
+            } else {
+                alloc::alloc(layout)
+            }
+        };
+        let Some(ptr) = NonNull::new(raw as *mut T) else {
+            alloc::handle_alloc_error(layout)
+        };
+        Self { ptr }
+    }
+}
+
+impl<T> Deref for AlignedBox<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        // SAFETY: initialized by the constructors, freed only on drop.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for AlignedBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the value is initialized and allocated with `layout()`.
+        unsafe {
+            std::ptr::drop_in_place(self.ptr.as_ptr());
+            alloc::dealloc(self.ptr.as_ptr() as *mut u8, Self::layout());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_aligned_box_alignment_and_drop() {
+        let counters: Vec<AlignedBox<AtomicU64>> =
+            (0..8).map(|i| AlignedBox::new(AtomicU64::new(i))).collect();
+        for (i, c) in counters.iter().enumerate() {
+            assert_eq!(&**c as *const AtomicU64 as usize % SHARED_ALIGN, 0);
+            assert_eq!(c.load(Ordering::Relaxed), i as u64);
+        }
+        assert_eq!(AlignedBox::<u8>::layout().size(), SHARED_ALIGN);
+        assert_eq!(AlignedBox::<[u8; 129]>::layout().size(), 2 * SHARED_ALIGN);
+
+        let zeroed = unsafe { AlignedBox::<[u64; 1024]>::new_zeroed() };
+        assert!(zeroed.iter().all(|&v| v == 0));
+
+        let tracked = std::rc::Rc::new(());
+        drop(AlignedBox::new(tracked.clone()));
+        assert_eq!(std::rc::Rc::strong_count(&tracked), 1);
+    }
+}
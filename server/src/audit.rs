@@ -0,0 +1,377 @@
+//! A uniform sample of applied writes for moderators looking into bot
+//! rings: who placed what, where, from which address, without keeping
+//! every write.
+//!
+//! The master keeps AUDIT_RESERVOIR_LEN entries and replaces them by
+//! reservoir sampling, so every write applied since the last reset had
+//! the same chance of being in the sample. It skips ahead by Algorithm L:
+//! past the first fill a write costs one decrement, and only the chosen
+//! ones are built and stored. Every AUDIT_PUBLISH_MS the master copies
+//! the reservoir out for the dashboard (`GET /admin/audit`), which filters
+//! and pages it; `POST /admin/audit` starts a new sample.
+
+use crate::const_settings::AUDIT_RESERVOIR_LEN;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// One sampled write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Unix ms the master applied it.
+    pub ms: u64,
+    /// The sender's IPv4 address; 0 for IPv6 peers.
+    pub ipv4: u32,
+    /// Per worker: the same id on two workers is two users.
+    pub user_id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub color: u8,
+    pub worker: u8,
+}
+
+/// The sender's address as carried in a PixelWrite: IPv4 (or IPv4-mapped)
+/// only, 0 otherwise.
+pub fn ipv4_of(addr: SocketAddr) -> u32 {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.into(),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(0, u32::from),
+    }
+}
+
+/// The reservoir as last published, with the writes it was drawn from.
+struct Published {
+    seen: u64,
+    entries: Vec<AuditEntry>,
+}
+
+static PUBLISHED: RwLock<Published> = RwLock::new(Published {
+    seen: 0,
+    entries: Vec::new(),
+});
+
+/// Set by `POST /admin/audit`; the master empties the reservoir at its
+/// next publish.
+static RESET: AtomicBool = AtomicBool::new(false);
+
+/// Fixed-size uniform sample, owned by the master thread.
+pub struct AuditReservoir {
+    entries: Vec<AuditEntry>,
+    capacity: usize,
+    /// Writes offered since the last reset.
+    seen: u64,
+    /// Writes to pass over before the next replacement.
+    skip: u64,
+    /// Algorithm L's running weight: the largest of the `capacity` smallest
+    /// random keys drawn so far.
+    w: f64,
+    rng: StdRng,
+}
+
+impl Default for AuditReservoir {
+    fn default() -> Self {
+        Self::new(AUDIT_RESERVOIR_LEN, StdRng::from_entropy())
+    }
+}
+
+impl AuditReservoir {
+    pub fn new(capacity: usize, rng: StdRng) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            capacity: capacity.max(1),
+            seen: 0,
+            skip: 0,
+            w: 1.0,
+            rng,
+        }
+    }
+
+    /// Offers one applied write; `entry` only runs for the writes that get
+    /// a slot.
+    #[inline(always)]
+    pub fn record(&mut self, entry: impl FnOnce() -> AuditEntry) {
+        self.seen += 1;
+        if self.skip > 0 {
+            self.skip -= 1;
+            return;
+        }
+        self.replace(entry());
+    }
+
+    #[cold]
+    fn replace(&mut self, entry: AuditEntry) {
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+            if self.entries.len() < self.capacity {
+                return;
+            }
+        } else {
+            let slot = self.rng.gen_range(0..self.capacity);
+            self.entries[slot] = entry;
+        }
+        self.w *= (self.uniform().ln() / self.capacity as f64).exp();
+        // Geometric: how many writes go by before one beats the weight.
+        self.skip = (self.uniform().ln() / (1.0 - self.w).ln()) as u64;
+    }
+
+    /// In (0, 1]: the logarithms above stay finite.
+    fn uniform(&mut self) -> f64 {
+        1.0 - self.rng.r#gen::<f64>()
+    }
+
+    pub fn entries(&self) -> &[AuditEntry] {
+        &self.entries
+    }
+
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.seen = 0;
+        self.skip = 0;
+        self.w = 1.0;
+    }
+
+    /// Makes the sample visible to the dashboard, after starting over if a
+    /// reset was asked for. Skipped while a query holds the copy: the
+    /// master does not wait on the dashboard.
+    pub fn publish(&mut self) {
+        if RESET.swap(false, Ordering::Relaxed) {
+            self.clear();
+        }
+        let Ok(mut published) = PUBLISHED.try_write() else {
+            return;
+        };
+        published.seen = self.seen;
+        published.entries.clone_from(&self.entries);
+    }
+}
+
+/// Asks the master to start a new sample.
+pub fn request_reset() {
+    RESET.store(true, Ordering::Relaxed);
+}
+
+/// Which entries an audit query wants. Unset fields match everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub worker: Option<u8>,
+    pub user_id: Option<u32>,
+    /// Address and prefix mask; never matches entries without an IPv4.
+    pub ip: Option<(u32, u32)>,
+    /// `[x0, x1) × [y0, y1)`.
+    pub region: (u16, u16, u16, u16),
+}
+
+impl Default for AuditFilter {
+    fn default() -> Self {
+        Self {
+            worker: None,
+            user_id: None,
+            ip: None,
+            region: (0, 0, u16::MAX, u16::MAX),
+        }
+    }
+}
+
+impl AuditFilter {
+    /// `a.b.c.d/len`, or a bare address for /32.
+    pub fn parse_ip_prefix(prefix: &str) -> Option<(u32, u32)> {
+        let (addr, len) = prefix.split_once('/').unwrap_or((prefix, "32"));
+        let addr: u32 = addr.parse::<std::net::Ipv4Addr>().ok()?.into();
+        let mask = match len.parse::<u32>().ok()? {
+            0 => 0,
+            len @ 1..=32 => u32::MAX << (32 - len),
+            _ => return None,
+        };
+        Some((addr & mask, mask))
+    }
+
+    pub fn matches(&self, e: &AuditEntry) -> bool {
+        let (x0, y0, x1, y1) = self.region;
+        self.worker.is_none_or(|w| w == e.worker)
+            && self.user_id.is_none_or(|u| u == e.user_id)
+            && self
+                .ip
+                .is_none_or(|(addr, mask)| e.ipv4 != 0 && e.ipv4 & mask == addr)
+            && (x0..x1).contains(&e.x)
+            && (y0..y1).contains(&e.y)
+    }
+}
+
+/// One page of the published entries matching `filter`, oldest first:
+/// `{"seen":..,"sampled":..,"matched":..,"offset":..,"entries":[..]}`.
+pub fn query_json(filter: &AuditFilter, offset: usize, limit: usize) -> String {
+    let published = PUBLISHED.read().unwrap();
+    page_json(published.seen, &published.entries, filter, offset, limit)
+}
+
+fn page_json(
+    seen: u64,
+    entries: &[AuditEntry],
+    filter: &AuditFilter,
+    offset: usize,
+    limit: usize,
+) -> String {
+    let mut matched: Vec<AuditEntry> = entries
+        .iter()
+        .filter(|e| filter.matches(e))
+        .copied()
+        .collect();
+    matched.sort_unstable_by_key(|e| (e.ms, e.worker, e.user_id));
+
+    let mut out = format!(
+        "{{\"seen\":{},\"sampled\":{},\"matched\":{},\"offset\":{},\"entries\":[",
+        seen,
+        entries.len(),
+        matched.len(),
+        offset
+    );
+    for (i, e) in matched.iter().skip(offset).take(limit).enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"ms\":{},\"worker\":{},\"user\":{},\"ip\":",
+            e.ms, e.worker, e.user_id
+        );
+        if e.ipv4 == 0 {
+            out.push_str("null");
+        } else {
+            let _ = write!(out, "\"{}\"", std::net::Ipv4Addr::from(e.ipv4));
+        }
+        let _ = write!(out, ",\"x\":{},\"y\":{},\"color\":{}}}", e.x, e.y, e.color);
+    }
+    out.push_str("]}");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u32) -> AuditEntry {
+        AuditEntry {
+            ms: n as u64,
+            ipv4: 0x0a00_0000 | (n % 256),
+            user_id: n % 10,
+            x: (n % 100) as u16,
+            y: 0,
+            color: 1,
+            worker: (n % 2) as u8,
+        }
+    }
+
+    #[test]
+    fn test_reservoir_samples_uniformly() {
+        // 4000 runs of a 10-slot reservoir over 100 writes: each write
+        // should be kept 400 times.
+        const WRITES: u32 = 100;
+        let mut kept = [0u32; WRITES as usize];
+        for run in 0..4000 {
+            let mut reservoir = AuditReservoir::new(10, StdRng::seed_from_u64(run));
+            for n in 0..WRITES {
+                reservoir.record(|| entry(n));
+            }
+            assert_eq!(reservoir.seen(), WRITES as u64);
+            assert_eq!(reservoir.entries().len(), 10);
+            for e in reservoir.entries() {
+                kept[e.ms as usize] += 1;
+            }
+        }
+        // Binomial(4000, 0.1): sd 19, so ±100 is over five of them.
+        for (n, &k) in kept.iter().enumerate() {
+            assert!((300..=500).contains(&k), "write {} kept {} times", n, k);
+        }
+        // Late writes are not favoured or starved as a group either.
+        let (early, late): (u32, u32) = (kept[..50].iter().sum(), kept[50..].iter().sum());
+        assert!(early.abs_diff(late) < 800, "{} vs {}", early, late);
+    }
+
+    #[test]
+    fn test_reservoir_stays_bounded_and_skips_most_writes() {
+        let mut reservoir = AuditReservoir::new(64, StdRng::seed_from_u64(7));
+        let mut built = 0;
+        for n in 0..1_000_000 {
+            reservoir.record(|| {
+                built += 1;
+                entry(n)
+            });
+        }
+        assert_eq!(reservoir.entries().len(), 64);
+        // About k + k·ln(n/k) entries are built: ~680 here.
+        assert!(built < 2_000, "{} entries built", built);
+
+        reservoir.clear();
+        assert_eq!((reservoir.seen(), reservoir.entries().len()), (0, 0));
+        reservoir.record(|| entry(1));
+        assert_eq!(reservoir.entries(), &[entry(1)]);
+    }
+
+    #[test]
+    fn test_filter_by_user_ip_prefix_and_region() {
+        let all = AuditFilter::default();
+        assert!(all.matches(&entry(3)));
+
+        let user = AuditFilter {
+            worker: Some(1),
+            user_id: Some(3),
+            ..all
+        };
+        assert!(user.matches(&entry(13)));
+        assert!(!user.matches(&entry(14)));
+        assert!(!user.matches(&entry(4)));
+
+        let ip = AuditFilter {
+            ip: AuditFilter::parse_ip_prefix("10.0.0.16/28"),
+            ..all
+        };
+        assert!(ip.matches(&entry(16)) && ip.matches(&entry(31)));
+        assert!(!ip.matches(&entry(32)));
+        let anywhere = AuditFilter {
+            ip: AuditFilter::parse_ip_prefix("0.0.0.0/0"),
+            ..all
+        };
+        assert!(!anywhere.matches(&AuditEntry {
+            ipv4: 0,
+            ..entry(1)
+        }));
+        assert_eq!(
+            AuditFilter::parse_ip_prefix("10.0.0.1"),
+            Some((0x0a00_0001, u32::MAX))
+        );
+        assert_eq!(AuditFilter::parse_ip_prefix("10.0.0.0/33"), None);
+        assert_eq!(AuditFilter::parse_ip_prefix("::1/8"), None);
+
+        let region = AuditFilter {
+            region: (10, 0, 20, 1),
+            ..all
+        };
+        assert!(region.matches(&entry(10)) && region.matches(&entry(19)));
+        assert!(!region.matches(&entry(20)));
+    }
+
+    #[test]
+    fn test_pages_are_filtered_and_oldest_first() {
+        let entries: Vec<_> = (0..50).rev().map(entry).collect();
+        let odd = AuditFilter {
+            worker: Some(1),
+            ..Default::default()
+        };
+        let page = page_json(1_000, &entries, &odd, 2, 2);
+        assert_eq!(
+            page,
+            "{\"seen\":1000,\"sampled\":50,\"matched\":25,\"offset\":2,\"entries\":[\
+             {\"ms\":5,\"worker\":1,\"user\":5,\"ip\":\"10.0.0.5\",\"x\":5,\"y\":0,\"color\":1},\
+             {\"ms\":7,\"worker\":1,\"user\":7,\"ip\":\"10.0.0.7\",\"x\":7,\"y\":0,\"color\":1}]}"
+        );
+        let past_end = page_json(1_000, &entries, &odd, 25, 10);
+        assert!(past_end.ends_with("\"matched\":25,\"offset\":25,\"entries\":[]}"));
+    }
+}
@@ -0,0 +1,348 @@
+//! Broadcast bandwidth caps, for servers behind a modest uplink: a ceiling
+//! per connection and one per worker, both over BANDWIDTH_WINDOW_MS windows.
+//!
+//! Diff chunks a connection has no budget left for are skipped, not queued:
+//! the next full broadcast (every FULL_BROADCAST_INTERVAL_MS) brings the client
+//! back in line. Full syncs are exempt from both ceilings so new clients
+//! still onboard, and draw from a budget of their own instead. What they send
+//! still counts against the other two, so diffs back off after a full sync.
+//! A worker therefore sends at most `worker_kbps + full_sync_kbps` of
+//! broadcast payload (QUIC and UDP headers not included).
+//!
+//! Limits are set from the command line and can be changed while running
+//! (`POST /admin/bandwidth` on the dashboard); workers pick changes up at
+//! their next window. 0 means unlimited.
+
+use crate::const_settings::BANDWIDTH_WINDOW_MS;
+use crate::metrics::WorkerMetrics;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Kilobits (1000 bits) per second for each ceiling; 0 = unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub conn_kbps: u64,
+    pub worker_kbps: u64,
+    pub full_sync_kbps: u64,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.conn_kbps == 0 && self.worker_kbps == 0 && self.full_sync_kbps == 0
+    }
+}
+
+/// The limits every worker applies, shared with the dashboard.
+pub struct SharedLimits {
+    conn_kbps: AtomicU64,
+    worker_kbps: AtomicU64,
+    full_sync_kbps: AtomicU64,
+}
+
+impl SharedLimits {
+    pub const fn new() -> Self {
+        Self {
+            conn_kbps: AtomicU64::new(0),
+            worker_kbps: AtomicU64::new(0),
+            full_sync_kbps: AtomicU64::new(0),
+        }
+    }
+
+    pub fn load(&self) -> Limits {
+        Limits {
+            conn_kbps: self.conn_kbps.load(Ordering::Relaxed),
+            worker_kbps: self.worker_kbps.load(Ordering::Relaxed),
+            full_sync_kbps: self.full_sync_kbps.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn store(&self, limits: Limits) {
+        self.conn_kbps.store(limits.conn_kbps, Ordering::Relaxed);
+        self.worker_kbps
+            .store(limits.worker_kbps, Ordering::Relaxed);
+        self.full_sync_kbps
+            .store(limits.full_sync_kbps, Ordering::Relaxed);
+    }
+}
+
+impl Default for SharedLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static BANDWIDTH_LIMITS: SharedLimits = SharedLimits::new();
+
+/// Payload bytes `kbps` allows per window; u64::MAX for unlimited.
+fn window_bytes(kbps: u64) -> u64 {
+    match kbps {
+        0 => u64::MAX,
+        kbps => kbps.saturating_mul(BANDWIDTH_WINDOW_MS) / 8,
+    }
+}
+
+/// Whether a diff chunk may go out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    /// This connection spent its budget; others may still get the chunk.
+    ConnThrottled,
+    /// The worker spent its budget: nobody gets anything until the window
+    /// rolls over.
+    WorkerThrottled,
+}
+
+/// One worker's spending in the current window.
+pub struct BroadcastBudget {
+    limits: &'static SharedLimits,
+    metrics: &'static WorkerMetrics,
+    /// None until the first broadcast.
+    window_start_ms: Option<u64>,
+    /// Budgets of the current window, in bytes (u64::MAX = unlimited).
+    conn_limit: u64,
+    worker_limit: u64,
+    full_sync_limit: u64,
+    worker_sent: u64,
+    full_sync_sent: u64,
+    /// Indexed by user id.
+    conn_sent: Box<[u32]>,
+    worker_engaged: bool,
+    /// Connection index the next diff (and full) broadcast starts at: where
+    /// the last one ran out of budget, so a tight cap does not always serve
+    /// the same clients. Kept apart so full syncs rotate on their own.
+    diff_cursor: usize,
+    full_cursor: usize,
+}
+
+impl BroadcastBudget {
+    pub fn new(
+        max_users: usize,
+        limits: &'static SharedLimits,
+        metrics: &'static WorkerMetrics,
+    ) -> Self {
+        Self {
+            limits,
+            metrics,
+            window_start_ms: None,
+            conn_limit: u64::MAX,
+            worker_limit: u64::MAX,
+            full_sync_limit: u64::MAX,
+            worker_sent: 0,
+            full_sync_sent: 0,
+            conn_sent: vec![0; max_users].into_boxed_slice(),
+            worker_engaged: false,
+            diff_cursor: 0,
+            full_cursor: 0,
+        }
+    }
+
+    /// Starts a diff or full broadcast to `connections` connections at
+    /// `now_ms`, opening a new window (with the current limits) if the last
+    /// one is over. Returns the index of the connection to serve first.
+    pub fn begin(&mut self, now_ms: u64, connections: usize, full_sync: bool) -> usize {
+        if self
+            .window_start_ms
+            .is_none_or(|start| now_ms.wrapping_sub(start) >= BANDWIDTH_WINDOW_MS)
+        {
+            let limits = self.limits.load();
+            self.conn_limit = window_bytes(limits.conn_kbps);
+            self.worker_limit = window_bytes(limits.worker_kbps);
+            self.full_sync_limit = window_bytes(limits.full_sync_kbps);
+            if self.worker_sent > 0 || self.full_sync_sent > 0 {
+                self.conn_sent.fill(0);
+            }
+            self.worker_sent = 0;
+            self.full_sync_sent = 0;
+            self.worker_engaged = false;
+            self.window_start_ms = Some(now_ms);
+        }
+        let cursor = if full_sync {
+            self.full_cursor
+        } else {
+            self.diff_cursor
+        };
+        if connections == 0 {
+            0
+        } else {
+            cursor % connections
+        }
+    }
+
+    /// Whether a diff chunk of `len` bytes may go to `user_id`; charged if so.
+    #[inline]
+    pub fn diff_chunk(&mut self, user_id: u32, len: usize) -> Verdict {
+        let len = len as u64;
+        if self.worker_sent + len > self.worker_limit {
+            if !self.worker_engaged {
+                self.worker_engaged = true;
+                self.metrics
+                    .global_throttle_windows
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            return Verdict::WorkerThrottled;
+        }
+        let sent = &mut self.conn_sent[user_id as usize];
+        if *sent as u64 + len > self.conn_limit {
+            self.metrics
+                .conn_throttled_chunks
+                .fetch_add(1, Ordering::Relaxed);
+            return Verdict::ConnThrottled;
+        }
+        *sent = sent.saturating_add(len as u32);
+        self.worker_sent += len;
+        Verdict::Send
+    }
+
+    /// Whether a full sync of `len` bytes fits the full-sync budget; charged
+    /// (to every budget) if so. Full syncs go out whole or not at all.
+    #[inline]
+    pub fn full_sync(&mut self, user_id: u32, len: usize) -> bool {
+        let len = len as u64;
+        if self.full_sync_sent + len > self.full_sync_limit {
+            return false;
+        }
+        self.full_sync_sent += len;
+        self.worker_sent = self.worker_sent.saturating_add(len);
+        let sent = &mut self.conn_sent[user_id as usize];
+        *sent = sent.saturating_add(len.min(u32::MAX as u64) as u32);
+        true
+    }
+
+    /// Records that the broadcast stopped short at connection `index`
+    /// (`remaining` connections were not served); the next one starts there.
+    pub fn stop_at(&mut self, index: usize, remaining: usize, full_sync: bool) {
+        if full_sync {
+            self.full_cursor = index;
+            self.metrics
+                .full_syncs_deferred
+                .fetch_add(remaining as u64, Ordering::Relaxed);
+        } else {
+            self.diff_cursor = index;
+        }
+    }
+
+    /// A fresh connection under `user_id` starts with a clean budget.
+    pub fn forget(&mut self, user_id: u32) {
+        self.conn_sent[user_id as usize] = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::{BROADCAST_INTERVAL_MS, FULL_BROADCAST_INTERVAL_MS, MAX_WORKERS};
+    use crate::metrics::WORKER_METRICS;
+
+    /// One broadcast the way the worker loops do it.
+    fn broadcast(
+        budget: &mut BroadcastBudget,
+        now_ms: u64,
+        users: &[u32],
+        payload: usize,
+        full: bool,
+        received: &mut [u64],
+        full_syncs: &mut [u32],
+    ) -> u64 {
+        let n = users.len();
+        let start = budget.begin(now_ms, n, full);
+        let mut sent = 0;
+        for i in 0..n {
+            let index = (start + i) % n;
+            let user = users[index];
+            if full {
+                if !budget.full_sync(user, payload) {
+                    budget.stop_at(index, n - i, true);
+                    break;
+                }
+                full_syncs[user as usize] += 1;
+                sent += payload as u64;
+                continue;
+            }
+            let mut left = payload;
+            while left > 0 {
+                let chunk = left.min(1200);
+                match budget.diff_chunk(user, chunk) {
+                    Verdict::Send => {
+                        received[user as usize] += chunk as u64;
+                        sent += chunk as u64;
+                    }
+                    Verdict::ConnThrottled => {}
+                    Verdict::WorkerThrottled => {
+                        budget.stop_at(index, n - i, false);
+                        return sent;
+                    }
+                }
+                left -= chunk;
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn test_caps_hold_and_everyone_is_served() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 1];
+        let throttles_before = metrics.global_throttle_windows.load(Ordering::Relaxed);
+        let limits = Limits {
+            conn_kbps: 64,
+            worker_kbps: 800,
+            full_sync_kbps: 400,
+        };
+        static LIMITS: SharedLimits = SharedLimits::new();
+        LIMITS.store(limits);
+        let mut budget = BroadcastBudget::new(512, &LIMITS, metrics);
+
+        // 400 observers, a 6 KB diff every tick, a 4 KB full sync every
+        // FULL_BROADCAST_INTERVAL_MS: far more than the caps allow.
+        let users: Vec<u32> = (0..400).collect();
+        let mut received = vec![0u64; 512];
+        let mut full_syncs = vec![0u32; 512];
+        let ticks_per_window = BANDWIDTH_WINDOW_MS / BROADCAST_INTERVAL_MS;
+        let ticks_per_full = FULL_BROADCAST_INTERVAL_MS / BROADCAST_INTERVAL_MS;
+        let mut window_sent = 0;
+        for tick in 0..40 * ticks_per_full {
+            if tick % ticks_per_window == 0 {
+                assert!(window_sent <= window_bytes(limits.worker_kbps + limits.full_sync_kbps));
+                window_sent = 0;
+            }
+            let full = tick % ticks_per_full == 0;
+            let payload = if full { 4_000 } else { 6_000 };
+            window_sent += broadcast(
+                &mut budget,
+                tick * BROADCAST_INTERVAL_MS,
+                &users,
+                payload,
+                full,
+                &mut received,
+                &mut full_syncs,
+            );
+        }
+
+        // The worker cap engaged in every window.
+        let windows = 40 * ticks_per_full / ticks_per_window;
+        let throttles = metrics.global_throttle_windows.load(Ordering::Relaxed);
+        assert_eq!(throttles - throttles_before, windows);
+
+        // No connection went over its own cap, yet all got diffs and, over
+        // enough full broadcasts, a full sync.
+        let conn_cap_total = windows * window_bytes(limits.conn_kbps);
+        for &user in &users {
+            assert!(received[user as usize] > 0, "user {} starved", user);
+            assert!(received[user as usize] <= conn_cap_total);
+            assert!(full_syncs[user as usize] > 0, "user {} never synced", user);
+        }
+
+        // Raising the cap at runtime applies from the next window.
+        LIMITS.store(Limits::default());
+        let start = budget.begin(1_000_000, users.len(), false);
+        assert!(start < users.len());
+        for _ in 0..1_000 {
+            assert_eq!(budget.diff_chunk(0, 1_200), Verdict::Send);
+        }
+    }
+
+    #[test]
+    fn test_window_bytes() {
+        assert_eq!(window_bytes(0), u64::MAX);
+        assert_eq!(window_bytes(8), BANDWIDTH_WINDOW_MS);
+        assert!(Limits::default().is_unlimited());
+    }
+}
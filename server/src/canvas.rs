@@ -1,5 +1,15 @@
-use crate::const_settings::{CANVAS_BUFFER_POOL_SIZE, CANVAS_HEIGHT, CANVAS_SIZE, CANVAS_WIDTH};
-use std::sync::atomic::AtomicUsize;
+use crate::config::ServerConfig;
+use crate::const_settings::{
+    CANVAS_BUFFER_POOL_MASK, CANVAS_BUFFER_POOL_SIZE, CANVAS_HEIGHT, CANVAS_SIZE, CANVAS_WIDTH,
+    DIFF_ENTRY_SIZE, TILE_BITMAP_LEN, TILE_COUNT, TILE_SIZE, TILES_X,
+};
+use protocol::control::{
+    NEWEST_SNAPSHOT, SUBSCRIBE_BITMAP_LEN, SUBSCRIBE_TILE_SIZE, SUBSCRIBE_TILES_X, Subscription,
+    TILE_CHECKSUM_COUNT, canvas_tile_checksum,
+};
+use protocol::rle::{rle_encode, rle_max_len};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 #[derive(Clone, Copy)]
 pub struct CanvasBuffer {
@@ -27,13 +37,13 @@ pub static mut BUFFER_POOL: [CanvasBuffer; CANVAS_BUFFER_POOL_SIZE] =
 // Compressed buffers can be up to 2x the original size in worst case RLE
 #[derive(Clone, Copy)]
 pub struct CompressedBuffer {
-    pub data: [u8; CANVAS_SIZE * 2],
+    pub data: [u8; rle_max_len(CANVAS_SIZE)],
 }
 
 impl CompressedBuffer {
     pub const fn new() -> Self {
         Self {
-            data: [0; CANVAS_SIZE * 2],
+            data: [0; rle_max_len(CANVAS_SIZE)],
         }
     }
 }
@@ -48,12 +58,43 @@ pub static mut COMPRESSED_BUFFER_POOL: [CompressedBuffer; CANVAS_BUFFER_POOL_SIZ
     [CompressedBuffer::new(); CANVAS_BUFFER_POOL_SIZE];
 pub static mut COMPRESSED_LENS: [usize; CANVAS_BUFFER_POOL_SIZE] = [0; CANVAS_BUFFER_POOL_SIZE];
 
+/// Per-tile checksums of each pool slot (`protocol::control::tile_checksum`),
+/// kept up to date by the master as it copies tiles in: a snapshot only
+/// rehashes the tiles it publishes.
+pub static mut TILE_CHECKSUMS: [[u32; TILE_CHECKSUM_COUNT]; CANVAS_BUFFER_POOL_SIZE] =
+    [[0; TILE_CHECKSUM_COUNT]; CANVAS_BUFFER_POOL_SIZE];
+
 // The currently active buffer index that workers read from.
 // RCU like without atomic pointers, just offsets of fixed size array
 pub static ACTIVE_INDEX: AtomicUsize = AtomicUsize::new(0);
 
+// Snapshots published so far. The master advances one slot per publish, so
+// snapshot `seq` lives in `snapshot_slot(seq)` until the pool wraps around.
+pub static PUBLISH_SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[inline(always)]
+pub fn snapshot_slot(seq: u64) -> usize {
+    seq as usize & CANVAS_BUFFER_POOL_MASK
+}
+
+/// Master side: makes `slot`, the one after the current, the active snapshot.
+pub fn publish_slot(slot: usize) {
+    ACTIVE_INDEX.store(slot, Ordering::Release);
+    PUBLISH_SEQ.fetch_add(1, Ordering::Release);
+}
+
+/// Whether snapshot `old_seq` is still intact while `newest_seq` is the
+/// latest: the master is already rewriting the slot of `newest_seq + 1`.
+#[inline(always)]
+pub fn snapshot_retained(old_seq: u64, newest_seq: u64) -> bool {
+    newest_seq + 1 - old_seq < CANVAS_BUFFER_POOL_SIZE as u64
+}
+
 pub struct Canvas {
     pub pixels: Box<[u8; CANVAS_SIZE]>,
+    /// What each pixel looked like before anyone painted it. Writes landing on
+    /// a pixel still at its background color count as virgin writes.
+    pub background: Box<[u8; CANVAS_SIZE]>,
 }
 
 impl Default for Canvas {
@@ -64,11 +105,31 @@ impl Default for Canvas {
 
 impl Canvas {
     pub fn new() -> Self {
+        Self::with_background(solid_background(0))
+    }
+
+    pub fn with_background(background: Box<[u8; CANVAS_SIZE]>) -> Self {
         Self {
-            pixels: vec![0; CANVAS_SIZE].into_boxed_slice().try_into().unwrap(),
+            pixels: background.clone(),
+            background,
         }
     }
 
+    /// Builds the startup canvas from `--background-image` if given, else a
+    /// solid `--background` color.
+    pub fn load_or_new(config: &ServerConfig) -> std::io::Result<Self> {
+        let background = match &config.background_image {
+            Some(path) => load_background_image(path)?,
+            None => solid_background(config.background),
+        };
+        Ok(Self::with_background(background))
+    }
+
+    #[inline(always)]
+    pub fn background_at(&self, x: usize, y: usize) -> u8 {
+        self.background[y * CANVAS_WIDTH + x]
+    }
+
     #[inline(always)]
     pub fn set_pixel(&self, x: usize, y: usize, color: u8) {
         if x < CANVAS_WIDTH && y < CANVAS_HEIGHT {
@@ -80,11 +141,410 @@ impl Canvas {
         }
     }
 
+    /// Writes a pixel and returns the color it replaced, or None if out of bounds.
+    #[inline(always)]
+    pub fn swap_pixel(&self, x: usize, y: usize, color: u8) -> Option<u8> {
+        if x < CANVAS_WIDTH && y < CANVAS_HEIGHT {
+            let index = y * CANVAS_WIDTH + x;
+            unsafe {
+                let pixel_ptr = (self.pixels.as_ptr() as *mut u8).add(index);
+                Some(std::ptr::replace(pixel_ptr, color))
+            }
+        } else {
+            None
+        }
+    }
+
     pub fn snapshot_to_pool(&self, target_index: usize) {
         unsafe {
             let src = self.pixels.as_ptr();
             let dst = BUFFER_POOL[target_index].data.as_mut_ptr();
             std::ptr::copy_nonoverlapping(src, dst, CANVAS_SIZE);
+            for (tile, checksum) in TILE_CHECKSUMS[target_index].iter_mut().enumerate() {
+                *checksum = self.tile_checksum(tile);
+            }
+        }
+    }
+
+    /// Copies a single tile of the live canvas into a pool slot, and its
+    /// checksum along with it.
+    pub fn copy_tile_to_pool(&self, tile: usize, target_index: usize) {
+        let (x0, y0, w, h) = tile_bounds(tile);
+        unsafe {
+            let dst = BUFFER_POOL[target_index].data.as_mut_ptr();
+            for y in y0..y0 + h {
+                let offset = y * CANVAS_WIDTH + x0;
+                std::ptr::copy_nonoverlapping(self.pixels.as_ptr().add(offset), dst.add(offset), w);
+            }
+            TILE_CHECKSUMS[target_index][tile] = self.tile_checksum(tile);
+        }
+    }
+
+    /// Checksum of a tile of the live canvas, in the protocol's tile grid.
+    pub fn tile_checksum(&self, tile: usize) -> u32 {
+        canvas_tile_checksum(
+            &self.pixels[..],
+            CANVAS_WIDTH as u16,
+            CANVAS_HEIGHT as u16,
+            tile,
+        )
+    }
+}
+
+/// Publishes the startup canvas into the currently active pool slot, so the
+/// first snapshot the master derives from it (and the baseline workers diff
+/// against) already carries the background instead of zeros.
+pub fn seed_active_slot(canvas: &Canvas) -> usize {
+    let active = ACTIVE_INDEX.load(Ordering::Acquire);
+    canvas.snapshot_to_pool(active);
+    active
+}
+
+pub fn solid_background(color: u8) -> Box<[u8; CANVAS_SIZE]> {
+    vec![color; CANVAS_SIZE]
+        .into_boxed_slice()
+        .try_into()
+        .unwrap()
+}
+
+/// Reads a default-canvas image: CANVAS_SIZE raw color indices, row-major.
+/// These pixels are the background, not user placements, so they don't
+/// count as writes.
+pub fn load_background_image(path: &Path) -> std::io::Result<Box<[u8; CANVAS_SIZE]>> {
+    let data = std::fs::read(path)?;
+    let len = data.len();
+    data.into_boxed_slice().try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{}: expected {} bytes ({}x{} color indices), got {}",
+                path.display(),
+                CANVAS_SIZE,
+                CANVAS_WIDTH,
+                CANVAS_HEIGHT,
+                len
+            ),
+        )
+    })
+}
+
+/// Appends `[u32 LE index, u8 color]` for every pixel of `new` that differs
+/// from `old`.
+pub fn diff_canvas(new: &[u8], old: &[u8], out: &mut Vec<u8>) {
+    diff_canvas_capped(new, old, usize::MAX, out);
+}
+
+/// [`diff_canvas`], giving up once the diff would grow past `cap_bytes`:
+/// returns false with `out` left empty, so a mass change never costs more
+/// than the cap to find out.
+pub fn diff_canvas_capped(new: &[u8], old: &[u8], cap_bytes: usize, out: &mut Vec<u8>) -> bool {
+    for (i, (&new_pixel, &old_pixel)) in new.iter().zip(old).enumerate() {
+        if old_pixel != new_pixel {
+            if out.len() + DIFF_ENTRY_SIZE > cap_bytes {
+                out.clear();
+                return false;
+            }
+            out.extend_from_slice(&(i as u32).to_le_bytes());
+            out.push(new_pixel);
+        }
+    }
+    true
+}
+
+/// How a [`diff_snapshots`] came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotDiff {
+    Built,
+    /// `old_seq` was recycled before or during the comparison.
+    Recycled,
+    /// The diff would have been larger than the cap.
+    OverCap,
+}
+
+/// Diffs published snapshot `new_seq` against the older `old_seq` straight
+/// from the pool; both are immutable while retained, so nothing is copied.
+/// Unless the diff was built, `out` is left empty and the caller must fall
+/// back to a full broadcast.
+pub fn diff_snapshots(
+    old_seq: u64,
+    new_seq: u64,
+    cap_bytes: usize,
+    out: &mut Vec<u8>,
+) -> SnapshotDiff {
+    if !snapshot_retained(old_seq, new_seq) {
+        return SnapshotDiff::Recycled;
+    }
+    let within_cap = unsafe {
+        let old = &BUFFER_POOL[snapshot_slot(old_seq)].data;
+        let new = &BUFFER_POOL[snapshot_slot(new_seq)].data;
+        diff_canvas_capped(new, old, cap_bytes, out)
+    };
+    // Seqlock-style re-check: if the master lapped us mid-read, the old slot
+    // may have been half rewritten.
+    std::sync::atomic::fence(Ordering::Acquire);
+    if !snapshot_retained(old_seq, PUBLISH_SEQ.load(Ordering::Relaxed)) {
+        out.clear();
+        return SnapshotDiff::Recycled;
+    }
+    if !within_cap {
+        return SnapshotDiff::OverCap;
+    }
+    SnapshotDiff::Built
+}
+
+/// Diff that brings a client from published snapshot `since` to the newest
+/// one, for CATCHUP on the control stream. The pool is the history: any
+/// snapshot still retained can be diffed against. Returns the newest
+/// sequence number, or None (with `out` left empty) when `since` has been
+/// recycled or was never published.
+pub fn catchup_diff(since: u64, out: &mut Vec<u8>) -> Option<u64> {
+    let newest = PUBLISH_SEQ.load(Ordering::Acquire);
+    if since > newest || diff_snapshots(since, newest, usize::MAX, out) != SnapshotDiff::Built {
+        return None;
+    }
+    Some(newest)
+}
+
+/// Resolves a TILE_CHECKSUMS / TILE_REFRESH snapshot number against the
+/// newest published one. None if it was never published or is recycled.
+fn retained_seq(seq: u64) -> Option<u64> {
+    let newest = PUBLISH_SEQ.load(Ordering::Acquire);
+    let seq = if seq == NEWEST_SNAPSHOT { newest } else { seq };
+    (seq <= newest && snapshot_retained(seq, newest)).then_some(seq)
+}
+
+/// Whether `seq` survived a read of its slot that began when it was retained
+/// (see `diff_snapshots`).
+fn still_retained(seq: u64) -> bool {
+    std::sync::atomic::fence(Ordering::Acquire);
+    snapshot_retained(seq, PUBLISH_SEQ.load(Ordering::Relaxed))
+}
+
+/// Tile checksums of published snapshot `seq` (or the newest), for
+/// TILE_CHECKSUMS on the control stream. Returns the snapshot's number with
+/// them, or None once it has been recycled.
+pub fn snapshot_tile_checksums(seq: u64) -> Option<(u64, [u32; TILE_CHECKSUM_COUNT])> {
+    let seq = retained_seq(seq)?;
+    let checksums = unsafe { TILE_CHECKSUMS[snapshot_slot(seq)] };
+    still_retained(seq).then_some((seq, checksums))
+}
+
+/// Run-length encodes the pixels of the `mask` tiles of published snapshot
+/// `seq` (or the newest) into `out`, tile by tile, for TILE_REFRESH. Same
+/// tiles and order as the reply's bitmap ([`tile_bitmap`]). Returns the
+/// snapshot's number, or None (with `out` left as it was) once it has been
+/// recycled.
+pub fn snapshot_tiles(seq: u64, mask: &TileMask, out: &mut Vec<u8>) -> Option<u64> {
+    let seq = retained_seq(seq)?;
+    let pixels = tile_pixels(unsafe { &BUFFER_POOL[snapshot_slot(seq)].data }, mask);
+    if !still_retained(seq) {
+        return None;
+    }
+    let start = out.len();
+    out.resize(start + rle_max_len(pixels.len()), 0);
+    let len = rle_encode(&pixels, &mut out[start..]);
+    out.truncate(start + len);
+    Some(seq)
+}
+
+/// The pixels of the `mask` tiles of `snapshot`, tile by tile, each tile
+/// row by row.
+pub fn tile_pixels(snapshot: &[u8], mask: &TileMask) -> Vec<u8> {
+    let mut pixels = Vec::new();
+    for tile in (0..TILE_COUNT).filter(|&t| mask[t / 64] & (1 << (t % 64)) != 0) {
+        let (x0, y0, w, h) = tile_bounds(tile);
+        for y in y0..y0 + h {
+            pixels.extend_from_slice(&snapshot[y * CANVAS_WIDTH + x0..][..w]);
+        }
+    }
+    pixels
+}
+
+/// Runs `read` over the pixels of the newest published snapshot, for
+/// readers off the hot path (the dashboard thumbnail). Returns the
+/// snapshot's number with what `read` made of it, or None if the slot was
+/// recycled while `read` ran.
+pub fn read_newest_snapshot<R>(read: impl FnOnce(&[u8]) -> R) -> Option<(u64, R)> {
+    let seq = retained_seq(NEWEST_SNAPSHOT)?;
+    let result = read(unsafe { &BUFFER_POOL[snapshot_slot(seq)].data });
+    still_retained(seq).then_some((seq, result))
+}
+
+/// Copies one published pool slot into another (used to carry over tiles that
+/// are not part of the next snapshot's budget), checksums included.
+pub fn copy_pool_slot(src_index: usize, dst_index: usize) {
+    if src_index == dst_index {
+        return;
+    }
+    unsafe {
+        let src = BUFFER_POOL[src_index].data.as_ptr();
+        let dst = BUFFER_POOL[dst_index].data.as_mut_ptr();
+        std::ptr::copy_nonoverlapping(src, dst, CANVAS_SIZE);
+        TILE_CHECKSUMS[dst_index] = TILE_CHECKSUMS[src_index];
+    }
+}
+
+#[inline(always)]
+pub fn tile_index(x: usize, y: usize) -> usize {
+    (y / TILE_SIZE) * TILES_X + x / TILE_SIZE
+}
+
+/// Returns (x0, y0, width, height) of a tile, clipped to the canvas edge.
+pub fn tile_bounds(tile: usize) -> (usize, usize, usize, usize) {
+    let x0 = (tile % TILES_X) * TILE_SIZE;
+    let y0 = (tile / TILES_X) * TILE_SIZE;
+    let w = TILE_SIZE.min(CANVAS_WIDTH - x0);
+    let h = TILE_SIZE.min(CANVAS_HEIGHT - y0);
+    (x0, y0, w, h)
+}
+
+// SUBSCRIBE bitmaps are built by clients against the protocol's tile grid.
+const _: () = assert!(
+    TILE_SIZE == SUBSCRIBE_TILE_SIZE as usize
+        && TILES_X == SUBSCRIBE_TILES_X as usize
+        && TILE_COUNT <= SUBSCRIBE_BITMAP_LEN * 8
+        && CANVAS_WIDTH <= u16::MAX as usize
+        && CANVAS_HEIGHT <= u16::MAX as usize
+);
+
+/// Tiles a connection receives diffs for, one bit per tile.
+pub type TileMask = [u64; TILE_BITMAP_LEN];
+
+/// Every tile; what a connection gets until it subscribes.
+pub const ALL_TILES: TileMask = {
+    let mut mask = [0u64; TILE_BITMAP_LEN];
+    let mut tile = 0;
+    while tile < TILE_COUNT {
+        mask[tile / 64] |= 1 << (tile % 64);
+        tile += 1;
+    }
+    mask
+};
+
+/// Converts a SUBSCRIBE argument to a mask; rectangles cover every tile
+/// they touch. Bits past TILE_COUNT are ignored.
+pub fn tile_mask(subscription: &Subscription) -> TileMask {
+    let bitmap = match subscription {
+        Subscription::All => return ALL_TILES,
+        Subscription::Tiles(bitmap) => *bitmap,
+        Subscription::Rect(rect) => {
+            let mut bitmap = [0u8; SUBSCRIBE_BITMAP_LEN];
+            rect.add_tiles(&mut bitmap);
+            bitmap
+        }
+    };
+    let mut mask = [0u64; TILE_BITMAP_LEN];
+    for (i, word) in mask.iter_mut().enumerate() {
+        let mut bytes = [0u8; 8];
+        let src =
+            &bitmap[(i * 8).min(SUBSCRIBE_BITMAP_LEN)..((i + 1) * 8).min(SUBSCRIBE_BITMAP_LEN)];
+        bytes[..src.len()].copy_from_slice(src);
+        *word = u64::from_le_bytes(bytes) & ALL_TILES[i];
+    }
+    mask
+}
+
+/// The SUBSCRIBE-style bitmap of a mask.
+pub fn tile_bitmap(mask: &TileMask) -> [u8; SUBSCRIBE_BITMAP_LEN] {
+    let mut bitmap = [0u8; SUBSCRIBE_BITMAP_LEN];
+    for tile in (0..TILE_COUNT).filter(|&t| mask[t / 64] & (1 << (t % 64)) != 0) {
+        bitmap[tile / 8] |= 1 << (tile % 8);
+    }
+    bitmap
+}
+
+/// Appends the entries of `diff` (as built by [`diff_canvas`]) whose pixel
+/// lies in a tile of `mask`.
+pub fn filter_diff(diff: &[u8], mask: &TileMask, out: &mut Vec<u8>) {
+    for entry in diff.chunks_exact(DIFF_ENTRY_SIZE) {
+        let index = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+        let tile = tile_index(index % CANVAS_WIDTH, index / CANVAS_WIDTH);
+        if mask[tile / 64] & (1 << (tile % 64)) != 0 {
+            out.extend_from_slice(entry);
+        }
+    }
+}
+
+/// Tiles changed on the master canvas but not yet published to the pool,
+/// with a per-tile write count used to estimate the diff each will cost.
+pub struct DirtyTiles {
+    bits: [u64; TILE_BITMAP_LEN],
+    pending_writes: [u16; TILE_COUNT],
+    dirty_count: usize,
+    cursor: usize,
+}
+
+impl Default for DirtyTiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DirtyTiles {
+    pub fn new() -> Self {
+        Self {
+            bits: [0; TILE_BITMAP_LEN],
+            pending_writes: [0; TILE_COUNT],
+            dirty_count: 0,
+            cursor: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn mark(&mut self, tile: usize) {
+        let mask = 1u64 << (tile & 63);
+        let word = &mut self.bits[tile >> 6];
+        if *word & mask == 0 {
+            *word |= mask;
+            self.dirty_count += 1;
+        }
+        self.pending_writes[tile] = self.pending_writes[tile].saturating_add(1);
+    }
+
+    #[inline(always)]
+    pub fn is_dirty(&self, tile: usize) -> bool {
+        self.bits[tile >> 6] & (1u64 << (tile & 63)) != 0
+    }
+
+    /// Number of tiles still waiting to be published.
+    pub fn len(&self) -> usize {
+        self.dirty_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirty_count == 0
+    }
+
+    /// Upper bound on the diff bytes workers will emit for this tile.
+    #[inline(always)]
+    pub fn estimated_diff_bytes(&self, tile: usize) -> usize {
+        (self.pending_writes[tile] as usize).min(TILE_SIZE * TILE_SIZE) * DIFF_ENTRY_SIZE
+    }
+
+    /// Hands dirty tiles to `publish` in round-robin order starting at the
+    /// cursor until `budget_bytes` of estimated diff is spent (0 = unlimited).
+    /// At least one tile is always published so the backlog keeps draining.
+    pub fn drain_budgeted(&mut self, budget_bytes: usize, mut publish: impl FnMut(usize)) {
+        let mut spent = 0;
+        for step in 0..TILE_COUNT {
+            if self.dirty_count == 0 {
+                return;
+            }
+            let tile = (self.cursor + step) % TILE_COUNT;
+            if !self.is_dirty(tile) {
+                continue;
+            }
+            let cost = self.estimated_diff_bytes(tile);
+            if budget_bytes != 0 && spent > 0 && spent + cost > budget_bytes {
+                self.cursor = tile;
+                return;
+            }
+            publish(tile);
+            self.bits[tile >> 6] &= !(1u64 << (tile & 63));
+            self.pending_writes[tile] = 0;
+            self.dirty_count -= 1;
+            spent += cost;
+            self.cursor = (tile + 1) % TILE_COUNT;
         }
     }
 }
@@ -106,4 +566,248 @@ mod tests {
             assert_eq!(buffer.data[0], 0); // other pixels are unaffected
         }
     }
+
+    #[test]
+    fn test_filter_diff_by_tile_mask() {
+        use protocol::control::PixelRect;
+
+        // One pixel in each of three tiles: 0, 1 and the last one.
+        let mut old = vec![0u8; CANVAS_SIZE];
+        let mut new = old.clone();
+        for (x, y) in [(3, 3), (70, 10), (999, 999)] {
+            new[y * CANVAS_WIDTH + x] = 9;
+        }
+        let mut diff = Vec::new();
+        diff_canvas(&new, &old, &mut diff);
+        old.clear();
+
+        let kept = |mask: &TileMask| {
+            let mut out = Vec::new();
+            filter_diff(&diff, mask, &mut out);
+            out.chunks(DIFF_ENTRY_SIZE)
+                .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]) as usize)
+                .map(|i| (i % CANVAS_WIDTH, i / CANVAS_WIDTH))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(&ALL_TILES), vec![(3, 3), (70, 10), (999, 999)]);
+        assert_eq!(kept(&[0; TILE_BITMAP_LEN]), vec![]);
+
+        // Disjoint viewports: the top-left corner and the bottom-right one.
+        let mut bitmap = [0u8; SUBSCRIBE_BITMAP_LEN];
+        PixelRect {
+            x0: 0,
+            y0: 0,
+            x1: 10,
+            y1: 10,
+        }
+        .add_tiles(&mut bitmap);
+        PixelRect {
+            x0: 990,
+            y0: 990,
+            x1: 1000,
+            y1: 1000,
+        }
+        .add_tiles(&mut bitmap);
+        assert_eq!(
+            kept(&tile_mask(&Subscription::Tiles(bitmap))),
+            vec![(3, 3), (999, 999)]
+        );
+
+        // A rectangle is widened to whole tiles.
+        let rect = PixelRect {
+            x0: 100,
+            y0: 0,
+            x1: 101,
+            y1: 1,
+        };
+        assert_eq!(kept(&tile_mask(&Subscription::Rect(rect))), vec![(70, 10)]);
+
+        assert_eq!(tile_mask(&Subscription::All), ALL_TILES);
+        assert_eq!(
+            tile_mask(&Subscription::Tiles([0xff; SUBSCRIBE_BITMAP_LEN])),
+            ALL_TILES
+        );
+    }
+
+    #[test]
+    fn test_diff_cap_bounds_a_mass_change() {
+        const CAP: usize = 64 * 1024;
+        let old = vec![0u8; CANVAS_SIZE];
+        let mut new = vec![3u8; CANVAS_SIZE];
+        let mut out = Vec::new();
+        assert!(!diff_canvas_capped(&new, &old, CAP, &mut out));
+        assert!(out.is_empty());
+        assert!(out.capacity() <= 2 * CAP);
+
+        // Under the cap the diff is whole, and applying it converges.
+        new.copy_from_slice(&old);
+        for i in (0..CANVAS_SIZE).step_by(CANVAS_SIZE / 100) {
+            new[i] = 4;
+        }
+        assert!(diff_canvas_capped(&new, &old, CAP, &mut out));
+        assert_eq!(out.len(), 100 * DIFF_ENTRY_SIZE);
+        let mut client = old.clone();
+        for entry in out.chunks_exact(DIFF_ENTRY_SIZE) {
+            let index = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            client[index as usize] = entry[4];
+        }
+        assert!(client == new);
+    }
+
+    #[test]
+    fn test_diff_snapshots_falls_back_when_recycled() {
+        // Slots 2 and 3; the other tests only touch 0 and 1.
+        const POOL: u64 = CANVAS_BUFFER_POOL_SIZE as u64;
+        PUBLISH_SEQ.store(3, Ordering::Release);
+        unsafe {
+            BUFFER_POOL[2].data[7] = 0;
+            BUFFER_POOL[3].data[7] = 5;
+        }
+        let mut out = Vec::new();
+        assert_eq!(
+            diff_snapshots(2, 3, usize::MAX, &mut out),
+            SnapshotDiff::Built
+        );
+        assert_eq!(out, [7, 0, 0, 0, 5]);
+        out.clear();
+        assert_eq!(
+            diff_snapshots(2, 3, DIFF_ENTRY_SIZE - 1, &mut out),
+            SnapshotDiff::OverCap
+        );
+        assert!(out.is_empty());
+
+        // Slot 2 survives until the master starts preparing seq 2 + POOL.
+        assert!(snapshot_retained(2, POOL));
+        assert!(!snapshot_retained(2, POOL + 1));
+
+        // The master lapped the worker while it was reading.
+        PUBLISH_SEQ.store(POOL + 1, Ordering::Release);
+        assert_eq!(
+            diff_snapshots(2, 3, usize::MAX, &mut out),
+            SnapshotDiff::Recycled
+        );
+        assert!(out.is_empty());
+
+        // The worker itself fell too far behind.
+        assert_eq!(
+            diff_snapshots(2, POOL + 1, usize::MAX, &mut out),
+            SnapshotDiff::Recycled
+        );
+        assert!(out.is_empty());
+
+        // Catch-up reads the same history.
+        PUBLISH_SEQ.store(3, Ordering::Release);
+        assert_eq!(catchup_diff(2, &mut out), Some(3));
+        assert_eq!(out, [7, 0, 0, 0, 5]);
+        out.clear();
+        assert_eq!(catchup_diff(3, &mut out), Some(3));
+        assert!(out.is_empty());
+        assert_eq!(catchup_diff(4, &mut out), None);
+        PUBLISH_SEQ.store(POOL + 2, Ordering::Release);
+        assert_eq!(catchup_diff(2, &mut out), None);
+        assert!(out.is_empty());
+
+        // So do tile checksums and refreshes.
+        PUBLISH_SEQ.store(3, Ordering::Release);
+        unsafe { TILE_CHECKSUMS[3][5] = 77 };
+        let (seq, checksums) = snapshot_tile_checksums(NEWEST_SNAPSHOT).unwrap();
+        assert_eq!((seq, checksums[5]), (3, 77));
+        assert_eq!(snapshot_tile_checksums(2).map(|(seq, _)| seq), Some(2));
+        assert_eq!(snapshot_tile_checksums(4), None);
+
+        let mut only_first = [0; TILE_BITMAP_LEN];
+        only_first[0] = 1;
+        assert_eq!(snapshot_tiles(3, &only_first, &mut out), Some(3));
+        let mut tile = vec![0; TILE_SIZE * TILE_SIZE];
+        assert_eq!(
+            protocol::rle::rle_decode(&out, &mut tile),
+            Ok(TILE_SIZE * TILE_SIZE)
+        );
+        assert_eq!(tile[7], 5);
+        out.clear();
+        PUBLISH_SEQ.store(POOL + 2, Ordering::Release);
+        assert_eq!(snapshot_tiles(2, &only_first, &mut out), None);
+        assert_eq!(
+            snapshot_tile_checksums(NEWEST_SNAPSHOT).map(|(seq, _)| seq),
+            Some(POOL + 2)
+        );
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_background_image() {
+        let path = std::env::temp_dir().join(format!("canvas-bg-{}.raw", std::process::id()));
+        let mut image = vec![3u8; CANVAS_SIZE];
+        image[CANVAS_WIDTH + 2] = 8;
+        std::fs::write(&path, &image).unwrap();
+
+        let config = ServerConfig {
+            background_image: Some(path.clone()),
+            ..Default::default()
+        };
+        let canvas = Canvas::load_or_new(&config).unwrap();
+        assert_eq!(canvas.background_at(2, 1), 8);
+        assert_eq!(canvas.swap_pixel(2, 1, 5), Some(8));
+        assert_eq!(canvas.background_at(2, 1), 8);
+
+        std::fs::write(&path, &image[..10]).unwrap();
+        assert!(Canvas::load_or_new(&config).is_err());
+        let _ = std::fs::remove_file(&path);
+
+        let solid = Canvas::load_or_new(&ServerConfig {
+            background: 4,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(solid.swap_pixel(0, 0, 1), Some(4));
+    }
+
+    #[test]
+    fn test_swap_pixel_returns_previous() {
+        let canvas = Canvas::new();
+        assert_eq!(canvas.swap_pixel(3, 4, 7), Some(0));
+        assert_eq!(canvas.swap_pixel(3, 4, 9), Some(7));
+        assert_eq!(canvas.swap_pixel(CANVAS_WIDTH, 0, 1), None);
+    }
+
+    #[test]
+    fn test_dirty_tiles_budget_drains_everything() {
+        let mut dirty = DirtyTiles::new();
+        // Every tile fully rewritten: ~20 KB estimated diff each.
+        for tile in 0..TILE_COUNT {
+            for _ in 0..TILE_SIZE * TILE_SIZE {
+                dirty.mark(tile);
+            }
+        }
+        let budget = 64 * 1024;
+        let per_tile = TILE_SIZE * TILE_SIZE * DIFF_ENTRY_SIZE;
+        let max_intervals = TILE_COUNT.div_ceil(budget / per_tile);
+
+        let mut published = vec![false; TILE_COUNT];
+        let mut intervals = 0;
+        while !dirty.is_empty() {
+            let mut spent = 0;
+            dirty.drain_budgeted(budget, |tile| {
+                assert!(!published[tile]);
+                published[tile] = true;
+                spent += per_tile;
+            });
+            assert!(spent <= budget);
+            intervals += 1;
+        }
+        assert!(published.iter().all(|&p| p));
+        assert!(intervals <= max_intervals);
+    }
+
+    #[test]
+    fn test_dirty_tiles_oversized_tile_still_progresses() {
+        let mut dirty = DirtyTiles::new();
+        for _ in 0..TILE_SIZE * TILE_SIZE {
+            dirty.mark(5);
+        }
+        let mut published = Vec::new();
+        dirty.drain_budgeted(1, |tile| published.push(tile));
+        assert_eq!(published, vec![5]);
+        assert!(dirty.is_empty());
+    }
 }
@@ -0,0 +1,528 @@
+use crate::bandwidth::Limits;
+use crate::conn_memory::MemoryLimits;
+use crate::const_settings::{
+    ADMIN_DRAIN_RATIO, BROADCAST_INTERVAL_MAX_MS, DASHBOARD_DEFAULT_BIND, DGRAM_DRAIN_BUDGET,
+    DIFF_CAP_BYTES, FAST_DIFF_INTERVAL_MS, FEC_BLOCK_CHUNKS, FULL_BROADCAST_INTERVAL_MS,
+    FULL_BROADCAST_MAX_BYTES, MAX_DGRAMS_PER_RECV, QLOG_SAMPLE_ONE_IN, QUIC_MAX_IDLE_TIMEOUT_MS,
+    RESERVE_GRACE_MS, RETRY_TOKEN_TTL_MS, SERVER_PORT, SNAPSHOT_DIFF_BUDGET_BYTES,
+    TIER1_PER_WORKER, TIMELINE_WINDOW_MINUTES, TIMING_WHEEL_TICKS,
+};
+use crate::half_open::HalfOpenLimits;
+use crate::instance::parse_cpu_list;
+use crate::junk::JunkLimits;
+use crate::sandbox::RunAs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Runtime configuration: compile-time defaults from const_settings.rs,
+/// overridden by command-line flags.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Number of worker threads; None = one per core minus the master.
+    pub workers: Option<usize>,
+    /// Estimated diff bytes one published snapshot may carry (0 = unlimited).
+    pub snapshot_diff_budget_bytes: usize,
+    /// Stretch the broadcast interval while workers overrun their loop budget.
+    pub adaptive_broadcast: bool,
+    /// Ceiling for the adaptive broadcast interval (ms).
+    pub max_broadcast_interval_ms: u64,
+    /// Scopes relative file paths, the lock file and shared names when several
+    /// servers run on one host.
+    pub instance_name: Option<String>,
+    /// Explicit cores to pin to: first is the master, the rest are workers.
+    pub cpu_list: Option<Vec<usize>>,
+    /// Refuse to start (or keep running) with any thread unpinned.
+    pub strict_affinity: bool,
+    /// Directory where running instances advertise their port and cores.
+    pub lock_dir: PathBuf,
+    /// TLS certificate and key every worker loads (`--cert`, `--key`).
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Neither path was configured, or `--san` was given: a missing pair
+    /// may be replaced by a generated self-signed one.
+    pub generate_cert: bool,
+    /// Names (DNS or IP) a generated certificate is issued for, from
+    /// `--san a,b`; empty = localhost.
+    pub san: Vec<String>,
+    /// HTTP dashboard address; None = disabled.
+    pub dashboard_bind: Option<SocketAddr>,
+    /// Color every pixel starts with.
+    pub background: u8,
+    /// Raw CANVAS_SIZE color-index file used as the starting canvas instead of
+    /// a solid `background`.
+    pub background_image: Option<PathBuf>,
+    /// Minutes of activity history served by STATS_TIMELINE.
+    pub timeline_minutes: usize,
+    /// Log the fate of one pixel in N from worker to snapshot; 0 = off.
+    pub trace_pixels: u32,
+    /// In-process bot users painting over loopback (`--demo <n>`); 0 = off.
+    pub demo_bots: usize,
+    /// Broadcast bandwidth caps at startup (`--conn-kbps`, `--worker-kbps`,
+    /// `--full-sync-kbps`); 0 = unlimited. Adjustable while running.
+    pub bandwidth: Limits,
+    /// zstd dictionary to compress diffs with for clients that negotiated
+    /// ZSTD; one of protocol/dicts/. None = diffs go out uncompressed.
+    pub diff_dict: Option<PathBuf>,
+    /// Per-worker connection memory limits (`--conn-mem-soft-mb`,
+    /// `--conn-mem-hard-mb`); 0 = none.
+    pub conn_memory: MemoryLimits,
+    /// Per-worker handshake deadline and the half-open count that tightens
+    /// admission (`--handshake-deadline-ms`, `--half-open-threshold`); 0 =
+    /// none.
+    pub half_open: HalfOpenLimits,
+    /// Deny list for sources of junk traffic (`--strict-junk`,
+    /// `--junk-threshold`, `--junk-deny-ttl-ms`, `--junk-deny-max`); off
+    /// unless strict, junk is counted either way.
+    pub junk: JunkLimits,
+    /// Answer token-less Initials with a Retry and only accept a connection
+    /// once the client echoes a valid token (`--quic-retry`, retry.rs).
+    pub quic_retry: bool,
+    /// How long a Retry token stays good (ms).
+    pub retry_token_ttl_ms: u64,
+    /// QUIC idle timeout offered to peers (ms); 0 = none.
+    pub idle_timeout_ms: u64,
+    /// Seconds between a connection's accepted pixels at normal load;
+    /// stretched under load (rate_contract.rs), at most the wheel's horizon.
+    pub cooldown_secs: u32,
+    /// Tokens that make a connection tier 1 (TIER control op), one per
+    /// line. None = no tiers, and no micro-diffs.
+    pub tier1_tokens: Option<PathBuf>,
+    /// Tier-1 seats per worker.
+    pub tier1_per_worker: usize,
+    /// How often the master cuts micro-diffs for tier-1 connections (ms).
+    pub fast_diff_ms: u64,
+    /// Full-broadcast chunks per parity chunk for FEC connections; 0 = FEC
+    /// is not offered.
+    pub fec_k: usize,
+    /// How long a closed connection's user id is held for a reconnect
+    /// (ms); 0 = freed right away.
+    pub reserve_grace_ms: u64,
+    /// Interval between full broadcasts, per worker (ms); 0 = only when a
+    /// diff cannot be built.
+    pub full_broadcast_ms: u64,
+    /// RLE size (bytes) of a snapshot above which full broadcasts are
+    /// suspended; 0 = no limit.
+    pub full_broadcast_max_bytes: usize,
+    /// Size (bytes) past which a worker abandons a diff broadcast for a full
+    /// one; 0 = no cap.
+    pub diff_cap_bytes: usize,
+    /// Admin pixels the master applies per loop iteration, as a multiple
+    /// of what it drains from one worker ring (admin_writes.rs).
+    pub admin_drain_ratio: usize,
+    /// Datagrams read off one connection per packet, at most
+    /// MAX_DGRAMS_PER_RECV; the rest wait for a backlog pass.
+    pub dgram_drain_cap: usize,
+    /// Datagrams a worker's backlog pass reads per loop iteration.
+    pub dgram_drain_budget: usize,
+    /// Pick each new connection's worker by client source port instead of
+    /// the kernel's hash, so load-test runs are reproducible (steering.rs).
+    pub steer_by_port: bool,
+    /// Receive long-header packets on a second socket per worker with
+    /// buffers of their own, so bursts cannot starve handshakes
+    /// (recv_lane.rs). Needs `steer_by_port`.
+    pub handshake_reserve: bool,
+    /// Where run-info.json (environment fingerprint plus this config) is
+    /// written at startup; None = not written.
+    pub metrics_dir: Option<PathBuf>,
+    /// Where sampled connections write their qlog traces; None = no traces
+    /// (qlog.rs).
+    pub qlog_dir: Option<PathBuf>,
+    /// Trace 1 in this many accepted connections, per worker.
+    pub qlog_sample: u64,
+    /// Samples per second of each worker's loop phase, served at
+    /// `/admin/profile`; 0 = off (profiler.rs).
+    pub profile_hz: u32,
+    /// User (and group) to switch to once sockets and rings are set up;
+    /// None = keep running as started (sandbox.rs).
+    pub run_as: Option<RunAs>,
+    /// With `run_as`, confine the server to its syscall allowlist;
+    /// `--no-sandbox` turns it off.
+    pub sandbox: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: SERVER_PORT,
+            workers: None,
+            snapshot_diff_budget_bytes: SNAPSHOT_DIFF_BUDGET_BYTES,
+            adaptive_broadcast: false,
+            max_broadcast_interval_ms: BROADCAST_INTERVAL_MAX_MS,
+            instance_name: None,
+            cpu_list: None,
+            strict_affinity: false,
+            lock_dir: std::env::temp_dir(),
+            cert_path: PathBuf::from("cert.crt"),
+            key_path: PathBuf::from("key.key"),
+            generate_cert: true,
+            san: Vec::new(),
+            dashboard_bind: None,
+            background: 0,
+            background_image: None,
+            timeline_minutes: TIMELINE_WINDOW_MINUTES,
+            trace_pixels: 0,
+            demo_bots: 0,
+            bandwidth: Limits::default(),
+            diff_dict: None,
+            conn_memory: MemoryLimits::default(),
+            half_open: HalfOpenLimits::default(),
+            junk: JunkLimits::default(),
+            quic_retry: false,
+            retry_token_ttl_ms: RETRY_TOKEN_TTL_MS,
+            idle_timeout_ms: QUIC_MAX_IDLE_TIMEOUT_MS,
+            cooldown_secs: TIMING_WHEEL_TICKS as u32,
+            tier1_tokens: None,
+            tier1_per_worker: TIER1_PER_WORKER,
+            fast_diff_ms: FAST_DIFF_INTERVAL_MS,
+            fec_k: FEC_BLOCK_CHUNKS,
+            reserve_grace_ms: RESERVE_GRACE_MS,
+            full_broadcast_ms: FULL_BROADCAST_INTERVAL_MS,
+            full_broadcast_max_bytes: FULL_BROADCAST_MAX_BYTES,
+            diff_cap_bytes: DIFF_CAP_BYTES,
+            admin_drain_ratio: ADMIN_DRAIN_RATIO,
+            dgram_drain_cap: MAX_DGRAMS_PER_RECV,
+            dgram_drain_budget: DGRAM_DRAIN_BUDGET,
+            steer_by_port: false,
+            handshake_reserve: false,
+            metrics_dir: None,
+            qlog_dir: None,
+            qlog_sample: QLOG_SAMPLE_ONE_IN,
+            profile_hz: 0,
+            run_as: None,
+            sandbox: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_args(args: &[String]) -> Self {
+        let defaults = Self::default();
+        let instance_name: Option<String> = parse_flag(args, &["--instance-name"]);
+        let cert_path: Option<PathBuf> = parse_flag(args, &["--cert"]);
+        let key_path: Option<PathBuf> = parse_flag(args, &["--key"]);
+        let san: Vec<String> = flag_value(args, &["--san"]).map_or(Vec::new(), |v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        let cpu_list = flag_value(args, &["--cpu-list"])
+            .map(|v| parse_cpu_list(v).unwrap_or_else(|e| panic!("Invalid --cpu-list: {}", e)));
+        Self {
+            port: parse_flag(args, &["-p", "--port"]).unwrap_or(defaults.port),
+            workers: parse_flag(args, &["-w", "--workers"]),
+            snapshot_diff_budget_bytes: parse_flag(args, &["--snapshot-diff-budget"])
+                .unwrap_or(defaults.snapshot_diff_budget_bytes),
+            adaptive_broadcast: has_flag(args, "--adaptive-broadcast"),
+            max_broadcast_interval_ms: parse_flag(args, &["--max-broadcast-interval-ms"])
+                .unwrap_or(defaults.max_broadcast_interval_ms),
+            generate_cert: (cert_path.is_none() && key_path.is_none()) || !san.is_empty(),
+            san,
+            cert_path: cert_path
+                .unwrap_or_else(|| instance_scoped(instance_name.as_deref(), &defaults.cert_path)),
+            key_path: key_path
+                .unwrap_or_else(|| instance_scoped(instance_name.as_deref(), &defaults.key_path)),
+            lock_dir: parse_flag(args, &["--lock-dir"]).unwrap_or(defaults.lock_dir),
+            dashboard_bind: parse_flag(args, &["--dashboard-bind"]).or_else(|| {
+                has_flag(args, "--dashboard").then(|| DASHBOARD_DEFAULT_BIND.parse().unwrap())
+            }),
+            background: parse_flag(args, &["--background"]).unwrap_or(defaults.background),
+            background_image: parse_flag(args, &["--background-image"]),
+            timeline_minutes: parse_flag(args, &["--timeline-minutes"])
+                .unwrap_or(defaults.timeline_minutes),
+            trace_pixels: parse_flag(args, &["--trace-pixels"]).unwrap_or(defaults.trace_pixels),
+            demo_bots: parse_flag(args, &["--demo"]).unwrap_or(defaults.demo_bots),
+            bandwidth: Limits {
+                conn_kbps: parse_flag(args, &["--conn-kbps"]).unwrap_or(0),
+                worker_kbps: parse_flag(args, &["--worker-kbps"]).unwrap_or(0),
+                full_sync_kbps: parse_flag(args, &["--full-sync-kbps"]).unwrap_or(0),
+            },
+            diff_dict: parse_flag(args, &["--diff-dict"]),
+            conn_memory: MemoryLimits {
+                soft_bytes: parse_flag::<u64>(args, &["--conn-mem-soft-mb"]).unwrap_or(0) << 20,
+                hard_bytes: parse_flag::<u64>(args, &["--conn-mem-hard-mb"]).unwrap_or(0) << 20,
+            },
+            half_open: HalfOpenLimits {
+                deadline_ms: parse_flag(args, &["--handshake-deadline-ms"])
+                    .unwrap_or(defaults.half_open.deadline_ms),
+                threshold: parse_flag(args, &["--half-open-threshold"])
+                    .unwrap_or(defaults.half_open.threshold),
+            },
+            junk: JunkLimits {
+                strict: has_flag(args, "--strict-junk"),
+                threshold: parse_flag(args, &["--junk-threshold"])
+                    .unwrap_or(defaults.junk.threshold),
+                deny_ttl_ms: parse_flag(args, &["--junk-deny-ttl-ms"])
+                    .unwrap_or(defaults.junk.deny_ttl_ms),
+                deny_max: parse_flag(args, &["--junk-deny-max"]).unwrap_or(defaults.junk.deny_max),
+            },
+            quic_retry: has_flag(args, "--quic-retry"),
+            retry_token_ttl_ms: parse_flag(args, &["--retry-token-ttl-ms"])
+                .unwrap_or(defaults.retry_token_ttl_ms),
+            idle_timeout_ms: parse_flag(args, &["--idle-timeout-ms"])
+                .unwrap_or(defaults.idle_timeout_ms),
+            cooldown_secs: parse_flag(args, &["--cooldown-secs"]).unwrap_or(defaults.cooldown_secs),
+            tier1_tokens: parse_flag(args, &["--tier1-tokens"]),
+            tier1_per_worker: parse_flag(args, &["--tier1-per-worker"])
+                .unwrap_or(defaults.tier1_per_worker),
+            fast_diff_ms: parse_flag(args, &["--fast-diff-ms"]).unwrap_or(defaults.fast_diff_ms),
+            fec_k: parse_flag(args, &["--fec-k"]).unwrap_or(defaults.fec_k),
+            reserve_grace_ms: parse_flag(args, &["--reserve-grace-ms"])
+                .unwrap_or(defaults.reserve_grace_ms),
+            full_broadcast_ms: parse_flag(args, &["--full-broadcast-ms"])
+                .unwrap_or(defaults.full_broadcast_ms),
+            full_broadcast_max_bytes: parse_flag(args, &["--full-broadcast-max-bytes"])
+                .unwrap_or(defaults.full_broadcast_max_bytes),
+            diff_cap_bytes: parse_flag(args, &["--diff-cap-bytes"])
+                .unwrap_or(defaults.diff_cap_bytes),
+            admin_drain_ratio: parse_flag(args, &["--admin-drain-ratio"])
+                .unwrap_or(defaults.admin_drain_ratio),
+            dgram_drain_cap: parse_flag(args, &["--dgram-drain-cap"])
+                .unwrap_or(defaults.dgram_drain_cap),
+            dgram_drain_budget: parse_flag(args, &["--dgram-drain-budget"])
+                .unwrap_or(defaults.dgram_drain_budget),
+            steer_by_port: has_flag(args, "--steer-by-port"),
+            handshake_reserve: has_flag(args, "--handshake-reserve"),
+            metrics_dir: parse_flag(args, &["--metrics-dir"]),
+            qlog_dir: parse_flag(args, &["--qlog-dir"]),
+            qlog_sample: parse_flag(args, &["--qlog-sample"]).unwrap_or(defaults.qlog_sample),
+            profile_hz: parse_flag(args, &["--profile-hz"]).unwrap_or(defaults.profile_hz),
+            run_as: parse_flag(args, &["--run-as"]),
+            sandbox: !has_flag(args, "--no-sandbox"),
+            instance_name,
+            cpu_list,
+            strict_affinity: has_flag(args, "--strict-affinity"),
+        }
+    }
+
+    /// Instance label used for the lock file; "default" when unnamed.
+    pub fn instance_label(&self) -> &str {
+        self.instance_name.as_deref().unwrap_or("default")
+    }
+
+    /// Prefixes a relative path with the instance directory.
+    pub fn instance_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        instance_scoped(self.instance_name.as_deref(), path.as_ref())
+    }
+
+    /// Namespaces a host-global identifier (shared memory, abstract sockets).
+    pub fn namespaced(&self, name: &str) -> String {
+        format!("canvas.{}.{}", self.instance_label(), name)
+    }
+}
+
+fn instance_scoped(instance_name: Option<&str>, path: &Path) -> PathBuf {
+    match instance_name {
+        Some(name) if path.is_relative() => Path::new(name).join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|a| a == name)
+}
+
+/// Value following the first occurrence of any of `names`.
+fn flag_value<'a>(args: &'a [String], names: &[&str]) -> Option<&'a str> {
+    args.iter()
+        .position(|a| names.contains(&a.as_str()))
+        .and_then(|pos| args.get(pos + 1))
+        .map(String::as_str)
+}
+
+fn parse_flag<T: FromStr>(args: &[String], names: &[&str]) -> Option<T> {
+    flag_value(args, names).map(|val| {
+        val.parse::<T>()
+            .unwrap_or_else(|_| panic!("Invalid value {:?} for {}", val, names.join("/")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        std::iter::once("server")
+            .chain(s.split_whitespace())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults_and_overrides() {
+        let cfg = ServerConfig::from_args(&args(""));
+        assert_eq!(cfg.port, SERVER_PORT);
+        assert_eq!(cfg.workers, None);
+        assert_eq!(cfg.snapshot_diff_budget_bytes, SNAPSHOT_DIFF_BUDGET_BYTES);
+        assert!(!cfg.adaptive_broadcast);
+        assert_eq!(cfg.dashboard_bind, None);
+        assert_eq!(cfg.background, 0);
+        assert_eq!(cfg.timeline_minutes, TIMELINE_WINDOW_MINUTES);
+        assert_eq!(cfg.trace_pixels, 0);
+
+        let cfg = ServerConfig::from_args(&args(
+            "-w 3 --snapshot-diff-budget 0 --adaptive-broadcast --max-broadcast-interval-ms 300",
+        ));
+        assert_eq!(cfg.workers, Some(3));
+        assert_eq!(cfg.snapshot_diff_budget_bytes, 0);
+        assert!(cfg.adaptive_broadcast);
+        assert_eq!(cfg.max_broadcast_interval_ms, 300);
+
+        let cfg = ServerConfig::from_args(&args("--background 31 --background-image bg.raw"));
+        assert_eq!(cfg.background, 31);
+        assert_eq!(cfg.background_image, Some(PathBuf::from("bg.raw")));
+
+        let cfg = ServerConfig::from_args(&args("--trace-pixels 1000"));
+        assert_eq!(cfg.trace_pixels, 1000);
+        assert_eq!(cfg.demo_bots, 0);
+
+        let cfg = ServerConfig::from_args(&args("--demo 12"));
+        assert_eq!(cfg.demo_bots, 12);
+        assert!(cfg.bandwidth.is_unlimited());
+
+        let cfg = ServerConfig::from_args(&args("--conn-kbps 500 --worker-kbps 20000"));
+        assert_eq!(cfg.bandwidth.conn_kbps, 500);
+        assert_eq!(cfg.bandwidth.worker_kbps, 20_000);
+        assert_eq!(cfg.bandwidth.full_sync_kbps, 0);
+        assert_eq!(cfg.diff_dict, None);
+
+        let cfg = ServerConfig::from_args(&args("--diff-dict protocol/dicts/diff-v1.zdict"));
+        assert_eq!(
+            cfg.diff_dict,
+            Some(PathBuf::from("protocol/dicts/diff-v1.zdict"))
+        );
+        assert_eq!(cfg.conn_memory, MemoryLimits::default());
+
+        let cfg = ServerConfig::from_args(&args("--conn-mem-soft-mb 1500 --conn-mem-hard-mb 2048"));
+        assert_eq!(cfg.conn_memory.soft_bytes, 1500 << 20);
+        assert_eq!(cfg.conn_memory.hard_bytes, 2 << 30);
+        assert_eq!(cfg.half_open, HalfOpenLimits::default());
+        let cfg = ServerConfig::from_args(&args(
+            "--handshake-deadline-ms 3000 --half-open-threshold 0",
+        ));
+        assert_eq!(cfg.half_open.deadline_ms, 3_000);
+        assert_eq!(cfg.half_open.threshold, 0);
+        assert_eq!(cfg.junk, JunkLimits::default());
+        assert!(!cfg.junk.strict);
+        let cfg = ServerConfig::from_args(&args(
+            "--strict-junk --junk-threshold 8 --junk-deny-ttl-ms 5000 --junk-deny-max 16",
+        ));
+        assert_eq!(
+            cfg.junk,
+            JunkLimits {
+                strict: true,
+                threshold: 8,
+                deny_ttl_ms: 5_000,
+                deny_max: 16,
+            }
+        );
+        assert!(!cfg.quic_retry);
+        assert_eq!(cfg.retry_token_ttl_ms, RETRY_TOKEN_TTL_MS);
+        let cfg = ServerConfig::from_args(&args("--quic-retry --retry-token-ttl-ms 3000"));
+        assert!(cfg.quic_retry);
+        assert_eq!(cfg.retry_token_ttl_ms, 3_000);
+        assert_eq!(cfg.idle_timeout_ms, QUIC_MAX_IDLE_TIMEOUT_MS);
+        let cfg = ServerConfig::from_args(&args("--idle-timeout-ms 5000"));
+        assert_eq!(cfg.idle_timeout_ms, 5_000);
+        assert_eq!(cfg.cooldown_secs, TIMING_WHEEL_TICKS as u32);
+        assert_eq!(
+            ServerConfig::from_args(&args("--cooldown-secs 60")).cooldown_secs,
+            60
+        );
+        assert_eq!(cfg.tier1_tokens, None);
+        assert_eq!(cfg.tier1_per_worker, TIER1_PER_WORKER);
+
+        let cfg = ServerConfig::from_args(&args(
+            "--tier1-tokens tiers.txt --tier1-per-worker 8 --fast-diff-ms 0",
+        ));
+        assert_eq!(cfg.tier1_tokens, Some(PathBuf::from("tiers.txt")));
+        assert_eq!(cfg.tier1_per_worker, 8);
+        assert_eq!(cfg.fast_diff_ms, 0);
+
+        assert_eq!(ServerConfig::from_args(&args("")).fec_k, FEC_BLOCK_CHUNKS);
+        let cfg = ServerConfig::from_args(&args("--fec-k 0 --metrics-dir /opt/canvas/metrics"));
+        assert_eq!(cfg.fec_k, 0);
+        assert_eq!(cfg.metrics_dir, Some(PathBuf::from("/opt/canvas/metrics")));
+        assert_eq!(cfg.qlog_dir, None);
+        assert_eq!(cfg.qlog_sample, QLOG_SAMPLE_ONE_IN);
+        let cfg = ServerConfig::from_args(&args("--qlog-dir /tmp/qlog --qlog-sample 1"));
+        assert_eq!(cfg.qlog_dir, Some(PathBuf::from("/tmp/qlog")));
+        assert_eq!(cfg.qlog_sample, 1);
+        assert_eq!(cfg.profile_hz, 0);
+        assert_eq!(
+            ServerConfig::from_args(&args("--profile-hz 97")).profile_hz,
+            97
+        );
+        assert_eq!(cfg.reserve_grace_ms, RESERVE_GRACE_MS);
+        let cfg = ServerConfig::from_args(&args("--reserve-grace-ms 30000"));
+        assert_eq!(cfg.reserve_grace_ms, 30_000);
+        assert!(!cfg.steer_by_port);
+        assert_eq!(cfg.full_broadcast_ms, FULL_BROADCAST_INTERVAL_MS);
+        let cfg = ServerConfig::from_args(&args("--full-broadcast-ms 2500"));
+        assert_eq!(cfg.full_broadcast_ms, 2_500);
+        assert_eq!(cfg.full_broadcast_max_bytes, FULL_BROADCAST_MAX_BYTES);
+        let cfg = ServerConfig::from_args(&args("--full-broadcast-max-bytes 0"));
+        assert_eq!(cfg.full_broadcast_max_bytes, 0);
+        assert_eq!(cfg.diff_cap_bytes, DIFF_CAP_BYTES);
+        let cfg = ServerConfig::from_args(&args("--diff-cap-bytes 0"));
+        assert_eq!(cfg.diff_cap_bytes, 0);
+        assert_eq!(cfg.admin_drain_ratio, ADMIN_DRAIN_RATIO);
+        let cfg = ServerConfig::from_args(&args("--admin-drain-ratio 4"));
+        assert_eq!(cfg.admin_drain_ratio, 4);
+        assert_eq!(cfg.dgram_drain_cap, MAX_DGRAMS_PER_RECV);
+        assert_eq!(cfg.dgram_drain_budget, DGRAM_DRAIN_BUDGET);
+        let cfg = ServerConfig::from_args(&args("--dgram-drain-cap 4 --dgram-drain-budget 64"));
+        assert_eq!((cfg.dgram_drain_cap, cfg.dgram_drain_budget), (4, 64));
+        assert!(ServerConfig::from_args(&args("--steer-by-port")).steer_by_port);
+        assert!(!cfg.handshake_reserve);
+        assert!(ServerConfig::from_args(&args("--handshake-reserve")).handshake_reserve);
+        assert_eq!(cfg.run_as, None);
+        assert!(cfg.sandbox);
+        let cfg = ServerConfig::from_args(&args("--run-as canvas:canvas --no-sandbox"));
+        assert_eq!(cfg.run_as, Some("canvas:canvas".parse().unwrap()));
+        assert!(!cfg.sandbox);
+    }
+
+    #[test]
+    fn test_instance_scoping() {
+        let cfg = ServerConfig::from_args(&args(""));
+        assert_eq!(cfg.cert_path, PathBuf::from("cert.crt"));
+        assert_eq!(cfg.namespaced("shm"), "canvas.default.shm");
+
+        let cfg = ServerConfig::from_args(&args("--instance-name b --port 4434 --cpu-list 4-6"));
+        assert_eq!(cfg.port, 4434);
+        assert_eq!(cfg.cpu_list, Some(vec![4, 5, 6]));
+        assert!(!cfg.strict_affinity);
+        assert!(ServerConfig::from_args(&args("--strict-affinity")).strict_affinity);
+        assert_eq!(cfg.cert_path, PathBuf::from("b/cert.crt"));
+        assert_eq!(cfg.key_path, PathBuf::from("b/key.key"));
+        assert_eq!(cfg.instance_path("/abs/x"), PathBuf::from("/abs/x"));
+        assert_eq!(cfg.namespaced("shm"), "canvas.b.shm");
+        assert!(cfg.generate_cert);
+
+        // Explicit paths are taken as given, even under an instance name.
+        let cfg = ServerConfig::from_args(&args("--instance-name b --cert tls/c.pem"));
+        assert_eq!(cfg.cert_path, PathBuf::from("tls/c.pem"));
+        assert_eq!(cfg.key_path, PathBuf::from("b/key.key"));
+        assert!(!cfg.generate_cert);
+        assert!(cfg.san.is_empty());
+
+        // Names to issue a certificate for allow generating one there.
+        let cfg = ServerConfig::from_args(&args(
+            "--cert /etc/canvas/c.pem --key /etc/canvas/k.pem --san canvas.example.com,203.0.113.7",
+        ));
+        assert_eq!(cfg.san, ["canvas.example.com", "203.0.113.7"]);
+        assert!(cfg.generate_cert);
+    }
+
+    #[test]
+    fn test_dashboard_bind() {
+        let cfg = ServerConfig::from_args(&args("--dashboard"));
+        assert_eq!(cfg.dashboard_bind, Some("127.0.0.1:8080".parse().unwrap()));
+        let cfg = ServerConfig::from_args(&args("--dashboard-bind 0.0.0.0:9000"));
+        assert_eq!(cfg.dashboard_bind, Some("0.0.0.0:9000".parse().unwrap()));
+    }
+}
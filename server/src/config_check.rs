@@ -0,0 +1,539 @@
+//! Cross-field checks on the configuration, run once before anything
+//! starts. Each flag is validated on its own where it is parsed; the rules
+//! here cover combinations that parse fine but make no sense together.
+//!
+//! A hard conflict is a setup that cannot do what the operator asked for
+//! (a protection that never engages, limits in the wrong order): the server
+//! refuses to start. A soft one is a setting that has no effect or works
+//! against another; the server starts, prints it, and lists it under
+//! `warnings` in the CONFIG dump so it shows up in post-incident analysis.
+
+use crate::config::ServerConfig;
+use crate::const_settings::{
+    BROADCAST_INTERVAL_MAX_MS, BROADCAST_INTERVAL_MS, FAST_DIFF_INTERVAL_MS,
+    MAX_CONNECTIONS_PER_WORKER, MAX_DGRAMS_PER_RECV, TIER1_PER_WORKER, TIMING_WHEEL_TICKS,
+};
+use protocol::fec::MAX_FEC_K;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Refuses to start.
+    Hard,
+    /// Starts with a warning.
+    Soft,
+}
+
+/// One broken rule, with the options involved and their values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub detail: String,
+    pub fix: &'static str,
+}
+
+impl Conflict {
+    pub fn is_hard(&self) -> bool {
+        self.severity == Severity::Hard
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Hard => "Configuration error",
+            Severity::Soft => "Configuration warning",
+        };
+        write!(
+            f,
+            "{} [{}]: {}\n  fix: {}",
+            label, self.rule, self.detail, self.fix
+        )
+    }
+}
+
+struct Rule {
+    name: &'static str,
+    severity: Severity,
+    /// What is wrong with `config`, naming the options; None if nothing.
+    check: fn(&ServerConfig) -> Option<String>,
+    fix: &'static str,
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        name: "conn-mem-order",
+        severity: Severity::Hard,
+        check: |c| {
+            let m = c.conn_memory;
+            (m.soft_bytes > 0 && m.hard_bytes > 0 && m.soft_bytes >= m.hard_bytes).then(|| {
+                format!(
+                    "--conn-mem-soft-mb {} is not below --conn-mem-hard-mb {}: handshakes would be reaped while new ones are still admitted",
+                    m.soft_bytes >> 20,
+                    m.hard_bytes >> 20
+                )
+            })
+        },
+        fix: "lower --conn-mem-soft-mb below --conn-mem-hard-mb, or set one of them to 0",
+    },
+    Rule {
+        name: "tier1-no-seats",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.tier1_tokens.is_some() && c.tier1_per_worker == 0).then(|| {
+                "--tier1-tokens is set but --tier1-per-worker is 0: every TIER request would be granted tier 0".to_string()
+            })
+        },
+        fix: "raise --tier1-per-worker, or drop --tier1-tokens",
+    },
+    Rule {
+        name: "strict-junk-inert",
+        severity: Severity::Hard,
+        check: |c| {
+            let j = c.junk;
+            (j.strict && (j.deny_max == 0 || j.deny_ttl_ms == 0)).then(|| {
+                format!(
+                    "--strict-junk with --junk-deny-max {} and --junk-deny-ttl-ms {} would never deny a source",
+                    j.deny_max, j.deny_ttl_ms
+                )
+            })
+        },
+        fix: "give --junk-deny-max and --junk-deny-ttl-ms non-zero values, or drop --strict-junk",
+    },
+    Rule {
+        name: "retry-ttl-zero",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.quic_retry && c.retry_token_ttl_ms == 0).then(|| {
+                "--quic-retry with --retry-token-ttl-ms 0: every token has expired by the time it comes back, so no client could connect".to_string()
+            })
+        },
+        fix: "give --retry-token-ttl-ms a few seconds, or drop --quic-retry",
+    },
+    Rule {
+        name: "fec-k-range",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.fec_k > MAX_FEC_K)
+                .then(|| format!("--fec-k {} is over the maximum of {}", c.fec_k, MAX_FEC_K))
+        },
+        fix: "pick a --fec-k of at most the maximum, or 0 to turn FEC off",
+    },
+    Rule {
+        name: "admin-drain-zero",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.admin_drain_ratio == 0).then(|| {
+                "--admin-drain-ratio 0: the master would never apply an admin clear".to_string()
+            })
+        },
+        fix: "give --admin-drain-ratio 1 or more",
+    },
+    Rule {
+        name: "dgram-drain-cap-range",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.dgram_drain_cap == 0 || c.dgram_drain_cap > MAX_DGRAMS_PER_RECV).then(|| {
+                format!(
+                    "--dgram-drain-cap {} is outside 1 to {}: a connection's datagrams would never be read, or overflow the scratch buffer",
+                    c.dgram_drain_cap, MAX_DGRAMS_PER_RECV
+                )
+            })
+        },
+        fix: "pick a --dgram-drain-cap from 1 to the maximum",
+    },
+    Rule {
+        name: "dgram-drain-budget-zero",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.dgram_drain_budget == 0).then(|| {
+                "--dgram-drain-budget 0: backlog passes would read nothing, and datagrams past a connection's first few would wait forever".to_string()
+            })
+        },
+        fix: "give --dgram-drain-budget at least --dgram-drain-cap",
+    },
+    Rule {
+        name: "adaptive-ceiling",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.adaptive_broadcast && c.max_broadcast_interval_ms <= BROADCAST_INTERVAL_MS).then(|| {
+                format!(
+                    "--adaptive-broadcast with --max-broadcast-interval-ms {} at or below the {} ms base interval never stretches",
+                    c.max_broadcast_interval_ms, BROADCAST_INTERVAL_MS
+                )
+            })
+        },
+        fix: "raise --max-broadcast-interval-ms above the base interval, or drop --adaptive-broadcast",
+    },
+    Rule {
+        name: "adaptive-ceiling-unused",
+        severity: Severity::Soft,
+        check: |c| {
+            (!c.adaptive_broadcast && c.max_broadcast_interval_ms != BROADCAST_INTERVAL_MAX_MS)
+                .then(|| {
+                    "--max-broadcast-interval-ms has no effect without --adaptive-broadcast"
+                        .to_string()
+                })
+        },
+        fix: "add --adaptive-broadcast, or drop --max-broadcast-interval-ms",
+    },
+    Rule {
+        name: "fec-without-full-broadcasts",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.fec_k > 0 && c.full_broadcast_ms == 0).then(|| {
+                format!(
+                    "--fec-k {} protects full broadcasts, but --full-broadcast-ms 0 only sends them when a diff cannot be built",
+                    c.fec_k
+                )
+            })
+        },
+        fix: "set --full-broadcast-ms, or --fec-k 0 to stop offering FEC",
+    },
+    Rule {
+        name: "full-broadcast-every-tick",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.full_broadcast_ms > 0 && c.full_broadcast_ms <= BROADCAST_INTERVAL_MS).then(|| {
+                format!(
+                    "--full-broadcast-ms {} is not above the {} ms broadcast interval: every broadcast is a full canvas",
+                    c.full_broadcast_ms, BROADCAST_INTERVAL_MS
+                )
+            })
+        },
+        fix: "raise --full-broadcast-ms well above the broadcast interval",
+    },
+    Rule {
+        name: "conn-kbps-over-worker",
+        severity: Severity::Soft,
+        check: |c| {
+            let b = c.bandwidth;
+            (b.worker_kbps > 0 && b.conn_kbps > b.worker_kbps).then(|| {
+                format!(
+                    "--conn-kbps {} is above --worker-kbps {}: one connection could take the worker's whole budget",
+                    b.conn_kbps, b.worker_kbps
+                )
+            })
+        },
+        fix: "lower --conn-kbps below --worker-kbps",
+    },
+    Rule {
+        name: "full-sync-kbps-over-worker",
+        severity: Severity::Soft,
+        check: |c| {
+            let b = c.bandwidth;
+            (b.worker_kbps > 0 && b.full_sync_kbps > b.worker_kbps).then(|| {
+                format!(
+                    "--full-sync-kbps {} is above --worker-kbps {} and never binds",
+                    b.full_sync_kbps, b.worker_kbps
+                )
+            })
+        },
+        fix: "lower --full-sync-kbps below --worker-kbps",
+    },
+    Rule {
+        name: "tier-settings-without-tokens",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.tier1_tokens.is_none()
+                && (c.tier1_per_worker != TIER1_PER_WORKER
+                    || c.fast_diff_ms != FAST_DIFF_INTERVAL_MS))
+                .then(|| {
+                    "--tier1-per-worker and --fast-diff-ms have no effect without --tier1-tokens"
+                        .to_string()
+                })
+        },
+        fix: "add --tier1-tokens, or drop the tier settings",
+    },
+    Rule {
+        name: "cpu-list-shared-cores",
+        severity: Severity::Soft,
+        check: |c| match (&c.cpu_list, c.workers) {
+            (Some(cores), Some(workers)) if workers + 1 > cores.len() => Some(format!(
+                "--workers {} needs {} cores with the master, --cpu-list has {}: workers share cores, one with the master",
+                workers,
+                workers + 1,
+                cores.len()
+            )),
+            _ => None,
+        },
+        fix: "list one more core than --workers in --cpu-list, or lower --workers",
+    },
+    Rule {
+        name: "half-open-threshold-unreachable",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.half_open.threshold > MAX_CONNECTIONS_PER_WORKER).then(|| {
+                format!(
+                    "--half-open-threshold {} is above the {} connections a worker holds: admission never tightens",
+                    c.half_open.threshold, MAX_CONNECTIONS_PER_WORKER
+                )
+            })
+        },
+        fix: "lower --half-open-threshold, or 0 to say it is off",
+    },
+    Rule {
+        name: "idle-timeout-off",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.idle_timeout_ms == 0).then(|| {
+                "--idle-timeout-ms 0: connections of clients that vanish keep their user ids until quiche gives up on them".to_string()
+            })
+        },
+        fix: "set --idle-timeout-ms, e.g. 30000",
+    },
+    Rule {
+        name: "cooldown-range",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.cooldown_secs == 0 || c.cooldown_secs as usize > TIMING_WHEEL_TICKS).then(|| {
+                format!(
+                    "--cooldown-secs {} is outside 1..={}: the timing wheel cannot hold it",
+                    c.cooldown_secs, TIMING_WHEEL_TICKS
+                )
+            })
+        },
+        fix: "set --cooldown-secs between 1 and the wheel's horizon",
+    },
+    Rule {
+        name: "handshake-deadline-past-idle-timeout",
+        severity: Severity::Soft,
+        check: |c| {
+            let deadline_ms = c.half_open.deadline_ms;
+            (deadline_ms > 0 && c.idle_timeout_ms > 0 && deadline_ms >= c.idle_timeout_ms).then(
+                || {
+                    format!(
+                        "--handshake-deadline-ms {} is not below --idle-timeout-ms {}: stalled handshakes time out before the deadline reaches them",
+                        deadline_ms, c.idle_timeout_ms
+                    )
+                },
+            )
+        },
+        fix: "lower --handshake-deadline-ms below --idle-timeout-ms",
+    },
+    Rule {
+        name: "junk-threshold-hair-trigger",
+        severity: Severity::Soft,
+        check: |c| {
+            (c.junk.strict && c.junk.threshold <= 1).then(|| {
+                format!(
+                    "--strict-junk with --junk-threshold {} denies a source for its first stray packet",
+                    c.junk.threshold
+                )
+            })
+        },
+        fix: "raise --junk-threshold",
+    },
+    Rule {
+        name: "dashboard-public",
+        severity: Severity::Soft,
+        check: |c| match c.dashboard_bind {
+            Some(addr) if !addr.ip().is_loopback() => Some(format!(
+                "--dashboard-bind {} exposes /admin/bandwidth, which changes limits, beyond this host",
+                addr
+            )),
+            _ => None,
+        },
+        fix: "bind the dashboard to 127.0.0.1 and reach it through a tunnel or proxy",
+    },
+    Rule {
+        name: "handshake-reserve-without-steering",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.handshake_reserve && !c.steer_by_port).then(|| {
+                "--handshake-reserve without --steer-by-port: the reserve is part of the port steering program".to_string()
+            })
+        },
+        fix: "add --steer-by-port, or remove --handshake-reserve",
+    },
+    Rule {
+        name: "no-sandbox-without-run-as",
+        severity: Severity::Soft,
+        check: |c| {
+            (!c.sandbox && c.run_as.is_none()).then(|| {
+                "--no-sandbox without --run-as: the syscall filter is only loaded when dropping privileges".to_string()
+            })
+        },
+        fix: "remove --no-sandbox, or add --run-as user:group",
+    },
+    Rule {
+        name: "demo-in-sandbox",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.demo_bots > 0 && c.run_as.is_some() && c.sandbox).then(|| {
+                format!(
+                    "--demo {} with --run-as: the bot thread opens its sockets after the syscall filter is loaded",
+                    c.demo_bots
+                )
+            })
+        },
+        fix: "add --no-sandbox, or run the demo without --run-as",
+    },
+    Rule {
+        name: "qlog-in-sandbox",
+        severity: Severity::Hard,
+        check: |c| {
+            (c.qlog_dir.is_some() && c.run_as.is_some() && c.sandbox).then(|| {
+                "--qlog-dir with --run-as: traces are created per connection, after the syscall filter is loaded".to_string()
+            })
+        },
+        fix: "add --no-sandbox, or trace without --run-as",
+    },
+];
+
+/// Every rule `config` breaks, in table order.
+pub fn check(config: &ServerConfig) -> Vec<Conflict> {
+    RULES
+        .iter()
+        .filter_map(|rule| {
+            (rule.check)(config).map(|detail| Conflict {
+                rule: rule.name,
+                severity: rule.severity,
+                detail,
+                fix: rule.fix,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(s: &str) -> ServerConfig {
+        let args: Vec<String> = std::iter::once("server")
+            .chain(s.split_whitespace())
+            .map(String::from)
+            .collect();
+        ServerConfig::from_args(&args)
+    }
+
+    fn broken(s: &str) -> Vec<&'static str> {
+        check(&config(s)).iter().map(|c| c.rule).collect()
+    }
+
+    #[test]
+    fn test_every_rule() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            (
+                "--conn-mem-soft-mb 2048 --conn-mem-hard-mb 1024",
+                &["conn-mem-order"],
+            ),
+            (
+                "--conn-mem-soft-mb 1024 --conn-mem-hard-mb 1024",
+                &["conn-mem-order"],
+            ),
+            ("--conn-mem-soft-mb 1024 --conn-mem-hard-mb 2048", &[]),
+            ("--conn-mem-soft-mb 2048", &[]),
+            (
+                "--tier1-tokens t.txt --tier1-per-worker 0",
+                &["tier1-no-seats"],
+            ),
+            ("--tier1-tokens t.txt --tier1-per-worker 8", &[]),
+            ("--strict-junk --junk-deny-max 0", &["strict-junk-inert"]),
+            ("--strict-junk --junk-deny-ttl-ms 0", &["strict-junk-inert"]),
+            ("--junk-deny-max 0", &[]),
+            ("--quic-retry --retry-token-ttl-ms 0", &["retry-ttl-zero"]),
+            ("--retry-token-ttl-ms 0", &[]),
+            ("--fec-k 4096", &["fec-k-range"]),
+            ("--admin-drain-ratio 0", &["admin-drain-zero"]),
+            ("--dgram-drain-cap 0", &["dgram-drain-cap-range"]),
+            ("--dgram-drain-cap 64", &["dgram-drain-cap-range"]),
+            ("--dgram-drain-cap 4", &[]),
+            ("--dgram-drain-budget 0", &["dgram-drain-budget-zero"]),
+            (
+                "--adaptive-broadcast --max-broadcast-interval-ms 100",
+                &["adaptive-ceiling"],
+            ),
+            ("--adaptive-broadcast --max-broadcast-interval-ms 300", &[]),
+            (
+                "--max-broadcast-interval-ms 300",
+                &["adaptive-ceiling-unused"],
+            ),
+            ("--full-broadcast-ms 0", &["fec-without-full-broadcasts"]),
+            ("--full-broadcast-ms 0 --fec-k 0", &[]),
+            ("--full-broadcast-ms 50", &["full-broadcast-every-tick"]),
+            (
+                "--conn-kbps 900 --worker-kbps 500",
+                &["conn-kbps-over-worker"],
+            ),
+            ("--conn-kbps 900", &[]),
+            (
+                "--full-sync-kbps 900 --worker-kbps 500",
+                &["full-sync-kbps-over-worker"],
+            ),
+            ("--tier1-per-worker 8", &["tier-settings-without-tokens"]),
+            ("--fast-diff-ms 0", &["tier-settings-without-tokens"]),
+            ("-w 3 --cpu-list 0-2", &["cpu-list-shared-cores"]),
+            ("-w 2 --cpu-list 0-2", &[]),
+            ("--cpu-list 0-2", &[]),
+            (
+                "--half-open-threshold 100000",
+                &["half-open-threshold-unreachable"],
+            ),
+            (
+                "--strict-junk --junk-threshold 1",
+                &["junk-threshold-hair-trigger"],
+            ),
+            ("--junk-threshold 1", &[]),
+            ("--idle-timeout-ms 0", &["idle-timeout-off"]),
+            (
+                "--idle-timeout-ms 5000",
+                &["handshake-deadline-past-idle-timeout"],
+            ),
+            ("--idle-timeout-ms 5000 --handshake-deadline-ms 3000", &[]),
+            ("--cooldown-secs 0", &["cooldown-range"]),
+            ("--cooldown-secs 301", &["cooldown-range"]),
+            ("--cooldown-secs 60", &[]),
+            ("--dashboard-bind 0.0.0.0:8080", &["dashboard-public"]),
+            ("--dashboard", &[]),
+            (
+                "--handshake-reserve",
+                &["handshake-reserve-without-steering"],
+            ),
+            ("--handshake-reserve --steer-by-port", &[]),
+            ("--no-sandbox", &["no-sandbox-without-run-as"]),
+            ("--no-sandbox --run-as canvas", &[]),
+            ("--demo 4 --run-as canvas", &["demo-in-sandbox"]),
+            ("--demo 4 --run-as canvas --no-sandbox", &[]),
+            ("--qlog-dir /tmp/qlog --run-as canvas", &["qlog-in-sandbox"]),
+            ("--qlog-dir /tmp/qlog --run-as canvas --no-sandbox", &[]),
+            // Several at once, in table order.
+            (
+                "--conn-kbps 900 --worker-kbps 500 --conn-mem-soft-mb 2 --conn-mem-hard-mb 1",
+                &["conn-mem-order", "conn-kbps-over-worker"],
+            ),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(broken(args), *expected, "{:?}", args);
+        }
+        // Each rule is covered above.
+        for rule in RULES {
+            assert!(
+                cases.iter().any(|(_, names)| names.contains(&rule.name)),
+                "{} untested",
+                rule.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_severity_and_message() {
+        let conflicts = check(&config(
+            "--conn-mem-soft-mb 2048 --conn-mem-hard-mb 1024 --conn-kbps 900 --worker-kbps 500",
+        ));
+        assert!(conflicts[0].is_hard());
+        assert!(!conflicts[1].is_hard());
+        assert_eq!(
+            conflicts[0].to_string(),
+            "Configuration error [conn-mem-order]: --conn-mem-soft-mb 2048 is not below --conn-mem-hard-mb 1024: handshakes would be reaped while new ones are still admitted\n  fix: lower --conn-mem-soft-mb below --conn-mem-hard-mb, or set one of them to 0"
+        );
+        assert!(
+            conflicts[1]
+                .to_string()
+                .starts_with("Configuration warning [conn-kbps-over-worker]: --conn-kbps 900")
+        );
+    }
+}
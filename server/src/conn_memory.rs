@@ -0,0 +1,137 @@
+//! Memory held by one worker's connections, and the limits that keep a
+//! connection ramp from getting the process OOM-killed.
+//!
+//! Each connection counts CONN_STATE_BYTES (quiche and TLS state, a static
+//! estimate) plus what all connections' datagram queues held at the last
+//! connection sweep. Past the soft limit the worker drops new connections'
+//! Initials unread: refusing them the way it does when every user id is
+//! taken would build the very connection state it is short of. Past the
+//! hard limit each sweep also drops every connection still in its
+//! handshake: they hold the most state for the least use, and their peers
+//! retry into the soft limit.
+//!
+//! Limits are per worker, set from the command line (`--conn-mem-soft-mb`,
+//! `--conn-mem-hard-mb`); 0 means none.
+
+use crate::const_settings::CONN_STATE_BYTES;
+use crate::metrics::WorkerMetrics;
+use std::sync::atomic::Ordering;
+
+/// Connection memory ceilings in bytes; 0 = none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryLimits {
+    pub soft_bytes: u64,
+    pub hard_bytes: u64,
+}
+
+/// One worker's estimate, kept current as connections come and go.
+pub struct ConnMemory {
+    limits: MemoryLimits,
+    metrics: &'static WorkerMetrics,
+    connections: u64,
+    /// Datagram bytes queued inside quiche, as of the last sweep.
+    queued_bytes: u64,
+}
+
+impl ConnMemory {
+    pub fn new(limits: MemoryLimits, metrics: &'static WorkerMetrics) -> Self {
+        Self {
+            limits,
+            metrics,
+            connections: 0,
+            queued_bytes: 0,
+        }
+    }
+
+    /// Estimated bytes held by the worker's connections.
+    pub fn bytes(&self) -> u64 {
+        self.connections * CONN_STATE_BYTES as u64 + self.queued_bytes
+    }
+
+    /// Whether one more connection stays within both limits.
+    #[inline]
+    pub fn admits(&self) -> bool {
+        let after = self.bytes() + CONN_STATE_BYTES as u64;
+        [self.limits.soft_bytes, self.limits.hard_bytes]
+            .iter()
+            .all(|&limit| limit == 0 || after <= limit)
+    }
+
+    /// Whether the sweep should drop connections that are still handshaking.
+    pub fn over_hard_limit(&self) -> bool {
+        self.limits.hard_bytes != 0 && self.bytes() > self.limits.hard_bytes
+    }
+
+    pub fn accepted(&mut self) {
+        self.connections += 1;
+        self.publish();
+    }
+
+    pub fn removed(&mut self) {
+        self.connections -= 1;
+        self.publish();
+    }
+
+    /// Records what the sweep found queued across all connections.
+    pub fn sampled(&mut self, queued_bytes: u64) {
+        self.queued_bytes = queued_bytes;
+        self.publish();
+    }
+
+    fn publish(&self) {
+        self.metrics
+            .conn_memory_bytes
+            .store(self.bytes(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::MAX_WORKERS;
+    use crate::metrics::WORKER_METRICS;
+
+    const CONN: u64 = CONN_STATE_BYTES as u64;
+
+    #[test]
+    fn test_limits_engage_at_the_right_connection() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 2];
+        let mut memory = ConnMemory::new(
+            MemoryLimits {
+                soft_bytes: 3 * CONN,
+                hard_bytes: 5 * CONN,
+            },
+            metrics,
+        );
+        for _ in 0..3 {
+            assert!(memory.admits());
+            memory.accepted();
+        }
+        // Full at the soft limit, but not yet reaping.
+        assert!(!memory.admits());
+        assert!(!memory.over_hard_limit());
+        assert_eq!(metrics.conn_memory_bytes.load(Ordering::Relaxed), 3 * CONN);
+
+        // Queued datagrams count: a backlog pushes the worker over the hard
+        // limit without a single new connection.
+        memory.sampled(2 * CONN + 1);
+        assert!(memory.over_hard_limit());
+        memory.sampled(0);
+        memory.removed();
+        assert!(memory.admits());
+        assert_eq!(metrics.conn_memory_bytes.load(Ordering::Relaxed), 2 * CONN);
+
+        // Each limit applies on its own; 0 turns it off.
+        let hard_only = ConnMemory::new(
+            MemoryLimits {
+                soft_bytes: 0,
+                hard_bytes: CONN,
+            },
+            metrics,
+        );
+        assert!(hard_only.admits());
+        let mut unlimited = ConnMemory::new(MemoryLimits::default(), metrics);
+        unlimited.sampled(u64::MAX / 2);
+        assert!(unlimited.admits() && !unlimited.over_hard_limit());
+    }
+}
@@ -0,0 +1,401 @@
+//! What the transport keeps per user id about the connection holding it:
+//! where it is in its life, when it was established (until its first
+//! broadcast is timed), its tile subscription, negotiated features, latency
+//! tier, pixel-rate contract, STATS rate limit and whether it is raw or
+//! WebTransport. One `ConnSlot` per id,
+//! so no piece of it can outlive the others when the id changes hands.
+//!
+//! ```text
+//!   Free ──accept──▶ Handshaking ──handshake done──▶ Established
+//!    ▲  ◀──removed──────┘  ▲                          │     │
+//!    │                     │ accept (same client,     │     │ RESUME onto
+//!    │                     │ or nothing else free)    │     │ a parked id:
+//!    │                     │          removed, parking on   │ the slot moves
+//!    │                   Parked ◀─────────────────────┘     │ there, this id
+//!    └───grace over, evicted─┘                              ▼ goes Free
+//! ```
+//!
+//! Every way out of a connection (to Free or Parked, and the source of a
+//! RESUME) goes through `reset_for_reuse`, and so does every accept: the
+//! next occupant starts exactly like the first one did. A parked id keeps
+//! nothing here; its cooldown, pixel tokens and bandwidth are the worker's
+//! and go with the id until it is freed (`TransportState::drain_released`).
+
+use crate::canvas::{ALL_TILES, TileMask};
+use crate::const_settings::MAX_CONNECTIONS_PER_WORKER;
+use crate::fast_diff;
+use crate::rate_contract::{Contract, ContractTerms};
+use crate::runtime_config::SERVER_FEATURES;
+use crate::webtransport::AppProtocol;
+use protocol::version::Features;
+use protocol::webtransport::SessionPrefix;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    Free,
+    Handshaking,
+    Established,
+    /// Held for a reconnect (reservation.rs).
+    Parked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnSlot {
+    state: SlotState,
+    /// When the handshake completed, until the first broadcast datagram is
+    /// queued.
+    established_ms: Option<u64>,
+    subscription: TileMask,
+    /// Only connections that sent VERSION; the rest get `Features::LEGACY`.
+    features: Option<Features>,
+    tier1: bool,
+    /// Set at accept, changed with the tier and the load (rate_contract.rs).
+    pub contract: Contract,
+    /// Last STATS reply (live_stats.rs); 0 = never.
+    pub last_stats_reply_ms: u64,
+    /// Last cooldown NACK (`TransportState::nack_cooldown`); 0 = never.
+    pub last_nack_ms: u64,
+    /// From the ALPN, once established (webtransport.rs).
+    protocol: AppProtocol,
+}
+
+impl ConnSlot {
+    const FIRST_USE: ConnSlot = ConnSlot {
+        state: SlotState::Free,
+        established_ms: None,
+        subscription: ALL_TILES,
+        features: None,
+        tier1: false,
+        contract: Contract::NONE,
+        last_stats_reply_ms: 0,
+        last_nack_ms: 0,
+        protocol: AppProtocol::Raw,
+    };
+
+    /// Back to how the id was before anyone used it.
+    pub fn reset_for_reuse(&mut self) {
+        *self = Self::FIRST_USE;
+    }
+
+    pub fn state(&self) -> SlotState {
+        self.state
+    }
+
+    /// Optional features this connection may use.
+    #[inline]
+    pub fn features(&self) -> Features {
+        self.features.unwrap_or(Features::LEGACY & SERVER_FEATURES)
+    }
+
+    /// Tiles whose diffs go to this connection.
+    #[inline(always)]
+    pub fn subscription(&self) -> &TileMask {
+        &self.subscription
+    }
+
+    /// Whether this connection gets micro-diffs (fast_diff.rs).
+    #[inline(always)]
+    pub fn is_tier1(&self) -> bool {
+        self.tier1
+    }
+
+    /// How this connection's datagrams are framed.
+    #[inline(always)]
+    pub fn protocol(&self) -> AppProtocol {
+        self.protocol
+    }
+
+    /// Whether contract changes are pushed to this connection: it
+    /// negotiated them, on a raw connection's streams.
+    pub fn takes_contracts(&self) -> bool {
+        self.features().contains(Features::CONTRACTS) && self.protocol == AppProtocol::Raw
+    }
+
+    /// Whether cooldown changes are pushed to this connection, likewise.
+    pub fn takes_cooldowns(&self) -> bool {
+        self.features().contains(Features::COOLDOWNS) && self.protocol == AppProtocol::Raw
+    }
+
+    /// Whether broadcast interval changes are pushed to this connection,
+    /// likewise.
+    pub fn takes_intervals(&self) -> bool {
+        self.features().contains(Features::INTERVALS) && self.protocol == AppProtocol::Raw
+    }
+}
+
+/// One worker's slots, by user id, the tier-1 seats they hold and what
+/// their contracts derive from.
+pub struct ConnSlots {
+    slots: Box<[ConnSlot]>,
+    tier1_count: usize,
+    tier1_seats: usize,
+    /// The configured cooldown is set by the worker; the load by
+    /// `TransportState::refresh_contracts`.
+    pub terms: ContractTerms,
+}
+
+impl Default for ConnSlots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnSlots {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![ConnSlot::FIRST_USE; MAX_CONNECTIONS_PER_WORKER].into_boxed_slice(),
+            tier1_count: 0,
+            tier1_seats: fast_diff::tier1_seats(),
+            terms: ContractTerms::DEFAULT,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, user_id: u32) -> &ConnSlot {
+        &self.slots[user_id as usize]
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, user_id: u32) -> &mut ConnSlot {
+        &mut self.slots[user_id as usize]
+    }
+
+    /// Tier-1 seats taken on this worker.
+    pub fn tier1_count(&self) -> usize {
+        self.tier1_count
+    }
+
+    /// A new connection took the id, free or parked.
+    pub fn accepted(&mut self, user_id: u32) {
+        debug_assert!(matches!(
+            self.get(user_id).state,
+            SlotState::Free | SlotState::Parked
+        ));
+        self.reset(user_id);
+        let contract = Contract::new(self.terms.cooldown_secs(false));
+        let slot = self.get_mut(user_id);
+        slot.state = SlotState::Handshaking;
+        slot.contract = contract;
+    }
+
+    /// Starts the first-broadcast clock and records what the connection
+    /// negotiated the first time it sees it established. Cheap to call on
+    /// every packet: `protocol` is only asked once.
+    #[inline(always)]
+    pub fn established(
+        &mut self,
+        user_id: u32,
+        now_ms: u64,
+        protocol: impl FnOnce() -> AppProtocol,
+    ) {
+        let slot = self.get_mut(user_id);
+        if slot.state == SlotState::Handshaking {
+            slot.state = SlotState::Established;
+            slot.established_ms = Some(now_ms);
+            slot.protocol = protocol();
+        }
+    }
+
+    /// A broadcast datagram was queued; returns the wait if it is the first.
+    #[inline(always)]
+    pub fn first_broadcast(&mut self, user_id: u32, now_ms: u64) -> Option<u64> {
+        let since = self.get_mut(user_id).established_ms.take()?;
+        Some(now_ms.saturating_sub(since))
+    }
+
+    /// The connection is gone; the id is parked for a reconnect or free.
+    pub fn released(&mut self, user_id: u32, parked: bool) {
+        self.reset(user_id);
+        if parked {
+            self.get_mut(user_id).state = SlotState::Parked;
+        }
+    }
+
+    /// The connection of `from` took over the parked id `to` (RESUME).
+    pub fn moved(&mut self, from: u32, to: u32) {
+        debug_assert_eq!(self.get(to).state, SlotState::Parked);
+        let slot = std::mem::replace(self.get_mut(from), ConnSlot::FIRST_USE);
+        *self.get_mut(to) = slot;
+    }
+
+    /// VERSION succeeded.
+    pub fn negotiated(&mut self, user_id: u32, features: Features) {
+        self.get_mut(user_id).features = Some(features);
+    }
+
+    /// SUBSCRIBE succeeded.
+    pub fn subscribed(&mut self, user_id: u32, mask: TileMask) {
+        self.get_mut(user_id).subscription = mask;
+    }
+
+    /// A browser's WebTransport CONNECT was accepted.
+    pub fn opened_session(&mut self, user_id: u32, session: SessionPrefix) {
+        let slot = self.get_mut(user_id);
+        debug_assert_eq!(slot.protocol, AppProtocol::WebTransport(None));
+        slot.protocol = AppProtocol::WebTransport(Some(session));
+    }
+
+    /// TIER with a valid token: a tier-1 seat if one is left. Returns
+    /// whether the connection holds one.
+    pub fn claim_tier1(&mut self, user_id: u32) -> bool {
+        let seats_left = self.tier1_count < self.tier1_seats;
+        let slot = &mut self.slots[user_id as usize];
+        if !slot.tier1 && seats_left {
+            slot.tier1 = true;
+            self.tier1_count += 1;
+        }
+        slot.tier1
+    }
+
+    /// Brings the connection's contract in line with its tier and the
+    /// current terms; returns whether it changed.
+    pub fn recontract(&mut self, user_id: u32, now_ms: u64) -> bool {
+        let secs = self.terms.cooldown_secs(self.get(user_id).tier1);
+        self.get_mut(user_id).contract.update(secs, now_ms)
+    }
+
+    fn reset(&mut self, user_id: u32) {
+        let slot = &mut self.slots[user_id as usize];
+        if slot.tier1 {
+            self.tier1_count -= 1;
+        }
+        slot.reset_for_reuse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::TILE_BITMAP_LEN;
+
+    /// Everything a connection can leave behind, and whether it gets there.
+    #[derive(Debug, Clone, Copy)]
+    struct Occupancy {
+        established: bool,
+        broadcast_sent: bool,
+        negotiated: bool,
+        subscribed: bool,
+        tier1: bool,
+        stats_replied: bool,
+        browser: bool,
+    }
+
+    fn occupancies() -> impl Iterator<Item = Occupancy> {
+        (0..128u32).map(|bits| Occupancy {
+            established: bits & 1 != 0,
+            broadcast_sent: bits & 2 != 0,
+            negotiated: bits & 4 != 0,
+            subscribed: bits & 8 != 0,
+            tier1: bits & 16 != 0,
+            stats_replied: bits & 32 != 0,
+            browser: bits & 64 != 0,
+        })
+    }
+
+    /// Puts a connection on `user_id` and has it do what `o` says.
+    fn occupy(slots: &mut ConnSlots, user_id: u32, o: Occupancy) {
+        slots.accepted(user_id);
+        if o.established {
+            slots.established(user_id, 1_000, || match o.browser {
+                true => AppProtocol::WebTransport(None),
+                false => AppProtocol::Raw,
+            });
+        }
+        if o.broadcast_sent {
+            slots.first_broadcast(user_id, 1_050);
+        }
+        if o.negotiated {
+            slots.negotiated(user_id, Features::ZSTD | Features::FEC);
+        }
+        if o.subscribed {
+            slots.subscribed(user_id, [0; TILE_BITMAP_LEN]);
+        }
+        if o.tier1 {
+            slots.claim_tier1(user_id);
+            slots.get_mut(user_id).contract.update(60, 1_100);
+        }
+        if o.stats_replied {
+            slots.get_mut(user_id).last_stats_reply_ms = 1_100;
+        }
+        if o.established && o.browser {
+            slots.opened_session(user_id, SessionPrefix::new(4));
+        }
+    }
+
+    /// What a connection sees of its slot, from accept to first broadcast.
+    fn first_steps(slots: &mut ConnSlots, user_id: u32) -> (ConnSlot, ConnSlot, Option<u64>) {
+        slots.accepted(user_id);
+        let accepted = slots.get(user_id).clone();
+        slots.established(user_id, 5_000, || AppProtocol::Raw);
+        let established = slots.get(user_id).clone();
+        let wait = slots.first_broadcast(user_id, 5_040);
+        (accepted, established, wait)
+    }
+
+    #[test]
+    fn test_reused_slot_matches_first_use() {
+        let mut reference = ConnSlots::new();
+        let first_use = first_steps(&mut reference, 0);
+        assert_eq!(first_use.0.features(), Features::LEGACY & SERVER_FEATURES);
+        assert_eq!(first_use.1.state(), SlotState::Established);
+        assert_eq!(first_use.2, Some(40));
+
+        let mut slots = ConnSlots::new();
+        slots.tier1_seats = 1;
+        for o in occupancies() {
+            for parked in [false, true] {
+                occupy(&mut slots, 3, o);
+                assert_eq!(slots.get(3).is_tier1(), o.tier1);
+                slots.released(3, parked);
+                let expected = if parked {
+                    SlotState::Parked
+                } else {
+                    SlotState::Free
+                };
+                assert_eq!(slots.get(3).state(), expected, "{:?}", o);
+                assert_eq!(slots.tier1_count(), 0, "{:?}", o);
+                assert_eq!(first_steps(&mut slots, 3), first_use, "{:?}", o);
+                slots.released(3, false);
+                assert_eq!(*slots.get(3), ConnSlot::FIRST_USE, "{:?}", o);
+            }
+
+            // RESUME: the state goes along to the parked id, the id left
+            // behind is as good as new.
+            occupy(&mut slots, 4, Occupancy { tier1: false, ..o });
+            slots.released(4, true);
+            occupy(&mut slots, 5, o);
+            let before = slots.get(5).clone();
+            let seats = slots.tier1_count();
+            slots.moved(5, 4);
+            assert_eq!(*slots.get(4), before, "{:?}", o);
+            assert_eq!(*slots.get(5), ConnSlot::FIRST_USE, "{:?}", o);
+            assert_eq!(slots.tier1_count(), seats);
+            assert_eq!(first_steps(&mut slots, 5), first_use, "{:?}", o);
+            slots.released(4, false);
+            slots.released(5, false);
+            assert_eq!(slots.tier1_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_first_broadcast_is_measured_once_from_establishment() {
+        let mut slots = ConnSlots::new();
+        // Free ids and handshaking connections are not measured.
+        assert_eq!(slots.first_broadcast(3, 1_000), None);
+        slots.accepted(3);
+        assert_eq!(slots.first_broadcast(3, 1_000), None);
+
+        slots.established(3, 2_000, || AppProtocol::Raw);
+        slots.established(3, 2_500, || AppProtocol::Raw);
+        assert_eq!(slots.first_broadcast(3, 2_040), Some(40));
+        assert_eq!(slots.first_broadcast(3, 2_100), None);
+        slots.established(3, 3_000, || AppProtocol::Raw);
+        assert_eq!(slots.first_broadcast(3, 3_100), None);
+
+        // The id's next owner starts over.
+        slots.released(3, false);
+        slots.accepted(3);
+        slots.established(3, 9_000, || AppProtocol::Raw);
+        assert_eq!(slots.first_broadcast(3, 9_000), Some(0));
+    }
+}
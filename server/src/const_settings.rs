@@ -18,6 +18,10 @@
 /// Default server listening port (QUIC / UDP).
 pub const SERVER_PORT: u16 = 4433;
 
+/// Default bind address of the optional HTTP dashboard (`--dashboard`).
+/// Localhost only; use `--dashboard-bind` to expose it.
+pub const DASHBOARD_DEFAULT_BIND: &str = "127.0.0.1:8080";
+
 /// Maximum UDP packet buffer size for io_uring provided buffers.
 /// Must hold the largest possible incoming QUIC packet.
 /// QUIC mandates Initial packets are ≥1200 bytes; 2048 covers any UDP payload
@@ -34,6 +38,9 @@ pub const SOCKET_RECV_BUF_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 /// Kernel socket send buffer size (bytes).
 pub const SOCKET_SEND_BUF_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 
+/// Upper bound on worker threads; sizes the static per-worker metrics blocks.
+pub const MAX_WORKERS: usize = 256;
+
 // ---------------------------------------------------------------------------
 // Per-Worker Connection Limits  (BASE — most things derive from this)
 // ---------------------------------------------------------------------------
@@ -45,29 +52,108 @@ pub const SOCKET_SEND_BUF_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 /// Must be a multiple of 64 so the cooldown bitset packs evenly into u64s.
 pub const MAX_CONNECTIONS_PER_WORKER: usize = 65_536;
 
+/// Refused connections (worker at capacity) whose CONNECTION_CLOSE has not
+/// been flushed yet. Initials beyond this within one loop iteration are
+/// dropped without an answer, so a connection flood cannot make a full
+/// worker spend unbounded time on handshakes it will refuse anyway.
+pub const MAX_PENDING_REFUSALS: usize = 64;
+
+/// Stateless responses (a Retry with `--quic-retry`, Version Negotiation)
+/// not yet handed to the TX path. They cost no state beyond this queue;
+/// packets beyond it within one loop iteration go unanswered and their
+/// clients resend.
+pub const MAX_PENDING_RESPONSES: usize = 256;
+
+/// Largest stateless response: a Retry, with both connection ids, a token
+/// of retry::RETRY_TOKEN_MAX_LEN and the 16-byte integrity tag. Version
+/// Negotiation packets are smaller.
+pub const RESPONSE_PACKET_MAX_LEN: usize = 256;
+
+/// How long a Retry token stays good (retry.rs): one round trip in
+/// practice, with room for a slow client. Override with
+/// `--retry-token-ttl-ms`.
+pub const RETRY_TOKEN_TTL_MS: u64 = 10_000;
+
+/// With `--qlog-dir`, each worker traces 1 in this many of the connections
+/// it accepts (qlog.rs): a few dozen traces at 40k connections. Override
+/// with `--qlog-sample`.
+pub const QLOG_SAMPLE_ONE_IN: u64 = 1_000;
+
+/// Estimated heap held by one connection: quiche's state plus BoringSSL's,
+/// a handshake in flight included. Datagrams queued inside quiche come on
+/// top and are sampled at run time (see conn_memory.rs). A static figure;
+/// `bench_conn_memory` in transport.rs measures the real one.
+pub const CONN_STATE_BYTES: usize = 40 * 1024;
+
+/// Time constant of the pixels-per-second figure in STATS replies
+/// (live_stats.rs): a burst shows up over about this long.
+pub const LIVE_STATS_SMOOTHING_MS: u64 = 10_000;
+
+/// Shortest gap between two cooldown NACKs (protocol::nack) on one
+/// connection: a client flooding pixels hears once a second, not once per
+/// pixel.
+pub const COOLDOWN_NACK_INTERVAL_MS: u64 = 1_000;
+
+/// A connection still in its QUIC handshake this long after its Initial is
+/// dropped and its user id freed (half_open.rs). Override with
+/// `--handshake-deadline-ms`; 0 = never.
+pub const HANDSHAKE_DEADLINE_MS: u64 = 10_000;
+
+/// Half-open connections per worker at which admission tightens until
+/// half of them are gone. Override with `--half-open-threshold`; 0 = never.
+pub const HALF_OPEN_THRESHOLD: usize = 4_096;
+
+/// While admission is tightened, half-open connections one peer address
+/// may hold on a worker.
+pub const HALF_OPEN_PER_IP_TIGHT: usize = 16;
+
+/// Junk packets (junk.rs) one source may send within JUNK_WINDOW_MS
+/// before `--strict-junk` denies it. Override with `--junk-threshold`.
+pub const JUNK_DENY_THRESHOLD: u32 = 32;
+
+pub const JUNK_WINDOW_MS: u64 = 10_000;
+
+/// How long a denied source stays denied. Override with
+/// `--junk-deny-ttl-ms`.
+pub const JUNK_DENY_TTL_MS: u64 = 60_000;
+
+/// Sources denied at once per worker. Override with `--junk-deny-max`.
+pub const JUNK_DENY_LIST_MAX: usize = 1_024;
+
+/// Sources whose junk is being counted towards a denial at once per
+/// worker; more are ignored until their windows run out.
+pub const JUNK_OFFENDERS_MAX: usize = 8_192;
+
+/// Minimum gap between two logged `quiche::accept` failures; the ones in
+/// between are only counted.
+pub const ACCEPT_WARN_INTERVAL_MS: u64 = 1_000;
+
 // ---------------------------------------------------------------------------
 // Application-Layer Data Sizes  (used to derive heuristics below)
 // ---------------------------------------------------------------------------
 
-/// Size of individual pixel wire format: x(u16) + y(u16) + color(u8) = 5 bytes.
-/// Matches size_of::<PixelDatagram>() which is #[repr(C, packed)].
-pub const PIXEL_DATAGRAM_SIZE: usize = 5;
+/// Size of individual pixel wire format: x(u16) + y(u16) + color(u8) = 5 bytes,
+/// little-endian (protocol::pixel), after the datagram's type byte.
+pub const PIXEL_DATAGRAM_SIZE: usize = protocol::pixel::PIXEL_LEN;
 
-/// Size of a single diff entry in the broadcast diff buffer: index(u32) + color(u8).
-pub const DIFF_ENTRY_SIZE: usize = 5;
+/// Optional trailer of a pixel datagram: a client-chosen u32 idempotency
+/// token. A retransmitted pixel carries the same token as the original.
+pub const PIXEL_TOKEN_SIZE: usize = protocol::pixel::TRAILER_LEN;
 
-/// Estimated QUIC overhead per packet (short header + DATAGRAM frame + AEAD tag).
-///   1 (form byte) + 8 (conn ID) + 4 (pkt num) + 3 (frame hdr) + 16 (AEAD) ≈ 32
-///   Rounded up to 40 for safety margin.
-#[allow(dead_code)]
-const EST_QUIC_OVERHEAD: usize = 40;
+/// Idempotency tokens remembered per connection; a retransmit older than
+/// this many tokened pixels is applied again.
+pub const RECENT_PIXEL_TOKENS: usize = 64;
 
-/// Estimated total incoming packet size in an io_uring provided buffer.
-/// = recvmsg_out(16) + sockaddr_in(16) + cmsg(MSG_CONTROL_LEN) + QUIC payload.
-/// QUIC payload for a pixel datagram ≈ EST_QUIC_OVERHEAD + PIXEL_DATAGRAM_SIZE.
-#[allow(dead_code)]
-const EST_INCOMING_BUF_USAGE: usize =
-    16 + 16 + MSG_CONTROL_LEN + EST_QUIC_OVERHEAD + PIXEL_DATAGRAM_SIZE; // ~141 bytes
+/// Optional trailer after the token: a u32 nonce the client counts up with
+/// every pixel, across reconnects. 0-RTT can be replayed; a replayed pixel
+/// carries a nonce its user id has already seen and is dropped.
+pub const PIXEL_NONCE_SIZE: usize = protocol::pixel::TRAILER_LEN;
+
+/// Nonces below the highest seen that may still arrive, reordered.
+pub const PIXEL_NONCE_WINDOW: u32 = 64;
+
+/// Size of a single diff entry in the broadcast diff buffer: index(u32) + color(u8).
+pub const DIFF_ENTRY_SIZE: usize = 5;
 
 // ---------------------------------------------------------------------------
 // Broadcasting
@@ -83,9 +169,83 @@ pub const BROADCAST_CHUNK_SIZE: usize = 1200;
 /// How often the master publishes a new canvas snapshot (milliseconds).
 pub const BROADCAST_INTERVAL_MS: u64 = 100;
 
-/// Send a full (RLE-compressed) canvas every N broadcasts instead of a diff.
-/// 60 × 100ms = every 6 seconds.
-pub const FULL_BROADCAST_INTERVAL: u32 = 60;
+/// Upper bound for the broadcast interval when the adaptive controller
+/// (`--adaptive-broadcast`) stretches it under worker CPU saturation.
+/// Override with `--max-broadcast-interval-ms`.
+pub const BROADCAST_INTERVAL_MAX_MS: u64 = 500;
+
+/// A worker loop iteration whose busy time reaches this is counted as an overrun.
+pub const WORKER_LOOP_BUDGET_MS: u64 = BROADCAST_INTERVAL_MS;
+
+/// Percentage of overrunning worker iterations (per broadcast interval) above
+/// which the interval counts as overloaded.
+pub const LOOP_OVERRUN_THRESHOLD_PCT: u64 = 10;
+
+/// Consecutive overloaded intervals before the broadcast interval is stretched (×1.5).
+pub const INTERVAL_STRETCH_STREAK: u32 = 3;
+
+/// Consecutive healthy intervals before the broadcast interval steps back
+/// toward BROADCAST_INTERVAL_MS (by half the base interval per step).
+pub const INTERVAL_RECOVERY_STREAK: u32 = 10;
+
+/// Budget (bytes of worker diff traffic) a single published snapshot may carry.
+///
+/// Heuristic: the master estimates each dirty tile's diff as
+///   min(writes since last publish, TILE_SIZE²) × DIFF_ENTRY_SIZE
+/// and stops copying dirty tiles into the next snapshot once the budget is
+/// spent, resuming from a round-robin cursor on the next interval. At least one
+/// tile is always published, so every dirty tile lands within TILE_COUNT
+/// intervals (≈ TILE_COUNT × 20 KB / budget in practice). 0 disables the cap.
+/// Override with `--snapshot-diff-budget <bytes>`.
+pub const SNAPSHOT_DIFF_BUDGET_BYTES: usize = 128 * 1024;
+
+/// Send a full (RLE-compressed) canvas instead of a diff every N ms, by each
+/// worker's clock and offset per worker (full_broadcast.rs), whether or not
+/// the canvas changed. Override with `--full-broadcast-ms`.
+pub const FULL_BROADCAST_INTERVAL_MS: u64 = 6_000;
+
+/// Above this many bytes of RLE per snapshot, full broadcasts stop and
+/// clients make do with diffs plus TILE_REFRESH (full_broadcast.rs). A
+/// canvas that has turned to noise RLEs to up to twice its size, and every
+/// connection of every worker would get that each interval. Override with
+/// `--full-broadcast-max-bytes`, 0 = no limit.
+pub const FULL_BROADCAST_MAX_BYTES: usize = 256 * 1024;
+
+/// Full broadcasts resume once the RLE is back under this share (%) of
+/// FULL_BROADCAST_MAX_BYTES, so a canvas hovering at the limit does not
+/// flip the mode every snapshot.
+pub const FULL_BROADCAST_RESUME_PCT: usize = 75;
+
+/// Full-broadcast chunks per parity chunk for clients that negotiated FEC
+/// (`--fec-k`, 0 = no FEC): 12.5% more bytes, and any one lost chunk in
+/// eight is rebuilt by the client.
+pub const FEC_BLOCK_CHUNKS: usize = 8;
+
+/// How long the user id of a closed connection stays parked for the same
+/// client to reconnect to (reservation.rs), cooldown and all. 0 frees ids
+/// on disconnect. Override with `--reserve-grace-ms`.
+pub const RESERVE_GRACE_MS: u64 = 0;
+
+/// Window the broadcast bandwidth caps (`--conn-kbps`, `--worker-kbps`,
+/// `--full-sync-kbps`) are measured over. A connection may spend its whole
+/// window's budget in one broadcast, so shorter windows smooth bursts at the
+/// cost of cutting more diffs short.
+pub const BANDWIDTH_WINDOW_MS: u64 = 1000;
+
+/// How often the master cuts the pixels it applied into a micro-diff for
+/// tier-1 connections (fast_diff.rs). 0 cuts one per master loop pass.
+/// Override with `--fast-diff-ms`.
+pub const FAST_DIFF_INTERVAL_MS: u64 = 10;
+
+/// Micro-diff slots in the ring workers read from, BROADCAST_CHUNK_SIZE
+/// bytes each. At 10 ms a slot a worker may fall 2.5 s behind before the
+/// master laps it; busy intervals cut several slots and shorten that.
+pub const FAST_RING_LEN: usize = 256;
+
+/// Tier-1 connections one worker accepts; further TIER requests are
+/// granted tier 0. Each costs a datagram per micro-diff, so this is what
+/// keeps the fast path cheap. Override with `--tier1-per-worker`.
+pub const TIER1_PER_WORKER: usize = 64;
 
 // ---------------------------------------------------------------------------
 // Cooldown Bitset  (derived from MAX_CONNECTIONS_PER_WORKER)
@@ -95,17 +255,25 @@ pub const FULL_BROADCAST_INTERVAL: u32 = 60;
 /// Each u64 tracks BITS_PER_COOLDOWN_CHUNK connections.
 /// Bits per chunk in the cooldown bitset (= 64 for u64).
 const BITS_PER_COOLDOWN_CHUNK: usize = std::mem::size_of::<u64>() * 8;
-pub const COOLDOWN_ARRAY_LEN: usize = MAX_CONNECTIONS_PER_WORKER / BITS_PER_COOLDOWN_CHUNK;
+pub const COOLDOWN_ARRAY_LEN: usize = DERIVED.cooldown_array_len;
 
 // ---------------------------------------------------------------------------
 // Timing Wheel
 // ---------------------------------------------------------------------------
 
 /// Number of ticks in the timing wheel (1 tick = 1 second).
-/// Determines how long a user stays on cooldown before being evicted.
-/// 300 ticks = 5 minutes.
+/// Determines the longest cooldown a user can be held to, and the default
+/// one (`--cooldown-secs`). 300 ticks = 5 minutes.
 pub const TIMING_WHEEL_TICKS: usize = 300;
 
+/// Notice a connection gets of a tighter pixel-rate contract before the
+/// server enforces it (rate_contract.rs): time for the push to cross the
+/// network and for the client to slow down.
+pub const CONTRACT_NOTICE_MS: u64 = 5_000;
+
+/// u64 words in each wheel bucket's summary: one bit per cooldown chunk.
+pub const WHEEL_SUMMARY_LEN: usize = COOLDOWN_ARRAY_LEN.div_ceil(BITS_PER_COOLDOWN_CHUNK);
+
 // ---------------------------------------------------------------------------
 // io_uring  (derived from MAX_CONNECTIONS_PER_WORKER & socket buffers)
 // ---------------------------------------------------------------------------
@@ -116,18 +284,12 @@ pub const TIMING_WHEEL_TICKS: usize = 300;
 ///   max queued packets = SOCKET_RECV_BUF_SIZE / PKT_BUF_SIZE
 ///   Capped at u16::MAX (65535) which is the io_uring provided-buffer limit.
 ///
-/// Buffers are consumed on RX and replenished after processing each CQE.
+/// Buffers are consumed on RX and replenished after each pass over the
+/// completion queue (replenish.rs).
 /// Under burst, all buffers may fill before we process any. If that happens,
 /// io_uring returns ENOBUFS and RecvMsgMulti is resubmitted — packets stay
 /// safe in the kernel socket buffer until we replenish.
-pub const IO_URING_NUM_BUFFERS: u16 = {
-    let derived = SOCKET_RECV_BUF_SIZE / PKT_BUF_SIZE; // 32MB / 2048 = 16384
-    if derived > u16::MAX as usize {
-        u16::MAX
-    } else {
-        derived as u16
-    }
-};
+pub const IO_URING_NUM_BUFFERS: u16 = DERIVED.io_uring_num_buffers; // 32MB / 2048 = 16384
 
 /// io_uring submission queue depth (must be a power of two).
 ///
@@ -138,13 +300,14 @@ pub const IO_URING_NUM_BUFFERS: u16 = {
 ///   - When the SQ fills, the code calls submit() and retries, so undersizing
 ///     is safe — it just causes extra syscalls.
 ///
-/// We size at half of MAX_CONNECTIONS_PER_WORKER (rounded to power of two)
-/// since TX items are recycled by completed CQEs between pushes.
-pub const IO_URING_SQ_DEPTH: u32 = {
-    let target = MAX_CONNECTIONS_PER_WORKER / 2;
-    // const next_power_of_two: 1 << (BITS - leading_zeros(target - 1))
-    1u32 << (u32::BITS - ((target as u32) - 1).leading_zeros())
-};
+/// We size at half of MAX_CONNECTIONS_PER_WORKER (rounded up to a power of
+/// two) since TX items are recycled by completed CQEs between pushes, capped
+/// at IO_URING_MAX_ENTRIES.
+pub const IO_URING_SQ_DEPTH: u32 = DERIVED.io_uring_sq_depth;
+
+/// Largest submission queue the kernel accepts (IORING_MAX_ENTRIES);
+/// io_uring_setup fails with EINVAL above it.
+pub const IO_URING_MAX_ENTRIES: u32 = 32_768;
 
 /// Buffer Group ID for io_uring provided buffers.
 pub const IO_URING_BGID: u16 = 0;
@@ -155,6 +318,20 @@ pub const TAG_INCOMING_UDP: u64 = 1;
 /// Tag embedded in io_uring CQE user_data to identify outgoing UDP completions.
 pub const TAG_OUTGOING_UDP: u64 = 2;
 
+/// Buffer Group ID of the handshake lane (`--handshake-reserve`,
+/// recv_lane.rs).
+pub const IO_URING_HANDSHAKE_BGID: u16 = 1;
+
+/// Tag embedded in io_uring CQE user_data to identify completions of the
+/// handshake lane's receive.
+pub const TAG_INCOMING_HANDSHAKE: u64 = 3;
+
+/// Receive buffers reserved for long-header packets per worker with
+/// `--handshake-reserve`: 1 MB at PKT_BUF_SIZE. Only handshakes land there,
+/// and each pass hands them back, so this covers a connection storm of a
+/// few hundred Initials per loop iteration without touching the data lane.
+pub const HANDSHAKE_NUM_BUFFERS: u16 = 512;
+
 /// Number of pre-allocated TX items (outgoing sendmsg slots).
 ///
 /// Heuristic: one slot per connection.
@@ -162,10 +339,10 @@ pub const TAG_OUTGOING_UDP: u64 = 2;
 ///   conn.send() call → 1 TxItem. So MAX_CONNECTIONS_PER_WORKER covers a
 ///   full diff flush without running out of items.
 ///
-///   During a full RLE broadcast (rare, every FULL_BROADCAST_INTERVAL), each
+///   During a full RLE broadcast (rare, every FULL_BROADCAST_INTERVAL_MS), each
 ///   connection may produce many more sends. TX items are recycled as CQEs
 ///   complete, so the flush loop naturally throttles itself when items run out.
-pub const TX_CAPACITY: usize = MAX_CONNECTIONS_PER_WORKER;
+pub const TX_CAPACITY: usize = DERIVED.tx_capacity;
 
 // ---------------------------------------------------------------------------
 // msghdr / ancillary control buffer
@@ -180,22 +357,69 @@ pub const MSG_CONTROL_LEN: usize = 64;
 // Canvas
 // ---------------------------------------------------------------------------
 
-/// Canvas width in pixels.
-pub const CANVAS_WIDTH: usize = 1000;
+/// Canvas width in pixels (protocol::pixel, shared with the client).
+pub const CANVAS_WIDTH: usize = protocol::pixel::CANVAS_WIDTH as usize;
 
 /// Canvas height in pixels.
-pub const CANVAS_HEIGHT: usize = 1000;
+pub const CANVAS_HEIGHT: usize = protocol::pixel::CANVAS_HEIGHT as usize;
 
 /// Total number of pixels in the canvas (1 byte per pixel).
 pub const CANVAS_SIZE: usize = CANVAS_WIDTH * CANVAS_HEIGHT;
 
 /// Number of canvas snapshot buffers in the RCU-like pool.
 /// Must be a power of two so the master can advance with a bitmask.
-pub const CANVAS_BUFFER_POOL_SIZE: usize = 16;
+/// Workers diff against the slot they last broadcast, so this is also their
+/// history: a worker more than POOL_SIZE - 2 snapshots behind (~3 s at
+/// 100 ms) falls back to a full broadcast. The same window bounds CATCHUP:
+/// a client that missed more than that needs a full resync. Each slot costs
+/// ~3 MB (raw + compressed).
+pub const CANVAS_BUFFER_POOL_SIZE: usize = 32;
 
 /// Bitmask for cycling through the canvas buffer pool indices.
 pub const CANVAS_BUFFER_POOL_MASK: usize = CANVAS_BUFFER_POOL_SIZE - 1;
 
+/// Side length (pixels) of the square tiles used for coarse change tracking.
+/// 64 × 64 tiles cover a 1000 × 1000 canvas in a 16 × 16 grid (edge tiles are partial).
+pub const TILE_SIZE: usize = 64;
+
+/// Number of tile columns.
+pub const TILES_X: usize = CANVAS_WIDTH.div_ceil(TILE_SIZE);
+
+/// Number of tile rows.
+pub const TILES_Y: usize = CANVAS_HEIGHT.div_ceil(TILE_SIZE);
+
+/// Total number of tiles.
+pub const TILE_COUNT: usize = TILES_X * TILES_Y;
+
+/// Number of u64 words in a one-bit-per-tile bitmap.
+pub const TILE_BITMAP_LEN: usize = TILE_COUNT.div_ceil(64);
+
+/// Number of fastest-churning tiles reported by the dashboard.
+pub const CHURN_TOP_TILES: usize = 10;
+
+/// Width of the dashboard's `/thumb.png` preview; the height follows the
+/// canvas aspect ratio.
+pub const THUMBNAIL_WIDTH: usize = 100;
+
+/// Height of the `/thumb.png` preview.
+pub const THUMBNAIL_HEIGHT: usize = (CANVAS_HEIGHT * THUMBNAIL_WIDTH).div_ceil(CANVAS_WIDTH);
+
+/// `/thumb.png` is remade from the newest snapshot at most this often (ms).
+pub const THUMBNAIL_REFRESH_MS: u64 = 1_000;
+
+/// Minutes of per-minute activity kept for the timeline (`--timeline-minutes`).
+pub const TIMELINE_WINDOW_MINUTES: usize = 24 * 60;
+
+/// Applied writes kept in the master's audit sample (audit.rs): 24 bytes
+/// each, plus the same again for the copy the dashboard reads.
+pub const AUDIT_RESERVOIR_LEN: usize = 65_536;
+
+/// The audit sample is copied out for `/admin/audit` at most this often (ms).
+pub const AUDIT_PUBLISH_MS: u64 = 5_000;
+
+/// Most entries one `/admin/audit` page returns.
+pub const AUDIT_PAGE_MAX: usize = 1_000;
+
 // ---------------------------------------------------------------------------
 // SPSC Ring Buffer  (worker → master pixel queue)
 // ---------------------------------------------------------------------------
@@ -207,6 +431,11 @@ pub const SPSC_CAPACITY: usize = 65_536;
 /// per iteration of its hot loop.
 pub const MASTER_BATCH_DRAIN: usize = 4096;
 
+/// Admin pixels (region clears, admin_writes.rs) the master applies per
+/// iteration, as a multiple of MASTER_BATCH_DRAIN. Override with
+/// `--admin-drain-ratio`.
+pub const ADMIN_DRAIN_RATIO: usize = 1;
+
 // ---------------------------------------------------------------------------
 // QUIC / quiche Configuration
 // ---------------------------------------------------------------------------
@@ -229,6 +458,12 @@ pub const QUIC_INITIAL_MAX_STREAMS_BIDI: u64 = 100;
 /// Maximum number of concurrent unidirectional streams.
 pub const QUIC_INITIAL_MAX_STREAMS_UNI: u64 = 100;
 
+/// Idle timeout offered to peers (ms): a connection nothing arrives on for
+/// this long closes, and its user id and cooldown slot are freed. quiche
+/// uses the lower of both sides' values (the load client offers 60 s) and
+/// never less than three PTOs. Override with `--idle-timeout-ms`; 0 = none.
+pub const QUIC_MAX_IDLE_TIMEOUT_MS: u64 = 30_000;
+
 /// Datagram send and receive queue depth inside quiche.
 ///
 /// Heuristic: must hold all chunks of one broadcast round per connection.
@@ -239,6 +474,26 @@ pub const QUIC_INITIAL_MAX_STREAMS_UNI: u64 = 100;
 ///   diffs and most practical full broadcasts.
 pub const QUIC_DGRAM_QUEUE_LEN: usize = 1000;
 
+/// Capacity of the worker's reusable pixel scratch buffer.
+/// A single recv reads at most MAX_DGRAMS_PER_RECV datagrams of at most
+/// MAX_BATCH_PIXELS pixels each, so sizing to QUIC_DGRAM_QUEUE_LEN means
+/// the buffer never grows.
+pub const MAX_PIXELS_PER_PACKET: usize = QUIC_DGRAM_QUEUE_LEN;
+const _: () =
+    assert!(MAX_DGRAMS_PER_RECV * protocol::batch::MAX_BATCH_PIXELS <= MAX_PIXELS_PER_PACKET);
+
+/// Datagrams read off one connection per packet it sends (or per backlog
+/// pass), so a client that queued hundreds cannot hold up every other
+/// connection's packets. The rest stay queued in quiche for the worker's
+/// next backlog pass. Lower it with `--dgram-drain-cap`; the scratch
+/// buffer is sized for this many.
+pub const MAX_DGRAMS_PER_RECV: usize = 16;
+
+/// Datagrams a backlog pass reads per loop iteration, over all the
+/// connections in it; the ones it does not get to go first in the next
+/// iteration's pass. Override with `--dgram-drain-budget`.
+pub const DGRAM_DRAIN_BUDGET: usize = 256;
+
 // ---------------------------------------------------------------------------
 // Connection Maintenance
 // ---------------------------------------------------------------------------
@@ -247,6 +502,10 @@ pub const QUIC_DGRAM_QUEUE_LEN: usize = 1000;
 /// excessive CPU overhead on large connection counts.
 pub const CONN_TIMEOUT_THROTTLE_MS: u128 = 20;
 
+/// Interval (ms) between samples of quiche's per-connection statistics
+/// (transport_stats.rs). A sample walks every connection.
+pub const TRANSPORT_STATS_INTERVAL_MS: u64 = 5_000;
+
 // ---------------------------------------------------------------------------
 // Diff Buffer
 // ---------------------------------------------------------------------------
@@ -254,6 +513,12 @@ pub const CONN_TIMEOUT_THROTTLE_MS: u128 = 20;
 /// Initial capacity for the per-worker diff buffer used in delta broadcasts.
 pub const DIFF_BUFFER_INITIAL_CAPACITY: usize = 1024;
 
+/// Largest diff a worker broadcasts (`--diff-cap-bytes`). A diff is 5 bytes
+/// per changed pixel, so a mass change would otherwise fill the buffer up to
+/// 5 MB and go out as thousands of chunks, where the RLE snapshot is far
+/// smaller; past the cap the worker falls back to a full broadcast.
+pub const DIFF_CAP_BYTES: usize = 64 * 1024;
+
 // =============================================================================
 // MEMORY BUDGET PER WORKER  (compile-time computed, for documentation)
 // =============================================================================
@@ -264,29 +529,147 @@ pub const DIFF_BUFFER_INITIAL_CAPACITY: usize = 1024;
 
 /// Buffer slab: io_uring provided receive buffers.
 ///   IO_URING_NUM_BUFFERS × PKT_BUF_SIZE bytes.
-pub const MEM_BUFFER_SLAB: usize = (IO_URING_NUM_BUFFERS as usize) * PKT_BUF_SIZE;
+pub const MEM_BUFFER_SLAB: usize = DERIVED.mem_buffer_slab;
 
 /// TX items: pre-allocated outgoing sendmsg slots.
 ///   TX_CAPACITY × DGRAM_MAX_SEND_SIZE bytes (dominates; addr/iov/msghdr are small).
-pub const MEM_TX_ITEMS: usize = TX_CAPACITY * (DGRAM_MAX_SEND_SIZE + 88); // +88 for sockaddr+iov+msghdr
+pub const MEM_TX_ITEMS: usize = DERIVED.mem_tx_items;
+
+/// Destination cache: one prepared sockaddr (plus the address it was built
+/// from) per user id.
+pub const MEM_DEST_CACHE: usize = DERIVED.mem_dest_cache;
 
 /// Cooldown bitset: one per worker.
-pub const MEM_COOLDOWN: usize = COOLDOWN_ARRAY_LEN * std::mem::size_of::<u64>();
+pub const MEM_COOLDOWN: usize = DERIVED.mem_cooldown;
+
+/// Tile subscription masks: one `[u64; TILE_BITMAP_LEN]` per user id.
+pub const MEM_SUBSCRIPTIONS: usize = DERIVED.mem_subscriptions;
+
+/// Per-user handshake timestamps for the time-to-first-broadcast metric.
+pub const MEM_FIRST_BROADCAST: usize = DERIVED.mem_first_broadcast;
 
-/// Timing wheel: TIMING_WHEEL_TICKS copies of the cooldown bitset.
-pub const MEM_TIMING_WHEEL: usize = TIMING_WHEEL_TICKS * MEM_COOLDOWN;
+/// Per-user bytes sent in the current bandwidth window.
+pub const MEM_BANDWIDTH: usize = DERIVED.mem_bandwidth;
 
-/// Canvas copy: last_sent_canvas snapshot.
-pub const MEM_CANVAS_COPY: usize = CANVAS_SIZE;
+/// Timing wheel: TIMING_WHEEL_TICKS buckets, each a copy of the cooldown
+/// bitset plus its chunk summary, and a u16 bucket index per user id (see
+/// `TimingWheel::heap_bytes`).
+pub const MEM_TIMING_WHEEL: usize = DERIVED.mem_timing_wheel;
+
+/// quiche connection state with every user id taken.
+///   MAX_CONNECTIONS_PER_WORKER × CONN_STATE_BYTES; dominates at scale.
+pub const MEM_CONNECTIONS: usize = DERIVED.mem_connections;
 
 /// Total estimated heap memory per worker (bytes).
-pub const MEM_PER_WORKER: usize =
-    MEM_BUFFER_SLAB + MEM_TX_ITEMS + MEM_COOLDOWN + MEM_TIMING_WHEEL + MEM_CANVAS_COPY;
+pub const MEM_PER_WORKER: usize = DERIVED.mem_per_worker;
 
 /// Total buffer pool memory (static, shared across all workers).
 ///   CANVAS_BUFFER_POOL_SIZE × CANVAS_SIZE × 3 (raw + compressed + lens).
 pub const MEM_CANVAS_POOL: usize = CANVAS_BUFFER_POOL_SIZE * CANVAS_SIZE * 3;
 
+// =============================================================================
+// DERIVED VALUES
+// =============================================================================
+
+/// Every value above that is computed from the base knobs, in one place so
+/// it can be logged at startup and pinned by tests for other tunings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Derived {
+    pub max_connections_per_worker: usize,
+    pub cooldown_array_len: usize,
+    pub io_uring_num_buffers: u16,
+    pub io_uring_sq_depth: u32,
+    pub tx_capacity: usize,
+    pub mem_buffer_slab: usize,
+    pub mem_tx_items: usize,
+    pub mem_dest_cache: usize,
+    pub mem_cooldown: usize,
+    pub mem_subscriptions: usize,
+    pub mem_first_broadcast: usize,
+    pub mem_bandwidth: usize,
+    pub mem_timing_wheel: usize,
+    pub mem_connections: usize,
+    pub mem_per_worker: usize,
+}
+
+const DERIVED: Derived = derived_for(MAX_CONNECTIONS_PER_WORKER);
+
+/// Derived values for the compiled-in tuning.
+pub const fn derived() -> Derived {
+    DERIVED
+}
+
+/// Derived values for a given MAX_CONNECTIONS_PER_WORKER, everything else as
+/// configured. See the constant of the same name for each heuristic.
+pub const fn derived_for(max_connections_per_worker: usize) -> Derived {
+    assert!(
+        max_connections_per_worker > 0
+            && max_connections_per_worker.is_multiple_of(BITS_PER_COOLDOWN_CHUNK),
+        "MAX_CONNECTIONS_PER_WORKER must be a positive multiple of 64"
+    );
+
+    let io_uring_num_buffers = {
+        let buffers = SOCKET_RECV_BUF_SIZE / PKT_BUF_SIZE;
+        if buffers > u16::MAX as usize {
+            u16::MAX
+        } else {
+            buffers as u16
+        }
+    };
+    let io_uring_sq_depth = {
+        let target = (max_connections_per_worker / 2).next_power_of_two();
+        if target > IO_URING_MAX_ENTRIES as usize {
+            IO_URING_MAX_ENTRIES
+        } else {
+            target as u32
+        }
+    };
+    let cooldown_array_len = max_connections_per_worker / BITS_PER_COOLDOWN_CHUNK;
+    let tx_capacity = max_connections_per_worker;
+
+    let mem_buffer_slab = io_uring_num_buffers as usize * PKT_BUF_SIZE;
+    // +88 for sockaddr+iov+msghdr
+    let mem_tx_items = tx_capacity * (DGRAM_MAX_SEND_SIZE + 88);
+    let mem_dest_cache = max_connections_per_worker
+        * (std::mem::size_of::<libc::sockaddr_in>()
+            + std::mem::size_of::<Option<std::net::SocketAddrV4>>());
+    let mem_cooldown = cooldown_array_len * std::mem::size_of::<u64>();
+    let mem_subscriptions = max_connections_per_worker * TILE_BITMAP_LEN * 8;
+    let mem_first_broadcast = max_connections_per_worker * std::mem::size_of::<u64>();
+    let mem_bandwidth = max_connections_per_worker * std::mem::size_of::<u32>();
+    let mem_wheel_summary =
+        cooldown_array_len.div_ceil(BITS_PER_COOLDOWN_CHUNK) * std::mem::size_of::<u64>();
+    let mem_timing_wheel = TIMING_WHEEL_TICKS * (mem_cooldown + mem_wheel_summary)
+        + max_connections_per_worker * std::mem::size_of::<u16>();
+    let mem_connections = max_connections_per_worker * CONN_STATE_BYTES;
+
+    Derived {
+        max_connections_per_worker,
+        cooldown_array_len,
+        io_uring_num_buffers,
+        io_uring_sq_depth,
+        tx_capacity,
+        mem_buffer_slab,
+        mem_tx_items,
+        mem_dest_cache,
+        mem_cooldown,
+        mem_subscriptions,
+        mem_first_broadcast,
+        mem_bandwidth,
+        mem_timing_wheel,
+        mem_connections,
+        mem_per_worker: mem_buffer_slab
+            + mem_tx_items
+            + mem_dest_cache
+            + mem_cooldown
+            + mem_subscriptions
+            + mem_first_broadcast
+            + mem_bandwidth
+            + mem_timing_wheel
+            + mem_connections,
+    }
+}
+
 /// Pretty-print the pre-calculated memory budget to stdout.
 pub fn print_mem_footprint(num_workers: usize) {
     let to_mb = |bytes: usize| bytes as f64 / 1024.0 / 1024.0;
@@ -305,15 +688,30 @@ pub fn print_mem_footprint(num_workers: usize) {
         to_mb(MEM_TX_ITEMS),
         TX_CAPACITY
     );
+    println!(
+        "    - Dest Cache:         {:>8.2} MB",
+        to_mb(MEM_DEST_CACHE)
+    );
     println!("    - Cooldown Bitset:    {:>8.2} MB", to_mb(MEM_COOLDOWN));
+    println!(
+        "    - Subscriptions:      {:>8.2} MB",
+        to_mb(MEM_SUBSCRIPTIONS)
+    );
+    println!(
+        "    - First Broadcast:    {:>8.2} MB",
+        to_mb(MEM_FIRST_BROADCAST)
+    );
+    println!("    - Bandwidth Budget:   {:>8.2} MB", to_mb(MEM_BANDWIDTH));
     println!(
         "    - Timing Wheel:       {:>8.2} MB ({} ticks)",
         to_mb(MEM_TIMING_WHEEL),
         TIMING_WHEEL_TICKS
     );
     println!(
-        "    - Canvas Snapshot:    {:>8.2} MB",
-        to_mb(MEM_CANVAS_COPY)
+        "    - Connections:        {:>8.2} MB (~{} KB each, all {} ids taken)",
+        to_mb(MEM_CONNECTIONS),
+        CONN_STATE_BYTES / 1024,
+        MAX_CONNECTIONS_PER_WORKER
     );
     println!("    ----------------------------------");
     println!(
@@ -337,3 +735,57 @@ pub fn print_mem_footprint(num_workers: usize) {
     );
     println!("+----------------------------------------------------------------------------+");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derived_values_for_current_tuning() {
+        assert_eq!(derived(), derived_for(MAX_CONNECTIONS_PER_WORKER));
+        assert_eq!(
+            derived_for(65_536),
+            Derived {
+                max_connections_per_worker: 65_536,
+                cooldown_array_len: 1_024,
+                io_uring_num_buffers: 16_384,
+                io_uring_sq_depth: 32_768,
+                tx_capacity: 65_536,
+                mem_buffer_slab: 33_554_432,
+                mem_tx_items: 104_071_168,
+                mem_dest_cache: 1_572_864,
+                mem_cooldown: 8_192,
+                mem_subscriptions: 2_097_152,
+                mem_first_broadcast: 524_288,
+                mem_bandwidth: 262_144,
+                mem_timing_wheel: 2_627_072,
+                mem_connections: 2_684_354_560,
+                mem_per_worker: 2_829_071_872,
+            }
+        );
+    }
+
+    #[test]
+    fn test_derived_values_for_other_tunings() {
+        let small = derived_for(4_096);
+        assert_eq!(small.cooldown_array_len, 64);
+        assert_eq!(small.io_uring_sq_depth, 2_048);
+        assert_eq!(small.tx_capacity, 4_096);
+        assert_eq!(small.mem_timing_wheel, 300 * (64 + 1) * 8 + 4_096 * 2);
+
+        // Half of it is 131_072, above what the kernel accepts.
+        let large = derived_for(262_144);
+        assert_eq!(large.cooldown_array_len, 4_096);
+        assert_eq!(large.io_uring_sq_depth, IO_URING_MAX_ENTRIES);
+        assert_eq!(large.tx_capacity, 262_144);
+
+        // Targets that are not a power of two round up; tiny ones stay valid.
+        assert_eq!(derived_for(3_008).io_uring_sq_depth, 2_048);
+        assert_eq!(derived_for(64).io_uring_sq_depth, 32);
+        assert_eq!(derived_for(128).io_uring_sq_depth, 64);
+
+        // The receive side does not depend on the connection count.
+        assert_eq!(small.io_uring_num_buffers, large.io_uring_num_buffers);
+        assert_eq!(small.mem_buffer_slab, large.mem_buffer_slab);
+    }
+}
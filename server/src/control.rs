@@ -0,0 +1,483 @@
+use crate::canvas;
+use crate::conn_slot::ConnSlots;
+use crate::const_settings::{CANVAS_HEIGHT, CANVAS_WIDTH};
+use crate::fast_diff;
+use crate::metrics::MASTER_METRICS;
+use crate::reservation::{Reservations, ResumeKey};
+use crate::runtime_config;
+use crate::timeline;
+use crate::transport::CidKey;
+use protocol::close::AppCloseCode;
+use protocol::control::{
+    BROADCAST_INTERVAL_LEN, ControlOp, ControlStatus, CooldownConfig, MAX_TIER_TOKEN_LEN,
+    RATE_CONTRACT_LEN, SUBSCRIBE_BITMAP_LEN, Subscription, TILE_REFRESH_HEADER_LEN,
+    decode_catchup_request, decode_resume_request, decode_subscribe, decode_tier_request,
+    decode_tile_checksums_request, decode_tile_refresh_request, encode_broadcast_interval,
+    encode_broadcast_interval_push, encode_catchup, encode_cooldown_config,
+    encode_cooldown_config_push, encode_rate_contract, encode_rate_contract_push,
+    encode_tile_checksums, encode_tile_refresh,
+};
+use protocol::version::{
+    Features, MAX_BUILD_LEN, VERSION_FIXED_LEN, decode_version, encode_version, negotiate,
+};
+use quiche::Connection;
+use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+
+/// Longest request any op takes: VERSION with a full-length build string
+/// (opcode, version, build); SUBSCRIBE and TILE_REFRESH with a tile bitmap
+/// are shorter.
+/// Anything beyond is read and discarded.
+const MAX_REQUEST_LEN: usize = 1 + VERSION_FIXED_LEN + MAX_BUILD_LEN;
+const _: () = assert!(MAX_REQUEST_LEN >= 9 + SUBSCRIBE_BITMAP_LEN);
+const _: () = assert!(MAX_REQUEST_LEN > MAX_TIER_TOKEN_LEN);
+
+/// Request bytes received on a stream whose FIN has not arrived yet.
+struct PartialRequest {
+    buf: [u8; MAX_REQUEST_LEN],
+    len: usize,
+}
+
+impl Default for PartialRequest {
+    fn default() -> Self {
+        Self {
+            buf: [0; MAX_REQUEST_LEN],
+            len: 0,
+        }
+    }
+}
+
+/// Reply bytes quiche's flow control has not accepted yet. Pushes wait here
+/// too, on the stream the server opened for them.
+struct PendingReply {
+    stream_id: u64,
+    data: Vec<u8>,
+    offset: usize,
+}
+
+/// Answers control-stream requests (see `protocol::control`) on the
+/// connections of one worker. Replies are small and rare, so they are built
+/// on demand; only what does not fit the stream window is kept around.
+/// SUBSCRIBE, VERSION and TIER change the connection's slot (conn_slot.rs);
+/// RESUME changes the user id itself, and the slot and anything queued here
+/// move along to the new one. COOLDOWN_CONFIG needs the worker's cooldowns,
+/// so it is set aside for the worker to answer (`answer_cooldown`). A
+/// VERSION that negotiates CONTRACTS, and a TIER that changes the contract,
+/// are followed by a push of the contract (`push_contract`); one that
+/// negotiates COOLDOWNS, a TIER or a RESUME by a push of the cooldown, set
+/// aside the same way (`push_cooldown`); one that negotiates INTERVALS by a
+/// push of the broadcast interval (`push_interval`).
+pub struct ControlStreams {
+    pending: FxHashMap<u32, Vec<PendingReply>>,
+    partial: FxHashMap<(u32, u64), PartialRequest>,
+    /// COOLDOWN_CONFIG requests (stream id) and pushes (None) waiting for
+    /// the worker, oldest first, by the connection's original DCID: a
+    /// RESUME changes its user id, and a close makes it resolve to nothing.
+    cooldown_queries: VecDeque<(CidKey, Option<u64>)>,
+    /// Set by a RESUME that took over a parked id, until `service` returns.
+    resumed: Option<u32>,
+}
+
+impl Default for ControlStreams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlStreams {
+    pub fn new() -> Self {
+        Self {
+            pending: FxHashMap::default(),
+            partial: FxHashMap::default(),
+            cooldown_queries: VecDeque::new(),
+            resumed: None,
+        }
+    }
+
+    /// Flushes queued replies, then answers every stream whose request is
+    /// complete (FIN received). Cheap when the peer never opens a stream:
+    /// `readable()` is empty and there is nothing pending.
+    ///
+    /// Returns the parked user id the connection took over with RESUME, if
+    /// it did; the caller switches the connection to it and frees
+    /// `user_id`.
+    pub fn service(
+        &mut self,
+        mut user_id: u32,
+        dcid: CidKey,
+        conn: &mut Connection,
+        reservations: &mut Reservations,
+        slots: &mut ConnSlots,
+    ) -> Option<u32> {
+        if let Some(replies) = self.pending.get_mut(&user_id) {
+            replies.retain_mut(|r| !send_reply(conn, r));
+            if replies.is_empty() {
+                self.pending.remove(&user_id);
+            }
+        }
+
+        for stream_id in conn.readable() {
+            let mut request = self
+                .partial
+                .remove(&(user_id, stream_id))
+                .unwrap_or_default();
+            if !read_request(conn, stream_id, &mut request) {
+                self.partial.insert((user_id, stream_id), request);
+                continue;
+            }
+            if request.len == 0 {
+                continue;
+            }
+            if request.buf[0] == ControlOp::CooldownConfig as u8 {
+                self.cooldown_queries.push_back((dcid, Some(stream_id)));
+                continue;
+            }
+
+            let slot = slots.get(user_id);
+            let told = slot.takes_contracts().then(|| slot.contract.seq());
+            let cooled = slot.takes_cooldowns().then(|| slot.contract.seq());
+            let paced = slot.takes_intervals();
+            let mut reply = PendingReply {
+                stream_id,
+                data: self.respond(user_id, &request.buf[..request.len], reservations, slots),
+                offset: 0,
+            };
+            let resumed = self.resumed.is_some();
+            if let Some(parked) = self.resumed {
+                user_id = parked;
+            }
+            let mismatch = reply.data[0] == ControlStatus::VersionMismatch as u8;
+            if !send_reply(conn, &mut reply) && !mismatch {
+                self.pending.entry(user_id).or_default().push(reply);
+            }
+            if mismatch {
+                // The peer cannot parse what we would send next.
+                let code = AppCloseCode::VersionMismatch;
+                let _ = conn.close(true, code.code(), code.reason());
+                break;
+            }
+            let slot = slots.get(user_id);
+            if slot.takes_contracts() && told != Some(slot.contract.seq()) {
+                self.push_contract(user_id, conn, slots, crate::time::CLOCK.now_ms());
+            }
+            let slot = slots.get(user_id);
+            if slot.takes_cooldowns() && (resumed || cooled != Some(slot.contract.seq())) {
+                self.push_cooldown(dcid);
+            }
+            if slot.takes_intervals() && !paced {
+                self.push_interval(user_id, conn, slots, broadcast_interval_ms());
+            }
+        }
+        self.resumed.take()
+    }
+
+    /// Opens a stream to tell the connection its contract as of `now_ms`.
+    pub fn push_contract(
+        &mut self,
+        user_id: u32,
+        conn: &mut Connection,
+        slots: &mut ConnSlots,
+        now_ms: u64,
+    ) {
+        let contract = &mut slots.get_mut(user_id).contract;
+        let mut push = PendingReply {
+            stream_id: contract.next_push_stream(),
+            data: Vec::with_capacity(1 + RATE_CONTRACT_LEN),
+            offset: 0,
+        };
+        encode_rate_contract_push(&contract.told(now_ms), &mut push.data);
+        if !send_reply(conn, &mut push) {
+            self.pending.entry(user_id).or_default().push(push);
+        }
+    }
+
+    /// Opens a stream to tell the connection the broadcast interval.
+    pub fn push_interval(
+        &mut self,
+        user_id: u32,
+        conn: &mut Connection,
+        slots: &mut ConnSlots,
+        interval_ms: u32,
+    ) {
+        let mut push = PendingReply {
+            stream_id: slots.get_mut(user_id).contract.next_push_stream(),
+            data: Vec::with_capacity(1 + BROADCAST_INTERVAL_LEN),
+            offset: 0,
+        };
+        encode_broadcast_interval_push(interval_ms, &mut push.data);
+        if !send_reply(conn, &mut push) {
+            self.pending.entry(user_id).or_default().push(push);
+        }
+    }
+
+    /// Drops queued replies and half-read requests of a connection that went
+    /// away; its user id is about to be handed to someone else, or parked
+    /// for the same client to take back without this state. Its cooldown
+    /// queries no longer resolve and are skipped.
+    pub fn forget(&mut self, user_id: u32) {
+        self.pending.remove(&user_id);
+        if !self.partial.is_empty() {
+            self.partial.retain(|&(id, _), _| id != user_id);
+        }
+    }
+
+    /// Sets aside a push of the cooldown to the connection `dcid` names,
+    /// for the worker to fill in.
+    pub fn push_cooldown(&mut self, dcid: CidKey) {
+        self.cooldown_queries.push_back((dcid, None));
+    }
+
+    /// The oldest COOLDOWN_CONFIG request (with its stream) or push still
+    /// unanswered.
+    pub fn next_cooldown_query(&mut self) -> Option<(CidKey, Option<u64>)> {
+        self.cooldown_queries.pop_front()
+    }
+
+    /// Answers a request from `next_cooldown_query` on its stream, or
+    /// pushes on a new one, with the cooldown the worker enforces on the
+    /// connection.
+    pub fn answer_cooldown(
+        &mut self,
+        user_id: u32,
+        conn: &mut Connection,
+        stream_id: Option<u64>,
+        config: &CooldownConfig,
+        slots: &mut ConnSlots,
+    ) {
+        let mut reply = match stream_id {
+            Some(stream_id) => {
+                let mut data = vec![ControlStatus::Ok as u8];
+                encode_cooldown_config(config, &mut data);
+                PendingReply {
+                    stream_id,
+                    data,
+                    offset: 0,
+                }
+            }
+            None => {
+                let mut data = Vec::new();
+                encode_cooldown_config_push(config, &mut data);
+                PendingReply {
+                    stream_id: slots.get_mut(user_id).contract.next_push_stream(),
+                    data,
+                    offset: 0,
+                }
+            }
+        };
+        if !send_reply(conn, &mut reply) {
+            self.pending.entry(user_id).or_default().push(reply);
+        }
+    }
+
+    /// Moves the state of `from` to the parked id `to` it is taking over.
+    /// `to` was forgotten when it parked.
+    fn rekey(&mut self, from: u32, to: u32, slots: &mut ConnSlots) {
+        if let Some(replies) = self.pending.remove(&from) {
+            self.pending.insert(to, replies);
+        }
+        let streams: Vec<u64> = self
+            .partial
+            .keys()
+            .filter(|&&(id, _)| id == from)
+            .map(|&(_, stream_id)| stream_id)
+            .collect();
+        for stream_id in streams {
+            let request = self.partial.remove(&(from, stream_id)).unwrap();
+            self.partial.insert((to, stream_id), request);
+        }
+        slots.moved(from, to);
+        self.resumed = Some(to);
+    }
+
+    fn respond(
+        &mut self,
+        user_id: u32,
+        request: &[u8],
+        reservations: &mut Reservations,
+        slots: &mut ConnSlots,
+    ) -> Vec<u8> {
+        match ControlOp::from_u8(request[0]) {
+            Some(ControlOp::StatsTimeline) => {
+                let mut data = vec![ControlStatus::Ok as u8];
+                data.extend_from_slice(&timeline::published());
+                data
+            }
+            Some(ControlOp::Catchup) => {
+                let Some(since) = decode_catchup_request(&request[1..]) else {
+                    return vec![ControlStatus::BadRequest as u8];
+                };
+                let mut diff = Vec::new();
+                match canvas::catchup_diff(since, &mut diff) {
+                    Some(seq) => {
+                        let mut data = vec![ControlStatus::Ok as u8];
+                        encode_catchup(seq, &diff, &mut data);
+                        data
+                    }
+                    None => vec![ControlStatus::ResyncRequired as u8],
+                }
+            }
+            Some(ControlOp::TileChecksums) => {
+                let Some(seq) = decode_tile_checksums_request(&request[1..]) else {
+                    return vec![ControlStatus::BadRequest as u8];
+                };
+                match canvas::snapshot_tile_checksums(seq) {
+                    Some((seq, checksums)) => {
+                        let mut data = vec![ControlStatus::Ok as u8];
+                        encode_tile_checksums(seq, &checksums, &mut data);
+                        data
+                    }
+                    None => vec![ControlStatus::ResyncRequired as u8],
+                }
+            }
+            Some(ControlOp::TileRefresh) => {
+                let Some((seq, bitmap)) = decode_tile_refresh_request(&request[1..]) else {
+                    return vec![ControlStatus::BadRequest as u8];
+                };
+                // Bits past the canvas drop out here, so the reply's bitmap
+                // names exactly the tiles it carries.
+                let mask = canvas::tile_mask(&Subscription::Tiles(bitmap));
+                let mut rle = Vec::new();
+                match canvas::snapshot_tiles(seq, &mask, &mut rle) {
+                    Some(seq) => {
+                        let mut data = Vec::with_capacity(1 + TILE_REFRESH_HEADER_LEN + rle.len());
+                        data.push(ControlStatus::Ok as u8);
+                        encode_tile_refresh(
+                            seq,
+                            CANVAS_WIDTH as u16,
+                            CANVAS_HEIGHT as u16,
+                            &canvas::tile_bitmap(&mask),
+                            &rle,
+                            &mut data,
+                        );
+                        data
+                    }
+                    None => vec![ControlStatus::ResyncRequired as u8],
+                }
+            }
+            Some(ControlOp::Config) => {
+                let mut data = vec![ControlStatus::Ok as u8];
+                data.extend_from_slice(runtime_config::published_json().as_bytes());
+                data
+            }
+            Some(ControlOp::Version) => {
+                let Ok(client) = decode_version(&request[1..]) else {
+                    return vec![ControlStatus::BadRequest as u8];
+                };
+                let ours = runtime_config::build_info();
+                let status = match negotiate(&ours, &client) {
+                    Ok(features) => {
+                        slots.negotiated(user_id, features);
+                        ControlStatus::Ok
+                    }
+                    Err(_) => ControlStatus::VersionMismatch,
+                };
+                let mut data = vec![status as u8];
+                encode_version(&ours, &mut data);
+                data
+            }
+            Some(ControlOp::Subscribe)
+                if !slots
+                    .get(user_id)
+                    .features()
+                    .contains(Features::SUBSCRIPTIONS) =>
+            {
+                vec![ControlStatus::UnknownOp as u8]
+            }
+            Some(ControlOp::Subscribe) => match decode_subscribe(&request[1..]) {
+                Some(subscription) => {
+                    slots.subscribed(user_id, canvas::tile_mask(&subscription));
+                    vec![ControlStatus::Ok as u8]
+                }
+                None => vec![ControlStatus::BadRequest as u8],
+            },
+            Some(ControlOp::Tier) => match decode_tier_request(&request[1..]) {
+                Some(token) if fast_diff::grants_tier1(token) => {
+                    let seat = slots.claim_tier1(user_id);
+                    slots.recontract(user_id, crate::time::CLOCK.now_ms());
+                    vec![ControlStatus::Ok as u8, seat as u8]
+                }
+                Some(_) => vec![ControlStatus::Forbidden as u8],
+                None => vec![ControlStatus::BadRequest as u8],
+            },
+            Some(ControlOp::Resume) => match decode_resume_request(&request[1..]) {
+                Some(token) => {
+                    let key = ResumeKey::Token(*token);
+                    let resumed = match reservations.claim(&key) {
+                        Some(parked) => {
+                            self.rekey(user_id, parked, slots);
+                            true
+                        }
+                        None => {
+                            reservations.bind(user_id, key);
+                            false
+                        }
+                    };
+                    vec![ControlStatus::Ok as u8, resumed as u8]
+                }
+                None => vec![ControlStatus::BadRequest as u8],
+            },
+            Some(ControlOp::RateContract) => {
+                let contract = slots.get(user_id).contract;
+                let mut data = vec![ControlStatus::Ok as u8];
+                encode_rate_contract(&contract.told(crate::time::CLOCK.now_ms()), &mut data);
+                data
+            }
+            Some(ControlOp::BroadcastInterval) => {
+                let mut data = vec![ControlStatus::Ok as u8];
+                encode_broadcast_interval(broadcast_interval_ms(), &mut data);
+                data
+            }
+            // Set aside by `service` before it gets here.
+            Some(ControlOp::CooldownConfig) | None => vec![ControlStatus::UnknownOp as u8],
+        }
+    }
+}
+
+/// The broadcast interval the master is on (health.rs).
+fn broadcast_interval_ms() -> u32 {
+    let interval_ms = MASTER_METRICS
+        .effective_broadcast_interval_ms
+        .load(Ordering::Relaxed);
+    interval_ms.min(u32::MAX as u64) as u32
+}
+
+/// Reads what the stream has into `request`. Returns true once the request
+/// is complete: FIN seen, or the stream is gone.
+fn read_request(conn: &mut Connection, stream_id: u64, request: &mut PartialRequest) -> bool {
+    let mut overflow = [0u8; MAX_REQUEST_LEN];
+    loop {
+        let room = MAX_REQUEST_LEN - request.len;
+        let received = if room > 0 {
+            conn.stream_recv(stream_id, &mut request.buf[request.len..])
+        } else {
+            conn.stream_recv(stream_id, &mut overflow)
+        };
+        match received {
+            Ok((len, fin)) => {
+                if room > 0 {
+                    request.len += len;
+                }
+                if fin {
+                    return true;
+                }
+            }
+            Err(quiche::Error::Done) => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+/// Writes as much of the reply as the stream accepts, finishing the stream
+/// with the last byte. Returns true once the reply is done (or the stream
+/// is gone).
+fn send_reply(conn: &mut Connection, reply: &mut PendingReply) -> bool {
+    match conn.stream_send(reply.stream_id, &reply.data[reply.offset..], true) {
+        Ok(written) => {
+            reply.offset += written;
+            reply.offset == reply.data.len()
+        }
+        Err(quiche::Error::Done) => false,
+        Err(_) => true,
+    }
+}
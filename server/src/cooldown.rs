@@ -30,6 +30,13 @@ impl CooldownArray {
         let bit_mask = 1 << (local_id & 63);
         self.bits[chunk_idx] |= bit_mask;
     }
+
+    #[inline(always)]
+    pub fn clear_cooldown(&mut self, local_id: u32) {
+        let chunk_idx = (local_id >> 6) as usize;
+        let bit_mask = 1 << (local_id & 63);
+        self.bits[chunk_idx] &= !bit_mask;
+    }
 }
 
 #[cfg(test)]
@@ -49,5 +56,9 @@ mod tests {
         assert!(arr.is_on_cooldown(52000));
         assert!(!arr.is_on_cooldown(11));
         assert!(!arr.is_on_cooldown(52001));
+
+        arr.clear_cooldown(10);
+        assert!(!arr.is_on_cooldown(10));
+        assert!(arr.is_on_cooldown(52000));
     }
 }
@@ -0,0 +1,1231 @@
+use crate::admin_writes::AdminWrite;
+use crate::audit::AuditFilter;
+use crate::bandwidth::BANDWIDTH_LIMITS;
+use crate::const_settings::{
+    AUDIT_PAGE_MAX, CANVAS_HEIGHT, CANVAS_WIDTH, CHURN_TOP_TILES, TILE_COUNT, TILES_X, TILES_Y,
+};
+use crate::metrics::{LatencyHistogram, MASTER_METRICS, WORKER_METRICS, WorkerMetrics, top_tiles};
+use crate::runtime_config::bandwidth_json;
+use crate::spsc::SharedRing;
+use crate::thumbnail::Thumbnail;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const INDEX_HTML: &str = include_str!("dashboard.html");
+
+/// Largest request head we are willing to buffer.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Longest one request may take, reading it and writing the response
+/// together.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(2);
+
+/// Binds the dashboard listener and serves it from a dedicated thread.
+/// Returns the bound address (useful when binding port 0). `admin` is the
+/// master's admin ring; this thread is its only producer.
+pub fn spawn(
+    bind: SocketAddr,
+    num_workers: usize,
+    admin: SharedRing<AdminWrite>,
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind)?;
+    let local_addr = listener.local_addr()?;
+    std::thread::spawn(move || serve(listener, num_workers, admin));
+    Ok(local_addr)
+}
+
+/// What the dashboard thread keeps between requests.
+struct State {
+    rates: RateTracker,
+    thumbnail: Thumbnail,
+    admin: SharedRing<AdminWrite>,
+}
+
+fn serve(listener: TcpListener, num_workers: usize, admin: SharedRing<AdminWrite>) {
+    let mut state = State {
+        rates: RateTracker::new(num_workers),
+        thumbnail: Thumbnail::new(),
+        admin,
+    };
+    for stream in listener.incoming().flatten() {
+        // Plain sequential handling: the dashboard is for a handful of operators.
+        let _ = handle(stream, num_workers, &mut state);
+    }
+}
+
+fn handle(mut stream: TcpStream, num_workers: usize, state: &mut State) -> std::io::Result<()> {
+    // Requests are served one at a time, so a client that drips its
+    // request or reads a large body (/metrics, audit pages) slowly would
+    // hold every later one, clears included. Socket timeouts only bound
+    // each read or write, so every one is given what is left of the
+    // request's deadline. The master never waits on this thread either way.
+    let deadline = Instant::now() + REQUEST_DEADLINE;
+
+    let mut buf = [0u8; MAX_REQUEST_BYTES];
+    let mut len = 0;
+    while len < buf.len() {
+        stream.set_read_timeout(Some(time_left(deadline)?))?;
+        let n = stream.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+        if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf[..len]);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, content_type, body) = route(method, path, num_workers, state);
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    write_all_by(&mut stream, head.as_bytes(), deadline)?;
+    write_all_by(&mut stream, &body, deadline)
+}
+
+/// What is left until `deadline`, as a socket timeout; TimedOut once it
+/// has passed (a zero timeout would mean none).
+fn time_left(deadline: Instant) -> std::io::Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(std::io::ErrorKind::TimedOut.into());
+    }
+    Ok(left)
+}
+
+/// `write_all` that gives up at `deadline` however the reader trickles.
+fn write_all_by(stream: &mut TcpStream, mut buf: &[u8], deadline: Instant) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        stream.set_write_timeout(Some(time_left(deadline)?))?;
+        match stream.write(buf) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn route(
+    method: &str,
+    path: &str,
+    num_workers: usize,
+    state: &mut State,
+) -> (&'static str, &'static str, Vec<u8>) {
+    let (route, query) = path.split_once('?').unwrap_or((path, ""));
+    if route == "/admin/bandwidth" {
+        let (status, content_type, body) = admin_bandwidth(method, query);
+        return (status, content_type, body.into());
+    }
+    if route == "/admin/clear" {
+        let (status, content_type, body) = admin_clear(method, query, &state.admin);
+        return (status, content_type, body.into());
+    }
+    if route == "/admin/epoch" {
+        let (status, content_type, body) = admin_epoch(method, query, crate::sandbox::confined());
+        return (status, content_type, body.into());
+    }
+    if route == "/admin/audit" {
+        let (status, content_type, body) = admin_audit(method, query);
+        return (status, content_type, body.into());
+    }
+    if method != "GET" {
+        return ("405 Method Not Allowed", "text/plain", "GET only\n".into());
+    }
+    match route {
+        "/" | "/index.html" => ("200 OK", "text/html; charset=utf-8", INDEX_HTML.into()),
+        "/stats.json" => (
+            "200 OK",
+            "application/json",
+            stats_json(num_workers, &mut state.rates).into(),
+        ),
+        "/config.json" => (
+            "200 OK",
+            "application/json",
+            crate::runtime_config::published_json().into(),
+        ),
+        "/admin/profile" => (
+            "200 OK",
+            "application/json",
+            crate::profiler::profile_json(num_workers).into(),
+        ),
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            prometheus_text(num_workers).into(),
+        ),
+        "/thumb.png" => match state.thumbnail.png() {
+            [] => (
+                "503 Service Unavailable",
+                "text/plain",
+                "no snapshot yet\n".into(),
+            ),
+            png => ("200 OK", "image/png", png.to_vec()),
+        },
+        _ => ("404 Not Found", "text/plain", "not found\n".into()),
+    }
+}
+
+/// `GET /admin/bandwidth` shows the broadcast bandwidth caps;
+/// `POST /admin/bandwidth?conn_kbps=500&worker_kbps=20000` changes the ones
+/// given (0 = unlimited). Workers apply them from their next window. Anyone
+/// who can reach the dashboard can do this: keep it on a private address.
+fn admin_bandwidth(method: &str, query: &str) -> (&'static str, &'static str, String) {
+    let mut limits = BANDWIDTH_LIMITS.load();
+    match method {
+        "GET" => {}
+        "POST" => {
+            for pair in query.split('&').filter(|p| !p.is_empty()) {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let slot = match key {
+                    "conn_kbps" => &mut limits.conn_kbps,
+                    "worker_kbps" => &mut limits.worker_kbps,
+                    "full_sync_kbps" => &mut limits.full_sync_kbps,
+                    _ => {
+                        return (
+                            "400 Bad Request",
+                            "text/plain",
+                            format!("unknown {}\n", key),
+                        );
+                    }
+                };
+                match value.parse() {
+                    Ok(kbps) => *slot = kbps,
+                    Err(_) => {
+                        return (
+                            "400 Bad Request",
+                            "text/plain",
+                            format!("invalid {}={}\n", key, value),
+                        );
+                    }
+                }
+            }
+            BANDWIDTH_LIMITS.store(limits);
+            println!(
+                "Dashboard: broadcast bandwidth caps now {} (kbps, 0 = unlimited)",
+                bandwidth_json(limits)
+            );
+        }
+        _ => {
+            return (
+                "405 Method Not Allowed",
+                "text/plain",
+                "GET or POST only\n".into(),
+            );
+        }
+    }
+    ("200 OK", "application/json", bandwidth_json(limits))
+}
+
+/// `POST /admin/clear?color=0&x0=0&y0=0&x1=100&y1=50` paints the rectangle
+/// `[x0, x1) × [y0, y1)` in `color`; the bounds default to the whole canvas.
+/// The master applies it over the next iterations, next to user pixels
+/// (admin_writes.rs). Same caveat as `/admin/bandwidth`.
+fn admin_clear(
+    method: &str,
+    query: &str,
+    admin: &SharedRing<AdminWrite>,
+) -> (&'static str, &'static str, String) {
+    if method != "POST" {
+        return ("405 Method Not Allowed", "text/plain", "POST only\n".into());
+    }
+    let mut color = None;
+    let (mut x0, mut y0) = (0, 0);
+    let (mut x1, mut y1) = (CANVAS_WIDTH as u16, CANVAS_HEIGHT as u16);
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let parsed = match key {
+            "color" => value.parse().map(|c| color = Some(c)),
+            "x0" => value.parse().map(|v| x0 = v),
+            "y0" => value.parse().map(|v| y0 = v),
+            "x1" => value.parse().map(|v| x1 = v),
+            "y1" => value.parse().map(|v| y1 = v),
+            _ => {
+                return (
+                    "400 Bad Request",
+                    "text/plain",
+                    format!("unknown {}\n", key),
+                );
+            }
+        };
+        if parsed.is_err() {
+            return (
+                "400 Bad Request",
+                "text/plain",
+                format!("invalid {}={}\n", key, value),
+            );
+        }
+    }
+    let Some(color) = color else {
+        return ("400 Bad Request", "text/plain", "color missing\n".into());
+    };
+    let Some(write) = AdminWrite::clear(x0, y0, x1, y1, color) else {
+        return (
+            "400 Bad Request",
+            "text/plain",
+            format!("empty or off-canvas region {},{} - {},{}\n", x0, y0, x1, y1),
+        );
+    };
+    if admin.push(write).is_err() {
+        return (
+            "503 Service Unavailable",
+            "text/plain",
+            "too many clears queued\n".into(),
+        );
+    }
+    println!(
+        "Dashboard: clear of {},{} - {},{} to color {} queued",
+        x0, y0, x1, y1, color
+    );
+    (
+        "202 Accepted",
+        "application/json",
+        format!("{{\"pixels\":{}}}", write.pixels()),
+    )
+}
+
+/// `POST /admin/epoch?color=0` starts a new epoch (epoch.rs) from a solid
+/// color; `?image=bg.raw` from a file in the `--background-image` format,
+/// read by this thread so the master never waits on the disk. Under the
+/// sandbox (`confined`) opening it would kill the server, so only colors
+/// are taken. Same caveat as `/admin/bandwidth`.
+fn admin_epoch(method: &str, query: &str, confined: bool) -> (&'static str, &'static str, String) {
+    if method != "POST" {
+        return ("405 Method Not Allowed", "text/plain", "POST only\n".into());
+    }
+    let background = match query.split_once('=') {
+        Some(("color", value)) => match value.parse() {
+            Ok(color) => crate::canvas::solid_background(color),
+            Err(_) => {
+                return (
+                    "400 Bad Request",
+                    "text/plain",
+                    format!("invalid color={}\n", value),
+                );
+            }
+        },
+        Some(("image", _)) if confined => {
+            return (
+                "403 Forbidden",
+                "text/plain",
+                "image= cannot open files under the sandbox; use color=, or run with --no-sandbox\n"
+                    .into(),
+            );
+        }
+        Some(("image", path)) => match crate::canvas::load_background_image(Path::new(path)) {
+            Ok(background) => background,
+            Err(e) => return ("400 Bad Request", "text/plain", format!("{}\n", e)),
+        },
+        _ => {
+            return (
+                "400 Bad Request",
+                "text/plain",
+                "one of color or image expected\n".into(),
+            );
+        }
+    };
+    crate::epoch::request(background);
+    println!(
+        "Dashboard: epoch {} requested from {}",
+        crate::epoch::current() + 1,
+        query
+    );
+    ("202 Accepted", "application/json", "{}".into())
+}
+
+/// `GET /admin/audit?worker=1&user=42&ip=10.0.0.0/8&x0=0&y0=0&x1=100&y1=50&offset=0&limit=100`
+/// pages through the audit sample (audit.rs), oldest first; every filter is
+/// optional, and user ids only mean something together with `worker`. The
+/// sample is at most AUDIT_PUBLISH_MS old. `POST /admin/audit` starts a new
+/// one from the master's next publish. Same caveat as `/admin/bandwidth`.
+fn admin_audit(method: &str, query: &str) -> (&'static str, &'static str, String) {
+    match method {
+        "GET" => {}
+        "POST" => {
+            crate::audit::request_reset();
+            println!("Dashboard: audit sample reset requested");
+            return ("202 Accepted", "application/json", "{}".into());
+        }
+        _ => {
+            return (
+                "405 Method Not Allowed",
+                "text/plain",
+                "GET or POST only\n".into(),
+            );
+        }
+    }
+    let mut filter = AuditFilter::default();
+    let (mut offset, mut limit) = (0, 100);
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let parsed = match key {
+            "worker" => value.parse().map(|w| filter.worker = Some(w)).is_ok(),
+            "user" => value.parse().map(|u| filter.user_id = Some(u)).is_ok(),
+            "ip" => AuditFilter::parse_ip_prefix(value)
+                .map(|ip| filter.ip = Some(ip))
+                .is_some(),
+            "x0" => value.parse().map(|v| filter.region.0 = v).is_ok(),
+            "y0" => value.parse().map(|v| filter.region.1 = v).is_ok(),
+            "x1" => value.parse().map(|v| filter.region.2 = v).is_ok(),
+            "y1" => value.parse().map(|v| filter.region.3 = v).is_ok(),
+            "offset" => value.parse().map(|v| offset = v).is_ok(),
+            "limit" => value
+                .parse()
+                .ok()
+                .filter(|v| (1..=AUDIT_PAGE_MAX).contains(v))
+                .map(|v| limit = v)
+                .is_some(),
+            _ => {
+                return (
+                    "400 Bad Request",
+                    "text/plain",
+                    format!("unknown {}\n", key),
+                );
+            }
+        };
+        if !parsed {
+            return (
+                "400 Bad Request",
+                "text/plain",
+                format!("invalid {}={}\n", key, value),
+            );
+        }
+    }
+    (
+        "200 OK",
+        "application/json",
+        crate::audit::query_json(&filter, offset, limit),
+    )
+}
+
+/// Turns cumulative counters into per-second rates between two stats requests.
+struct RateTracker {
+    last_sample: Instant,
+    last_accepted: Vec<u64>,
+    last_tile_overwrites: Vec<u32>,
+}
+
+impl RateTracker {
+    fn new(num_workers: usize) -> Self {
+        Self {
+            last_sample: Instant::now(),
+            last_accepted: vec![0; num_workers],
+            last_tile_overwrites: vec![0; TILE_COUNT],
+        }
+    }
+}
+
+/// Assembles the `/stats.json` document from the shared metrics blocks.
+fn stats_json(num_workers: usize, rates: &mut RateTracker) -> String {
+    let elapsed = rates.last_sample.elapsed().as_secs_f64().max(1e-3);
+    rates.last_sample = Instant::now();
+
+    let mut out = String::with_capacity(4096);
+    out.push_str("{\"workers\":[");
+    for (id, m) in WORKER_METRICS[..num_workers].iter().enumerate() {
+        let accepted = m.pixels_accepted.load(Ordering::Relaxed);
+        let pixels_per_sec = accepted.saturating_sub(rates.last_accepted[id]) as f64 / elapsed;
+        rates.last_accepted[id] = accepted;
+        if id > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"connections\":{},\"connections_accepted\":{},\"pixels_accepted\":{},\"pixels_per_sec\":{:.1},\"broadcast_bytes\":{},\"spsc_drops\":{},\"cooldown_rejections\":{},\"duplicate_pixels\":{},\"loop_overruns\":{},\"conn_memory_bytes\":{}}}",
+            id,
+            m.connections.load(Ordering::Relaxed),
+            m.connections_accepted.load(Ordering::Relaxed),
+            accepted,
+            pixels_per_sec,
+            m.broadcast_bytes.load(Ordering::Relaxed),
+            m.spsc_drops.load(Ordering::Relaxed),
+            m.cooldown_rejections.load(Ordering::Relaxed),
+            m.duplicate_pixels.load(Ordering::Relaxed),
+            m.loop_overruns.load(Ordering::Relaxed),
+            m.conn_memory_bytes.load(Ordering::Relaxed),
+        );
+    }
+
+    let _ = write!(
+        out,
+        "],\"master\":{{\"pixels_applied\":{},\"snapshot_backlog_tiles\":{},\"broadcast_interval_ms\":{},\"diff_only\":{},\"snapshot_rle_bytes\":{},\"admin_pixels_applied\":{},\"epoch\":{}}}",
+        MASTER_METRICS.pixels_applied.load(Ordering::Relaxed),
+        MASTER_METRICS
+            .snapshot_backlog_tiles
+            .load(Ordering::Relaxed),
+        MASTER_METRICS
+            .effective_broadcast_interval_ms
+            .load(Ordering::Relaxed),
+        MASTER_METRICS.diff_only.load(Ordering::Relaxed) == 1,
+        MASTER_METRICS.snapshot_rle_bytes.load(Ordering::Relaxed),
+        MASTER_METRICS.admin_pixels_applied.load(Ordering::Relaxed),
+        crate::epoch::current(),
+    );
+
+    // Churn: tiles ranked by overwrites since the previous stats request.
+    let mut churn = vec![0u32; TILE_COUNT];
+    for (tile, delta) in churn.iter_mut().enumerate() {
+        let total = MASTER_METRICS.tile_overwrites[tile].load(Ordering::Relaxed);
+        *delta = total.wrapping_sub(rates.last_tile_overwrites[tile]);
+        rates.last_tile_overwrites[tile] = total;
+    }
+    let applied = MASTER_METRICS.pixels_applied.load(Ordering::Relaxed);
+    let overwrites = MASTER_METRICS.pixel_overwrites.load(Ordering::Relaxed);
+    let _ = write!(
+        out,
+        ",\"churn\":{{\"overwrites\":{},\"virgin_writes\":{},\"overwrite_ratio\":{:.4},\"top_tiles\":[",
+        overwrites,
+        applied.saturating_sub(overwrites),
+        MASTER_METRICS.overwrite_ratio(),
+    );
+    for (rank, (tile, delta)) in top_tiles(&churn, CHURN_TOP_TILES).into_iter().enumerate() {
+        if rank > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"tile\":{},\"tile_x\":{},\"tile_y\":{},\"overwrites_per_sec\":{:.1}}}",
+            tile,
+            tile % TILES_X,
+            tile / TILES_X,
+            delta as f64 / elapsed
+        );
+    }
+    out.push_str("]}");
+
+    let _ = write!(
+        out,
+        ",\"heatmap\":{{\"tiles_x\":{},\"tiles_y\":{},\"writes\":[",
+        TILES_X, TILES_Y
+    );
+    for tile in 0..TILE_COUNT {
+        if tile > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{}",
+            MASTER_METRICS.tile_writes[tile].load(Ordering::Relaxed)
+        );
+    }
+    out.push_str("]}}");
+    out
+}
+
+type WorkerCounter = fn(&WorkerMetrics) -> &AtomicU64;
+type WorkerHistogram = fn(&WorkerMetrics) -> &LatencyHistogram;
+
+/// Prometheus text exposition of the same counters. Only cumulative values
+/// are exported (rates are left to PromQL), so scrapes never disturb the
+/// `/stats.json` rate tracker.
+fn prometheus_text(num_workers: usize) -> String {
+    let mut out = String::with_capacity(16 * 1024);
+    let worker_counters: [(&str, &str, WorkerCounter); 78] = [
+        ("canvas_worker_pinned", "gauge", |m| &m.pinned),
+        ("canvas_worker_connections", "gauge", |m| &m.connections),
+        ("canvas_worker_pixels_accepted_total", "counter", |m| {
+            &m.pixels_accepted
+        }),
+        ("canvas_worker_spsc_drops_total", "counter", |m| {
+            &m.spsc_drops
+        }),
+        ("canvas_worker_cooldown_rejections_total", "counter", |m| {
+            &m.cooldown_rejections
+        }),
+        ("canvas_worker_duplicate_pixels_total", "counter", |m| {
+            &m.duplicate_pixels
+        }),
+        ("canvas_worker_replayed_pixels_total", "counter", |m| {
+            &m.replayed_pixels
+        }),
+        ("canvas_worker_broadcast_bytes_total", "counter", |m| {
+            &m.broadcast_bytes
+        }),
+        ("canvas_worker_loop_overruns_total", "counter", |m| {
+            &m.loop_overruns
+        }),
+        ("canvas_worker_tx_faults_total", "counter", |m| &m.tx_faults),
+        ("canvas_worker_tx_rebuilds_total", "counter", |m| {
+            &m.tx_rebuilds
+        }),
+        (
+            "canvas_worker_recycled_diff_fallbacks_total",
+            "counter",
+            |m| &m.recycled_diff_fallbacks,
+        ),
+        ("canvas_worker_diff_cap_fallbacks_total", "counter", |m| {
+            &m.diff_cap_fallbacks
+        }),
+        ("canvas_worker_connections_accepted_total", "counter", |m| {
+            &m.connections_accepted
+        }),
+        (
+            "canvas_worker_accept_capacity_rejections_total",
+            "counter",
+            |m| &m.accept_capacity_rejections,
+        ),
+        ("canvas_worker_accept_errors_total", "counter", |m| {
+            &m.accept_errors
+        }),
+        ("canvas_worker_duplicate_initials_total", "counter", |m| {
+            &m.duplicate_initials
+        }),
+        ("canvas_worker_conn_memory_bytes", "gauge", |m| {
+            &m.conn_memory_bytes
+        }),
+        ("canvas_worker_memory_rejections_total", "counter", |m| {
+            &m.memory_rejections
+        }),
+        ("canvas_worker_handshakes_reaped_total", "counter", |m| {
+            &m.handshakes_reaped
+        }),
+        ("canvas_worker_half_open_connections", "gauge", |m| {
+            &m.half_open_connections
+        }),
+        ("canvas_worker_half_open_oldest_ms", "gauge", |m| {
+            &m.half_open_oldest_ms
+        }),
+        ("canvas_worker_handshakes_expired_total", "counter", |m| {
+            &m.handshakes_expired
+        }),
+        ("canvas_worker_half_open_tightened", "gauge", |m| {
+            &m.half_open_tightened
+        }),
+        (
+            "canvas_worker_half_open_tightenings_total",
+            "counter",
+            |m| &m.half_open_tightenings,
+        ),
+        ("canvas_worker_half_open_rejections_total", "counter", |m| {
+            &m.half_open_rejections
+        }),
+        ("canvas_worker_junk_too_short_total", "counter", |m| {
+            &m.junk_too_short
+        }),
+        ("canvas_worker_junk_not_quic_total", "counter", |m| {
+            &m.junk_not_quic
+        }),
+        (
+            "canvas_worker_junk_unsupported_version_total",
+            "counter",
+            |m| &m.junk_unsupported_version,
+        ),
+        ("canvas_worker_junk_unknown_cid_total", "counter", |m| {
+            &m.junk_unknown_cid
+        }),
+        ("canvas_worker_junk_denied_total", "counter", |m| {
+            &m.junk_denied
+        }),
+        ("canvas_worker_junk_denials_total", "counter", |m| {
+            &m.junk_denials
+        }),
+        ("canvas_worker_junk_deny_listed", "gauge", |m| {
+            &m.junk_deny_listed
+        }),
+        ("canvas_worker_stats_replies_total", "counter", |m| {
+            &m.stats_replies
+        }),
+        (
+            "canvas_worker_stats_requests_limited_total",
+            "counter",
+            |m| &m.stats_requests_limited,
+        ),
+        ("canvas_worker_cooldown_nacks_total", "counter", |m| {
+            &m.cooldown_nacks
+        }),
+        (
+            "canvas_worker_cooldown_nacks_limited_total",
+            "counter",
+            |m| &m.cooldown_nacks_limited,
+        ),
+        ("canvas_worker_pixel_acks_total", "counter", |m| {
+            &m.pixel_acks
+        }),
+        ("canvas_worker_unknown_datagrams_total", "counter", |m| {
+            &m.unknown_datagrams
+        }),
+        ("canvas_worker_malformed_datagrams_total", "counter", |m| {
+            &m.malformed_datagrams
+        }),
+        ("canvas_worker_dgram_backlog_connections", "gauge", |m| {
+            &m.dgram_backlog_connections
+        }),
+        (
+            "canvas_worker_dgram_backlog_deferred_total",
+            "counter",
+            |m| &m.dgram_backlog_deferred,
+        ),
+        ("canvas_worker_migrations_total", "counter", |m| {
+            &m.migrations
+        }),
+        ("canvas_worker_migration_failures_total", "counter", |m| {
+            &m.migration_failures
+        }),
+        (
+            "canvas_worker_conn_throttled_chunks_total",
+            "counter",
+            |m| &m.conn_throttled_chunks,
+        ),
+        (
+            "canvas_worker_global_throttle_windows_total",
+            "counter",
+            |m| &m.global_throttle_windows,
+        ),
+        ("canvas_worker_full_syncs_deferred_total", "counter", |m| {
+            &m.full_syncs_deferred
+        }),
+        ("canvas_worker_zstd_diff_raw_bytes_total", "counter", |m| {
+            &m.zstd_diff_raw_bytes
+        }),
+        ("canvas_worker_zstd_diff_wire_bytes_total", "counter", |m| {
+            &m.zstd_diff_wire_bytes
+        }),
+        ("canvas_worker_tier1_connections", "gauge", |m| {
+            &m.tier1_connections
+        }),
+        ("canvas_worker_fast_diff_bytes_total", "counter", |m| {
+            &m.fast_diff_bytes
+        }),
+        ("canvas_worker_fast_diff_laps_total", "counter", |m| {
+            &m.fast_diff_laps
+        }),
+        ("canvas_worker_fec_parity_bytes_total", "counter", |m| {
+            &m.fec_parity_bytes
+        }),
+        (
+            "canvas_worker_full_broadcasts_suppressed_total",
+            "counter",
+            |m| &m.full_broadcasts_suppressed,
+        ),
+        ("canvas_worker_retries_sent_total", "counter", |m| {
+            &m.retries_sent
+        }),
+        (
+            "canvas_worker_retry_token_rejections_total",
+            "counter",
+            |m| &m.retry_token_rejections,
+        ),
+        ("canvas_worker_version_negotiations_total", "counter", |m| {
+            &m.version_negotiations
+        }),
+        ("canvas_worker_buffers_replenished_total", "counter", |m| {
+            &m.buffers_replenished
+        }),
+        ("canvas_worker_replenish_sqes_total", "counter", |m| {
+            &m.replenish_sqes
+        }),
+        ("canvas_worker_recv_exhaustions_total", "counter", |m| {
+            &m.recv_exhaustions
+        }),
+        ("canvas_worker_exhaustion_drops_total", "counter", |m| {
+            &m.exhaustion_drops
+        }),
+        (
+            "canvas_worker_handshake_drops_estimated_total",
+            "counter",
+            |m| &m.handshake_drops_estimated,
+        ),
+        (
+            "canvas_worker_handshake_lane_packets_total",
+            "counter",
+            |m| &m.handshake_lane_packets,
+        ),
+        ("canvas_worker_handshakes_full_total", "counter", |m| {
+            &m.handshakes_full
+        }),
+        ("canvas_worker_handshakes_resumed_total", "counter", |m| {
+            &m.handshakes_resumed
+        }),
+        (
+            "canvas_worker_handshake_recv_full_ns_total",
+            "counter",
+            |m| &m.handshake_recv_full_ns,
+        ),
+        (
+            "canvas_worker_handshake_recv_resumed_ns_total",
+            "counter",
+            |m| &m.handshake_recv_resumed_ns,
+        ),
+        ("canvas_worker_early_data_pixels_total", "counter", |m| {
+            &m.early_data_pixels
+        }),
+        ("canvas_worker_parked_user_ids", "gauge", |m| {
+            &m.parked_user_ids
+        }),
+        ("canvas_worker_resumed_user_ids_total", "counter", |m| {
+            &m.resumed_user_ids
+        }),
+        ("canvas_worker_expired_reservations_total", "counter", |m| {
+            &m.expired_reservations
+        }),
+        ("canvas_worker_quic_handshaking", "gauge", |m| {
+            &m.quic_handshaking
+        }),
+        ("canvas_worker_quic_established", "gauge", |m| {
+            &m.quic_established
+        }),
+        ("canvas_worker_quic_draining", "gauge", |m| &m.quic_draining),
+        ("canvas_worker_quic_packets_sent", "gauge", |m| {
+            &m.quic_packets_sent
+        }),
+        ("canvas_worker_quic_packets_recv", "gauge", |m| {
+            &m.quic_packets_recv
+        }),
+        ("canvas_worker_quic_packets_lost", "gauge", |m| {
+            &m.quic_packets_lost
+        }),
+        ("canvas_worker_quic_packets_retrans", "gauge", |m| {
+            &m.quic_packets_retrans
+        }),
+        ("canvas_worker_quic_rtt_min_us", "gauge", |m| {
+            &m.quic_rtt_min_us
+        }),
+        ("canvas_worker_quic_rtt_median_us", "gauge", |m| {
+            &m.quic_rtt_median_us
+        }),
+        ("canvas_worker_quic_rtt_max_us", "gauge", |m| {
+            &m.quic_rtt_max_us
+        }),
+        ("canvas_worker_quic_cwnd_mean_bytes", "gauge", |m| {
+            &m.quic_cwnd_mean_bytes
+        }),
+    ];
+    for (name, kind, value) in worker_counters {
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (w, m) in WORKER_METRICS[..num_workers].iter().enumerate() {
+            let value = value(m).load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{worker=\"{}\"}} {}", name, w, value);
+        }
+    }
+
+    // Replenishment cost per 1k packets received, since startup.
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_worker_replenish_sqes_per_1k_packets gauge"
+    );
+    for (w, m) in WORKER_METRICS[..num_workers].iter().enumerate() {
+        let buffers = m.buffers_replenished.load(Ordering::Relaxed);
+        let sqes = m.replenish_sqes.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "canvas_worker_replenish_sqes_per_1k_packets{{worker=\"{}\"}} {:.3}",
+            w,
+            if buffers == 0 {
+                0.0
+            } else {
+                sqes as f64 * 1000.0 / buffers as f64
+            }
+        );
+    }
+
+    // Handshake CPU per completed handshake, full and resumed, since
+    // startup.
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_worker_handshake_recv_us_per_handshake gauge"
+    );
+    for (w, m) in WORKER_METRICS[..num_workers].iter().enumerate() {
+        for (kind, spent, handshakes) in [
+            ("full", &m.handshake_recv_full_ns, &m.handshakes_full),
+            (
+                "resumed",
+                &m.handshake_recv_resumed_ns,
+                &m.handshakes_resumed,
+            ),
+        ] {
+            let spent = spent.load(Ordering::Relaxed);
+            let handshakes = handshakes.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "canvas_worker_handshake_recv_us_per_handshake{{worker=\"{}\",kind=\"{}\"}} {:.1}",
+                w,
+                kind,
+                if handshakes == 0 {
+                    0.0
+                } else {
+                    spent as f64 / 1000.0 / handshakes as f64
+                }
+            );
+        }
+    }
+
+    let histograms: [(&str, WorkerHistogram); 2] = [
+        ("canvas_worker_handshake_ms", |m| &m.handshake_ms),
+        ("canvas_worker_first_broadcast_ms", |m| {
+            &m.first_broadcast_ms
+        }),
+    ];
+    for (name, histogram) in histograms {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (w, m) in WORKER_METRICS[..num_workers].iter().enumerate() {
+            let h = histogram(m);
+            let cumulative = h.cumulative();
+            let bounds = h.bounds_ms.iter().map(|b| b.to_string());
+            for (le, count) in bounds.chain(["+Inf".to_string()]).zip(cumulative) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{worker=\"{}\",le=\"{}\"}} {}",
+                    name, w, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_sum{{worker=\"{}\"}} {}\n{}_count{{worker=\"{}\"}} {}",
+                name,
+                w,
+                h.sum_ms.load(Ordering::Relaxed),
+                name,
+                w,
+                h.count.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_master_pinned gauge\ncanvas_master_pinned {}",
+        MASTER_METRICS.pinned.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_accept_steering gauge\ncanvas_accept_steering{{mode=\"{}\"}} 1",
+        crate::steering::current().as_str()
+    );
+    let applied = MASTER_METRICS.pixels_applied.load(Ordering::Relaxed);
+    let overwrites = MASTER_METRICS.pixel_overwrites.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_pixels_applied_total counter\ncanvas_pixels_applied_total {}",
+        applied
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_pixel_overwrites_total counter\ncanvas_pixel_overwrites_total {}",
+        overwrites
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_pixel_virgin_writes_total counter\ncanvas_pixel_virgin_writes_total {}",
+        applied.saturating_sub(overwrites)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_admin_pixels_applied_total counter\ncanvas_admin_pixels_applied_total {}",
+        MASTER_METRICS.admin_pixels_applied.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_epoch gauge\ncanvas_epoch {}",
+        crate::epoch::current()
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_stale_epoch_pixels_total counter\ncanvas_stale_epoch_pixels_total {}",
+        MASTER_METRICS.stale_epoch_pixels.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_overwrite_ratio gauge\ncanvas_overwrite_ratio {:.6}",
+        MASTER_METRICS.overwrite_ratio()
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_snapshot_backlog_tiles gauge\ncanvas_snapshot_backlog_tiles {}",
+        MASTER_METRICS
+            .snapshot_backlog_tiles
+            .load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_broadcast_interval_ms gauge\ncanvas_broadcast_interval_ms {}",
+        MASTER_METRICS
+            .effective_broadcast_interval_ms
+            .load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_pixels_per_sec gauge\ncanvas_pixels_per_sec {}",
+        MASTER_METRICS.pixels_per_sec.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_fast_diff_slots_total counter\ncanvas_fast_diff_slots_total {}",
+        MASTER_METRICS.fast_diff_slots.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_snapshot_rle_bytes gauge\ncanvas_snapshot_rle_bytes {}",
+        MASTER_METRICS.snapshot_rle_bytes.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_diff_only gauge\ncanvas_diff_only {}",
+        MASTER_METRICS.diff_only.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE canvas_diff_only_switches_total counter\ncanvas_diff_only_switches_total {}",
+        MASTER_METRICS.diff_only_switches.load(Ordering::Relaxed)
+    );
+
+    // Per-tile overwrites for every tile that has churned at all; topk() over
+    // rate() of this series gives the fastest-churning tiles.
+    let _ = writeln!(out, "# TYPE canvas_tile_overwrites_total counter");
+    for tile in 0..TILE_COUNT {
+        let count = MASTER_METRICS.tile_overwrites[tile].load(Ordering::Relaxed);
+        if count > 0 {
+            let _ = writeln!(
+                out,
+                "canvas_tile_overwrites_total{{tile_x=\"{}\",tile_y=\"{}\"}} {}",
+                tile % TILES_X,
+                tile / TILES_X,
+                count
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spsc::SpscRingBuffer;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        request(addr, "GET", path)
+    }
+
+    fn request(addr: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn test_dashboard_endpoints() {
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 2, SpscRingBuffer::shared()).unwrap();
+
+        WORKER_METRICS[1]
+            .pixels_accepted
+            .fetch_add(7, Ordering::Relaxed);
+
+        let index = get(addr, "/");
+        assert!(index.starts_with("HTTP/1.1 200 OK"));
+        assert!(index.contains("<canvas"));
+
+        let stats = get(addr, "/stats.json");
+        assert!(stats.starts_with("HTTP/1.1 200 OK"));
+        assert!(stats.contains("application/json"));
+        let body = stats.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.starts_with("{\"workers\":[{\"id\":0,"));
+        assert!(body.contains("{\"id\":1,"));
+        assert!(!body.contains("{\"id\":2,"));
+        assert!(body.contains("\"heatmap\":{\"tiles_x\":16,\"tiles_y\":16"));
+        assert!(body.ends_with("]}}"));
+
+        assert!(body.contains(",\"churn\":{\"overwrites\":"));
+
+        let prom = get(addr, "/metrics");
+        assert!(prom.starts_with("HTTP/1.1 200 OK"));
+        assert!(prom.contains("canvas_worker_pixels_accepted_total{worker=\"1\"}"));
+        assert!(!prom.contains("{worker=\"2\"}"));
+        assert!(prom.contains("# TYPE canvas_overwrite_ratio gauge"));
+        assert!(prom.contains("canvas_worker_first_broadcast_ms_bucket{worker=\"1\",le=\"10\"}"));
+        assert!(prom.contains("canvas_worker_first_broadcast_ms_bucket{worker=\"0\",le=\"+Inf\"}"));
+        assert!(prom.contains("canvas_worker_first_broadcast_ms_count{worker=\"1\"}"));
+        assert!(prom.contains("canvas_worker_handshake_ms_bucket{worker=\"0\",le=\"5\"}"));
+        assert!(prom.contains("# TYPE canvas_worker_half_open_connections gauge"));
+        assert!(prom.contains("canvas_worker_quic_rtt_median_us{worker=\"1\"}"));
+
+        let config = get(addr, "/config.json");
+        assert!(config.starts_with("HTTP/1.1 200 OK"));
+        assert!(config.contains("application/json"));
+
+        let profile = get(addr, "/admin/profile");
+        assert!(profile.starts_with("HTTP/1.1 200 OK"));
+        assert!(profile.contains("{\"worker\":1,\"samples\":"));
+        assert!(request(addr, "POST", "/admin/profile").starts_with("HTTP/1.1 405"));
+
+        let thumb = get(addr, "/thumb.png");
+        assert!(thumb.starts_with("HTTP/1.1 200 OK"));
+        assert!(thumb.contains("Content-Type: image/png"));
+        assert!(thumb.contains("\r\n\r\n\u{fffd}PNG\r\n"));
+
+        assert!(get(addr, "/missing").starts_with("HTTP/1.1 404"));
+        assert!(request(addr, "POST", "/stats.json").starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn test_a_slow_client_holds_the_dashboard_at_most_one_deadline() {
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 1, SpscRingBuffer::shared()).unwrap();
+
+        // One byte of request every 300 ms: no single read ever times out.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let drip = std::thread::spawn(move || {
+            for byte in b"GET /stats.json HTTP/1.1\r\n" {
+                if slow.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(300));
+            }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(get(addr, "/config.json").starts_with("HTTP/1.1 200 OK"));
+        assert!(start.elapsed() < REQUEST_DEADLINE + Duration::from_secs(1));
+        drip.join().unwrap();
+    }
+
+    #[test]
+    fn test_a_stalled_reader_is_given_up_on_at_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        // Far more than the socket buffers hold, to a peer that never reads.
+        let body = vec![0u8; 64 << 20];
+        let start = Instant::now();
+        let written = write_all_by(&mut stream, &body, start + Duration::from_millis(300));
+        assert!(written.is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(reader);
+    }
+
+    #[test]
+    fn test_admin_bandwidth() {
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 1, SpscRingBuffer::shared()).unwrap();
+
+        let set = request(
+            addr,
+            "POST",
+            "/admin/bandwidth?conn_kbps=500&worker_kbps=20000",
+        );
+        assert!(set.starts_with("HTTP/1.1 200 OK"));
+        assert!(set.ends_with("{\"conn_kbps\":500,\"worker_kbps\":20000,\"full_sync_kbps\":0}"));
+        assert_eq!(BANDWIDTH_LIMITS.load().conn_kbps, 500);
+
+        // Keys not given are left alone.
+        let set = request(addr, "POST", "/admin/bandwidth?worker_kbps=0");
+        assert!(set.ends_with("{\"conn_kbps\":500,\"worker_kbps\":0,\"full_sync_kbps\":0}"));
+        assert!(get(addr, "/admin/bandwidth").ends_with("\"worker_kbps\":0,\"full_sync_kbps\":0}"));
+
+        assert!(
+            request(addr, "POST", "/admin/bandwidth?conn_kbps=fast").starts_with("HTTP/1.1 400")
+        );
+        assert!(request(addr, "POST", "/admin/bandwidth?egress=1").starts_with("HTTP/1.1 400"));
+        assert!(request(addr, "DELETE", "/admin/bandwidth").starts_with("HTTP/1.1 405"));
+        assert_eq!(BANDWIDTH_LIMITS.load().conn_kbps, 500);
+        BANDWIDTH_LIMITS.store(Default::default());
+    }
+
+    #[test]
+    fn test_admin_clear() {
+        let admin = SpscRingBuffer::shared();
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 1, admin.clone()).unwrap();
+
+        let queued = request(addr, "POST", "/admin/clear?color=3&x1=10&y0=5&y1=7");
+        assert!(queued.starts_with("HTTP/1.1 202 Accepted"));
+        assert!(queued.ends_with("{\"pixels\":20}"));
+        assert_eq!(admin.pop(), AdminWrite::clear(0, 5, 10, 7, 3));
+        assert!(request(addr, "POST", "/admin/clear?color=0").ends_with("{\"pixels\":1000000}"));
+        assert_eq!(admin.pop().map(|w| w.pixels()), Some(1_000_000));
+
+        for bad in [
+            "",
+            "?color=300",
+            "?color=1&x0=5&x1=5",
+            "?color=1&y1=1001",
+            "?color=1&w=2",
+        ] {
+            let response = request(addr, "POST", &format!("/admin/clear{}", bad));
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", bad);
+        }
+        assert!(get(addr, "/admin/clear").starts_with("HTTP/1.1 405"));
+        assert_eq!(admin.pop(), None);
+    }
+
+    #[test]
+    fn test_admin_epoch() {
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 1, SpscRingBuffer::shared()).unwrap();
+
+        let image = std::env::temp_dir().join(format!("epoch-{}.raw", std::process::id()));
+        std::fs::write(&image, vec![5; CANVAS_WIDTH * CANVAS_HEIGHT]).unwrap();
+        let path = format!("/admin/epoch?image={}", image.display());
+        assert!(request(addr, "POST", &path).starts_with("HTTP/1.1 202 Accepted"));
+        // The later request wins.
+        assert!(request(addr, "POST", "/admin/epoch?color=3").starts_with("HTTP/1.1 202"));
+        let background = crate::epoch::take_request().unwrap();
+        assert!(background.iter().all(|&c| c == 3));
+        assert!(crate::epoch::take_request().is_none());
+
+        std::fs::write(&image, [5; 10]).unwrap();
+        let path = format!("/admin/epoch?image={}", image.display());
+        for bad in ["", "?color=300", "?colour=1", &path[12..]] {
+            let response = request(addr, "POST", &format!("/admin/epoch{}", bad));
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", bad);
+        }
+        std::fs::remove_file(&image).unwrap();
+        assert!(get(addr, "/admin/epoch").starts_with("HTTP/1.1 405"));
+        assert!(crate::epoch::take_request().is_none());
+    }
+
+    #[test]
+    fn test_admin_epoch_image_refused_when_confined() {
+        // Checked before any path is touched: under the filter the open
+        // itself would raise SIGSYS.
+        let (status, _, _) = admin_epoch("POST", "image=/no/such/file.raw", true);
+        assert_eq!(status, "403 Forbidden");
+        let (status, _, _) = admin_epoch("POST", "image=/no/such/file.raw", false);
+        assert_eq!(status, "400 Bad Request");
+    }
+
+    #[test]
+    fn test_admin_audit() {
+        let addr = spawn("127.0.0.1:0".parse().unwrap(), 1, SpscRingBuffer::shared()).unwrap();
+
+        let page = get(
+            addr,
+            "/admin/audit?worker=0&user=3&ip=10.0.0.0/8&x1=10&offset=0&limit=5",
+        );
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(page.contains("\"offset\":0,\"entries\":["));
+        assert!(request(addr, "POST", "/admin/audit").starts_with("HTTP/1.1 202"));
+
+        for bad in [
+            "?user=me",
+            "?ip=10.0.0.0/40",
+            "?limit=0",
+            "?limit=1001",
+            "?account=1",
+        ] {
+            let response = get(addr, &format!("/admin/audit{}", bad));
+            assert!(
+                response.starts_with("HTTP/1.1 400"),
+                "{}: {}",
+                bad,
+                response
+            );
+        }
+        assert!(request(addr, "DELETE", "/admin/audit").starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,319 @@
+//! `--demo <n>`: scripted bot users inside the server process, for
+//! workshops and demos that should show activity with a single command.
+//!
+//! The bots are real QUIC clients (quiche in client mode) on one thread,
+//! talking to the workers over loopback UDP, so the demo exercises the same
+//! path as remote users and doubles as a continuous self-test: each cycle a
+//! bot connects, paints one pixel and waits for the broadcast that follows.
+//! The cooldown is per connection, so a bot reconnects for every pixel; with
+//! a persistent connection the demo would paint one pixel per bot every
+//! five minutes.
+
+use crate::affinity;
+use crate::const_settings::{CANVAS_HEIGHT, CANVAS_WIDTH, PIXEL_DATAGRAM_SIZE};
+use protocol::close::AppCloseCode;
+use protocol::pixel::{self, Pixel};
+use protocol::webtransport::RAW_ALPN;
+use quiche::{Connection, RecvInfo};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Pause between a bot's broadcast (or giving up on it) and its next pixel.
+const PIXEL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A painted pixel with no broadcast within this window counts as missed.
+const BROADCAST_WAIT: Duration = Duration::from_secs(2);
+
+/// Bots sleep this long between polls of their sockets.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How often the self-test prints a summary line.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pixels along one pinwheel arm before it starts over from the center.
+const ARM_PIXELS: u64 = 96;
+
+/// Largest UDP payload a bot sends or receives.
+const MAX_UDP_PAYLOAD: usize = 1350;
+
+/// Pixel `step` of bot `bot` out of `bots`: the bots are the arms of a
+/// pinwheel around the canvas center that turns a little with every pixel.
+/// Each arm has its own color, shifted by one every time it starts over.
+pub fn demo_pixel(bot: usize, bots: usize, step: u64) -> (u16, u16, u8) {
+    let (cx, cy) = (CANVAS_WIDTH as f64 / 2.0, CANVAS_HEIGHT as f64 / 2.0);
+    let max_radius = cx.min(cy) - 1.0;
+    let radius = 8.0 + (step % ARM_PIXELS) as f64 * (max_radius - 8.0) / ARM_PIXELS as f64;
+    let angle = std::f64::consts::TAU * bot as f64 / bots.max(1) as f64 + step as f64 * 0.02;
+    let x = (cx + radius * angle.cos()).clamp(0.0, (CANVAS_WIDTH - 1) as f64);
+    let y = (cy + radius * angle.sin()).clamp(0.0, (CANVAS_HEIGHT - 1) as f64);
+    let color = ((bot as u64 + step / ARM_PIXELS) % 16) as u8;
+    (x as u16, y as u16, color)
+}
+
+/// Running bot thread; dropping it closes every bot connection and joins.
+pub struct DemoBots {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for DemoBots {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Starts `bots` bots against the local server on `port`, on `core` if
+/// there is one to spare.
+pub fn spawn(bots: usize, port: u16, core: Option<usize>) -> std::io::Result<DemoBots> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let handle = std::thread::Builder::new()
+        .name("demo-bots".to_string())
+        .spawn(move || {
+            match core {
+                Some(core) => {
+                    affinity::pin_current("Demo bots", core, false);
+                }
+                None => println!("Demo bots: no spare core, running unpinned"),
+            }
+            run(
+                bots,
+                SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+                &thread_stop,
+            );
+        })?;
+    Ok(DemoBots {
+        stop,
+        handle: Some(handle),
+    })
+}
+
+#[derive(Default)]
+struct DemoStats {
+    painted: u64,
+    broadcasts: u64,
+    missed: u64,
+    /// Connections that closed or timed out without the bot closing them.
+    failed: u64,
+}
+
+impl DemoStats {
+    fn report(&mut self, bots: usize) {
+        println!(
+            "Demo: {} bots, last {}s: {} pixels painted, {} broadcasts seen, {} missed, {} failed connections",
+            bots,
+            REPORT_INTERVAL.as_secs(),
+            self.painted,
+            self.broadcasts,
+            self.missed,
+            self.failed
+        );
+        if self.painted > 0 && self.broadcasts == 0 {
+            println!(
+                "WARNING: demo bots painted but saw no broadcast; the broadcast path is stuck"
+            );
+        }
+        *self = Self::default();
+    }
+}
+
+struct Bot {
+    id: usize,
+    socket: UdpSocket,
+    local: SocketAddr,
+    server: SocketAddr,
+    conn: Connection,
+    step: u64,
+    painted_at: Option<Instant>,
+    next_at: Instant,
+}
+
+impl Bot {
+    fn new(id: usize, server: SocketAddr, config: &mut quiche::Config) -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Failed to bind demo bot");
+        socket.set_nonblocking(true).unwrap();
+        let local = socket.local_addr().unwrap();
+        let conn = connect(local, server, config);
+        Self {
+            id,
+            socket,
+            local,
+            server,
+            conn,
+            // Bots start at different points of their arm so the pinwheel
+            // is not drawn in lockstep.
+            step: id as u64 * 7 % ARM_PIXELS,
+            painted_at: None,
+            next_at: Instant::now(),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        now: Instant,
+        bots: usize,
+        config: &mut quiche::Config,
+        buf: &mut [u8],
+        stats: &mut DemoStats,
+    ) {
+        while let Ok((len, from)) = self.socket.recv_from(buf) {
+            let info = RecvInfo {
+                from,
+                to: self.local,
+            };
+            let _ = self.conn.recv(&mut buf[..len], info);
+        }
+        if self.conn.timeout_instant().is_some_and(|at| at <= now) {
+            self.conn.on_timeout();
+        }
+
+        match self.painted_at {
+            None if self.conn.is_established() && now >= self.next_at => {
+                // Whatever arrived before the pixel says nothing about it.
+                while self.conn.dgram_recv(buf).is_ok() {}
+                let (x, y, color) = demo_pixel(self.id, bots, self.step);
+                let mut record = [0u8; PIXEL_DATAGRAM_SIZE];
+                Pixel { x, y, color }.to_wire(&mut record);
+                if self.conn.dgram_send(&pixel::datagram(&record)).is_ok() {
+                    self.painted_at = Some(now);
+                    self.step += 1;
+                    stats.painted += 1;
+                }
+            }
+            Some(painted_at) => {
+                let mut seen = false;
+                while self.conn.dgram_recv(buf).is_ok() {
+                    seen = true;
+                }
+                if seen || now - painted_at >= BROADCAST_WAIT {
+                    if seen {
+                        stats.broadcasts += 1;
+                    } else {
+                        stats.missed += 1;
+                    }
+                    self.close();
+                    self.flush(buf);
+                    self.reconnect(now, config);
+                    return;
+                }
+            }
+            None => {}
+        }
+
+        self.flush(buf);
+        if self.conn.is_closed() {
+            stats.failed += 1;
+            self.reconnect(now, config);
+        }
+    }
+
+    fn close(&mut self) {
+        let _ = self.conn.close(
+            true,
+            AppCloseCode::ClientExit.code(),
+            AppCloseCode::ClientExit.reason(),
+        );
+    }
+
+    fn flush(&mut self, out: &mut [u8]) {
+        let out = &mut out[..MAX_UDP_PAYLOAD];
+        while let Ok((len, info)) = self.conn.send(out) {
+            let _ = self.socket.send_to(&out[..len], info.to);
+        }
+    }
+
+    fn reconnect(&mut self, now: Instant, config: &mut quiche::Config) {
+        self.conn = connect(self.local, self.server, config);
+        self.painted_at = None;
+        self.next_at = now + PIXEL_INTERVAL;
+    }
+}
+
+fn client_config() -> quiche::Config {
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+    config.set_application_protos(&[RAW_ALPN]).unwrap();
+    // Loopback to ourselves, with a possibly self-signed certificate.
+    config.verify_peer(false);
+    config.set_max_idle_timeout(BROADCAST_WAIT.as_millis() as u64 * 2);
+    config.set_max_recv_udp_payload_size(MAX_UDP_PAYLOAD);
+    config.set_max_send_udp_payload_size(MAX_UDP_PAYLOAD);
+    config.set_initial_max_data(1 << 20);
+    config.enable_dgram(true, 64, 64);
+    config
+}
+
+fn connect(local: SocketAddr, server: SocketAddr, config: &mut quiche::Config) -> Connection {
+    let scid: [u8; quiche::MAX_CONN_ID_LEN] = rand::random();
+    quiche::connect(
+        Some("localhost"),
+        &quiche::ConnectionId::from_ref(&scid),
+        local,
+        server,
+        config,
+    )
+    .expect("Failed to create demo bot connection")
+}
+
+fn run(bots: usize, server: SocketAddr, stop: &AtomicBool) {
+    let mut config = client_config();
+    let mut fleet: Vec<Bot> = (0..bots)
+        .map(|id| Bot::new(id, server, &mut config))
+        .collect();
+    println!("Demo: {} bots painting against {}", bots, server);
+
+    let mut stats = DemoStats::default();
+    let mut report_at = Instant::now() + REPORT_INTERVAL;
+    let mut buf = vec![0u8; 65_535];
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        for bot in fleet.iter_mut() {
+            bot.poll(now, bots, &mut config, &mut buf, &mut stats);
+        }
+        if now >= report_at {
+            stats.report(bots);
+            report_at = now + REPORT_INTERVAL;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    for bot in fleet.iter_mut() {
+        bot.close();
+        bot.flush(&mut buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo_pattern_stays_on_canvas_and_turns() {
+        for bots in [1, 3, 16, 200] {
+            for bot in 0..bots {
+                for step in 0..3 * ARM_PIXELS {
+                    let (x, y, _) = demo_pixel(bot, bots, step);
+                    assert!((x as usize) < CANVAS_WIDTH && (y as usize) < CANVAS_HEIGHT);
+                }
+            }
+        }
+
+        // Arms are spread around the center, each in its own color.
+        let arms: Vec<_> = (0..8).map(|bot| demo_pixel(bot, 8, 40)).collect();
+        for (i, a) in arms.iter().enumerate() {
+            assert!(arms[i + 1..].iter().all(|b| (a.0, a.1) != (b.0, b.1)));
+            assert!(arms[i + 1..].iter().all(|b| a.2 != b.2));
+        }
+
+        // A full sweep later the arm has turned and changed color.
+        let (x0, y0, c0) = demo_pixel(0, 8, 40);
+        let (x1, y1, c1) = demo_pixel(0, 8, 40 + ARM_PIXELS);
+        assert_ne!((x0, y0), (x1, y1));
+        assert_ne!(c0, c1);
+    }
+}
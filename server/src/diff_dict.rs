@@ -0,0 +1,172 @@
+//! Dictionary-compressed diff broadcasts (`--diff-dict`, see
+//! `protocol::dict`). Only connections that negotiated ZSTD get them; the
+//! others keep receiving raw diff chunks.
+//!
+//! Each BROADCAST_CHUNK_SIZE chunk of the diff is packed and compressed on
+//! its own, so compressed datagrams cover the same pixels as raw ones and
+//! losing one costs no more. A chunk that does not shrink goes out raw.
+
+use crate::const_settings::BROADCAST_CHUNK_SIZE;
+use protocol::dict::{DIFF_ZSTD_LEVEL, ZSTD_DIFF_TAG, dict_version, diff_dict, pack_diff};
+use protocol::version::Features;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The dictionary loaded at startup, shared by every worker.
+static DICT: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Loads the dictionary at `path` for every worker. It must be one of the
+/// shipped versions, or clients could not decode what it produces. Returns
+/// its version.
+pub fn load(path: &Path) -> Result<u16, String> {
+    let dict = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let version =
+        dict_version(&dict).ok_or_else(|| format!("{}: not a diff dictionary", path.display()))?;
+    if diff_dict(version) != Some(&dict[..]) {
+        return Err(format!(
+            "{}: version {} is not the shipped dictionary of that version",
+            path.display(),
+            version
+        ));
+    }
+    let _ = DICT.set(dict);
+    Ok(version)
+}
+
+/// ZSTD when a dictionary is loaded, nothing otherwise.
+pub fn features() -> Features {
+    if DICT.get().is_some() {
+        Features::ZSTD
+    } else {
+        Features::NONE
+    }
+}
+
+/// A diff compressed chunk by chunk, ready to send datagram by datagram.
+#[derive(Default)]
+pub struct CompressedDiff {
+    bytes: Vec<u8>,
+    /// End of each datagram in `bytes`, and the raw bytes it stands for.
+    datagrams: Vec<(usize, usize)>,
+}
+
+impl CompressedDiff {
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.datagrams.clear();
+    }
+
+    /// Each datagram with the raw diff bytes it carries.
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], usize)> {
+        let mut start = 0;
+        self.datagrams.iter().map(move |&(end, raw_len)| {
+            let datagram = &self.bytes[start..end];
+            start = end;
+            (datagram, raw_len)
+        })
+    }
+}
+
+/// One worker's compression context.
+pub struct DiffCompressor {
+    compressor: zstd::bulk::Compressor<'static>,
+    packed: Vec<u8>,
+    frame: Vec<u8>,
+}
+
+impl DiffCompressor {
+    /// None unless a dictionary was loaded.
+    pub fn new() -> Option<Self> {
+        let dict = DICT.get()?;
+        Some(Self {
+            compressor: zstd::bulk::Compressor::with_dictionary(DIFF_ZSTD_LEVEL, dict)
+                .expect("Failed to load the diff dictionary into zstd"),
+            packed: Vec::with_capacity(BROADCAST_CHUNK_SIZE),
+            frame: Vec::with_capacity(BROADCAST_CHUNK_SIZE),
+        })
+    }
+
+    /// Compresses the raw diff `diff` into `out`, replacing its contents.
+    pub fn compress(&mut self, diff: &[u8], out: &mut CompressedDiff) {
+        out.clear();
+        for chunk in diff.chunks(BROADCAST_CHUNK_SIZE) {
+            self.packed.clear();
+            pack_diff(chunk, &mut self.packed);
+            self.frame.clear();
+            self.frame
+                .reserve(zstd::zstd_safe::compress_bound(self.packed.len()));
+            let shrunk = self
+                .compressor
+                .compress_to_buffer(&self.packed, &mut self.frame)
+                .is_ok_and(|len| 1 + len < chunk.len());
+            if shrunk {
+                out.bytes.push(ZSTD_DIFF_TAG);
+                out.bytes.extend_from_slice(&self.frame);
+            } else {
+                out.bytes.extend_from_slice(chunk);
+            }
+            out.datagrams.push((out.bytes.len(), chunk.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::dict::{DIFF_DICTS, unpack_diff, zstd_frame};
+
+    fn diff(indices: impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for i in indices {
+            out.extend_from_slice(&i.to_le_bytes());
+            out.push((i % 5) as u8);
+        }
+        out
+    }
+
+    #[test]
+    fn test_compressed_chunks_decode_to_the_raw_diff() {
+        let (version, shipped) = DIFF_DICTS[DIFF_DICTS.len() - 1];
+        let path = std::env::temp_dir().join(format!("canvas-dict-{}", std::process::id()));
+        std::fs::write(&path, b"not a dictionary").unwrap();
+        assert!(load(&path).is_err());
+        let mut foreign = shipped.to_vec();
+        foreign[100] ^= 1;
+        std::fs::write(&path, &foreign).unwrap();
+        assert!(load(&path).is_err());
+        std::fs::write(&path, shipped).unwrap();
+        assert_eq!(load(&path), Ok(version));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(features(), Features::ZSTD);
+
+        // A dense run spanning several chunks, then one lone pixel whose
+        // chunk does not shrink.
+        let raw = diff((0..480).map(|i| 40_000 + i * 2).chain([9_999_999]));
+        let mut compressor = DiffCompressor::new().unwrap();
+        let mut out = CompressedDiff::default();
+        compressor.compress(&raw, &mut out);
+
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(shipped).unwrap();
+        let mut decoded = Vec::new();
+        let mut raw_lens = Vec::new();
+        let mut wire = 0;
+        for (datagram, raw_len) in out.datagrams() {
+            wire += datagram.len();
+            raw_lens.push(raw_len);
+            match zstd_frame(datagram) {
+                Some(frame) => {
+                    let packed = decompressor.decompress(frame, 4096).unwrap();
+                    unpack_diff(&packed, &mut decoded).unwrap();
+                }
+                None => decoded.extend_from_slice(datagram),
+            }
+        }
+        assert_eq!(decoded, raw);
+        assert_eq!(raw_lens, [1200, 1200, 5]);
+        assert_eq!(out.datagrams().last().unwrap().0, &raw[raw.len() - 5..]);
+        assert!(wire < raw.len() / 2);
+
+        compressor.compress(&[], &mut out);
+        assert_eq!(out.datagrams().count(), 0);
+    }
+}
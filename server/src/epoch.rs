@@ -0,0 +1,55 @@
+//! Rounds of the canvas (epochs), so events can run back to back on one
+//! server: `POST /admin/epoch` on the dashboard starts a new one from a
+//! solid color or a background image, with every cooldown lifted.
+//!
+//! The master owns the counter. Between two drains it takes the request,
+//! resets the canvas to the new background, publishes it as a snapshot of
+//! its own and only then bumps EPOCH. A worker checks EPOCH before each
+//! broadcast; on a change it lifts every cooldown (timing_wheel.rs) and
+//! sends the new snapshot in full, announced by an EPOCH datagram
+//! (protocol::epoch) to the connections that negotiated EPOCHS.
+//!
+//! Pixels in flight across the change carry the epoch the worker admitted
+//! them in (`PixelWrite::epoch`). The master drops those from an older one
+//! (`canvas_stale_epoch_pixels_total`): they were charged cooldowns that no
+//! longer exist, and must not paint the new canvas.
+
+use crate::const_settings::CANVAS_SIZE;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// The current epoch, 0 from startup. Stored by the master after the
+/// epoch's first snapshot, so a worker that sees it sees that snapshot.
+static EPOCH: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the dashboard with NEXT filled in; cleared by the master.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// The background of the requested epoch.
+static NEXT: Mutex<Option<Box<[u8; CANVAS_SIZE]>>> = Mutex::new(None);
+
+#[inline]
+pub fn current() -> u32 {
+    EPOCH.load(Ordering::Acquire)
+}
+
+/// Master side: epoch `epoch` has its first snapshot published.
+pub fn publish(epoch: u32) {
+    EPOCH.store(epoch, Ordering::Release);
+}
+
+/// Asks the master for a new epoch starting from `background`. A second
+/// request before the master gets to the first replaces it.
+pub fn request(background: Box<[u8; CANVAS_SIZE]>) {
+    *NEXT.lock().unwrap() = Some(background);
+    REQUESTED.store(true, Ordering::Release);
+}
+
+/// Master side: the background of a requested epoch, if any. One load per
+/// loop iteration when there is none.
+#[inline]
+pub fn take_request() -> Option<Box<[u8; CANVAS_SIZE]>> {
+    if !REQUESTED.load(Ordering::Relaxed) || !REQUESTED.swap(false, Ordering::Acquire) {
+        return None;
+    }
+    NEXT.lock().unwrap().take()
+}
@@ -0,0 +1,326 @@
+//! Latency tiers. A connection that presents a known token in a TIER
+//! request (`--tier1-tokens`) becomes tier 1, up to `--tier1-per-worker`
+//! per worker. On top of the snapshot broadcasts everyone gets, tier-1
+//! connections receive micro-diffs: the master cuts the pixels it applied
+//! into one every `--fast-diff-ms`, publishes it in a ring every worker
+//! reads, and workers forward it right away.
+//!
+//! Micro-diffs use the raw diff format (sorted `u32 index | u8 color`
+//! records, at most BROADCAST_CHUNK_SIZE bytes), so clients apply them like
+//! any diff. Snapshot diffs and full broadcasts stay the source of truth: a
+//! tier-1 client that loses micro-diffs, or sits on a worker the master
+//! lapped, is put right by the next snapshot diff.
+//!
+//! Each slot records how many snapshots had been published when it was cut.
+//! A worker sends a slot only once it has sent that snapshot, and sends
+//! every earlier slot before the next one, so a snapshot diff never takes
+//! a pixel back to a color older than a micro-diff already delivered. A
+//! full broadcast can, for tiles the diff budget deferred; the snapshot
+//! diff that finally carries them fixes it.
+
+use crate::const_settings::{BROADCAST_CHUNK_SIZE, DIFF_ENTRY_SIZE, FAST_RING_LEN};
+use protocol::control::MAX_TIER_TOKEN_LEN;
+use std::cell::UnsafeCell;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+
+/// Tokens that grant tier 1, and how many tier-1 seats each worker has.
+struct Tiers {
+    tokens: Vec<Vec<u8>>,
+    per_worker: usize,
+}
+
+/// Set once at startup; without it nobody is tier 1 and the master cuts no
+/// micro-diffs.
+static TIERS: OnceLock<Tiers> = OnceLock::new();
+
+/// Loads the tier-1 tokens in `path`, one per line (blank lines and `#`
+/// comments skipped). Returns how many there are.
+pub fn load_tokens(path: &Path, per_worker: usize) -> Result<usize, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tokens: Vec<Vec<u8>> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.as_bytes().to_vec())
+        .collect();
+    if tokens.is_empty() {
+        return Err(format!("{}: no tokens", path.display()));
+    }
+    if let Some(long) = tokens.iter().position(|t| t.len() > MAX_TIER_TOKEN_LEN) {
+        return Err(format!(
+            "{}: token {} is longer than {} bytes",
+            path.display(),
+            long + 1,
+            MAX_TIER_TOKEN_LEN
+        ));
+    }
+    let count = tokens.len();
+    let _ = TIERS.set(Tiers { tokens, per_worker });
+    Ok(count)
+}
+
+/// Whether tiers are configured (and the master cuts micro-diffs).
+pub fn enabled() -> bool {
+    TIERS.get().is_some()
+}
+
+/// Tier-1 seats per worker; 0 without tiers.
+pub fn tier1_seats() -> usize {
+    TIERS.get().map_or(0, |t| t.per_worker)
+}
+
+/// Whether `token` is one of the tier-1 tokens.
+pub fn grants_tier1(token: &[u8]) -> bool {
+    TIERS
+        .get()
+        .is_some_and(|t| t.tokens.iter().any(|known| known == token))
+}
+
+struct FastSlot {
+    /// Snapshots published when the slot was cut (`canvas::PUBLISH_SEQ`).
+    snapshot_seq: u64,
+    len: usize,
+    records: [u8; BROADCAST_CHUNK_SIZE],
+}
+
+/// Single producer (the master), any number of readers. Slots are reused
+/// round-robin; a reader checks after copying a slot that the master has
+/// not started rewriting it, like workers do with snapshot pool slots.
+pub struct FastRing {
+    /// Slots published so far; slot `n` lives at `n % FAST_RING_LEN`.
+    published: AtomicU64,
+    slots: [UnsafeCell<FastSlot>; FAST_RING_LEN],
+}
+
+// SAFETY: only the master writes slots, and readers validate what they copied.
+unsafe impl Sync for FastRing {}
+
+impl FastRing {
+    pub const fn new() -> Self {
+        Self {
+            published: AtomicU64::new(0),
+            slots: [const {
+                UnsafeCell::new(FastSlot {
+                    snapshot_seq: 0,
+                    len: 0,
+                    records: [0; BROADCAST_CHUNK_SIZE],
+                })
+            }; FAST_RING_LEN],
+        }
+    }
+
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Acquire)
+    }
+
+    /// Master side: appends one micro-diff of at most BROADCAST_CHUNK_SIZE
+    /// bytes, cut while `snapshot_seq` snapshots were published.
+    pub fn publish(&self, snapshot_seq: u64, records: &[u8]) {
+        let n = self.published.load(Ordering::Relaxed);
+        // Readers that see any of the writes below must also see `n`, so
+        // they know the slot is being rewritten.
+        fence(Ordering::Release);
+        // SAFETY: the master is the only writer.
+        let slot = unsafe { &mut *self.slots[n as usize % FAST_RING_LEN].get() };
+        slot.snapshot_seq = snapshot_seq;
+        slot.len = records.len();
+        slot.records[..records.len()].copy_from_slice(records);
+        self.published.store(n + 1, Ordering::Release);
+    }
+}
+
+impl Default for FastRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static FAST_RING: FastRing = FastRing::new();
+
+/// Whether slot `n` is still intact while `published` slots are out: the
+/// master is already rewriting the slot of `published`.
+#[inline(always)]
+fn slot_retained(n: u64, published: u64) -> bool {
+    published - n < FAST_RING_LEN as u64
+}
+
+/// Master side: collects the pixels applied since the last cut.
+pub struct FastBatcher {
+    interval_ms: u64,
+    last_cut_ms: u64,
+    pixels: Vec<(u32, u8)>,
+    records: Vec<u8>,
+}
+
+impl FastBatcher {
+    pub fn new(interval_ms: u64) -> Self {
+        Self {
+            interval_ms,
+            last_cut_ms: 0,
+            pixels: Vec::with_capacity(BROADCAST_CHUNK_SIZE),
+            records: Vec::with_capacity(BROADCAST_CHUNK_SIZE),
+        }
+    }
+
+    /// A pixel changed color.
+    #[inline(always)]
+    pub fn record(&mut self, index: u32, color: u8) {
+        self.pixels.push((index, color));
+    }
+
+    /// Whether pixels are waiting and the interval is up.
+    #[inline]
+    pub fn due(&self, now_ms: u64) -> bool {
+        !self.pixels.is_empty() && now_ms.wrapping_sub(self.last_cut_ms) >= self.interval_ms
+    }
+
+    /// Publishes what was recorded as micro-diffs, the last color of each
+    /// pixel in index order, chunked like diff broadcasts.
+    pub fn cut(&mut self, ring: &FastRing, snapshot_seq: u64, now_ms: u64) {
+        self.last_cut_ms = now_ms;
+        if self.pixels.is_empty() {
+            return;
+        }
+        // Stable: of several writes to one pixel, the last stays last.
+        self.pixels.sort_by_key(|&(index, _)| index);
+        self.records.clear();
+        for (i, &(index, color)) in self.pixels.iter().enumerate() {
+            if self.pixels.get(i + 1).is_some_and(|next| next.0 == index) {
+                continue;
+            }
+            self.records.extend_from_slice(&index.to_le_bytes());
+            self.records.push(color);
+        }
+        self.pixels.clear();
+        const CHUNK: usize = BROADCAST_CHUNK_SIZE / DIFF_ENTRY_SIZE * DIFF_ENTRY_SIZE;
+        for chunk in self.records.chunks(CHUNK) {
+            ring.publish(snapshot_seq, chunk);
+        }
+    }
+}
+
+/// Worker side: position in the ring.
+pub struct FastReader {
+    next: u64,
+    /// Slots the master overwrote before this worker got to them.
+    pub lapped: u64,
+}
+
+impl FastReader {
+    /// Starts at the ring's head: micro-diffs cut before the worker
+    /// existed are of no use to anyone.
+    pub fn new(ring: &FastRing) -> Self {
+        Self {
+            next: ring.published(),
+            lapped: 0,
+        }
+    }
+
+    /// Skips everything published so far, e.g. with no tier-1 connection
+    /// to send it to.
+    pub fn skip(&mut self, ring: &FastRing) {
+        self.next = ring.published();
+    }
+
+    /// Copies the next micro-diff into `out` (replacing its contents) if
+    /// there is one and it was cut before snapshot `before_seq` was
+    /// published. Returns false otherwise; a slot cut later stays next.
+    pub fn next(&mut self, ring: &FastRing, before_seq: u64, out: &mut Vec<u8>) -> bool {
+        loop {
+            let published = ring.published();
+            if self.next == published {
+                return false;
+            }
+            if !slot_retained(self.next, published) {
+                self.lapped += published - self.next;
+                self.next = published;
+                return false;
+            }
+            // SAFETY: plain data; a slot rewritten while it is copied is
+            // detected below and thrown away.
+            let slot = unsafe { &*ring.slots[self.next as usize % FAST_RING_LEN].get() };
+            let snapshot_seq = slot.snapshot_seq;
+            let len = slot.len.min(BROADCAST_CHUNK_SIZE);
+            out.clear();
+            out.extend_from_slice(&slot.records[..len]);
+            fence(Ordering::Acquire);
+            if !slot_retained(self.next, ring.published.load(Ordering::Relaxed)) {
+                continue;
+            }
+            if snapshot_seq >= before_seq {
+                return false;
+            }
+            self.next += 1;
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(pixels: &[(u32, u8)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &(index, color) in pixels {
+            out.extend_from_slice(&index.to_le_bytes());
+            out.push(color);
+        }
+        out
+    }
+
+    #[test]
+    fn test_micro_diffs_wait_for_their_snapshot() {
+        let ring = Box::new(FastRing::new());
+        let mut reader = FastReader::new(&ring);
+        let mut batcher = FastBatcher::new(10);
+        let mut out = Vec::new();
+
+        assert!(!batcher.due(1_000));
+        batcher.record(70, 1);
+        batcher.record(5, 2);
+        batcher.record(70, 3);
+        assert!(batcher.due(1_000));
+        // Cut while 4 snapshots were out: sorted, last color per pixel.
+        batcher.cut(&ring, 4, 1_000);
+        assert!(!batcher.due(1_005));
+        batcher.record(9, 4);
+        assert!(!batcher.due(1_005));
+        assert!(batcher.due(1_010));
+        batcher.cut(&ring, 5, 1_010);
+
+        // The worker has not sent snapshot 4 yet.
+        assert!(!reader.next(&ring, 4, &mut out));
+        assert!(reader.next(&ring, 5, &mut out));
+        assert_eq!(out, records(&[(5, 2), (70, 3)]));
+        // The second slot follows snapshot 5.
+        assert!(!reader.next(&ring, 5, &mut out));
+        assert!(reader.next(&ring, 6, &mut out));
+        assert_eq!(out, records(&[(9, 4)]));
+        assert!(!reader.next(&ring, u64::MAX, &mut out));
+
+        // A busy interval is cut into broadcast-sized slots.
+        for index in 0..500 {
+            batcher.record(index, 1);
+        }
+        batcher.cut(&ring, 6, 2_000);
+        let mut lens = Vec::new();
+        while reader.next(&ring, 7, &mut out) {
+            lens.push(out.len());
+        }
+        assert_eq!(lens, [1200, 1200, 100]);
+        assert_eq!(reader.lapped, 0);
+
+        // A reader the master laps skips to the head and counts the loss.
+        for _ in 0..FAST_RING_LEN + 3 {
+            ring.publish(7, &records(&[(1, 1)]));
+        }
+        assert!(!reader.next(&ring, 8, &mut out));
+        assert_eq!(reader.lapped, FAST_RING_LEN as u64 + 3);
+        ring.publish(7, &records(&[(2, 2)]));
+        assert!(reader.next(&ring, 8, &mut out));
+        assert_eq!(out, records(&[(2, 2)]));
+    }
+}
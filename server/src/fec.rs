@@ -0,0 +1,113 @@
+//! Parity-protected full broadcasts (`--fec-k`, see `protocol::fec`). Only
+//! connections that negotiated FEC get them; the others keep receiving the
+//! bare RLE chunks.
+//!
+//! A full broadcast is encoded once, for the first FEC connection that
+//! needs it, and the same datagrams go to every FEC connection.
+
+use crate::const_settings::BROADCAST_CHUNK_SIZE;
+use protocol::fec::{FEC_CHUNK_LEN, FEC_HEADER_LEN, MAX_FEC_K, encode_fec};
+use protocol::version::Features;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const _: () = assert!(FEC_HEADER_LEN + FEC_CHUNK_LEN <= BROADCAST_CHUNK_SIZE);
+
+/// Data chunks per parity chunk; 0 = FEC off. Set once at startup.
+static BLOCK_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Turns FEC on with `k` data chunks per parity chunk (0 leaves it off).
+pub fn configure(k: usize) -> Result<(), String> {
+    if k > MAX_FEC_K {
+        return Err(format!(
+            "--fec-k {} is over the maximum of {}",
+            k, MAX_FEC_K
+        ));
+    }
+    BLOCK_CHUNKS.store(k, Ordering::Relaxed);
+    Ok(())
+}
+
+/// FEC when configured, nothing otherwise.
+pub fn features() -> Features {
+    if BLOCK_CHUNKS.load(Ordering::Relaxed) > 0 {
+        Features::FEC
+    } else {
+        Features::NONE
+    }
+}
+
+/// One full broadcast as FEC datagrams.
+#[derive(Default)]
+pub struct FecBroadcast {
+    bytes: Vec<u8>,
+    ends: Vec<usize>,
+    parity_bytes: usize,
+}
+
+impl FecBroadcast {
+    /// Encodes `snapshot`, replacing what was there.
+    pub fn encode(&mut self, snapshot_id: u32, snapshot: &[u8]) {
+        let k = BLOCK_CHUNKS.load(Ordering::Relaxed).max(1);
+        self.bytes.clear();
+        self.ends.clear();
+        encode_fec(snapshot_id, snapshot, k, &mut self.bytes, &mut self.ends);
+        let blocks = snapshot.len().div_ceil(FEC_CHUNK_LEN).div_ceil(k);
+        self.parity_bytes = blocks * (FEC_HEADER_LEN + FEC_CHUNK_LEN);
+    }
+
+    /// Bytes all datagrams take on the wire.
+    pub fn wire_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Of `wire_len`, what the parity datagrams take.
+    pub fn parity_bytes(&self) -> usize {
+        self.parity_bytes
+    }
+
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(&self.ends)
+            .map(|(start, &end)| &self.bytes[start..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::fec::{FecAssembler, decode_fec};
+
+    #[test]
+    fn test_broadcast_round_trip() {
+        assert!(configure(MAX_FEC_K + 1).is_err());
+        configure(4).unwrap();
+        assert_eq!(features(), Features::FEC);
+
+        let snapshot: Vec<u8> = (0..20_000u32).map(|i| (i % 200) as u8 + 1).collect();
+        let mut broadcast = FecBroadcast::default();
+        broadcast.encode(7, &snapshot);
+        // 17 data chunks in 5 blocks.
+        assert_eq!(broadcast.datagrams().count(), 17 + 5);
+        assert_eq!(broadcast.parity_bytes(), 5 * 1200);
+        assert_eq!(
+            broadcast.datagrams().map(<[u8]>::len).sum::<usize>(),
+            broadcast.wire_len()
+        );
+        assert!(
+            broadcast
+                .datagrams()
+                .all(|d| d.len() <= BROADCAST_CHUNK_SIZE)
+        );
+
+        let mut assembler = FecAssembler::default();
+        let mut rebuilt = None;
+        for d in broadcast.datagrams().skip(1) {
+            let (header, payload) = decode_fec(d).unwrap();
+            if let Some(done) = assembler.push(&header, payload) {
+                rebuilt = Some(done.to_vec());
+            }
+        }
+        assert_eq!(rebuilt, Some(snapshot));
+    }
+}
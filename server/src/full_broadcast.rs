@@ -0,0 +1,276 @@
+//! When each worker sends the full canvas (`--full-broadcast-ms`). Full
+//! broadcasts used to go out every 60th snapshot, so their rate followed
+//! the snapshot rate: a canvas that went quiet went without one, one with
+//! `--adaptive-broadcast` got them late, and every worker sent its own in
+//! the same tick. Instead, each worker keeps a wall-clock schedule:
+//!
+//! - the first full broadcast is due `phase_ms(worker_id)` after the worker
+//!   starts, spreading the workers over one interval;
+//! - each full broadcast, scheduled or not (a diff that could not be built
+//!   falls back to one), makes the next one due an interval later;
+//! - a due broadcast goes out with the next snapshot, or on its own with
+//!   the current one if the master has not published since.
+//!
+//! A scheduled full broadcast therefore comes at least an interval after
+//! the previous one, however bursty the snapshots, and at most an interval
+//! plus the wait for the next pass of the worker loop.
+//!
+//! A full broadcast is only worth it while the canvas compresses. Once the
+//! master's RLE of a snapshot grows past `--full-broadcast-max-bytes` (a
+//! canvas gone to noise), [`FullBroadcastGate`] switches to diff-only: the
+//! master raises [`diff_only`], and workers skip the full broadcasts that
+//! come due, the schedule moving on as if they had gone out. Clients keep
+//! getting diffs, and a client that joins or falls behind syncs over its
+//! control stream (TILE_REFRESH). Full broadcasts resume once the RLE is
+//! back under FULL_BROADCAST_RESUME_PCT of the limit.
+
+use crate::const_settings::{FULL_BROADCAST_INTERVAL_MS, FULL_BROADCAST_RESUME_PCT};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Interval between full broadcasts (ms); 0 = only when a diff cannot be
+/// built. Set once at startup.
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(FULL_BROADCAST_INTERVAL_MS);
+
+pub fn configure(interval_ms: u64) {
+    INTERVAL_MS.store(interval_ms, Ordering::Relaxed);
+}
+
+pub fn interval_ms() -> u64 {
+    INTERVAL_MS.load(Ordering::Relaxed)
+}
+
+/// Raised by the master while snapshots are too big to broadcast in full.
+static DIFF_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn diff_only() -> bool {
+    DIFF_ONLY.load(Ordering::Relaxed)
+}
+
+pub fn set_diff_only(on: bool) {
+    DIFF_ONLY.store(on, Ordering::Relaxed);
+}
+
+/// The master's side: whether each snapshot's RLE is small enough to go
+/// out in full.
+pub struct FullBroadcastGate {
+    /// 0 = no limit.
+    max_bytes: usize,
+    diff_only: bool,
+}
+
+impl FullBroadcastGate {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            diff_only: false,
+        }
+    }
+
+    pub fn diff_only(&self) -> bool {
+        self.diff_only
+    }
+
+    /// A snapshot RLE'd to `rle_len` bytes. Returns the new mode when it
+    /// changed (true = diff-only).
+    pub fn observe(&mut self, rle_len: usize) -> Option<bool> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+        let next = if self.diff_only {
+            rle_len > self.max_bytes * FULL_BROADCAST_RESUME_PCT / 100
+        } else {
+            rle_len > self.max_bytes
+        };
+        (next != self.diff_only).then(|| {
+            self.diff_only = next;
+            next
+        })
+    }
+}
+
+/// Offset of worker `worker_id`'s schedule into the interval. Successive
+/// multiples of the golden ratio, so any number of workers comes out
+/// roughly evenly spread without knowing how many there are.
+pub fn phase_ms(worker_id: usize, interval_ms: u64) -> u64 {
+    const GOLDEN: u64 = 0x9E37_79B9_7F4A_7C15;
+    let fraction = (worker_id as u64).wrapping_mul(GOLDEN);
+    ((fraction as u128 * interval_ms as u128) >> 64) as u64
+}
+
+/// One worker's full broadcast schedule.
+pub struct FullBroadcastSchedule {
+    interval_ms: u64,
+    phase_ms: u64,
+    /// u64::MAX until [`start`](Self::start).
+    next_due_ms: u64,
+}
+
+impl FullBroadcastSchedule {
+    /// On the configured interval.
+    pub fn new(worker_id: usize) -> Self {
+        Self::with_interval(interval_ms(), worker_id)
+    }
+
+    pub fn with_interval(interval_ms: u64, worker_id: usize) -> Self {
+        Self {
+            interval_ms,
+            phase_ms: phase_ms(worker_id, interval_ms),
+            next_due_ms: u64::MAX,
+        }
+    }
+
+    /// The worker loop starts at `now_ms`.
+    pub fn start(&mut self, now_ms: u64) {
+        if self.interval_ms > 0 {
+            self.next_due_ms = now_ms + self.phase_ms;
+        }
+    }
+
+    #[inline(always)]
+    pub fn due(&self, now_ms: u64) -> bool {
+        now_ms >= self.next_due_ms
+    }
+
+    /// When the next full broadcast is due; u64::MAX = none scheduled.
+    pub fn next_due_ms(&self) -> u64 {
+        self.next_due_ms
+    }
+
+    /// A full broadcast went out at `now_ms`.
+    pub fn sent(&mut self, now_ms: u64) {
+        if self.interval_ms > 0 {
+            self.next_due_ms = now_ms + self.interval_ms;
+        }
+    }
+
+    /// A full broadcast was due at `now_ms` but skipped (diff-only); the
+    /// next one is due an interval later, as if it had gone out.
+    pub fn skipped(&mut self, now_ms: u64) {
+        self.sent(now_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: u64 = 6_000;
+    /// Worker loop passes, as in a quiet worker.
+    const LOOP_MS: u64 = 5;
+
+    /// Runs a worker loop for `duration_ms` against snapshots published at
+    /// `published` (sorted, ms) and returns when full broadcasts went out
+    /// and with how many snapshots each.
+    fn run(worker_id: usize, published: &[u64], duration_ms: u64) -> Vec<(u64, usize)> {
+        let mut schedule = FullBroadcastSchedule::with_interval(INTERVAL, worker_id);
+        schedule.start(0);
+        let mut seen = 0;
+        let mut fulls = Vec::new();
+        for now in (0..duration_ms).step_by(LOOP_MS as usize) {
+            // Snapshots that came in since the last pass are sent as one,
+            // like handle_broadcast does.
+            while seen < published.len() && published[seen] <= now {
+                seen += 1;
+            }
+            if schedule.due(now) {
+                schedule.sent(now);
+                fulls.push((now, seen));
+            }
+        }
+        fulls
+    }
+
+    fn assert_interval_holds(fulls: &[(u64, usize)]) {
+        for pair in fulls.windows(2) {
+            let gap = pair[1].0 - pair[0].0;
+            assert!(
+                (INTERVAL..INTERVAL + LOOP_MS).contains(&gap),
+                "full broadcasts {} ms apart",
+                gap
+            );
+        }
+    }
+
+    #[test]
+    fn test_idle_canvas_still_gets_full_broadcasts() {
+        // Nothing published after startup.
+        let fulls = run(0, &[0], 60_000);
+        assert_eq!(fulls.len(), 10);
+        assert_eq!(fulls[0].0, 0);
+        assert_interval_holds(&fulls);
+        assert!(fulls.iter().all(|&(_, seen)| seen == 1));
+    }
+
+    #[test]
+    fn test_bursty_snapshots_keep_the_interval() {
+        // Bursts of 50 snapshots 1 ms apart, separated by 0.2 to 18 s of
+        // silence.
+        let mut published = Vec::new();
+        let mut at = 0;
+        for burst in 0..20u64 {
+            published.extend((0..50).map(|i| at + i));
+            at += 50 + 200 + burst * burst * 50;
+        }
+        let fulls = run(3, &published, at);
+        let first = phase_ms(3, INTERVAL);
+        assert!((first..first + LOOP_MS).contains(&fulls[0].0));
+        assert_interval_holds(&fulls);
+        assert_eq!(fulls.len() as u64, (at - fulls[0].0).div_ceil(INTERVAL));
+        // Both in the middle of bursts and in the silences.
+        assert!(fulls.windows(2).any(|pair| pair[1].1 - pair[0].1 >= 100));
+        assert!(fulls.windows(2).any(|pair| pair[1].1 == pair[0].1));
+    }
+
+    #[test]
+    fn test_fallback_full_broadcast_pushes_the_schedule_back() {
+        let mut schedule = FullBroadcastSchedule::with_interval(INTERVAL, 0);
+        schedule.start(1_000);
+        assert!(schedule.due(1_000));
+        schedule.sent(1_000);
+        // A diff that could not be built went out full halfway through.
+        schedule.sent(4_000);
+        assert!(!schedule.due(9_999));
+        assert!(schedule.due(10_000));
+
+        // 0 turns the schedule off.
+        let mut off = FullBroadcastSchedule::with_interval(0, 2);
+        off.start(1_000);
+        off.sent(1_000);
+        assert!(!off.due(u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_gate_switches_with_hysteresis() {
+        const MAX: usize = 256 * 1024;
+        let mut gate = FullBroadcastGate::new(MAX);
+        assert_eq!(gate.observe(MAX), None);
+        assert_eq!(gate.observe(MAX + 1), Some(true));
+        assert_eq!(gate.observe(2 * MAX), None);
+        assert!(gate.diff_only());
+        // Back under the limit is not enough to resume.
+        assert_eq!(gate.observe(MAX), None);
+        assert_eq!(gate.observe(MAX * 3 / 4), Some(false));
+
+        // 0 never suspends.
+        let mut off = FullBroadcastGate::new(0);
+        assert_eq!(off.observe(usize::MAX), None);
+
+        // Skipped broadcasts keep the interval.
+        let mut schedule = FullBroadcastSchedule::with_interval(INTERVAL, 0);
+        schedule.start(0);
+        schedule.skipped(0);
+        assert!(!schedule.due(INTERVAL - 1));
+        assert!(schedule.due(INTERVAL));
+    }
+
+    #[test]
+    fn test_workers_are_spread_over_the_interval() {
+        let mut phases: Vec<u64> = (0..8).map(|w| phase_ms(w, INTERVAL)).collect();
+        phases.sort_unstable();
+        assert_eq!(phases[0], 0);
+        for pair in phases.windows(2) {
+            assert!(pair[1] - pair[0] >= INTERVAL / 16, "{:?}", phases);
+        }
+        assert!(phases.iter().all(|&p| p < INTERVAL));
+    }
+}
@@ -0,0 +1,279 @@
+//! Connections whose QUIC handshake has not completed (half-open), and the
+//! admission that tightens when too many pile up.
+//!
+//! A flood of Initials that never finish the handshake costs the worker a
+//! user id and a connection's worth of state each, for as long as the QUIC
+//! idle timeout (`--idle-timeout-ms`) takes to get rid of them. So:
+//!
+//! - every connection is tracked from accept until `is_established()`, with
+//!   the peer address it came from; the time it took to establish goes into
+//!   the `handshake_ms` histogram;
+//! - each connection sweep drops those still handshaking
+//!   `--handshake-deadline-ms` after their Initial, freeing their user ids
+//!   (a real client finishes in a few round trips);
+//! - once `--half-open-threshold` connections are half-open, admission
+//!   tightens: no new connection while the count is at the threshold, and
+//!   at most HALF_OPEN_PER_IP_TIGHT half-open per peer address. Refused
+//!   Initials are dropped unread: answering would cost the TLS work the
+//!   flood is made of. Admission relaxes once the count is back to half
+//!   the threshold, so it does not flap at the boundary.
+//!
+//! Both are per worker; 0 turns either off.
+
+use crate::const_settings::{HALF_OPEN_PER_IP_TIGHT, HALF_OPEN_THRESHOLD, HANDSHAKE_DEADLINE_MS};
+use crate::metrics::WorkerMetrics;
+use rustc_hash::FxHashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+
+/// Handshake deadline and tightening threshold; 0 = none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HalfOpenLimits {
+    pub deadline_ms: u64,
+    pub threshold: usize,
+}
+
+impl HalfOpenLimits {
+    pub const OFF: Self = Self {
+        deadline_ms: 0,
+        threshold: 0,
+    };
+}
+
+impl Default for HalfOpenLimits {
+    fn default() -> Self {
+        Self {
+            deadline_ms: HANDSHAKE_DEADLINE_MS,
+            threshold: HALF_OPEN_THRESHOLD,
+        }
+    }
+}
+
+/// One worker's half-open connections, by user id.
+pub struct HalfOpen {
+    limits: HalfOpenLimits,
+    metrics: &'static WorkerMetrics,
+    /// Accept time (ms) and peer address.
+    pending: FxHashMap<u32, (u64, IpAddr)>,
+    per_ip: FxHashMap<IpAddr, usize>,
+    tightened: bool,
+}
+
+impl HalfOpen {
+    pub fn new(limits: HalfOpenLimits, metrics: &'static WorkerMetrics) -> Self {
+        Self {
+            limits,
+            metrics,
+            pending: FxHashMap::default(),
+            per_ip: FxHashMap::default(),
+            tightened: false,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn tightened(&self) -> bool {
+        self.tightened
+    }
+
+    /// Whether a new connection from `ip` may be accepted.
+    #[inline]
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        !self.tightened
+            || (self.pending.len() < self.limits.threshold
+                && self.per_ip.get(&ip).copied().unwrap_or(0) < HALF_OPEN_PER_IP_TIGHT)
+    }
+
+    pub fn accepted(&mut self, user_id: u32, ip: IpAddr, now_ms: u64) {
+        if let Some((_, previous)) = self.pending.insert(user_id, (now_ms, ip)) {
+            self.forget_ip(previous);
+        }
+        *self.per_ip.entry(ip).or_default() += 1;
+        self.update();
+    }
+
+    /// The connection of `user_id` completed its handshake. Cheap to call
+    /// on every packet of an established connection.
+    #[inline]
+    pub fn established(&mut self, user_id: u32, now_ms: u64) {
+        if let Some((accepted_ms, ip)) = self.pending.remove(&user_id) {
+            self.metrics
+                .handshake_ms
+                .record(now_ms.saturating_sub(accepted_ms));
+            self.forget_ip(ip);
+            self.update();
+        }
+    }
+
+    /// The connection of `user_id` is gone, established or not.
+    pub fn removed(&mut self, user_id: u32) {
+        if let Some((_, ip)) = self.pending.remove(&user_id) {
+            self.forget_ip(ip);
+            self.update();
+        }
+    }
+
+    /// Whether the connection of `user_id` is past the handshake deadline.
+    pub fn expired(&self, user_id: u32, now_ms: u64) -> bool {
+        self.limits.deadline_ms != 0
+            && self.pending.get(&user_id).is_some_and(|&(accepted_ms, _)| {
+                now_ms.saturating_sub(accepted_ms) >= self.limits.deadline_ms
+            })
+    }
+
+    /// Publishes the age of the oldest half-open connection; once per
+    /// sweep, after the expired ones are gone.
+    pub fn sampled(&self, now_ms: u64) {
+        let oldest = self
+            .pending
+            .values()
+            .map(|&(accepted_ms, _)| now_ms.saturating_sub(accepted_ms))
+            .max()
+            .unwrap_or(0);
+        self.metrics
+            .half_open_oldest_ms
+            .store(oldest, Ordering::Relaxed);
+    }
+
+    fn forget_ip(&mut self, ip: IpAddr) {
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let count = self.pending.len();
+        self.metrics
+            .half_open_connections
+            .store(count as u64, Ordering::Relaxed);
+        let threshold = self.limits.threshold;
+        if threshold == 0 {
+            return;
+        }
+        if !self.tightened && count >= threshold {
+            self.tightened = true;
+            self.metrics
+                .half_open_tightenings
+                .fetch_add(1, Ordering::Relaxed);
+            println!(
+                "Warning: {} half-open connections, tightening admission to {} per address",
+                count, HALF_OPEN_PER_IP_TIGHT
+            );
+        } else if self.tightened && count <= threshold / 2 {
+            self.tightened = false;
+            println!("{} half-open connections, admission back to normal", count);
+        } else {
+            return;
+        }
+        self.metrics
+            .half_open_tightened
+            .store(self.tightened as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::MAX_WORKERS;
+    use crate::metrics::WORKER_METRICS;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_deadline_expires_only_handshaking_connections() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 7];
+        let mut half_open = HalfOpen::new(
+            HalfOpenLimits {
+                deadline_ms: 10_000,
+                threshold: 0,
+            },
+            metrics,
+        );
+        half_open.accepted(1, ip(1), 1_000);
+        half_open.accepted(2, ip(1), 4_000);
+        half_open.accepted(3, ip(2), 4_000);
+        half_open.established(3, 4_120);
+        assert_eq!(half_open.count(), 2);
+        assert_eq!(metrics.half_open_connections.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.handshake_ms.count.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.handshake_ms.sum_ms.load(Ordering::Relaxed), 120);
+
+        assert!(!half_open.expired(1, 10_999));
+        assert!(half_open.expired(1, 11_000));
+        assert!(!half_open.expired(2, 11_000));
+        // Established or unknown ids never expire.
+        assert!(!half_open.expired(3, u64::MAX));
+        assert!(!half_open.expired(9, u64::MAX));
+        half_open.sampled(11_000);
+        assert_eq!(metrics.half_open_oldest_ms.load(Ordering::Relaxed), 10_000);
+
+        half_open.removed(1);
+        half_open.sampled(11_000);
+        assert_eq!(metrics.half_open_oldest_ms.load(Ordering::Relaxed), 7_000);
+        half_open.removed(2);
+        assert_eq!(half_open.count(), 0);
+        assert!(half_open.per_ip.is_empty());
+
+        // 0 keeps them forever.
+        let mut forever = HalfOpen::new(HalfOpenLimits::OFF, metrics);
+        forever.accepted(1, ip(1), 0);
+        assert!(!forever.expired(1, u64::MAX));
+    }
+
+    #[test]
+    fn test_threshold_tightens_until_the_count_recedes() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 8];
+        let tightenings = metrics.half_open_tightenings.load(Ordering::Relaxed);
+        let mut half_open = HalfOpen::new(
+            HalfOpenLimits {
+                deadline_ms: 10_000,
+                threshold: 40,
+            },
+            metrics,
+        );
+        // A flood from one address, and a few clients of its own each.
+        for user_id in 0..36 {
+            assert!(half_open.admits(ip(66)));
+            half_open.accepted(user_id, ip(66), 0);
+        }
+        for user_id in 36..39 {
+            half_open.accepted(user_id, ip(user_id as u8), 0);
+        }
+        assert!(!half_open.tightened());
+        half_open.accepted(39, ip(39), 0);
+        assert!(half_open.tightened());
+        assert_eq!(
+            metrics.half_open_tightenings.load(Ordering::Relaxed),
+            tightenings + 1
+        );
+
+        // At the threshold nothing gets in; below it the flooding address
+        // still does not, other addresses do.
+        assert!(!half_open.admits(ip(1)));
+        half_open.removed(0);
+        assert!(!half_open.admits(ip(66)));
+        assert!(half_open.admits(ip(1)));
+
+        // Reaping the flood down to half the threshold relaxes admission.
+        for user_id in 1..19 {
+            half_open.removed(user_id);
+            assert!(half_open.tightened());
+        }
+        half_open.removed(19);
+        assert_eq!(half_open.count(), 20);
+        assert!(!half_open.tightened());
+        assert!(half_open.admits(ip(66)));
+        assert_eq!(metrics.half_open_tightened.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            metrics.half_open_tightenings.load(Ordering::Relaxed),
+            tightenings + 1
+        );
+    }
+}
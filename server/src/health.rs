@@ -0,0 +1,136 @@
+use crate::const_settings::{
+    INTERVAL_RECOVERY_STREAK, INTERVAL_STRETCH_STREAK, LOOP_OVERRUN_THRESHOLD_PCT,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntervalChange {
+    Unchanged,
+    Stretched,
+    Recovered,
+}
+
+/// Feedback controller for the master's broadcast interval: stretches it while
+/// workers keep overrunning their loop budget and walks it back once they recover.
+/// The effective interval is published in MASTER_METRICS; workers push it to
+/// connections that negotiated INTERVALS (`TransportState::refresh_interval`).
+pub struct IntervalController {
+    base_ms: u64,
+    max_ms: u64,
+    current_ms: u64,
+    overloaded_streak: u32,
+    healthy_streak: u32,
+    last_iterations: u64,
+    last_overruns: u64,
+}
+
+impl IntervalController {
+    pub fn new(base_ms: u64, max_ms: u64) -> Self {
+        Self {
+            base_ms,
+            max_ms: max_ms.max(base_ms),
+            current_ms: base_ms,
+            overloaded_streak: 0,
+            healthy_streak: 0,
+            last_iterations: 0,
+            last_overruns: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn current_ms(&self) -> u64 {
+        self.current_ms
+    }
+
+    /// Feeds the cumulative worker loop counters, sampled once per broadcast interval.
+    pub fn observe(&mut self, iterations: u64, overruns: u64) -> IntervalChange {
+        let d_iter = iterations.wrapping_sub(self.last_iterations);
+        let d_over = overruns.wrapping_sub(self.last_overruns);
+        self.last_iterations = iterations;
+        self.last_overruns = overruns;
+
+        // No iterations means idle workers blocked in io_uring, not saturation.
+        let overloaded = d_iter > 0 && d_over * 100 >= d_iter * LOOP_OVERRUN_THRESHOLD_PCT;
+
+        if overloaded {
+            self.healthy_streak = 0;
+            self.overloaded_streak += 1;
+            if self.overloaded_streak >= INTERVAL_STRETCH_STREAK && self.current_ms < self.max_ms {
+                self.overloaded_streak = 0;
+                self.current_ms = (self.current_ms * 3 / 2).min(self.max_ms);
+                return IntervalChange::Stretched;
+            }
+        } else {
+            self.overloaded_streak = 0;
+            self.healthy_streak += 1;
+            if self.healthy_streak >= INTERVAL_RECOVERY_STREAK && self.current_ms > self.base_ms {
+                self.healthy_streak = 0;
+                self.current_ms = self
+                    .current_ms
+                    .saturating_sub(self.base_ms / 2)
+                    .max(self.base_ms);
+                return IntervalChange::Recovered;
+            }
+        }
+        IntervalChange::Unchanged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stretches_under_sustained_overload_and_caps() {
+        let mut ctrl = IntervalController::new(100, 500);
+        let (mut it, mut ov) = (0, 0);
+        let mut stretches = 0;
+        for _ in 0..100 {
+            it += 10;
+            ov += 5;
+            if ctrl.observe(it, ov) == IntervalChange::Stretched {
+                stretches += 1;
+            }
+        }
+        assert_eq!(ctrl.current_ms(), 500);
+        // 100 -> 150 -> 225 -> 337 -> 500
+        assert_eq!(stretches, 4);
+    }
+
+    #[test]
+    fn test_transient_overload_does_not_stretch() {
+        let mut ctrl = IntervalController::new(100, 500);
+        let (mut it, mut ov) = (0, 0);
+        for i in 0..50 {
+            it += 10;
+            if i % INTERVAL_STRETCH_STREAK == 0 {
+                ov += 10;
+            }
+            assert_eq!(ctrl.observe(it, ov), IntervalChange::Unchanged);
+        }
+        assert_eq!(ctrl.current_ms(), 100);
+    }
+
+    #[test]
+    fn test_recovers_gradually_to_base() {
+        let mut ctrl = IntervalController::new(100, 500);
+        let (mut it, mut ov) = (0, 0);
+        for _ in 0..100 {
+            it += 10;
+            ov += 10;
+            ctrl.observe(it, ov);
+        }
+        assert_eq!(ctrl.current_ms(), 500);
+
+        for _ in 0..INTERVAL_RECOVERY_STREAK {
+            it += 10;
+            ctrl.observe(it, ov);
+        }
+        assert_eq!(ctrl.current_ms(), 450);
+
+        for _ in 0..INTERVAL_RECOVERY_STREAK * 20 {
+            it += 10;
+            ctrl.observe(it, ov);
+        }
+        assert_eq!(ctrl.current_ms(), 100);
+    }
+}
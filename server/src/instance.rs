@@ -0,0 +1,214 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+const LOCK_PREFIX: &str = "canvas-";
+const LOCK_SUFFIX: &str = ".lock";
+
+/// Parses a Linux-style CPU list ("0,2-4,7") into core ids, preserving order.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cores = Vec::new();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid core id {:?} in cpu list {:?}", v, s))
+        };
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+                if lo > hi {
+                    return Err(format!("invalid core range {:?} in cpu list {:?}", part, s));
+                }
+                cores.extend(lo..=hi);
+            }
+            None => cores.push(parse(part)?),
+        }
+    }
+    if cores.is_empty() {
+        return Err(format!("empty cpu list {:?}", s));
+    }
+    Ok(cores)
+}
+
+/// What a running instance advertises to its siblings in its lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceClaim {
+    pub name: String,
+    pub pid: u32,
+    pub port: u16,
+    pub cores: BTreeSet<usize>,
+}
+
+impl InstanceClaim {
+    fn serialize(&self) -> String {
+        let cores: Vec<String> = self.cores.iter().map(|c| c.to_string()).collect();
+        format!(
+            "name={}\npid={}\nport={}\ncores={}\n",
+            self.name,
+            self.pid,
+            self.port,
+            cores.join(",")
+        )
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut name = None;
+        let mut pid = None;
+        let mut port = None;
+        let mut cores = None;
+        for line in contents.lines() {
+            match line.split_once('=')? {
+                ("name", v) => name = Some(v.to_string()),
+                ("pid", v) => pid = v.parse().ok(),
+                ("port", v) => port = v.parse().ok(),
+                ("cores", v) => cores = parse_cpu_list(v).ok().map(|c| c.into_iter().collect()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            name: name?,
+            pid: pid?,
+            port: port?,
+            cores: cores?,
+        })
+    }
+}
+
+/// Lock file advertising this instance's port and core set; removed on drop.
+pub struct InstanceLock {
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Refuses to start if a live sibling instance already claims the same
+    /// name, the same port (SO_REUSEPORT would silently split its traffic),
+    /// or any of the requested cores. Lock files of dead processes are ignored.
+    pub fn acquire(lock_dir: &Path, claim: &InstanceClaim) -> Result<Self, String> {
+        std::fs::create_dir_all(lock_dir)
+            .map_err(|e| format!("cannot create lock dir {}: {}", lock_dir.display(), e))?;
+
+        let entries = std::fs::read_dir(lock_dir)
+            .map_err(|e| format!("cannot read lock dir {}: {}", lock_dir.display(), e))?;
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if !file_name.starts_with(LOCK_PREFIX) || !file_name.ends_with(LOCK_SUFFIX) {
+                continue;
+            }
+            let Some(other) = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|c| InstanceClaim::parse(&c))
+            else {
+                continue;
+            };
+            if !process_alive(other.pid) {
+                continue;
+            }
+            if other.name == claim.name {
+                return Err(format!(
+                    "instance {:?} is already running (pid {})",
+                    other.name, other.pid
+                ));
+            }
+            if other.port == claim.port {
+                return Err(format!(
+                    "port {} is already used by instance {:?} (pid {})",
+                    claim.port, other.name, other.pid
+                ));
+            }
+            let overlap: Vec<usize> = claim.cores.intersection(&other.cores).copied().collect();
+            if !overlap.is_empty() {
+                return Err(format!(
+                    "cores {:?} are already pinned by instance {:?} (pid {}); pass a disjoint --cpu-list",
+                    overlap, other.name, other.pid
+                ));
+            }
+        }
+
+        let path = lock_dir.join(format!("{}{}{}", LOCK_PREFIX, claim.name, LOCK_SUFFIX));
+        std::fs::write(&path, claim.serialize())
+            .map_err(|e| format!("cannot write lock file {}: {}", path.display(), e))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 performs the permission and existence checks only.
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(name: &str, port: u16, cores: &[usize]) -> InstanceClaim {
+        InstanceClaim {
+            name: name.to_string(),
+            pid: std::process::id(),
+            port,
+            cores: cores.iter().copied().collect(),
+        }
+    }
+
+    fn temp_lock_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("canvas-lock-test-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0,2-4,7").unwrap(), vec![0, 2, 3, 4, 7]);
+        assert_eq!(parse_cpu_list(" 5 ").unwrap(), vec![5]);
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("4-2").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_two_disjoint_instances_coexist() {
+        let dir = temp_lock_dir("disjoint");
+        let a = InstanceLock::acquire(&dir, &claim("a", 4433, &[0, 1, 2])).unwrap();
+        let b = InstanceLock::acquire(&dir, &claim("b", 4434, &[3, 4, 5])).unwrap();
+        drop((a, b));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_overlapping_cores_or_port_refused() {
+        let dir = temp_lock_dir("overlap");
+        let a = InstanceLock::acquire(&dir, &claim("a", 4433, &[0, 1, 2])).unwrap();
+
+        let err = InstanceLock::acquire(&dir, &claim("b", 4434, &[2, 3]))
+            .err()
+            .unwrap();
+        assert!(err.contains("[2]"), "{}", err);
+        assert!(InstanceLock::acquire(&dir, &claim("b", 4433, &[3])).is_err());
+        assert!(InstanceLock::acquire(&dir, &claim("a", 4435, &[7])).is_err());
+
+        // Released on drop.
+        drop(a);
+        let _b = InstanceLock::acquire(&dir, &claim("b", 4434, &[2, 3])).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stale_lock_ignored() {
+        let dir = temp_lock_dir("stale");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut dead = claim("dead", 4433, &[0]);
+        dead.pid = i32::MAX as u32;
+        std::fs::write(dir.join("canvas-dead.lock"), dead.serialize()).unwrap();
+
+        let _a = InstanceLock::acquire(&dir, &claim("a", 4433, &[0])).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
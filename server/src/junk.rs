@@ -0,0 +1,291 @@
+//! Inbound UDP that reaches no connection and may not start one: port
+//! scans, probes for other QUIC versions, stray traffic. Every such packet
+//! is classified and counted ([`JunkKind`]), at the cost of a few byte
+//! compares and no log line.
+//!
+//! With `--strict-junk` a source address that sends `--junk-threshold`
+//! junk packets within JUNK_WINDOW_MS goes on a deny list for
+//! `--junk-deny-ttl-ms`; its packets are then dropped before any QUIC
+//! parsing. The list holds at most `--junk-deny-max` addresses, so a
+//! spoofed flood cannot grow it without bound; once full, new offenders are
+//! only counted until entries expire.
+//!
+//! Packets for an unknown connection id do not count towards a denial:
+//! leftovers of connections this worker closed, and peers migrated onto it
+//! (see the SO_REUSEPORT caveat in transport.rs), look exactly like that.
+//!
+//! Per worker, like everything else on the receive path.
+
+use crate::const_settings::{
+    JUNK_DENY_LIST_MAX, JUNK_DENY_THRESHOLD, JUNK_DENY_TTL_MS, JUNK_OFFENDERS_MAX, JUNK_WINDOW_MS,
+};
+use crate::metrics::WorkerMetrics;
+use rustc_hash::FxHashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Shortest packet that can be QUIC addressed to this server: short
+/// headers carry one of our MAX_CONN_ID_LEN ids, and long headers need more
+/// than that for the version, ids and an AEAD tag.
+pub const MIN_QUIC_PACKET_LEN: usize = 1 + quiche::MAX_CONN_ID_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JunkKind {
+    /// Shorter than MIN_QUIC_PACKET_LEN.
+    TooShort,
+    /// A header no QUIC version sends: fixed bit clear, or a long header
+    /// whose connection id length is out of range.
+    NotQuic,
+    /// A long header of a version quiche does not speak.
+    UnsupportedVersion,
+    /// Well-formed, but for no connection on this worker, and not an
+    /// Initial that could start one.
+    UnknownCid,
+}
+
+/// Why a packet that reached no connection and started none was dropped.
+pub fn classify(buf: &[u8]) -> JunkKind {
+    if buf.len() < MIN_QUIC_PACKET_LEN {
+        return JunkKind::TooShort;
+    }
+    let first = buf[0];
+    if first & 0x80 != 0 {
+        // Long header: the version says how to read the rest.
+        let version = u32::from_be_bytes(buf[1..5].try_into().unwrap());
+        if buf[5] as usize > quiche::MAX_CONN_ID_LEN {
+            return JunkKind::NotQuic;
+        }
+        if !quiche::version_is_supported(version) {
+            return JunkKind::UnsupportedVersion;
+        }
+    }
+    if first & 0x40 == 0 {
+        return JunkKind::NotQuic;
+    }
+    JunkKind::UnknownCid
+}
+
+/// Deny list settings. `strict: false` only counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JunkLimits {
+    pub strict: bool,
+    /// Junk packets within JUNK_WINDOW_MS that deny a source.
+    pub threshold: u32,
+    pub deny_ttl_ms: u64,
+    /// Addresses on the deny list at once.
+    pub deny_max: usize,
+}
+
+impl Default for JunkLimits {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            threshold: JUNK_DENY_THRESHOLD,
+            deny_ttl_ms: JUNK_DENY_TTL_MS,
+            deny_max: JUNK_DENY_LIST_MAX,
+        }
+    }
+}
+
+/// One worker's junk counters and deny list.
+pub struct JunkFilter {
+    limits: JunkLimits,
+    metrics: &'static WorkerMetrics,
+    /// Start of the source's current window and its junk packets in it.
+    offenders: FxHashMap<IpAddr, (u64, u32)>,
+    /// Denied until (ms).
+    denied: FxHashMap<IpAddr, u64>,
+}
+
+impl JunkFilter {
+    pub fn new(limits: JunkLimits, metrics: &'static WorkerMetrics) -> Self {
+        Self {
+            limits,
+            metrics,
+            offenders: FxHashMap::default(),
+            denied: FxHashMap::default(),
+        }
+    }
+
+    pub fn denied_count(&self) -> usize {
+        self.denied.len()
+    }
+
+    /// Whether packets from `ip` are dropped unread. One branch when the
+    /// list is empty, which it always is outside strict mode.
+    #[inline]
+    pub fn denies(&mut self, ip: IpAddr, now_ms: u64) -> bool {
+        if self.denied.is_empty() {
+            return false;
+        }
+        match self.denied.get(&ip) {
+            Some(&until) if now_ms < until => {
+                self.metrics.junk_denied.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Some(_) => {
+                self.denied.remove(&ip);
+                self.publish();
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a dropped packet from `ip` and, in strict mode, denies the
+    /// source once it reaches the threshold.
+    pub fn record(&mut self, ip: IpAddr, kind: JunkKind, now_ms: u64) {
+        self.counter(kind).fetch_add(1, Ordering::Relaxed);
+        if !self.limits.strict || kind == JunkKind::UnknownCid {
+            return;
+        }
+        if self.offenders.len() >= JUNK_OFFENDERS_MAX && !self.offenders.contains_key(&ip) {
+            self.offenders
+                .retain(|_, &mut (start, _)| now_ms.saturating_sub(start) < JUNK_WINDOW_MS);
+            if self.offenders.len() >= JUNK_OFFENDERS_MAX {
+                return;
+            }
+        }
+        let (start, count) = self.offenders.entry(ip).or_insert((now_ms, 0));
+        if now_ms.saturating_sub(*start) >= JUNK_WINDOW_MS {
+            (*start, *count) = (now_ms, 0);
+        }
+        *count += 1;
+        if *count >= self.limits.threshold {
+            self.offenders.remove(&ip);
+            self.deny(ip, now_ms);
+        }
+    }
+
+    fn deny(&mut self, ip: IpAddr, now_ms: u64) {
+        if self.denied.len() >= self.limits.deny_max {
+            self.denied.retain(|_, &mut until| now_ms < until);
+            if self.denied.len() >= self.limits.deny_max {
+                return;
+            }
+        }
+        self.denied.insert(ip, now_ms + self.limits.deny_ttl_ms);
+        self.metrics.junk_denials.fetch_add(1, Ordering::Relaxed);
+        self.publish();
+    }
+
+    fn counter(&self, kind: JunkKind) -> &'static AtomicU64 {
+        let m = self.metrics;
+        match kind {
+            JunkKind::TooShort => &m.junk_too_short,
+            JunkKind::NotQuic => &m.junk_not_quic,
+            JunkKind::UnsupportedVersion => &m.junk_unsupported_version,
+            JunkKind::UnknownCid => &m.junk_unknown_cid,
+        }
+    }
+
+    fn publish(&self) {
+        self.metrics
+            .junk_deny_listed
+            .store(self.denied.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::MAX_WORKERS;
+    use crate::metrics::WORKER_METRICS;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    fn long_header(flags: u8, version: u32, dcid_len: u8) -> Vec<u8> {
+        let mut pkt = vec![flags];
+        pkt.extend_from_slice(&version.to_be_bytes());
+        pkt.push(dcid_len);
+        pkt.resize(64, 0xaa);
+        pkt
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&[]), JunkKind::TooShort);
+        assert_eq!(classify(b"GET / HTTP/1.1"), JunkKind::TooShort);
+        assert_eq!(classify(&[0u8; 64]), JunkKind::NotQuic);
+        assert_eq!(classify(&[0x41; 64]), JunkKind::UnknownCid);
+        assert_eq!(classify(&long_header(0xc3, 1, 8)), JunkKind::UnknownCid);
+        assert_eq!(
+            classify(&long_header(0xc3, 0x1a2a_3a4a, 8)),
+            JunkKind::UnsupportedVersion
+        );
+        // Version negotiation is never ours to receive.
+        assert_eq!(
+            classify(&long_header(0x80, 0, 8)),
+            JunkKind::UnsupportedVersion
+        );
+        assert_eq!(classify(&long_header(0x83, 1, 8)), JunkKind::NotQuic);
+        assert_eq!(classify(&long_header(0xc3, 1, 21)), JunkKind::NotQuic);
+    }
+
+    #[test]
+    fn test_repeat_offenders_are_denied_until_the_ttl() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 11];
+        let mut junk = JunkFilter::new(
+            JunkLimits {
+                strict: true,
+                threshold: 3,
+                deny_ttl_ms: 5_000,
+                deny_max: 2,
+            },
+            metrics,
+        );
+        // Two in one window, the third after it: no denial.
+        junk.record(ip(1), JunkKind::NotQuic, 0);
+        junk.record(ip(1), JunkKind::TooShort, 1_000);
+        junk.record(ip(1), JunkKind::NotQuic, JUNK_WINDOW_MS);
+        assert_eq!(junk.denied_count(), 0);
+        // Unknown connection ids never deny.
+        for _ in 0..10 {
+            junk.record(ip(2), JunkKind::UnknownCid, 0);
+        }
+        assert!(!junk.denies(ip(2), 0));
+
+        let now = JUNK_WINDOW_MS;
+        junk.record(ip(1), JunkKind::UnsupportedVersion, now);
+        junk.record(ip(1), JunkKind::NotQuic, now);
+        assert!(junk.denies(ip(1), now));
+        assert!(!junk.denies(ip(3), now));
+        assert_eq!(metrics.junk_denials.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_deny_listed.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_not_quic.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.junk_unknown_cid.load(Ordering::Relaxed), 10);
+
+        // The list is bounded: a third offender waits for a slot.
+        for source in [ip(4), ip(5)] {
+            for _ in 0..3 {
+                junk.record(source, JunkKind::NotQuic, now + 100);
+            }
+        }
+        assert!(junk.denies(ip(4), now + 100));
+        assert!(!junk.denies(ip(5), now + 100));
+        assert_eq!(junk.denied_count(), 2);
+
+        // Past the TTL the source is let through again.
+        assert!(junk.denies(ip(1), now + 4_999));
+        assert!(!junk.denies(ip(1), now + 5_000));
+        assert_eq!(junk.denied_count(), 1);
+        assert_eq!(metrics.junk_denied.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_lenient_mode_only_counts() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 12];
+        let mut junk = JunkFilter::new(JunkLimits::default(), metrics);
+        for _ in 0..10 * JUNK_DENY_THRESHOLD {
+            junk.record(ip(1), JunkKind::NotQuic, 0);
+        }
+        assert!(!junk.denies(ip(1), 0));
+        assert_eq!(
+            metrics.junk_not_quic.load(Ordering::Relaxed),
+            10 * JUNK_DENY_THRESHOLD as u64
+        );
+        assert_eq!(metrics.junk_denials.load(Ordering::Relaxed), 0);
+    }
+}
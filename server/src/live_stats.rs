@@ -0,0 +1,194 @@
+//! STATS replies (see `protocol::stats`): live figures any established
+//! connection may ask for with a STATS_REQUEST datagram, at most once per
+//! STATS_MIN_INTERVAL_MS. Everything in a reply comes from the shared
+//! atomics, so answering costs a few loads and one 32-byte datagram:
+//!
+//! - online: the workers' connection gauges, summed;
+//! - pixels per second: the master's applied-pixel count, smoothed over
+//!   LIVE_STATS_SMOOTHING_MS ([`PixelRate`]);
+//! - cooldown and broadcast interval: what the server runs with now;
+//! - next full sync: from the worker's own full broadcast schedule.
+//!
+//! Requests over the limit are dropped and counted; the reply is never
+//! queued twice for one request.
+
+use crate::conn_slot::ConnSlot;
+use crate::const_settings::{LIVE_STATS_SMOOTHING_MS, TIMING_WHEEL_TICKS};
+use crate::metrics::{MASTER_METRICS, WORKER_METRICS, WorkerMetrics};
+use protocol::stats::{
+    LiveStats, NO_FULL_SCHEDULED, STATS_LEN, STATS_MIN_INTERVAL_MS, encode_stats,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Workers whose connections count as online. Set once at startup.
+static NUM_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn configure(num_workers: usize) {
+    NUM_WORKERS.store(num_workers, Ordering::Relaxed);
+}
+
+/// The stats as of now, with the next full broadcast due at
+/// `next_full_due_ms` (u64::MAX = none scheduled).
+pub fn current(next_full_due_ms: u64, now_ms: u64) -> LiveStats {
+    let online: u64 = WORKER_METRICS[..NUM_WORKERS.load(Ordering::Relaxed)]
+        .iter()
+        .map(|m| m.connections.load(Ordering::Relaxed))
+        .sum();
+    let next_full_ms = match next_full_due_ms {
+        u64::MAX => NO_FULL_SCHEDULED,
+        due => due.saturating_sub(now_ms).min(NO_FULL_SCHEDULED as u64 - 1) as u32,
+    };
+    LiveStats {
+        online: online.min(u32::MAX as u64) as u32,
+        pixels_per_sec: MASTER_METRICS
+            .pixels_per_sec
+            .load(Ordering::Relaxed)
+            .min(u32::MAX as u64) as u32,
+        cooldown_secs: TIMING_WHEEL_TICKS as u16,
+        broadcast_interval_ms: MASTER_METRICS
+            .effective_broadcast_interval_ms
+            .load(Ordering::Relaxed)
+            .min(u16::MAX as u64) as u16,
+        next_full_ms,
+        snapshot: crate::canvas::PUBLISH_SEQ.load(Ordering::Acquire),
+    }
+}
+
+/// One worker's replies. When each connection last got one is kept in its
+/// slot (`ConnSlot::last_stats_reply_ms`).
+pub struct StatsReplies {
+    /// When the worker's next full broadcast is due; u64::MAX = none.
+    pub next_full_due_ms: u64,
+    metrics: &'static WorkerMetrics,
+}
+
+impl StatsReplies {
+    pub fn new(metrics: &'static WorkerMetrics) -> Self {
+        Self {
+            next_full_due_ms: u64::MAX,
+            metrics,
+        }
+    }
+
+    /// The reply to a STATS_REQUEST from the connection in `slot`, or None
+    /// if it already got one within STATS_MIN_INTERVAL_MS.
+    pub fn reply(&mut self, slot: &mut ConnSlot, now_ms: u64) -> Option<[u8; STATS_LEN]> {
+        let last = &mut slot.last_stats_reply_ms;
+        if *last != 0 && now_ms.saturating_sub(*last) < STATS_MIN_INTERVAL_MS {
+            self.metrics
+                .stats_requests_limited
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *last = now_ms.max(1);
+        self.metrics.stats_replies.fetch_add(1, Ordering::Relaxed);
+        Some(encode_stats(&current(self.next_full_due_ms, now_ms)))
+    }
+}
+
+/// Pixels per second, exponentially smoothed. Kept by the master, which
+/// owns the applied-pixel count.
+pub struct PixelRate {
+    last_ms: u64,
+    last_total: u64,
+    rate: f64,
+}
+
+impl PixelRate {
+    pub fn new(total: u64, now_ms: u64) -> Self {
+        Self {
+            last_ms: now_ms,
+            last_total: total,
+            rate: 0.0,
+        }
+    }
+
+    /// Folds in the pixels applied since the last update, once a second at
+    /// most, and publishes the rate.
+    pub fn update(&mut self, total: u64, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.last_ms);
+        if elapsed < 1_000 {
+            return;
+        }
+        let instant = total.saturating_sub(self.last_total) as f64 * 1_000.0 / elapsed as f64;
+        let weight = 1.0 - (-(elapsed as f64) / LIVE_STATS_SMOOTHING_MS as f64).exp();
+        self.rate += weight * (instant - self.rate);
+        self.last_ms = now_ms;
+        self.last_total = total;
+        MASTER_METRICS
+            .pixels_per_sec
+            .store(self.rate.round() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn_slot::ConnSlots;
+    use crate::const_settings::MAX_WORKERS;
+    use protocol::stats::decode_stats;
+
+    #[test]
+    fn test_replies_are_rate_limited_per_connection() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 10];
+        let mut replies = StatsReplies::new(metrics);
+        replies.next_full_due_ms = 64_000;
+        let mut slots = ConnSlots::new();
+        let (a, b) = (7, 8);
+
+        let reply = replies.reply(slots.get_mut(a), 60_000).unwrap();
+        let stats = decode_stats(&reply).unwrap();
+        assert_eq!(stats.cooldown_secs, TIMING_WHEEL_TICKS as u16);
+        assert_eq!(stats.next_full_ms, 4_000);
+
+        // A second request within the interval is dropped, on that
+        // connection only.
+        assert!(replies.reply(slots.get_mut(a), 69_999).is_none());
+        assert!(replies.reply(slots.get_mut(b), 69_999).is_some());
+        assert!(replies.reply(slots.get_mut(a), 70_000).is_some());
+        assert!(replies.reply(slots.get_mut(a), 70_001).is_none());
+        assert_eq!(metrics.stats_replies.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.stats_requests_limited.load(Ordering::Relaxed), 2);
+
+        // The id's next owner is not held to the old one's limit.
+        slots.released(a, false);
+        slots.accepted(a);
+        assert!(replies.reply(slots.get_mut(a), 70_002).is_some());
+
+        // A late schedule reads as due now; none scheduled as the sentinel.
+        slots.get_mut(a).reset_for_reuse();
+        replies.next_full_due_ms = 1_000;
+        let stats = decode_stats(&replies.reply(slots.get_mut(a), 80_000).unwrap()).unwrap();
+        assert_eq!(stats.next_full_ms, 0);
+        slots.get_mut(a).reset_for_reuse();
+        replies.next_full_due_ms = u64::MAX;
+        let stats = decode_stats(&replies.reply(slots.get_mut(a), 80_000).unwrap()).unwrap();
+        assert_eq!(stats.next_full_ms, NO_FULL_SCHEDULED);
+    }
+
+    #[test]
+    fn test_pixel_rate_is_smoothed() {
+        let mut rate = PixelRate::new(0, 0);
+        // Under a second apart: nothing changes.
+        rate.update(500, 400);
+        assert_eq!(rate.rate, 0.0);
+
+        // A steady 1000 px/s is approached, not jumped to.
+        let mut total = 0;
+        for second in 1..=5 {
+            total += 1_000;
+            rate.update(total, second * 1_000);
+        }
+        assert!(rate.rate > 300.0 && rate.rate < 500.0, "{}", rate.rate);
+        for second in 6..=60 {
+            total += 1_000;
+            rate.update(total, second * 1_000);
+        }
+        assert!((rate.rate - 1_000.0).abs() < 5.0, "{}", rate.rate);
+
+        // A one-second spike moves it by about a tenth of the jump.
+        total += 11_000;
+        rate.update(total, 61_000);
+        assert!(rate.rate > 1_800.0 && rate.rate < 2_100.0, "{}", rate.rate);
+    }
+}
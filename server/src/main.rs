@@ -1,20 +1,63 @@
+pub mod admin_writes;
+pub mod admission;
+pub mod affinity;
+pub mod aligned;
+pub mod audit;
+pub mod bandwidth;
 pub mod canvas;
+pub mod config;
+pub mod config_check;
+pub mod conn_memory;
+pub mod conn_slot;
 pub mod const_settings;
+pub mod control;
 pub mod cooldown;
+pub mod dashboard;
+pub mod demo;
+pub mod diff_dict;
+pub mod epoch;
+pub mod fast_diff;
+pub mod fec;
+pub mod full_broadcast;
+pub mod half_open;
+pub mod health;
+pub mod instance;
+pub mod junk;
+pub mod live_stats;
 pub mod master;
+pub mod metrics;
+pub mod pixel_trace;
+pub mod profiler;
+pub mod qlog;
+pub mod rate_contract;
+pub mod recv_lane;
+pub mod replenish;
+pub mod reservation;
+pub mod retry;
+pub mod run_info;
+pub mod runtime_config;
+pub mod sandbox;
 pub mod spsc;
+pub mod steering;
+pub mod thumbnail;
 pub mod time;
+pub mod timeline;
 pub mod timing_wheel;
+pub mod tls;
 pub mod transport;
+pub mod transport_stats;
+pub mod tx_pool;
+pub mod webtransport;
 pub mod worker;
 
 use crate::canvas::Canvas;
-use crate::const_settings::{SERVER_PORT, print_mem_footprint};
+use crate::config::ServerConfig;
+use crate::const_settings::{MAX_WORKERS, derived, print_mem_footprint};
+use crate::instance::{InstanceClaim, InstanceLock};
 use crate::master::{MasterCore, PixelWrite};
 use crate::spsc::SpscRingBuffer;
 use crate::time::CLOCK;
 use crate::worker::WorkerCore;
-use std::sync::Arc;
 
 #[cfg(target_os = "linux")]
 fn maximize_memlock() {
@@ -29,33 +72,80 @@ fn maximize_memlock() {
     }
 }
 
-fn create_certificates() -> Result<(), std::io::Error> {
-    if std::path::Path::new("cert.crt").exists() && std::path::Path::new("key.key").exists() {
-        return Ok(());
-    }
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    std::fs::write("cert.crt", cert.cert.pem())?;
-    std::fs::write("key.key", cert.key_pair.serialize_pem())?;
-    Ok(())
-}
-
 fn main() {
     #[cfg(target_os = "linux")]
     maximize_memlock();
 
     println!("Bare-metal canvas server initializing...");
 
-    let port = SERVER_PORT;
     let args: Vec<String> = std::env::args().collect();
-    let num_workers_arg = args
-        .iter()
-        .position(|r| r == "-w" || r == "--workers")
-        .and_then(|pos| args.get(pos + 1))
-        .and_then(|val| val.parse::<usize>().ok());
+    let config = ServerConfig::from_args(&args);
+    let num_workers_arg = config.workers;
+
+    let conflicts = config_check::check(&config);
+    for conflict in &conflicts {
+        println!("{}", conflict);
+    }
+    let hard = conflicts.iter().filter(|c| c.is_hard()).count();
+    if hard > 0 {
+        panic!("Refusing to start: {} configuration error(s)", hard);
+    }
+
+    // Looked up now: the user database is out of reach once sandboxed.
+    let run_as = config.run_as.as_ref().map(|run_as| {
+        let creds = run_as
+            .resolve()
+            .unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+        (run_as, creds)
+    });
+
+    tls::ensure_certificates(&config).expect("Failed to set up TLS certificates");
+
+    if let Some(path) = &config.diff_dict {
+        let version = diff_dict::load(path)
+            .unwrap_or_else(|e| panic!("Failed to load the diff dictionary: {}", e));
+        println!(
+            "Compressing diffs with dictionary v{} for clients that negotiate zstd",
+            version
+        );
+    }
+
+    fec::configure(config.fec_k).unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+    reservation::configure(config.reserve_grace_ms);
+    full_broadcast::configure(config.full_broadcast_ms);
+    if let Some(dir) = &config.qlog_dir {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Failed to create {}: {}", dir.display(), e));
+        println!(
+            "Writing qlog traces of 1 in {} connections per worker to {}",
+            config.qlog_sample.max(1),
+            dir.display()
+        );
+    }
+    if config.reserve_grace_ms > 0 {
+        println!(
+            "Parking the user ids of closed connections for {} ms",
+            config.reserve_grace_ms
+        );
+    }
 
-    create_certificates().expect("Failed to create certificates");
+    if let Some(path) = &config.tier1_tokens {
+        let tokens = fast_diff::load_tokens(path, config.tier1_per_worker)
+            .unwrap_or_else(|e| panic!("Failed to load the tier-1 tokens: {}", e));
+        println!(
+            "Latency tiers on: {} tier-1 tokens, {} seats per worker, micro-diffs every {} ms",
+            tokens, config.tier1_per_worker, config.fast_diff_ms
+        );
+    }
 
-    let core_ids = core_affinity::get_core_ids().expect("Failed to get core IDs");
+    let core_ids: Vec<usize> = match &config.cpu_list {
+        Some(cpu_list) => cpu_list.clone(),
+        None => core_affinity::get_core_ids()
+            .expect("Failed to get core IDs")
+            .into_iter()
+            .map(|c| c.id)
+            .collect(),
+    };
     let num_cores = core_ids.len();
 
     let num_workers = num_workers_arg.unwrap_or(num_cores.saturating_sub(1));
@@ -64,6 +154,10 @@ fn main() {
         panic!("At least 1 worker is required. Use -w <num> to specify.");
     }
 
+    if num_workers > MAX_WORKERS {
+        panic!("At most {} workers are supported.", MAX_WORKERS);
+    }
+
     if num_cores < 2 && num_workers_arg.is_none() {
         panic!(
             "Single core system detected. At least 2 cores are recommended, or force number of workers with -w 1"
@@ -73,10 +167,10 @@ fn main() {
     // Partition Cores
     // Core 0: Master (Primary writer + Broadcast)
     // Cores 1+: Workers (Ingress/Validation)
-    let master_core_id = core_ids[0].id;
+    let master_core_id = core_ids[0];
 
     let worker_cores: Vec<usize> = (0..num_workers)
-        .map(|i| core_ids[(i + 1) % num_cores].id)
+        .map(|i| core_ids[(i + 1) % num_cores])
         .collect();
 
     println!(
@@ -86,6 +180,65 @@ fn main() {
         worker_cores
     );
 
+    // Demo bots get the first core nobody else uses, if there is one.
+    let demo_core = core_ids
+        .get(num_workers + 1)
+        .copied()
+        .filter(|_| config.demo_bots > 0);
+
+    let requested_cores: Vec<usize> = std::iter::once(master_core_id)
+        .chain(worker_cores.iter().copied())
+        .chain(demo_core)
+        .collect();
+    if let Some(allowed) = affinity::allowed_cpus()
+        && let Err(e) = affinity::check_cores(&requested_cores, &allowed)
+    {
+        if config.strict_affinity {
+            panic!("Refusing to start: {}", e);
+        }
+        println!(
+            "WARNING: {}; threads on those cores will run unpinned (--strict-affinity refuses instead)",
+            e
+        );
+    }
+
+    // Held for the lifetime of the process; advertises our port and cores to
+    // sibling instances on the same host.
+    let _instance_lock = InstanceLock::acquire(
+        &config.lock_dir,
+        &InstanceClaim {
+            name: config.instance_label().to_string(),
+            pid: std::process::id(),
+            port: config.port,
+            cores: requested_cores.iter().copied().collect(),
+        },
+    )
+    .unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+
+    println!("Derived settings: {:#?}", derived());
+    println!("RLE encoder: {}", protocol::rle::rle_encoder().name());
+    run_info::publish(run_info::environment_json(
+        std::path::Path::new("/"),
+        &run_info::probe_io_uring(),
+    ));
+    runtime_config::publish(&config, num_workers);
+    live_stats::configure(num_workers);
+    if let Some(dir) = &config.metrics_dir {
+        let started_unix_s = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let json = run_info::run_info_json(started_unix_s, &runtime_config::published_json());
+        match run_info::write_run_info(dir, &json) {
+            Ok(path) => println!("Run info written to {}", path.display()),
+            Err(e) => println!(
+                "Warning: failed to write run info to {}: {}",
+                dir.display(),
+                e
+            ),
+        }
+    }
+    bandwidth::BANDWIDTH_LIMITS.store(config.bandwidth);
     print_mem_footprint(num_workers);
 
     let mut worker_queues = Vec::with_capacity(worker_cores.len());
@@ -93,24 +246,106 @@ fn main() {
 
     CLOCK.init();
 
+    for _ in 0..worker_cores.len() {
+        worker_queues.push(SpscRingBuffer::<PixelWrite>::shared());
+    }
+
+    // Initialize Master first: it publishes the starting canvas (background)
+    // that workers take as their broadcast baseline.
+    let canvas = Canvas::load_or_new(&config).expect("Failed to load background image");
+    let master = MasterCore::new(worker_queues.clone(), canvas, &config);
+
     // Initialize Workers
-    for &core_id in &worker_cores {
-        let queue = Arc::new(SpscRingBuffer::<PixelWrite>::new());
-        worker_queues.push(queue.clone());
-        workers.push((WorkerCore::new(queue, port), core_id));
+    for (worker_id, (&core_id, queue)) in worker_cores.iter().zip(worker_queues).enumerate() {
+        workers.push((WorkerCore::new(worker_id, queue, &config), core_id));
+    }
+
+    // Every worker has bound by now, so the reuseport group is complete and
+    // in worker id order. The handshake sockets follow, in the same order.
+    #[cfg(target_os = "linux")]
+    {
+        if config.handshake_reserve {
+            for (worker, _) in workers.iter_mut() {
+                worker.bind_handshake_socket(config.port);
+            }
+        }
+        match steering::configure(
+            config.steer_by_port,
+            config.handshake_reserve,
+            workers[0].0.socket(),
+            num_workers,
+        ) {
+            steering::Steering::Port => println!(
+                "Steering new connections by client port % {} workers",
+                num_workers
+            ),
+            steering::Steering::PortReserve => println!(
+                "Steering new connections by client port % {} workers, handshakes to reserved buffers",
+                num_workers
+            ),
+            steering::Steering::Hash | steering::Steering::HashFallback => {}
+        }
+        if config.handshake_reserve && steering::current() != steering::Steering::PortReserve {
+            println!("Warning: handshake reserve unavailable, handshakes share the data buffers");
+            for (worker, _) in workers.iter_mut() {
+                worker.close_handshake_socket();
+            }
+        }
+    }
+
+    if let Some(bind) = config.dashboard_bind {
+        match dashboard::spawn(bind, num_workers, master.admin_ring()) {
+            Ok(addr) => println!("Dashboard listening on http://{}", addr),
+            Err(e) => println!("Warning: failed to start dashboard on {}: {}", bind, e),
+        }
     }
 
-    // Initialize Master
-    let canvas = Canvas::new();
-    let master = MasterCore::new(worker_queues, canvas);
+    if config.profile_hz > 0 {
+        profiler::spawn(config.profile_hz, num_workers);
+        println!(
+            "Sampling worker loop phases at {} Hz (/admin/profile)",
+            config.profile_hz
+        );
+    }
 
     // Spawn Workers
     let mut handles = Vec::new();
+    let (ready, workers_ready) = std::sync::mpsc::channel();
     for (worker, core_id) in workers {
+        let ready = ready.clone();
         handles.push(std::thread::spawn(move || {
-            worker.run(core_id);
+            worker.run(core_id, ready);
         }));
     }
+    drop(ready);
+
+    // Dropped after the master loop, which closes the bot connections.
+    let _demo = (config.demo_bots > 0).then(|| {
+        demo::spawn(config.demo_bots, config.port, demo_core).expect("Failed to start demo bots")
+    });
+
+    // Everything that needs root is set up once each worker has its ring;
+    // a worker that died before that never signals.
+    if let Some((run_as, creds)) = run_as {
+        for _ in 0..num_workers {
+            workers_ready
+                .recv()
+                .expect("Refusing to start: a worker failed to set up");
+        }
+        sandbox::enter(creds, config.sandbox)
+            .unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+        println!(
+            "Running as {} (uid {}, gid {}), {}",
+            run_as,
+            creds.uid,
+            creds.gid,
+            if config.sandbox {
+                "syscalls confined to the sandbox allowlist"
+            } else {
+                "without the sandbox (--no-sandbox)"
+            }
+        );
+    }
 
     //  Run Master on main thread
     println!("Starting Master loop on core {}...", master_core_id);
@@ -1,7 +1,20 @@
-use crate::canvas::Canvas;
-use crate::const_settings::{BROADCAST_INTERVAL_MS, CANVAS_BUFFER_POOL_MASK, MASTER_BATCH_DRAIN};
-use crate::spsc::SpscRingBuffer;
-use std::sync::Arc;
+use crate::admin_writes::{AdminQueue, AdminWrite};
+use crate::audit::{AuditEntry, AuditReservoir};
+use crate::canvas::{Canvas, DirtyTiles, copy_pool_slot, seed_active_slot, tile_index};
+use crate::config::ServerConfig;
+use crate::const_settings::{
+    AUDIT_PUBLISH_MS, BROADCAST_INTERVAL_MS, CANVAS_BUFFER_POOL_MASK, CANVAS_SIZE, CANVAS_WIDTH,
+    MASTER_BATCH_DRAIN,
+};
+use crate::fast_diff::{FAST_RING, FastBatcher};
+use crate::full_broadcast::FullBroadcastGate;
+use crate::health::{IntervalChange, IntervalController};
+use crate::live_stats::PixelRate;
+use crate::metrics::{MASTER_METRICS, WORKER_METRICS};
+use crate::pixel_trace::{self, Fate};
+use crate::spsc::{SharedRing, SpscRingBuffer};
+use crate::timeline::Timeline;
+use protocol::rle::rle_encode;
 use std::sync::atomic::Ordering;
 
 #[derive(Clone, Copy)]
@@ -9,129 +22,682 @@ pub struct PixelWrite {
     pub x: u16,
     pub y: u16,
     pub color: u8,
+    /// Sampled by `--trace-pixels`: the master logs what happens to it.
+    pub traced: bool,
+    /// Who sent it, for the audit sample: the worker's user id and the
+    /// peer's IPv4 address (0 for IPv6, see `audit::ipv4_of`).
+    pub user_id: u32,
+    pub ipv4: u32,
+    /// The epoch the worker admitted it in; the master drops it once that
+    /// epoch has ended (epoch.rs).
+    pub epoch: u32,
 }
 
-#[inline(always)]
-pub fn rle_compress(src: &[u8], dst: &mut [u8]) -> usize {
-    if src.is_empty() {
-        return 0;
-    }
-    let mut src_idx = 0;
-    let mut dst_idx = 0;
-    let len = src.len();
-
-    while src_idx < len {
-        let color = src[src_idx];
-        let mut count = 1;
-        src_idx += 1;
-
-        // SIMD optimization for x86_64
-        #[cfg(target_arch = "x86_64")]
-        {
-            if is_x86_feature_detected!("avx2") {
-                unsafe {
-                    use std::arch::x86_64::*;
-                    let color_vec = _mm256_set1_epi8(color as i8);
-                    while src_idx + 32 <= len && count + 32 <= 255 {
-                        let chunk = _mm256_loadu_si256(src.as_ptr().add(src_idx) as *const __m256i);
-                        let mask = _mm256_movemask_epi8(_mm256_cmpeq_epi8(chunk, color_vec)) as u32;
-                        if mask == 0xFFFFFFFF {
-                            count += 32;
-                            src_idx += 32;
-                        } else {
-                            let matching = (!mask).trailing_zeros() as usize;
-                            count += matching;
-                            src_idx += matching;
-                            break;
-                        }
-                    }
+#[inline]
+fn unix_minute(now_ms: u64) -> u32 {
+    (now_ms / 60_000) as u32
+}
+
+pub struct MasterCore {
+    workers: Vec<SharedRing<PixelWrite>>,
+    /// Region clears from the dashboard, applied between worker drains.
+    admin: AdminQueue,
+    /// Admin pixels applied per iteration.
+    admin_budget: usize,
+    pub canvas: Canvas,
+    dirty_tiles: DirtyTiles,
+    snapshot_diff_budget_bytes: usize,
+    interval_controller: Option<IntervalController>,
+    timeline: Timeline,
+    /// Uniform sample of applied writes for `/admin/audit`.
+    audit: AuditReservoir,
+    /// Traced pixels applied but not yet part of a published snapshot.
+    traced: Vec<PixelWrite>,
+    /// Micro-diffs for tier-1 connections; None without `--tier1-tokens`.
+    fast: Option<FastBatcher>,
+    /// Smoothed pixel rate for STATS replies.
+    pixel_rate: PixelRate,
+    /// Suspends full broadcasts while snapshots RLE too big.
+    full_gate: FullBroadcastGate,
+    /// The current epoch; only pixels admitted in it are applied.
+    epoch: u32,
+    strict_affinity: bool,
+}
+
+impl MasterCore {
+    /// Also publishes `canvas` as the initial snapshot, so it must run before
+    /// workers are created (they take their diff baseline from it).
+    pub fn new(
+        workers: Vec<SharedRing<PixelWrite>>,
+        canvas: Canvas,
+        config: &ServerConfig,
+    ) -> Self {
+        let active = seed_active_slot(&canvas);
+
+        let mut master = Self {
+            workers,
+            admin: AdminQueue::new(SpscRingBuffer::shared()),
+            admin_budget: config.admin_drain_ratio * MASTER_BATCH_DRAIN,
+            canvas,
+            dirty_tiles: DirtyTiles::new(),
+            snapshot_diff_budget_bytes: config.snapshot_diff_budget_bytes,
+            interval_controller: config.adaptive_broadcast.then(|| {
+                IntervalController::new(BROADCAST_INTERVAL_MS, config.max_broadcast_interval_ms)
+            }),
+            timeline: Timeline::new(
+                config.timeline_minutes,
+                unix_minute(crate::time::CLOCK.now_ms()),
+            ),
+            audit: AuditReservoir::default(),
+            traced: Vec::new(),
+            fast: crate::fast_diff::enabled().then(|| FastBatcher::new(config.fast_diff_ms)),
+            pixel_rate: PixelRate::new(
+                MASTER_METRICS.pixels_applied.load(Ordering::Relaxed),
+                crate::time::CLOCK.now_ms(),
+            ),
+            full_gate: FullBroadcastGate::new(config.full_broadcast_max_bytes),
+            epoch: 0,
+            strict_affinity: config.strict_affinity,
+        };
+        master.compress_slot(active);
+        master
+    }
+
+    /// Where the dashboard queues region clears.
+    pub fn admin_ring(&self) -> SharedRing<AdminWrite> {
+        self.admin.ring()
+    }
+
+    /// Feeds aggregated worker loop health to the adaptive controller,
+    /// publishes the load factor it implies and returns the broadcast
+    /// interval to use next.
+    fn next_broadcast_interval(&mut self, current_ms: u64) -> u64 {
+        let Some(ctrl) = self.interval_controller.as_mut() else {
+            return current_ms;
+        };
+
+        let (iterations, overruns) =
+            WORKER_METRICS[..self.workers.len()]
+                .iter()
+                .fold((0u64, 0u64), |(it, ov), m| {
+                    (
+                        it + m.loop_iterations.load(Ordering::Relaxed),
+                        ov + m.loop_overruns.load(Ordering::Relaxed),
+                    )
+                });
+
+        match ctrl.observe(iterations, overruns) {
+            IntervalChange::Stretched => {
+                MASTER_METRICS
+                    .interval_stretches
+                    .fetch_add(1, Ordering::Relaxed);
+                println!(
+                    "Master: workers overrunning, broadcast interval stretched {} -> {} ms",
+                    current_ms,
+                    ctrl.current_ms()
+                );
+            }
+            IntervalChange::Recovered => {
+                MASTER_METRICS
+                    .interval_recoveries
+                    .fetch_add(1, Ordering::Relaxed);
+                println!(
+                    "Master: workers recovered, broadcast interval {} -> {} ms",
+                    current_ms,
+                    ctrl.current_ms()
+                );
+            }
+            IntervalChange::Unchanged => {}
+        }
+
+        MASTER_METRICS
+            .effective_broadcast_interval_ms
+            .store(ctrl.current_ms(), Ordering::Relaxed);
+        // Workers stretch their rate contracts by as much (rate_contract.rs).
+        crate::rate_contract::set_load(ctrl.current_ms(), BROADCAST_INTERVAL_MS);
+        ctrl.current_ms()
+    }
+
+    /// A user pixel from worker `worker`'s queue.
+    #[inline(always)]
+    fn apply_pixel(&mut self, worker: usize, pixel: PixelWrite) {
+        if pixel.epoch != self.epoch {
+            self.drop_stale(pixel);
+            return;
+        }
+        let (x, y) = (pixel.x as usize, pixel.y as usize);
+        if let Some(prev) = self.canvas.swap_pixel(x, y, pixel.color) {
+            let tile = tile_index(x, y);
+            MASTER_METRICS.record_apply(tile, prev != self.canvas.background_at(x, y));
+            self.timeline.record(tile);
+            self.audit.record(|| AuditEntry {
+                ms: crate::time::CLOCK.now_ms(),
+                ipv4: pixel.ipv4,
+                user_id: pixel.user_id,
+                x: pixel.x,
+                y: pixel.y,
+                color: pixel.color,
+                worker: worker as u8,
+            });
+            if prev != pixel.color {
+                self.dirty_tiles.mark(tile);
+                if let Some(fast) = &mut self.fast {
+                    fast.record((y * CANVAS_WIDTH + x) as u32, pixel.color);
                 }
-            } else if is_x86_feature_detected!("sse2") {
-                unsafe {
-                    use std::arch::x86_64::*;
-                    let color_vec = _mm_set1_epi8(color as i8);
-                    while src_idx + 16 <= len && count + 16 <= 255 {
-                        let chunk = _mm_loadu_si128(src.as_ptr().add(src_idx) as *const __m128i);
-                        let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, color_vec)) as u32;
-                        if mask == 0xFFFF {
-                            count += 16;
-                            src_idx += 16;
-                        } else {
-                            let matching = (!mask).trailing_zeros() as usize;
-                            count += matching;
-                            src_idx += matching;
-                            break;
-                        }
-                    }
+            }
+            if pixel.traced {
+                self.trace_applied(pixel, prev);
+            }
+        } else if pixel.traced {
+            pixel_trace::log("master", &pixel, Fate::OutOfBounds);
+        }
+    }
+
+    /// An admin pixel: on the canvas and in the next diffs like a user's,
+    /// but counted apart and kept out of the activity statistics.
+    #[inline(always)]
+    fn apply_admin_pixel(&mut self, x: usize, y: usize, color: u8) {
+        if let Some(prev) = self.canvas.swap_pixel(x, y, color) {
+            let applied = &MASTER_METRICS.admin_pixels_applied;
+            applied.store(applied.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            if prev != color {
+                self.dirty_tiles.mark(tile_index(x, y));
+                if let Some(fast) = &mut self.fast {
+                    fast.record((y * CANVAS_WIDTH + x) as u32, color);
                 }
             }
         }
+    }
 
-        while src_idx < len && src[src_idx] == color && count < 255 {
-            count += 1;
-            src_idx += 1;
+    /// One pass over the queues: up to MASTER_BATCH_DRAIN pixels from each
+    /// worker, then up to the admin budget from queued clears.
+    fn drain(&mut self) {
+        for w in 0..self.workers.len() {
+            // Batch drain to minimize lock duration effectively
+            for _ in 0..MASTER_BATCH_DRAIN {
+                if let Some(pixel) = self.workers[w].pop() {
+                    self.apply_pixel(w, pixel);
+                } else {
+                    break;
+                }
+            }
         }
 
-        dst[dst_idx] = count as u8;
-        dst[dst_idx + 1] = color;
-        dst_idx += 2;
+        let mut budget = self.admin_budget;
+        while budget > 0 {
+            let Some(span) = self.admin.next_span(budget) else {
+                break;
+            };
+            budget -= span.pixels();
+            for x in span.x0..span.x1 {
+                self.apply_admin_pixel(x as usize, span.y as usize, span.color);
+            }
+        }
     }
-    dst_idx
-}
 
-pub struct MasterCore {
-    workers: Vec<Arc<SpscRingBuffer<PixelWrite>>>,
-    pub canvas: Canvas,
-}
+    /// A pixel admitted before the current epoch began: it was charged a
+    /// cooldown the workers have since lifted, and the canvas it was meant
+    /// for is gone.
+    #[cold]
+    fn drop_stale(&mut self, pixel: PixelWrite) {
+        let stale = &MASTER_METRICS.stale_epoch_pixels;
+        stale.store(stale.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        if pixel.traced {
+            pixel_trace::log("master", &pixel, Fate::StaleEpoch);
+        }
+    }
 
-impl MasterCore {
-    pub fn new(workers: Vec<Arc<SpscRingBuffer<PixelWrite>>>, canvas: Canvas) -> Self {
-        Self { workers, canvas }
+    /// Ends the current epoch: the canvas becomes `background`, and what
+    /// was pending against the old one (clears, dirty tiles, traced pixels)
+    /// is dropped. Pixels admitted so far are stale from here on.
+    fn begin_epoch(&mut self, background: Box<[u8; CANVAS_SIZE]>, now_ms: u64) {
+        // Micro-diffs of the old canvas go out ahead of the snapshot that
+        // replaces it.
+        self.cut_fast_diff(now_ms);
+        self.admin.discard();
+        self.dirty_tiles = DirtyTiles::new();
+        for pixel in self.traced.drain(..) {
+            pixel_trace::log("master", &pixel, Fate::Overwritten);
+        }
+        self.canvas.pixels.copy_from_slice(&background[..]);
+        self.canvas.background = background;
+        self.epoch += 1;
+    }
+
+    /// Starts a new epoch from `background` and publishes its first
+    /// snapshot, whole, before telling the workers: one that sees the new
+    /// epoch finds that snapshot published.
+    fn start_epoch(&mut self, background: Box<[u8; CANVAS_SIZE]>, now_ms: u64) {
+        self.begin_epoch(background, now_ms);
+        let current_active = crate::canvas::ACTIVE_INDEX.load(Ordering::Relaxed);
+        let next_active = (current_active + 1) & CANVAS_BUFFER_POOL_MASK;
+        self.canvas.snapshot_to_pool(next_active);
+        MASTER_METRICS
+            .snapshot_backlog_tiles
+            .store(0, Ordering::Relaxed);
+        self.compress_slot(next_active);
+        crate::canvas::publish_slot(next_active);
+        crate::epoch::publish(self.epoch);
+        println!("Master: epoch {} started", self.epoch);
+    }
+
+    #[cold]
+    fn trace_applied(&mut self, pixel: PixelWrite, prev: u8) {
+        if prev == pixel.color {
+            pixel_trace::log("master", &pixel, Fate::AppliedNoChange);
+        } else {
+            pixel_trace::log("master", &pixel, Fate::Applied);
+            self.traced.push(pixel);
+        }
+    }
+
+    /// Reports traced pixels whose tile made it into the snapshot just
+    /// published; those still dirty wait for a later one.
+    fn trace_published(&mut self) {
+        let (canvas, dirty_tiles) = (&self.canvas, &self.dirty_tiles);
+        self.traced.retain(|p| {
+            let (x, y) = (p.x as usize, p.y as usize);
+            if dirty_tiles.is_dirty(tile_index(x, y)) {
+                return true;
+            }
+            let fate = if canvas.pixels[y * CANVAS_WIDTH + x] == p.color {
+                Fate::Published
+            } else {
+                Fate::Overwritten
+            };
+            pixel_trace::log("master", p, fate);
+            false
+        });
+    }
+
+    /// Builds the next pool slot from the currently published one plus as many
+    /// dirty tiles as the diff budget allows. Tiles left over stay dirty and
+    /// are picked up by the following intervals.
+    fn publish_snapshot(&mut self, current_active: usize, next_active: usize) {
+        copy_pool_slot(current_active, next_active);
+
+        let canvas = &self.canvas;
+        self.dirty_tiles
+            .drain_budgeted(self.snapshot_diff_budget_bytes, |tile| {
+                canvas.copy_tile_to_pool(tile, next_active)
+            });
+
+        MASTER_METRICS
+            .snapshot_backlog_tiles
+            .store(self.dirty_tiles.len(), Ordering::Relaxed);
+
+        #[cfg(feature = "debug-logs")]
+        if !self.dirty_tiles.is_empty() {
+            println!(
+                "Master: diff budget exhausted, {} dirty tiles deferred",
+                self.dirty_tiles.len()
+            );
+        }
     }
 
-    pub fn run(&self, core_id: usize) {
-        // Pin to physical core using core_affinity
-        if core_affinity::set_for_current(core_affinity::CoreId { id: core_id }) {
-            // Successfully pinned
+    /// RLE-encodes pool slot `slot` for full broadcasts, and suspends or
+    /// resumes them depending on how big that came out.
+    fn compress_slot(&mut self, slot: usize) {
+        let len = unsafe {
+            let src = &crate::canvas::BUFFER_POOL[slot].data;
+            let dst = &mut crate::canvas::COMPRESSED_BUFFER_POOL[slot].data;
+            let len = rle_encode(src, dst);
+            crate::canvas::COMPRESSED_LENS[slot] = len;
+            len
+        };
+        MASTER_METRICS
+            .snapshot_rle_bytes
+            .store(len, Ordering::Relaxed);
+
+        let Some(diff_only) = self.full_gate.observe(len) else {
+            return;
+        };
+        crate::full_broadcast::set_diff_only(diff_only);
+        MASTER_METRICS
+            .diff_only
+            .store(diff_only as u64, Ordering::Relaxed);
+        MASTER_METRICS
+            .diff_only_switches
+            .fetch_add(1, Ordering::Relaxed);
+        if diff_only {
+            println!(
+                "Master: snapshot RLE is {} bytes, over --full-broadcast-max-bytes; full broadcasts suspended, clients sync per tile",
+                len
+            );
+        } else {
+            println!(
+                "Master: snapshot RLE back to {} bytes; full broadcasts resume",
+                len
+            );
+        }
+    }
+
+    /// Publishes the pixels applied since the last micro-diff, tagged with
+    /// the snapshots published so far.
+    fn cut_fast_diff(&mut self, now_ms: u64) {
+        if let Some(fast) = &mut self.fast {
+            let snapshot_seq = crate::canvas::PUBLISH_SEQ.load(Ordering::Relaxed);
+            fast.cut(&FAST_RING, snapshot_seq, now_ms);
+            MASTER_METRICS
+                .fast_diff_slots
+                .store(FAST_RING.published(), Ordering::Relaxed);
         }
+    }
+
+    pub fn run(mut self, core_id: usize) {
+        let pinned = crate::affinity::pin_current("master", core_id, self.strict_affinity);
+        MASTER_METRICS
+            .pinned
+            .store(pinned as u64, Ordering::Relaxed);
         // Use AtomicTime for high-performance timing without syscall overhead
         let mut last_broadcast_time = crate::time::CLOCK.now_ms();
-        let broadcast_threshold_ms = BROADCAST_INTERVAL_MS;
+        let mut broadcast_threshold_ms = BROADCAST_INTERVAL_MS;
+        let mut last_audit_publish = last_broadcast_time;
 
         loop {
-            for worker_queue in &self.workers {
-                // Batch drain to minimize lock duration effectively
-                for _ in 0..MASTER_BATCH_DRAIN {
-                    if let Some(pixel) = worker_queue.pop() {
-                        self.canvas
-                            .set_pixel(pixel.x as usize, pixel.y as usize, pixel.color);
-                    } else {
-                        break;
-                    }
-                }
-            }
+            self.drain();
 
             let now = crate::time::CLOCK.now_ms();
+            if let Some(background) = crate::epoch::take_request() {
+                self.start_epoch(background, now);
+                last_broadcast_time = now;
+            }
+            if self.fast.as_ref().is_some_and(|fast| fast.due(now)) {
+                self.cut_fast_diff(now);
+            }
             if now.wrapping_sub(last_broadcast_time) >= broadcast_threshold_ms {
+                // Everything applied so far goes into the snapshot; the
+                // micro-diff carrying it must be cut before it.
+                self.cut_fast_diff(now);
                 let current_active = crate::canvas::ACTIVE_INDEX.load(Ordering::Relaxed);
                 let next_active = (current_active + 1) & CANVAS_BUFFER_POOL_MASK;
 
-                self.canvas.snapshot_to_pool(next_active);
+                self.publish_snapshot(current_active, next_active);
+                if !self.traced.is_empty() {
+                    self.trace_published();
+                }
 
                 // Compress the snapshot
-                unsafe {
-                    let src = &crate::canvas::BUFFER_POOL[next_active].data;
-                    let dst = &mut crate::canvas::COMPRESSED_BUFFER_POOL[next_active].data;
-                    let compressed_len = rle_compress(src, dst);
-                    crate::canvas::COMPRESSED_LENS[next_active] = compressed_len;
-                }
+                self.compress_slot(next_active);
 
-                crate::canvas::ACTIVE_INDEX.store(next_active, Ordering::Release);
+                crate::canvas::publish_slot(next_active);
 
                 last_broadcast_time = now;
+                broadcast_threshold_ms = self.next_broadcast_interval(broadcast_threshold_ms);
+
+                if self.timeline.rotate(unix_minute(now)) {
+                    self.timeline.publish();
+                }
+                if now.wrapping_sub(last_audit_publish) >= AUDIT_PUBLISH_MS {
+                    self.audit.publish();
+                    last_audit_publish = now;
+                }
+                self.pixel_rate
+                    .update(MASTER_METRICS.pixels_applied.load(Ordering::Relaxed), now);
             }
 
             std::hint::spin_loop();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{ACTIVE_INDEX, BUFFER_POOL, COMPRESSED_LENS, diff_canvas};
+    use crate::const_settings::{CANVAS_SIZE, CANVAS_WIDTH};
+
+    #[test]
+    fn test_background_is_published_before_first_broadcast() {
+        let config = ServerConfig {
+            background: 9,
+            ..Default::default()
+        };
+        let mut master =
+            MasterCore::new(Vec::new(), Canvas::load_or_new(&config).unwrap(), &config);
+
+        // The seeded slot holds the background, RLE'd as full 255-pixel runs.
+        let active = ACTIVE_INDEX.load(Ordering::Acquire);
+        let len = unsafe { COMPRESSED_LENS[active] };
+        assert_eq!(len, CANVAS_SIZE.div_ceil(255) * 2);
+        assert_eq!(
+            unsafe { &crate::canvas::COMPRESSED_BUFFER_POOL[active].data[..2] },
+            &[255, 9]
+        );
+
+        // A worker built now takes that snapshot as its diff baseline, so the
+        // first diff after one write is one entry, not a million.
+        let last_sent = unsafe { &BUFFER_POOL[active].data };
+        master.apply_pixel(
+            0,
+            PixelWrite {
+                x: 3,
+                y: 2,
+                color: 1,
+                traced: false,
+                user_id: 0,
+                ipv4: 0,
+                epoch: 0,
+            },
+        );
+        let mut diff = Vec::new();
+        diff_canvas(&master.canvas.pixels[..], last_sent, &mut diff);
+        let index = (2 * CANVAS_WIDTH + 3) as u32;
+        assert_eq!(diff, [&index.to_le_bytes()[..], &[1]].concat());
+
+        // Painting over the background counts as virgin, repainting as overwrite.
+        let before = MASTER_METRICS.pixel_overwrites.load(Ordering::Relaxed);
+        master.apply_pixel(
+            0,
+            PixelWrite {
+                x: 3,
+                y: 2,
+                color: 4,
+                traced: false,
+                user_id: 0,
+                ipv4: 0,
+                epoch: 0,
+            },
+        );
+        assert!(MASTER_METRICS.pixel_overwrites.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn test_tile_checksums_follow_published_tiles() {
+        use crate::canvas::TILE_CHECKSUMS;
+        use crate::const_settings::{TILE_COUNT, TILE_SIZE};
+
+        // Slots 4 and 5; the other tests stay below.
+        let config = ServerConfig::default();
+        let mut master = MasterCore::new(Vec::new(), Canvas::new(), &config);
+        master.canvas.snapshot_to_pool(4);
+        let painted = tile_index(TILE_SIZE + 1, 2);
+        master.apply_pixel(
+            0,
+            PixelWrite {
+                x: TILE_SIZE as u16 + 1,
+                y: 2,
+                color: 6,
+                traced: false,
+                user_id: 0,
+                ipv4: 0,
+                epoch: 0,
+            },
+        );
+        master.publish_snapshot(4, 5);
+
+        let (before, after) = unsafe { (TILE_CHECKSUMS[4], TILE_CHECKSUMS[5]) };
+        let changed: Vec<usize> = (0..TILE_COUNT).filter(|&t| before[t] != after[t]).collect();
+        assert_eq!(changed, vec![painted]);
+        for (tile, &checksum) in after[..TILE_COUNT].iter().enumerate() {
+            assert_eq!(checksum, master.canvas.tile_checksum(tile));
+        }
+    }
+
+    #[test]
+    fn test_noise_canvas_goes_diff_only_and_still_syncs_per_tile() {
+        use crate::canvas::{ALL_TILES, tile_bounds, tile_pixels};
+        use crate::const_settings::TILE_COUNT;
+        use protocol::rle::{rle_decode, rle_max_len};
+
+        // Slot 6; the other tests stay below.
+        let config = ServerConfig::default();
+        let mut master = MasterCore::new(Vec::new(), Canvas::new(), &config);
+        assert!(!master.full_gate.diff_only());
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        for pixel in master.canvas.pixels.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *pixel = (state % 16) as u8;
+        }
+        master.canvas.snapshot_to_pool(6);
+        let switches = MASTER_METRICS.diff_only_switches.load(Ordering::Relaxed);
+        master.compress_slot(6);
+        assert!(master.full_gate.diff_only());
+        assert!(unsafe { COMPRESSED_LENS[6] } > config.full_broadcast_max_bytes);
+        assert!(MASTER_METRICS.diff_only_switches.load(Ordering::Relaxed) > switches);
+
+        // A client joining now gets no full broadcast; a TILE_REFRESH of
+        // every tile rebuilds the canvas.
+        let pixels = tile_pixels(unsafe { &BUFFER_POOL[6].data }, &ALL_TILES);
+        let mut rle = vec![0; rle_max_len(pixels.len())];
+        let len = rle_encode(&pixels, &mut rle);
+        let mut decoded = vec![0; pixels.len()];
+        assert_eq!(rle_decode(&rle[..len], &mut decoded), Ok(CANVAS_SIZE));
+        let mut rebuilt = vec![0; CANVAS_SIZE];
+        let mut at = 0;
+        for tile in 0..TILE_COUNT {
+            let (x0, y0, w, h) = tile_bounds(tile);
+            for y in y0..y0 + h {
+                rebuilt[y * CANVAS_WIDTH + x0..][..w].copy_from_slice(&decoded[at..][..w]);
+                at += w;
+            }
+        }
+        assert!(rebuilt[..] == master.canvas.pixels[..]);
+
+        // Painted over, the canvas compresses again.
+        master.canvas.pixels.fill(2);
+        master.canvas.snapshot_to_pool(6);
+        master.compress_slot(6);
+        assert!(!master.full_gate.diff_only());
+    }
+
+    #[test]
+    fn test_full_canvas_clear_under_user_load() {
+        use crate::const_settings::CANVAS_HEIGHT;
+
+        let config = ServerConfig::default();
+        let users = SpscRingBuffer::shared();
+        let mut master = MasterCore::new(vec![users.clone()], Canvas::new(), &config);
+        master.canvas.pixels.fill(7);
+        let clear = AdminWrite::clear(0, 0, CANVAS_WIDTH as u16, CANVAS_HEIGHT as u16, 0);
+        master.admin_ring().push(clear.unwrap()).unwrap();
+        let admin_before = MASTER_METRICS.admin_pixels_applied.load(Ordering::Relaxed);
+
+        // Users repaint row 0 every iteration while the clear runs. Each
+        // burst is applied in the iteration it arrives, and the clear still
+        // finishes in CANVAS_SIZE / budget iterations.
+        let iterations = CANVAS_SIZE.div_ceil(master.admin_budget);
+        for i in 0..iterations {
+            assert_eq!(master.canvas.pixels[CANVAS_SIZE - 1], 7);
+            for x in 0..CANVAS_WIDTH as u16 {
+                let color = 1 + (i % 15) as u8;
+                let pixel = PixelWrite {
+                    x,
+                    y: 0,
+                    color,
+                    traced: false,
+                    user_id: 0,
+                    ipv4: 0,
+                    epoch: 0,
+                };
+                assert!(users.push(pixel).is_ok());
+            }
+            master.drain();
+            assert!(users.pop().is_none());
+        }
+        assert_eq!(
+            MASTER_METRICS.admin_pixels_applied.load(Ordering::Relaxed) - admin_before,
+            CANVAS_SIZE as u64
+        );
+
+        // Row 0 holds the users' last burst, the rest is cleared.
+        let last = 1 + ((iterations - 1) % 15) as u8;
+        assert!(
+            master.canvas.pixels[..CANVAS_WIDTH]
+                .iter()
+                .all(|&p| p == last)
+        );
+        assert!(master.canvas.pixels[CANVAS_WIDTH..].iter().all(|&p| p == 0));
+        assert_eq!(master.admin.next_span(1), None);
+    }
+
+    #[test]
+    fn test_epoch_change_under_user_load_drops_old_pixels() {
+        use crate::canvas::solid_background;
+        use crate::const_settings::CANVAS_HEIGHT;
+
+        let config = ServerConfig::default();
+        let users = SpscRingBuffer::shared();
+        let mut master = MasterCore::new(vec![users.clone()], Canvas::new(), &config);
+        let clear = AdminWrite::clear(0, 0, CANVAS_WIDTH as u16, CANVAS_HEIGHT as u16, 9);
+        master.admin_ring().push(clear.unwrap()).unwrap();
+        // Left queued: applying it would race the clear test's count.
+        master.admin_budget = 0;
+        let stale_before = MASTER_METRICS.stale_epoch_pixels.load(Ordering::Relaxed);
+        let pixel = |x: u16, color, epoch| PixelWrite {
+            x,
+            y: 500,
+            color,
+            traced: false,
+            user_id: x as u32,
+            ipv4: 0,
+            epoch,
+        };
+
+        // Old-epoch pixels, some applied and some still queued when the
+        // epoch changes, behind a clear.
+        for x in 0..100 {
+            users.push(pixel(x, 7, 0)).unwrap();
+        }
+        master.drain();
+        assert_eq!(master.canvas.pixels[500 * CANVAS_WIDTH + 50], 7);
+        for x in 100..200 {
+            users.push(pixel(x, 7, 0)).unwrap();
+        }
+        master.begin_epoch(solid_background(4), 0);
+
+        // Workers that have not seen the new epoch yet still stamp the old
+        // one; those that have, the new one.
+        for x in 200..300 {
+            users.push(pixel(x, 7, 0)).unwrap();
+            if x % 10 == 0 {
+                users.push(pixel(x + 100, 2, 1)).unwrap();
+            }
+        }
+        master.drain();
+        master.drain();
+        assert!(users.pop().is_none());
+        assert_eq!(
+            MASTER_METRICS.stale_epoch_pixels.load(Ordering::Relaxed) - stale_before,
+            200
+        );
+
+        // Nothing of the old epoch survives, its clear included. The new
+        // epoch's pixels land on the new background.
+        let painted: Vec<usize> = (0..CANVAS_SIZE)
+            .filter(|&i| master.canvas.pixels[i] != 4)
+            .collect();
+        let expected: Vec<usize> = (300..400)
+            .step_by(10)
+            .map(|x| 500 * CANVAS_WIDTH + x)
+            .collect();
+        assert_eq!(painted, expected);
+        assert!(painted.iter().all(|&i| master.canvas.pixels[i] == 2));
+        assert_eq!(master.canvas.background_at(0, 0), 4);
+        assert_eq!(master.admin.next_span(1), None);
+        assert!(master.dirty_tiles.is_dirty(tile_index(300, 500)));
+        assert!(!master.dirty_tiles.is_dirty(tile_index(0, 500)));
+    }
+}
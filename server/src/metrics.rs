@@ -0,0 +1,507 @@
+use crate::aligned::SHARED_ALIGN;
+use crate::const_settings::{BROADCAST_INTERVAL_MS, MAX_WORKERS, TILE_COUNT};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// 128, not 64: see `aligned`.
+#[repr(align(128))]
+pub struct MasterMetrics {
+    /// 1 once the master thread is pinned to its core.
+    pub pinned: AtomicU64,
+    /// Dirty tiles applied on the master canvas but not yet published to the pool.
+    pub snapshot_backlog_tiles: AtomicUsize,
+    /// Broadcast interval currently used by the master (ms).
+    pub effective_broadcast_interval_ms: AtomicU64,
+    /// Times the adaptive controller lengthened the broadcast interval.
+    pub interval_stretches: AtomicU64,
+    /// Times the adaptive controller stepped the broadcast interval back down.
+    pub interval_recoveries: AtomicU64,
+    /// Pixel writes applied to the canvas.
+    pub pixels_applied: AtomicU64,
+    /// Writes that landed on a pixel no longer at its background color.
+    /// Virgin writes are `pixels_applied - pixel_overwrites`.
+    pub pixel_overwrites: AtomicU64,
+    /// Pixels applied by admin writes (admin_writes.rs), not in
+    /// `pixels_applied`.
+    pub admin_pixels_applied: AtomicU64,
+    /// User pixels dropped because they were admitted in an epoch that had
+    /// ended by the time the master got to them (epoch.rs).
+    pub stale_epoch_pixels: AtomicU64,
+    /// Micro-diff slots published for tier-1 connections (fast_diff.rs).
+    pub fast_diff_slots: AtomicU64,
+    /// Applied pixels per second, smoothed (live_stats.rs).
+    pub pixels_per_sec: AtomicU64,
+    /// RLE size of the last published snapshot (bytes).
+    pub snapshot_rle_bytes: AtomicUsize,
+    /// 1 while full broadcasts are suspended (`--full-broadcast-max-bytes`).
+    pub diff_only: AtomicU64,
+    /// Times full broadcasts were suspended or resumed.
+    pub diff_only_switches: AtomicU64,
+    /// Cumulative applied writes per tile (dashboard heatmap).
+    pub tile_writes: [AtomicU32; TILE_COUNT],
+    /// Cumulative overwrites per tile (churn).
+    pub tile_overwrites: [AtomicU32; TILE_COUNT],
+}
+
+impl MasterMetrics {
+    pub const fn new() -> Self {
+        Self {
+            pinned: AtomicU64::new(0),
+            snapshot_backlog_tiles: AtomicUsize::new(0),
+            effective_broadcast_interval_ms: AtomicU64::new(BROADCAST_INTERVAL_MS),
+            interval_stretches: AtomicU64::new(0),
+            interval_recoveries: AtomicU64::new(0),
+            pixels_applied: AtomicU64::new(0),
+            pixel_overwrites: AtomicU64::new(0),
+            admin_pixels_applied: AtomicU64::new(0),
+            stale_epoch_pixels: AtomicU64::new(0),
+            fast_diff_slots: AtomicU64::new(0),
+            pixels_per_sec: AtomicU64::new(0),
+            snapshot_rle_bytes: AtomicUsize::new(0),
+            diff_only: AtomicU64::new(0),
+            diff_only_switches: AtomicU64::new(0),
+            tile_writes: [const { AtomicU32::new(0) }; TILE_COUNT],
+            tile_overwrites: [const { AtomicU32::new(0) }; TILE_COUNT],
+        }
+    }
+
+    /// Single-writer increment: only the master thread calls this, so a
+    /// load/store pair avoids a locked RMW on the apply path. `overwrite` is
+    /// added as 0/1 to keep the apply loop branch-free.
+    #[inline(always)]
+    pub fn record_apply(&self, tile: usize, overwrite: bool) {
+        let applied = &self.pixels_applied;
+        applied.store(applied.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        let overwrites = &self.pixel_overwrites;
+        overwrites.store(
+            overwrites.load(Ordering::Relaxed) + overwrite as u64,
+            Ordering::Relaxed,
+        );
+        let writes = &self.tile_writes[tile];
+        writes.store(
+            writes.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+        let tile_overwrites = &self.tile_overwrites[tile];
+        tile_overwrites.store(
+            tile_overwrites
+                .load(Ordering::Relaxed)
+                .wrapping_add(overwrite as u32),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Fraction of applied writes that replaced an already painted pixel.
+    pub fn overwrite_ratio(&self) -> f64 {
+        let applied = self.pixels_applied.load(Ordering::Relaxed);
+        if applied == 0 {
+            return 0.0;
+        }
+        self.pixel_overwrites.load(Ordering::Relaxed) as f64 / applied as f64
+    }
+}
+
+impl Default for MasterMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static MASTER_METRICS: MasterMetrics = MasterMetrics::new();
+
+/// Indices and counts of the `n` tiles with the highest (non-zero) count,
+/// highest first; ties keep the lower tile index first.
+pub fn top_tiles(counts: &[u32], n: usize) -> Vec<(usize, u32)> {
+    let mut ranked: Vec<(usize, u32)> = counts
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(_, c)| c > 0)
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(n);
+    ranked
+}
+
+/// Upper bounds (ms) of the time-to-first-broadcast buckets; the load client
+/// uses the same ones so both sides' histograms line up.
+pub const FIRST_BROADCAST_BUCKETS_MS: &[u64] =
+    &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Upper bounds (ms) of the Initial-to-established buckets; past the last
+/// one a handshake is near `--handshake-deadline-ms`.
+pub const HANDSHAKE_BUCKETS_MS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Cumulative latency histogram with fixed bounds and an overflow bucket,
+/// exported in Prometheus form. Single writer, like the counters around it.
+pub struct LatencyHistogram {
+    pub bounds_ms: &'static [u64],
+    /// Per-bucket (non-cumulative) counts; `buckets[bounds_ms.len()]` holds
+    /// everything above the last bound.
+    buckets: [AtomicU64; HISTOGRAM_MAX_BUCKETS],
+    pub sum_ms: AtomicU64,
+    pub count: AtomicU64,
+}
+
+const HISTOGRAM_MAX_BUCKETS: usize = 16;
+
+impl LatencyHistogram {
+    pub const fn new(bounds_ms: &'static [u64]) -> Self {
+        assert!(bounds_ms.len() < HISTOGRAM_MAX_BUCKETS);
+        Self {
+            bounds_ms,
+            buckets: [const { AtomicU64::new(0) }; HISTOGRAM_MAX_BUCKETS],
+            sum_ms: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, ms: u64) {
+        let bucket = self.bounds_ms.partition_point(|&bound| bound < ms);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Observations at or below each bound, then the total (`+Inf`).
+    pub fn cumulative(&self) -> Vec<u64> {
+        let mut total = 0;
+        self.buckets[..=self.bounds_ms.len()]
+            .iter()
+            .map(|b| {
+                total += b.load(Ordering::Relaxed);
+                total
+            })
+            .collect()
+    }
+}
+
+/// Counters owned (written) by a single worker thread; each block sits on its
+/// own pair of cache lines so workers never contend with each other.
+#[repr(align(128))]
+pub struct WorkerMetrics {
+    /// 1 once the worker thread is pinned to its core.
+    pub pinned: AtomicU64,
+    /// Event loop iterations completed.
+    pub loop_iterations: AtomicU64,
+    /// Iterations whose busy time reached WORKER_LOOP_BUDGET_MS.
+    pub loop_overruns: AtomicU64,
+    /// TX item accounting faults caught (tx_pool.rs): a stale completion,
+    /// an item handed out while in use, free and in-flight items drifting.
+    pub tx_faults: AtomicU64,
+    /// Free lists rebuilt after a drift.
+    pub tx_rebuilds: AtomicU64,
+    /// Live QUIC connections (refreshed on each maintenance sweep).
+    pub connections: AtomicU64,
+    /// Pixels pushed to the master queue.
+    pub pixels_accepted: AtomicU64,
+    /// Pixels dropped because the master queue was full.
+    pub spsc_drops: AtomicU64,
+    /// Pixels rejected because the user was on cooldown.
+    pub cooldown_rejections: AtomicU64,
+    /// Pixels dropped as retransmits (idempotency token already seen).
+    pub duplicate_pixels: AtomicU64,
+    /// Pixels dropped as 0-RTT replays (nonce already seen, admission.rs).
+    pub replayed_pixels: AtomicU64,
+    /// Broadcast payload bytes queued to connections.
+    pub broadcast_bytes: AtomicU64,
+    /// Diff broadcasts replaced by a full one because the last sent snapshot
+    /// had been recycled from the pool.
+    pub recycled_diff_fallbacks: AtomicU64,
+    /// Diff broadcasts replaced by a full one because the diff outgrew
+    /// `--diff-cap-bytes`.
+    pub diff_cap_fallbacks: AtomicU64,
+    /// Connections accepted since startup; with `--steer-by-port`, how the
+    /// load test's source ports spread over the workers.
+    pub connections_accepted: AtomicU64,
+    /// Initials refused because every user id was taken.
+    pub accept_capacity_rejections: AtomicU64,
+    /// Initials `quiche::accept` failed on (TLS or config problem).
+    pub accept_errors: AtomicU64,
+    /// Initials routed to the connection an earlier one to the same DCID
+    /// started, instead of accepting a second.
+    pub duplicate_initials: AtomicU64,
+    /// Estimated bytes held by the worker's connections (conn_memory.rs).
+    pub conn_memory_bytes: AtomicU64,
+    /// Initials dropped because connections were over the soft memory limit.
+    pub memory_rejections: AtomicU64,
+    /// Handshaking connections dropped over the hard memory limit.
+    pub handshakes_reaped: AtomicU64,
+    /// Connections that moved to a new, validated peer address.
+    pub migrations: AtomicU64,
+    /// New peer paths that failed validation; the connection stays on the
+    /// old one.
+    pub migration_failures: AtomicU64,
+    /// Diff chunks skipped because their connection had spent its
+    /// bandwidth window (`--conn-kbps`).
+    pub conn_throttled_chunks: AtomicU64,
+    /// Bandwidth windows in which the worker ceiling (`--worker-kbps`) cut
+    /// diffs short.
+    pub global_throttle_windows: AtomicU64,
+    /// Full syncs postponed to the next full broadcast (`--full-sync-kbps`).
+    pub full_syncs_deferred: AtomicU64,
+    /// Raw diff bytes sent to connections that negotiated ZSTD, and what
+    /// they took on the wire (`--diff-dict`).
+    pub zstd_diff_raw_bytes: AtomicU64,
+    pub zstd_diff_wire_bytes: AtomicU64,
+    /// Connections holding a tier-1 seat (TIER control op).
+    pub tier1_connections: AtomicU64,
+    /// Micro-diff bytes queued to tier-1 connections, also counted in
+    /// `broadcast_bytes`.
+    pub fast_diff_bytes: AtomicU64,
+    /// Micro-diffs the master overwrote before this worker forwarded them.
+    pub fast_diff_laps: AtomicU64,
+    /// Parity bytes queued with full broadcasts to FEC connections.
+    pub fec_parity_bytes: AtomicU64,
+    /// Full broadcasts skipped while the master was in diff-only mode.
+    pub full_broadcasts_suppressed: AtomicU64,
+    /// Token-less Initials answered with a Retry (`--quic-retry`).
+    pub retries_sent: AtomicU64,
+    /// Initials dropped for a token that was forged, expired or minted for
+    /// another address.
+    pub retry_token_rejections: AtomicU64,
+    /// Packets of an unsupported QUIC version answered with the versions
+    /// this server speaks.
+    pub version_negotiations: AtomicU64,
+    /// Receive buffers handed back to the kernel, one per packet, and the
+    /// ProvideBuffers SQEs that took (replenish.rs).
+    pub buffers_replenished: AtomicU64,
+    pub replenish_sqes: AtomicU64,
+    /// Receives that ended with every buffer taken, kernel drops while they
+    /// waited to be re-armed, and the estimated share of those that were
+    /// handshake packets (recv_lane.rs).
+    pub recv_exhaustions: AtomicU64,
+    pub exhaustion_drops: AtomicU64,
+    pub handshake_drops_estimated: AtomicU64,
+    /// Packets received on the handshake lane (`--handshake-reserve`).
+    pub handshake_lane_packets: AtomicU64,
+    /// Handshakes completed in full and resumed from a session ticket, and
+    /// the time `conn.recv` spent on handshaking connections of each kind.
+    pub handshakes_full: AtomicU64,
+    pub handshakes_resumed: AtomicU64,
+    pub handshake_recv_full_ns: AtomicU64,
+    pub handshake_recv_resumed_ns: AtomicU64,
+    /// Pixels read from 0-RTT, before their connection was established.
+    pub early_data_pixels: AtomicU64,
+    /// User ids of closed connections held for a reconnect
+    /// (`--reserve-grace-ms`).
+    pub parked_user_ids: AtomicU64,
+    /// Parked ids a reconnecting client took back.
+    pub resumed_user_ids: AtomicU64,
+    /// Parked ids freed unclaimed: grace period over, replaced under the
+    /// same key, or needed for a new connection.
+    pub expired_reservations: AtomicU64,
+    /// Connections accepted but not yet established (half_open.rs).
+    pub half_open_connections: AtomicU64,
+    /// Age of the oldest of them, as of the last connection sweep.
+    pub half_open_oldest_ms: AtomicU64,
+    /// Handshaking connections dropped at `--handshake-deadline-ms`.
+    pub handshakes_expired: AtomicU64,
+    /// 1 while admission is tightened (`--half-open-threshold`).
+    pub half_open_tightened: AtomicU64,
+    /// Times admission tightened.
+    pub half_open_tightenings: AtomicU64,
+    /// Initials dropped while admission was tightened.
+    pub half_open_rejections: AtomicU64,
+    /// Dropped inbound packets that reached no connection, by kind
+    /// (junk.rs).
+    pub junk_too_short: AtomicU64,
+    pub junk_not_quic: AtomicU64,
+    pub junk_unsupported_version: AtomicU64,
+    pub junk_unknown_cid: AtomicU64,
+    /// Packets dropped unread from denied sources (`--strict-junk`), sources
+    /// denied, and sources on the deny list now.
+    pub junk_denied: AtomicU64,
+    pub junk_denials: AtomicU64,
+    pub junk_deny_listed: AtomicU64,
+    /// STATS replies sent, and requests dropped for coming within
+    /// STATS_MIN_INTERVAL_MS of the connection's last reply.
+    pub stats_replies: AtomicU64,
+    pub stats_requests_limited: AtomicU64,
+    /// Cooldown NACKs sent, and those skipped for coming within
+    /// COOLDOWN_NACK_INTERVAL_MS of the connection's last one.
+    pub cooldown_nacks: AtomicU64,
+    pub cooldown_nacks_limited: AtomicU64,
+    /// Receipts (PIXEL_ACK) sent to connections that negotiated RECEIPTS,
+    /// one per pixel queued for the master.
+    pub pixel_acks: AtomicU64,
+    /// Datagrams from clients dropped for a type this server does not know,
+    /// and for a known type whose body does not parse (protocol::datagram).
+    pub unknown_datagrams: AtomicU64,
+    pub malformed_datagrams: AtomicU64,
+    /// Connections in the last datagram backlog pass, and connections a
+    /// pass ran out of `--dgram-drain-budget` before reading (transport.rs).
+    pub dgram_backlog_connections: AtomicU64,
+    pub dgram_backlog_deferred: AtomicU64,
+    /// Last transport sample (transport_stats.rs): connections by state,
+    /// packet counts summed over them, and RTT and cwnd of the established
+    /// ones.
+    pub quic_handshaking: AtomicU64,
+    pub quic_established: AtomicU64,
+    pub quic_draining: AtomicU64,
+    pub quic_packets_sent: AtomicU64,
+    pub quic_packets_recv: AtomicU64,
+    pub quic_packets_lost: AtomicU64,
+    pub quic_packets_retrans: AtomicU64,
+    pub quic_rtt_min_us: AtomicU64,
+    pub quic_rtt_median_us: AtomicU64,
+    pub quic_rtt_max_us: AtomicU64,
+    pub quic_cwnd_mean_bytes: AtomicU64,
+    /// Initial to handshake completion.
+    pub handshake_ms: LatencyHistogram,
+    /// Handshake completion to the first broadcast datagram queued for the
+    /// connection: how long a new user looks at an empty canvas.
+    pub first_broadcast_ms: LatencyHistogram,
+}
+
+impl WorkerMetrics {
+    pub const fn new() -> Self {
+        Self {
+            pinned: AtomicU64::new(0),
+            loop_iterations: AtomicU64::new(0),
+            loop_overruns: AtomicU64::new(0),
+            tx_faults: AtomicU64::new(0),
+            tx_rebuilds: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            pixels_accepted: AtomicU64::new(0),
+            spsc_drops: AtomicU64::new(0),
+            cooldown_rejections: AtomicU64::new(0),
+            duplicate_pixels: AtomicU64::new(0),
+            replayed_pixels: AtomicU64::new(0),
+            broadcast_bytes: AtomicU64::new(0),
+            recycled_diff_fallbacks: AtomicU64::new(0),
+            diff_cap_fallbacks: AtomicU64::new(0),
+            connections_accepted: AtomicU64::new(0),
+            accept_capacity_rejections: AtomicU64::new(0),
+            accept_errors: AtomicU64::new(0),
+            duplicate_initials: AtomicU64::new(0),
+            conn_memory_bytes: AtomicU64::new(0),
+            memory_rejections: AtomicU64::new(0),
+            handshakes_reaped: AtomicU64::new(0),
+            migrations: AtomicU64::new(0),
+            migration_failures: AtomicU64::new(0),
+            conn_throttled_chunks: AtomicU64::new(0),
+            global_throttle_windows: AtomicU64::new(0),
+            full_syncs_deferred: AtomicU64::new(0),
+            zstd_diff_raw_bytes: AtomicU64::new(0),
+            zstd_diff_wire_bytes: AtomicU64::new(0),
+            tier1_connections: AtomicU64::new(0),
+            fast_diff_bytes: AtomicU64::new(0),
+            fast_diff_laps: AtomicU64::new(0),
+            fec_parity_bytes: AtomicU64::new(0),
+            full_broadcasts_suppressed: AtomicU64::new(0),
+            retries_sent: AtomicU64::new(0),
+            retry_token_rejections: AtomicU64::new(0),
+            version_negotiations: AtomicU64::new(0),
+            buffers_replenished: AtomicU64::new(0),
+            replenish_sqes: AtomicU64::new(0),
+            recv_exhaustions: AtomicU64::new(0),
+            exhaustion_drops: AtomicU64::new(0),
+            handshake_drops_estimated: AtomicU64::new(0),
+            handshake_lane_packets: AtomicU64::new(0),
+            handshakes_full: AtomicU64::new(0),
+            handshakes_resumed: AtomicU64::new(0),
+            handshake_recv_full_ns: AtomicU64::new(0),
+            handshake_recv_resumed_ns: AtomicU64::new(0),
+            early_data_pixels: AtomicU64::new(0),
+            parked_user_ids: AtomicU64::new(0),
+            resumed_user_ids: AtomicU64::new(0),
+            expired_reservations: AtomicU64::new(0),
+            half_open_connections: AtomicU64::new(0),
+            half_open_oldest_ms: AtomicU64::new(0),
+            handshakes_expired: AtomicU64::new(0),
+            half_open_tightened: AtomicU64::new(0),
+            half_open_tightenings: AtomicU64::new(0),
+            half_open_rejections: AtomicU64::new(0),
+            junk_too_short: AtomicU64::new(0),
+            junk_not_quic: AtomicU64::new(0),
+            junk_unsupported_version: AtomicU64::new(0),
+            junk_unknown_cid: AtomicU64::new(0),
+            junk_denied: AtomicU64::new(0),
+            junk_denials: AtomicU64::new(0),
+            junk_deny_listed: AtomicU64::new(0),
+            stats_replies: AtomicU64::new(0),
+            stats_requests_limited: AtomicU64::new(0),
+            cooldown_nacks: AtomicU64::new(0),
+            cooldown_nacks_limited: AtomicU64::new(0),
+            pixel_acks: AtomicU64::new(0),
+            unknown_datagrams: AtomicU64::new(0),
+            malformed_datagrams: AtomicU64::new(0),
+            dgram_backlog_connections: AtomicU64::new(0),
+            dgram_backlog_deferred: AtomicU64::new(0),
+            quic_handshaking: AtomicU64::new(0),
+            quic_established: AtomicU64::new(0),
+            quic_draining: AtomicU64::new(0),
+            quic_packets_sent: AtomicU64::new(0),
+            quic_packets_recv: AtomicU64::new(0),
+            quic_packets_lost: AtomicU64::new(0),
+            quic_packets_retrans: AtomicU64::new(0),
+            quic_rtt_min_us: AtomicU64::new(0),
+            quic_rtt_median_us: AtomicU64::new(0),
+            quic_rtt_max_us: AtomicU64::new(0),
+            quic_cwnd_mean_bytes: AtomicU64::new(0),
+            handshake_ms: LatencyHistogram::new(HANDSHAKE_BUCKETS_MS),
+            first_broadcast_ms: LatencyHistogram::new(FIRST_BROADCAST_BUCKETS_MS),
+        }
+    }
+}
+
+impl Default for WorkerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static WORKER_METRICS: [WorkerMetrics; MAX_WORKERS] =
+    [const { WorkerMetrics::new() }; MAX_WORKERS];
+
+const _: () = assert!(std::mem::align_of::<MasterMetrics>() == SHARED_ALIGN);
+const _: () = assert!(std::mem::align_of::<WorkerMetrics>() == SHARED_ALIGN);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overwrite_ratio_and_churn() {
+        let m = MasterMetrics::new();
+        assert_eq!(m.overwrite_ratio(), 0.0);
+
+        // 10 virgin writes spread over tiles 0..10, then tile 3 repainted 6
+        // times and tile 7 twice: 8 overwrites out of 18 writes.
+        for tile in 0..10 {
+            m.record_apply(tile, false);
+        }
+        for _ in 0..6 {
+            m.record_apply(3, true);
+        }
+        for _ in 0..2 {
+            m.record_apply(7, true);
+        }
+
+        assert_eq!(m.pixels_applied.load(Ordering::Relaxed), 18);
+        assert_eq!(m.pixel_overwrites.load(Ordering::Relaxed), 8);
+        assert!((m.overwrite_ratio() - 8.0 / 18.0).abs() < 1e-9);
+        assert_eq!(m.tile_writes[3].load(Ordering::Relaxed), 7);
+
+        let churn: Vec<u32> = m
+            .tile_overwrites
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(top_tiles(&churn, 5), vec![(3, 6), (7, 2)]);
+        assert_eq!(top_tiles(&churn, 1), vec![(3, 6)]);
+        assert_eq!(top_tiles(&[4, 9, 4, 0], 3), vec![(1, 9), (0, 4), (2, 4)]);
+    }
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        let h = LatencyHistogram::new(&[10, 100, 1000]);
+        for ms in [0, 10, 11, 100, 999, 1000, 1001, 60_000] {
+            h.record(ms);
+        }
+        // Bounds are inclusive, like Prometheus `le`.
+        assert_eq!(h.cumulative(), vec![2, 4, 6, 8]);
+        assert_eq!(h.count.load(Ordering::Relaxed), 8);
+        assert_eq!(h.sum_ms.load(Ordering::Relaxed), 63_121);
+    }
+}
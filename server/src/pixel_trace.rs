@@ -0,0 +1,110 @@
+use crate::master::PixelWrite;
+
+/// Where a sampled pixel ended up. Workers report the first four, the
+/// master the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    /// Dropped: a replay of a pixel already seen (same nonce).
+    Replay,
+    /// Dropped: a retransmit of a placement already seen (same token).
+    Duplicate,
+    /// Dropped: the sender was still on cooldown.
+    Cooldown,
+    /// Dropped: the master queue was full.
+    SpscDrop,
+    /// Handed to the master.
+    Queued,
+    /// Dropped by the master: admitted in an epoch that has since ended
+    /// (epoch.rs).
+    StaleEpoch,
+    /// Outside the canvas; the master ignored it.
+    OutOfBounds,
+    /// Written to the canvas.
+    Applied,
+    /// Written, but the pixel already had that color: no diff will carry it.
+    AppliedNoChange,
+    /// Its tile went out with a snapshot and still shows this color.
+    Published,
+    /// Its tile went out with a snapshot, but a later write won.
+    Overwritten,
+}
+
+impl Fate {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fate::Replay => "replay",
+            Fate::Duplicate => "duplicate",
+            Fate::Cooldown => "cooldown",
+            Fate::SpscDrop => "spsc_drop",
+            Fate::Queued => "queued",
+            Fate::StaleEpoch => "stale_epoch",
+            Fate::OutOfBounds => "out_of_bounds",
+            Fate::Applied => "applied",
+            Fate::AppliedNoChange => "applied_no_change",
+            Fate::Published => "published",
+            Fate::Overwritten => "overwritten",
+        }
+    }
+}
+
+/// Picks one pixel in `every` for `--trace-pixels`. Off (never samples)
+/// when `every` is 0; otherwise costs one decrement per pixel.
+pub struct PixelSampler {
+    every: u32,
+    countdown: u32,
+}
+
+impl PixelSampler {
+    pub fn new(every: u32) -> Self {
+        Self {
+            every,
+            countdown: every,
+        }
+    }
+
+    #[inline(always)]
+    pub fn sample(&mut self) -> bool {
+        if self.every == 0 {
+            return false;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.every;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// One line per step of a sampled pixel; grep by `x=.. y=.. color=..` to
+/// follow it from worker to snapshot.
+#[cold]
+pub fn log(stage: &str, pixel: &PixelWrite, fate: Fate) {
+    println!(
+        "pixel-trace {} x={} y={} color={} fate={}",
+        stage,
+        pixel.x,
+        pixel.y,
+        pixel.color,
+        fate.as_str()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler_picks_one_in_n() {
+        let mut off = PixelSampler::new(0);
+        assert!((0..1000).all(|_| !off.sample()));
+
+        let mut every = PixelSampler::new(4);
+        let picked: Vec<usize> = (0..12).filter(|_| every.sample()).collect();
+        assert_eq!(picked, vec![3, 7, 11]);
+
+        let mut all = PixelSampler::new(1);
+        assert!((0..10).all(|_| all.sample()));
+    }
+}
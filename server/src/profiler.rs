@@ -0,0 +1,266 @@
+//! Phase sampling for the worker loop (`--profile-hz`), for hosts where
+//! perf is not available or attaching it would change what it measures.
+//!
+//! Each worker marks which phase of its loop it is in, with the time its
+//! iteration started: one relaxed store per phase, always on. With
+//! `--profile-hz` set, a sampler thread reads every worker's marker at that
+//! rate and counts the samples per phase, plus how long the iteration in
+//! progress had been running. `/admin/profile` on the dashboard returns
+//! both per worker. No stacks and no symbols: the phases are the loop's
+//! own, so the counts read as the share of time spent in each.
+
+use crate::const_settings::MAX_WORKERS;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A phase of the worker loop, in loop order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Phase {
+    /// Blocked in io_uring, waiting for completions.
+    Wait,
+    /// Cooldown ticks.
+    Tick,
+    /// Sending snapshot broadcasts.
+    Broadcast,
+    /// Handling completions: packets in, sends done.
+    Receive,
+    /// Reading on from connections with datagrams left queued.
+    Backlog,
+    /// Flushing QUIC packets into send SQEs.
+    Flush,
+    /// Timeouts, connection cleanup, stats.
+    Maintain,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::Wait,
+        Phase::Tick,
+        Phase::Broadcast,
+        Phase::Receive,
+        Phase::Backlog,
+        Phase::Flush,
+        Phase::Maintain,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Wait => "wait",
+            Phase::Tick => "tick",
+            Phase::Broadcast => "broadcast",
+            Phase::Receive => "receive",
+            Phase::Backlog => "backlog",
+            Phase::Flush => "flush",
+            Phase::Maintain => "maintain",
+        }
+    }
+}
+
+/// Upper bounds (ms) of the iteration-age buckets; the last bucket takes
+/// everything above.
+pub const ITERATION_BUCKETS_MS: [u64; 7] = [1, 2, 5, 10, 25, 50, 100];
+
+/// A worker's current phase and the start (ms) of its iteration, 0 while
+/// it waits: `started_ms << 8 | phase`.
+pub struct PhaseMarker(AtomicU64);
+
+impl PhaseMarker {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline(always)]
+    pub fn enter(&self, phase: Phase, started_ms: u64) {
+        self.0
+            .store((started_ms << 8) | phase as u64, Ordering::Relaxed);
+    }
+
+    fn read(&self) -> (Phase, u64) {
+        let state = self.0.load(Ordering::Relaxed);
+        let phase = Phase::ALL
+            .get((state & 0xff) as usize)
+            .copied()
+            .unwrap_or(Phase::Wait);
+        (phase, state >> 8)
+    }
+}
+
+/// What the sampler has counted for one worker.
+pub struct PhaseProfile {
+    samples: [AtomicU64; Phase::ALL.len()],
+    /// Age of the iteration in progress when sampled, by
+    /// ITERATION_BUCKETS_MS. Samples taken while waiting are not counted.
+    iteration_ms: [AtomicU64; ITERATION_BUCKETS_MS.len() + 1],
+}
+
+impl PhaseProfile {
+    const fn new() -> Self {
+        Self {
+            samples: [const { AtomicU64::new(0) }; Phase::ALL.len()],
+            iteration_ms: [const { AtomicU64::new(0) }; ITERATION_BUCKETS_MS.len() + 1],
+        }
+    }
+}
+
+/// Written by each worker.
+pub static PHASE_MARKERS: [PhaseMarker; MAX_WORKERS] = [const { PhaseMarker::new() }; MAX_WORKERS];
+
+/// Written by the sampler thread only.
+pub static PROFILES: [PhaseProfile; MAX_WORKERS] = [const { PhaseProfile::new() }; MAX_WORKERS];
+
+/// The sampler's rate; 0 until it starts.
+static SAMPLE_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the sampler thread, reading `num_workers` workers `hz` times a
+/// second.
+pub fn spawn(hz: u32, num_workers: usize) {
+    SAMPLE_HZ.store(hz, Ordering::Relaxed);
+    let period = Duration::from_secs(1) / hz.max(1);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(period);
+            let now_ms = crate::time::CLOCK.now_ms();
+            for worker in 0..num_workers {
+                sample(worker, now_ms);
+            }
+        }
+    });
+}
+
+/// Takes one sample of `worker`.
+pub fn sample(worker: usize, now_ms: u64) {
+    let (phase, started_ms) = PHASE_MARKERS[worker].read();
+    let profile = &PROFILES[worker];
+    profile.samples[phase as usize].fetch_add(1, Ordering::Relaxed);
+    if phase != Phase::Wait && started_ms != 0 {
+        let age = now_ms.saturating_sub(started_ms);
+        let bucket = ITERATION_BUCKETS_MS.partition_point(|&bound| bound < age);
+        profile.iteration_ms[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// `/admin/profile`: `{"hz":n,"workers":[..]}`, one `worker_json` each.
+pub fn profile_json(num_workers: usize) -> String {
+    let workers: Vec<String> = (0..num_workers).map(worker_json).collect();
+    format!(
+        "{{\"hz\":{},\"workers\":[{}]}}",
+        SAMPLE_HZ.load(Ordering::Relaxed),
+        workers.join(",")
+    )
+}
+
+fn worker_json(worker: usize) -> String {
+    let profile = &PROFILES[worker];
+    let mut out = String::new();
+    let samples: Vec<u64> = profile
+        .samples
+        .iter()
+        .map(|s| s.load(Ordering::Relaxed))
+        .collect();
+    let _ = write!(
+        out,
+        "{{\"worker\":{},\"samples\":{},\"phases\":{{",
+        worker,
+        samples.iter().sum::<u64>()
+    );
+    for (i, (phase, n)) in Phase::ALL.iter().zip(&samples).enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "\"{}\":{}", phase.name(), n);
+    }
+    let le: Vec<String> = ITERATION_BUCKETS_MS.iter().map(|b| b.to_string()).collect();
+    let counts: Vec<String> = profile
+        .iteration_ms
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed).to_string())
+        .collect();
+    let _ = write!(
+        out,
+        "}},\"iteration_ms\":{{\"le\":[{}],\"counts\":[{}]}}}}",
+        le.join(","),
+        counts.join(",")
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn test_samples_follow_the_time_spent_per_phase() {
+        let worker = MAX_WORKERS - 27;
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            // A synthetic loop: 60% receiving, 30% flushing, 10% waiting.
+            s.spawn(|| {
+                let marker = &PHASE_MARKERS[worker];
+                for _ in 0..12 {
+                    let started_ms = now_ms();
+                    marker.enter(Phase::Receive, started_ms);
+                    std::thread::sleep(Duration::from_millis(30));
+                    marker.enter(Phase::Flush, started_ms);
+                    std::thread::sleep(Duration::from_millis(15));
+                    marker.enter(Phase::Wait, 0);
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            let start = Instant::now();
+            while !done.load(Ordering::Relaxed) {
+                sample(worker, now_ms());
+                std::thread::sleep(Duration::from_micros(500));
+            }
+            assert!(start.elapsed() >= Duration::from_millis(600));
+        });
+
+        let profile = &PROFILES[worker];
+        let count = |phase: Phase| profile.samples[phase as usize].load(Ordering::Relaxed) as f64;
+        let total: f64 = Phase::ALL.iter().map(|&p| count(p)).sum();
+        assert!(total > 300.0, "{} samples", total);
+        let share = |phase| count(phase) / total;
+        assert!(
+            (share(Phase::Receive) - 0.6).abs() < 0.1,
+            "{}",
+            share(Phase::Receive)
+        );
+        assert!(
+            (share(Phase::Flush) - 0.3).abs() < 0.1,
+            "{}",
+            share(Phase::Flush)
+        );
+        assert!(
+            (share(Phase::Wait) - 0.1).abs() < 0.1,
+            "{}",
+            share(Phase::Wait)
+        );
+        assert_eq!(count(Phase::Broadcast), 0.0);
+
+        // Iterations run 45 ms: no busy sample is older than that bucket.
+        let ages: Vec<u64> = profile
+            .iteration_ms
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        let busy = count(Phase::Receive) + count(Phase::Flush);
+        assert_eq!(ages.iter().sum::<u64>() as f64, busy);
+        assert_eq!(ages[ITERATION_BUCKETS_MS.len()], 0);
+
+        let json = worker_json(worker);
+        assert!(json.starts_with(&format!("{{\"worker\":{},\"samples\":{},", worker, total)));
+        assert!(json.contains(",\"broadcast\":0,"));
+        assert!(json.contains("\"iteration_ms\":{\"le\":[1,2,5,10,25,50,100],\"counts\":["));
+    }
+}
@@ -0,0 +1,65 @@
+//! Per-connection qlog traces (`--qlog-dir`), for diagnosing connections
+//! that stall. Tracing every connection at 40k of them would swamp the
+//! disk, so each worker traces 1 in `--qlog-sample` of the connections it
+//! accepts, starting with its first. A trace is one JSON-SEQ file per
+//! connection, named by the server's source connection id in hex.
+//!
+//! quiche owns the writer: it drops it once the connection is closed, which
+//! flushes it, and `finish` flushes the trace of a connection dropped while
+//! still open.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+pub struct QlogSampler {
+    dir: PathBuf,
+    /// Trace 1 in `one_in` accepted connections.
+    one_in: u64,
+    accepted: u64,
+}
+
+impl QlogSampler {
+    pub fn new(dir: PathBuf, one_in: u64) -> Self {
+        Self {
+            dir,
+            one_in: one_in.max(1),
+            accepted: 0,
+        }
+    }
+
+    /// Starts a trace for the connection just accepted with `scid`, if it
+    /// is sampled. A trace file that cannot be created is skipped.
+    pub fn trace(&mut self, conn: &mut quiche::Connection, scid: &[u8]) {
+        let sampled = self.accepted % self.one_in == 0;
+        self.accepted += 1;
+        if !sampled {
+            return;
+        }
+        let path = trace_path(&self.dir, scid);
+        match File::create(&path) {
+            Ok(file) => conn.set_qlog(
+                Box::new(BufWriter::new(file)),
+                "canvas server".to_string(),
+                format!("connection {}", hex(scid)),
+            ),
+            Err(e) => eprintln!("qlog: cannot create {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Ends the trace of a connection that is being dropped.
+pub fn finish(conn: &mut quiche::Connection) {
+    if let Some(streamer) = conn.qlog_streamer() {
+        // Flushes the writer.
+        streamer.finish_log().ok();
+    }
+}
+
+pub fn trace_path(dir: &Path, scid: &[u8]) -> PathBuf {
+    dir.join(format!("{}.sqlog", hex(scid)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,207 @@
+//! The pixel rate each connection is held to, and told (RATE_CONTRACT in
+//! protocol::control): one accepted pixel per cooldown. The cooldown is
+//! `--cooldown-secs`, stretched by the load factor while the adaptive
+//! controller (health.rs) has the broadcast interval stretched, up to the
+//! timing wheel's horizon. Tier-1 connections keep the configured cooldown
+//! whatever the load.
+//!
+//! The contract is what the worker enforces: every accepted pixel is
+//! charged exactly its cooldown (`Contract::enforced_secs`), so a client
+//! pacing to what it was told is never rejected. A looser contract applies
+//! at once. A tighter one is told first and enforced CONTRACT_NOTICE_MS
+//! later, which leaves the push time to arrive; pixels accepted in between
+//! still pay the old cooldown.
+
+use crate::const_settings::{CONTRACT_NOTICE_MS, TIMING_WHEEL_TICKS};
+use protocol::control::{COOLDOWN_MULTIPLIER_ONE, RateContract};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const LOAD_ONE: u32 = COOLDOWN_MULTIPLIER_ONE as u32;
+
+/// The load factor in thousandths, published by the master.
+static LOAD_PERMILLE: AtomicU32 = AtomicU32::new(LOAD_ONE);
+
+/// Publishes the load factor: how far the adaptive controller has
+/// stretched the broadcast interval past its base.
+pub fn set_load(interval_ms: u64, base_ms: u64) {
+    let permille = (interval_ms * LOAD_ONE as u64 / base_ms.max(1)).min(u32::MAX as u64);
+    LOAD_PERMILLE.store(permille as u32, Ordering::Relaxed);
+}
+
+pub fn load_permille() -> u32 {
+    LOAD_PERMILLE.load(Ordering::Relaxed)
+}
+
+/// What one worker derives contracts from: the configured cooldown and the
+/// last load factor it saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContractTerms {
+    pub base_secs: u32,
+    pub load_permille: u32,
+}
+
+impl ContractTerms {
+    pub const DEFAULT: ContractTerms = ContractTerms {
+        base_secs: TIMING_WHEEL_TICKS as u32,
+        load_permille: LOAD_ONE,
+    };
+
+    /// The cooldown of a connection, in seconds (wheel ticks).
+    pub fn cooldown_secs(&self, tier1: bool) -> u32 {
+        if tier1 {
+            return self.base_secs;
+        }
+        let stretched = self.base_secs as u64 * self.load_permille.max(LOAD_ONE) as u64;
+        (stretched / LOAD_ONE as u64).min(TIMING_WHEEL_TICKS as u64) as u32
+    }
+
+    /// `cooldown_secs` as a COOLDOWN_CONFIG multiplier.
+    pub fn multiplier_permille(&self, cooldown_secs: u32) -> u16 {
+        let permille = cooldown_secs as u64 * LOAD_ONE as u64 / self.base_secs.max(1) as u64;
+        permille.min(u16::MAX as u64) as u16
+    }
+}
+
+/// One connection's contract: what it was last told and what is enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contract {
+    /// Changes so far; the latest was told under this number.
+    seq: u32,
+    /// Charged to pixels now; 0 without a connection.
+    secs: u32,
+    /// A tighter cooldown already told, and when it is enforced (ms).
+    tightening: Option<(u32, u64)>,
+    /// Streams pushed so far (contracts, and cooldowns to connections with
+    /// COOLDOWNS), which numbers the next one.
+    pushes: u32,
+}
+
+impl Contract {
+    pub const NONE: Contract = Contract {
+        seq: 0,
+        secs: 0,
+        tightening: None,
+        pushes: 0,
+    };
+
+    /// A new connection's first contract, in force at once.
+    pub fn new(secs: u32) -> Self {
+        Self {
+            seq: 1,
+            secs,
+            ..Self::NONE
+        }
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Moves the contract to `secs` at `now_ms`, with notice if that is
+    /// tighter than what is enforced. Returns whether it changed.
+    pub fn update(&mut self, secs: u32, now_ms: u64) -> bool {
+        let told = self.tightening.map_or(self.secs, |(secs, _)| secs);
+        if secs == told {
+            return false;
+        }
+        self.seq += 1;
+        if secs > self.secs {
+            self.tightening = Some((secs, now_ms + CONTRACT_NOTICE_MS));
+        } else {
+            self.secs = secs;
+            self.tightening = None;
+        }
+        true
+    }
+
+    /// The cooldown to charge a pixel accepted at `now_ms`.
+    #[inline]
+    pub fn enforced_secs(&mut self, now_ms: u64) -> u32 {
+        if let Some((secs, at)) = self.tightening
+            && now_ms >= at
+        {
+            self.secs = secs;
+            self.tightening = None;
+        }
+        self.secs
+    }
+
+    /// The contract as told at `now_ms`.
+    pub fn told(&self, now_ms: u64) -> RateContract {
+        let (secs, effective_in_ms) = match self.tightening {
+            Some((secs, at)) => (secs, at.saturating_sub(now_ms) as u32),
+            None => (self.secs, 0),
+        };
+        RateContract {
+            seq: self.seq,
+            interval_ms: secs * 1000,
+            effective_in_ms,
+        }
+    }
+
+    /// The stream to push on next, contract or cooldown: the server's
+    /// unidirectional streams are 3, 7, 11 and so on.
+    pub fn next_push_stream(&mut self) -> u64 {
+        self.pushes += 1;
+        4 * self.pushes as u64 - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tightening_is_told_before_it_is_enforced() {
+        let terms = ContractTerms {
+            base_secs: 60,
+            ..ContractTerms::DEFAULT
+        };
+        let mut contract = Contract::new(terms.cooldown_secs(false));
+        assert_eq!(contract.enforced_secs(0), 60);
+        assert!(!contract.update(60, 0));
+
+        // Load stretches it 1.5 times: told now, enforced after the notice.
+        let loaded = ContractTerms {
+            load_permille: 1_500,
+            ..terms
+        };
+        assert!(contract.update(loaded.cooldown_secs(false), 10_000));
+        let told = contract.told(11_000);
+        assert_eq!((told.seq, told.interval_ms), (2, 90_000));
+        assert_eq!(told.effective_in_ms, CONTRACT_NOTICE_MS as u32 - 1_000);
+        assert_eq!(contract.enforced_secs(11_000), 60);
+        assert_eq!(contract.enforced_secs(10_000 + CONTRACT_NOTICE_MS), 90);
+        assert_eq!(contract.told(20_000).effective_in_ms, 0);
+
+        // Back down: at once.
+        assert!(contract.update(terms.cooldown_secs(false), 30_000));
+        assert_eq!(contract.enforced_secs(30_000), 60);
+        assert_eq!(contract.told(30_000).seq, 3);
+
+        // Tier 1 ignores the load; nobody goes past the wheel.
+        assert_eq!(loaded.cooldown_secs(true), 60);
+        let crushed = ContractTerms {
+            load_permille: 10_000,
+            ..terms
+        };
+        assert_eq!(crushed.cooldown_secs(false), TIMING_WHEEL_TICKS as u32);
+        assert_eq!(
+            loaded.multiplier_permille(90),
+            COOLDOWN_MULTIPLIER_ONE * 3 / 2
+        );
+
+        let pushes: Vec<u64> = (0..3).map(|_| contract.next_push_stream()).collect();
+        assert_eq!(pushes, [3, 7, 11]);
+    }
+
+    #[test]
+    fn test_easing_during_notice_cancels_the_tightening() {
+        let mut contract = Contract::new(60);
+        contract.update(120, 0);
+        assert!(contract.update(60, 1_000));
+        assert_eq!(contract.told(1_000).effective_in_ms, 0);
+        assert_eq!(contract.enforced_secs(CONTRACT_NOTICE_MS), 60);
+        assert_eq!(contract.told(CONTRACT_NOTICE_MS).interval_ms, 60_000);
+    }
+}
@@ -0,0 +1,191 @@
+//! A lane is one receive: a socket, the RecvMsgMulti armed on it and the
+//! provided-buffer group the kernel fills for it. Every worker has a data
+//! lane on its member of the reuseport group.
+//!
+//! Under a burst the data lane's buffers can all be taken before the worker
+//! gets to them. RecvMsgMulti then ends with ENOBUFS and packets wait in the
+//! socket buffer until the pass hands buffers back (replenish.rs); once that
+//! overflows too, the kernel drops. A dropped Initial costs its client a
+//! retransmission timeout, a second or more: the long tail of connect
+//! times under load.
+//!
+//! With `--handshake-reserve` each worker gets a handshake lane as well: a
+//! second socket the steering program sends long-header packets to
+//! (steering.rs), with HANDSHAKE_NUM_BUFFERS buffers of its own. A flood of
+//! pixel datagrams exhausts the data lane only; handshakes keep their
+//! buffers.
+//!
+//! Without the reserve (not asked for, or the program refused) handshakes
+//! share the data lane, and it estimates what they lost. The socket's
+//! kernel drop counter is read when the receive ends with ENOBUFS and
+//! again when it is re-armed; the drops in between are split by the share
+//! of long headers among the packets the lane received since its previous
+//! exhaustion (`canvas_worker_handshake_drops_estimated_total`).
+
+use crate::const_settings::PKT_BUF_SIZE;
+use crate::replenish::Replenisher;
+use socket2::Socket;
+use std::os::unix::io::AsRawFd;
+
+pub struct RecvLane {
+    pub socket: Socket,
+    pub slab: Vec<u8>,
+    /// Slab buffers handled this pass, waiting to go back to the kernel.
+    pub replenisher: Replenisher,
+    pub num_buffers: u16,
+    pub bgid: u16,
+    /// user_data of the lane's RecvMsgMulti completions.
+    pub tag: u64,
+    /// False once RecvMsgMulti has ended, until it is re-armed after the
+    /// pass.
+    pub armed: bool,
+    pub drops: DropWindow,
+}
+
+impl RecvLane {
+    pub fn new(socket: Socket, num_buffers: u16, bgid: u16, tag: u64) -> Self {
+        Self {
+            socket,
+            slab: vec![0; PKT_BUF_SIZE * num_buffers as usize],
+            replenisher: Replenisher::new(num_buffers),
+            num_buffers,
+            bgid,
+            tag,
+            armed: false,
+            drops: DropWindow::default(),
+        }
+    }
+}
+
+/// Kernel drops of one lane between an ENOBUFS and the re-arm.
+#[derive(Debug, Default)]
+pub struct DropWindow {
+    /// The socket's drop count when the receive ended; None while armed.
+    opened_at: Option<u32>,
+    /// Packets received since the previous window closed, and how many of
+    /// them had a long header.
+    packets: u64,
+    long_headers: u64,
+}
+
+/// What the kernel dropped during one window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowDrops {
+    pub dropped: u64,
+    /// `dropped` scaled by the lane's share of long headers.
+    pub handshake_estimate: u64,
+}
+
+impl DropWindow {
+    /// Counts a received packet by the first byte of its UDP payload.
+    #[inline(always)]
+    pub fn packet(&mut self, payload: &[u8]) {
+        self.packets += 1;
+        if payload.first().is_some_and(|&b| b & 0x80 != 0) {
+            self.long_headers += 1;
+        }
+    }
+
+    /// The receive ended with ENOBUFS; the socket has dropped `drops`
+    /// datagrams so far.
+    pub fn open(&mut self, drops: u32) {
+        self.opened_at.get_or_insert(drops);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.opened_at.is_some()
+    }
+
+    /// The receive is re-armed with `drops` dropped so far. None if no
+    /// window was open.
+    pub fn close(&mut self, drops: u32) -> Option<WindowDrops> {
+        let opened_at = self.opened_at.take()?;
+        let dropped = drops.wrapping_sub(opened_at) as u64;
+        let handshake_estimate = (dropped * self.long_headers)
+            .checked_div(self.packets)
+            .unwrap_or(0);
+        self.packets = 0;
+        self.long_headers = 0;
+        Some(WindowDrops {
+            dropped,
+            handshake_estimate,
+        })
+    }
+}
+
+/// Datagrams the kernel dropped on `socket` since it was created
+/// (`SK_MEMINFO_DROPS`), mostly for a full receive buffer.
+pub fn kernel_drops(socket: &Socket) -> Option<u32> {
+    // The kernel copies as much of its array as fits.
+    let mut meminfo = [0u32; libc::SK_MEMINFO_DROPS as usize + 1];
+    let mut len = std::mem::size_of_val(&meminfo) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MEMINFO,
+            meminfo.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0 && len as usize == std::mem::size_of_val(&meminfo))
+        .then(|| meminfo[libc::SK_MEMINFO_DROPS as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Type};
+
+    const INITIAL: &[u8] = &[0xc3, 0, 0, 0, 1];
+    const SHORT: &[u8] = &[0x43, 1, 2, 3];
+
+    #[test]
+    fn test_window_splits_drops_by_long_header_share() {
+        let mut window = DropWindow::default();
+        assert_eq!(window.close(5), None);
+
+        for _ in 0..3 {
+            window.packet(SHORT);
+        }
+        window.packet(INITIAL);
+        window.packet(&[]);
+        window.open(1_000);
+        // A second ENOBUFS before the re-arm keeps the first reading.
+        window.open(1_010);
+        assert_eq!(
+            window.close(1_100),
+            Some(WindowDrops {
+                dropped: 100,
+                handshake_estimate: 20,
+            })
+        );
+
+        // The share restarts with each window; the counter wraps.
+        window.packet(INITIAL);
+        window.open(u32::MAX - 1);
+        assert_eq!(
+            window.close(2),
+            Some(WindowDrops {
+                dropped: 4,
+                handshake_estimate: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_kernel_drops_counts_overflow() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&addr.into()).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(kernel_drops(&socket), Some(0));
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..64 {
+            client.send_to(&[0u8; 1200], addr).unwrap();
+        }
+        assert!(kernel_drops(&socket).unwrap() > 0);
+    }
+}
@@ -0,0 +1,321 @@
+//! Sticky user ids (`--reserve-grace-ms`). A client that drops and
+//! reconnects would otherwise get a fresh user id with no cooldown, and its
+//! old id would go to someone else. Instead, the id of an established
+//! connection that closes is parked for the grace period, with its cooldown
+//! and pixel tokens and nonces left as they are, under a resumption key:
+//!
+//! - the token the connection presented with RESUME
+//!   (`protocol::control::ControlOp::Resume`), or else
+//! - the peer IP it was accepted from.
+//!
+//! A new connection from that IP, or one presenting the token, takes the id
+//! back. Reservations that run out are freed when the worker ticks its
+//! timing wheel (once a second), so the grace period is rounded up to the
+//! next tick. An id freed that way gets the same treatment as one freed on
+//! disconnect.
+//!
+//! Parked ids come out of the worker's pool. When every other id is taken,
+//! the reservation closest to expiry is freed to make room for the new
+//! connection. Each key holds one reservation; parking a second id under it
+//! frees the first. Every client behind one NAT shares the IP fallback, so
+//! after a disconnect there, the next client to connect may inherit someone
+//! else's cooldown.
+
+use crate::const_settings::MAX_CONNECTIONS_PER_WORKER;
+use crate::metrics::WorkerMetrics;
+use protocol::control::RESUME_TOKEN_LEN;
+use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How long a closed connection's id stays parked (ms); 0 = ids are freed
+/// on disconnect. Set once at startup.
+static GRACE_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn configure(grace_ms: u64) {
+    GRACE_MS.store(grace_ms, Ordering::Relaxed);
+}
+
+pub fn grace_ms() -> u64 {
+    GRACE_MS.load(Ordering::Relaxed)
+}
+
+/// What a parked id is found by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResumeKey {
+    Token([u8; RESUME_TOKEN_LEN]),
+    Peer(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Parked {
+    user_id: u32,
+    expires_ms: u64,
+}
+
+/// The parked user ids of one worker and the key each live one will park
+/// under. Freed ids are handed to a `free` callback; putting them back in
+/// the pool and releasing their cooldown is the caller's job.
+pub struct Reservations {
+    grace_ms: u64,
+    /// Indexed by user id; empty when parking is off.
+    keys: Box<[Option<ResumeKey>]>,
+    parked: FxHashMap<ResumeKey, Parked>,
+    /// Parking order, which is expiry order. Entries whose id was taken
+    /// back or parked again since are skipped when they come up.
+    expiry: VecDeque<(u64, ResumeKey)>,
+    metrics: &'static WorkerMetrics,
+}
+
+impl Reservations {
+    /// Parks for the configured grace period.
+    pub fn new(metrics: &'static WorkerMetrics) -> Self {
+        Self::with_grace(grace_ms(), metrics)
+    }
+
+    pub fn with_grace(grace_ms: u64, metrics: &'static WorkerMetrics) -> Self {
+        let users = if grace_ms > 0 {
+            MAX_CONNECTIONS_PER_WORKER
+        } else {
+            0
+        };
+        Self {
+            grace_ms,
+            keys: vec![None; users].into_boxed_slice(),
+            parked: FxHashMap::default(),
+            expiry: VecDeque::new(),
+            metrics,
+        }
+    }
+
+    #[inline(always)]
+    pub fn enabled(&self) -> bool {
+        self.grace_ms > 0
+    }
+
+    /// Ids currently parked.
+    pub fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parked.is_empty()
+    }
+
+    /// `user_id` will park under `key` when its connection closes.
+    pub fn bind(&mut self, user_id: u32, key: ResumeKey) {
+        if let Some(slot) = self.keys.get_mut(user_id as usize) {
+            *slot = Some(key);
+        }
+    }
+
+    /// `user_id` went back to the pool without parking.
+    pub fn unbind(&mut self, user_id: u32) {
+        if let Some(slot) = self.keys.get_mut(user_id as usize) {
+            *slot = None;
+        }
+    }
+
+    /// Takes the id parked under `key` back for a new connection; it will
+    /// park under the same key again.
+    pub fn claim(&mut self, key: &ResumeKey) -> Option<u32> {
+        let parked = self.parked.remove(key)?;
+        self.keys[parked.user_id as usize] = Some(*key);
+        self.metrics
+            .resumed_user_ids
+            .fetch_add(1, Ordering::Relaxed);
+        self.publish();
+        Some(parked.user_id)
+    }
+
+    /// Parks the id of a connection that just closed. False if it has no
+    /// key (parking is off): the caller frees it.
+    pub fn park(&mut self, user_id: u32, now_ms: u64, mut free: impl FnMut(u32)) -> bool {
+        let Some(key) = self.keys.get_mut(user_id as usize).and_then(Option::take) else {
+            return false;
+        };
+        let expires_ms = now_ms + self.grace_ms;
+        if let Some(previous) = self.parked.insert(
+            key,
+            Parked {
+                user_id,
+                expires_ms,
+            },
+        ) {
+            self.metrics
+                .expired_reservations
+                .fetch_add(1, Ordering::Relaxed);
+            free(previous.user_id);
+        }
+        self.expiry.push_back((expires_ms, key));
+        self.publish();
+        true
+    }
+
+    /// Frees every reservation whose grace period is over.
+    pub fn sweep(&mut self, now_ms: u64, mut free: impl FnMut(u32)) {
+        while let Some(&(expires_ms, _)) = self.expiry.front()
+            && expires_ms <= now_ms
+        {
+            self.expire_front(&mut free);
+        }
+    }
+
+    /// Frees the reservation closest to expiry, for a new connection that
+    /// found the pool empty. False if nothing is parked.
+    pub fn evict_oldest(&mut self, mut free: impl FnMut(u32)) -> bool {
+        while !self.expiry.is_empty() {
+            if self.expire_front(&mut free) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expire_front(&mut self, free: &mut impl FnMut(u32)) -> bool {
+        let Some((expires_ms, key)) = self.expiry.pop_front() else {
+            return false;
+        };
+        let Some(parked) = self.parked.get(&key).copied() else {
+            return false;
+        };
+        if parked.expires_ms != expires_ms {
+            return false;
+        }
+        self.parked.remove(&key);
+        self.metrics
+            .expired_reservations
+            .fetch_add(1, Ordering::Relaxed);
+        self.publish();
+        free(parked.user_id);
+        true
+    }
+
+    fn publish(&self) {
+        self.metrics
+            .parked_user_ids
+            .store(self.parked.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::MAX_WORKERS;
+    use crate::metrics::WORKER_METRICS;
+
+    fn token(byte: u8) -> ResumeKey {
+        ResumeKey::Token([byte; RESUME_TOKEN_LEN])
+    }
+
+    #[test]
+    fn test_reclaim_within_window() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 4];
+        let mut reservations = Reservations::with_grace(5_000, metrics);
+        let mut freed = Vec::new();
+
+        reservations.bind(7, token(1));
+        reservations.bind(8, ResumeKey::Peer("10.0.0.1".parse().unwrap()));
+        assert!(reservations.park(7, 1_000, |id| freed.push(id)));
+        assert!(reservations.park(8, 1_000, |id| freed.push(id)));
+        // Parked once: the key went with it.
+        assert!(!reservations.park(7, 1_000, |id| freed.push(id)));
+        assert_eq!(reservations.len(), 2);
+
+        reservations.sweep(5_999, |id| freed.push(id));
+        assert!(freed.is_empty());
+        assert_eq!(reservations.claim(&token(2)), None);
+        assert_eq!(reservations.claim(&token(1)), Some(7));
+        assert_eq!(reservations.claim(&token(1)), None);
+        assert_eq!(
+            reservations.claim(&ResumeKey::Peer("10.0.0.1".parse().unwrap())),
+            Some(8)
+        );
+
+        // A taken-back id parks under its key again, on a fresh window; the
+        // stale expiry of its first parking does not free it.
+        assert!(reservations.park(7, 4_000, |id| freed.push(id)));
+        reservations.sweep(8_999, |id| freed.push(id));
+        assert!(freed.is_empty());
+        assert_eq!(reservations.claim(&token(1)), Some(7));
+        assert_eq!(metrics.resumed_user_ids.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.parked_user_ids.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_expired_reservations_are_freed() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 5];
+        let mut reservations = Reservations::with_grace(2_000, metrics);
+        let mut freed = Vec::new();
+
+        for (user_id, at) in [(1, 0), (2, 500), (3, 1_500)] {
+            reservations.bind(user_id, token(user_id as u8));
+            assert!(reservations.park(user_id, at, |id| freed.push(id)));
+        }
+        reservations.sweep(2_500, |id| freed.push(id));
+        assert_eq!(freed, vec![1, 2]);
+        assert_eq!(reservations.claim(&token(1)), None);
+        reservations.sweep(3_500, |id| freed.push(id));
+        assert_eq!(freed, vec![1, 2, 3]);
+        assert!(reservations.is_empty());
+
+        // A second id parked under the same key frees the first.
+        reservations.bind(4, token(9));
+        reservations.bind(5, token(9));
+        assert!(reservations.park(4, 4_000, |id| freed.push(id)));
+        assert!(reservations.park(5, 4_100, |id| freed.push(id)));
+        assert_eq!(freed, vec![1, 2, 3, 4]);
+        reservations.sweep(6_050, |id| freed.push(id));
+        assert_eq!(freed.len(), 4);
+        reservations.sweep(6_100, |id| freed.push(id));
+        assert_eq!(freed, vec![1, 2, 3, 4, 5]);
+        assert_eq!(metrics.expired_reservations.load(Ordering::Relaxed), 5);
+
+        // Unbound ids and a worker with parking off never park.
+        reservations.bind(6, token(6));
+        reservations.unbind(6);
+        assert!(!reservations.park(6, 7_000, |id| freed.push(id)));
+        let mut off = Reservations::with_grace(0, metrics);
+        off.bind(6, token(6));
+        assert!(!off.enabled() && !off.park(6, 7_000, |id| freed.push(id)));
+    }
+
+    #[test]
+    fn test_full_pool_evicts_parked_ids() {
+        // A worker whose whole pool is parked: new connections still get
+        // ids, oldest reservation first, and the freshest survive.
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 6];
+        let mut reservations = Reservations::with_grace(60_000, metrics);
+        let mut free: Vec<u32> = Vec::new();
+        const USERS: u32 = 1_000;
+        for user_id in 0..USERS {
+            reservations.bind(
+                user_id,
+                ResumeKey::Peer(IpAddr::from(user_id.to_be_bytes())),
+            );
+            assert!(reservations.park(user_id, user_id as u64, |id| free.push(id)));
+        }
+        assert!(free.is_empty());
+        assert_eq!(reservations.len(), USERS as usize);
+
+        // Take one back first, so its stale expiry entry is skipped.
+        let last = ResumeKey::Peer(IpAddr::from(0u32.to_be_bytes()));
+        assert_eq!(reservations.claim(&last), Some(0));
+        for _ in 0..USERS / 2 {
+            assert!(free.is_empty());
+            assert!(reservations.evict_oldest(|id| free.push(id)));
+            free.pop().unwrap();
+        }
+        assert_eq!(reservations.len(), USERS as usize / 2 - 1);
+        let newest = ResumeKey::Peer(IpAddr::from((USERS - 1).to_be_bytes()));
+        assert_eq!(reservations.claim(&newest), Some(USERS - 1));
+        let evicted = ResumeKey::Peer(IpAddr::from(300u32.to_be_bytes()));
+        assert_eq!(reservations.claim(&evicted), None);
+
+        while reservations.evict_oldest(|id| free.push(id)) {}
+        assert_eq!(free.len(), USERS as usize / 2 - 2);
+        assert!(!reservations.evict_oldest(|id| free.push(id)));
+        assert!(reservations.is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! QUIC address validation (`--quic-retry`, RFC 9000 section 8.1). Without
+//! it, every Initial with an unknown DCID gets a connection and a user id
+//! on the spot, so spoofed source addresses can use up a worker's ids for
+//! the price of one packet each, at least until the handshake deadline.
+//!
+//! With it, an Initial without a token is answered with a Retry and
+//! nothing else: no connection, no user id. The Retry carries a token the
+//! client has to echo in its next Initial, which only arrives if the source
+//! address is real. The token is stateless: an HMAC-SHA256, under a key
+//! drawn per worker at startup, over
+//!
+//! - the client's address and port;
+//! - the DCID of its first Initial (the original DCID, which quiche needs
+//!   to accept the connection and which the client checks);
+//! - the SCID the Retry gave out, which the client's next Initial must be
+//!   addressed to;
+//! - an expiry, `--retry-token-ttl-ms` after the Retry.
+//!
+//! The original DCID and expiry travel in the token in the clear, followed
+//! by the tag. A key per worker is enough: the Retry and the client's
+//! answer come from the same address and port, so SO_REUSEPORT delivers
+//! both to the same worker.
+
+use ring::hmac;
+use std::net::{IpAddr, SocketAddr};
+
+/// Expiry (u64), original DCID length (u8) and DCID, then the tag.
+pub const RETRY_TOKEN_MAX_LEN: usize = 8 + 1 + quiche::MAX_CONN_ID_LEN + TAG_LEN;
+
+const TAG_LEN: usize = 32;
+
+/// What the tag covers: address (16, IPv4 mapped), port (2), expiry (8),
+/// original DCID with its length, Retry SCID with its length.
+const MESSAGE_MAX_LEN: usize = 16 + 2 + 8 + 2 * (1 + quiche::MAX_CONN_ID_LEN);
+
+/// Mints and checks one worker's Retry tokens.
+pub struct RetryTokens {
+    key: hmac::Key,
+    ttl_ms: u64,
+}
+
+impl RetryTokens {
+    pub fn new(ttl_ms: u64) -> Self {
+        let secret: [u8; 32] = rand::random();
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            ttl_ms,
+        }
+    }
+
+    /// Writes the token for a Retry that sends `peer`, whose first Initial
+    /// went to `odcid`, to `retry_scid`. Returns its length.
+    pub fn mint(
+        &self,
+        peer: SocketAddr,
+        odcid: &[u8],
+        retry_scid: &[u8],
+        now_ms: u64,
+        out: &mut [u8; RETRY_TOKEN_MAX_LEN],
+    ) -> usize {
+        let expires_ms = now_ms.saturating_add(self.ttl_ms);
+        let mut message = [0; MESSAGE_MAX_LEN];
+        let len = message_for(peer, expires_ms, odcid, retry_scid, &mut message);
+        let tag = hmac::sign(&self.key, &message[..len]);
+
+        out[..8].copy_from_slice(&expires_ms.to_be_bytes());
+        out[8] = odcid.len() as u8;
+        out[9..9 + odcid.len()].copy_from_slice(odcid);
+        let tag_at = 9 + odcid.len();
+        out[tag_at..tag_at + TAG_LEN].copy_from_slice(tag.as_ref());
+        tag_at + TAG_LEN
+    }
+
+    /// The original DCID `token` vouches for, if this worker minted it for
+    /// `peer` and a Retry SCID of `dcid`, and it has not expired.
+    pub fn validate<'a>(
+        &self,
+        token: &'a [u8],
+        peer: SocketAddr,
+        dcid: &[u8],
+        now_ms: u64,
+    ) -> Option<&'a [u8]> {
+        let expires_ms = u64::from_be_bytes(token.get(..8)?.try_into().unwrap());
+        let odcid_len = *token.get(8)? as usize;
+        if odcid_len > quiche::MAX_CONN_ID_LEN
+            || dcid.len() > quiche::MAX_CONN_ID_LEN
+            || token.len() != 9 + odcid_len + TAG_LEN
+        {
+            return None;
+        }
+        let (odcid, tag) = token[9..].split_at(odcid_len);
+        let mut message = [0; MESSAGE_MAX_LEN];
+        let len = message_for(peer, expires_ms, odcid, dcid, &mut message);
+        hmac::verify(&self.key, &message[..len], tag).ok()?;
+        (now_ms < expires_ms).then_some(odcid)
+    }
+}
+
+fn message_for(
+    peer: SocketAddr,
+    expires_ms: u64,
+    odcid: &[u8],
+    retry_scid: &[u8],
+    out: &mut [u8; MESSAGE_MAX_LEN],
+) -> usize {
+    let ip = match peer.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    out[..16].copy_from_slice(&ip.octets());
+    out[16..18].copy_from_slice(&peer.port().to_be_bytes());
+    out[18..26].copy_from_slice(&expires_ms.to_be_bytes());
+    let mut at = 26;
+    for cid in [odcid, retry_scid] {
+        out[at] = cid.len() as u8;
+        out[at + 1..at + 1 + cid.len()].copy_from_slice(cid);
+        at += 1 + cid.len();
+    }
+    at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_binds_address_cids_and_expiry() {
+        let tokens = RetryTokens::new(10_000);
+        let peer: SocketAddr = "192.0.2.7:51000".parse().unwrap();
+        let (odcid, retry_scid) = ([7u8; 16], [9u8; quiche::MAX_CONN_ID_LEN]);
+        let mut token = [0; RETRY_TOKEN_MAX_LEN];
+        let len = tokens.mint(peer, &odcid, &retry_scid, 1_000, &mut token);
+        let token = &token[..len];
+
+        assert_eq!(
+            tokens.validate(token, peer, &retry_scid, 10_999),
+            Some(&odcid[..])
+        );
+        assert_eq!(tokens.validate(token, peer, &retry_scid, 11_000), None);
+
+        // Another port, another address, another SCID.
+        let other_port: SocketAddr = "192.0.2.7:51001".parse().unwrap();
+        let other_ip: SocketAddr = "192.0.2.8:51000".parse().unwrap();
+        assert_eq!(tokens.validate(token, other_port, &retry_scid, 2_000), None);
+        assert_eq!(tokens.validate(token, other_ip, &retry_scid, 2_000), None);
+        assert_eq!(tokens.validate(token, peer, &[8; 20], 2_000), None);
+
+        // A pushed-out expiry breaks the tag; so does another worker's key.
+        let mut extended = token.to_vec();
+        extended[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(tokens.validate(&extended, peer, &retry_scid, 2_000), None);
+        let other_worker = RetryTokens::new(10_000);
+        assert_eq!(other_worker.validate(token, peer, &retry_scid, 2_000), None);
+
+        // Truncated or padded.
+        assert_eq!(
+            tokens.validate(&token[..len - 1], peer, &retry_scid, 2_000),
+            None
+        );
+        assert_eq!(tokens.validate(&token[..8], peer, &retry_scid, 2_000), None);
+        let mut padded = token.to_vec();
+        padded.push(0);
+        assert_eq!(tokens.validate(&padded, peer, &retry_scid, 2_000), None);
+    }
+}
@@ -0,0 +1,417 @@
+//! Environment fingerprint of a run: kernel, CPU, mitigations, NICs, the
+//! sysctls that bound UDP throughput and what io_uring offers. Two benchmark
+//! runs are only comparable if these match, so main gathers them once at
+//! startup. They are part of the CONFIG dump (`"environment"`), which is
+//! also written to `<--metrics-dir>/run-info.json` for
+//! bench/plot_results.py.
+//!
+//! Everything is read from procfs and sysfs under a root directory (`/` in
+//! production). Whatever a container or kernel does not expose is `null`.
+
+use crate::runtime_config::json_str;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Version of the run-info.json layout.
+pub const RUN_INFO_VERSION: u32 = 1;
+
+/// CPU flags worth recording: those the hot paths or the clock depend on.
+const CPU_FLAGS: [&str; 9] = [
+    "sse4_2",
+    "avx2",
+    "avx512f",
+    "aes",
+    "pclmulqdq",
+    "constant_tsc",
+    "nonstop_tsc",
+    "invariant_tsc",
+    "hypervisor",
+];
+
+/// Sysctls that change UDP and io_uring behavior, by their dotted name.
+const SYSCTLS: [&str; 9] = [
+    "net.core.rmem_max",
+    "net.core.wmem_max",
+    "net.core.rmem_default",
+    "net.core.wmem_default",
+    "net.core.netdev_max_backlog",
+    "net.core.busy_poll",
+    "net.ipv4.udp_mem",
+    "net.ipv4.udp_rmem_min",
+    "kernel.io_uring_disabled",
+];
+
+/// Opcodes the workers submit.
+pub const IO_URING_OPCODES: [&str; 3] = ["ProvideBuffers", "RecvMsgMulti", "SendMsg"];
+
+/// What a throwaway ring told us about io_uring.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UringProbe {
+    /// Why a ring with the workers' setup flags could not be created.
+    pub error: Option<String>,
+    /// Support for each of IO_URING_OPCODES; empty without a ring.
+    pub opcodes: Vec<(&'static str, bool)>,
+}
+
+/// Creates a ring set up like the workers' and asks which of their opcodes
+/// the kernel supports.
+#[cfg(target_os = "linux")]
+pub fn probe_io_uring() -> UringProbe {
+    use io_uring::{IoUring, Probe, opcode};
+
+    let ring: IoUring = match IoUring::builder()
+        .setup_coop_taskrun()
+        .setup_single_issuer()
+        .build(8)
+    {
+        Ok(ring) => ring,
+        Err(e) => {
+            return UringProbe {
+                error: Some(e.to_string()),
+                opcodes: Vec::new(),
+            };
+        }
+    };
+    let mut probe = Probe::new();
+    if let Err(e) = ring.submitter().register_probe(&mut probe) {
+        return UringProbe {
+            error: Some(format!("probe: {}", e)),
+            opcodes: Vec::new(),
+        };
+    }
+    let codes = [
+        opcode::ProvideBuffers::CODE,
+        opcode::RecvMsgMulti::CODE,
+        opcode::SendMsg::CODE,
+    ];
+    UringProbe {
+        error: None,
+        opcodes: IO_URING_OPCODES
+            .into_iter()
+            .zip(codes)
+            .map(|(name, code)| (name, probe.is_supported(code)))
+            .collect(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe_io_uring() -> UringProbe {
+    UringProbe {
+        error: Some("not Linux".to_string()),
+        opcodes: Vec::new(),
+    }
+}
+
+static ENVIRONMENT: OnceLock<String> = OnceLock::new();
+
+/// Records the fingerprint gathered at startup. Called once by main.
+pub fn publish(environment: String) {
+    let _ = ENVIRONMENT.set(environment);
+}
+
+/// The published fingerprint; `null` before `publish`.
+pub fn published_environment() -> &'static str {
+    ENVIRONMENT.get().map_or("null", String::as_str)
+}
+
+/// `{"version":n,"started_unix_s":n,"server":{..}}`, `server` being the
+/// CONFIG dump (build, config, settings and `environment`).
+pub fn run_info_json(started_unix_s: u64, server: &str) -> String {
+    format!(
+        "{{\"version\":{},\"started_unix_s\":{},\"server\":{}}}",
+        RUN_INFO_VERSION, started_unix_s, server
+    )
+}
+
+/// Writes run-info.json into `dir`; returns the path written.
+pub fn write_run_info(dir: &Path, json: &str) -> std::io::Result<std::path::PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("run-info.json");
+    std::fs::write(&path, json)?;
+    Ok(path)
+}
+
+/// The fingerprint of the machine whose procfs and sysfs are under `root`.
+pub fn environment_json(root: &Path, io_uring: &UringProbe) -> String {
+    let read = |path: &str| {
+        std::fs::read_to_string(root.join(path))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    let mut out = String::with_capacity(2048);
+
+    let _ = write!(
+        out,
+        "{{\"kernel\":{{\"sysname\":{},\"release\":{},\"version\":{},\"machine\":{},\"hostname\":{}}}",
+        json_opt(read("proc/sys/kernel/ostype").as_deref()),
+        json_opt(read("proc/sys/kernel/osrelease").as_deref()),
+        json_opt(read("proc/sys/kernel/version").as_deref()),
+        json_str(std::env::consts::ARCH),
+        json_opt(read("proc/sys/kernel/hostname").as_deref())
+    );
+
+    let cpuinfo = read("proc/cpuinfo").unwrap_or_default();
+    let field = |name: &str| {
+        cpuinfo.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name).then(|| value.trim())
+        })
+    };
+    let logical_cpus = cpuinfo
+        .lines()
+        .filter(|line| {
+            line.split(':')
+                .next()
+                .is_some_and(|k| k.trim() == "processor")
+        })
+        .count();
+    let flags: Vec<String> = field("flags").map_or(Vec::new(), |flags| {
+        let present: Vec<&str> = flags.split_whitespace().collect();
+        CPU_FLAGS
+            .iter()
+            .filter(|f| present.contains(f))
+            .map(|f| json_str(f))
+            .collect()
+    });
+    let _ = write!(
+        out,
+        ",\"cpu\":{{\"model\":{},\"logical_cpus\":{},\"flags\":{},\"governor\":{}}}",
+        json_opt(field("model name")),
+        if cpuinfo.is_empty() {
+            "null".to_string()
+        } else {
+            logical_cpus.to_string()
+        },
+        if cpuinfo.is_empty() {
+            "null".to_string()
+        } else {
+            format!("[{}]", flags.join(","))
+        },
+        json_opt(read("sys/devices/system/cpu/cpu0/cpufreq/scaling_governor").as_deref())
+    );
+
+    let cmdline = read("proc/cmdline");
+    let mitigations_param = cmdline.as_deref().and_then(|cmdline| {
+        cmdline
+            .split_whitespace()
+            .find_map(|param| param.strip_prefix("mitigations="))
+    });
+    let vulnerabilities =
+        sorted_entries(&root.join("sys/devices/system/cpu/vulnerabilities")).map(|names| {
+            let fields: Vec<String> = names
+                .iter()
+                .map(|name| {
+                    let state = read(&format!("sys/devices/system/cpu/vulnerabilities/{}", name));
+                    format!("{}:{}", json_str(name), json_opt(state.as_deref()))
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        });
+    let _ = write!(
+        out,
+        ",\"mitigations\":{{\"cmdline\":{},\"vulnerabilities\":{}}}",
+        // Booting without the parameter means the kernel's default, "auto".
+        match (&cmdline, mitigations_param) {
+            (None, _) => "null".to_string(),
+            (Some(_), param) => json_str(param.unwrap_or("auto")),
+        },
+        vulnerabilities.as_deref().unwrap_or("null")
+    );
+
+    let sysctls: Vec<String> = SYSCTLS
+        .iter()
+        .map(|name| {
+            let value = read(&format!("proc/sys/{}", name.replace('.', "/")));
+            format!("{}:{}", json_str(name), json_opt(value.as_deref()))
+        })
+        .collect();
+    let _ = write!(out, ",\"sysctls\":{{{}}}", sysctls.join(","));
+
+    let nics = sorted_entries(&root.join("sys/class/net")).map(|names| {
+        let nics: Vec<String> = names
+            .iter()
+            .filter(|name| name.as_str() != "lo")
+            .map(|name| {
+                let dir = format!("sys/class/net/{}", name);
+                let queues = |prefix: &str| {
+                    sorted_entries(&root.join(&dir).join("queues")).map(|q| {
+                        q.iter()
+                            .filter(|q| q.starts_with(prefix))
+                            .count()
+                            .to_string()
+                    })
+                };
+                let driver = std::fs::read_link(root.join(&dir).join("device/driver"))
+                    .ok()
+                    .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+                format!(
+                    "{{\"name\":{},\"driver\":{},\"mtu\":{},\"speed_mbps\":{},\"tx_queue_len\":{},\"rx_queues\":{},\"tx_queues\":{}}}",
+                    json_str(name),
+                    json_opt(driver.as_deref()),
+                    json_num(read(&format!("{}/mtu", dir))),
+                    // Virtual NICs report -1 or refuse the read.
+                    json_num(read(&format!("{}/speed", dir)).filter(|s| !s.starts_with('-'))),
+                    json_num(read(&format!("{}/tx_queue_len", dir))),
+                    queues("rx-").unwrap_or_else(|| "null".to_string()),
+                    queues("tx-").unwrap_or_else(|| "null".to_string())
+                )
+            })
+            .collect();
+        format!("[{}]", nics.join(","))
+    });
+    let _ = write!(out, ",\"nics\":{}", nics.as_deref().unwrap_or("null"));
+
+    let opcodes: Vec<String> = io_uring
+        .opcodes
+        .iter()
+        .map(|(name, supported)| format!("{}:{}", json_str(name), supported))
+        .collect();
+    let _ = write!(
+        out,
+        ",\"io_uring\":{{\"available\":{},\"error\":{},\"opcodes\":{{{}}}}}}}",
+        io_uring.error.is_none(),
+        json_opt(io_uring.error.as_deref()),
+        opcodes.join(",")
+    );
+    out
+}
+
+/// Names in `dir`, sorted; None if it cannot be listed.
+fn sorted_entries(dir: &Path) -> Option<Vec<String>> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    Some(names)
+}
+
+fn json_opt(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_str)
+}
+
+/// A number read from a file, or null if the file is missing or not one.
+fn json_num(value: Option<String>) -> String {
+    value
+        .and_then(|v| v.parse::<i64>().ok())
+        .map_or("null".to_string(), |n| n.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("canvas-run-info-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn put(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_environment_from_a_fake_root() {
+        let root = fake_root("full");
+        put(&root, "proc/sys/kernel/ostype", "Linux\n");
+        put(&root, "proc/sys/kernel/osrelease", "6.8.0-45-generic\n");
+        put(
+            &root,
+            "proc/cpuinfo",
+            "processor\t: 0\nmodel name\t: AMD EPYC 7502P\nflags\t\t: fpu avx2 aes constant_tsc\n\nprocessor\t: 1\nmodel name\t: AMD EPYC 7502P\n",
+        );
+        put(
+            &root,
+            "sys/devices/system/cpu/cpu0/cpufreq/scaling_governor",
+            "performance\n",
+        );
+        put(&root, "proc/cmdline", "ro quiet mitigations=off\n");
+        put(
+            &root,
+            "sys/devices/system/cpu/vulnerabilities/spectre_v2",
+            "Vulnerable\n",
+        );
+        put(
+            &root,
+            "sys/devices/system/cpu/vulnerabilities/meltdown",
+            "Not affected\n",
+        );
+        put(&root, "proc/sys/net/core/rmem_max", "16777216\n");
+        put(
+            &root,
+            "proc/sys/net/ipv4/udp_mem",
+            "764283\t1019046\t1528566\n",
+        );
+        put(&root, "sys/class/net/lo/mtu", "65536\n");
+        put(&root, "sys/class/net/eth0/mtu", "9000\n");
+        put(&root, "sys/class/net/eth0/speed", "-1\n");
+        put(&root, "sys/class/net/eth0/queues/rx-0/rps_cpus", "0\n");
+        put(&root, "sys/class/net/eth0/queues/rx-1/rps_cpus", "0\n");
+        put(&root, "sys/class/net/eth0/queues/tx-0/xps_cpus", "0\n");
+        let probe = UringProbe {
+            error: None,
+            opcodes: vec![("RecvMsgMulti", true), ("SendMsg", false)],
+        };
+
+        let json = environment_json(&root, &probe);
+        let _ = std::fs::remove_dir_all(&root);
+        assert!(json.starts_with(
+            "{\"kernel\":{\"sysname\":\"Linux\",\"release\":\"6.8.0-45-generic\",\"version\":null,"
+        ));
+        assert!(json.contains(
+            "\"cpu\":{\"model\":\"AMD EPYC 7502P\",\"logical_cpus\":2,\"flags\":[\"avx2\",\"aes\",\"constant_tsc\"],\"governor\":\"performance\"}"
+        ));
+        assert!(json.contains(
+            "\"mitigations\":{\"cmdline\":\"off\",\"vulnerabilities\":{\"meltdown\":\"Not affected\",\"spectre_v2\":\"Vulnerable\"}}"
+        ));
+        assert!(json.contains("\"net.core.rmem_max\":\"16777216\",\"net.core.wmem_max\":null,"));
+        assert!(json.contains("\"net.ipv4.udp_mem\":\"764283\\u00091019046\\u00091528566\""));
+        assert!(json.contains(
+            "\"nics\":[{\"name\":\"eth0\",\"driver\":null,\"mtu\":9000,\"speed_mbps\":null,\"tx_queue_len\":null,\"rx_queues\":2,\"tx_queues\":1}]"
+        ));
+        assert!(json.ends_with(
+            "\"io_uring\":{\"available\":true,\"error\":null,\"opcodes\":{\"RecvMsgMulti\":true,\"SendMsg\":false}}}"
+        ));
+
+        assert_eq!(
+            run_info_json(1_700_000_000, "{\"build\":{}}"),
+            "{\"version\":1,\"started_unix_s\":1700000000,\"server\":{\"build\":{}}}"
+        );
+    }
+
+    #[test]
+    fn test_missing_proc_files_are_null() {
+        // A locked-down container: nothing under /proc or /sys is readable.
+        let root = fake_root("empty");
+        let probe = UringProbe {
+            error: Some("Operation not permitted (os error 1)".to_string()),
+            opcodes: Vec::new(),
+        };
+        let json = environment_json(&root, &probe);
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(
+            json,
+            format!(
+                "{{\"kernel\":{{\"sysname\":null,\"release\":null,\"version\":null,\"machine\":\"{}\",\"hostname\":null}},\"cpu\":{{\"model\":null,\"logical_cpus\":null,\"flags\":null,\"governor\":null}},\"mitigations\":{{\"cmdline\":null,\"vulnerabilities\":null}},\"sysctls\":{{{}}},\"nics\":null,\"io_uring\":{{\"available\":false,\"error\":\"Operation not permitted (os error 1)\",\"opcodes\":{{}}}}}}",
+                std::env::consts::ARCH,
+                SYSCTLS
+                    .iter()
+                    .map(|s| format!("\"{}\":null", s))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+        );
+
+        // The real machine, whatever it exposes, still gives a fingerprint.
+        let real = environment_json(Path::new("/"), &UringProbe::default());
+        assert!(real.starts_with("{\"kernel\":{") && real.ends_with("}}"));
+    }
+}
@@ -0,0 +1,393 @@
+//! Effective configuration of the running server (defaults merged with the
+//! command line, the derived constants, the build and what each worker got
+//! from the kernel), served as `/config.json` and by the CONFIG control op.
+
+use crate::bandwidth::{BANDWIDTH_LIMITS, Limits};
+use crate::config::ServerConfig;
+use crate::const_settings::{
+    BROADCAST_INTERVAL_MS, CANVAS_BUFFER_POOL_SIZE, CANVAS_HEIGHT, CANVAS_WIDTH, MAX_WORKERS,
+    SOCKET_RECV_BUF_SIZE, SOCKET_SEND_BUF_SIZE, TILE_SIZE, TIMING_WHEEL_TICKS, derived,
+};
+use crate::metrics::WORKER_METRICS;
+use protocol::rle::rle_encoder;
+use protocol::version::{BuildInfo, Features, PROTOCOL_MAJOR, PROTOCOL_MINOR};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Stands in for any value that must not leave the process. The dump is
+/// readable by anyone who can reach the dashboard or open a control stream.
+pub const REDACTED: &str = "<redacted>";
+
+/// Commit the binary was built from (see build.rs).
+pub const GIT_HASH: &str = match option_env!("CANVAS_GIT_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+/// Optional protocol features this server always implements.
+pub const SERVER_FEATURES: Features = Features::BATCHING
+    .union(Features::SUBSCRIPTIONS)
+    .union(Features::STATS)
+    .union(Features::CONTRACTS)
+    .union(Features::RECEIPTS)
+    .union(Features::EPOCHS)
+    .union(Features::COOLDOWNS)
+    .union(Features::INTERVALS);
+
+/// Features offered in VERSION replies: SERVER_FEATURES plus those that
+/// depend on the command line (ZSTD with `--diff-dict`, FEC unless
+/// `--fec-k 0`).
+pub fn server_features() -> Features {
+    SERVER_FEATURES | crate::diff_dict::features() | crate::fec::features()
+}
+
+/// What the server says about itself in a VERSION reply.
+pub fn build_info() -> BuildInfo {
+    let build = format!("server {}+{}", env!("CARGO_PKG_VERSION"), GIT_HASH);
+    BuildInfo::current(server_features(), &build)
+}
+
+/// What one worker actually got at startup, which may differ from what it
+/// asked for (the kernel caps socket buffers at `net.core.rmem_max`).
+pub struct WorkerFacts {
+    /// usize::MAX until the worker has started.
+    pub core_id: AtomicUsize,
+    pub socket_recv_buf: AtomicUsize,
+    pub socket_send_buf: AtomicUsize,
+    pub sq_entries: AtomicU32,
+    pub cq_entries: AtomicU32,
+}
+
+impl WorkerFacts {
+    pub const fn new() -> Self {
+        Self {
+            core_id: AtomicUsize::new(usize::MAX),
+            socket_recv_buf: AtomicUsize::new(0),
+            socket_send_buf: AtomicUsize::new(0),
+            sq_entries: AtomicU32::new(0),
+            cq_entries: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Default for WorkerFacts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static WORKER_FACTS: [WorkerFacts; MAX_WORKERS] = [const { WorkerFacts::new() }; MAX_WORKERS];
+
+static PUBLISHED: OnceLock<(ServerConfig, usize)> = OnceLock::new();
+
+/// Records the configuration the server started with. Called once by main.
+pub fn publish(config: &ServerConfig, num_workers: usize) {
+    let _ = PUBLISHED.set((config.clone(), num_workers));
+}
+
+/// The dump for the published configuration; `null` before `publish`.
+pub fn published_json() -> String {
+    match PUBLISHED.get() {
+        Some((config, num_workers)) => config_json(config, *num_workers),
+        None => "null".to_string(),
+    }
+}
+
+pub fn config_json(config: &ServerConfig, num_workers: usize) -> String {
+    let mut out = String::with_capacity(4096);
+
+    let mut features = Vec::new();
+    if cfg!(feature = "debug-logs") {
+        features.push(json_str("debug-logs"));
+    }
+    let _ = write!(
+        out,
+        "{{\"build\":{{\"version\":{},\"git\":{},\"features\":[{}],\"protocol\":\"{}.{}\",\"protocol_features\":[{}],\"rle_encoder\":\"{}\"}}",
+        json_str(env!("CARGO_PKG_VERSION")),
+        json_str(GIT_HASH),
+        features.join(","),
+        PROTOCOL_MAJOR,
+        PROTOCOL_MINOR,
+        server_features()
+            .names()
+            .into_iter()
+            .map(json_str)
+            .collect::<Vec<_>>()
+            .join(","),
+        rle_encoder().name()
+    );
+
+    let c = config;
+    let _ = write!(
+        out,
+        ",\"config\":{{\"port\":{},\"workers\":{},\"snapshot_diff_budget_bytes\":{},\"adaptive_broadcast\":{},\"max_broadcast_interval_ms\":{},\"instance_name\":{},\"cpu_list\":{},\"strict_affinity\":{},\"lock_dir\":{},\"cert_path\":{},\"key_path\":{},\"generate_cert\":{},\"san\":[{}],\"dashboard_bind\":{},\"background\":{},\"background_image\":{},\"timeline_minutes\":{},\"trace_pixels\":{},\"demo_bots\":{},\"conn_kbps\":{},\"worker_kbps\":{},\"full_sync_kbps\":{},\"diff_dict\":{},\"conn_mem_soft_bytes\":{},\"conn_mem_hard_bytes\":{},\"handshake_deadline_ms\":{},\"half_open_threshold\":{},\"strict_junk\":{},\"junk_threshold\":{},\"junk_deny_ttl_ms\":{},\"junk_deny_max\":{},\"quic_retry\":{},\"retry_token_ttl_ms\":{},\"idle_timeout_ms\":{},\"cooldown_secs\":{},\"tier1_tokens\":{},\"tier1_per_worker\":{},\"fast_diff_ms\":{},\"fec_k\":{},\"reserve_grace_ms\":{},\"full_broadcast_ms\":{},\"full_broadcast_max_bytes\":{},\"diff_cap_bytes\":{},\"admin_drain_ratio\":{},\"dgram_drain_cap\":{},\"dgram_drain_budget\":{},\"steer_by_port\":{},\"handshake_reserve\":{},\"metrics_dir\":{},\"qlog_dir\":{},\"qlog_sample\":{},\"profile_hz\":{},\"run_as\":{},\"sandbox\":{}}}",
+        c.port,
+        json_opt(c.workers),
+        c.snapshot_diff_budget_bytes,
+        c.adaptive_broadcast,
+        c.max_broadcast_interval_ms,
+        c.instance_name
+            .as_deref()
+            .map_or("null".to_string(), json_str),
+        c.cpu_list.as_ref().map_or("null".to_string(), |cores| {
+            let cores: Vec<String> = cores.iter().map(|c| c.to_string()).collect();
+            format!("[{}]", cores.join(","))
+        }),
+        c.strict_affinity,
+        json_path(&c.lock_dir),
+        json_path(&c.cert_path),
+        // Where the private key lives is nobody else's business.
+        json_str(REDACTED),
+        c.generate_cert,
+        c.san
+            .iter()
+            .map(|name| json_str(name))
+            .collect::<Vec<_>>()
+            .join(","),
+        c.dashboard_bind
+            .map_or("null".to_string(), |addr| json_str(&addr.to_string())),
+        c.background,
+        c.background_image
+            .as_deref()
+            .map_or("null".to_string(), json_path),
+        c.timeline_minutes,
+        c.trace_pixels,
+        c.demo_bots,
+        c.bandwidth.conn_kbps,
+        c.bandwidth.worker_kbps,
+        c.bandwidth.full_sync_kbps,
+        c.diff_dict.as_deref().map_or("null".to_string(), json_path),
+        c.conn_memory.soft_bytes,
+        c.conn_memory.hard_bytes,
+        c.half_open.deadline_ms,
+        c.half_open.threshold,
+        c.junk.strict,
+        c.junk.threshold,
+        c.junk.deny_ttl_ms,
+        c.junk.deny_max,
+        c.quic_retry,
+        c.retry_token_ttl_ms,
+        c.idle_timeout_ms,
+        c.cooldown_secs,
+        // The tokens are credentials; even their location stays private.
+        c.tier1_tokens
+            .as_ref()
+            .map_or("null".to_string(), |_| json_str(REDACTED)),
+        c.tier1_per_worker,
+        c.fast_diff_ms,
+        c.fec_k,
+        c.reserve_grace_ms,
+        c.full_broadcast_ms,
+        c.full_broadcast_max_bytes,
+        c.diff_cap_bytes,
+        c.admin_drain_ratio,
+        c.dgram_drain_cap,
+        c.dgram_drain_budget,
+        c.steer_by_port,
+        c.handshake_reserve,
+        c.metrics_dir
+            .as_deref()
+            .map_or("null".to_string(), json_path),
+        c.qlog_dir.as_deref().map_or("null".to_string(), json_path),
+        c.qlog_sample,
+        c.profile_hz,
+        c.run_as
+            .as_ref()
+            .map_or("null".to_string(), |run_as| json_str(&run_as.to_string())),
+        c.sandbox
+    );
+
+    // Hard conflicts never get this far; soft ones stay on record.
+    let warnings: Vec<String> = crate::config_check::check(config)
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"rule\":{},\"detail\":{}}}",
+                json_str(c.rule),
+                json_str(&c.detail)
+            )
+        })
+        .collect();
+    let _ = write!(out, ",\"warnings\":[{}]", warnings.join(","));
+
+    let d = derived();
+    let _ = write!(
+        out,
+        ",\"derived\":{{\"max_connections_per_worker\":{},\"cooldown_array_len\":{},\"io_uring_num_buffers\":{},\"io_uring_sq_depth\":{},\"tx_capacity\":{},\"mem_buffer_slab\":{},\"mem_tx_items\":{},\"mem_dest_cache\":{},\"mem_cooldown\":{},\"mem_subscriptions\":{},\"mem_first_broadcast\":{},\"mem_bandwidth\":{},\"mem_timing_wheel\":{},\"mem_connections\":{},\"mem_per_worker\":{}}}",
+        d.max_connections_per_worker,
+        d.cooldown_array_len,
+        d.io_uring_num_buffers,
+        d.io_uring_sq_depth,
+        d.tx_capacity,
+        d.mem_buffer_slab,
+        d.mem_tx_items,
+        d.mem_dest_cache,
+        d.mem_cooldown,
+        d.mem_subscriptions,
+        d.mem_first_broadcast,
+        d.mem_bandwidth,
+        d.mem_timing_wheel,
+        d.mem_connections,
+        d.mem_per_worker
+    );
+    let _ = write!(
+        out,
+        ",\"settings\":{{\"canvas_width\":{},\"canvas_height\":{},\"tile_size\":{},\"broadcast_interval_ms\":{},\"canvas_buffer_pool_size\":{},\"timing_wheel_ticks\":{},\"socket_recv_buf_size\":{},\"socket_send_buf_size\":{}}}",
+        CANVAS_WIDTH,
+        CANVAS_HEIGHT,
+        TILE_SIZE,
+        BROADCAST_INTERVAL_MS,
+        CANVAS_BUFFER_POOL_SIZE,
+        TIMING_WHEEL_TICKS,
+        SOCKET_RECV_BUF_SIZE,
+        SOCKET_SEND_BUF_SIZE
+    );
+
+    // The caps may have been changed since startup (POST /admin/bandwidth).
+    let _ = write!(
+        out,
+        ",\"bandwidth\":{}",
+        bandwidth_json(BANDWIDTH_LIMITS.load())
+    );
+    let _ = write!(
+        out,
+        ",\"environment\":{}",
+        crate::run_info::published_environment()
+    );
+    let _ = write!(
+        out,
+        ",\"accept_steering\":\"{}\"",
+        crate::steering::current().as_str()
+    );
+
+    out.push_str(",\"workers\":[");
+    for (w, facts) in WORKER_FACTS[..num_workers].iter().enumerate() {
+        if w > 0 {
+            out.push(',');
+        }
+        let core = facts.core_id.load(Ordering::Relaxed);
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"core\":{},\"pinned\":{},\"socket_recv_buf\":{},\"socket_send_buf\":{},\"sq_entries\":{},\"cq_entries\":{}}}",
+            w,
+            json_opt((core != usize::MAX).then_some(core)),
+            WORKER_METRICS[w].pinned.load(Ordering::Relaxed) != 0,
+            facts.socket_recv_buf.load(Ordering::Relaxed),
+            facts.socket_send_buf.load(Ordering::Relaxed),
+            facts.sq_entries.load(Ordering::Relaxed),
+            facts.cq_entries.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Live bandwidth caps in kbps, 0 = unlimited.
+pub fn bandwidth_json(limits: Limits) -> String {
+    format!(
+        "{{\"conn_kbps\":{},\"worker_kbps\":{},\"full_sync_kbps\":{}}}",
+        limits.conn_kbps, limits.worker_kbps, limits.full_sync_kbps
+    )
+}
+
+fn json_opt(value: Option<usize>) -> String {
+    value.map_or("null".to_string(), |v| v.to_string())
+}
+
+fn json_path(path: &Path) -> String {
+    json_str(&path.to_string_lossy())
+}
+
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_config_dump_is_complete_and_redacted() {
+        let config = ServerConfig {
+            workers: Some(2),
+            cpu_list: Some(vec![4, 5, 6]),
+            key_path: PathBuf::from("/etc/canvas/secret-key.pem"),
+            lock_dir: PathBuf::from("/tmp/a \"quoted\" dir"),
+            ..Default::default()
+        };
+        WORKER_FACTS[1].core_id.store(6, Ordering::Relaxed);
+        WORKER_FACTS[1].sq_entries.store(4096, Ordering::Relaxed);
+
+        let json = config_json(&config, 2);
+        assert!(json.starts_with("{\"build\":{\"version\":\"0.1.0\",\"git\":\""));
+        // Plus "zstd" once another test has loaded a diff dictionary.
+        assert!(json.contains(
+            "\"protocol\":\"2.4\",\"protocol_features\":[\"batching\",\"receipts\",\"subscriptions\""
+        ));
+        assert!(json.contains(&format!("\"rle_encoder\":\"{}\"}}", rle_encoder().name())));
+        assert!(json.contains("\"key_path\":\"<redacted>\""));
+        assert!(!json.contains("secret-key"));
+        assert!(json.contains("\"lock_dir\":\"/tmp/a \\\"quoted\\\" dir\""));
+        assert!(json.contains("\"port\":4433,\"workers\":2,"));
+        assert!(json.contains("\"cpu_list\":[4,5,6]"));
+        assert!(json.contains("\"generate_cert\":true,\"san\":[],"));
+        assert!(json.contains("\"dashboard_bind\":null"));
+        assert!(json.contains("\"mem_per_worker\":"));
+        assert!(json.contains(
+            "\"conn_kbps\":0,\"worker_kbps\":0,\"full_sync_kbps\":0,\"diff_dict\":null,\"conn_mem_soft_bytes\":0,\"conn_mem_hard_bytes\":0,\"handshake_deadline_ms\":10000,\"half_open_threshold\":4096,\"strict_junk\":false,\"junk_threshold\":32,\"junk_deny_ttl_ms\":60000,\"junk_deny_max\":1024,\"quic_retry\":false,\"retry_token_ttl_ms\":10000,\"idle_timeout_ms\":30000,\"cooldown_secs\":300,\"tier1_tokens\":null,\"tier1_per_worker\":64,\"fast_diff_ms\":10,\"fec_k\":8,\"reserve_grace_ms\":0,\"full_broadcast_ms\":6000,\"full_broadcast_max_bytes\":262144,\"diff_cap_bytes\":65536,\"admin_drain_ratio\":1,\"dgram_drain_cap\":16,\"dgram_drain_budget\":256,\"steer_by_port\":false,\"handshake_reserve\":false,\"metrics_dir\":null,\"qlog_dir\":null,\"qlog_sample\":1000,\"profile_hz\":0,\"run_as\":null,\"sandbox\":true}"
+        ));
+        assert!(json.contains(",\"warnings\":[],"));
+        assert!(json.contains(",\"environment\":"));
+        assert!(json.contains("{\"id\":0,\"core\":null,"));
+        assert!(json.contains("{\"id\":1,\"core\":6,\"pinned\":false,"));
+        assert!(json.contains("\"sq_entries\":4096"));
+        assert!(!json.contains("{\"id\":2,"));
+        assert!(json.ends_with("}]}"));
+
+        // Balanced outside of strings: every object and array is closed.
+        let mut depth = 0i32;
+        let mut in_str = false;
+        let mut escaped = false;
+        for ch in json.chars() {
+            match (in_str, escaped, ch) {
+                (true, true, _) => escaped = false,
+                (true, false, '\\') => escaped = true,
+                (true, false, '"') => in_str = false,
+                (false, _, '"') => in_str = true,
+                (false, _, '{' | '[') => depth += 1,
+                (false, _, '}' | ']') => depth -= 1,
+                _ => {}
+            }
+            assert!(depth >= 0);
+        }
+        assert_eq!((depth, in_str), (0, false));
+    }
+
+    #[test]
+    fn test_config_dump_lists_soft_warnings() {
+        let config = ServerConfig {
+            full_broadcast_ms: 0,
+            ..Default::default()
+        };
+        let json = config_json(&config, 1);
+        assert!(json.contains(
+            "\"warnings\":[{\"rule\":\"fec-without-full-broadcasts\",\"detail\":\"--fec-k 8 protects"
+        ));
+    }
+}
@@ -0,0 +1,505 @@
+//! Dropping root once the server is set up (`--run-as user[:group]`) and
+//! confining it to the syscalls its loops make.
+//!
+//! The server starts as root to raise RLIMIT_MEMLOCK, bind low ports and
+//! size its socket buffers. Each worker creates its io_uring and provides
+//! its receive buffers on its own thread, then reports ready; once all have,
+//! and before the master loop starts, the process switches to the given
+//! user and group (every thread at once) and, unless `--no-sandbox`, loads
+//! a seccomp filter on every thread.
+//!
+//! The filter is an allowlist ([`ALLOWED`]): memory, futexes, clocks,
+//! io_uring_enter, datagram and dashboard socket I/O, writes for the logs.
+//! Nothing opens a file, creates a socket or starts a process or thread
+//! after that point (which is why `--demo`, whose bots bind on their own
+//! thread, and `--qlog-dir`, which creates a trace per connection, need
+//! `--no-sandbox`, and why the dashboard refuses `/admin/epoch?image=`
+//! once [`confined`]). A syscall outside the list raises SIGSYS; the handler
+//! names its number on stderr and exits with status 159 (128 + SIGSYS), so
+//! a missing entry shows up as a message rather than a bare kill. (The
+//! kernel still kills without one if the thread has SIGSYS blocked, as
+//! glibc's posix_spawn does around its clone.)
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the filter is loaded.
+static CONFINED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the process runs under the filter, so cannot open a file.
+pub fn confined() -> bool {
+    CONFINED.load(Ordering::Relaxed)
+}
+
+/// `--run-as user[:group]`. Without a group, the user's primary group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: String,
+    pub group: Option<String>,
+}
+
+impl FromStr for RunAs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        if user.is_empty() || group.is_some_and(str::is_empty) {
+            return Err(format!("{:?} is not user or user:group", s));
+        }
+        Ok(Self {
+            user: user.to_string(),
+            group: group.map(String::from),
+        })
+    }
+}
+
+impl fmt::Display for RunAs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.group {
+            Some(group) => write!(f, "{}:{}", self.user, group),
+            None => write!(f, "{}", self.user),
+        }
+    }
+}
+
+/// The ids `--run-as` names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Room for one passwd or group entry and the strings it points into.
+const LOOKUP_BUF_LEN: usize = 16 * 1024;
+
+impl RunAs {
+    /// Looks the names up. Done at startup, while the user and group
+    /// databases can still be read.
+    pub fn resolve(&self) -> Result<Credentials, String> {
+        let user = CString::new(self.user.as_str()).map_err(|e| e.to_string())?;
+        let mut buf = vec![0 as libc::c_char; LOOKUP_BUF_LEN];
+        let (uid, primary_gid) = unsafe {
+            let mut entry: libc::passwd = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let ret = libc::getpwnam_r(
+                user.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            );
+            if found.is_null() {
+                return Err(lookup_error("user", &self.user, ret));
+            }
+            (entry.pw_uid, entry.pw_gid)
+        };
+        let Some(group_name) = &self.group else {
+            return Ok(Credentials {
+                uid,
+                gid: primary_gid,
+            });
+        };
+        let group = CString::new(group_name.as_str()).map_err(|e| e.to_string())?;
+        let gid = unsafe {
+            let mut entry: libc::group = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let ret = libc::getgrnam_r(
+                group.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            );
+            if found.is_null() {
+                return Err(lookup_error("group", group_name, ret));
+            }
+            entry.gr_gid
+        };
+        Ok(Credentials { uid, gid })
+    }
+}
+
+fn lookup_error(kind: &str, name: &str, ret: libc::c_int) -> String {
+    if ret == 0 {
+        format!("--run-as: no {} named {:?}", kind, name)
+    } else {
+        format!(
+            "--run-as: looking up {} {:?}: {}",
+            kind,
+            name,
+            io::Error::from_raw_os_error(ret)
+        )
+    }
+}
+
+/// Switches every thread of the process to `creds`, for good: supplementary
+/// groups are dropped, and root cannot be taken back.
+pub fn drop_privileges(creds: Credentials) -> Result<(), String> {
+    let failed = |call: &str| {
+        format!(
+            "--run-as: {} failed: {} (the server must start as root to switch users)",
+            call,
+            io::Error::last_os_error()
+        )
+    };
+    // glibc applies each of these to all threads, not only the caller.
+    unsafe {
+        if libc::setgroups(1, &creds.gid) != 0 {
+            return Err(failed("setgroups"));
+        }
+        if libc::setgid(creds.gid) != 0 {
+            return Err(failed("setgid"));
+        }
+        if libc::setuid(creds.uid) != 0 {
+            return Err(failed("setuid"));
+        }
+        if creds.uid != 0 && libc::setuid(0) == 0 {
+            return Err("--run-as: root could be taken back after dropping it".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Syscalls the running server makes, by name for the error messages.
+pub const ALLOWED: &[(&str, libc::c_long)] = &[
+    // Memory.
+    ("brk", libc::SYS_brk),
+    ("mmap", libc::SYS_mmap),
+    ("munmap", libc::SYS_munmap),
+    ("mremap", libc::SYS_mremap),
+    ("mprotect", libc::SYS_mprotect),
+    ("madvise", libc::SYS_madvise),
+    // Threads already running: locks, sleeps, pinning (the master pins
+    // itself after the filter is in place).
+    ("futex", libc::SYS_futex),
+    ("sched_yield", libc::SYS_sched_yield),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity),
+    ("sched_setaffinity", libc::SYS_sched_setaffinity),
+    ("clock_gettime", libc::SYS_clock_gettime),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep),
+    ("nanosleep", libc::SYS_nanosleep),
+    ("gettimeofday", libc::SYS_gettimeofday),
+    ("getrandom", libc::SYS_getrandom),
+    // Workers.
+    ("io_uring_enter", libc::SYS_io_uring_enter),
+    // Sockets opened before the filter: the workers' and the dashboard's.
+    ("sendto", libc::SYS_sendto),
+    ("recvfrom", libc::SYS_recvfrom),
+    ("sendmsg", libc::SYS_sendmsg),
+    ("recvmsg", libc::SYS_recvmsg),
+    ("sendmmsg", libc::SYS_sendmmsg),
+    ("accept4", libc::SYS_accept4),
+    ("setsockopt", libc::SYS_setsockopt),
+    ("getsockopt", libc::SYS_getsockopt),
+    ("getsockname", libc::SYS_getsockname),
+    ("getpeername", libc::SYS_getpeername),
+    ("shutdown", libc::SYS_shutdown),
+    ("fcntl", libc::SYS_fcntl),
+    ("read", libc::SYS_read),
+    ("write", libc::SYS_write),
+    ("writev", libc::SYS_writev),
+    ("close", libc::SYS_close),
+    // Signals and exits, panics included.
+    ("rt_sigreturn", libc::SYS_rt_sigreturn),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+    ("sigaltstack", libc::SYS_sigaltstack),
+    ("restart_syscall", libc::SYS_restart_syscall),
+    ("getpid", libc::SYS_getpid),
+    ("gettid", libc::SYS_gettid),
+    ("tgkill", libc::SYS_tgkill),
+    ("exit", libc::SYS_exit),
+    ("exit_group", libc::SYS_exit_group),
+];
+
+/// `seccomp_data.arch` of the syscalls [`ALLOWED`] numbers; any other ABI
+/// (x32, 32-bit compat) is refused outright.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// The classic BPF program run on every syscall:
+///
+/// ```text
+/// ld  [arch]
+/// jeq #AUDIT_ARCH, +1     ; other ABIs are killed
+/// ret #KILL_PROCESS
+/// ld  [nr]
+/// jeq #allowed[0], +0, +1
+/// ret #ALLOW
+/// ...                     ; one pair per allowed syscall
+/// ret #TRAP               ; SIGSYS, see on_sigsys
+/// ```
+pub fn program(arch: u32, allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let arch_offset = std::mem::offset_of!(libc::seccomp_data, arch) as u32;
+    let nr_offset = std::mem::offset_of!(libc::seccomp_data, nr) as u32;
+    let mut program = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, arch_offset),
+        jeq(arch, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, nr_offset),
+    ];
+    for &nr in allowed {
+        program.push(jeq(nr as u32, 0, 1));
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_TRAP));
+    program
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jeq(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Exit status of a process stopped by the filter.
+pub const BLOCKED_EXIT_STATUS: i32 = 128 + libc::SIGSYS;
+
+const BLOCKED_PREFIX: &[u8] = b"sandbox: blocked syscall ";
+const BLOCKED_SUFFIX: &[u8] =
+    b", which is not in the allowlist (sandbox.rs ALLOWED); --no-sandbox runs without it\n";
+
+/// What the SIGSYS handler prints for syscall `nr`, built without
+/// allocating. Returns the length used.
+fn blocked_message(nr: i32, buf: &mut [u8; 128]) -> usize {
+    let mut digits = [0u8; 11];
+    let mut n = nr.unsigned_abs();
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    if nr < 0 {
+        start -= 1;
+        digits[start] = b'-';
+    }
+    let mut len = 0;
+    for part in [BLOCKED_PREFIX, &digits[start..], BLOCKED_SUFFIX] {
+        buf[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+    len
+}
+
+/// SIGSYS from the filter. Only async-signal-safe calls, both allowed.
+extern "C" fn on_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // siginfo_t as the kernel fills it for SIGSYS (its `_sigsys` member).
+    #[repr(C)]
+    struct Sigsys {
+        signo: libc::c_int,
+        errno: libc::c_int,
+        code: libc::c_int,
+        call_addr: *mut libc::c_void,
+        syscall: libc::c_int,
+        arch: libc::c_uint,
+    }
+    let nr = unsafe { (*(info as *const Sigsys)).syscall };
+    let mut buf = [0u8; 128];
+    let len = blocked_message(nr, &mut buf);
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            buf.as_ptr() as *const libc::c_void,
+            len,
+        );
+        libc::_exit(BLOCKED_EXIT_STATUS);
+    }
+}
+
+/// Loads the filter on every thread of the process. Threads cannot leave
+/// it, and neither can anything they would start.
+pub fn confine() -> Result<(), String> {
+    let arch = AUDIT_ARCH.ok_or("no syscall allowlist for this architecture (--no-sandbox)")?;
+    let allowed: Vec<libc::c_long> = ALLOWED.iter().map(|&(_, nr)| nr).collect();
+    let mut filter = program(arch, &allowed);
+    let fprog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let failed = |call: &str| format!("sandbox: {} failed: {}", call, io::Error::last_os_error());
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigsys as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        if libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) != 0 {
+            return Err(failed("sigaction"));
+        }
+        // Required to load a filter without CAP_SYS_ADMIN, which --run-as
+        // has just given up.
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(failed("prctl(PR_SET_NO_NEW_PRIVS)"));
+        }
+        match libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const libc::sock_fprog,
+        ) {
+            0 => {
+                CONFINED.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            tid if tid > 0 => Err(format!(
+                "sandbox: thread {} could not take the seccomp filter",
+                tid
+            )),
+            _ => Err(failed("seccomp")),
+        }
+    }
+}
+
+/// `--run-as`: drops to `creds`, then confines every thread unless
+/// `sandbox` is off. Called once everything that needs root is set up.
+pub fn enter(creds: Credentials, sandbox: bool) -> Result<(), String> {
+    drop_privileges(creds)?;
+    if sandbox {
+        confine()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::process::Command;
+
+    /// Set in the child process `test_confined_server_keeps_serving` starts.
+    const CHILD_ENV: &str = "CANVAS_SANDBOX_CHILD";
+
+    #[test]
+    fn test_run_as_parsing_and_lookup() {
+        let run_as: RunAs = "canvas:www".parse().unwrap();
+        assert_eq!(run_as.user, "canvas");
+        assert_eq!(run_as.group.as_deref(), Some("www"));
+        assert_eq!(run_as.to_string(), "canvas:www");
+        assert_eq!("canvas".parse::<RunAs>().unwrap().group, None);
+        assert!(":www".parse::<RunAs>().is_err());
+        assert!("canvas:".parse::<RunAs>().is_err());
+
+        let root = RunAs::from_str("root").unwrap().resolve().unwrap();
+        assert_eq!(root, Credentials { uid: 0, gid: 0 });
+        let err = RunAs::from_str("root:no-such-group-here")
+            .unwrap()
+            .resolve()
+            .unwrap_err();
+        assert_eq!(err, "--run-as: no group named \"no-such-group-here\"");
+    }
+
+    #[test]
+    fn test_program_and_message() {
+        let program = program(0xC000_003E, &[1, 2]);
+        assert_eq!(program.len(), 4 + 2 * 2 + 1);
+        assert_eq!(program[1].k, 0xC000_003E);
+        assert_eq!((program[4].k, program[4].jf), (1, 1));
+        assert_eq!(program[5].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(program[8].k, libc::SECCOMP_RET_TRAP);
+
+        let mut buf = [0u8; 128];
+        let len = blocked_message(435, &mut buf);
+        assert!(
+            std::str::from_utf8(&buf[..len])
+                .unwrap()
+                .starts_with("sandbox: blocked syscall 435, which is not in the allowlist")
+        );
+        let len = blocked_message(i32::MIN, &mut buf);
+        assert!(buf[..len].starts_with(b"sandbox: blocked syscall -2147483648,"));
+    }
+
+    /// Runs this test again in a child process, which confines itself and
+    /// then keeps answering datagrams on a socket opened beforehand, until
+    /// it tries to start a process.
+    #[test]
+    fn test_confined_server_keeps_serving() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            confined_child();
+        }
+        if AUDIT_ARCH.is_none() {
+            return;
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "sandbox::tests::test_confined_server_keeps_serving",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stdout.contains("served 3 datagrams"),
+            "{}\n{}",
+            stdout,
+            stderr
+        );
+        assert_eq!(
+            output.status.code(),
+            Some(BLOCKED_EXIT_STATUS),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("sandbox: blocked syscall "), "{}", stderr);
+        assert!(!stdout.contains("spawned"), "{}", stdout);
+    }
+
+    fn confined_child() -> ! {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // As root, drop to nobody first, like a deployment would.
+        if unsafe { libc::geteuid() } == 0 {
+            let nobody = Credentials {
+                uid: 65_534,
+                gid: 65_534,
+            };
+            drop_privileges(nobody).unwrap();
+            assert_eq!(unsafe { libc::setuid(0) }, -1);
+        }
+        confine().unwrap();
+
+        let mut buf = [0u8; 16];
+        for i in 0..3u8 {
+            client.send_to(&[i], server.local_addr().unwrap()).unwrap();
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&buf[..len], from).unwrap();
+            assert_eq!(client.recv(&mut buf).unwrap(), 1);
+            assert_eq!(buf[0], i);
+        }
+        println!("served 3 datagrams");
+
+        // Starting a process is not on the list.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe { libc::_exit(0) };
+        }
+        println!("spawned {}", pid);
+        std::process::exit(0);
+    }
+}
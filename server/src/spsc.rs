@@ -1,12 +1,15 @@
+use crate::aligned::{AlignedBox, CachePadded};
 use crate::const_settings::SPSC_CAPACITY;
 use std::cell::UnsafeCell;
 use std::mem::MaybeUninit;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-// https://docs.rs/crossbeam-utils/latest/src/crossbeam_utils/cache_padded.rs.html#148-150
-#[repr(align(64))]
-struct CachePadded<T>(T);
+/// A ring as the master and one worker share it: on lines of its own.
+pub type SharedRing<T> = Arc<AlignedBox<SpscRingBuffer<T>>>;
 
+/// Tail and head are 128 bytes apart (see `aligned`), so the worker's
+/// stores never invalidate the line the master polls, and vice versa.
 pub struct SpscRingBuffer<T> {
     tail: CachePadded<AtomicUsize>, // Written by Worker
     head: CachePadded<AtomicUsize>, // Written by Master
@@ -30,6 +33,14 @@ impl<T> SpscRingBuffer<T> {
         }
     }
 
+    /// Allocates the ring in place, 128-byte aligned; it is too large to
+    /// build on the stack and move.
+    pub fn shared() -> SharedRing<T> {
+        // SAFETY: zeroed head and tail are an empty ring, and the slots are
+        // MaybeUninit.
+        Arc::new(unsafe { AlignedBox::new_zeroed() })
+    }
+
     #[inline(always)]
     pub fn push(&self, value: T) -> Result<(), T> {
         let current_tail = self.tail.0.load(Ordering::Relaxed);
@@ -107,4 +118,146 @@ mod tests {
         }
         assert_eq!(buffer.pop(), None);
     }
+
+    #[test]
+    fn test_shared_ring_is_empty_and_aligned() {
+        let ring = SpscRingBuffer::<usize>::shared();
+        assert_eq!(
+            &**ring as *const _ as usize % crate::aligned::SHARED_ALIGN,
+            0
+        );
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(7).is_ok());
+        assert_eq!(ring.pop(), Some(7));
+    }
+
+    /// What both layouts under benchmark offer the producers and the master.
+    trait Lane: Sync {
+        fn push(&self, value: usize) -> bool;
+        fn pop(&self) -> Option<usize>;
+        /// Stands in for the worker's `pixels_accepted` counter.
+        fn count(&self);
+    }
+
+    /// The same ring algorithm with no padding: head, tail and the
+    /// producer's counter share a line, and lanes sit next to each other.
+    struct PackedLane {
+        tail: AtomicUsize,
+        head: AtomicUsize,
+        accepted: AtomicUsize,
+        buffer: Box<[UnsafeCell<usize>]>,
+    }
+
+    unsafe impl Sync for PackedLane {}
+
+    impl Lane for PackedLane {
+        fn push(&self, value: usize) -> bool {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= SPSC_CAPACITY {
+                return false;
+            }
+            unsafe { *self.buffer[tail & (SPSC_CAPACITY - 1)].get() = value };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            true
+        }
+
+        fn pop(&self) -> Option<usize> {
+            let head = self.head.load(Ordering::Relaxed);
+            if head == self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let value = unsafe { *self.buffer[head & (SPSC_CAPACITY - 1)].get() };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            Some(value)
+        }
+
+        fn count(&self) {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The production layout: an aligned ring plus a padded counter block.
+    struct PaddedLane {
+        ring: SharedRing<usize>,
+        accepted: CachePadded<AtomicUsize>,
+    }
+
+    impl Lane for PaddedLane {
+        fn push(&self, value: usize) -> bool {
+            self.ring.push(value).is_ok()
+        }
+
+        fn pop(&self) -> Option<usize> {
+            self.ring.pop()
+        }
+
+        fn count(&self) {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Two producers push `per_producer` values each while one consumer
+    /// drains both lanes round-robin, like the master. Returns items/s.
+    fn drain_throughput<L: Lane>(lanes: &[L], per_producer: usize) -> f64 {
+        let start = std::time::Instant::now();
+        let sum = std::thread::scope(|s| {
+            for lane in lanes {
+                s.spawn(move || {
+                    for i in 0..per_producer {
+                        while !lane.push(i) {
+                            std::hint::spin_loop();
+                        }
+                        lane.count();
+                    }
+                });
+            }
+            let mut received = 0;
+            let mut sum = 0usize;
+            while received < per_producer * lanes.len() {
+                for lane in lanes {
+                    if let Some(v) = lane.pop() {
+                        received += 1;
+                        sum = sum.wrapping_add(v);
+                    }
+                }
+            }
+            sum
+        });
+        let elapsed = start.elapsed().as_secs_f64();
+        assert_eq!(sum, lanes.len() * per_producer * (per_producer - 1) / 2);
+        (per_producer * lanes.len()) as f64 / elapsed
+    }
+
+    /// `cargo test --release -- --ignored bench_two_producer_drain --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_two_producer_drain() {
+        const PER_PRODUCER: usize = 20_000_000;
+        let packed: Vec<PackedLane> = (0..2)
+            .map(|_| PackedLane {
+                tail: AtomicUsize::new(0),
+                head: AtomicUsize::new(0),
+                accepted: AtomicUsize::new(0),
+                buffer: (0..SPSC_CAPACITY).map(|_| UnsafeCell::new(0)).collect(),
+            })
+            .collect();
+        let padded: Vec<PaddedLane> = (0..2)
+            .map(|_| PaddedLane {
+                ring: SpscRingBuffer::shared(),
+                accepted: CachePadded(AtomicUsize::new(0)),
+            })
+            .collect();
+
+        for round in 0..3 {
+            let packed_rate = drain_throughput(&packed, PER_PRODUCER);
+            let padded_rate = drain_throughput(&padded, PER_PRODUCER);
+            println!(
+                "round {}: packed {:.1} M/s, padded {:.1} M/s ({:.2}x)",
+                round,
+                packed_rate / 1e6,
+                padded_rate / 1e6,
+                padded_rate / packed_rate
+            );
+        }
+    }
 }
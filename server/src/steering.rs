@@ -0,0 +1,319 @@
+//! Port-steered accept (`--steer-by-port`). By default the kernel spreads
+//! the workers' SO_REUSEPORT sockets by a hash of the 4-tuple, salted per
+//! boot, so the same load test lands its connections on different workers
+//! from one run to the next. With steering, a classic BPF program attached
+//! to the group picks the socket instead:
+//!
+//! ```text
+//! worker = client UDP source port % workers
+//! ```
+//!
+//! Workers bind in worker id order at startup, so socket `i` of the group
+//! is worker `i`. A client that keeps its source ports between runs keeps
+//! its worker assignment too ([`worker_for_port`]).
+//!
+//! With `--handshake-reserve` each worker also binds a handshake socket,
+//! after every worker's data socket, so worker `i`'s sits at `workers + i`
+//! in the group. The program then sends long-header packets (Initials and
+//! the rest of the handshake) there, to a receive with buffers of its own
+//! (recv_lane.rs), and everything else to the data socket as before:
+//!
+//! ```text
+//! socket = client UDP source port % workers + workers * long_header
+//! ```
+//!
+//! Attaching can fail (an old kernel, a sandbox that filters setsockopt);
+//! the server then carries on with the kernel's hash, and without the
+//! reserve. Which is in effect shows in `/config.json` (`accept_steering`)
+//! and `/metrics` (`canvas_accept_steering`).
+
+use socket2::Socket;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How new datagrams are spread over the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Steering {
+    /// The kernel's 4-tuple hash; `--steer-by-port` not given.
+    Hash = 0,
+    /// The source port program is attached.
+    Port = 1,
+    /// `--steer-by-port` was given but the program was refused.
+    HashFallback = 2,
+    /// By source port, long headers to the handshake sockets
+    /// (`--handshake-reserve`).
+    PortReserve = 3,
+}
+
+impl Steering {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Steering::Hash => "hash",
+            Steering::Port => "port",
+            Steering::HashFallback => "hash_fallback",
+            Steering::PortReserve => "port_reserve",
+        }
+    }
+}
+
+static STEERING: AtomicU8 = AtomicU8::new(Steering::Hash as u8);
+
+pub fn current() -> Steering {
+    match STEERING.load(Ordering::Relaxed) {
+        1 => Steering::Port,
+        2 => Steering::HashFallback,
+        3 => Steering::PortReserve,
+        _ => Steering::Hash,
+    }
+}
+
+/// The worker a client sending from `port` is steered to.
+pub fn worker_for_port(port: u16, workers: usize) -> usize {
+    port as usize % workers
+}
+
+/// The program run for each datagram that does not match a connected
+/// socket. The reuseport hook hands it the UDP payload, so the headers are
+/// read relative to the network header (`SKF_NET_OFF`):
+///
+/// ```text
+/// ldxb 4*([net + 0] & 0xf)   ; X = IPv4 header length
+/// ldh  [x + net + 0]         ; A = UDP source port
+/// mod  #workers
+/// ret  a                     ; index into the reuseport group
+/// ```
+pub fn program(workers: usize) -> [libc::sock_filter; 4] {
+    let net = libc::SKF_NET_OFF as u32;
+    [
+        stmt(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, net),
+        stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, net),
+        stmt(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, workers as u32),
+        stmt(libc::BPF_RET | libc::BPF_A, 0),
+    ]
+}
+
+/// [`program`] with the handshake sockets behind the data sockets. The
+/// first payload byte is at offset 0 and has the QUIC long-header bit at
+/// the top; the port program overwrites X, so the offset it gives waits in
+/// scratch memory:
+///
+/// ```text
+/// ldb  [0]                   ; A = first byte of the QUIC packet
+/// rsh  #7                    ; A = 1 for a long header
+/// mul  #workers
+/// st   M[0]
+/// ldxb 4*([net + 0] & 0xf)
+/// ldh  [x + net + 0]
+/// mod  #workers
+/// ldx  M[0]
+/// add  x
+/// ret  a
+/// ```
+pub fn reserve_program(workers: usize) -> [libc::sock_filter; 10] {
+    let net = libc::SKF_NET_OFF as u32;
+    let workers = workers as u32;
+    [
+        stmt(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0),
+        stmt(libc::BPF_ALU | libc::BPF_RSH | libc::BPF_K, 7),
+        stmt(libc::BPF_ALU | libc::BPF_MUL | libc::BPF_K, workers),
+        stmt(libc::BPF_ST, 0),
+        stmt(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, net),
+        stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, net),
+        stmt(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, workers),
+        stmt(libc::BPF_LDX | libc::BPF_MEM, 0),
+        stmt(libc::BPF_ALU | libc::BPF_ADD | libc::BPF_X, 0),
+        stmt(libc::BPF_RET | libc::BPF_A, 0),
+    ]
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Attaches [`program`] to the reuseport group `socket` belongs to.
+pub fn attach(socket: &Socket, workers: usize) -> io::Result<()> {
+    attach_filter(socket, &mut program(workers))
+}
+
+/// Attaches [`reserve_program`]; the handshake sockets must have bound.
+pub fn attach_reserve(socket: &Socket, workers: usize) -> io::Result<()> {
+    attach_filter(socket, &mut reserve_program(workers))
+}
+
+fn attach_filter(socket: &Socket, filter: &mut [libc::sock_filter]) -> io::Result<()> {
+    let fprog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            &fprog as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Steers the group `socket` belongs to by source port when `enabled`,
+/// once every worker has bound, and long headers to the handshake sockets
+/// with `reserve`. Never fails: a refused reserve program falls back to the
+/// plain one (the caller then closes the handshake sockets), a refused
+/// plain one to the kernel's hash.
+pub fn configure(enabled: bool, reserve: bool, socket: &Socket, workers: usize) -> Steering {
+    let steering = if !enabled {
+        Steering::Hash
+    } else if reserve && attach_reserve(socket, workers).is_ok() {
+        Steering::PortReserve
+    } else if attach(socket, workers).is_ok() {
+        Steering::Port
+    } else {
+        Steering::HashFallback
+    };
+    STEERING.store(steering as u8, Ordering::Relaxed);
+    steering
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Type};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    const WORKERS: usize = 3;
+    const CAP_NET_ADMIN: u32 = 12;
+
+    fn has_net_admin() -> bool {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+    }
+
+    /// A reuseport group of `size` sockets on one loopback port, bound in
+    /// order like the workers.
+    fn group(size: usize) -> Vec<Socket> {
+        let mut addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        (0..size)
+            .map(|_| {
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+                let opt: libc::c_int = 1;
+                unsafe {
+                    libc::setsockopt(
+                        socket.as_raw_fd(),
+                        libc::SOL_SOCKET,
+                        libc::SO_REUSEPORT,
+                        &opt as *const _ as *const libc::c_void,
+                        std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                    );
+                }
+                socket.bind(&addr.into()).unwrap();
+                addr = socket.local_addr().unwrap().as_socket().unwrap();
+                socket
+            })
+            .collect()
+    }
+
+    /// Sends `payload` from each client and returns the index of the
+    /// socket that received it, by client.
+    fn landings(group: &[Socket], clients: &[UdpSocket], payload: &[u8]) -> Vec<usize> {
+        let server = group[0].local_addr().unwrap().as_socket().unwrap();
+        for client in clients {
+            client.send_to(payload, server).unwrap();
+        }
+        let mut landed = vec![usize::MAX; clients.len()];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut buf = [std::mem::MaybeUninit::new(0u8); 16];
+        while landed.contains(&usize::MAX) && Instant::now() < deadline {
+            for (index, socket) in group.iter().enumerate() {
+                socket.set_nonblocking(true).unwrap();
+                while let Ok((_, from)) = socket.recv_from(&mut buf) {
+                    let from = from.as_socket().unwrap();
+                    if let Some(client) =
+                        clients.iter().position(|c| c.local_addr().unwrap() == from)
+                    {
+                        landed[client] = index;
+                    }
+                }
+            }
+        }
+        landed
+    }
+
+    #[test]
+    fn test_program_shape() {
+        let program = program(7);
+        assert_eq!(program[0].k, libc::SKF_NET_OFF as u32);
+        assert_eq!(program[2].k, 7);
+        assert_eq!(worker_for_port(50_001, 7), 50_001 % 7);
+        assert_eq!(Steering::HashFallback.as_str(), "hash_fallback");
+
+        let reserve = reserve_program(7);
+        assert_eq!(reserve[2].k, 7);
+        assert_eq!(reserve[4].k, libc::SKF_NET_OFF as u32);
+        assert_eq!(reserve[6].k, 7);
+        assert_eq!(Steering::PortReserve.as_str(), "port_reserve");
+    }
+
+    #[test]
+    fn test_same_ports_land_identically() {
+        let clients: Vec<UdpSocket> = (0..24)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let group = group(WORKERS);
+            if let Err(e) = attach(&group[0], WORKERS) {
+                // Unprivileged sandboxes may refuse the program; the server
+                // falls back to the hash there too.
+                assert!(!has_net_admin(), "attach failed with CAP_NET_ADMIN: {}", e);
+                eprintln!("skipping: reuseport program refused ({})", e);
+                return;
+            }
+            runs.push(landings(&group, &clients, b"x"));
+        }
+        let expected: Vec<usize> = clients
+            .iter()
+            .map(|c| worker_for_port(c.local_addr().unwrap().port(), WORKERS))
+            .collect();
+        assert_eq!(runs[0], expected);
+        assert_eq!(runs[1], runs[0]);
+    }
+
+    #[test]
+    fn test_long_headers_land_on_handshake_sockets() {
+        let clients: Vec<UdpSocket> = (0..24)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let group = group(2 * WORKERS);
+        if let Err(e) = attach_reserve(&group[0], WORKERS) {
+            assert!(!has_net_admin(), "attach failed with CAP_NET_ADMIN: {}", e);
+            eprintln!("skipping: reuseport program refused ({})", e);
+            return;
+        }
+        let data: Vec<usize> = clients
+            .iter()
+            .map(|c| worker_for_port(c.local_addr().unwrap().port(), WORKERS))
+            .collect();
+        let handshake: Vec<usize> = data.iter().map(|worker| WORKERS + worker).collect();
+        // An Initial's first byte, then a short header's.
+        assert_eq!(landings(&group, &clients, &[0xc3, 0, 0, 0, 1]), handshake);
+        assert_eq!(landings(&group, &clients, &[0x43, 0, 0, 0, 1]), data);
+    }
+}
@@ -0,0 +1,316 @@
+//! Live canvas preview for embeds: `GET /thumb.png` on the dashboard, a
+//! THUMBNAIL_WIDTH × THUMBNAIL_HEIGHT RGB PNG.
+//!
+//! The dashboard thread makes it on request, at most once per
+//! THUMBNAIL_REFRESH_MS and only when a new snapshot was published since,
+//! straight from the newest pool slot (`canvas::read_newest_snapshot`): the
+//! master never does any of this work. Each thumbnail pixel is the average
+//! color of the block of canvas pixels it covers, colors being looked up in
+//! [`PALETTE`]. The PNG is not compressed (about 30 KB at 100 × 100);
+//! embedders that care can recompress it at their CDN.
+
+use crate::const_settings::{
+    CANVAS_HEIGHT, CANVAS_WIDTH, THUMBNAIL_HEIGHT, THUMBNAIL_REFRESH_MS, THUMBNAIL_WIDTH,
+};
+use std::time::{Duration, Instant};
+
+/// RGB of the color indices clients paint with (the 2017 r/place colors).
+/// Indices past the end wrap around.
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0xFF, 0xFF, 0xFF],
+    [0xE4, 0xE4, 0xE4],
+    [0x88, 0x88, 0x88],
+    [0x22, 0x22, 0x22],
+    [0xFF, 0xA7, 0xD1],
+    [0xE5, 0x00, 0x00],
+    [0xE5, 0x95, 0x00],
+    [0xA0, 0x6A, 0x42],
+    [0xE5, 0xD9, 0x00],
+    [0x94, 0xE0, 0x44],
+    [0x02, 0xBE, 0x01],
+    [0x00, 0xD3, 0xDD],
+    [0x00, 0x83, 0xC7],
+    [0x00, 0x00, 0xEA],
+    [0xCF, 0x6E, 0xE4],
+    [0x82, 0x00, 0x80],
+];
+
+#[inline(always)]
+pub fn rgb(color: u8) -> [u8; 3] {
+    PALETTE[color as usize % PALETTE.len()]
+}
+
+/// Box-filters the `width` × `height` canvas down to `thumb_width` ×
+/// `thumb_height` RGB pixels, row-major, into `out`.
+pub fn downsample(
+    canvas: &[u8],
+    (width, height): (usize, usize),
+    (thumb_width, thumb_height): (usize, usize),
+    out: &mut Vec<u8>,
+) {
+    out.clear();
+    for ty in 0..thumb_height {
+        let (y0, y1) = (ty * height / thumb_height, (ty + 1) * height / thumb_height);
+        for tx in 0..thumb_width {
+            let (x0, x1) = (tx * width / thumb_width, (tx + 1) * width / thumb_width);
+            let mut sum = [0u32; 3];
+            for row in canvas[y0 * width..y1 * width].chunks_exact(width) {
+                for &color in &row[x0..x1] {
+                    let [r, g, b] = rgb(color);
+                    sum[0] += r as u32;
+                    sum[1] += g as u32;
+                    sum[2] += b as u32;
+                }
+            }
+            let count = ((x1 - x0) * (y1 - y0)).max(1) as u32;
+            out.extend(sum.map(|channel| ((channel + count / 2) / count) as u8));
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |c, &b| {
+        CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn chunk(kind: &[u8; 4], data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Largest stored deflate block.
+const STORED_BLOCK_LEN: usize = 65_535;
+
+/// Encodes `rgb` (`width` × `height`, row-major) as an 8-bit truecolor PNG
+/// whose image data is zlib with stored (uncompressed) blocks.
+pub fn encode_png(rgb: &[u8], width: usize, height: usize, out: &mut Vec<u8>) {
+    out.clear();
+    out.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolor, deflate, adaptive filters, no interlace.
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    chunk(b"IHDR", &ihdr, out);
+
+    // Each row starts with its filter type, 0 = none.
+    let mut raw = Vec::with_capacity(height * (1 + 3 * width));
+    for row in rgb.chunks_exact(3 * width) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let mut start = 0;
+    loop {
+        let end = (start + STORED_BLOCK_LEN).min(raw.len());
+        let block = &raw[start..end];
+        // BFINAL on the last block, BTYPE 00 (stored).
+        zlib.push((end == raw.len()) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+        if end == raw.len() {
+            break;
+        }
+        start = end;
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    chunk(b"IDAT", &zlib, out);
+    chunk(b"IEND", &[], out);
+}
+
+const READ_ATTEMPTS: usize = 3;
+
+/// The dashboard's cached thumbnail.
+pub struct Thumbnail {
+    png: Vec<u8>,
+    rgb: Vec<u8>,
+    seq: Option<u64>,
+    checked_at: Option<Instant>,
+}
+
+impl Thumbnail {
+    pub fn new() -> Self {
+        Self {
+            png: Vec::new(),
+            rgb: Vec::with_capacity(3 * THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT),
+            seq: None,
+            checked_at: None,
+        }
+    }
+
+    /// The PNG of a snapshot at most THUMBNAIL_REFRESH_MS old (plus the
+    /// time since it was published). Empty if none could be read yet.
+    pub fn png(&mut self) -> &[u8] {
+        let refresh = Duration::from_millis(THUMBNAIL_REFRESH_MS);
+        if self.checked_at.is_none_or(|at| at.elapsed() >= refresh) {
+            self.checked_at = Some(Instant::now());
+            self.refresh();
+        }
+        &self.png
+    }
+
+    fn refresh(&mut self) {
+        let newest = crate::canvas::PUBLISH_SEQ.load(std::sync::atomic::Ordering::Acquire);
+        if self.seq == Some(newest) {
+            return;
+        }
+        // A slot recycled under the read is tried again with the next
+        // snapshot, then given up on until the next refresh.
+        for _ in 0..READ_ATTEMPTS {
+            let rgb = &mut self.rgb;
+            let read = crate::canvas::read_newest_snapshot(|canvas| {
+                downsample(
+                    canvas,
+                    (CANVAS_WIDTH, CANVAS_HEIGHT),
+                    (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT),
+                    rgb,
+                )
+            });
+            if let Some((seq, ())) = read {
+                encode_png(&self.rgb, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT, &mut self.png);
+                self.seq = Some(seq);
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Thumbnail {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pulls the pixels back out of a PNG made by `encode_png`, checking
+    /// every CRC and the Adler-32 on the way.
+    fn decode_png(png: &[u8]) -> (usize, usize, Vec<u8>) {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let (mut pos, mut size, mut zlib) = (8, (0, 0), Vec::new());
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+            match &body[..4] {
+                b"IHDR" => {
+                    let field = |i: usize| u32::from_be_bytes(body[i..i + 4].try_into().unwrap());
+                    size = (field(4) as usize, field(8) as usize);
+                    assert_eq!(&body[12..], [8, 2, 0, 0, 0]);
+                }
+                b"IDAT" => zlib.extend_from_slice(&body[4..]),
+                b"IEND" => assert_eq!(pos + 12, png.len()),
+                _ => panic!("unexpected chunk"),
+            }
+            pos += 12 + len;
+        }
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        let (mut at, mut raw) = (2, Vec::new());
+        loop {
+            let last = zlib[at] == 1;
+            let len = u16::from_le_bytes([zlib[at + 1], zlib[at + 2]]) as usize;
+            let nlen = u16::from_le_bytes([zlib[at + 3], zlib[at + 4]]);
+            assert_eq!(!nlen as usize, len);
+            raw.extend_from_slice(&zlib[at + 5..at + 5 + len]);
+            at += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(zlib[at..], adler32(&raw).to_be_bytes());
+        let (width, height) = size;
+        let mut rgb = Vec::new();
+        for row in raw.chunks_exact(1 + 3 * width) {
+            assert_eq!(row[0], 0);
+            rgb.extend_from_slice(&row[1..]);
+        }
+        assert_eq!(rgb.len(), 3 * width * height);
+        (width, height, rgb)
+    }
+
+    #[test]
+    fn test_thumbnail_shows_a_placed_pixel() {
+        // White canvas; a 10 × 10 red block fills thumbnail pixel (50, 25)
+        // exactly, and one blue pixel tints (0, 0).
+        let mut canvas = vec![0u8; CANVAS_WIDTH * CANVAS_HEIGHT];
+        for y in 250..260 {
+            canvas[y * CANVAS_WIDTH + 500..y * CANVAS_WIDTH + 510].fill(5);
+        }
+        canvas[3 * CANVAS_WIDTH + 4] = 13;
+
+        let mut rgb = Vec::new();
+        downsample(
+            &canvas,
+            (CANVAS_WIDTH, CANVAS_HEIGHT),
+            (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT),
+            &mut rgb,
+        );
+        let mut png = Vec::new();
+        encode_png(&rgb, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT, &mut png);
+        let (width, height, pixels) = decode_png(&png);
+        assert_eq!((width, height), (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT));
+        let at = |x: usize, y: usize| &pixels[3 * (y * width + x)..][..3];
+
+        assert_eq!(at(50, 25), [0xE5, 0x00, 0x00]);
+        assert_eq!(at(49, 25), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(at(51, 26), [0xFF, 0xFF, 0xFF]);
+        // One pixel in a hundred: 99 % white, 1 % blue.
+        assert_eq!(at(0, 0), [0xFC, 0xFC, 0xFF]);
+        assert_eq!(at(1, 0), [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_png_framing() {
+        // Known CRC-32 and Adler-32 values.
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // A canvas larger than one stored block still round-trips.
+        let rgb: Vec<u8> = (0..3 * 200 * 150).map(|i| (i % 251) as u8).collect();
+        let mut png = Vec::new();
+        encode_png(&rgb, 200, 150, &mut png);
+        assert_eq!(decode_png(&png), (200, 150, rgb));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::const_settings::{TILE_BITMAP_LEN, TIMING_WHEEL_TICKS};
+use protocol::control::{TimelineEntry, encode_timeline};
+use std::collections::VecDeque;
+use std::sync::RwLock;
+
+/// Minutes covered by one cooldown: a writer lands at most one pixel per
+/// cooldown, so writes summed over this many minutes estimate active writers.
+const COOLDOWN_MINUTES: usize = TIMING_WHEEL_TICKS.div_ceil(60);
+
+/// Encoded ring as last published by the master; read by workers answering
+/// STATS_TIMELINE on the control stream. Written once a minute.
+static PUBLISHED: RwLock<Vec<u8>> = RwLock::new(Vec::new());
+
+/// Per-minute activity ring owned by the master thread.
+pub struct Timeline {
+    window: usize,
+    ring: VecDeque<TimelineEntry>,
+    current: TimelineEntry,
+    touched_tiles: [u64; TILE_BITMAP_LEN],
+}
+
+impl Timeline {
+    pub fn new(window_minutes: usize, now_minute: u32) -> Self {
+        Self {
+            window: window_minutes.max(1),
+            ring: VecDeque::with_capacity(window_minutes.max(1)),
+            current: TimelineEntry {
+                minute: now_minute,
+                ..Default::default()
+            },
+            touched_tiles: [0; TILE_BITMAP_LEN],
+        }
+    }
+
+    /// Counts one applied write. O(1): a counter bump and one bitmap test.
+    #[inline(always)]
+    pub fn record(&mut self, tile: usize) {
+        self.current.writes += 1;
+        let (word, bit) = (tile / 64, 1u64 << (tile % 64));
+        if self.touched_tiles[word] & bit == 0 {
+            self.touched_tiles[word] |= bit;
+            self.current.unique_tiles += 1;
+        }
+    }
+
+    /// Closes the current minute once the clock has moved past it. Minutes
+    /// the master skipped entirely are recorded as empty. Returns true if
+    /// the ring changed.
+    pub fn rotate(&mut self, now_minute: u32) -> bool {
+        if now_minute <= self.current.minute {
+            return false;
+        }
+        // Anything older than the window would be evicted right away.
+        let skipped = (now_minute - self.current.minute - 1).min(self.window as u32);
+        self.close_current();
+        self.current.minute = now_minute - skipped;
+        for _ in 0..skipped {
+            self.close_current();
+        }
+        true
+    }
+
+    fn close_current(&mut self) {
+        let recent: u32 = self
+            .ring
+            .iter()
+            .rev()
+            .take(COOLDOWN_MINUTES - 1)
+            .map(|e| e.writes)
+            .sum();
+        self.current.active_writers = recent + self.current.writes;
+        if self.ring.len() == self.window {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(self.current);
+        self.current = TimelineEntry {
+            minute: self.current.minute + 1,
+            ..Default::default()
+        };
+        self.touched_tiles = [0; TILE_BITMAP_LEN];
+    }
+
+    pub fn entries(&self) -> impl ExactSizeIterator<Item = &TimelineEntry> {
+        self.ring.iter()
+    }
+
+    /// Makes the completed minutes visible to workers.
+    pub fn publish(&self) {
+        let mut encoded = Vec::new();
+        encode_timeline(self.entries(), &mut encoded);
+        *PUBLISHED.write().unwrap() = encoded;
+    }
+}
+
+/// Encoded ring (`u32 count` + entries) from the last publish.
+pub fn published() -> Vec<u8> {
+    let encoded = PUBLISHED.read().unwrap();
+    if encoded.is_empty() {
+        let mut empty = Vec::new();
+        encode_timeline(std::iter::empty(), &mut empty);
+        return empty;
+    }
+    encoded.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_rotation() {
+        let mut t = Timeline::new(3, 100);
+        for tile in [1, 1, 2, 70] {
+            t.record(tile);
+        }
+        assert!(!t.rotate(100));
+        assert!(t.rotate(101));
+        t.record(5);
+
+        // Minute 101 had one write; 102 and 103 were skipped entirely.
+        assert!(t.rotate(104));
+        let got: Vec<_> = t
+            .entries()
+            .map(|e| (e.minute, e.writes, e.unique_tiles))
+            .collect();
+        assert_eq!(got, vec![(101, 1, 1), (102, 0, 0), (103, 0, 0)]);
+
+        // Writers active within a cooldown are summed across the recent
+        // minutes, including minute 100 even though it has left the window.
+        assert!(t.entries().all(|e| e.active_writers == 5));
+
+        // A huge gap is capped at the window size.
+        assert!(t.rotate(10_000));
+        assert_eq!(t.entries().len(), 3);
+        assert_eq!(t.entries().last().unwrap().minute, 9_999);
+
+        t.publish();
+        let decoded = protocol::control::decode_timeline(&published()).unwrap();
+        assert_eq!(decoded.len(), 3);
+    }
+}
@@ -1,51 +1,190 @@
-use crate::const_settings::TIMING_WHEEL_TICKS;
+use crate::const_settings::{COOLDOWN_ARRAY_LEN, TIMING_WHEEL_TICKS, WHEEL_SUMMARY_LEN};
 use crate::cooldown::CooldownArray;
 
-pub struct TimingWheel {
-    pub wheel: Box<[CooldownArray; TIMING_WHEEL_TICKS]>,
+/// The users whose cooldown ends when the wheel comes back around to this
+/// bucket. Alongside the bitset, a summary with one bit per bitset chunk
+/// marks the chunks that may hold users, so a tick visits only those: its
+/// cost follows how many users are expiring, not how many could.
+#[derive(Clone)]
+pub struct WheelBucket {
+    users: CooldownArray,
+    summary: [u64; WHEEL_SUMMARY_LEN],
+}
+
+impl WheelBucket {
+    pub fn new() -> Self {
+        Self {
+            users: CooldownArray::new(),
+            summary: [0; WHEEL_SUMMARY_LEN],
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_on_cooldown(&self, local_id: u32) -> bool {
+        self.users.is_on_cooldown(local_id)
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, local_id: u32) {
+        self.users.set_cooldown(local_id);
+        let chunk_idx = (local_id >> 6) as usize;
+        self.summary[chunk_idx >> 6] |= 1 << (chunk_idx & 63);
+    }
+
+    /// Leaves the summary bit alone: a chunk that turns out empty costs the
+    /// next tick one extra visit.
+    #[inline(always)]
+    pub fn remove(&mut self, local_id: u32) {
+        self.users.clear_cooldown(local_id);
+    }
+
+    /// Lifts the cooldown of every user in the bucket and empties it.
+    #[inline(always)]
+    pub fn expire(&mut self, master: &mut CooldownArray) {
+        let marked: usize = self.summary.iter().map(|w| w.count_ones() as usize).sum();
+        if marked * DENSE_SWEEP_FRACTION >= COOLDOWN_ARRAY_LEN {
+            // Most chunks are marked: the vectorized sweep over the whole
+            // bitset beats visiting them one by one.
+            for (master_chunk, expiring_chunk) in
+                master.bits.iter_mut().zip(self.users.bits.iter_mut())
+            {
+                *master_chunk &= !*expiring_chunk;
+                *expiring_chunk = 0;
+            }
+            self.summary = [0; WHEEL_SUMMARY_LEN];
+            return;
+        }
+        for (word_idx, word) in self.summary.iter_mut().enumerate() {
+            let mut chunks = std::mem::take(word);
+            while chunks != 0 {
+                let chunk_idx = word_idx * 64 + chunks.trailing_zeros() as usize;
+                chunks &= chunks - 1;
+                master.bits[chunk_idx] &= !self.users.bits[chunk_idx];
+                self.users.bits[chunk_idx] = 0;
+            }
+        }
+    }
+}
+
+impl Default for WheelBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A bucket with at least 1/N of its chunks marked is swept whole.
+const DENSE_SWEEP_FRACTION: usize = 8;
+
+// Every chunk of the bitset has a summary bit.
+const _: () = assert!(WHEEL_SUMMARY_LEN * 64 >= COOLDOWN_ARRAY_LEN);
+
+/// `TICKS` is only overridden by tests that need a short horizon.
+pub struct TimingWheel<const TICKS: usize = TIMING_WHEEL_TICKS> {
+    pub wheel: Box<[WheelBucket; TICKS]>,
     pub current_tick: usize,
+    /// The bucket each user id was last put in, so how long its cooldown
+    /// has left is one lookup (`remaining_ticks`).
+    placed: Box<[u16]>,
 }
 
 impl TimingWheel {
     pub fn new() -> Self {
+        Self::with_ticks()
+    }
+}
+
+impl<const TICKS: usize> TimingWheel<TICKS> {
+    pub fn with_ticks() -> Self {
+        const { assert!(TICKS <= u16::MAX as usize) };
         // Allocate directly on the heap via Vec to avoid a ~2.4 MB stack frame.
-        // Box::new([CooldownArray::new(); TIMING_WHEEL_TICKS]) constructs the
+        // Box::new([WheelBucket::new(); TIMING_WHEEL_TICKS]) constructs the
         // full array on the stack before boxing it — fatal in debug builds.
-        // SAFETY: Vec is built with exactly TIMING_WHEEL_TICKS elements, so the
+        // SAFETY: Vec is built with exactly TICKS elements, so the
         // raw pointer cast from *mut [T] to *mut [T; N] is valid.
-        let wheel: Box<[CooldownArray; TIMING_WHEEL_TICKS]> = unsafe {
-            let boxed_slice: Box<[CooldownArray]> = (0..TIMING_WHEEL_TICKS)
-                .map(|_| CooldownArray::new())
+        let wheel: Box<[WheelBucket; TICKS]> = unsafe {
+            let boxed_slice: Box<[WheelBucket]> = (0..TICKS)
+                .map(|_| WheelBucket::new())
                 .collect::<Vec<_>>()
                 .into_boxed_slice();
-            Box::from_raw(Box::into_raw(boxed_slice) as *mut [CooldownArray; TIMING_WHEEL_TICKS])
+            Box::from_raw(Box::into_raw(boxed_slice) as *mut [WheelBucket; TICKS])
         };
         Self {
             wheel,
             current_tick: 0,
+            placed: vec![0; COOLDOWN_ARRAY_LEN * 64].into_boxed_slice(),
         }
     }
 
     #[inline(always)]
     pub fn tick(&mut self, master: &mut CooldownArray) {
-        self.current_tick = (self.current_tick + 1) % TIMING_WHEEL_TICKS;
-
-        let expiring_users = &mut self.wheel[self.current_tick];
+        self.current_tick = (self.current_tick + 1) % TICKS;
 
-        // SIMD Vectorized mass eviction (AND NOT)
-        for (master_chunk, expiring_chunk) in
-            master.bits.iter_mut().zip(expiring_users.bits.iter_mut())
-        {
-            *master_chunk &= !*expiring_chunk;
-            *expiring_chunk = 0; // Wipe bucket for future use in one pass
-        }
+        // Mass eviction (AND NOT) of the chunks that have expiring users.
+        self.wheel[self.current_tick].expire(master);
     }
 
     #[inline(always)]
     pub fn add_cooldown(&mut self, local_id: u32) {
         // Find bucket that is basically just before current tick
-        // So they will expire TIMING_WHEEL_TICKS ticks from now.
-        self.wheel[self.current_tick].set_cooldown(local_id);
+        // So they will expire TICKS ticks from now.
+        self.wheel[self.current_tick].insert(local_id);
+        self.placed[local_id as usize] = self.current_tick as u16;
+    }
+
+    /// `add_cooldown` for a cooldown of `ticks` (1..=TICKS) rather than the
+    /// full horizon: the bucket the wheel reaches `ticks` ticks from now.
+    #[inline(always)]
+    pub fn add_cooldown_for(&mut self, local_id: u32, ticks: usize) {
+        let bucket = (self.current_tick + ticks.clamp(1, TICKS)) % TICKS;
+        self.wheel[bucket].insert(local_id);
+        self.placed[local_id as usize] = bucket as u16;
+    }
+
+    /// Ticks until `local_id`'s cooldown is lifted: 1 if the next tick does
+    /// it, TICKS if it started during the current one. None when it is not
+    /// on cooldown. Only looks in the bucket the id was last put in, so
+    /// the pixel path can afford it for cooldown NACKs.
+    pub fn remaining_ticks(&self, local_id: u32) -> Option<usize> {
+        let bucket = self.placed[local_id as usize] as usize;
+        if !self.wheel[bucket].is_on_cooldown(local_id) {
+            return None;
+        }
+        Some((bucket + TICKS - self.current_tick - 1) % TICKS + 1)
+    }
+
+    /// `remaining_ticks` in ms, 0 when not on cooldown. The worker ticks on
+    /// each second boundary, so the first of those ticks is only what is
+    /// left of the current second.
+    pub fn remaining_ms(&self, local_id: u32, now_ms: u64) -> u32 {
+        self.remaining_ticks(local_id)
+            .map_or(0, |ticks| (ticks as u64 * 1000 - now_ms % 1000) as u32)
+    }
+
+    /// Forgets everything about a freed user id: its live cooldown and any
+    /// pending expiry. Without this, a stale expiry from the previous owner
+    /// would fire early and clear the next owner's cooldown. One bit-clear
+    /// per bucket, only on disconnect.
+    pub fn release(&mut self, master: &mut CooldownArray, local_id: u32) {
+        master.clear_cooldown(local_id);
+        for bucket in self.wheel.iter_mut() {
+            bucket.remove(local_id);
+        }
+    }
+
+    /// Lifts every cooldown, for a new epoch (epoch.rs). Each bucket only
+    /// visits the chunks that hold users, as a tick does.
+    pub fn reset(&mut self, master: &mut CooldownArray) {
+        for bucket in self.wheel.iter_mut() {
+            bucket.expire(master);
+        }
+        master.bits.fill(0);
+    }
+
+    /// Heap bytes the wheel holds; `derived().mem_timing_wheel` for the
+    /// default horizon.
+    pub const fn heap_bytes() -> usize {
+        TICKS * std::mem::size_of::<WheelBucket>()
+            + COOLDOWN_ARRAY_LEN * 64 * std::mem::size_of::<u16>()
     }
 }
 
@@ -58,6 +197,8 @@ impl Default for TimingWheel {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::const_settings::{MAX_CONNECTIONS_PER_WORKER, derived};
+    use std::time::Instant;
 
     #[test]
     fn test_timing_wheel() {
@@ -77,4 +218,212 @@ mod tests {
         wheel.tick(&mut master);
         assert!(!master.is_on_cooldown(55));
     }
+
+    #[test]
+    fn test_reused_id_keeps_its_cooldown() {
+        const TICKS: usize = 4;
+        let mut master = CooldownArray::new();
+        let mut wheel = TimingWheel::<TICKS>::with_ticks();
+
+        // Previous owner of id 7 goes on cooldown in bucket 0, then disconnects.
+        master.set_cooldown(7);
+        wheel.add_cooldown(7);
+        wheel.release(&mut master, 7);
+        assert!(!master.is_on_cooldown(7));
+
+        // Two ticks later the id is reassigned and the new owner paints.
+        wheel.tick(&mut master);
+        wheel.tick(&mut master);
+        master.set_cooldown(7);
+        wheel.add_cooldown(7);
+
+        // Bucket 0 (the stale expiry) fires after two more ticks; the new
+        // owner's cooldown must survive it...
+        for _ in 0..TICKS - 1 {
+            wheel.tick(&mut master);
+            assert!(master.is_on_cooldown(7));
+        }
+        // ...and expire on its own schedule.
+        wheel.tick(&mut master);
+        assert!(!master.is_on_cooldown(7));
+    }
+
+    #[test]
+    fn test_remaining_ticks_count_down() {
+        const TICKS: usize = 4;
+        let mut master = CooldownArray::new();
+        let mut wheel = TimingWheel::<TICKS>::with_ticks();
+        wheel.tick(&mut master);
+        assert_eq!(wheel.remaining_ticks(9), None);
+
+        master.set_cooldown(9);
+        wheel.add_cooldown(9);
+        assert_eq!(wheel.remaining_ms(9, 12_250), 3_750);
+        for remaining in (1..=TICKS).rev() {
+            assert_eq!(wheel.remaining_ticks(9), Some(remaining));
+            wheel.tick(&mut master);
+        }
+        assert_eq!(wheel.remaining_ticks(9), None);
+        assert_eq!(wheel.remaining_ms(9, 12_250), 0);
+        assert!(!master.is_on_cooldown(9));
+    }
+
+    #[test]
+    fn test_shorter_cooldown_expires_sooner() {
+        const TICKS: usize = 6;
+        let mut master = CooldownArray::new();
+        let mut wheel = TimingWheel::<TICKS>::with_ticks();
+        wheel.tick(&mut master);
+
+        master.set_cooldown(4);
+        wheel.add_cooldown_for(4, 2);
+        // Out of range asks for the whole horizon, same as add_cooldown.
+        master.set_cooldown(5);
+        wheel.add_cooldown_for(5, TICKS + 3);
+        assert_eq!(wheel.remaining_ticks(4), Some(2));
+        assert_eq!(wheel.remaining_ticks(5), Some(TICKS));
+
+        wheel.tick(&mut master);
+        assert!(master.is_on_cooldown(4));
+        wheel.tick(&mut master);
+        assert!(!master.is_on_cooldown(4));
+        for _ in 2..TICKS {
+            assert!(master.is_on_cooldown(5));
+            wheel.tick(&mut master);
+        }
+        assert!(!master.is_on_cooldown(5));
+    }
+
+    #[test]
+    fn test_reset_lifts_every_cooldown() {
+        const TICKS: usize = 5;
+        let mut master = CooldownArray::new();
+        let mut wheel = TimingWheel::<TICKS>::with_ticks();
+        for (id, ticks) in [(3, 1), (64, 2), (9_000, TICKS)] {
+            master.set_cooldown(id);
+            wheel.add_cooldown_for(id, ticks);
+        }
+        wheel.tick(&mut master);
+
+        wheel.reset(&mut master);
+        assert!(master.bits.iter().all(|&chunk| chunk == 0));
+        assert_eq!(wheel.remaining_ticks(9_000), None);
+        // Cooldowns charged after it run their course as usual.
+        master.set_cooldown(64);
+        wheel.add_cooldown_for(64, 2);
+        wheel.tick(&mut master);
+        assert!(master.is_on_cooldown(64));
+        wheel.tick(&mut master);
+        assert!(!master.is_on_cooldown(64));
+    }
+
+    #[test]
+    fn test_heap_size_matches_estimate() {
+        assert_eq!(
+            TimingWheel::<TIMING_WHEEL_TICKS>::heap_bytes(),
+            derived().mem_timing_wheel
+        );
+        assert_eq!(
+            std::mem::size_of::<WheelBucket>(),
+            (COOLDOWN_ARRAY_LEN + WHEEL_SUMMARY_LEN) * 8
+        );
+    }
+
+    #[test]
+    fn test_expiry_matches_dense_eviction() {
+        const TICKS: usize = 3;
+        let mut master = CooldownArray::new();
+        let mut wheel = TimingWheel::<TICKS>::with_ticks();
+        let ids = [
+            0,
+            63,
+            64,
+            4_095,
+            4_096,
+            30_001,
+            MAX_CONNECTIONS_PER_WORKER as u32 - 1,
+        ];
+        for &id in &ids {
+            master.set_cooldown(id);
+            wheel.add_cooldown(id);
+        }
+        // Removed before expiry: its chunk stays marked, nothing else changes.
+        wheel.release(&mut master, 30_001);
+        // A user on cooldown that is not in the expiring bucket.
+        master.set_cooldown(30_002);
+
+        for _ in 0..TICKS {
+            wheel.tick(&mut master);
+        }
+        assert!(ids.iter().all(|&id| !master.is_on_cooldown(id)));
+        assert!(master.is_on_cooldown(30_002));
+        let bucket = &wheel.wheel[0];
+        assert!(bucket.users.bits.iter().all(|&chunk| chunk == 0));
+        assert!(bucket.summary.iter().all(|&word| word == 0));
+    }
+
+    /// Tick cost at 0%, 1% and 50% of user ids expiring per bucket, against
+    /// the full-bitset sweep every tick used to do. Ids are handed out from
+    /// the top of the range, so on a worker that is not full the expiring
+    /// users sit in a few chunks; the spread 1% case is the worst one.
+    /// `cargo test -p server --release bench_tick_density -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_tick_density() {
+        const ROUNDS: usize = 20;
+        let users = MAX_CONNECTIONS_PER_WORKER as u32;
+        let cases = [
+            ("0%", 0, 0),
+            ("1% spread", 100, 0),
+            // 1% of the id range, as 10% of a worker a tenth full.
+            ("1% clustered", 10, users - users / 10),
+            ("50%", 2, 0),
+        ];
+        for (label, stride, first) in cases {
+            let mut master = CooldownArray::new();
+            let mut wheel = TimingWheel::new();
+            let mut dense = vec![CooldownArray::new(); TIMING_WHEEL_TICKS];
+            let (mut sparse_ns, mut dense_ns) = (0, 0);
+            // Round 0 only faults the pages in.
+            for round in 0..=ROUNDS {
+                if round == 1 {
+                    (sparse_ns, dense_ns) = (0, 0);
+                }
+                let buckets = wheel.wheel.iter_mut().zip(dense.iter_mut());
+                for (tick, (bucket, reference)) in buckets.enumerate() {
+                    if stride > 0 {
+                        // Offset per tick so buckets do not all hold the same ids.
+                        let ids = (first + tick as u32 % stride..users).step_by(stride as usize);
+                        for id in ids {
+                            bucket.insert(id);
+                            reference.set_cooldown(id);
+                        }
+                    }
+                }
+
+                let start = Instant::now();
+                for _ in 0..TIMING_WHEEL_TICKS {
+                    wheel.tick(&mut master);
+                }
+                sparse_ns += start.elapsed().as_nanos();
+
+                let start = Instant::now();
+                for bucket in dense.iter_mut() {
+                    for (m, e) in master.bits.iter_mut().zip(bucket.bits.iter_mut()) {
+                        *m &= !*e;
+                        *e = 0;
+                    }
+                    std::hint::black_box(&mut master);
+                }
+                dense_ns += start.elapsed().as_nanos();
+            }
+            let ticks = (ROUNDS * TIMING_WHEEL_TICKS) as u128;
+            println!(
+                "{:>12}: {:>6} ns/tick, {:>6} ns/tick with a full sweep",
+                label,
+                sparse_ns / ticks,
+                dense_ns / ticks
+            );
+        }
+    }
 }
@@ -0,0 +1,285 @@
+use crate::config::ServerConfig;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// Names a generated certificate is issued for without `--san`.
+pub const DEFAULT_SAN: &str = "localhost";
+
+/// Makes sure the certificate and key workers load exist and load, before
+/// any worker starts. Existing files are never rewritten. A self-signed pair
+/// for the `--san` names (`localhost` without it) is generated only when
+/// both files are missing and either `--san` was given or neither `--cert`
+/// nor `--key` was; a configured path that does not exist is an error
+/// rather than something to paper over. Files that are there but do not
+/// parse, or a key that does not match its certificate, fail here with
+/// their paths instead of panicking a worker.
+pub fn ensure_certificates(config: &ServerConfig) -> io::Result<()> {
+    let (cert, key) = (&config.cert_path, &config.key_path);
+    match (cert.exists(), key.exists()) {
+        (true, true) => return check_loadable(cert, key),
+        _ if !config.generate_cert => {
+            let missing = if cert.exists() { key } else { cert };
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "configured TLS file {} does not exist (add --san <names> to generate a self-signed pair)",
+                    missing.display()
+                ),
+            ));
+        }
+        (false, false) => {}
+        _ => {
+            // Regenerating both would clobber the half that is there.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "only one of {} and {} exists; remove it or provide both",
+                    cert.display(),
+                    key.display()
+                ),
+            ));
+        }
+    }
+
+    for path in [cert, key] {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    let names = if config.san.is_empty() {
+        vec![DEFAULT_SAN.to_string()]
+    } else {
+        config.san.clone()
+    };
+    let generated = rcgen::generate_simple_self_signed(names.clone()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot issue a certificate for {:?}: {}", names, e),
+        )
+    })?;
+    write_new(cert, generated.cert.pem().as_bytes())?;
+    write_new(key, generated.key_pair.serialize_pem().as_bytes())?;
+    println!(
+        "Generated self-signed certificate {} (key {}) for {}",
+        cert.display(),
+        key.display(),
+        names.join(", ")
+    );
+    check_loadable(cert, key)
+}
+
+/// Loads the pair the way every worker does (transport.rs).
+fn check_loadable(cert: &Path, key: &Path) -> io::Result<()> {
+    let invalid = |what: &str, path: &Path, e: quiche::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cannot load TLS {} {}: {:?}", what, path.display(), e),
+        )
+    };
+    let utf8 = |path: &Path| {
+        path.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("TLS path {} is not UTF-8", path.display()),
+            )
+        })
+    };
+    let mut quic = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(io::Error::other)?;
+    quic.load_cert_chain_from_pem_file(utf8(cert)?)
+        .map_err(|e| invalid("certificate", cert, e))?;
+    // BoringSSL also checks the key against the certificate here.
+    quic.load_priv_key_from_pem_file(utf8(key)?)
+        .map_err(|e| invalid("key", key, e))
+}
+
+/// Session ticket key: BoringSSL's 48 bytes of key name, HMAC key and AES
+/// key. Drawn once per process and set on every worker's config, so a
+/// ticket one worker issued resumes on whichever worker the client's next
+/// connection is steered to. A restart draws a new key and every ticket
+/// issued before it falls back to a full handshake.
+pub fn ticket_key() -> &'static [u8; 48] {
+    static KEY: OnceLock<[u8; 48]> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let mut key = [0; 48];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut key[..]);
+        key
+    })
+}
+
+/// Where connections write their TLS secrets, in NSS key log format, when
+/// SSLKEYLOGFILE names a file: Wireshark decrypts captures with it. Each
+/// worker opens the file once and shares the handle between its
+/// connections. quiche hands over a line per write, and appending keeps
+/// lines from different workers whole.
+#[derive(Clone)]
+pub struct KeyLog(Arc<File>);
+
+impl KeyLog {
+    /// None when SSLKEYLOGFILE is unset, or names a file that cannot be
+    /// opened (which is reported).
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("SSLKEYLOGFILE")?;
+        match Self::open(Path::new(&path)) {
+            Ok(keylog) => Some(keylog),
+            Err(e) => {
+                eprintln!(
+                    "Warning: SSLKEYLOGFILE {}: {}; TLS secrets are not logged",
+                    Path::new(&path).display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// The secrets decrypt every connection they belong to: owner-only, like
+    /// the private key.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        Ok(Self(Arc::new(options.open(path)?)))
+    }
+}
+
+impl Write for KeyLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// Fails instead of overwriting if another process got there first.
+fn write_new(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("canvas-tls-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config_in(dir: &Path, generate_cert: bool) -> ServerConfig {
+        ServerConfig {
+            cert_path: dir.join("cert.crt"),
+            key_path: dir.join("key.key"),
+            generate_cert,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_existing_certificates_are_left_alone() {
+        let dir = scratch_dir("existing");
+        let config = config_in(&dir, true);
+
+        ensure_certificates(&config).unwrap();
+        let cert = std::fs::read(&config.cert_path).unwrap();
+        let key = std::fs::read(&config.key_path).unwrap();
+        assert!(cert.starts_with(b"-----BEGIN CERTIFICATE-----"));
+
+        for _ in 0..3 {
+            ensure_certificates(&config).unwrap();
+        }
+        assert_eq!(std::fs::read(&config.cert_path).unwrap(), cert);
+        assert_eq!(std::fs::read(&config.key_path).unwrap(), key);
+
+        // Half a pair is never completed by regenerating both.
+        std::fs::remove_file(&config.key_path).unwrap();
+        assert!(ensure_certificates(&config).is_err());
+        assert_eq!(std::fs::read(&config.cert_path).unwrap(), cert);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_configured_paths_are_never_generated() {
+        let dir = scratch_dir("configured");
+        let config = config_in(&dir, false);
+        let err = ensure_certificates(&config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!config.cert_path.exists() && !config.key_path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// DER of the first PEM block.
+    fn pem_der(pem: &str) -> Vec<u8> {
+        let body: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        let value = |c: u8| match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            _ => 63,
+        };
+        let mut der = Vec::new();
+        for quad in body.trim_end_matches('=').as_bytes().chunks(4) {
+            let bits = quad
+                .iter()
+                .fold(0u32, |acc, &c| (acc << 6) | value(c) as u32)
+                << (6 * (4 - quad.len()));
+            der.extend_from_slice(&bits.to_be_bytes()[1..quad.len()]);
+        }
+        der
+    }
+
+    #[test]
+    fn test_san_generates_for_configured_paths() {
+        let dir = scratch_dir("san");
+        let config = ServerConfig {
+            san: vec!["canvas.example.com".to_string(), "203.0.113.7".to_string()],
+            ..config_in(&dir, true)
+        };
+        ensure_certificates(&config).unwrap();
+        let der = pem_der(&std::fs::read_to_string(&config.cert_path).unwrap());
+        let contains = |needle: &[u8]| der.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"canvas.example.com"));
+        assert!(contains(&[203, 0, 113, 7]));
+        assert!(!contains(b"localhost"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unloadable_files_are_named() {
+        let dir = scratch_dir("garbage");
+        let config = config_in(&dir, true);
+        std::fs::write(&config.cert_path, b"not a certificate").unwrap();
+        std::fs::write(&config.key_path, b"not a key").unwrap();
+        let err = ensure_certificates(&config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("certificate"));
+        assert!(err.to_string().contains("cert.crt"));
+        // Left as they were.
+        assert_eq!(
+            std::fs::read(&config.cert_path).unwrap(),
+            b"not a certificate"
+        );
+
+        // A good certificate with another pair's key.
+        let other = scratch_dir("garbage-other");
+        ensure_certificates(&config_in(&other, true)).unwrap();
+        std::fs::copy(other.join("cert.crt"), &config.cert_path).unwrap();
+        let err = ensure_certificates(&config).unwrap_err();
+        assert!(err.to_string().contains("key.key"), "{}", err);
+        std::fs::copy(other.join("key.key"), &config.key_path).unwrap();
+        ensure_certificates(&config).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&other);
+    }
+}
@@ -1,52 +1,371 @@
+use crate::conn_memory::{ConnMemory, MemoryLimits};
+use crate::conn_slot::{ConnSlots, SlotState};
 use crate::const_settings::{
-    DGRAM_MAX_SEND_SIZE, MAX_CONNECTIONS_PER_WORKER, QUIC_DGRAM_QUEUE_LEN, QUIC_INITIAL_MAX_DATA,
-    QUIC_INITIAL_MAX_STREAM_DATA_BIDI_LOCAL, QUIC_INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
-    QUIC_INITIAL_MAX_STREAM_DATA_UNI, QUIC_INITIAL_MAX_STREAMS_BIDI, QUIC_INITIAL_MAX_STREAMS_UNI,
+    ACCEPT_WARN_INTERVAL_MS, BROADCAST_INTERVAL_MS, CONTRACT_NOTICE_MS, COOLDOWN_NACK_INTERVAL_MS,
+    DGRAM_DRAIN_BUDGET, MAX_CONNECTIONS_PER_WORKER, MAX_DGRAMS_PER_RECV, MAX_PENDING_REFUSALS,
+    MAX_PENDING_RESPONSES, PIXEL_DATAGRAM_SIZE, PIXEL_NONCE_SIZE, PIXEL_TOKEN_SIZE,
+    QUIC_DGRAM_QUEUE_LEN, QUIC_INITIAL_MAX_DATA, QUIC_INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+    QUIC_INITIAL_MAX_STREAM_DATA_BIDI_REMOTE, QUIC_INITIAL_MAX_STREAM_DATA_UNI,
+    QUIC_INITIAL_MAX_STREAMS_BIDI, QUIC_INITIAL_MAX_STREAMS_UNI, QUIC_MAX_IDLE_TIMEOUT_MS,
+    RESPONSE_PACKET_MAX_LEN,
 };
-use quiche::{Connection, RecvInfo};
+use crate::control::ControlStreams;
+use crate::half_open::{HalfOpen, HalfOpenLimits};
+use crate::junk::{self, JunkFilter, JunkLimits};
+use crate::live_stats::StatsReplies;
+use crate::metrics::WorkerMetrics;
+use crate::qlog::QlogSampler;
+use crate::reservation::{Reservations, ResumeKey};
+use crate::retry::{RETRY_TOKEN_MAX_LEN, RetryTokens};
+use crate::tls::KeyLog;
+use crate::transport_stats::WorkerTransportStats;
+use crate::webtransport::{self, AppProtocol, WebTransport};
+use protocol::batch::decode_batch;
+use protocol::close::AppCloseCode;
+use protocol::control::CooldownConfig;
+use protocol::datagram;
+use protocol::nack::{self, encode_cooldown_nack};
+use protocol::pixel::{Pixel, trailer_at};
+use protocol::receipt::{MAX_ACK_LEN, encode_ack, split_seq};
+use protocol::stats::is_stats_request;
+use quiche::{Connection, PathEvent, RecvInfo};
 use rand::Rng;
 use rustc_hash::FxHashMap;
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 
-#[repr(C, packed)]
+/// QUIC transport error code CONNECTION_REFUSED (RFC 9000, section 20.1).
+const CONNECTION_REFUSED: u64 = 0x2;
+
+/// One pixel placement: x(u16) | y(u16) | color(u8), optionally followed by
+/// a u32 idempotency token and then a u32 nonce, all little-endian (the
+/// layout is protocol::pixel, shared with the client). A batch datagram
+/// (protocol::batch) carries several of these without the trailers; a
+/// PIXEL_SEQ one (protocol::receipt) puts a u16 sequence number before.
 pub struct PixelDatagram {
     pub x: u16,
     pub y: u16,
     pub color: u8,
+    /// Set by clients that may send the same placement twice; the worker
+    /// applies a placement once per token.
+    pub token: Option<u32>,
+    /// Set by clients that resume in 0-RTT; the worker applies a nonce once
+    /// per user id (admission.rs).
+    pub nonce: Option<u32>,
+    /// Set by clients that want it echoed in the pixel's receipt.
+    pub seq: Option<u16>,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct SourceConnectionId(pub Vec<u8>);
+impl PixelDatagram {
+    /// Decodes the body of a PIXEL datagram, or a batch record, rejecting
+    /// anything that isn't exactly PIXEL_DATAGRAM_SIZE bytes, with a token
+    /// or with a token and a nonce.
+    #[inline]
+    pub fn from_wire(payload: &[u8]) -> Option<Self> {
+        let (token, nonce) = match payload.len() {
+            PIXEL_DATAGRAM_SIZE => (None, None),
+            TOKENED_PIXEL_SIZE => (Some(trailer_at(payload, 5)), None),
+            NONCED_PIXEL_SIZE => (Some(trailer_at(payload, 5)), Some(trailer_at(payload, 9))),
+            _ => return None,
+        };
+        let Pixel { x, y, color } = Pixel::from_wire(payload)?;
+        Some(PixelDatagram {
+            x,
+            y,
+            color,
+            token,
+            nonce,
+            seq: None,
+        })
+    }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct DestinationConnectionId(pub Vec<u8>);
+    /// Decodes the body of a PIXEL_SEQ datagram: the sequence number, then
+    /// what `from_wire` takes.
+    #[inline]
+    pub fn from_seq_wire(body: &[u8]) -> Option<Self> {
+        let (seq, payload) = split_seq(body)?;
+        Some(Self {
+            seq: Some(seq),
+            ..Self::from_wire(payload)?
+        })
+    }
 
-pub struct TransportState {
-    // Map of QUIC Source Connection ID -> Active Connection (Thread local)
-    pub connections: FxHashMap<SourceConnectionId, (u32, Connection, DestinationConnectionId)>,
-    pub cid_map: FxHashMap<DestinationConnectionId, SourceConnectionId>,
-    pub free_user_ids: Vec<u32>,
+    /// Encodes the placement, without token or nonce.
+    pub fn to_wire(&self, out: &mut [u8; PIXEL_DATAGRAM_SIZE]) {
+        Pixel {
+            x: self.x,
+            y: self.y,
+            color: self.color,
+        }
+        .to_wire(out);
+    }
+}
 
-    // Quiche backend config
-    pub config: quiche::Config,
+const TOKENED_PIXEL_SIZE: usize = PIXEL_DATAGRAM_SIZE + PIXEL_TOKEN_SIZE;
+const NONCED_PIXEL_SIZE: usize = TOKENED_PIXEL_SIZE + PIXEL_NONCE_SIZE;
+
+/// Outcome of feeding one packet to its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    pub user_id: u32,
+    /// Where the connection is in `connections`, unless `closed`.
+    pub handle: ConnHandle,
+    /// Number of pixels written to the caller's scratch buffer.
+    pub pixels: usize,
+    /// The peer closed the connection; it has already been removed and its
+    /// user id freed or parked. Freed ids wait in `drain_released`.
+    pub closed: bool,
+    /// Datagrams still queued on the connection past `drain_cap`; they wait
+    /// for the next backlog pass (`next_backlogged`).
+    pub dgrams_left: usize,
+}
+
+/// What one `process_datagrams_internal` read.
+#[derive(Debug, Default, Clone, Copy)]
+struct Drained {
+    stats_requested: bool,
+    /// Datagrams taken off quiche's queue, and those left in it.
+    read: usize,
+    left: usize,
+}
+
+/// Why an Initial did not become a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptError {
+    /// Every user id of the worker is taken.
+    AtCapacity,
+    /// Connections are over the worker's soft memory limit.
+    OverMemory,
+    /// Admission is tightened over half-open connections (half_open.rs).
+    HalfOpen,
+    /// quiche refused to create the connection (TLS or config problem).
+    QuicError(quiche::Error),
+}
+
+/// Rate limit for accept-failure warnings: one line per
+/// ACCEPT_WARN_INTERVAL_MS, the failures in between are only counted.
+#[derive(Default)]
+struct AcceptWarnings {
+    next_ms: u64,
+    suppressed: u64,
+}
+
+impl AcceptWarnings {
+    /// Returns the number of warnings suppressed since the last logged one
+    /// if this one should be logged.
+    fn admit(&mut self, now_ms: u64) -> Option<u64> {
+        if now_ms < self.next_ms {
+            self.suppressed += 1;
+            return None;
+        }
+        self.next_ms = now_ms + ACCEPT_WARN_INTERVAL_MS;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// A connection id stored inline, so map keys never touch the heap.
+#[derive(Debug, Clone, Copy)]
+pub struct CidKey {
+    len: u8,
+    bytes: [u8; quiche::MAX_CONN_ID_LEN],
+}
+
+impl CidKey {
+    pub fn new(cid: &[u8]) -> Self {
+        let mut bytes = [0; quiche::MAX_CONN_ID_LEN];
+        bytes[..cid.len()].copy_from_slice(cid);
+        Self {
+            len: cid.len() as u8,
+            bytes,
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+// Compares and hashes exactly like its bytes, so the map can be probed with
+// a borrowed `&[u8]` straight from the packet header.
+impl PartialEq for CidKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for CidKey {}
+
+impl Hash for CidKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state);
+    }
+}
+
+impl Borrow<[u8]> for CidKey {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// Index of a live connection in `TransportState::connections`. Valid until
+/// the next removal.
+pub type ConnHandle = usize;
+
+/// DCID of a short-header packet, read without parsing the rest of the
+/// header. Short headers only ever carry an SCID we issued, and we issue
+/// them all at MAX_CONN_ID_LEN.
+#[inline(always)]
+fn short_header_dcid(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first()? & 0x80 != 0 {
+        return None;
+    }
+    buf.get(1..1 + quiche::MAX_CONN_ID_LEN)
+}
+
+/// DCID of a long-header packet and whether the packet is an Initial, read
+/// in place. quiche's `Header::from_slice` copies both connection ids and
+/// the token into fresh Vecs, several times per connection during a ramp
+/// (every Initial and Handshake packet).
+#[inline(always)]
+fn long_header_dcid(buf: &[u8]) -> Option<(&[u8], bool)> {
+    let first = *buf.first()?;
+    if first & 0x80 == 0 {
+        return None;
+    }
+    let version = u32::from_be_bytes(buf.get(1..5)?.try_into().unwrap());
+    let len = *buf.get(5)? as usize;
+    if len > quiche::MAX_CONN_ID_LEN {
+        return None;
+    }
+    let dcid = buf.get(6..6 + len)?;
+    // Version negotiation (version 0) has no packet type; for QUIC v1 the
+    // type bits of an Initial are 00.
+    Some((dcid, version != 0 && first & 0x30 == 0))
+}
+
+/// Version of a packet `long_header_dcid` accepted.
+#[inline(always)]
+fn long_header_version(buf: &[u8]) -> u32 {
+    u32::from_be_bytes(buf[1..5].try_into().unwrap())
+}
 
-    /// Scratch space for parsing pixel datagrams to avoid per-packet allocations.
-    pub pixels_scratch: Vec<PixelDatagram>,
+/// SCID of a packet `long_header_dcid` accepted, read in place like its
+/// DCID, with the offset of what follows it.
+#[inline(always)]
+fn long_header_scid(buf: &[u8]) -> Option<(&[u8], usize)> {
+    let at = 6 + *buf.get(5)? as usize;
+    let scid_len = *buf.get(at)? as usize;
+    if scid_len > quiche::MAX_CONN_ID_LEN {
+        return None;
+    }
+    let scid = buf.get(at + 1..at + 1 + scid_len)?;
+    Some((scid, at + 1 + scid_len))
 }
 
-impl Default for TransportState {
-    fn default() -> Self {
-        Self::new()
+/// SCID and token of an Initial `long_header_dcid` accepted.
+#[inline(always)]
+fn initial_scid_and_token(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (scid, at) = long_header_scid(buf)?;
+    // Token length: a QUIC variable-length integer.
+    let first = *buf.get(at)?;
+    let width = 1 << (first >> 6);
+    let mut len = (first & 0x3f) as u64;
+    for &byte in buf.get(at + 1..at + width)? {
+        len = len << 8 | byte as u64;
     }
+    let start = at + width;
+    let token = buf.get(start..start.checked_add(usize::try_from(len).ok()?)?)?;
+    Some((scid, token))
+}
+
+/// A packet answered without a connection (Retry, Version Negotiation),
+/// waiting for the worker's TX path.
+pub struct PendingResponse {
+    pub to: SocketAddr,
+    pub len: usize,
+    pub buf: [u8; RESPONSE_PACKET_MAX_LEN],
+}
+
+pub struct TransportState {
+    /// Live connections (user id, connection, the client's original DCID),
+    /// densely packed so iteration touches no empty slots.
+    pub connections: Vec<(u32, Connection, CidKey)>,
+    /// Every id a peer may address a connection by: its original DCID, the
+    /// SCID issued at accept and any spare SCIDs.
+    pub cid_map: FxHashMap<CidKey, ConnHandle>,
+    pub free_user_ids: Vec<u32>,
+    /// Ids of closed connections held for a reconnect (reservation.rs).
+    pub reservations: Reservations,
+    /// Ids put back in `free_user_ids` whose per-user state the worker has
+    /// not released yet.
+    released: Vec<u32>,
+    pub control: ControlStreams,
+    /// Per-connection state, by user id.
+    pub slots: ConnSlots,
+    /// HTTP/3 side of browser connections.
+    pub webtransport: WebTransport,
+    /// Connections refused at capacity, closed and waiting for the worker to
+    /// flush their CONNECTION_CLOSE. They never get a user id.
+    pub refused: Vec<Connection>,
+    /// Estimated connection memory; unlimited until the worker sets limits.
+    pub memory: ConnMemory,
+    /// STATS replies and their per-connection rate limit.
+    pub stats: StatsReplies,
+    /// Connections still handshaking; no deadline or threshold until the
+    /// worker sets them.
+    pub half_open: HalfOpen,
+    /// Counts traffic that reaches no connection; denies nobody until the
+    /// worker sets strict limits.
+    pub junk: JunkFilter,
+    /// Retry tokens; None (no address validation) until the worker sets
+    /// them.
+    pub retry: Option<RetryTokens>,
+    /// Stateless responses waiting for the worker to send them.
+    pub responses: Vec<PendingResponse>,
+    accept_warnings: AcceptWarnings,
+    /// Set from SSLKEYLOGFILE; every accepted connection logs its secrets
+    /// there.
+    keylog: Option<KeyLog>,
+    /// `--qlog-dir`; None (no traces) until the worker sets it.
+    pub qlog: Option<QlogSampler>,
+    /// Datagrams read off a connection at a time (`--dgram-drain-cap`).
+    pub drain_cap: usize,
+    /// Datagrams one backlog pass reads (`--dgram-drain-budget`).
+    pub drain_budget: usize,
+    /// Connections left with datagrams queued, by original DCID, for the
+    /// next backlog pass.
+    backlog: Vec<CidKey>,
+    /// Connections the last pass ran out of budget before reading; they go
+    /// first in the next one.
+    backlog_deferred: Vec<CidKey>,
+    /// The current backlog pass: each connection once, popped from the end.
+    backlog_pass: Vec<ConnHandle>,
+    /// What is left of the current pass's `drain_budget`.
+    pass_budget: usize,
+    /// When the tighter contracts `refresh_contracts` told are enforced,
+    /// so the cooldowns they change are pushed then.
+    tightening_at_ms: Option<u64>,
+    /// The broadcast interval last pushed by `refresh_interval`.
+    broadcast_interval_ms: u32,
+    /// RTTs gathered by `collect_stats`, kept to not allocate per sample.
+    stats_rtts_us: Vec<u64>,
+    metrics: &'static WorkerMetrics,
+
+    // Quiche backend config
+    pub config: quiche::Config,
 }
 
 impl TransportState {
-    pub fn new() -> Self {
+    pub fn new(cert_path: &Path, key_path: &Path, metrics: &'static WorkerMetrics) -> Self {
         let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 
-        // Load WebTransport configurations
+        // Browsers (WebTransport over HTTP/3) and raw clients.
         config
-            .set_application_protos(quiche::h3::APPLICATION_PROTOCOL)
+            .set_application_protos(&webtransport::APPLICATION_PROTOS)
             .unwrap();
 
         config.set_initial_max_data(QUIC_INITIAL_MAX_DATA);
@@ -55,29 +374,75 @@ impl TransportState {
         config.set_initial_max_stream_data_uni(QUIC_INITIAL_MAX_STREAM_DATA_UNI);
         config.set_initial_max_streams_bidi(QUIC_INITIAL_MAX_STREAMS_BIDI);
         config.set_initial_max_streams_uni(QUIC_INITIAL_MAX_STREAMS_UNI);
-        config.set_disable_active_migration(true);
+        // Without it a dead client's connection only goes once quiche gives
+        // up on its loss timers, if ever (`--idle-timeout-ms`).
+        config.set_max_idle_timeout(QUIC_MAX_IDLE_TIMEOUT_MS);
+        // Peers may move to a new address (Wi-Fi to cellular); quiche
+        // validates the new path before switching to it. Caveat: the
+        // SO_REUSEPORT group hashes on the 4-tuple, so with several workers
+        // the migrated packets usually land on a worker that does not know
+        // the connection and are dropped.
+        config.set_disable_active_migration(false);
 
         // Required for WebTransport / Datagrams
         config.enable_dgram(true, QUIC_DGRAM_QUEUE_LEN, QUIC_DGRAM_QUEUE_LEN);
 
-        // NOTE: certs created in main.rs
-        config.load_cert_chain_from_pem_file("cert.crt").unwrap();
-        config.load_priv_key_from_pem_file("key.key").unwrap();
+        // Session tickets, so a reconnecting client skips the certificate
+        // signature, and 0-RTT, so its first pixels ride along with the
+        // Initial. All workers share the ticket key (tls::ticket_key).
+        config.set_ticket_key(crate::tls::ticket_key()).unwrap();
+        config.enable_early_data();
+
+        let keylog = KeyLog::from_env();
+        if keylog.is_some() {
+            config.log_keys();
+        }
+
+        // Provisioned once by main.rs (tls::ensure_certificates) before any
+        // worker starts; workers only ever read them.
+        config
+            .load_cert_chain_from_pem_file(cert_path.to_str().unwrap())
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file(key_path.to_str().unwrap())
+            .unwrap();
 
         let free_user_ids: Vec<u32> = (0..MAX_CONNECTIONS_PER_WORKER as u32).collect();
 
         Self {
-            connections: FxHashMap::with_capacity_and_hasher(
-                MAX_CONNECTIONS_PER_WORKER,
-                Default::default(),
-            ),
+            connections: Vec::with_capacity(MAX_CONNECTIONS_PER_WORKER),
+            // Original DCID, accept SCID and one spare per connection.
             cid_map: FxHashMap::with_capacity_and_hasher(
-                MAX_CONNECTIONS_PER_WORKER,
+                3 * MAX_CONNECTIONS_PER_WORKER,
                 Default::default(),
             ),
             free_user_ids,
+            reservations: Reservations::new(metrics),
+            released: Vec::new(),
+            control: ControlStreams::new(),
+            slots: ConnSlots::new(),
+            webtransport: WebTransport::new(),
+            refused: Vec::with_capacity(MAX_PENDING_REFUSALS),
+            memory: ConnMemory::new(MemoryLimits::default(), metrics),
+            stats: StatsReplies::new(metrics),
+            half_open: HalfOpen::new(HalfOpenLimits::OFF, metrics),
+            junk: JunkFilter::new(JunkLimits::default(), metrics),
+            retry: None,
+            responses: Vec::with_capacity(MAX_PENDING_RESPONSES),
+            accept_warnings: AcceptWarnings::default(),
+            keylog,
+            qlog: None,
+            drain_cap: MAX_DGRAMS_PER_RECV,
+            drain_budget: DGRAM_DRAIN_BUDGET,
+            backlog: Vec::new(),
+            backlog_deferred: Vec::new(),
+            tightening_at_ms: None,
+            broadcast_interval_ms: BROADCAST_INTERVAL_MS as u32,
+            backlog_pass: Vec::new(),
+            pass_budget: 0,
+            stats_rtts_us: Vec::new(),
+            metrics,
             config,
-            pixels_scratch: Vec::with_capacity(128), // Plenty for any single QUIC packet
         }
     }
 
@@ -88,20 +453,55 @@ impl TransportState {
         odcid: Option<&[u8]>,
         local: SocketAddr,
         peer: SocketAddr,
-    ) -> Result<(), quiche::Error> {
-        if self.free_user_ids.is_empty() {
-            {
-                #[cfg(feature = "debug-logs")]
-                println!("Worker at capacity, rejecting connection from {:?}", peer);
-            }
-            return Err(quiche::Error::Done);
+    ) -> Result<ConnHandle, AcceptError> {
+        if !self.memory.admits() {
+            return Err(AcceptError::OverMemory);
+        }
+        if !self.half_open.admits(peer.ip()) {
+            return Err(AcceptError::HalfOpen);
         }
-
         let scid_val = quiche::ConnectionId::from_ref(scid);
         let odcid_val = odcid.map(quiche::ConnectionId::from_ref);
-        let conn = quiche::accept(&scid_val, odcid_val.as_ref(), local, peer, &mut self.config)?;
-
-        let user_id = self.free_user_ids.pop().unwrap();
+        let key = ResumeKey::Peer(peer.ip());
+        // A client back from the same address within the grace period gets
+        // its parked id; otherwise parked ids make room once nothing else
+        // is free.
+        let reclaimed = self.reservations.claim(&key);
+        let (free, released, slots) =
+            (&mut self.free_user_ids, &mut self.released, &mut self.slots);
+        if let Some(parked) = reclaimed {
+            free.push(parked);
+        } else if free.is_empty() {
+            self.reservations.evict_oldest(|evicted| {
+                free.push(evicted);
+                released.push(evicted);
+                slots.released(evicted, false);
+            });
+        }
+        let config = &mut self.config;
+        let claimed = claim_user_id(free, || {
+            quiche::accept(&scid_val, odcid_val.as_ref(), local, peer, config)
+        });
+        let (user_id, mut conn) = match claimed {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                // Back in the pool: its old cooldown must not follow it to
+                // the next owner.
+                if let Some(parked) = reclaimed {
+                    self.reservations.unbind(parked);
+                    released.push(parked);
+                    slots.released(parked, false);
+                }
+                return Err(e);
+            }
+        };
+        self.reservations.bind(user_id, key);
+        if let Some(keylog) = &self.keylog {
+            conn.set_keylog(Box::new(keylog.clone()));
+        }
+        if let Some(qlog) = &mut self.qlog {
+            qlog.trace(&mut conn, scid);
+        }
 
         #[cfg(feature = "debug-logs")]
         println!(
@@ -109,127 +509,2915 @@ impl TransportState {
             scid_val, user_id
         );
 
-        self.connections.insert(
-            SourceConnectionId(scid.to_vec()),
-            (user_id, conn, DestinationConnectionId(dcid.to_vec())),
-        );
-        Ok(())
+        let handle = self.connections.len();
+        let dcid = CidKey::new(dcid);
+        self.cid_map.insert(dcid, handle);
+        self.cid_map.insert(CidKey::new(scid), handle);
+        self.connections.push((user_id, conn, dcid));
+        self.slots.accepted(user_id);
+        self.memory.accepted();
+        self.half_open
+            .accepted(user_id, peer.ip(), crate::time::CLOCK.now_ms());
+        Ok(handle)
+    }
+
+    /// A random SCID that routes to no connection yet, so a new one never
+    /// takes over another's packets.
+    fn fresh_scid(
+        cid_map: &FxHashMap<CidKey, ConnHandle>,
+        rng: &mut impl Rng,
+    ) -> [u8; quiche::MAX_CONN_ID_LEN] {
+        loop {
+            let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+            rng.fill(&mut scid);
+            if !cid_map.contains_key(&scid[..]) {
+                return scid;
+            }
+        }
     }
 
+    /// Maps a header DCID to its connection. One probe, no allocation.
+    #[inline(always)]
     fn resolve_connection_id(
+        cid_map: &FxHashMap<CidKey, ConnHandle>,
+        dcid: &[u8],
+    ) -> Option<ConnHandle> {
+        cid_map.get(dcid).copied()
+    }
+
+    /// Accepts a new connection for an Initial packet with an unknown DCID.
+    /// Only this path allocates connection ids. After a Retry, `odcid` is
+    /// the DCID of the client's first Initial, and the connection keeps the
+    /// SCID the Retry gave out (`dcid`).
+    fn accept_initial(
         &mut self,
         dcid: &[u8],
-        ty: quiche::Type,
+        odcid: Option<&[u8]>,
         local: SocketAddr,
         peer: SocketAddr,
-    ) -> Option<SourceConnectionId> {
-        let process_id = self
-            .cid_map
-            .get(&DestinationConnectionId(dcid.to_vec()))
-            .map_or_else(|| SourceConnectionId(dcid.to_vec()), |id| id.clone());
+    ) -> Result<ConnHandle, AcceptError> {
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        if odcid.is_some() {
+            scid.copy_from_slice(dcid);
+        } else {
+            scid = Self::fresh_scid(&self.cid_map, &mut rand::thread_rng());
+        }
 
-        if self.connections.contains_key(&process_id) {
-            return Some(process_id);
+        match self.accept_connection(&scid[..], dcid, odcid, local, peer) {
+            Ok(handle) => {
+                self.metrics
+                    .connections_accepted
+                    .fetch_add(1, Ordering::Relaxed);
+                Ok(handle)
+            }
+            Err(e @ AcceptError::AtCapacity) => {
+                self.metrics
+                    .accept_capacity_rejections
+                    .fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "debug-logs")]
+                println!("Worker at capacity, rejecting connection from {:?}", peer);
+                Err(e)
+            }
+            Err(e @ AcceptError::OverMemory) => {
+                self.metrics
+                    .memory_rejections
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+            Err(e @ AcceptError::HalfOpen) => {
+                self.metrics
+                    .half_open_rejections
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+            Err(e @ AcceptError::QuicError(err)) => {
+                self.metrics.accept_errors.fetch_add(1, Ordering::Relaxed);
+                if let Some(suppressed) = self.accept_warnings.admit(crate::time::CLOCK.now_ms()) {
+                    println!(
+                        "Warning: failed to accept connection from {}: {:?} ({} more since last warning)",
+                        peer, err, suppressed
+                    );
+                }
+                Err(e)
+            }
         }
+    }
 
-        if ty != quiche::Type::Initial {
+    /// With `--quic-retry`, whether an Initial with an unknown DCID may
+    /// start a connection: Some with the original DCID its token vouches
+    /// for, or None if it was answered with a Retry or dropped. Neither
+    /// costs a user id.
+    fn validate_address<'a>(
+        &mut self,
+        buf: &'a [u8],
+        dcid: &[u8],
+        peer: SocketAddr,
+    ) -> Option<&'a [u8]> {
+        let tokens = self.retry.as_ref()?;
+        let (scid, token) = initial_scid_and_token(buf)?;
+        let now_ms = crate::time::CLOCK.now_ms();
+        if token.is_empty() {
+            self.send_retry(long_header_version(buf), scid, dcid, peer, now_ms);
             return None;
         }
+        // Retry SCIDs are all MAX_CONN_ID_LEN; the token binds the one
+        // this client was given.
+        let odcid = (dcid.len() == quiche::MAX_CONN_ID_LEN)
+            .then(|| tokens.validate(token, peer, dcid, now_ms))
+            .flatten();
+        if odcid.is_none() {
+            self.metrics
+                .retry_token_rejections
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        odcid
+    }
 
-        // else new connection has arrived, accept it
-        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
-        rand::thread_rng().fill(&mut scid);
+    /// Queues a Retry for a token-less Initial from `peer` (`scid` to
+    /// `dcid`).
+    fn send_retry(
+        &mut self,
+        version: u32,
+        scid: &[u8],
+        dcid: &[u8],
+        peer: SocketAddr,
+        now_ms: u64,
+    ) {
+        let Some(tokens) = &self.retry else {
+            return;
+        };
+        let mut retry_scid = [0; quiche::MAX_CONN_ID_LEN];
+        rand::thread_rng().fill(&mut retry_scid);
+        let mut token = [0; RETRY_TOKEN_MAX_LEN];
+        let token_len = tokens.mint(peer, dcid, &retry_scid, now_ms, &mut token);
 
-        match self.accept_connection(&scid[..], dcid, None, local, peer) {
-            Ok(_) => {
-                let source_cid = SourceConnectionId(scid.to_vec());
-                self.cid_map
-                    .insert(DestinationConnectionId(dcid.to_vec()), source_cid.clone());
-                Some(source_cid)
-            }
-            Err(_e) => {
-                #[cfg(feature = "debug-logs")]
-                println!("Failed to accept connection: {:?}", _e);
-                None
-            }
+        let queued = self.queue_response(peer, |out| {
+            quiche::retry(
+                &quiche::ConnectionId::from_ref(scid),
+                &quiche::ConnectionId::from_ref(dcid),
+                &quiche::ConnectionId::from_ref(&retry_scid),
+                &token[..token_len],
+                version,
+                out,
+            )
+        });
+        if queued {
+            self.metrics.retries_sent.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    fn process_datagrams_internal(conn: &mut Connection, scratch: &mut Vec<PixelDatagram>) {
-        scratch.clear();
-        if !conn.is_established() {
+    /// Answers a long-header packet of a version quiche does not speak with
+    /// the versions it does, so the client can pick one instead of timing
+    /// out. Only for a datagram big enough to be a client's first (RFC 9000
+    /// section 6.1): anything smaller would make the answer an amplifier.
+    fn negotiate_version(&mut self, buf: &[u8], peer: SocketAddr) {
+        if buf.len() < quiche::MIN_CLIENT_INITIAL_LEN {
+            return;
+        }
+        let (Some((dcid, _)), Some((scid, _))) = (long_header_dcid(buf), long_header_scid(buf))
+        else {
+            return;
+        };
+        let queued = self.queue_response(peer, |out| {
+            quiche::negotiate_version(
+                &quiche::ConnectionId::from_ref(scid),
+                &quiche::ConnectionId::from_ref(dcid),
+                out,
+            )
+        });
+        if queued {
+            self.metrics
+                .version_negotiations
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Queues what `write` puts in a fresh buffer for `peer`. Beyond
+    /// MAX_PENDING_RESPONSES, or if `write` fails, nothing is sent.
+    fn queue_response(
+        &mut self,
+        peer: SocketAddr,
+        write: impl FnOnce(&mut [u8]) -> quiche::Result<usize>,
+    ) -> bool {
+        if self.responses.len() >= MAX_PENDING_RESPONSES {
+            return false;
+        }
+        let mut response = PendingResponse {
+            to: peer,
+            len: 0,
+            buf: [0; RESPONSE_PACKET_MAX_LEN],
+        };
+        let Ok(len) = write(&mut response.buf) else {
+            return false;
+        };
+        response.len = len;
+        self.responses.push(response);
+        true
+    }
+
+    /// Answers an Initial the worker has no user id for with
+    /// CONNECTION_CLOSE.
+    /// Application close codes only go out once the handshake is complete,
+    /// so the refusal is a transport CONNECTION_REFUSED carrying the
+    /// capacity reason (`AppCloseCode::Capacity`). The connection is parked
+    /// in `refused` until the worker flushes it.
+    fn refuse(&mut self, buf: &mut [u8], local: SocketAddr, peer: SocketAddr) {
+        if self.refused.len() >= MAX_PENDING_REFUSALS {
+            return;
+        }
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        rand::thread_rng().fill(&mut scid);
+        let scid = quiche::ConnectionId::from_ref(&scid);
+        let Ok(mut conn) = quiche::accept(&scid, None, local, peer, &mut self.config) else {
             return;
+        };
+        let _ = conn.recv(
+            buf,
+            RecvInfo {
+                from: peer,
+                to: local,
+            },
+        );
+        let _ = conn.close(false, CONNECTION_REFUSED, AppCloseCode::Capacity.reason());
+        self.refused.push(conn);
+    }
+
+    /// Reads up to `cap` datagrams from quiche's queue into `out`, so one
+    /// connection with hundreds queued cannot hold up every
+    /// other connection's packets; the rest stay queued. Datagrams are taken
+    /// by value (`dgram_recv_vec`) and parsed in place instead of being
+    /// copied into an intermediate MTU-sized buffer first.
+    /// Returns whether a STATS_REQUEST came in among them, and how many
+    /// datagrams are left. Each is dispatched on its type byte
+    /// (protocol::datagram); unknown types and bodies that do not parse are
+    /// dropped and counted, never read as pixels.
+    ///
+    /// A resumed connection's datagrams are read before the handshake
+    /// completes, while it is in early data (0-RTT). 0-RTT can be replayed;
+    /// a replayed pixel is held to the same cooldown as any other.
+    fn process_datagrams_internal(
+        conn: &mut Connection,
+        protocol: AppProtocol,
+        metrics: &WorkerMetrics,
+        cap: usize,
+        out: &mut Vec<PixelDatagram>,
+    ) -> Drained {
+        let mut drained = Drained::default();
+        if !conn.is_established() && !conn.is_in_early_data() {
+            return drained;
         }
 
-        let mut dgram_buf = [0; DGRAM_MAX_SEND_SIZE];
-        while let Ok(len) = conn.dgram_recv(&mut dgram_buf) {
-            if len == std::mem::size_of::<PixelDatagram>() {
-                scratch.push(PixelDatagram {
-                    x: u16::from_ne_bytes([dgram_buf[0], dgram_buf[1]]),
-                    y: u16::from_ne_bytes([dgram_buf[2], dgram_buf[3]]),
-                    color: dgram_buf[4],
-                });
-            } else {
-                #[cfg(feature = "debug-logs")]
-                println!(
-                    "Received datagram of incorrect size: {} (expected {})",
-                    len,
-                    std::mem::size_of::<PixelDatagram>()
-                );
+        for _ in 0..cap {
+            let Ok(dgram) = conn.dgram_recv_vec() else {
+                break;
+            };
+            drained.read += 1;
+            let Some(dgram) = protocol.unframe(&dgram) else {
+                continue;
+            };
+            match datagram::split(dgram) {
+                // `out` is sized for a full share of full batches, so this
+                // never reallocates; anything beyond is dropped like an
+                // oversized dgram.
+                Some((datagram::PIXEL, body)) => match PixelDatagram::from_wire(body) {
+                    Some(pixel) if out.len() < out.capacity() => out.push(pixel),
+                    Some(_) => {}
+                    None => Self::malformed_datagram(metrics, dgram),
+                },
+                Some((datagram::PIXEL_SEQ, body)) => match PixelDatagram::from_seq_wire(body) {
+                    Some(pixel) if out.len() < out.capacity() => out.push(pixel),
+                    Some(_) => {}
+                    None => Self::malformed_datagram(metrics, dgram),
+                },
+                // Each pixel of a batch is admitted on its own by the worker,
+                // so a batch gets no more past the cooldown than singles.
+                Some((datagram::PIXEL_BATCH, _)) => match decode_batch(dgram) {
+                    Some(records) => {
+                        let room = out.capacity() - out.len();
+                        out.extend(records.take(room).filter_map(PixelDatagram::from_wire));
+                    }
+                    None => Self::malformed_datagram(metrics, dgram),
+                },
+                Some((datagram::TAGGED, _)) if is_stats_request(dgram) => {
+                    drained.stats_requested = true
+                }
+                // A type, or a tag, from a newer client.
+                Some(_) => {
+                    metrics.unknown_datagrams.fetch_add(1, Ordering::Relaxed);
+                }
+                None => Self::malformed_datagram(metrics, dgram),
             }
         }
+        drained.left = conn.dgram_recv_queue_len();
+        drained
+    }
+
+    #[cold]
+    fn malformed_datagram(metrics: &WorkerMetrics, _dgram: &[u8]) {
+        #[cfg(feature = "debug-logs")]
+        println!(
+            "Received malformed datagram: {} bytes, type {:?}",
+            _dgram.len(),
+            _dgram.first()
+        );
+        metrics.malformed_datagrams.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Feeds one UDP payload to its connection and appends the decoded pixels
+    /// to the caller-owned `out` (cleared first). Returns `None` if the packet
+    /// neither carried pixels nor closed the connection.
     pub fn handle_incoming(
         &mut self,
         buf: &mut [u8],
         peer: SocketAddr,
         local: SocketAddr,
-    ) -> Option<(u32, &[PixelDatagram])> {
-        let hdr = quiche::Header::from_slice(buf, quiche::MAX_CONN_ID_LEN).ok()?;
-
-        let process_id = self.resolve_connection_id(&hdr.dcid[..], hdr.ty, local, peer)?;
-
-        let tuple = self.connections.get_mut(&process_id)?;
-        let user_id = tuple.0;
-        let conn = &mut tuple.1;
-        let scratch = &mut self.pixels_scratch;
+        out: &mut Vec<PixelDatagram>,
+    ) -> Option<Received> {
+        out.clear();
+        // First thing after Framing::parse: denied sources cost one lookup.
+        if self.junk.denies(peer.ip(), crate::time::CLOCK.now_ms()) {
+            return None;
+        }
+        // Established connections send short headers; long headers (the
+        // handshake) take a slightly longer in-place parse.
+        let known = short_header_dcid(buf)
+            .and_then(|dcid| Self::resolve_connection_id(&self.cid_map, dcid));
+        let handle = match known {
+            Some(handle) => handle,
+            None => {
+                let Some((dcid, initial)) = long_header_dcid(buf) else {
+                    return self.drop_junk(buf, peer);
+                };
+                match Self::resolve_connection_id(&self.cid_map, dcid) {
+                    // Another Initial to the DCID a connection was accepted
+                    // under: a retransmission, or the rest of a ClientHello
+                    // too big for one packet. Routed, never accepted again.
+                    Some(handle) => {
+                        if initial && self.connections[handle].2.as_slice() == dcid {
+                            self.metrics
+                                .duplicate_initials
+                                .fetch_add(1, Ordering::Relaxed);
+                        }
+                        handle
+                    }
+                    // Only Initials of a version quiche speaks may start a
+                    // connection; anything else would hold a user id until
+                    // the handshake deadline. Other versions (but never a
+                    // Version Negotiation, version 0) hear which ones it
+                    // does speak.
+                    None if !initial || !quiche::version_is_supported(long_header_version(buf)) => {
+                        let version = long_header_version(buf);
+                        if version != 0 && !quiche::version_is_supported(version) {
+                            self.negotiate_version(buf, peer);
+                        }
+                        return self.drop_junk(buf, peer);
+                    }
+                    None => {
+                        // With --quic-retry, only once the peer has proven
+                        // its address.
+                        let odcid = match self.retry {
+                            Some(_) => Some(self.validate_address(buf, dcid, peer)?),
+                            None => None,
+                        };
+                        match self.accept_initial(dcid, odcid, local, peer) {
+                            Ok(handle) => handle,
+                            Err(AcceptError::AtCapacity) => {
+                                self.refuse(buf, local, peer);
+                                return None;
+                            }
+                            // Refusing takes a connection and a TLS flight,
+                            // what these limits are there to save. The
+                            // client's Initial comes again on its own.
+                            Err(
+                                AcceptError::OverMemory
+                                | AcceptError::HalfOpen
+                                | AcceptError::QuicError(_),
+                            ) => return None,
+                        }
+                    }
+                }
+            }
+        };
+        let (user_id, conn, dcid) = &mut self.connections[handle];
 
         let recv_info = RecvInfo {
             from: peer,
             to: local,
         };
-        let _ = conn.recv(buf, recv_info);
+        if conn.is_established() {
+            let _ = conn.recv(buf, recv_info);
+        } else {
+            Self::handshake_recv(self.metrics, conn, buf, recv_info);
+        }
+
+        let established = conn.is_established();
+        let now_ms = crate::time::CLOCK.now_ms();
+        if established {
+            if self.slots.get(*user_id).state() == SlotState::Handshaking {
+                let handshakes = if conn.is_resumed() {
+                    &self.metrics.handshakes_resumed
+                } else {
+                    &self.metrics.handshakes_full
+                };
+                handshakes.fetch_add(1, Ordering::Relaxed);
+            }
+            self.slots
+                .established(*user_id, now_ms, || AppProtocol::of(conn));
+            self.half_open.established(*user_id, now_ms);
+            if let AppProtocol::WebTransport(_) = self.slots.get(*user_id).protocol() {
+                self.webtransport.service(*user_id, conn, &mut self.slots);
+            }
+        }
+        // The slot learns the protocol at establishment; before that only
+        // early data gets this far.
+        let protocol = if established {
+            self.slots.get(*user_id).protocol()
+        } else {
+            AppProtocol::of(conn)
+        };
+        let drained =
+            Self::process_datagrams_internal(conn, protocol, self.metrics, self.drain_cap, out);
+        if drained.left > 0 {
+            self.backlog.push(*dcid);
+        }
+        if !established {
+            self.metrics
+                .early_data_pixels
+                .fetch_add(out.len() as u64, Ordering::Relaxed);
+        }
+        if established {
+            if drained.stats_requested
+                && let Some(reply) = self.stats.reply(self.slots.get_mut(*user_id), now_ms)
+            {
+                // Dropped like any broadcast datagram if the queue is full.
+                let _ = protocol.dgram_send(conn, &reply, &mut Vec::new());
+            }
+            if protocol == AppProtocol::Raw
+                && let Some(parked) = self.control.service(
+                    *user_id,
+                    *dcid,
+                    conn,
+                    &mut self.reservations,
+                    &mut self.slots,
+                )
+            {
+                let previous = std::mem::replace(user_id, parked);
+                self.reservations.unbind(previous);
+                self.free_user_ids.push(previous);
+                self.released.push(previous);
+            }
+            Self::update_connection_ids(&mut self.cid_map, handle, conn);
+            while let Some(event) = conn.path_event_next() {
+                Self::on_path_event(self.metrics, conn, event);
+            }
+        }
 
-        Self::process_datagrams_internal(conn, scratch);
+        // A peer CONNECTION_CLOSE puts the connection into draining; nothing
+        // more will be sent on it, so free the slot now rather than waiting
+        // for the throttled sweep to see is_closed() after the drain timeout.
+        let user_id = *user_id;
+        let closed = conn.is_draining() || conn.is_closed();
+        if closed {
+            self.remove_connection(handle);
+        }
 
-        if scratch.is_empty() {
+        if out.is_empty() && !closed {
             None
         } else {
             #[cfg(feature = "debug-logs")]
-            println!("Received {} pixels from {:?}", scratch.len(), peer);
-            Some((user_id, scratch))
+            println!(
+                "Received {} pixels from {:?} (closed: {})",
+                out.len(),
+                peer,
+                closed
+            );
+            Some(Received {
+                user_id,
+                handle,
+                pixels: out.len(),
+                closed,
+                dgrams_left: drained.left,
+            })
         }
     }
 
-    pub fn cleanup_connections(&mut self) {
-        let mut freed_ids = Vec::new();
-        let mut freed_dcids = Vec::new();
+    /// Whether connections were left with datagrams queued, so the worker
+    /// should run a backlog pass without waiting for another packet.
+    pub fn has_backlog(&self) -> bool {
+        !self.backlog.is_empty() || !self.backlog_deferred.is_empty()
+    }
+
+    /// Starts a backlog pass over the connections left with datagrams
+    /// queued since the last one, each once however many packets left it
+    /// behind. The ones the last pass deferred come first.
+    pub fn start_backlog_pass(&mut self) {
+        let cid_map = &self.cid_map;
+        let resolve = |dcid: CidKey| Self::resolve_connection_id(cid_map, dcid.as_slice());
+        self.backlog_pass.clear();
+        self.backlog_pass
+            .extend(self.backlog.drain(..).filter_map(resolve));
+        self.backlog_pass.sort_unstable();
+        self.backlog_pass.dedup();
+        if !self.backlog_deferred.is_empty() {
+            let split = self.backlog_pass.len();
+            self.backlog_pass
+                .extend(self.backlog_deferred.drain(..).filter_map(resolve));
+            // Each connection once: drop the fresh entries that were also
+            // deferred.
+            let (fresh, deferred) = self.backlog_pass.split_at_mut(split);
+            deferred.sort_unstable();
+            fresh.sort_unstable_by_key(|h| deferred.binary_search(h).is_ok());
+            let keep = fresh.partition_point(|h| deferred.binary_search(h).is_err());
+            self.backlog_pass.drain(keep..split);
+        }
+        self.pass_budget = self.drain_budget;
+        self.metrics
+            .dgram_backlog_connections
+            .store(self.backlog_pass.len() as u64, Ordering::Relaxed);
+    }
 
-        self.connections.retain(|_, (id, conn, dcid)| {
-            if conn.is_closed() {
-                freed_ids.push(*id);
-                freed_dcids.push(dcid.clone());
-                false
+    /// Reads the next `drain_cap` datagrams of a connection in the current
+    /// backlog pass into `out` (cleared first), like `handle_incoming` does
+    /// for a packet; a connection with more left goes into the next pass.
+    /// None once the pass is over, or once it has read its `drain_budget`:
+    /// the connections it did not get to are deferred to the next pass.
+    /// Returns the connection's peer address with what it read.
+    pub fn next_backlogged(
+        &mut self,
+        out: &mut Vec<PixelDatagram>,
+    ) -> Option<(Received, SocketAddr)> {
+        out.clear();
+        loop {
+            if self.pass_budget == 0 {
+                self.defer_backlog_pass();
+                return None;
+            }
+            let handle = self.backlog_pass.pop()?;
+            let (user_id, conn, dcid) = &mut self.connections[handle];
+            if conn.is_draining() || conn.is_closed() {
+                continue;
+            }
+            let established = conn.is_established();
+            let protocol = if established {
+                self.slots.get(*user_id).protocol()
             } else {
-                true
+                AppProtocol::of(conn)
+            };
+            let drained =
+                Self::process_datagrams_internal(conn, protocol, self.metrics, self.drain_cap, out);
+            self.pass_budget = self.pass_budget.saturating_sub(drained.read);
+            if drained.left > 0 {
+                self.backlog.push(*dcid);
             }
-        });
+            if !established {
+                self.metrics
+                    .early_data_pixels
+                    .fetch_add(out.len() as u64, Ordering::Relaxed);
+            }
+            let now_ms = crate::time::CLOCK.now_ms();
+            if established
+                && drained.stats_requested
+                && let Some(reply) = self.stats.reply(self.slots.get_mut(*user_id), now_ms)
+            {
+                let _ = protocol.dgram_send(conn, &reply, &mut Vec::new());
+            }
+            let Some(peer) = conn.path_stats().find(|p| p.active).map(|p| p.peer_addr) else {
+                continue;
+            };
+            let received = Received {
+                user_id: *user_id,
+                handle,
+                pixels: out.len(),
+                closed: false,
+                dgrams_left: drained.left,
+            };
+            return Some((received, peer));
+        }
+    }
+
+    /// Moves what is left of a backlog pass that ran out of budget to the
+    /// front of the next one, by DCID: handles do not outlive a removal.
+    #[cold]
+    fn defer_backlog_pass(&mut self) {
+        self.metrics
+            .dgram_backlog_deferred
+            .fetch_add(self.backlog_pass.len() as u64, Ordering::Relaxed);
+        let connections = &self.connections;
+        self.backlog_deferred
+            .extend(self.backlog_pass.drain(..).map(|h| connections[h].2));
+    }
+
+    /// `conn.recv` for a connection still handshaking, timed: the CPU a
+    /// handshake costs, full (certificate signature) or resumed from a
+    /// session ticket. Handshakes that never complete count their time too.
+    #[cold]
+    fn handshake_recv(
+        metrics: &WorkerMetrics,
+        conn: &mut Connection,
+        buf: &mut [u8],
+        recv_info: RecvInfo,
+    ) {
+        let start = std::time::Instant::now();
+        let _ = conn.recv(buf, recv_info);
+        let elapsed_ns = start.elapsed().as_nanos() as u64;
+        let spent = if conn.is_resumed() {
+            &metrics.handshake_recv_resumed_ns
+        } else {
+            &metrics.handshake_recv_full_ns
+        };
+        spent.fetch_add(elapsed_ns, Ordering::Relaxed);
+    }
+
+    /// Counts a packet that reached no connection and started none.
+    #[cold]
+    fn drop_junk(&mut self, buf: &[u8], peer: SocketAddr) -> Option<Received> {
+        self.junk
+            .record(peer.ip(), junk::classify(buf), crate::time::CLOCK.now_ms());
+        None
+    }
+
+    /// Keeps one spare SCID issued to the peer, so it has one to move to
+    /// when it migrates, and routes every issued SCID to the connection.
+    /// Retired ones stop routing.
+    fn update_connection_ids(
+        cid_map: &mut FxHashMap<CidKey, ConnHandle>,
+        handle: ConnHandle,
+        conn: &mut Connection,
+    ) {
+        while let Some(retired) = conn.retired_scid_next() {
+            cid_map.remove(&retired[..]);
+        }
+        if conn.scids_left() == 0 {
+            return;
+        }
+        let mut rng = rand::thread_rng();
+        let scid = Self::fresh_scid(cid_map, &mut rng);
+        let cid = quiche::ConnectionId::from_ref(&scid);
+        if conn.new_scid(&cid, rng.r#gen(), false).is_ok() {
+            cid_map.insert(CidKey::new(&scid), handle);
+        }
+    }
+
+    /// quiche already switches the active path once a migrated peer's new
+    /// path is validated; this only probes new paths and counts outcomes.
+    /// Sends follow the path through `SendInfo::to` (see `CachedDest`).
+    fn on_path_event(metrics: &WorkerMetrics, conn: &mut Connection, event: PathEvent) {
+        match event {
+            PathEvent::New(local, peer) => {
+                let _ = conn.probe_path(local, peer);
+            }
+            PathEvent::PeerMigrated(_local, _peer) => {
+                metrics.migrations.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "debug-logs")]
+                println!("Peer migrated to {:?}", _peer);
+            }
+            PathEvent::FailedValidation(_local, _peer) => {
+                metrics.migration_failures.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "debug-logs")]
+                println!("Path validation to {:?} failed", _peer);
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops a connection and returns its user id to the free list, or parks
+    /// it if the connection was established and parking is on. The last
+    /// connection moves into the vacated slot and its ids are pointed there.
+    fn remove_connection(&mut self, handle: ConnHandle) -> u32 {
+        let (user_id, mut conn, dcid) = self.connections.swap_remove(handle);
+        crate::qlog::finish(&mut conn);
+        self.cid_map.remove(dcid.as_slice());
+        for alias in conn.source_ids() {
+            self.cid_map.remove(&alias[..]);
+        }
+        if let Some((_, moved, moved_dcid)) = self.connections.get(handle) {
+            let ids =
+                std::iter::once(moved_dcid.as_slice()).chain(moved.source_ids().map(|c| &c[..]));
+            for id in ids {
+                if let Some(h) = self.cid_map.get_mut(id) {
+                    *h = handle;
+                }
+            }
+        }
+        self.control.forget(user_id);
+        self.webtransport.forget(user_id);
+        let (free, released, slots) =
+            (&mut self.free_user_ids, &mut self.released, &mut self.slots);
+        let now_ms = crate::time::CLOCK.now_ms();
+        let parked = conn.is_established()
+            && self.reservations.park(user_id, now_ms, |evicted| {
+                free.push(evicted);
+                released.push(evicted);
+                slots.released(evicted, false);
+            });
+        slots.released(user_id, parked);
+        if !parked {
+            self.reservations.unbind(user_id);
+            free.push(user_id);
+            released.push(user_id);
+        }
+        self.memory.removed();
+        self.half_open.removed(user_id);
+        user_id
+    }
+
+    /// Calls `on_free` with every user id freed since the last call, so the
+    /// caller can release the state it keeps per user (cooldown, pixel
+    /// tokens) before the id's next owner shows up.
+    #[inline]
+    pub fn drain_released(&mut self, mut on_free: impl FnMut(u32)) {
+        for user_id in self.released.drain(..) {
+            on_free(user_id);
+        }
+    }
 
-        for dcid in freed_dcids {
-            self.cid_map.remove(&dcid);
+    /// Answers the COOLDOWN_CONFIG requests and sends the pushes the
+    /// control streams set aside (control.rs), oldest first, with the
+    /// cooldown each connection's contract enforces at `now_ms`;
+    /// `remaining_ms` reads a user's cooldown from the worker's timing
+    /// wheel. A connection gone in the meantime gets none.
+    pub fn answer_cooldown_queries(
+        &mut self,
+        now_ms: u64,
+        mut remaining_ms: impl FnMut(u32) -> u32,
+    ) {
+        while let Some((dcid, stream_id)) = self.control.next_cooldown_query() {
+            let Some(handle) = Self::resolve_connection_id(&self.cid_map, dcid.as_slice()) else {
+                continue;
+            };
+            let (user_id, conn, _) = &mut self.connections[handle];
+            let user_id = *user_id;
+            let cooldown_secs = self.slots.get_mut(user_id).contract.enforced_secs(now_ms);
+            let config = CooldownConfig {
+                cooldown_ms: cooldown_secs * 1000,
+                multiplier_permille: self.slots.terms.multiplier_permille(cooldown_secs),
+                remaining_ms: remaining_ms(user_id),
+            };
+            self.control
+                .answer_cooldown(user_id, conn, stream_id, &config, &mut self.slots);
+        }
+    }
+
+    /// Pushes the cooldown to every connection that takes it, e.g. once a
+    /// new epoch has lifted them all.
+    pub fn push_cooldowns(&mut self) {
+        for (user_id, _, dcid) in &self.connections {
+            if self.slots.get(*user_id).takes_cooldowns() {
+                self.control.push_cooldown(*dcid);
+            }
+        }
+    }
+
+    /// Tells the connection at `handle` that a pixel it sent was turned
+    /// away for cooldown, with `remaining_ms` of it left (protocol::nack).
+    /// One NACK per COOLDOWN_NACK_INTERVAL_MS per connection at most, so
+    /// a flood of early pixels is not answered in kind.
+    pub fn nack_cooldown(&mut self, handle: ConnHandle, remaining_ms: u32, now_ms: u64) {
+        let (user_id, conn, _) = &mut self.connections[handle];
+        if !conn.is_established() {
+            return;
+        }
+        let slot = self.slots.get_mut(*user_id);
+        let protocol = slot.protocol();
+        let last = &mut slot.last_nack_ms;
+        if *last != 0 && now_ms.saturating_sub(*last) < COOLDOWN_NACK_INTERVAL_MS {
+            self.metrics
+                .cooldown_nacks_limited
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        *last = now_ms.max(1);
+        let nack = encode_cooldown_nack(nack::remaining_secs(remaining_ms));
+        // Dropped like any broadcast datagram if the queue is full.
+        let _ = protocol.dgram_send(conn, &nack, &mut Vec::new());
+        self.metrics.cooldown_nacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sends the receipt (PIXEL_ACK) for a pixel queued for the master,
+    /// echoing `seq` if it came with one. The caller checks that the
+    /// connection negotiated RECEIPTS.
+    pub fn ack_pixel(&mut self, handle: ConnHandle, seq: Option<u16>) {
+        let (user_id, conn, _) = &mut self.connections[handle];
+        if !conn.is_established() {
+            return;
+        }
+        let protocol = self.slots.get(*user_id).protocol();
+        let mut ack = [0; MAX_ACK_LEN];
+        // Dropped like any broadcast datagram if the queue is full.
+        let _ = protocol.dgram_send(conn, encode_ack(seq, &mut ack), &mut Vec::new());
+        self.metrics.pixel_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Brings every connection's pixel-rate contract in line with the load
+    /// factor `load_permille` (rate_contract.rs), pushing the new terms to
+    /// the connections that take them. Cheap while the load stays put.
+    ///
+    /// The cooldown is pushed when it changes: at once for a looser
+    /// contract, once the notice has run out for a tighter one.
+    pub fn refresh_contracts(&mut self, load_permille: u32, now_ms: u64) {
+        if self.tightening_at_ms.is_some_and(|at| now_ms >= at) {
+            self.tightening_at_ms = None;
+            self.push_cooldowns();
+        }
+        if self.slots.terms.load_permille == load_permille {
+            return;
+        }
+        let tighter = load_permille > self.slots.terms.load_permille;
+        if tighter {
+            // Every tightening told now is enforced at the same time.
+            self.tightening_at_ms = Some(now_ms + CONTRACT_NOTICE_MS);
+        }
+        self.slots.terms.load_permille = load_permille;
+        for (user_id, conn, dcid) in self.connections.iter_mut() {
+            if !self.slots.recontract(*user_id, now_ms) {
+                continue;
+            }
+            let slot = self.slots.get(*user_id);
+            if slot.takes_cooldowns() && !tighter {
+                self.control.push_cooldown(*dcid);
+            }
+            if slot.takes_contracts() {
+                self.control
+                    .push_contract(*user_id, conn, &mut self.slots, now_ms);
+            }
         }
+    }
 
-        self.free_user_ids.extend(freed_ids);
+    /// Pushes the broadcast interval to the connections that take it when
+    /// the master has changed it (health.rs).
+    pub fn refresh_interval(&mut self, interval_ms: u32) {
+        if self.broadcast_interval_ms == interval_ms {
+            return;
+        }
+        self.broadcast_interval_ms = interval_ms;
+        for (user_id, conn, _) in self.connections.iter_mut() {
+            if self.slots.get(*user_id).takes_intervals() {
+                self.control
+                    .push_interval(*user_id, conn, &mut self.slots, interval_ms);
+            }
+        }
+    }
+
+    /// Frees the parked ids whose grace period is over; they go through
+    /// `drain_released` like any other.
+    pub fn sweep_reservations(&mut self, now_ms: u64) {
+        let (free, released, slots) =
+            (&mut self.free_user_ids, &mut self.released, &mut self.slots);
+        self.reservations.sweep(now_ms, |expired| {
+            free.push(expired);
+            released.push(expired);
+            slots.released(expired, false);
+        });
+    }
+
+    /// Fires the quiche timers that are due (idle, loss detection,
+    /// draining) and samples the connections' datagram queues for the
+    /// memory estimate, in one pass over them. A connection whose idle
+    /// timer fired is closed; `cleanup_connections` then frees its id.
+    pub fn on_timeouts(&mut self) {
+        let mut queued_bytes = 0;
+        for (_, conn, _) in self.connections.iter_mut() {
+            if conn.timeout().is_some_and(|left| left.is_zero()) {
+                conn.on_timeout();
+            }
+            queued_bytes += conn.dgram_send_queue_byte_size() + conn.dgram_recv_queue_byte_size();
+        }
+        self.memory.sampled(queued_bytes as u64);
+    }
+
+    /// Drops closed connections, then calls `on_free` with each user id freed
+    /// (see `drain_released`).
+    pub fn cleanup_connections(&mut self, on_free: impl FnMut(u32)) {
+        let mut handle = 0;
+        while handle < self.connections.len() {
+            if !self.connections[handle].1.is_closed() {
+                handle += 1;
+                continue;
+            }
+            // The slot now holds the former last connection; check it next.
+            self.remove_connection(handle);
+        }
+        self.drain_released(on_free);
+    }
+
+    /// Sums quiche's statistics over the live connections and summarizes
+    /// the RTT and cwnd of the established ones (transport_stats.rs).
+    pub fn collect_stats(&mut self) -> WorkerTransportStats {
+        let mut stats = WorkerTransportStats::default();
+        let mut cwnd_sum = 0;
+        self.stats_rtts_us.clear();
+        for (_, conn, _) in &self.connections {
+            let counts = conn.stats();
+            stats.sent += counts.sent as u64;
+            stats.recv += counts.recv as u64;
+            stats.lost += counts.lost as u64;
+            stats.retrans += counts.retrans as u64;
+            if conn.is_draining() {
+                stats.draining += 1;
+            } else if !conn.is_established() {
+                stats.handshaking += 1;
+            } else {
+                stats.established += 1;
+                if let Some(path) = conn.path_stats().find(|p| p.active) {
+                    self.stats_rtts_us.push(path.rtt.as_micros() as u64);
+                    cwnd_sum += path.cwnd as u64;
+                }
+            }
+        }
+        stats.summarize_paths(&mut self.stats_rtts_us, cwnd_sum);
+        stats
+    }
+
+    /// Drops the connections still handshaking past the deadline, like
+    /// `reap_handshakes`, and publishes the age of the oldest one left.
+    pub fn reap_stale_handshakes(&mut self, now_ms: u64, on_free: impl FnMut(u32)) {
+        let mut handle = 0;
+        while handle < self.connections.len() {
+            let (user_id, conn, _) = &self.connections[handle];
+            if conn.is_established() || !self.half_open.expired(*user_id, now_ms) {
+                handle += 1;
+                continue;
+            }
+            self.metrics
+                .handshakes_expired
+                .fetch_add(1, Ordering::Relaxed);
+            self.remove_connection(handle);
+        }
+        self.half_open.sampled(now_ms);
+        self.drain_released(on_free);
+    }
+
+    /// Over the hard memory limit: drops every connection whose handshake
+    /// has not completed, without a CONNECTION_CLOSE (sending one would keep
+    /// the state around for the drain period). Calls `on_free` like
+    /// `cleanup_connections`.
+    pub fn reap_handshakes(&mut self, on_free: impl FnMut(u32)) {
+        let mut handle = 0;
+        while handle < self.connections.len() {
+            if self.connections[handle].1.is_established() {
+                handle += 1;
+                continue;
+            }
+            self.metrics
+                .handshakes_reaped
+                .fetch_add(1, Ordering::Relaxed);
+            self.remove_connection(handle);
+        }
+        self.drain_released(on_free);
+    }
+}
+
+/// Takes a user id for the connection `accept` builds. The id leaves the
+/// free list only once `accept` has succeeded, so a failed accept never
+/// loses one.
+fn claim_user_id<T>(
+    free_user_ids: &mut Vec<u32>,
+    accept: impl FnOnce() -> Result<T, quiche::Error>,
+) -> Result<(u32, T), AcceptError> {
+    if free_user_ids.is_empty() {
+        return Err(AcceptError::AtCapacity);
+    }
+    let conn = accept().map_err(AcceptError::QuicError)?;
+    Ok((free_user_ids.pop().unwrap(), conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::{
+        CONN_STATE_BYTES, CONN_TIMEOUT_THROTTLE_MS, CONTRACT_NOTICE_MS, MAX_PIXELS_PER_PACKET,
+        MAX_WORKERS, TILE_BITMAP_LEN, TIMING_WHEEL_TICKS,
+    };
+    use crate::cooldown::CooldownArray;
+    use crate::metrics::WORKER_METRICS;
+    use crate::timing_wheel::TimingWheel;
+    use protocol::control::{
+        COOLDOWN_MULTIPLIER_ONE, ControlOp, ControlStatus, CooldownConfig, ServerPush,
+        decode_cooldown_config, decode_rate_contract_push, decode_server_push,
+    };
+    use protocol::pixel;
+    use protocol::receipt;
+    use protocol::version::{BuildInfo, Features, encode_version_request};
+    use protocol::webtransport::{RAW_ALPN, SessionPrefix};
+    use quiche::h3::{self, NameValue};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    /// Counts allocations made by the current thread so parallel tests don't
+    /// pollute each other's numbers.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        /// Bytes allocated minus bytes freed by the current thread.
+        static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|a| a.set(a.get() + 1));
+            LIVE_BYTES.with(|b| b.set(b.get() + layout.size() as isize));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            LIVE_BYTES.with(|b| b.set(b.get() - layout.size() as isize));
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|a| a.get())
+    }
+
+    fn live_bytes() -> isize {
+        LIVE_BYTES.with(|b| b.get())
+    }
+
+    /// A worker transport with a freshly provisioned certificate.
+    fn test_transport(name: &str, metrics: &'static WorkerMetrics) -> TransportState {
+        let dir = std::env::temp_dir().join(format!("canvas-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = crate::config::ServerConfig {
+            cert_path: dir.join("cert.crt"),
+            key_path: dir.join("key.key"),
+            ..Default::default()
+        };
+        crate::tls::ensure_certificates(&config).unwrap();
+        let transport = TransportState::new(&config.cert_path, &config.key_path, metrics);
+        let _ = std::fs::remove_dir_all(&dir);
+        transport
+    }
+
+    fn client_config() -> quiche::Config {
+        let mut client_config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        client_config.set_application_protos(&[RAW_ALPN]).unwrap();
+        client_config.verify_peer(false);
+        client_config.enable_dgram(true, 16, 16);
+        client_config
+    }
+
+    /// The first packet (an Initial) of `n` clients, each from its own
+    /// address.
+    fn client_initials(n: usize, local: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut client_config = client_config();
+        let mut pkt = [0u8; 1350];
+        (0..n)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 40_000));
+                let scid: [u8; 16] = rand::random();
+                let mut client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut client_config,
+                )
+                .unwrap();
+                let (len, _) = client.send(&mut pkt).unwrap();
+                (pkt[..len].to_vec(), peer)
+            })
+            .collect()
+    }
+
+    /// Runs packets both ways between `clients` (peer address, connection)
+    /// and the transport until neither side has anything left to send.
+    /// Returns the pixels the transport took in, by user id.
+    fn exchange(
+        transport: &mut TransportState,
+        local: SocketAddr,
+        clients: &mut [(SocketAddr, quiche::Connection)],
+    ) -> Vec<(u32, u16, u16, u8)> {
+        let mut pixels = Vec::new();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut pkt = [0u8; 1500];
+        loop {
+            let mut sent = false;
+            for (peer, client) in clients.iter_mut() {
+                while let Ok((len, _)) = client.send(&mut pkt) {
+                    sent = true;
+                    if let Some(received) =
+                        transport.handle_incoming(&mut pkt[..len], *peer, local, &mut out)
+                    {
+                        let user_id = received.user_id;
+                        pixels.extend(out.iter().map(|p| (user_id, p.x, p.y, p.color)));
+                    }
+                }
+            }
+            // What the worker reads between packets.
+            transport.start_backlog_pass();
+            while let Some((received, _)) = transport.next_backlogged(&mut out) {
+                sent = true;
+                let user_id = received.user_id;
+                pixels.extend(out.iter().map(|p| (user_id, p.x, p.y, p.color)));
+            }
+            for (_, conn, _) in transport.connections.iter_mut() {
+                while let Ok((len, info)) = conn.send(&mut pkt) {
+                    sent = true;
+                    let (_, client) = clients
+                        .iter_mut()
+                        .find(|(peer, _)| *peer == info.to)
+                        .unwrap();
+                    let from_server = RecvInfo {
+                        from: local,
+                        to: info.to,
+                    };
+                    let _ = client.recv(&mut pkt[..len], from_server);
+                }
+            }
+            if !sent {
+                return pixels;
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_path_does_not_allocate() {
+        let mut cid_map: FxHashMap<CidKey, ConnHandle> = FxHashMap::default();
+        let (client_dcid, server_scid, spare_scid) = ([1u8; 16], [2u8; 20], [3u8; 20]);
+        cid_map.insert(CidKey::new(&client_dcid), 7);
+        cid_map.insert(CidKey::new(&server_scid), 7);
+        cid_map.insert(CidKey::new(&spare_scid), 7);
+
+        // Short-header packets as an established client sends them: flags,
+        // our SCID, then (opaque to the lookup) the protected payload.
+        let short_packet = |scid: &[u8; 20]| {
+            let mut pkt = vec![0x43];
+            pkt.extend_from_slice(scid);
+            pkt.extend_from_slice(&[0xaa; 40]);
+            pkt
+        };
+        let packets = [short_packet(&server_scid), short_packet(&spare_scid)];
+
+        // x 100, y 200, color 3, as any client sends it.
+        let payload = [100, 0, 200, 0, 3];
+
+        let mut out: Vec<PixelDatagram> = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let before = allocations();
+        for i in 0..10_000 {
+            // Alternate between the accept SCID and a spare one the client
+            // migrated to.
+            let dcid = short_header_dcid(&packets[i % 2]).unwrap();
+            let handle = TransportState::resolve_connection_id(&cid_map, dcid);
+            assert_eq!(handle, Some(7));
+
+            out.clear();
+            for _ in 0..8 {
+                out.push(PixelDatagram::from_wire(&payload).unwrap());
+            }
+            assert_eq!(out.len(), 8);
+        }
+        assert_eq!(allocations() - before, 0);
+
+        // The client's original DCID only shows up in long headers, which
+        // take quiche's full parse.
+        assert_eq!(
+            TransportState::resolve_connection_id(&cid_map, &client_dcid),
+            Some(7)
+        );
+        let mut long = short_packet(&server_scid);
+        long[0] = 0xc3;
+        assert_eq!(short_header_dcid(&long), None);
+        assert_eq!(short_header_dcid(&[0x43, 1, 2]), None);
+
+        // Long headers: flags, version, DCID length and DCID, then the rest.
+        let long_packet = |flags: u8, version: u32, dcid: &[u8]| {
+            let mut pkt = vec![flags];
+            pkt.extend_from_slice(&version.to_be_bytes());
+            pkt.push(dcid.len() as u8);
+            pkt.extend_from_slice(dcid);
+            pkt.extend_from_slice(&[0xaa; 40]);
+            pkt
+        };
+        let initial = long_packet(0xc3, 1, &client_dcid);
+        let handshake = long_packet(0xe3, 1, &server_scid);
+        let before = allocations();
+        for _ in 0..10_000 {
+            assert_eq!(long_header_dcid(&initial), Some((&client_dcid[..], true)));
+            assert_eq!(
+                long_header_dcid(&handshake),
+                Some((&server_scid[..], false))
+            );
+        }
+        assert_eq!(allocations() - before, 0);
+        let negotiation = long_packet(0x80, 0, &client_dcid);
+        assert_eq!(
+            long_header_dcid(&negotiation),
+            Some((&client_dcid[..], false))
+        );
+        assert_eq!(long_header_dcid(&packets[0]), None);
+        assert_eq!(long_header_dcid(&initial[..10]), None);
+        assert_eq!(long_header_dcid(&long_packet(0xc3, 1, &[0; 21])), None);
+
+        let p = PixelDatagram::from_wire(&payload).unwrap();
+        let (x, y, color) = (p.x, p.y, p.color);
+        assert_eq!((x, y, color), (100, 200, 3));
+        assert_eq!(p.token, None);
+        assert!(PixelDatagram::from_wire(&payload[..4]).is_none());
+        assert!(PixelDatagram::from_wire(&[0u8; 6]).is_none());
+        let mut wire = [0; PIXEL_DATAGRAM_SIZE];
+        p.to_wire(&mut wire);
+        assert_eq!(wire, payload);
+
+        let mut tokened = payload.to_vec();
+        tokened.extend_from_slice(&[0xef, 0xbe, 0xad, 0xde]);
+        let p = PixelDatagram::from_wire(&tokened).unwrap();
+        assert_eq!(
+            (p.x, p.y, p.color, p.token),
+            (100, 200, 3, Some(0xdead_beef))
+        );
+        assert!(PixelDatagram::from_wire(&tokened[..8]).is_none());
+        assert!(PixelDatagram::from_wire(&[0u8; 10]).is_none());
+        assert_eq!(p.nonce, None);
+
+        let mut nonced = tokened.clone();
+        nonced.extend_from_slice(&[7, 0, 0, 0]);
+        let p = PixelDatagram::from_wire(&nonced).unwrap();
+        assert_eq!((p.x, p.token, p.nonce), (100, Some(0xdead_beef), Some(7)));
+        assert!(PixelDatagram::from_wire(&nonced[..12]).is_none());
+        assert!(PixelDatagram::from_wire(&[0u8; 14]).is_none());
+        assert_eq!(p.seq, None);
+
+        let mut numbered = vec![0x34, 0x12];
+        numbered.extend_from_slice(&tokened);
+        let p = PixelDatagram::from_seq_wire(&numbered).unwrap();
+        assert_eq!(
+            (p.x, p.token, p.seq),
+            (100, Some(0xdead_beef), Some(0x1234))
+        );
+        assert!(PixelDatagram::from_seq_wire(&numbered[..2]).is_none());
+        assert!(PixelDatagram::from_seq_wire(&tokened).is_none());
+    }
+
+    #[test]
+    fn test_workers_share_provisioned_certificate() {
+        let dir = std::env::temp_dir().join(format!("canvas-transport-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = crate::config::ServerConfig {
+            cert_path: dir.join("cert.crt"),
+            key_path: dir.join("key.key"),
+            ..Default::default()
+        };
+        crate::tls::ensure_certificates(&config).unwrap();
+        let cert = std::fs::read(&config.cert_path).unwrap();
+        let key = std::fs::read(&config.key_path).unwrap();
+
+        // Workers come up concurrently; none of them may touch the files.
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    TransportState::new(&config.cert_path, &config.key_path, &WORKER_METRICS[0])
+                });
+            }
+        });
+        let _ = TransportState::new(&config.cert_path, &config.key_path, &WORKER_METRICS[0]);
+
+        assert_eq!(std::fs::read(&config.cert_path).unwrap(), cert);
+        assert_eq!(std::fs::read(&config.key_path).unwrap(), key);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Accept rate of one worker during a connection ramp, TLS included.
+    /// Initials are built up front so only the server side is timed.
+    /// `cargo test -p server --release bench_accept_ramp -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_accept_ramp() {
+        const RAMP: usize = 10_000;
+        let mut transport = test_transport("ramp", &WORKER_METRICS[0]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let initials = client_initials(RAMP.min(MAX_CONNECTIONS_PER_WORKER), local);
+
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let accepted = initials.len();
+        let before = allocations();
+        let start = std::time::Instant::now();
+        for (mut initial, peer) in initials {
+            transport.handle_incoming(&mut initial, peer, local, &mut out);
+        }
+        let elapsed = start.elapsed();
+        let allocs = allocations() - before;
+
+        assert_eq!(transport.connections.len(), accepted);
+        println!(
+            "accepted {} connections in {:?}: {:.0} accepts/s, {:.1} allocations per accept (quiche's included)",
+            accepted,
+            elapsed,
+            accepted as f64 / elapsed.as_secs_f64(),
+            allocs as f64 / accepted as f64
+        );
+    }
+
+    /// Heap held per connection right after its first Initial, the state a
+    /// ramp piles up, against the CONN_STATE_BYTES estimate.
+    /// `cargo test -p server --release bench_conn_memory -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_conn_memory() {
+        const CONNECTIONS: usize = 2_000;
+        let mut transport = test_transport("conn-memory", &WORKER_METRICS[0]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let initials = client_initials(CONNECTIONS, local);
+
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let before = live_bytes();
+        for (mut initial, peer) in initials {
+            transport.handle_incoming(&mut initial, peer, local, &mut out);
+        }
+        let per_connection = (live_bytes() - before) / transport.connections.len() as isize;
+        println!(
+            "{} bytes per handshaking connection (CONN_STATE_BYTES = {})",
+            per_connection, CONN_STATE_BYTES
+        );
+    }
+
+    #[test]
+    fn test_memory_limits_change_admission() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 3];
+        let mut transport = test_transport("memory", metrics);
+        const CONN: u64 = CONN_STATE_BYTES as u64;
+        transport.memory = ConnMemory::new(
+            MemoryLimits {
+                soft_bytes: 3 * CONN,
+                hard_bytes: 4 * CONN,
+            },
+            metrics,
+        );
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut initials = client_initials(6, local).into_iter();
+        let mut send = |transport: &mut TransportState| {
+            let (mut initial, peer) = initials.next().unwrap();
+            transport.handle_incoming(&mut initial, peer, local, &mut out);
+        };
+
+        // Accepted up to the soft limit, then dropped with user ids to
+        // spare, without a connection built to refuse them.
+        for _ in 0..3 {
+            send(&mut transport);
+        }
+        assert_eq!(transport.connections.len(), 3);
+        assert!(transport.refused.is_empty());
+        send(&mut transport);
+        assert_eq!(transport.connections.len(), 3);
+        assert!(transport.refused.is_empty() && transport.responses.is_empty());
+        assert!(transport.free_user_ids.len() > 3);
+        assert_eq!(metrics.memory_rejections.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            metrics.accept_capacity_rejections.load(Ordering::Relaxed),
+            0
+        );
+        assert_eq!(metrics.conn_memory_bytes.load(Ordering::Relaxed), 3 * CONN);
+
+        // A datagram backlog pushes the worker over the hard limit: the
+        // sweep drops the connections still handshaking (all of them here).
+        transport.memory.sampled(CONN + 1);
+        assert!(transport.memory.over_hard_limit());
+        let mut freed = Vec::new();
+        transport.reap_handshakes(|user_id| freed.push(user_id));
+        assert_eq!(freed.len(), 3);
+        assert!(transport.connections.is_empty() && transport.cid_map.is_empty());
+        assert_eq!(metrics.handshakes_reaped.load(Ordering::Relaxed), 3);
+
+        // Back under both limits, new connections are accepted again.
+        transport.memory.sampled(0);
+        send(&mut transport);
+        assert_eq!(transport.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_half_open_connections_expire_and_tighten_admission() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 9];
+        let mut transport = test_transport("half-open", metrics);
+        transport.half_open = HalfOpen::new(
+            HalfOpenLimits {
+                deadline_ms: 10_000,
+                threshold: 4,
+            },
+            metrics,
+        );
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut initials = client_initials(6, local).into_iter();
+        let mut send = |transport: &mut TransportState| {
+            let (mut initial, peer) = initials.next().unwrap();
+            transport.handle_incoming(&mut initial, peer, local, &mut out);
+        };
+
+        // Clients that send their Initial and nothing more. The fourth one
+        // tightens admission; the fifth is dropped with no TLS work: no
+        // connection to refuse it, nothing sent back.
+        let start = crate::time::CLOCK.now_ms();
+        for _ in 0..4 {
+            send(&mut transport);
+        }
+        assert_eq!(transport.half_open.count(), 4);
+        assert!(transport.half_open.tightened());
+        assert_eq!(metrics.half_open_tightened.load(Ordering::Relaxed), 1);
+        send(&mut transport);
+        assert_eq!(transport.connections.len(), 4);
+        assert!(transport.refused.is_empty() && transport.responses.is_empty());
+        assert_eq!(metrics.half_open_rejections.load(Ordering::Relaxed), 1);
+
+        // Nothing expires before the deadline; past it all four do, and
+        // their ids are freed.
+        let mut freed = Vec::new();
+        transport.reap_stale_handshakes(start + 9_999, |user_id| freed.push(user_id));
+        assert!(freed.is_empty());
+        let end = crate::time::CLOCK.now_ms();
+        transport.reap_stale_handshakes(end + 10_000, |user_id| freed.push(user_id));
+        assert_eq!(freed.len(), 4);
+        assert!(transport.connections.is_empty() && transport.cid_map.is_empty());
+        assert_eq!(metrics.handshakes_expired.load(Ordering::Relaxed), 4);
+        assert_eq!(metrics.half_open_connections.load(Ordering::Relaxed), 0);
+
+        // The count receded: admission is back to normal.
+        assert!(!transport.half_open.tightened());
+        send(&mut transport);
+        assert_eq!(transport.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_only_a_full_worker_refuses_with_a_close() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 36];
+        let mut transport = test_transport("refuse", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut initials = client_initials(2, local).into_iter();
+
+        // Out of user ids, the client is told so.
+        let free = std::mem::take(&mut transport.free_user_ids);
+        let (mut initial, peer) = initials.next().unwrap();
+        transport.handle_incoming(&mut initial, peer, local, &mut out);
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.refused.len(), 1);
+        assert_eq!(
+            metrics.accept_capacity_rejections.load(Ordering::Relaxed),
+            1
+        );
+
+        // Tightened over half-open connections, it is not.
+        transport.free_user_ids = free;
+        transport.half_open = HalfOpen::new(
+            HalfOpenLimits {
+                deadline_ms: 10_000,
+                threshold: 1,
+            },
+            metrics,
+        );
+        transport
+            .half_open
+            .accepted(0, "10.9.9.9".parse().unwrap(), 0);
+        assert!(transport.half_open.tightened());
+        let (mut initial, peer) = initials.next().unwrap();
+        transport.handle_incoming(&mut initial, peer, local, &mut out);
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.refused.len(), 1);
+        assert_eq!(metrics.half_open_rejections.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_junk_is_classified_and_repeat_offenders_denied() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 13];
+        let mut transport = test_transport("junk", metrics);
+        transport.junk = JunkFilter::new(
+            JunkLimits {
+                strict: true,
+                threshold: 4,
+                deny_ttl_ms: 60_000,
+                deny_max: 16,
+            },
+            metrics,
+        );
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let source = |last: u8| SocketAddr::from(([198, 51, 100, last], 53_000));
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut feed = |transport: &mut TransportState, pkt: &[u8], peer: SocketAddr| {
+            let mut pkt = pkt.to_vec();
+            transport.handle_incoming(&mut pkt, peer, local, &mut out)
+        };
+
+        // One of each kind, from sources of their own.
+        let mut other_version = vec![0xc3];
+        other_version.extend_from_slice(&0x1a2a_3a4au32.to_be_bytes());
+        other_version.push(8);
+        other_version.resize(1200, 0);
+        assert!(feed(&mut transport, b"\x00\x01", source(1)).is_none());
+        assert!(feed(&mut transport, &[0; 64], source(2)).is_none());
+        assert!(feed(&mut transport, &other_version, source(3)).is_none());
+        assert!(feed(&mut transport, &[0x41; 64], source(4)).is_none());
+        assert_eq!(metrics.junk_too_short.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_not_quic.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_unsupported_version.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_unknown_cid.load(Ordering::Relaxed), 1);
+        // The Initial of another version never got a user id.
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.free_user_ids.len(), MAX_CONNECTIONS_PER_WORKER);
+        assert_eq!(metrics.accept_errors.load(Ordering::Relaxed), 0);
+
+        // A scanner's fourth garbage packet puts it on the deny list...
+        let scanner = source(66);
+        for _ in 0..4 {
+            feed(&mut transport, &[0; 64], scanner);
+        }
+        assert_eq!(transport.junk.denied_count(), 1);
+        assert_eq!(metrics.junk_not_quic.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.junk_denials.load(Ordering::Relaxed), 1);
+
+        // ...after which even a real Initial from it is dropped unread,
+        // while other sources still connect.
+        let mut initials = client_initials(2, local).into_iter();
+        let (initial, _) = initials.next().unwrap();
+        feed(&mut transport, &initial, scanner);
+        assert!(transport.connections.is_empty());
+        assert_eq!(metrics.junk_denied.load(Ordering::Relaxed), 1);
+        let (initial, peer) = initials.next().unwrap();
+        feed(&mut transport, &initial, peer);
+        assert_eq!(transport.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_retry_keeps_spoofed_initials_from_taking_user_ids() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 14];
+        let mut transport = test_transport("retry", metrics);
+        transport.retry = Some(RetryTokens::new(10_000));
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+
+        // A flood of Initials from addresses that will never answer: each
+        // gets a Retry and nothing else.
+        for (mut initial, peer) in client_initials(32, local) {
+            assert!(
+                transport
+                    .handle_incoming(&mut initial, peer, local, &mut out)
+                    .is_none()
+            );
+        }
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.free_user_ids.len(), MAX_CONNECTIONS_PER_WORKER);
+        assert_eq!(transport.responses.len(), 32);
+        assert_eq!(metrics.retries_sent.load(Ordering::Relaxed), 32);
+        transport.responses.clear();
+
+        // A real client follows the Retry.
+        let peer = SocketAddr::from(([192, 0, 2, 7], 51_000));
+        let mut config = client_config();
+        let scid: [u8; 16] = rand::random();
+        let mut client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut config,
+        )
+        .unwrap();
+        let mut pkt = [0u8; 1350];
+        let (len, _) = client.send(&mut pkt).unwrap();
+        transport.handle_incoming(&mut pkt[..len], peer, local, &mut out);
+        let mut retry = transport.responses.pop().unwrap();
+        assert_eq!(retry.to, peer);
+        let from_server = RecvInfo {
+            from: local,
+            to: peer,
+        };
+        client
+            .recv(&mut retry.buf[..retry.len], from_server)
+            .unwrap();
+        let (len, _) = client.send(&mut pkt).unwrap();
+        let answer = pkt[..len].to_vec();
+        let (_, token) = initial_scid_and_token(&answer).unwrap();
+        assert!(!token.is_empty());
+
+        // Its token is no good from anywhere else, or tampered with.
+        let spoofed = SocketAddr::from(([192, 0, 2, 8], 51_000));
+        transport.handle_incoming(&mut answer.clone(), spoofed, local, &mut out);
+        let mut forged = answer.clone();
+        let token_at = token.as_ptr() as usize - answer.as_ptr() as usize;
+        forged[token_at] ^= 1;
+        transport.handle_incoming(&mut forged, peer, local, &mut out);
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.free_user_ids.len(), MAX_CONNECTIONS_PER_WORKER);
+        assert_eq!(metrics.retry_token_rejections.load(Ordering::Relaxed), 2);
+        assert!(transport.responses.is_empty());
+
+        // From the address it was minted for, it gets the connection.
+        transport.handle_incoming(&mut answer.clone(), peer, local, &mut out);
+        assert_eq!(transport.connections.len(), 1);
+        assert_eq!(
+            transport.free_user_ids.len(),
+            MAX_CONNECTIONS_PER_WORKER - 1
+        );
+        assert_eq!(metrics.connections_accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_repeated_initials_take_one_user_id() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 32];
+        let mut transport = test_transport("repeated-initial", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+
+        // Retransmitted before the server's first flight got through.
+        let (initial, peer) = client_initials(1, local).pop().unwrap();
+        for _ in 0..3 {
+            transport.handle_incoming(&mut initial.clone(), peer, local, &mut out);
+        }
+        assert_eq!(transport.connections.len(), 1);
+        assert_eq!(
+            transport.free_user_ids.len(),
+            MAX_CONNECTIONS_PER_WORKER - 1
+        );
+        assert_eq!(metrics.connections_accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.duplicate_initials.load(Ordering::Relaxed), 2);
+
+        // A generated SCID never lands on one already routed.
+        let taken = TransportState::fresh_scid(&transport.cid_map, &mut StdRng::seed_from_u64(7));
+        transport.cid_map.insert(CidKey::new(&taken), 0);
+        let scid = TransportState::fresh_scid(&transport.cid_map, &mut StdRng::seed_from_u64(7));
+        assert_ne!(scid, taken);
+    }
+
+    #[test]
+    fn test_unsupported_version_gets_version_negotiation() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 15];
+        let mut transport = test_transport("vn", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([203, 0, 113, 5], 52_000));
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let (dcid, scid) = ([0xd1; 8], [0x5c; 5]);
+        let long_packet = |version: u32, len: usize| {
+            let mut pkt = vec![0xc3];
+            pkt.extend_from_slice(&version.to_be_bytes());
+            pkt.push(dcid.len() as u8);
+            pkt.extend_from_slice(&dcid);
+            pkt.push(scid.len() as u8);
+            pkt.extend_from_slice(&scid);
+            pkt.resize(len, 0);
+            pkt
+        };
+
+        // A full-size first packet of a version nobody speaks yet.
+        let mut bogus = long_packet(0x0a0a_0a0a, 1200);
+        assert!(
+            transport
+                .handle_incoming(&mut bogus, peer, local, &mut out)
+                .is_none()
+        );
+        assert_eq!(transport.responses.len(), 1);
+        let response = transport.responses.pop().unwrap();
+        assert_eq!(response.to, peer);
+        let vn = &response.buf[..response.len];
+        assert_eq!(vn[0] & 0x80, 0x80);
+        assert_eq!(long_header_version(vn), 0);
+        // The client's connection ids, swapped.
+        assert_eq!(long_header_dcid(vn).unwrap().0, scid);
+        let (vn_scid, versions_at) = long_header_scid(vn).unwrap();
+        assert_eq!(vn_scid, dcid);
+        let versions: Vec<u32> = vn[versions_at..]
+            .chunks_exact(4)
+            .map(|v| u32::from_be_bytes(v.try_into().unwrap()))
+            .collect();
+        assert!(versions.contains(&quiche::PROTOCOL_VERSION));
+        assert_eq!(metrics.version_negotiations.load(Ordering::Relaxed), 1);
+
+        // Too short to be a first packet: answering would amplify. And a
+        // Version Negotiation is never answered with another.
+        let mut short = long_packet(0x0a0a_0a0a, 200);
+        transport.handle_incoming(&mut short, peer, local, &mut out);
+        let mut negotiation = long_packet(0, 1200);
+        transport.handle_incoming(&mut negotiation, peer, local, &mut out);
+        assert!(transport.responses.is_empty());
+        assert_eq!(metrics.version_negotiations.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.junk_unsupported_version.load(Ordering::Relaxed), 3);
+
+        assert!(transport.connections.is_empty());
+        assert_eq!(transport.free_user_ids.len(), MAX_CONNECTIONS_PER_WORKER);
+    }
+
+    #[test]
+    fn test_removal_repoints_moved_connection() {
+        let mut transport = test_transport("handles", &WORKER_METRICS[0]);
+
+        let local: SocketAddr = "10.0.0.9:4433".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let ids = [
+            ([1u8; 20], [11u8; 16]),
+            ([2; 20], [12; 16]),
+            ([3; 20], [13; 16]),
+        ];
+        for (scid, dcid) in &ids {
+            transport
+                .accept_connection(scid, dcid, None, local, peer)
+                .unwrap();
+        }
+        let user_of = |t: &TransportState, cid: &[u8]| {
+            TransportState::resolve_connection_id(&t.cid_map, cid).map(|h| t.connections[h].0)
+        };
+        let users: Vec<_> = ids
+            .iter()
+            .map(|(scid, _)| user_of(&transport, scid))
+            .collect();
+
+        // The last connection fills the hole; both of its ids follow it.
+        let freed = transport.remove_connection(0);
+        assert_eq!(Some(freed), users[0]);
+        assert_eq!(transport.connections.len(), 2);
+        assert_eq!(user_of(&transport, &ids[0].0), None);
+        assert_eq!(user_of(&transport, &ids[0].1), None);
+        for i in 1..3 {
+            assert_eq!(user_of(&transport, &ids[i].0), users[i]);
+            assert_eq!(user_of(&transport, &ids[i].1), users[i]);
+        }
+        assert_eq!(transport.free_user_ids.last(), Some(&freed));
+    }
+
+    #[test]
+    fn test_path_events_count_migrations() {
+        let mut transport = test_transport("migrate", &WORKER_METRICS[0]);
+
+        let local: SocketAddr = "10.0.0.9:4433".parse().unwrap();
+        let (wifi, cellular): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:5000".parse().unwrap(),
+            "10.0.0.2:6000".parse().unwrap(),
+        );
+        let scid = quiche::ConnectionId::from_ref(&[7; quiche::MAX_CONN_ID_LEN]);
+        let mut conn = quiche::accept(&scid, None, local, wifi, &mut transport.config).unwrap();
+
+        let metrics = WorkerMetrics::new();
+        for event in [
+            PathEvent::New(local, cellular),
+            PathEvent::Validated(local, cellular),
+            PathEvent::PeerMigrated(local, cellular),
+            PathEvent::FailedValidation(local, wifi),
+        ] {
+            TransportState::on_path_event(&metrics, &mut conn, event);
+        }
+        assert_eq!(metrics.migrations.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.migration_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_accept_failures_are_distinct_and_keep_ids() {
+        let mut free = vec![0, 1];
+
+        let err = claim_user_id(&mut free, || Err::<(), _>(quiche::Error::TlsFail));
+        assert_eq!(err, Err(AcceptError::QuicError(quiche::Error::TlsFail)));
+        assert_eq!(free, vec![0, 1]);
+
+        assert_eq!(claim_user_id(&mut free, || Ok("a")), Ok((1, "a")));
+        assert_eq!(claim_user_id(&mut free, || Ok("b")), Ok((0, "b")));
+
+        // At capacity quiche is never asked.
+        let err = claim_user_id(&mut free, || -> Result<(), _> {
+            unreachable!("accept attempted at capacity")
+        });
+        assert_eq!(err, Err(AcceptError::AtCapacity));
+    }
+
+    #[test]
+    fn test_accept_warnings_are_rate_limited() {
+        let mut warnings = AcceptWarnings::default();
+        assert_eq!(warnings.admit(5_000), Some(0));
+        assert_eq!(warnings.admit(5_001), None);
+        assert_eq!(warnings.admit(5_000 + ACCEPT_WARN_INTERVAL_MS - 1), None);
+        assert_eq!(warnings.admit(5_000 + ACCEPT_WARN_INTERVAL_MS), Some(2));
+        assert_eq!(warnings.admit(5_000 + 2 * ACCEPT_WARN_INTERVAL_MS), Some(0));
+    }
+
+    #[test]
+    fn test_recycled_user_id_starts_from_a_fresh_slot() {
+        let mut transport = test_transport("slots", &WORKER_METRICS[0]);
+        let local: SocketAddr = "10.0.0.9:4433".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        let handle = transport
+            .accept_connection(&[1; 20], &[11; 16], None, local, peer)
+            .unwrap();
+        let user_id = transport.connections[handle].0;
+        let mut first_use = ConnSlots::new();
+        first_use.accepted(user_id);
+        assert_eq!(transport.slots.get(user_id), first_use.get(user_id));
+
+        transport
+            .slots
+            .established(user_id, 1_000, || AppProtocol::WebTransport(None));
+        transport
+            .slots
+            .opened_session(user_id, SessionPrefix::new(0));
+        transport.slots.negotiated(user_id, Features::ZSTD);
+        transport.slots.subscribed(user_id, [0; TILE_BITMAP_LEN]);
+        transport.slots.claim_tier1(user_id);
+        transport.slots.get_mut(user_id).last_stats_reply_ms = 1_500;
+
+        // Never established as far as quiche knows: freed, not parked, and
+        // the free list hands it straight back out.
+        assert_eq!(transport.remove_connection(handle), user_id);
+        assert_eq!(transport.slots.get(user_id).state(), SlotState::Free);
+        let handle = transport
+            .accept_connection(&[2; 20], &[12; 16], None, local, peer)
+            .unwrap();
+        assert_eq!(transport.connections[handle].0, user_id);
+        assert_eq!(transport.slots.get(user_id), first_use.get(user_id));
+        assert_eq!(transport.slots.tier1_count(), 0);
+        assert_eq!(transport.slots.first_broadcast(user_id, 2_000), None);
+    }
+
+    #[test]
+    fn test_churn_past_capacity_keeps_accepting() {
+        const CAPACITY: u32 = 64;
+        let mut transport = test_transport("churn", &WORKER_METRICS[MAX_WORKERS - 34]);
+        // A worker's worth of ids, shrunk so the churn stays quick.
+        transport.free_user_ids = (0..CAPACITY).collect();
+        let local: SocketAddr = "10.0.0.9:4433".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        let mut freed = 0;
+        for round in 0..4 * CAPACITY {
+            let mut scid = [0; 20];
+            scid[..4].copy_from_slice(&round.to_le_bytes());
+            let mut dcid = [1; 16];
+            dcid[..4].copy_from_slice(&round.to_le_bytes());
+            let handle = transport
+                .accept_connection(&scid, &dcid, None, local, peer)
+                .unwrap_or_else(|e| panic!("connection {} refused: {:?}", round, e));
+            // Closed before anything came back: quiche drops it at once.
+            let conn = &mut transport.connections[handle].1;
+            conn.close(false, 0, b"").unwrap();
+            assert!(conn.is_closed());
+            // The worker sweeps every few connections, as it would.
+            if round % 16 == 15 {
+                transport.on_timeouts();
+                transport.cleanup_connections(|_| freed += 1);
+            }
+        }
+        assert_eq!(freed, 4 * CAPACITY);
+        assert!(transport.connections.is_empty());
+        assert!(transport.cid_map.is_empty());
+        assert_eq!(transport.free_user_ids.len(), CAPACITY as usize);
+        assert!(
+            transport
+                .accept_connection(&[9; 20], &[9; 16], None, local, peer)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_idle_connection_frees_its_user_id() {
+        const IDLE_TIMEOUT_MS: u64 = 200;
+        let sweep = Duration::from_millis(CONN_TIMEOUT_THROTTLE_MS as u64);
+        let mut transport = test_transport("idle", &WORKER_METRICS[MAX_WORKERS - 16]);
+        transport.config.set_max_idle_timeout(IDLE_TIMEOUT_MS);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let scid: [u8; 16] = rand::random();
+        let client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut client_config(),
+        )
+        .unwrap();
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients[0].1.is_established());
+        let user_id = transport.connections[0].0;
+
+        // The client goes quiet; the worker keeps sweeping as it would.
+        let quiet_since = Instant::now();
+        let sweep_until = |transport: &mut TransportState, deadline: Instant| {
+            while Instant::now() < deadline && !transport.free_user_ids.contains(&user_id) {
+                std::thread::sleep(sweep);
+                transport.on_timeouts();
+                transport.cleanup_connections(|_| {});
+            }
+        };
+        sweep_until(
+            &mut transport,
+            quiet_since + Duration::from_millis(IDLE_TIMEOUT_MS / 2),
+        );
+        assert!(!transport.free_user_ids.contains(&user_id));
+        assert_eq!(transport.connections.len(), 1);
+
+        sweep_until(
+            &mut transport,
+            quiet_since + Duration::from_millis(IDLE_TIMEOUT_MS) + 2 * sweep,
+        );
+        assert!(
+            transport.free_user_ids.contains(&user_id),
+            "connection still open {:?} after going quiet",
+            quiet_since.elapsed()
+        );
+        assert!(transport.connections.is_empty());
+    }
+
+    #[test]
+    fn test_resumed_client_paints_in_0rtt() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 17];
+        let mut transport = test_transport("0rtt", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let mut config = client_config();
+        config.enable_early_data();
+        let mut connect = || {
+            let scid: [u8; 16] = rand::random();
+            quiche::connect(
+                None,
+                &quiche::ConnectionId::from_ref(&scid),
+                peer,
+                local,
+                &mut config,
+            )
+            .unwrap()
+        };
+
+        // A full handshake; the ticket comes after it.
+        let mut clients = [(peer, connect())];
+        exchange(&mut transport, local, &mut clients);
+        let session = clients[0].1.session().unwrap().to_vec();
+        clients[0].1.close(true, 0, b"").unwrap();
+        exchange(&mut transport, local, &mut clients);
+        assert!(transport.connections.is_empty());
+        assert_eq!(metrics.handshakes_full.load(Ordering::Relaxed), 1);
+
+        // The reconnect sends a pixel with its Initial.
+        let mut client = connect();
+        client.set_session(&session).unwrap();
+        let mut payload = [0u8; 5];
+        payload[0..2].copy_from_slice(&7u16.to_le_bytes());
+        payload[2..4].copy_from_slice(&9u16.to_le_bytes());
+        payload[4] = 2;
+        client.dgram_send(&pixel::datagram(&payload)).unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut pkt = [0u8; 1500];
+        let mut early = Vec::new();
+        while let Ok((len, _)) = client.send(&mut pkt) {
+            if transport
+                .handle_incoming(&mut pkt[..len], peer, local, &mut out)
+                .is_some()
+            {
+                early.extend(out.iter().map(|p| (p.x, p.y, p.color)));
+            }
+        }
+        assert_eq!(early, [(7, 9, 2)]);
+        assert!(!transport.connections[0].1.is_established());
+        assert_eq!(metrics.early_data_pixels.load(Ordering::Relaxed), 1);
+
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients[0].1.is_established() && clients[0].1.is_resumed());
+        assert_eq!(metrics.handshakes_full.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.handshakes_resumed.load(Ordering::Relaxed), 1);
+        assert!(metrics.handshake_recv_full_ns.load(Ordering::Relaxed) > 0);
+        assert!(metrics.handshake_recv_resumed_ns.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_reconnect_is_told_the_cooldown_it_took_back() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 18];
+        let mut transport = test_transport("cooldown-config", metrics);
+        transport.reservations = Reservations::with_grace(60_000, metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut config = client_config();
+        config.set_initial_max_data(1 << 16);
+        config.set_initial_max_stream_data_bidi_local(1 << 16);
+        let mut connect = |peer| {
+            let scid: [u8; 16] = rand::random();
+            let conn = quiche::connect(
+                None,
+                &quiche::ConnectionId::from_ref(&scid),
+                peer,
+                local,
+                &mut config,
+            )
+            .unwrap();
+            (peer, conn)
+        };
+        let mut cooldown = CooldownArray::new();
+        let mut wheel = TimingWheel::new();
+
+        // A client paints, waits 100 s of its cooldown and drops.
+        let mut clients = [connect(SocketAddr::from(([10, 0, 0, 1], 5000)))];
+        exchange(&mut transport, local, &mut clients);
+        let user_id = transport.connections[0].0;
+        cooldown.set_cooldown(user_id);
+        wheel.add_cooldown(user_id);
+        for _ in 0..100 {
+            wheel.tick(&mut cooldown);
+        }
+        clients[0].1.close(true, 0, b"").unwrap();
+        exchange(&mut transport, local, &mut clients);
+        assert!(transport.connections.is_empty());
+
+        // It comes back from another port, and a stranger connects too.
+        let mut clients = [
+            connect(SocketAddr::from(([10, 0, 0, 1], 5001))),
+            connect(SocketAddr::from(([10, 0, 0, 2], 5000))),
+        ];
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(transport.connections[0].0, user_id);
+        for (_, client) in clients.iter_mut() {
+            client
+                .stream_send(0, &[ControlOp::CooldownConfig as u8], true)
+                .unwrap();
+        }
+        exchange(&mut transport, local, &mut clients);
+        transport.answer_cooldown_queries(7_400, |id| wheel.remaining_ms(id, 7_400));
+        exchange(&mut transport, local, &mut clients);
+
+        let replies: Vec<_> = clients
+            .iter_mut()
+            .map(|(_, client)| {
+                let mut buf = [0u8; 64];
+                let (len, fin) = client.stream_recv(0, &mut buf).unwrap();
+                assert!(fin);
+                assert_eq!(buf[0], ControlStatus::Ok as u8);
+                decode_cooldown_config(&buf[1..len]).unwrap()
+            })
+            .collect();
+        let cooldown_ms = TIMING_WHEEL_TICKS as u32 * 1000;
+        assert_eq!(
+            replies[0],
+            CooldownConfig {
+                cooldown_ms,
+                multiplier_permille: COOLDOWN_MULTIPLIER_ONE,
+                remaining_ms: cooldown_ms - 100_000 - 400,
+            }
+        );
+        assert_eq!(replies[1].remaining_ms, 0);
+    }
+
+    #[test]
+    fn test_cooldown_nacks_are_limited_per_connection() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 30];
+        let mut transport = test_transport("cooldown-nack", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut config = client_config();
+        let mut clients: Vec<_> = (1..=2)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, 0, i], 5000));
+                let scid: [u8; 16] = rand::random();
+                let client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut config,
+                )
+                .unwrap();
+                (peer, client)
+            })
+            .collect();
+        exchange(&mut transport, local, &mut clients);
+        let handles: Vec<ConnHandle> = clients
+            .iter()
+            .map(|(_, client)| {
+                let server_side = |(_, conn, _): &(u32, Connection, CidKey)| {
+                    conn.destination_id() == client.source_id()
+                };
+                transport.connections.iter().position(server_side).unwrap()
+            })
+            .collect();
+
+        // A flood from the first connection: one NACK for the first second.
+        for ms in [10_000, 10_400, 10_999] {
+            transport.nack_cooldown(handles[0], 250_500 - (ms - 10_000) as u32, ms);
+        }
+        transport.nack_cooldown(handles[1], 1, 10_400);
+        transport.nack_cooldown(handles[0], 249_000, 11_000);
+        exchange(&mut transport, local, &mut clients);
+
+        let received: Vec<Vec<u16>> = clients
+            .iter_mut()
+            .map(|(_, client)| {
+                let mut buf = [0u8; 64];
+                let mut secs = Vec::new();
+                while let Ok(len) = client.dgram_recv(&mut buf) {
+                    assert_eq!(buf[0], datagram::COOLDOWN_NACK);
+                    secs.push(nack::decode_cooldown_nack(&buf[1..len]).unwrap());
+                }
+                secs
+            })
+            .collect();
+        assert_eq!(received, [vec![251, 249], vec![1]]);
+        assert_eq!(metrics.cooldown_nacks.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.cooldown_nacks_limited.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_receipts_echo_the_pixel_seq() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 31];
+        let mut transport = test_transport("receipts", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let scid: [u8; 16] = rand::random();
+        let client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut client_config(),
+        )
+        .unwrap();
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+
+        let client = &mut clients[0].1;
+        client
+            .dgram_send(&receipt::seq_datagram(7, &[9, 0, 8, 0, 6]))
+            .unwrap();
+        // Too short for the number.
+        client.dgram_send(&[datagram::PIXEL_SEQ, 7]).unwrap();
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut pkt = [0u8; 1500];
+        let mut seqs = Vec::new();
+        while let Ok((len, _)) = clients[0].1.send(&mut pkt) {
+            if let Some(received) =
+                transport.handle_incoming(&mut pkt[..len], peer, local, &mut out)
+            {
+                for p in &out {
+                    assert_eq!((p.x, p.y, p.color), (9, 8, 6));
+                    seqs.push(p.seq);
+                    transport.ack_pixel(received.handle, p.seq);
+                }
+            }
+        }
+        assert_eq!(seqs, [Some(7)]);
+        assert_eq!(metrics.malformed_datagrams.load(Ordering::Relaxed), 1);
+        transport.ack_pixel(0, None);
+        exchange(&mut transport, local, &mut clients);
+
+        let mut buf = [0u8; 64];
+        let mut acks = Vec::new();
+        while let Ok(len) = clients[0].1.dgram_recv(&mut buf) {
+            assert_eq!(buf[0], datagram::PIXEL_ACK);
+            acks.push(receipt::decode_ack(&buf[1..len]).unwrap());
+        }
+        assert_eq!(acks, [Some(7), None]);
+        assert_eq!(metrics.pixel_acks.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_tightened_contract_is_pushed_before_it_is_enforced() {
+        let mut transport = test_transport("rate-contract", &WORKER_METRICS[MAX_WORKERS - 29]);
+        transport.slots.terms.base_secs = 60;
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let mut config = client_config();
+        config.set_initial_max_data(1 << 16);
+        config.set_initial_max_stream_data_bidi_local(1 << 16);
+        config.set_initial_max_stream_data_uni(1 << 16);
+        config.set_initial_max_streams_uni(4);
+        let scid: [u8; 16] = rand::random();
+        let conn = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut config,
+        )
+        .unwrap();
+        let mut clients = [(peer, conn)];
+        exchange(&mut transport, local, &mut clients);
+        let user_id = transport.connections[0].0;
+
+        // Negotiating CONTRACTS gets the first contract pushed.
+        let mut request = Vec::new();
+        encode_version_request(
+            &BuildInfo::current(Features::CONTRACTS, "test"),
+            &mut request,
+        );
+        clients[0].1.stream_send(0, &request, true).unwrap();
+        exchange(&mut transport, local, &mut clients);
+        let pushed = |client: &mut quiche::Connection, stream_id| {
+            let mut buf = [0u8; 64];
+            let (len, fin) = client.stream_recv(stream_id, &mut buf).unwrap();
+            assert!(fin);
+            decode_rate_contract_push(&buf[..len]).unwrap()
+        };
+        let first = pushed(&mut clients[0].1, 3);
+        assert_eq!(
+            (first.seq, first.interval_ms, first.effective_in_ms),
+            (1, 60_000, 0)
+        );
+
+        // The controller stretches the interval 1.5 times mid-session.
+        let now_ms = 1_000_000;
+        transport.refresh_contracts(1_500, now_ms);
+        transport.refresh_contracts(1_500, now_ms);
+        exchange(&mut transport, local, &mut clients);
+        let second = pushed(&mut clients[0].1, 7);
+        assert_eq!(
+            (second.seq, second.interval_ms, second.effective_in_ms),
+            (2, 90_000, CONTRACT_NOTICE_MS as u32)
+        );
+        assert_eq!(clients[0].1.readable().count(), 0);
+
+        // Pixels keep the old cooldown until the notice runs out.
+        let contract = &mut transport.slots.get_mut(user_id).contract;
+        assert_eq!(contract.enforced_secs(now_ms + CONTRACT_NOTICE_MS - 1), 60);
+        assert_eq!(contract.enforced_secs(now_ms + CONTRACT_NOTICE_MS), 90);
+    }
+
+    #[test]
+    fn test_cooldown_is_pushed_when_it_changes() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 35];
+        let mut transport = test_transport("cooldown-push", metrics);
+        transport.reservations = Reservations::with_grace(60_000, metrics);
+        transport.slots.terms.base_secs = 60;
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut config = client_config();
+        config.set_initial_max_data(1 << 16);
+        config.set_initial_max_stream_data_bidi_local(1 << 16);
+        config.set_initial_max_stream_data_uni(1 << 16);
+        config.set_initial_max_streams_uni(8);
+        let mut connect = |peer| {
+            let scid: [u8; 16] = rand::random();
+            let conn = quiche::connect(
+                None,
+                &quiche::ConnectionId::from_ref(&scid),
+                peer,
+                local,
+                &mut config,
+            )
+            .unwrap();
+            (peer, conn)
+        };
+        let mut request = Vec::new();
+        encode_version_request(
+            &BuildInfo::current(Features::CONTRACTS.union(Features::COOLDOWNS), "test"),
+            &mut request,
+        );
+        let pushed = |client: &mut quiche::Connection, stream_id| {
+            let mut buf = [0u8; 64];
+            let (len, fin) = client.stream_recv(stream_id, &mut buf).unwrap();
+            assert!(fin);
+            decode_server_push(&buf[..len]).unwrap()
+        };
+        let cooldown = |cooldown_ms, multiplier_permille, remaining_ms| {
+            ServerPush::Cooldown(CooldownConfig {
+                cooldown_ms,
+                multiplier_permille,
+                remaining_ms,
+            })
+        };
+        let mut cooldowns = CooldownArray::new();
+        let mut wheel = TimingWheel::new();
+
+        // VERSION gets the contract, then the cooldown once the worker
+        // fills it in.
+        let mut clients = [connect(SocketAddr::from(([10, 0, 0, 1], 5000)))];
+        exchange(&mut transport, local, &mut clients);
+        let user_id = transport.connections[0].0;
+        clients[0].1.stream_send(0, &request, true).unwrap();
+        exchange(&mut transport, local, &mut clients);
+        transport.answer_cooldown_queries(0, |id| wheel.remaining_ms(id, 0));
+        exchange(&mut transport, local, &mut clients);
+        assert!(matches!(
+            pushed(&mut clients[0].1, 3),
+            ServerPush::Contract(_)
+        ));
+        assert_eq!(
+            pushed(&mut clients[0].1, 7),
+            cooldown(60_000, COOLDOWN_MULTIPLIER_ONE, 0)
+        );
+
+        // It paints, drops 20 s later and comes back as if the server had
+        // restarted under it: the cooldown it takes back is pushed.
+        cooldowns.set_cooldown(user_id);
+        wheel.add_cooldown_for(user_id, 60);
+        for _ in 0..20 {
+            wheel.tick(&mut cooldowns);
+        }
+        clients[0].1.close(true, 0, b"").unwrap();
+        exchange(&mut transport, local, &mut clients);
+        assert!(transport.connections.is_empty());
+        let mut clients = [connect(SocketAddr::from(([10, 0, 0, 1], 5001)))];
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(transport.connections[0].0, user_id);
+        clients[0].1.stream_send(0, &request, true).unwrap();
+        exchange(&mut transport, local, &mut clients);
+        transport.answer_cooldown_queries(0, |id| wheel.remaining_ms(id, 0));
+        exchange(&mut transport, local, &mut clients);
+        assert!(matches!(
+            pushed(&mut clients[0].1, 3),
+            ServerPush::Contract(_)
+        ));
+        assert_eq!(
+            pushed(&mut clients[0].1, 7),
+            cooldown(60_000, COOLDOWN_MULTIPLIER_ONE, 40_000)
+        );
+
+        // A tightening is pushed as a contract at once, and as a cooldown
+        // only when it is enforced.
+        let now_ms = 1_000_000;
+        transport.refresh_contracts(1_500, now_ms);
+        transport.answer_cooldown_queries(now_ms, |_| 0);
+        exchange(&mut transport, local, &mut clients);
+        assert!(matches!(
+            pushed(&mut clients[0].1, 11),
+            ServerPush::Contract(_)
+        ));
+        assert_eq!(clients[0].1.readable().count(), 0);
+        let enforced_ms = now_ms + CONTRACT_NOTICE_MS;
+        transport.refresh_contracts(1_500, enforced_ms - 1);
+        transport.answer_cooldown_queries(enforced_ms - 1, |_| 0);
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(clients[0].1.readable().count(), 0);
+        transport.refresh_contracts(1_500, enforced_ms);
+        transport.answer_cooldown_queries(enforced_ms, |_| 0);
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(pushed(&mut clients[0].1, 15), cooldown(90_000, 1_500, 0));
+
+        // Easing applies at once, and both requests and pushes are
+        // answered in the order they were made.
+        clients[0]
+            .1
+            .stream_send(4, &[ControlOp::CooldownConfig as u8], true)
+            .unwrap();
+        exchange(&mut transport, local, &mut clients);
+        transport.refresh_contracts(1_000, enforced_ms + 1);
+        let mut answered = 0;
+        transport.answer_cooldown_queries(enforced_ms + 1, |_| {
+            answered += 1;
+            answered
+        });
+        exchange(&mut transport, local, &mut clients);
+        let mut buf = [0u8; 64];
+        let (len, fin) = clients[0].1.stream_recv(4, &mut buf).unwrap();
+        assert!(fin);
+        assert_eq!(buf[0], ControlStatus::Ok as u8);
+        assert_eq!(
+            decode_cooldown_config(&buf[1..len]).unwrap().remaining_ms,
+            1
+        );
+        assert!(matches!(
+            pushed(&mut clients[0].1, 19),
+            ServerPush::Contract(_)
+        ));
+        assert_eq!(
+            pushed(&mut clients[0].1, 23),
+            cooldown(60_000, COOLDOWN_MULTIPLIER_ONE, 2)
+        );
+    }
+
+    #[test]
+    fn test_broadcast_interval_is_pushed_when_it_changes() {
+        let mut transport = test_transport("interval-push", &WORKER_METRICS[MAX_WORKERS - 37]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let mut config = client_config();
+        config.set_initial_max_data(1 << 16);
+        config.set_initial_max_stream_data_bidi_local(1 << 16);
+        config.set_initial_max_stream_data_uni(1 << 16);
+        config.set_initial_max_streams_uni(4);
+        let scid: [u8; 16] = rand::random();
+        let conn = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut config,
+        )
+        .unwrap();
+        let mut clients = [(peer, conn)];
+        exchange(&mut transport, local, &mut clients);
+        let pushed = |client: &mut quiche::Connection, stream_id| {
+            let mut buf = [0u8; 64];
+            let (len, fin) = client.stream_recv(stream_id, &mut buf).unwrap();
+            assert!(fin);
+            decode_server_push(&buf[..len]).unwrap()
+        };
+
+        // Negotiating INTERVALS gets the interval the master is on.
+        let mut request = Vec::new();
+        encode_version_request(
+            &BuildInfo::current(Features::INTERVALS, "test"),
+            &mut request,
+        );
+        clients[0].1.stream_send(0, &request, true).unwrap();
+        exchange(&mut transport, local, &mut clients);
+        let current = crate::metrics::MASTER_METRICS
+            .effective_broadcast_interval_ms
+            .load(Ordering::Relaxed);
+        assert_eq!(
+            pushed(&mut clients[0].1, 3),
+            ServerPush::Interval(current as u32)
+        );
+
+        // The master stretches it: pushed once, however often the worker
+        // looks.
+        transport.refresh_interval(150);
+        transport.refresh_interval(150);
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(pushed(&mut clients[0].1, 7), ServerPush::Interval(150));
+        assert_eq!(clients[0].1.readable().count(), 0);
+    }
+
+    #[test]
+    fn test_accepted_connections_log_their_secrets() {
+        let mut transport = test_transport("keylog", &WORKER_METRICS[MAX_WORKERS - 19]);
+        let path = std::env::temp_dir().join(format!("canvas-keylog-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        transport.config.log_keys();
+        transport.keylog = Some(KeyLog::open(&path).unwrap());
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+
+        let mut clients: Vec<_> = (1..=2)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, 0, i], 5000));
+                let scid: [u8; 16] = rand::random();
+                let client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut client_config(),
+                )
+                .unwrap();
+                (peer, client)
+            })
+            .collect();
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients.iter().all(|(_, c)| c.is_established()));
+
+        // Both connections' TLS 1.3 secrets, one whole line each.
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut randoms = std::collections::HashSet::new();
+        for line in log.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3, "{}", line);
+            assert_eq!(fields[1].len(), 64, "{}", line);
+            randoms.insert(fields[1]);
+        }
+        assert_eq!(randoms.len(), 2);
+        for label in [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_TRAFFIC_SECRET_0",
+        ] {
+            let count = log.lines().filter(|l| l.starts_with(label)).count();
+            assert_eq!(count, 2, "{}", label);
+        }
+    }
+
+    #[test]
+    fn test_a_datagram_flood_is_read_in_shares() {
+        let mut transport = test_transport("dgram-shares", &WORKER_METRICS[MAX_WORKERS - 21]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut config = client_config();
+        config.enable_dgram(true, 128, 128);
+        let mut clients: Vec<_> = (1..=2)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, 0, i], 5000));
+                let scid: [u8; 16] = rand::random();
+                let client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut config,
+                )
+                .unwrap();
+                (peer, client)
+            })
+            .collect();
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients.iter().all(|(_, c)| c.is_established()));
+
+        let pixel = |x: u16| {
+            let mut payload = [0u8; 5];
+            payload[0..2].copy_from_slice(&x.to_le_bytes());
+            payload[2..4].copy_from_slice(&1u16.to_le_bytes());
+            payload[4] = 1;
+            pixel::datagram(&payload)
+        };
+        for x in 0..100 {
+            clients[0].1.dgram_send(&pixel(x)).unwrap();
+        }
+        clients[1].1.dgram_send(&pixel(500)).unwrap();
+
+        // The flood fits in one packet; only a share of it is read.
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut pkt = [0u8; 1500];
+        let mut flood = Vec::new();
+        let (peer, client) = &mut clients[0];
+        while let Ok((len, _)) = client.send(&mut pkt) {
+            if let Some(received) =
+                transport.handle_incoming(&mut pkt[..len], *peer, local, &mut out)
+            {
+                assert!(received.pixels <= MAX_DGRAMS_PER_RECV);
+                assert_eq!(received.dgrams_left, 100 - flood.len() - received.pixels);
+                flood.extend(out.iter().map(|p| p.x));
+            }
+        }
+        assert_eq!(flood.len(), MAX_DGRAMS_PER_RECV);
+        assert!(transport.has_backlog());
+
+        // The other connection's pixel is not held up behind the rest.
+        let (peer, client) = &mut clients[1];
+        let (len, _) = client.send(&mut pkt).unwrap();
+        let received = transport
+            .handle_incoming(&mut pkt[..len], *peer, local, &mut out)
+            .unwrap();
+        assert_eq!((received.pixels, received.dgrams_left), (1, 0));
+        assert_eq!(out[0].x, 500);
+
+        // Backlog passes read the rest, a share each, in order.
+        let mut passes = 0;
+        while transport.has_backlog() {
+            passes += 1;
+            transport.start_backlog_pass();
+            while let Some((received, from)) = transport.next_backlogged(&mut out) {
+                assert_eq!(from, clients[0].0);
+                assert!(received.pixels <= MAX_DGRAMS_PER_RECV);
+                flood.extend(out.iter().map(|p| p.x));
+            }
+        }
+        assert_eq!(flood, (0..100).collect::<Vec<u16>>());
+        assert_eq!(
+            passes,
+            (100 - MAX_DGRAMS_PER_RECV).div_ceil(MAX_DGRAMS_PER_RECV)
+        );
+    }
+
+    #[test]
+    fn test_a_spent_drain_budget_defers_to_the_next_pass() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 33];
+        let mut transport = test_transport("dgram-budget", metrics);
+        transport.drain_cap = 4;
+        transport.drain_budget = 8;
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mut config = client_config();
+        config.enable_dgram(true, 128, 128);
+        // The normal connection is accepted first, so it has the lowest
+        // handle and would be read last in every pass.
+        let mut clients: Vec<_> = (1..=3)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, 0, i], 5000));
+                let scid: [u8; 16] = rand::random();
+                let client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut config,
+                )
+                .unwrap();
+                (peer, client)
+            })
+            .collect();
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients.iter().all(|(_, c)| c.is_established()));
+
+        let pixel = |x: u16| {
+            let mut payload = [0u8; 5];
+            payload[0..2].copy_from_slice(&x.to_le_bytes());
+            payload[2..4].copy_from_slice(&1u16.to_le_bytes());
+            payload[4] = 1;
+            pixel::datagram(&payload)
+        };
+        for (i, (_, client)) in clients.iter_mut().enumerate() {
+            let count = if i == 0 { 8 } else { 64 };
+            for x in 0..count {
+                client.dgram_send(&pixel(x)).unwrap();
+            }
+        }
+        let normal_peer = clients[0].0;
+        let mut out = Vec::with_capacity(MAX_PIXELS_PER_PACKET);
+        let mut pkt = [0u8; 1500];
+        let mut normal = 0;
+        for (peer, client) in clients.iter_mut() {
+            while let Ok((len, _)) = client.send(&mut pkt) {
+                if let Some(received) =
+                    transport.handle_incoming(&mut pkt[..len], *peer, local, &mut out)
+                    && *peer == normal_peer
+                {
+                    normal += received.pixels;
+                }
+            }
+        }
+        assert_eq!(normal, 4);
+
+        // Each pass reads its budget and no more; the normal connection,
+        // deferred by the first, is read first in the second.
+        let deferred = metrics.dgram_backlog_deferred.load(Ordering::Relaxed);
+        let mut passes = 0;
+        let mut normal_done = None;
+        while transport.has_backlog() {
+            passes += 1;
+            transport.start_backlog_pass();
+            if passes == 1 {
+                let depth = metrics.dgram_backlog_connections.load(Ordering::Relaxed);
+                assert_eq!(depth, 3);
+            }
+            let mut read = 0;
+            while let Some((received, from)) = transport.next_backlogged(&mut out) {
+                read += received.pixels;
+                if from == normal_peer {
+                    normal += received.pixels;
+                    if normal == 8 {
+                        normal_done = Some(passes);
+                    }
+                }
+            }
+            assert!(read <= 8);
+        }
+        assert_eq!(normal_done, Some(2));
+        assert_eq!(passes, (8 + 2 * 64 - 3 * 4_usize).div_ceil(8));
+        assert!(metrics.dgram_backlog_deferred.load(Ordering::Relaxed) > deferred);
+    }
+
+    #[test]
+    fn test_single_and_batched_pixels_round_trip() {
+        use protocol::batch::{MAX_BATCH_PIXELS, PIXEL_RECORD_LEN, encode_batch};
+
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 26];
+        let mut transport = test_transport("batches", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let scid: [u8; 16] = rand::random();
+        let client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut client_config(),
+        )
+        .unwrap();
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+
+        let record = |x: u16| {
+            let mut r = [0u8; PIXEL_RECORD_LEN];
+            r[..2].copy_from_slice(&x.to_le_bytes());
+            r[2..4].copy_from_slice(&(x + 1).to_le_bytes());
+            r[4] = x as u8 % 32;
+            r
+        };
+        let client = &mut clients[0].1;
+        client.dgram_send(&pixel::datagram(&record(7))).unwrap();
+        let full: Vec<_> = (100..100 + MAX_BATCH_PIXELS as u16).map(record).collect();
+        let mut batch = Vec::new();
+        encode_batch(&full, &mut batch);
+        client.dgram_send(&batch).unwrap();
+        // A batch whose count disagrees with its length is dropped whole.
+        let mut bad = Vec::new();
+        encode_batch(&[record(9), record(10)], &mut bad);
+        bad[1] = 3;
+        client.dgram_send(&bad).unwrap();
+        batch.clear();
+        encode_batch(&[record(20), record(21)], &mut batch);
+        client.dgram_send(&batch).unwrap();
+
+        let pixels = exchange(&mut transport, local, &mut clients);
+        assert!(pixels.iter().all(|p| p.0 == pixels[0].0));
+        let expected: Vec<_> = [7]
+            .into_iter()
+            .chain(100..100 + MAX_BATCH_PIXELS as u16)
+            .chain([20, 21])
+            .map(|x| (x, x + 1, x as u8 % 32))
+            .collect();
+        let got: Vec<_> = pixels.iter().map(|&(_, x, y, c)| (x, y, c)).collect();
+        assert_eq!(got, expected);
+        assert_eq!(metrics.malformed_datagrams.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_datagrams_of_unknown_types_are_counted_not_parsed() {
+        let metrics = &WORKER_METRICS[MAX_WORKERS - 28];
+        let mut transport = test_transport("types", metrics);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let scid: [u8; 16] = rand::random();
+        let client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&scid),
+            peer,
+            local,
+            &mut client_config(),
+        )
+        .unwrap();
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+
+        let client = &mut clients[0].1;
+        // From a newer client: a new type, and a new tag.
+        client.dgram_send(&[0x7f, 1, 0, 2, 0, 3]).unwrap();
+        client
+            .dgram_send(&[datagram::TAGGED, b'N', b'E', b'W'])
+            .unwrap();
+        // Untyped, as before types: x 1 reads as PIXEL with a short body.
+        client.dgram_send(&[1, 0, 2, 0, 3]).unwrap();
+        client
+            .dgram_send(&[datagram::PIXEL_BATCH, 1, 4, 0, 5, 0])
+            .unwrap();
+        client
+            .dgram_send(&pixel::datagram(&[9, 0, 8, 0, 7]))
+            .unwrap();
+
+        let pixels = exchange(&mut transport, local, &mut clients);
+        let got: Vec<_> = pixels.iter().map(|&(_, x, y, c)| (x, y, c)).collect();
+        assert_eq!(got, [(9, 8, 7)]);
+        assert_eq!(metrics.unknown_datagrams.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.malformed_datagrams.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_sampled_connections_write_a_qlog_trace() {
+        let mut transport = test_transport("qlog", &WORKER_METRICS[MAX_WORKERS - 20]);
+        let dir = std::env::temp_dir().join(format!("canvas-qlog-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        transport.qlog = Some(QlogSampler::new(dir.clone(), 2));
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+
+        let mut clients: Vec<_> = (1..=3)
+            .map(|i| {
+                let peer = SocketAddr::from(([10, 0, 0, i], 5000));
+                let scid: [u8; 16] = rand::random();
+                let client = quiche::connect(
+                    None,
+                    &quiche::ConnectionId::from_ref(&scid),
+                    peer,
+                    local,
+                    &mut client_config(),
+                )
+                .unwrap();
+                (peer, client)
+            })
+            .collect();
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients.iter().all(|(_, c)| c.is_established()));
+
+        // The first and third connections accepted; dropping them flushes
+        // their traces.
+        let scids: Vec<Vec<u8>> = transport
+            .connections
+            .iter()
+            .map(|(_, conn, _)| conn.source_id().to_vec())
+            .collect();
+        drop(transport);
+        let traced: Vec<_> = scids
+            .iter()
+            .filter(|scid| crate::qlog::trace_path(&dir, scid).exists())
+            .collect();
+        assert_eq!(traced.len(), 2);
+        for scid in traced {
+            let trace = std::fs::read_to_string(crate::qlog::trace_path(&dir, scid)).unwrap();
+            // JSON-SEQ: each record starts with a record separator.
+            assert!(trace.starts_with("\u{1e}{"), "{}", trace);
+            assert!(trace.contains("\"qlog_version\""), "{}", trace);
+            assert!(trace.contains("canvas server"), "{}", trace);
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_raw_and_webtransport_clients_share_a_worker() {
+        let mut transport = test_transport("alpn", &WORKER_METRICS[0]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let raw_peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let browser_peer = SocketAddr::from(([10, 0, 0, 2], 5000));
+        let mut raw_config = client_config();
+        let mut browser_config = client_config();
+        browser_config.set_application_protos(&[b"h3"]).unwrap();
+        browser_config.set_initial_max_data(1 << 20);
+        browser_config.set_initial_max_stream_data_bidi_local(1 << 16);
+        browser_config.set_initial_max_stream_data_uni(1 << 16);
+        browser_config.set_initial_max_streams_uni(8);
+        let connect = |peer: SocketAddr, config: &mut quiche::Config| {
+            let scid: [u8; 16] = rand::random();
+            let scid = quiche::ConnectionId::from_ref(&scid);
+            quiche::connect(None, &scid, peer, local, config).unwrap()
+        };
+        let mut clients = [
+            (raw_peer, connect(raw_peer, &mut raw_config)),
+            (browser_peer, connect(browser_peer, &mut browser_config)),
+        ];
+
+        exchange(&mut transport, local, &mut clients);
+        assert!(clients.iter().all(|(_, client)| client.is_established()));
+        let (raw_id, browser_id) = (transport.connections[0].0, transport.connections[1].0);
+        assert_eq!(transport.slots.get(raw_id).protocol(), AppProtocol::Raw);
+        assert_eq!(
+            transport.slots.get(browser_id).protocol(),
+            AppProtocol::WebTransport(None)
+        );
+
+        // The browser opens its session and sends a pixel right behind the
+        // CONNECT; the raw client just sends one.
+        let browser = &mut clients[1].1;
+        let mut h3 = h3::Connection::with_transport(browser, &h3::Config::new().unwrap()).unwrap();
+        let request = [
+            h3::Header::new(b":method", b"CONNECT"),
+            h3::Header::new(b":protocol", b"webtransport"),
+            h3::Header::new(b":scheme", b"https"),
+            h3::Header::new(b":authority", b"localhost"),
+            h3::Header::new(b":path", b"/canvas"),
+        ];
+        let session_id = h3.send_request(browser, &request, false).unwrap();
+        let session = SessionPrefix::new(session_id);
+        let mut framed = Vec::new();
+        session.frame(&pixel::datagram(&[4, 0, 5, 0, 6]), &mut framed);
+        browser.dgram_send(&framed).unwrap();
+        // Unframed, it is not the browser's pixel.
+        browser
+            .dgram_send(&pixel::datagram(&[7, 0, 8, 0, 9]))
+            .unwrap();
+        clients[0]
+            .1
+            .dgram_send(&pixel::datagram(&[1, 0, 2, 0, 3]))
+            .unwrap();
+
+        let pixels = exchange(&mut transport, local, &mut clients);
+        assert_eq!(pixels.len(), 2);
+        assert!(pixels.contains(&(raw_id, 1, 2, 3)));
+        assert!(pixels.contains(&(browser_id, 4, 5, 6)));
+        assert_eq!(
+            transport.slots.get(browser_id).protocol(),
+            AppProtocol::WebTransport(Some(session))
+        );
+        let browser = &mut clients[1].1;
+        let mut status = None;
+        while let Ok((stream_id, event)) = h3.poll(browser) {
+            if let h3::Event::Headers { list, .. } = event {
+                assert_eq!(stream_id, session_id);
+                status = list
+                    .iter()
+                    .find(|h| h.name() == b":status")
+                    .map(|h| h.value().to_vec());
+            }
+        }
+        assert_eq!(status.as_deref(), Some(&b"200"[..]));
+
+        // One broadcast chunk, typed and framed for each.
+        for (user_id, conn, _) in transport.connections.iter_mut() {
+            let protocol = transport.slots.get(*user_id).protocol();
+            protocol
+                .dgram_send_typed(conn, datagram::DIFF, b"diff", &mut framed)
+                .unwrap();
+        }
+        exchange(&mut transport, local, &mut clients);
+        assert_eq!(clients[0].1.dgram_recv_vec().unwrap(), b"\x11diff");
+        let dgram = clients[1].1.dgram_recv_vec().unwrap();
+        assert_eq!(session.strip(&dgram), Some(&b"\x11diff"[..]));
+        assert!(clients.iter_mut().all(|(_, c)| c.dgram_recv_vec().is_err()));
+    }
+
+    #[test]
+    fn test_collect_stats_counts_connections_by_state() {
+        let mut transport = test_transport("quic-stats", &WORKER_METRICS[0]);
+        let local: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 5000));
+        let scid: [u8; 16] = rand::random();
+        let scid = quiche::ConnectionId::from_ref(&scid);
+        let client = quiche::connect(None, &scid, peer, local, &mut client_config()).unwrap();
+        let mut clients = [(peer, client)];
+        exchange(&mut transport, local, &mut clients);
+        // A second client that never gets past its Initial.
+        let mut out = Vec::new();
+        for (mut initial, from) in client_initials(1, local) {
+            transport.handle_incoming(&mut initial, from, local, &mut out);
+        }
+
+        let stats = transport.collect_stats();
+        assert_eq!(
+            (stats.established, stats.handshaking, stats.draining),
+            (1, 1, 0)
+        );
+        assert!(stats.sent >= 2 && stats.recv >= 2);
+        assert!(stats.rtt_min_us <= stats.rtt_median_us);
+        assert_eq!(stats.rtt_median_us, stats.rtt_max_us);
+        assert!(stats.cwnd_mean_bytes > 0);
+
+        // The server hangs up (a control stream or WebTransport error): the
+        // connection drains once its close is sent. One closed by the peer
+        // is removed as soon as the close arrives, and never counts.
+        let mut pkt = [0u8; 1500];
+        let (_, conn, _) = transport
+            .connections
+            .iter_mut()
+            .find(|(_, conn, _)| conn.is_established())
+            .unwrap();
+        conn.close(true, 0, b"").unwrap();
+        conn.send(&mut pkt).unwrap();
+        let stats = transport.collect_stats();
+        assert_eq!(
+            (stats.established, stats.handshaking, stats.draining),
+            (0, 1, 1)
+        );
+        assert_eq!(stats.cwnd_mean_bytes, 0);
     }
 }
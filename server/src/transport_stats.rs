@@ -0,0 +1,91 @@
+//! A worker's view of its connections as quiche sees them: packets sent,
+//! received and lost, RTT and congestion window, and how many connections
+//! are in each state. `TransportState::collect_stats` builds one every
+//! TRANSPORT_STATS_INTERVAL_MS from the worker's maintenance pass, and the
+//! worker publishes it in its WorkerMetrics (`canvas_worker_quic_*` on the
+//! dashboard).
+//!
+//! Packet counts are sums over the connections alive at the sample, not
+//! counters: a connection that closes takes its own with it. Compare
+//! `lost` to `sent` within one sample.
+
+use crate::metrics::WorkerMetrics;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerTransportStats {
+    pub handshaking: u64,
+    pub established: u64,
+    /// Closed by this side and waiting out the drain period. A connection
+    /// the peer closes is removed as soon as the close arrives.
+    pub draining: u64,
+    pub sent: u64,
+    pub recv: u64,
+    pub lost: u64,
+    pub retrans: u64,
+    /// Smoothed RTT of the established connections' active paths (µs).
+    pub rtt_min_us: u64,
+    pub rtt_median_us: u64,
+    pub rtt_max_us: u64,
+    /// Mean congestion window of the same paths (bytes).
+    pub cwnd_mean_bytes: u64,
+}
+
+impl WorkerTransportStats {
+    /// Fills the RTT and cwnd fields from one RTT per established path
+    /// (reordered) and the sum of their congestion windows.
+    pub fn summarize_paths(&mut self, rtts_us: &mut [u64], cwnd_sum: u64) {
+        if rtts_us.is_empty() {
+            return;
+        }
+        let mid = rtts_us.len() / 2;
+        let (below, &mut median, above) = rtts_us.select_nth_unstable(mid);
+        self.rtt_median_us = median;
+        self.rtt_min_us = below.iter().copied().min().unwrap_or(median);
+        self.rtt_max_us = above.iter().copied().max().unwrap_or(median);
+        self.cwnd_mean_bytes = cwnd_sum / rtts_us.len() as u64;
+    }
+
+    pub fn publish(&self, metrics: &WorkerMetrics) {
+        for (gauge, value) in [
+            (&metrics.quic_handshaking, self.handshaking),
+            (&metrics.quic_established, self.established),
+            (&metrics.quic_draining, self.draining),
+            (&metrics.quic_packets_sent, self.sent),
+            (&metrics.quic_packets_recv, self.recv),
+            (&metrics.quic_packets_lost, self.lost),
+            (&metrics.quic_packets_retrans, self.retrans),
+            (&metrics.quic_rtt_min_us, self.rtt_min_us),
+            (&metrics.quic_rtt_median_us, self.rtt_median_us),
+            (&metrics.quic_rtt_max_us, self.rtt_max_us),
+            (&metrics.quic_cwnd_mean_bytes, self.cwnd_mean_bytes),
+        ] {
+            gauge.store(value, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_summary() {
+        let mut stats = WorkerTransportStats::default();
+        stats.summarize_paths(&mut [], 0);
+        assert_eq!(stats, WorkerTransportStats::default());
+
+        stats.summarize_paths(&mut [900, 150, 40_000, 300, 200], 5 * 12_000);
+        assert_eq!(
+            (stats.rtt_min_us, stats.rtt_median_us, stats.rtt_max_us),
+            (150, 300, 40_000)
+        );
+        assert_eq!(stats.cwnd_mean_bytes, 12_000);
+
+        stats.summarize_paths(&mut [700], 1_350);
+        assert_eq!(
+            (stats.rtt_min_us, stats.rtt_median_us, stats.rtt_max_us),
+            (700, 700, 700)
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! The worker's TX items: pre-allocated sendmsg slots, each either free,
+//! leased to the code filling it, or in flight until its CQE comes back.
+//!
+//! Every transition goes through here. A send path takes a [`TxLease`];
+//! it either submits the item (in flight until [`TxPool::complete`]) or
+//! drops the lease, which frees the item. No path pushes an index back by
+//! hand, so an early `break` or `continue` cannot lose one.
+//!
+//! Bugs are still caught rather than trusted away:
+//!
+//! - each item carries a generation byte, bumped on every lease and carried
+//!   in the SQE's user_data, so a completion for an item that is not in
+//!   flight, or for an earlier use of it, is refused;
+//! - an item the free list hands out that is not free (an index pushed
+//!   twice) is skipped instead of being filled while in use;
+//! - [`TxPool::audit`], once per loop, checks that free plus in-flight items
+//!   add up to the capacity. If they drift, the free list is rebuilt from
+//!   the items' states.
+//!
+//! Each fault counts in `tx_faults`; debug builds panic on it instead.
+
+use crate::const_settings::{DGRAM_MAX_SEND_SIZE, TAG_OUTGOING_UDP};
+use crate::metrics::WorkerMetrics;
+use std::sync::atomic::Ordering;
+
+pub struct TxItem {
+    pub buf: [u8; DGRAM_MAX_SEND_SIZE],
+    pub addr: libc::sockaddr_in,
+    pub iov: libc::iovec,
+    pub msghdr: libc::msghdr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxState {
+    Free,
+    Leased,
+    InFlight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxFault {
+    /// The free list handed out an item that was not free.
+    NotFree(usize),
+    /// A completion for an item that was not in flight, or for an earlier
+    /// use of it.
+    StaleCompletion(usize),
+    /// Free and in-flight items do not add up to the capacity.
+    Drift { free: usize, in_flight: usize },
+}
+
+pub struct TxPool {
+    items: Box<[TxItem]>,
+    free: Vec<usize>,
+    states: Box<[TxState]>,
+    generations: Box<[u8]>,
+    in_flight: usize,
+    /// Panic on a fault instead of counting and repairing it.
+    strict: bool,
+    metrics: &'static WorkerMetrics,
+}
+
+impl TxPool {
+    pub fn new(capacity: usize, metrics: &'static WorkerMetrics) -> Self {
+        assert!(
+            capacity < 1 << 24,
+            "TX item indices take 24 bits of user_data"
+        );
+        let mut items = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            // SAFETY: TxItem is a plain-data struct (no padding that must be
+            // non-zero, no Drop impl). Using zeroed() avoids constructing the
+            // ~1,600-byte struct as a stack temporary before moving it into the
+            // Vec, which causes a stack overflow in debug builds (65,536 iters
+            // × ~1,600 bytes each quickly exhausts the 8 MB main-thread stack).
+            items.push(unsafe { std::mem::zeroed::<TxItem>() });
+        }
+        // Popped from the back: item 0 goes out first.
+        let free = (0..capacity).rev().collect();
+        Self {
+            items: Self::prepare(items),
+            free,
+            states: vec![TxState::Free; capacity].into_boxed_slice(),
+            generations: vec![0; capacity].into_boxed_slice(),
+            in_flight: 0,
+            strict: cfg!(debug_assertions),
+            metrics,
+        }
+    }
+
+    /// Points each item's msghdr at its own sockaddr/iovec and the iovec at
+    /// its buffer. The boxed slice never moves, so only the destination and
+    /// length change per packet.
+    fn prepare(items: Vec<TxItem>) -> Box<[TxItem]> {
+        let mut items = items.into_boxed_slice();
+        for item in items.iter_mut() {
+            item.iov.iov_base = item.buf.as_mut_ptr() as *mut _;
+            item.msghdr.msg_name = &mut item.addr as *mut _ as *mut _;
+            item.msghdr.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as _;
+            item.msghdr.msg_iov = &mut item.iov;
+            item.msghdr.msg_iovlen = 1;
+        }
+        items
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    /// A free item, until the lease is submitted or dropped.
+    pub fn lease(&mut self) -> Option<TxLease<'_>> {
+        let idx = loop {
+            let idx = self.free.pop()?;
+            if self.states[idx] == TxState::Free {
+                break idx;
+            }
+            self.fault(TxFault::NotFree(idx));
+        };
+        self.states[idx] = TxState::Leased;
+        self.generations[idx] = self.generations[idx].wrapping_add(1);
+        Some(TxLease {
+            pool: self,
+            idx,
+            submitted: false,
+        })
+    }
+
+    /// Frees the item of a send CQE's `user_data`.
+    pub fn complete(&mut self, user_data: u64) {
+        let idx = ((user_data >> 8) & 0xff_ffff) as usize;
+        let generation = (user_data >> 32) as u8;
+        let in_flight = self.states.get(idx) == Some(&TxState::InFlight);
+        if !in_flight || self.generations[idx] != generation {
+            self.fault(TxFault::StaleCompletion(idx));
+            return;
+        }
+        self.states[idx] = TxState::Free;
+        self.in_flight -= 1;
+        self.free.push(idx);
+    }
+
+    /// Checks that every item is either free or in flight; rebuilds the
+    /// free list from the items' states if not. Once per loop.
+    pub fn audit(&mut self) {
+        let (free, in_flight) = (self.free.len(), self.in_flight);
+        if free + in_flight == self.items.len() {
+            return;
+        }
+        self.fault(TxFault::Drift { free, in_flight });
+        self.free.clear();
+        self.in_flight = 0;
+        for (idx, state) in self.states.iter().enumerate().rev() {
+            match state {
+                TxState::Free => self.free.push(idx),
+                TxState::InFlight => self.in_flight += 1,
+                TxState::Leased => unreachable!("TX item {} leased across an audit", idx),
+            }
+        }
+        self.metrics.tx_rebuilds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cold]
+    fn fault(&self, fault: TxFault) {
+        self.metrics.tx_faults.fetch_add(1, Ordering::Relaxed);
+        if self.strict {
+            panic!("TX item accounting: {:?}", fault);
+        }
+    }
+}
+
+/// An item taken from the pool. Dropping it frees the item again.
+pub struct TxLease<'a> {
+    pool: &'a mut TxPool,
+    idx: usize,
+    submitted: bool,
+}
+
+impl TxLease<'_> {
+    pub fn item(&mut self) -> &mut TxItem {
+        &mut self.pool.items[self.idx]
+    }
+
+    /// Marks the item in flight and hands it to `push` with the user_data
+    /// its CQE must carry back to [`TxPool::complete`].
+    pub fn submit(mut self, push: impl FnOnce(&TxItem, u64)) {
+        let pool = &mut *self.pool;
+        pool.states[self.idx] = TxState::InFlight;
+        pool.in_flight += 1;
+        let user_data = TAG_OUTGOING_UDP
+            | ((self.idx as u64) << 8)
+            | ((pool.generations[self.idx] as u64) << 32);
+        self.submitted = true;
+        push(&pool.items[self.idx], user_data);
+    }
+}
+
+impl Drop for TxLease<'_> {
+    fn drop(&mut self) {
+        if !self.submitted {
+            self.pool.states[self.idx] = TxState::Free;
+            self.pool.free.push(self.idx);
+        }
+    }
+}
+
+#[cfg(test)]
+impl TxPool {
+    /// Loses a free item, as a missed push would.
+    fn leak_one(&mut self) {
+        self.free.pop();
+    }
+
+    /// Pushes `idx` onto the free list whatever its state, as an extra push
+    /// would.
+    fn push_extra(&mut self, idx: usize) {
+        self.free.push(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::const_settings::MAX_WORKERS;
+    use crate::metrics::WORKER_METRICS;
+
+    fn pool(slot: usize) -> TxPool {
+        let mut pool = TxPool::new(4, &WORKER_METRICS[MAX_WORKERS - slot]);
+        pool.strict = false;
+        pool
+    }
+
+    fn submit(pool: &mut TxPool) -> u64 {
+        let mut user_data = 0;
+        pool.lease().unwrap().submit(|_, u| user_data = u);
+        user_data
+    }
+
+    fn faults(pool: &TxPool) -> u64 {
+        pool.metrics.tx_faults.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_leases_free_themselves_unless_submitted() {
+        let mut pool = pool(22);
+        let user_data = submit(&mut pool);
+        assert_eq!(user_data & 0xff, TAG_OUTGOING_UDP);
+        {
+            let mut lease = pool.lease().unwrap();
+            lease.item().buf[0] = 1;
+            // A send path that bails out just drops the lease.
+        }
+        assert_eq!((pool.free.len(), pool.in_flight), (3, 1));
+        pool.complete(user_data);
+        assert_eq!((pool.free.len(), pool.in_flight), (4, 0));
+        pool.audit();
+        assert_eq!(faults(&pool), 0);
+    }
+
+    #[test]
+    fn test_stale_and_double_completions_are_refused() {
+        let mut pool = pool(23);
+        let first = submit(&mut pool);
+        pool.complete(first);
+        pool.complete(first);
+        assert_eq!(faults(&pool), 1);
+
+        // Item 0 again, one generation on: the old user_data must not free
+        // it while the new send is in flight.
+        let second = submit(&mut pool);
+        assert_eq!((first >> 8) & 0xff_ffff, (second >> 8) & 0xff_ffff);
+        assert_ne!(first, second);
+        pool.complete(first);
+        assert_eq!(faults(&pool), 2);
+        assert_eq!(pool.in_flight, 1);
+        pool.complete(second);
+        pool.audit();
+        assert_eq!((pool.free.len(), pool.in_flight, faults(&pool)), (4, 0, 2));
+    }
+
+    #[test]
+    fn test_leaked_and_extra_pushes_are_repaired() {
+        let mut pool = pool(24);
+        let metrics = pool.metrics;
+
+        // A missed push: the audit finds the item and frees it.
+        pool.leak_one();
+        pool.audit();
+        assert_eq!(faults(&pool), 1);
+        assert_eq!(metrics.tx_rebuilds.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.free.len(), 4);
+
+        // An extra push of an item in flight: it is not handed out twice.
+        let user_data = submit(&mut pool);
+        pool.push_extra(0);
+        let (mut leased, mut sent) = (Vec::new(), Vec::new());
+        while let Some(lease) = pool.lease() {
+            leased.push(lease.idx);
+            lease.submit(|_, u| sent.push(u));
+        }
+        assert_eq!(leased, [1, 2, 3]);
+        assert_eq!(faults(&pool), 2);
+        for u in sent {
+            pool.complete(u);
+        }
+
+        // An extra push of a free item: the audit drops the duplicate.
+        pool.push_extra(1);
+        pool.audit();
+        assert_eq!(faults(&pool), 3);
+        assert_eq!((pool.free.len(), pool.in_flight), (3, 1));
+        pool.complete(user_data);
+        pool.audit();
+        assert_eq!((pool.free.len(), faults(&pool)), (4, 3));
+        assert_eq!(metrics.tx_rebuilds.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "TX item accounting: StaleCompletion(0)")]
+    fn test_debug_builds_panic_on_a_fault() {
+        let mut pool = TxPool::new(4, &WORKER_METRICS[MAX_WORKERS - 25]);
+        let user_data = submit(&mut pool);
+        pool.complete(user_data);
+        pool.complete(user_data);
+    }
+}
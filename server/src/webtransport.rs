@@ -0,0 +1,260 @@
+//! WebTransport for browsers (`protocol::webtransport`). Raw clients and
+//! browsers share the port; which one a connection is follows from its
+//! ALPN, read once when the handshake completes and kept in its slot
+//! (conn_slot.rs) as an [`AppProtocol`].
+//!
+//! Connections that negotiated `h3` get an HTTP/3 connection on top of
+//! QUIC. Its only job is the session: the server answers one extended
+//! CONNECT to SESSION_PATH with 200 and from then on reads and writes the
+//! connection's datagrams behind the session prefix. Other requests get an
+//! error status. The connection is closed when the browser ends the
+//! session (the CONNECT stream finishes or is reset).
+//!
+//! Raw connections never touch any of this: their streams belong to
+//! control.rs.
+
+use crate::conn_slot::ConnSlots;
+use protocol::datagram;
+use protocol::webtransport::{RAW_ALPN, SESSION_PATH, SessionPrefix};
+use quiche::Connection;
+use quiche::h3::{self, NameValue};
+use rustc_hash::FxHashMap;
+use std::collections::hash_map::Entry;
+
+/// SETTINGS_ENABLE_WEBTRANSPORT (draft-02), still what Chrome checks.
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b60_3742;
+/// SETTINGS_WEBTRANSPORT_MAX_SESSIONS (draft-07 and later).
+const SETTINGS_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671_706a;
+
+const H3_NO_ERROR: u64 = 0x100;
+const H3_INTERNAL_ERROR: u64 = 0x102;
+
+/// ALPNs the server offers, in no particular order: the client picks.
+pub const APPLICATION_PROTOS: [&[u8]; 2] = [b"h3", RAW_ALPN];
+
+/// How a connection carries the canvas protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AppProtocol {
+    /// Datagrams as they are. Also what a connection counts as until its
+    /// handshake completes, when it cannot send or receive any.
+    #[default]
+    Raw,
+    /// HTTP/3, with the WebTransport session once the browser opened it.
+    /// Nothing goes out before that.
+    WebTransport(Option<SessionPrefix>),
+}
+
+impl AppProtocol {
+    /// What an established connection negotiated.
+    pub fn of(conn: &Connection) -> Self {
+        if conn.application_proto() == b"h3" {
+            Self::WebTransport(None)
+        } else {
+            Self::Raw
+        }
+    }
+
+    /// The payload of a received datagram, or None if it is not for the
+    /// canvas: on an HTTP/3 connection, anything outside the session.
+    #[inline(always)]
+    pub fn unframe<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        match self {
+            Self::Raw => Some(datagram),
+            Self::WebTransport(Some(session)) => session.strip(datagram),
+            Self::WebTransport(None) => None,
+        }
+    }
+
+    /// Queues `payload` as a datagram on `conn`, framed for it. `framed` is
+    /// scratch space.
+    #[inline(always)]
+    pub fn dgram_send(
+        &self,
+        conn: &mut Connection,
+        payload: &[u8],
+        framed: &mut Vec<u8>,
+    ) -> quiche::Result<()> {
+        match self {
+            Self::Raw => conn.dgram_send(payload),
+            Self::WebTransport(Some(session)) => {
+                session.frame(payload, framed);
+                conn.dgram_send(framed)
+            }
+            Self::WebTransport(None) => Err(quiche::Error::InvalidState),
+        }
+    }
+
+    /// Like `dgram_send`, for a `body` that goes out behind the type byte
+    /// `kind` (protocol::datagram), such as a slice of a snapshot.
+    #[inline(always)]
+    pub fn dgram_send_typed(
+        &self,
+        conn: &mut Connection,
+        kind: u8,
+        body: &[u8],
+        framed: &mut Vec<u8>,
+    ) -> quiche::Result<()> {
+        match self {
+            Self::Raw => framed.clear(),
+            Self::WebTransport(Some(session)) => session.frame(&[], framed),
+            Self::WebTransport(None) => return Err(quiche::Error::InvalidState),
+        }
+        datagram::push_typed(kind, body, framed);
+        conn.dgram_send(framed)
+    }
+}
+
+/// One worker's HTTP/3 connections, by user id.
+pub struct WebTransport {
+    config: h3::Config,
+    connections: FxHashMap<u32, h3::Connection>,
+}
+
+impl Default for WebTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebTransport {
+    pub fn new() -> Self {
+        let mut config = h3::Config::new().unwrap();
+        config.enable_extended_connect(true);
+        config
+            .set_additional_settings(vec![
+                (SETTINGS_ENABLE_WEBTRANSPORT, 1),
+                (SETTINGS_WEBTRANSPORT_MAX_SESSIONS, 1),
+            ])
+            .unwrap();
+        Self {
+            config,
+            connections: FxHashMap::default(),
+        }
+    }
+
+    /// Handles what the browser sent on its streams. Call for established
+    /// WebTransport connections, before reading their datagrams, so the ones that
+    /// arrive with the CONNECT are not dropped.
+    pub fn service(&mut self, user_id: u32, conn: &mut Connection, slots: &mut ConnSlots) {
+        let h3 = match self.connections.entry(user_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match h3::Connection::with_transport(conn, &self.config) {
+                Ok(h3) => entry.insert(h3),
+                Err(_) => {
+                    let _ = conn.close(true, H3_INTERNAL_ERROR, b"");
+                    return;
+                }
+            },
+        };
+        loop {
+            let session = match slots.get(user_id).protocol() {
+                AppProtocol::WebTransport(Some(session)) => Some(session.session_id()),
+                _ => None,
+            };
+            match h3.poll(conn) {
+                Ok((stream_id, h3::Event::Headers { list, .. })) => {
+                    let status = if session.is_some() {
+                        429
+                    } else {
+                        connect_status(&list)
+                    };
+                    if status == 200 {
+                        slots.opened_session(user_id, SessionPrefix::new(stream_id));
+                    }
+                    let headers = [
+                        h3::Header::new(b":status", status.to_string().as_bytes()),
+                        h3::Header::new(b"sec-webtransport-http3-draft", b"draft02"),
+                    ];
+                    let _ = h3.send_response(conn, stream_id, &headers, status != 200);
+                }
+                // Capsules on the CONNECT stream, or bodies nobody asked
+                // for: read and dropped.
+                Ok((stream_id, h3::Event::Data)) => {
+                    let mut sink = [0; 512];
+                    while h3.recv_body(conn, stream_id, &mut sink).is_ok() {}
+                }
+                Ok((stream_id, h3::Event::Finished | h3::Event::Reset(_)))
+                    if session == Some(stream_id) =>
+                {
+                    let _ = conn.close(true, H3_NO_ERROR, b"");
+                    return;
+                }
+                Ok((_, h3::Event::GoAway)) => {
+                    let _ = conn.close(true, H3_NO_ERROR, b"");
+                    return;
+                }
+                Ok(_) => {}
+                // Protocol errors have closed the connection already.
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// The connection of `user_id` is gone.
+    pub fn forget(&mut self, user_id: u32) {
+        self.connections.remove(&user_id);
+    }
+}
+
+/// The status for a request: 200 for a WebTransport CONNECT to the canvas.
+fn connect_status(headers: &[h3::Header]) -> u16 {
+    if header(headers, b":method") != b"CONNECT" || header(headers, b":protocol") != b"webtransport"
+    {
+        return 405;
+    }
+    let path = header(headers, b":path");
+    let path = path.split(|&b| b == b'?').next().unwrap_or_default();
+    if path != SESSION_PATH.as_bytes() {
+        return 404;
+    }
+    200
+}
+
+/// The value of header `name`, empty if it is missing.
+fn header<'a>(headers: &'a [h3::Header], name: &[u8]) -> &'a [u8] {
+    headers
+        .iter()
+        .find(|h| h.name() == name)
+        .map_or(&[][..], |h| h.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_webtransport_connects_to_the_canvas_open_sessions() {
+        let request = |method: &str, protocol: Option<&str>, path: &str| {
+            let mut headers = vec![
+                h3::Header::new(b":method", method.as_bytes()),
+                h3::Header::new(b":scheme", b"https"),
+                h3::Header::new(b":authority", b"canvas.example"),
+                h3::Header::new(b":path", path.as_bytes()),
+            ];
+            if let Some(protocol) = protocol {
+                headers.push(h3::Header::new(b":protocol", protocol.as_bytes()));
+            }
+            connect_status(&headers)
+        };
+        assert_eq!(request("CONNECT", Some("webtransport"), "/canvas"), 200);
+        assert_eq!(request("CONNECT", Some("webtransport"), "/canvas?v=2"), 200);
+        assert_eq!(request("CONNECT", Some("webtransport"), "/canvas/x"), 404);
+        assert_eq!(request("CONNECT", Some("websocket"), "/canvas"), 405);
+        assert_eq!(request("CONNECT", None, "/canvas"), 405);
+        assert_eq!(request("GET", None, "/canvas"), 405);
+    }
+
+    #[test]
+    fn test_datagrams_outside_the_session_are_dropped() {
+        let session = SessionPrefix::new(8);
+        let pixel = [1, 2, 3, 4, 5];
+        let mut framed = Vec::new();
+        session.frame(&pixel, &mut framed);
+
+        let browser = AppProtocol::WebTransport(Some(session));
+        assert_eq!(AppProtocol::Raw.unframe(&pixel), Some(&pixel[..]));
+        assert_eq!(browser.unframe(&framed), Some(&pixel[..]));
+        assert_eq!(browser.unframe(&pixel), None);
+        assert_eq!(AppProtocol::WebTransport(None).unframe(&framed), None);
+    }
+}
@@ -1,45 +1,149 @@
-use crate::canvas::{CanvasBuffer, CompressedBuffer};
+use crate::admission::{TokenFilter, admit_pixel};
+use crate::affinity;
+use crate::bandwidth::{BANDWIDTH_LIMITS, BroadcastBudget, Verdict};
+use crate::canvas::{CompressedBuffer, SnapshotDiff};
+use crate::config::ServerConfig;
+use crate::conn_memory::ConnMemory;
 use crate::const_settings::{
     BROADCAST_CHUNK_SIZE, CONN_TIMEOUT_THROTTLE_MS, DGRAM_MAX_SEND_SIZE,
-    DIFF_BUFFER_INITIAL_CAPACITY, FULL_BROADCAST_INTERVAL, IO_URING_BGID, IO_URING_NUM_BUFFERS,
-    IO_URING_SQ_DEPTH, MSG_CONTROL_LEN, PKT_BUF_SIZE, SOCKET_RECV_BUF_SIZE, SOCKET_SEND_BUF_SIZE,
-    TAG_INCOMING_UDP, TAG_OUTGOING_UDP, TX_CAPACITY,
+    DIFF_BUFFER_INITIAL_CAPACITY, HANDSHAKE_NUM_BUFFERS, IO_URING_BGID, IO_URING_HANDSHAKE_BGID,
+    IO_URING_NUM_BUFFERS, IO_URING_SQ_DEPTH, MAX_CONNECTIONS_PER_WORKER, MAX_PIXELS_PER_PACKET,
+    MSG_CONTROL_LEN, PKT_BUF_SIZE, SOCKET_RECV_BUF_SIZE, SOCKET_SEND_BUF_SIZE,
+    TAG_INCOMING_HANDSHAKE, TAG_INCOMING_UDP, TAG_OUTGOING_UDP, TRANSPORT_STATS_INTERVAL_MS,
+    TX_CAPACITY, WORKER_LOOP_BUDGET_MS,
 };
 use crate::cooldown::CooldownArray;
+use crate::diff_dict::{CompressedDiff, DiffCompressor};
+use crate::fast_diff::{FAST_RING, FastReader};
+use crate::fec::FecBroadcast;
+use crate::full_broadcast::FullBroadcastSchedule;
+use crate::half_open::HalfOpen;
+use crate::junk::JunkFilter;
 use crate::master::PixelWrite;
-use crate::spsc::SpscRingBuffer;
+use crate::metrics::{MASTER_METRICS, WORKER_METRICS, WorkerMetrics};
+use crate::pixel_trace::{self, Fate, PixelSampler};
+use crate::profiler::{PHASE_MARKERS, Phase, PhaseMarker};
+use crate::qlog::QlogSampler;
+use crate::rate_contract;
+use crate::recv_lane::{self, RecvLane};
+use crate::retry::RetryTokens;
+use crate::runtime_config::{WORKER_FACTS, WorkerFacts};
+use crate::spsc::SharedRing;
 use crate::timing_wheel::TimingWheel;
-use crate::transport::TransportState;
+use crate::transport::{PixelDatagram, Received, TransportState};
+use crate::tx_pool::{TxItem, TxPool};
 #[cfg(target_os = "linux")]
 use io_uring::{IoUring, opcode, types};
+use protocol::datagram::{self, TYPE_LEN};
+use protocol::epoch::{EPOCH_LEN, encode_epoch};
+use protocol::version::Features;
 use socket2::{Domain, Protocol, Socket, Type};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
-pub struct TxItem {
-    pub buf: [u8; DGRAM_MAX_SEND_SIZE],
-    pub addr: libc::sockaddr_in,
-    pub iov: libc::iovec,
-    pub msghdr: libc::msghdr,
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+/// Destination sockaddr prepared once per connection; rebuilt only when
+/// quiche reports a different peer address (migration).
+#[derive(Clone, Copy)]
+pub struct CachedDest {
+    to: Option<SocketAddrV4>,
+    sockaddr: libc::sockaddr_in,
+}
+
+impl CachedDest {
+    pub fn new() -> Self {
+        Self {
+            to: None,
+            // SAFETY: sockaddr_in is plain data; all-zero is a valid value.
+            sockaddr: unsafe { std::mem::zeroed() },
+        }
+    }
+
+    /// Returns the sockaddr for `to`, rebuilding it only if the address
+    /// changed since the last packet. None for non-IPv4 peers.
+    #[inline(always)]
+    pub fn get(&mut self, to: SocketAddr) -> Option<&libc::sockaddr_in> {
+        let SocketAddr::V4(v4) = to else {
+            return None;
+        };
+        if self.to != Some(v4) {
+            self.sockaddr.sin_family = libc::AF_INET as u16;
+            self.sockaddr.sin_port = v4.port().to_be();
+            self.sockaddr.sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+            self.to = Some(v4);
+        }
+        Some(&self.sockaddr)
+    }
+}
+
+impl Default for CachedDest {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub struct WorkerCore {
-    master_queue: Arc<SpscRingBuffer<PixelWrite>>,
+    metrics: &'static WorkerMetrics,
+    facts: &'static WorkerFacts,
+    /// The loop phase this worker is in, for `--profile-hz` (profiler.rs).
+    phase: &'static PhaseMarker,
+    master_queue: SharedRing<PixelWrite>,
     cooldown_master: CooldownArray,
     timing_wheel: Box<TimingWheel>,
-    port: u16,
-    buffer_slab: Vec<u8>,
+    /// Idempotency tokens of recent pixels, to drop retransmits.
+    pixel_tokens: TokenFilter,
+    /// Broadcast bytes this worker and each connection may still send.
+    bandwidth: BroadcastBudget,
+    /// Its socket is bound in `new`, so the reuseport group is in worker id
+    /// order.
+    #[cfg(target_os = "linux")]
+    rx: RecvLane,
+    /// Long-header packets, with `--handshake-reserve` (recv_lane.rs).
+    #[cfg(target_os = "linux")]
+    handshake: Option<RecvLane>,
     transport: TransportState,
+    /// Reused for every incoming packet so parsing pixels never allocates.
+    pixels_scratch: Vec<PixelDatagram>,
+    pixel_sampler: PixelSampler,
+    /// Stage name in pixel-trace lines.
+    trace_label: String,
+    strict_affinity: bool,
     framing: Framing,
-    last_broadcast_index: usize,
-    tx_items: Box<[TxItem]>,
-    tx_free_indices: Vec<usize>,
+    /// Publish sequence of the snapshot clients last received; diffs are
+    /// taken against that pool slot directly.
+    last_sent_seq: u64,
+    /// The epoch pixels are admitted in: the last one seen (epoch.rs).
+    epoch: u32,
+    tx: TxPool,
+    /// Indexed by user id.
+    dest_cache: Box<[CachedDest]>,
     msghdr: Box<libc::msghdr>,
-    last_sent_canvas: Box<[u8; crate::const_settings::CANVAS_SIZE]>,
-    local_canvas: Box<CanvasBuffer>,
     local_compressed: Box<CompressedBuffer>,
-    broadcast_ticks: u32,
+    full_schedule: FullBroadcastSchedule,
     diff_buffer: Vec<u8>,
+    /// `--diff-cap-bytes`, usize::MAX for no cap: `diff_buffer` never grows
+    /// past it.
+    diff_cap_bytes: usize,
+    /// `diff_buffer` narrowed to one connection's tile subscription.
+    filtered_diff: Vec<u8>,
+    /// One datagram behind a WebTransport session prefix.
+    framed: Vec<u8>,
+    /// When the maintenance pass next samples quiche's statistics.
+    next_transport_stats_ms: u64,
+    /// None without `--diff-dict`.
+    diff_compressor: Option<DiffCompressor>,
+    /// `diff_buffer` and `filtered_diff` compressed, for connections that
+    /// negotiated ZSTD.
+    zstd_diff: CompressedDiff,
+    zstd_filtered: CompressedDiff,
+    /// The last full broadcast as FEC datagrams, for connections that
+    /// negotiated FEC.
+    fec_broadcast: FecBroadcast,
+    /// Micro-diffs for tier-1 connections; None without `--tier1-tokens`.
+    fast_reader: Option<FastReader>,
+    fast_diff: Vec<u8>,
+    /// Connection indices of the tier-1 connections, gathered per pass.
+    tier1_indices: Vec<usize>,
 }
 
 unsafe impl Send for WorkerCore {}
@@ -122,69 +226,145 @@ impl Framing {
 }
 
 impl WorkerCore {
-    pub fn new(master_queue: Arc<SpscRingBuffer<PixelWrite>>, port: u16) -> Self {
-        let mut tx_items = Vec::with_capacity(TX_CAPACITY);
-        let mut tx_free_indices = Vec::with_capacity(TX_CAPACITY);
-        for i in 0..TX_CAPACITY {
-            // SAFETY: TxItem is a plain-data struct (no padding that must be
-            // non-zero, no Drop impl). Using zeroed() avoids constructing the
-            // ~1,600-byte struct as a stack temporary before moving it into the
-            // Vec, which causes a stack overflow in debug builds (65,536 iters
-            // × ~1,600 bytes each quickly exhausts the 8 MB main-thread stack).
-            tx_items.push(unsafe { std::mem::zeroed::<TxItem>() });
-            tx_free_indices.push(i);
-        }
-
+    pub fn new(
+        worker_id: usize,
+        master_queue: SharedRing<PixelWrite>,
+        config: &ServerConfig,
+    ) -> Self {
+        let port = config.port;
         Self {
+            metrics: &WORKER_METRICS[worker_id],
+            facts: &WORKER_FACTS[worker_id],
+            phase: &PHASE_MARKERS[worker_id],
             master_queue,
             cooldown_master: CooldownArray::new(),
             timing_wheel: Box::new(TimingWheel::new()),
-            port,
-            buffer_slab: vec![0; PKT_BUF_SIZE * (IO_URING_NUM_BUFFERS as usize)],
-            transport: TransportState::new(),
+            pixel_tokens: TokenFilter::new(),
+            bandwidth: BroadcastBudget::new(
+                MAX_CONNECTIONS_PER_WORKER,
+                &BANDWIDTH_LIMITS,
+                &WORKER_METRICS[worker_id],
+            ),
+            #[cfg(target_os = "linux")]
+            rx: RecvLane::new(
+                Self::setup_socket(port),
+                IO_URING_NUM_BUFFERS,
+                IO_URING_BGID,
+                TAG_INCOMING_UDP,
+            ),
+            #[cfg(target_os = "linux")]
+            handshake: None,
+            transport: {
+                let mut transport = TransportState::new(
+                    &config.cert_path,
+                    &config.key_path,
+                    &WORKER_METRICS[worker_id],
+                );
+                transport.memory = ConnMemory::new(config.conn_memory, &WORKER_METRICS[worker_id]);
+                transport.half_open = HalfOpen::new(config.half_open, &WORKER_METRICS[worker_id]);
+                transport.junk = JunkFilter::new(config.junk, &WORKER_METRICS[worker_id]);
+                transport.retry = config
+                    .quic_retry
+                    .then(|| RetryTokens::new(config.retry_token_ttl_ms));
+                transport.qlog = config
+                    .qlog_dir
+                    .as_ref()
+                    .map(|dir| QlogSampler::new(dir.clone(), config.qlog_sample));
+                transport
+                    .config
+                    .set_max_idle_timeout(config.idle_timeout_ms);
+                transport.slots.terms.base_secs = config.cooldown_secs;
+                transport.drain_cap = config.dgram_drain_cap;
+                transport.drain_budget = config.dgram_drain_budget;
+                transport
+            },
+            pixels_scratch: Vec::with_capacity(MAX_PIXELS_PER_PACKET),
+            pixel_sampler: PixelSampler::new(config.trace_pixels),
+            trace_label: format!("worker{}", worker_id),
+            strict_affinity: config.strict_affinity,
             framing: Framing::new(port),
-            last_broadcast_index: 0,
-            tx_items: tx_items.into_boxed_slice(),
-            tx_free_indices,
+            // Baseline is whatever the master seeded (the background), not zeros;
+            // otherwise a non-zero background would be resent as a giant diff.
+            last_sent_seq: crate::canvas::PUBLISH_SEQ.load(Ordering::Acquire),
+            epoch: crate::epoch::current(),
+            tx: TxPool::new(TX_CAPACITY, &WORKER_METRICS[worker_id]),
+            dest_cache: vec![CachedDest::new(); MAX_CONNECTIONS_PER_WORKER].into_boxed_slice(),
             msghdr: Box::new(unsafe {
                 let mut msghdr: libc::msghdr = std::mem::zeroed();
                 msghdr.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as _;
                 msghdr.msg_controllen = MSG_CONTROL_LEN as _; // Enough for IP_PKTINFO
                 msghdr
             }),
-            last_sent_canvas: vec![0; crate::const_settings::CANVAS_SIZE]
-                .into_boxed_slice()
-                .try_into()
-                .unwrap(),
-            local_canvas: unsafe {
-                let layout = std::alloc::Layout::new::<CanvasBuffer>();
-                let ptr = std::alloc::alloc_zeroed(layout) as *mut CanvasBuffer;
-                Box::from_raw(ptr)
-            },
             local_compressed: unsafe {
                 let layout = std::alloc::Layout::new::<CompressedBuffer>();
                 let ptr = std::alloc::alloc_zeroed(layout) as *mut CompressedBuffer;
                 Box::from_raw(ptr)
             },
-            broadcast_ticks: 0,
+            full_schedule: FullBroadcastSchedule::new(worker_id),
             diff_buffer: Vec::with_capacity(DIFF_BUFFER_INITIAL_CAPACITY),
+            diff_cap_bytes: match config.diff_cap_bytes {
+                0 => usize::MAX,
+                cap => cap,
+            },
+            filtered_diff: Vec::with_capacity(DIFF_BUFFER_INITIAL_CAPACITY),
+            framed: Vec::with_capacity(DGRAM_MAX_SEND_SIZE),
+            next_transport_stats_ms: 0,
+            diff_compressor: DiffCompressor::new(),
+            zstd_diff: CompressedDiff::default(),
+            zstd_filtered: CompressedDiff::default(),
+            fec_broadcast: FecBroadcast::default(),
+            fast_reader: crate::fast_diff::enabled().then(|| FastReader::new(&FAST_RING)),
+            fast_diff: Vec::with_capacity(BROADCAST_CHUNK_SIZE),
+            tier1_indices: Vec::new(),
         }
     }
 
-    pub fn run(mut self, core_id: usize) {
-        if core_affinity::set_for_current(core_affinity::CoreId { id: core_id }) {
-            // pinned
-        }
+    /// Pins the thread and serves. `ready` is signalled once the ring is
+    /// set up and the first receive armed: nothing after that needs root
+    /// or a syscall the sandbox refuses (sandbox.rs).
+    pub fn run(mut self, core_id: usize, ready: Sender<()>) {
+        let pinned = affinity::pin_current(&self.trace_label, core_id, self.strict_affinity);
+        self.metrics.pinned.store(pinned as u64, Ordering::Relaxed);
+        self.facts.core_id.store(core_id, Ordering::Relaxed);
 
         #[cfg(target_os = "linux")]
-        self.run_linux();
+        self.run_linux(ready);
 
         #[cfg(not(target_os = "linux"))]
-        println!("Worker core only supported via io_uring on Linux.");
+        {
+            let _ = ready.send(());
+            println!("Worker core only supported via io_uring on Linux.");
+        }
+    }
+
+    /// The worker's member of the SO_REUSEPORT group (see `steering`).
+    #[cfg(target_os = "linux")]
+    pub fn socket(&self) -> &Socket {
+        &self.rx.socket
     }
 
+    /// Binds the handshake socket. Called for each worker in id order once
+    /// every data socket has bound, so it lands at `workers + id` in the
+    /// group (see `steering`).
     #[cfg(target_os = "linux")]
-    fn setup_socket(&self) -> Socket {
+    pub fn bind_handshake_socket(&mut self, port: u16) {
+        self.handshake = Some(RecvLane::new(
+            Self::setup_socket(port),
+            HANDSHAKE_NUM_BUFFERS,
+            IO_URING_HANDSHAKE_BGID,
+            TAG_INCOMING_HANDSHAKE,
+        ));
+    }
+
+    /// Leaves the reuseport group again when the reserve program was
+    /// refused; handshakes then share the data lane.
+    #[cfg(target_os = "linux")]
+    pub fn close_handshake_socket(&mut self) {
+        self.handshake = None;
+    }
+
+    #[cfg(target_os = "linux")]
+    fn setup_socket(port: u16) -> Socket {
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
         unsafe {
             let opt: libc::c_int = 1;
@@ -215,7 +395,7 @@ impl WorkerCore {
             );
         }
 
-        let addr: std::net::SocketAddr = format!("0.0.0.0:{}", self.port).parse().unwrap();
+        let addr: std::net::SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
 
         // Increase Kernel UDP buffers
         socket.set_recv_buffer_size(SOCKET_RECV_BUF_SIZE).unwrap();
@@ -238,12 +418,12 @@ impl WorkerCore {
     }
 
     #[cfg(target_os = "linux")]
-    fn provide_initial_buffers(&mut self, ring: &mut IoUring) {
+    fn provide_initial_buffers(lane: &mut RecvLane, ring: &mut IoUring) {
         let provide_bufs_sqe = opcode::ProvideBuffers::new(
-            self.buffer_slab.as_mut_ptr(),
+            lane.slab.as_mut_ptr(),
             PKT_BUF_SIZE as i32,
-            IO_URING_NUM_BUFFERS as u16,
-            IO_URING_BGID,
+            lane.num_buffers,
+            lane.bgid,
             0,
         )
         .build()
@@ -263,47 +443,175 @@ impl WorkerCore {
         if now_sec > *last_tick_sec {
             // Execute O(1) tick mass eviction
             self.timing_wheel.tick(&mut self.cooldown_master);
+            self.transport
+                .refresh_contracts(rate_contract::load_permille(), crate::time::CLOCK.now_ms());
+            let interval_ms = MASTER_METRICS
+                .effective_broadcast_interval_ms
+                .load(Ordering::Relaxed);
+            self.transport.refresh_interval(interval_ms as u32);
+            self.answer_cooldown_queries();
+            if self.transport.reservations.enabled() {
+                self.transport
+                    .sweep_reservations(crate::time::CLOCK.now_ms());
+                self.release_freed_ids();
+            }
             *last_tick_sec = now_sec;
         }
     }
 
+    /// Forgets the cooldown, pixel tokens and bandwidth of every user id the
+    /// transport freed since the last call.
+    #[cfg(target_os = "linux")]
+    fn release_freed_ids(&mut self) {
+        let (wheel, cooldown) = (&mut self.timing_wheel, &mut self.cooldown_master);
+        let (tokens, bandwidth) = (&mut self.pixel_tokens, &mut self.bandwidth);
+        self.transport.drain_released(|user_id| {
+            wheel.release(cooldown, user_id);
+            tokens.forget(user_id);
+            bandwidth.forget(user_id);
+        });
+    }
+
     #[cfg(target_os = "linux")]
     fn handle_broadcast(&mut self) {
+        // Before the sequence: a new epoch's first snapshot is published
+        // before the epoch is.
+        let epoch = crate::epoch::current();
         // We need Acquire ordering to ensure memory visibility of the canvas buffers updated by the master thread (which uses Release).
-        let current_active = crate::canvas::ACTIVE_INDEX.load(std::sync::atomic::Ordering::Acquire);
-        if current_active == self.last_broadcast_index {
+        let current_seq = crate::canvas::PUBLISH_SEQ.load(Ordering::Acquire);
+        if epoch != self.epoch {
+            self.start_epoch(epoch, current_seq);
+            return;
+        }
+        // Micro-diffs cut before this snapshot go out ahead of it, those cut
+        // after it once it has been sent (see fast_diff.rs).
+        self.forward_fast_diffs(current_seq);
+        let now_ms = crate::time::CLOCK.now_ms();
+        if current_seq == self.last_sent_seq {
+            // Nothing new from the master: a due full broadcast goes out
+            // with the snapshot clients already have.
+            if current_seq > 0 && self.full_schedule.due(now_ms) {
+                self.broadcast_full_or_skip(current_seq, now_ms);
+            }
+            self.forward_fast_diffs(current_seq + 1);
             return;
         }
 
-        self.last_broadcast_index = current_active;
-        self.broadcast_ticks += 1;
+        let last_sent_seq = std::mem::replace(&mut self.last_sent_seq, current_seq);
 
-        if self.should_broadcast_full() {
-            self.broadcast_full_canvas(current_active);
-        } else {
-            self.broadcast_canvas_diff(current_active);
+        if self.full_schedule.due(now_ms) || !self.broadcast_canvas_diff(last_sent_seq, current_seq)
+        {
+            self.broadcast_full_or_skip(current_seq, now_ms);
         }
+        self.forward_fast_diffs(current_seq + 1);
+    }
+
+    /// The master started `epoch` with a snapshot no later than `seq`:
+    /// every cooldown is lifted, and `seq` goes out in full, whether or not
+    /// full broadcasts are suspended. A diff would patch the old canvas.
+    #[cfg(target_os = "linux")]
+    fn start_epoch(&mut self, epoch: u32, seq: u64) {
+        self.epoch = epoch;
+        self.timing_wheel.reset(&mut self.cooldown_master);
+        self.transport.push_cooldowns();
+        self.answer_cooldown_queries();
+        self.forward_fast_diffs(seq);
+        self.last_sent_seq = seq;
+        self.broadcast_full_canvas(seq);
+        self.forward_fast_diffs(seq + 1);
     }
 
+    /// Sends snapshot `seq` in full, unless the master has suspended full
+    /// broadcasts (full_broadcast.rs): then clients that missed a diff
+    /// catch up over their control stream instead.
     #[cfg(target_os = "linux")]
-    fn should_broadcast_full(&self) -> bool {
-        self.broadcast_ticks == 1 || self.broadcast_ticks % FULL_BROADCAST_INTERVAL == 0
+    fn broadcast_full_or_skip(&mut self, seq: u64, now_ms: u64) {
+        if !crate::full_broadcast::diff_only() {
+            self.broadcast_full_canvas(seq);
+            return;
+        }
+        self.full_schedule.skipped(now_ms);
+        self.transport.stats.next_full_due_ms = u64::MAX;
+        self.metrics
+            .full_broadcasts_suppressed
+            .fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Sends every micro-diff cut before snapshot `before_seq` was published
+    /// to the tier-1 connections. Bandwidth caps do not apply: the seats
+    /// per worker are what bound this.
     #[cfg(target_os = "linux")]
-    fn broadcast_full_canvas(&mut self, active_index: usize) {
-        let (len, new_canvas) = unsafe {
-            let len = crate::canvas::COMPRESSED_LENS[active_index];
-            let canvas = &crate::canvas::BUFFER_POOL[active_index].data;
-            (len, canvas)
+    fn forward_fast_diffs(&mut self, before_seq: u64) {
+        let Some(reader) = &mut self.fast_reader else {
+            return;
         };
+        let transport = &mut self.transport;
+        if transport.slots.tier1_count() == 0 {
+            reader.skip(&FAST_RING);
+            return;
+        }
+        let lapped = reader.lapped;
+        let mut gathered = false;
+        let mut queued_bytes = 0;
+        while reader.next(&FAST_RING, before_seq, &mut self.fast_diff) {
+            if !gathered {
+                self.tier1_indices.clear();
+                self.tier1_indices.extend(
+                    transport
+                        .connections
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (user_id, _, _))| transport.slots.get(*user_id).is_tier1())
+                        .map(|(index, _)| index),
+                );
+                gathered = true;
+            }
+            for &index in &self.tier1_indices {
+                let (user_id, conn, _) = &mut transport.connections[index];
+                let slot = transport.slots.get(*user_id);
+                let protocol = slot.protocol();
+                let mask = slot.subscription();
+                let diff = if *mask == crate::canvas::ALL_TILES {
+                    &self.fast_diff
+                } else {
+                    self.filtered_diff.clear();
+                    crate::canvas::filter_diff(&self.fast_diff, mask, &mut self.filtered_diff);
+                    &self.filtered_diff
+                };
+                if !diff.is_empty()
+                    && protocol
+                        .dgram_send_typed(conn, datagram::DIFF, diff, &mut self.framed)
+                        .is_ok()
+                {
+                    queued_bytes += TYPE_LEN + diff.len();
+                }
+            }
+        }
+        if reader.lapped > lapped {
+            self.metrics
+                .fast_diff_laps
+                .fetch_add(reader.lapped - lapped, Ordering::Relaxed);
+        }
+        if queued_bytes > 0 {
+            self.metrics
+                .fast_diff_bytes
+                .fetch_add(queued_bytes as u64, Ordering::Relaxed);
+            self.metrics
+                .broadcast_bytes
+                .fetch_add(queued_bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn broadcast_full_canvas(&mut self, seq: u64) {
+        let active_index = crate::canvas::snapshot_slot(seq);
+        let len = unsafe { crate::canvas::COMPRESSED_LENS[active_index] };
 
         // NOTE: use heap-allocated local_compressed to avoid ~2MB stack frame
         unsafe {
             self.local_compressed.data[..len]
                 .copy_from_slice(&crate::canvas::COMPRESSED_BUFFER_POOL[active_index].data[..len]);
         }
-        self.last_sent_canvas.copy_from_slice(new_canvas);
 
         #[cfg(feature = "debug-logs")]
         println!(
@@ -311,42 +619,114 @@ impl WorkerCore {
             len
         );
 
-        for (_, conn, _) in self.transport.connections.values_mut() {
-            for chunk in self.local_compressed.data[..len].chunks(BROADCAST_CHUNK_SIZE) {
-                let _ = conn.dgram_send(chunk);
+        let mut queued_bytes = 0;
+        let mut parity_bytes = 0;
+        // Encoded for the first FEC connection that gets this broadcast.
+        let mut fec_ready = false;
+        // Ahead of the snapshot on connections that negotiated EPOCHS.
+        let epoch = encode_epoch(self.epoch);
+        let now_ms = crate::time::CLOCK.now_ms();
+        self.full_schedule.sent(now_ms);
+        self.transport.stats.next_full_due_ms = self.full_schedule.next_due_ms();
+        let transport = &mut self.transport;
+        let n = transport.connections.len();
+        let start = self.bandwidth.begin(now_ms, n, true);
+        for i in 0..n {
+            let index = (start + i) % n;
+            let (user_id, conn, _) = &mut transport.connections[index];
+            let slot = transport.slots.get(*user_id);
+            let protocol = slot.protocol();
+            let fec =
+                slot.features().contains(Features::FEC) && crate::fec::features() == Features::FEC;
+            if fec && !fec_ready {
+                self.fec_broadcast
+                    .encode(seq as u32, &self.local_compressed.data[..len]);
+                fec_ready = true;
+            }
+            let epochs = slot.features().contains(Features::EPOCHS);
+            let mut wire_len = if fec {
+                self.fec_broadcast.wire_len()
+            } else {
+                len + len.div_ceil(BROADCAST_CHUNK_SIZE) * TYPE_LEN
+            };
+            if epochs {
+                wire_len += EPOCH_LEN;
+            }
+            if !self.bandwidth.full_sync(*user_id, wire_len) {
+                self.bandwidth.stop_at(index, n - i, true);
+                break;
+            }
+            let before = queued_bytes;
+            if epochs && protocol.dgram_send(conn, &epoch, &mut self.framed).is_ok() {
+                queued_bytes += EPOCH_LEN;
+            }
+            if fec {
+                for datagram in self.fec_broadcast.datagrams() {
+                    if protocol
+                        .dgram_send(conn, datagram, &mut self.framed)
+                        .is_ok()
+                    {
+                        queued_bytes += datagram.len();
+                    }
+                }
+                parity_bytes += self.fec_broadcast.parity_bytes();
+            } else {
+                for chunk in self.local_compressed.data[..len].chunks(BROADCAST_CHUNK_SIZE) {
+                    if protocol
+                        .dgram_send_typed(conn, datagram::FULL_RLE, chunk, &mut self.framed)
+                        .is_ok()
+                    {
+                        queued_bytes += TYPE_LEN + chunk.len();
+                    }
+                }
+            }
+            if queued_bytes > before
+                && let Some(wait) = transport.slots.first_broadcast(*user_id, now_ms)
+            {
+                self.metrics.first_broadcast_ms.record(wait);
             }
         }
+        self.metrics
+            .broadcast_bytes
+            .fetch_add(queued_bytes as u64, Ordering::Relaxed);
+        if parity_bytes > 0 {
+            self.metrics
+                .fec_parity_bytes
+                .fetch_add(parity_bytes as u64, Ordering::Relaxed);
+        }
     }
 
     #[cfg(target_os = "linux")]
-    fn broadcast_canvas_diff(&mut self, active_index: usize) {
+    /// Returns false if the last sent snapshot has been recycled, or the
+    /// diff outgrew `--diff-cap-bytes`, in which case nothing was sent and
+    /// clients need a full broadcast instead. With full broadcasts
+    /// suspended, they catch up over their control stream (TILE_REFRESH).
+    fn broadcast_canvas_diff(&mut self, last_sent_seq: u64, current_seq: u64) -> bool {
         self.diff_buffer.clear();
 
-        // NOTE: use heap-allocated local_canvas to avoid ~1MB stack frame
-        unsafe {
-            self.local_canvas
-                .data
-                .copy_from_slice(&crate::canvas::BUFFER_POOL[active_index].data)
-        };
-
-        for (i, (&new_pixel, old_pixel)) in self
-            .local_canvas
-            .data
-            .iter()
-            .zip(self.last_sent_canvas.iter_mut())
-            .enumerate()
-        {
-            if *old_pixel != new_pixel {
-                // Changed cell: [u32 index, u8 color]
-                self.diff_buffer
-                    .extend_from_slice(&(i as u32).to_le_bytes());
-                self.diff_buffer.push(new_pixel);
-                *old_pixel = new_pixel;
+        match crate::canvas::diff_snapshots(
+            last_sent_seq,
+            current_seq,
+            self.diff_cap_bytes,
+            &mut self.diff_buffer,
+        ) {
+            SnapshotDiff::Built => {}
+            SnapshotDiff::Recycled => {
+                self.metrics
+                    .recycled_diff_fallbacks
+                    .fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            SnapshotDiff::OverCap => {
+                self.metrics
+                    .diff_cap_fallbacks
+                    .fetch_add(1, Ordering::Relaxed);
+                return false;
             }
         }
 
         if self.diff_buffer.is_empty() {
-            return;
+            return true;
         }
 
         #[cfg(feature = "debug-logs")]
@@ -355,125 +735,395 @@ impl WorkerCore {
             self.diff_buffer.len()
         );
 
-        for (_, conn, _) in self.transport.connections.values_mut() {
-            for chunk in self.diff_buffer.chunks(BROADCAST_CHUNK_SIZE) {
-                let _ = conn.dgram_send(chunk);
+        let mut queued_bytes = 0;
+        let mut zstd_raw_bytes = 0;
+        let mut zstd_wire_bytes = 0;
+        // The whole diff is compressed once, for the first ZSTD connection
+        // that wants all of it.
+        let mut zstd_diff_ready = false;
+        let now_ms = crate::time::CLOCK.now_ms();
+        let transport = &mut self.transport;
+        let n = transport.connections.len();
+        let start = self.bandwidth.begin(now_ms, n, false);
+        'connections: for i in 0..n {
+            let index = (start + i) % n;
+            let (user_id, conn, _) = &mut transport.connections[index];
+            let before = queued_bytes;
+            let slot = transport.slots.get(*user_id);
+            let protocol = slot.protocol();
+            let mask = slot.subscription();
+            let all_tiles = *mask == crate::canvas::ALL_TILES;
+            let diff = if all_tiles {
+                &self.diff_buffer
+            } else {
+                self.filtered_diff.clear();
+                crate::canvas::filter_diff(&self.diff_buffer, mask, &mut self.filtered_diff);
+                &self.filtered_diff
+            };
+            let compressed = match &mut self.diff_compressor {
+                Some(compressor) if slot.features().contains(Features::ZSTD) => {
+                    if !all_tiles {
+                        compressor.compress(diff, &mut self.zstd_filtered);
+                        Some(&self.zstd_filtered)
+                    } else {
+                        if !zstd_diff_ready {
+                            compressor.compress(diff, &mut self.zstd_diff);
+                            zstd_diff_ready = true;
+                        }
+                        Some(&self.zstd_diff)
+                    }
+                }
+                _ => None,
+            };
+            let raw = if compressed.is_some() { &[][..] } else { diff };
+            let datagrams = compressed
+                .into_iter()
+                .flat_map(CompressedDiff::datagrams)
+                .chain(raw.chunks(BROADCAST_CHUNK_SIZE).map(|c| (c, c.len())));
+            for (dgram, raw_len) in datagrams {
+                match self.bandwidth.diff_chunk(*user_id, dgram.len()) {
+                    Verdict::Send => {}
+                    Verdict::ConnThrottled => continue,
+                    Verdict::WorkerThrottled => {
+                        self.bandwidth.stop_at(index, n - i, false);
+                        break 'connections;
+                    }
+                }
+                // Compressed diffs are tagged datagrams already.
+                let sent = if compressed.is_some() {
+                    protocol.dgram_send(conn, dgram, &mut self.framed)
+                } else {
+                    protocol.dgram_send_typed(conn, datagram::DIFF, dgram, &mut self.framed)
+                };
+                if sent.is_ok() {
+                    if compressed.is_some() {
+                        queued_bytes += dgram.len();
+                        zstd_raw_bytes += raw_len;
+                        zstd_wire_bytes += dgram.len();
+                    } else {
+                        queued_bytes += TYPE_LEN + dgram.len();
+                    }
+                }
             }
+            if queued_bytes > before
+                && let Some(wait) = transport.slots.first_broadcast(*user_id, now_ms)
+            {
+                self.metrics.first_broadcast_ms.record(wait);
+            }
+        }
+        self.metrics
+            .broadcast_bytes
+            .fetch_add(queued_bytes as u64, Ordering::Relaxed);
+        if zstd_wire_bytes > 0 {
+            self.metrics
+                .zstd_diff_raw_bytes
+                .fetch_add(zstd_raw_bytes as u64, Ordering::Relaxed);
+            self.metrics
+                .zstd_diff_wire_bytes
+                .fetch_add(zstd_wire_bytes as u64, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// The lane whose completions carry `tag`.
+    #[cfg(target_os = "linux")]
+    fn lane_mut(&mut self, tag: u64) -> Option<&mut RecvLane> {
+        match tag {
+            TAG_INCOMING_HANDSHAKE => self.handshake.as_mut(),
+            _ => Some(&mut self.rx),
         }
     }
 
     #[cfg(target_os = "linux")]
-    fn handle_incoming_cqe(&mut self, ring: &mut IoUring, flags: u32, fd_types: types::Fd) {
+    fn handle_incoming_cqe(&mut self, tag: u64, flags: u32) {
+        let lane = match tag {
+            TAG_INCOMING_HANDSHAKE => match self.handshake.as_mut() {
+                Some(lane) => lane,
+                None => return,
+            },
+            _ => &mut self.rx,
+        };
+        if !io_uring::cqueue::more(flags) {
+            lane.armed = false;
+        }
         let buffer_id = match io_uring::cqueue::buffer_select(flags) {
             Some(id) => id,
             None => return,
         };
 
         let offset = (buffer_id as usize) * PKT_BUF_SIZE;
-        let buf = &mut self.buffer_slab[offset..offset + PKT_BUF_SIZE];
+        let buf = &mut lane.slab[offset..offset + PKT_BUF_SIZE];
 
         let frame = self.framing.parse(buf);
+        lane.drops.packet(frame.payload);
+        if tag == TAG_INCOMING_HANDSHAKE {
+            self.metrics
+                .handshake_lane_packets
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        let received = self.transport.handle_incoming(
+            frame.payload,
+            frame.peer_addr,
+            frame.local_addr,
+            &mut self.pixels_scratch,
+        );
+        // Back to the kernel once the whole pass is done (replenish.rs).
+        lane.replenisher.free(buffer_id);
+
+        if let Some(received) = received {
+            self.admit_received(&received, frame.peer_addr);
+            if received.closed {
+                self.metrics
+                    .connections
+                    .store(self.transport.connections.len() as u64, Ordering::Relaxed);
+            }
+        }
+        // Ids freed by a close, a RESUME or a parked id making room for a
+        // new connection.
+        self.release_freed_ids();
+        self.answer_cooldown_queries();
+    }
 
-        if let Some((user_id, pixels)) =
+    /// Answers the COOLDOWN_CONFIG requests and pushes set aside since the
+    /// last call, with the remaining time from the timing wheel.
+    #[cfg(target_os = "linux")]
+    fn answer_cooldown_queries(&mut self) {
+        let (wheel, now_ms) = (&self.timing_wheel, crate::time::CLOCK.now_ms());
+        self.transport
+            .answer_cooldown_queries(now_ms, |user_id| wheel.remaining_ms(user_id, now_ms));
+    }
+
+    /// Runs the pixels `handle_incoming` (or a backlog pass) left in
+    /// `pixels_scratch` through admission, acknowledges each one queued if
+    /// the connection negotiated RECEIPTS, and NACKs the connection if its
+    /// cooldown turned any of them away.
+    #[cfg(target_os = "linux")]
+    fn admit_received(&mut self, received: &Received, peer: SocketAddr) {
+        let user_id = received.user_id;
+        let ipv4 = crate::audit::ipv4_of(peer);
+        let now_ms = crate::time::CLOCK.now_ms();
+        let slot = self.transport.slots.get_mut(user_id);
+        let cooldown_secs = slot.contract.enforced_secs(now_ms);
+        let receipts = !received.closed && slot.features().contains(Features::RECEIPTS);
+        let mut on_cooldown = false;
+        for p in &self.pixels_scratch[..received.pixels] {
+            let pixel = PixelWrite {
+                x: p.x,
+                y: p.y,
+                color: p.color,
+                traced: self.pixel_sampler.sample(),
+                user_id,
+                ipv4,
+                epoch: self.epoch,
+            };
+            let fate = admit_pixel(
+                &mut self.pixel_tokens,
+                &mut self.cooldown_master,
+                &mut self.timing_wheel,
+                &self.master_queue,
+                user_id,
+                p,
+                pixel,
+                cooldown_secs as usize,
+            );
+            let counter = match fate {
+                Fate::Queued => &self.metrics.pixels_accepted,
+                Fate::SpscDrop => &self.metrics.spsc_drops,
+                Fate::Duplicate => &self.metrics.duplicate_pixels,
+                Fate::Replay => &self.metrics.replayed_pixels,
+                _ => &self.metrics.cooldown_rejections,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if receipts && fate == Fate::Queued {
+                self.transport.ack_pixel(received.handle, p.seq);
+            }
+            on_cooldown |= fate == Fate::Cooldown;
+            if pixel.traced {
+                pixel_trace::log(&self.trace_label, &pixel, fate);
+            }
+        }
+        if on_cooldown && !received.closed {
+            let remaining_ms = self.timing_wheel.remaining_ms(user_id, now_ms);
             self.transport
-                .handle_incoming(frame.payload, frame.peer_addr, frame.local_addr)
+                .nack_cooldown(received.handle, remaining_ms, now_ms);
+        }
+    }
+
+    /// Reads on from the connections that packets left with datagrams
+    /// queued (`--dgram-drain-cap` each per pass, `--dgram-drain-budget` in
+    /// all), so their pixels do not wait for the next packet from them.
+    #[cfg(target_os = "linux")]
+    fn drain_datagram_backlog(&mut self) {
+        if !self.transport.has_backlog() {
+            return;
+        }
+        self.transport.start_backlog_pass();
+        while let Some((received, peer)) = self.transport.next_backlogged(&mut self.pixels_scratch)
         {
-            for p in pixels {
-                if !self.cooldown_master.is_on_cooldown(user_id) {
-                    self.cooldown_master.set_cooldown(user_id);
-                    self.timing_wheel.add_cooldown(user_id);
-                    let _ = self.master_queue.push(PixelWrite {
-                        x: p.x,
-                        y: p.y,
-                        color: p.color,
-                    });
-                }
-            }
+            self.admit_received(&received, peer);
         }
+    }
 
-        // Replenish buffer back to kernel
-        let replenish_sqe = opcode::ProvideBuffers::new(
-            self.buffer_slab[offset..].as_mut_ptr(),
-            PKT_BUF_SIZE as i32,
-            1,
-            IO_URING_BGID,
-            buffer_id as u16,
-        )
-        .build()
-        .user_data(0);
+    /// Returns the buffers each lane freed this pass, one ProvideBuffers per
+    /// run of consecutive ids, then re-arms its RecvMsgMulti if it ended. In
+    /// that order: a receive armed before its buffers are back would end
+    /// again with ENOBUFS.
+    #[cfg(target_os = "linux")]
+    fn replenish_buffers(&mut self, ring: &mut IoUring) {
+        Self::replenish_lane(&mut self.rx, self.metrics, &self.msghdr, ring);
+        if let Some(lane) = self.handshake.as_mut() {
+            Self::replenish_lane(lane, self.metrics, &self.msghdr, ring);
+        }
+    }
 
-        unsafe {
-            if ring.submission().push(&replenish_sqe).is_err() {
-                ring.submit().unwrap();
-                ring.submission().push(&replenish_sqe).unwrap();
+    #[cfg(target_os = "linux")]
+    fn replenish_lane(
+        lane: &mut RecvLane,
+        metrics: &WorkerMetrics,
+        msghdr: &libc::msghdr,
+        ring: &mut IoUring,
+    ) {
+        let slab = lane.slab.as_mut_ptr();
+        let bgid = lane.bgid;
+        let mut sqes = 0;
+        let buffers = lane.replenisher.drain_ranges(|first, count| {
+            let replenish_sqe = opcode::ProvideBuffers::new(
+                unsafe { slab.add(first as usize * PKT_BUF_SIZE) },
+                PKT_BUF_SIZE as i32,
+                count,
+                bgid,
+                first,
+            )
+            .build()
+            .user_data(0);
+
+            unsafe {
+                if ring.submission().push(&replenish_sqe).is_err() {
+                    ring.submit().unwrap();
+                    ring.submission().push(&replenish_sqe).unwrap();
+                }
             }
+            sqes += 1;
+        });
+        if buffers > 0 {
+            metrics
+                .buffers_replenished
+                .fetch_add(buffers as u64, Ordering::Relaxed);
+            metrics.replenish_sqes.fetch_add(sqes, Ordering::Relaxed);
         }
 
-        if !io_uring::cqueue::more(flags) {
+        if !lane.armed {
+            // Closes the exhaustion window the ENOBUFS opened, if any.
+            if lane.drops.is_open()
+                && let Some(window) =
+                    recv_lane::kernel_drops(&lane.socket).and_then(|drops| lane.drops.close(drops))
+            {
+                metrics
+                    .exhaustion_drops
+                    .fetch_add(window.dropped, Ordering::Relaxed);
+                metrics
+                    .handshake_drops_estimated
+                    .fetch_add(window.handshake_estimate, Ordering::Relaxed);
+            }
             let recv = opcode::RecvMsgMulti::new(
-                fd_types,
-                self.msghdr.as_ref() as *const _,
-                IO_URING_BGID,
+                types::Fd(lane.socket.as_raw_fd()),
+                msghdr as *const _,
+                lane.bgid,
             )
             .build()
-            .user_data(TAG_INCOMING_UDP);
+            .user_data(lane.tag);
             unsafe {
                 if ring.submission().push(&recv).is_err() {
                     ring.submit().unwrap();
                     ring.submission().push(&recv).unwrap();
                 }
             }
+            lane.armed = true;
         }
     }
 
     #[cfg(target_os = "linux")]
     fn flush_outgoing(&mut self, ring: &mut IoUring, fd_types: types::Fd) -> usize {
         let mut sqes_added = 0;
-        for (_, conn, _) in self.transport.connections.values_mut() {
-            while let Some(idx) = self.tx_free_indices.pop() {
-                let item = &mut self.tx_items[idx];
-                match conn.send(&mut item.buf) {
-                    Ok((len, send_info)) => {
-                        let dest_addr = match send_info.to {
-                            SocketAddr::V4(v4) => v4,
-                            _ => {
-                                self.tx_free_indices.push(idx);
-                                continue;
-                            }
-                        };
-
-                        item.addr.sin_family = libc::AF_INET as u16;
-                        item.addr.sin_port = dest_addr.port().to_be();
-                        item.addr.sin_addr.s_addr = u32::from(dest_addr.ip().clone()).to_be();
+        for (user_id, conn, _) in self.transport.connections.iter_mut() {
+            let dest = &mut self.dest_cache[*user_id as usize];
+            sqes_added += Self::flush_connection(conn, dest, &mut self.tx, ring, fd_types);
+        }
 
-                        item.iov.iov_base = item.buf.as_mut_ptr() as *mut _;
-                        item.iov.iov_len = len as _;
+        // Refused connections only ever send their CONNECTION_CLOSE; drop
+        // each once it is out. Leftovers wait for TX items to free up.
+        let mut dest = CachedDest::new();
+        while let Some(conn) = self.transport.refused.last_mut() {
+            sqes_added += Self::flush_connection(conn, &mut dest, &mut self.tx, ring, fd_types);
+            if self.tx.is_exhausted() {
+                break;
+            }
+            self.transport.refused.pop();
+        }
 
-                        item.msghdr.msg_name = &mut item.addr as *mut _ as *mut _;
-                        item.msghdr.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as _;
-                        item.msghdr.msg_iov = &mut item.iov;
-                        item.msghdr.msg_iovlen = 1;
+        // Stateless responses (Retry, Version Negotiation) come
+        // ready-made; each takes one TX item as is.
+        while let Some(response) = self.transport.responses.last() {
+            let Some(mut lease) = self.tx.lease() else {
+                break;
+            };
+            let Some(sockaddr) = dest.get(response.to) else {
+                self.transport.responses.pop();
+                continue;
+            };
+            let item = lease.item();
+            item.buf[..response.len].copy_from_slice(&response.buf[..response.len]);
+            item.addr = *sockaddr;
+            item.iov.iov_len = response.len as _;
+            lease.submit(|item, user_data| Self::push_send(ring, fd_types, item, user_data));
+            sqes_added += 1;
+            self.transport.responses.pop();
+        }
+        sqes_added
+    }
 
-                        let send_sqe = opcode::SendMsg::new(fd_types, &item.msghdr)
-                            .build()
-                            .user_data(TAG_OUTGOING_UDP | ((idx as u64) << 8));
+    /// Queues a SendMsg SQE for a submitted TX item, submitting first if the
+    /// submission queue is full.
+    #[cfg(target_os = "linux")]
+    fn push_send(ring: &mut IoUring, fd_types: types::Fd, item: &TxItem, user_data: u64) {
+        let send_sqe = opcode::SendMsg::new(fd_types, &item.msghdr)
+            .build()
+            .user_data(user_data);
 
-                        unsafe {
-                            if ring.submission().push(&send_sqe).is_err() {
-                                // flush the pending items to the Linux kernel, making room for the new job, and then retry pushing it.
-                                ring.submit().unwrap();
-                                ring.submission().push(&send_sqe).unwrap();
-                            }
-                        }
-                        sqes_added += 1;
-                    }
-                    Err(_e) => {
-                        self.tx_free_indices.push(idx);
-                        break;
-                    }
-                }
+        unsafe {
+            if ring.submission().push(&send_sqe).is_err() {
+                // flush the pending items to the Linux kernel, making room for the new job, and then retry pushing it.
+                ring.submit().unwrap();
+                ring.submission().push(&send_sqe).unwrap();
             }
         }
+    }
+
+    /// Queues SendMsg SQEs for everything `conn` has to send, until it is
+    /// done or TX items run out. Returns the number of SQEs pushed.
+    #[cfg(target_os = "linux")]
+    fn flush_connection(
+        conn: &mut quiche::Connection,
+        dest: &mut CachedDest,
+        tx: &mut TxPool,
+        ring: &mut IoUring,
+        fd_types: types::Fd,
+    ) -> usize {
+        let mut sqes_added = 0;
+        while let Some(mut lease) = tx.lease() {
+            let item = lease.item();
+            let Ok((len, send_info)) = conn.send(&mut item.buf) else {
+                break;
+            };
+            let Some(sockaddr) = dest.get(send_info.to) else {
+                continue;
+            };
+            item.addr = *sockaddr;
+            item.iov.iov_len = len as _;
+            lease.submit(|item, user_data| Self::push_send(ring, fd_types, item, user_data));
+            sqes_added += 1;
+        }
         sqes_added
     }
 
@@ -483,98 +1133,144 @@ impl WorkerCore {
 
         // Throttle to every CONN_TIMEOUT_THROTTLE_MS to save massive CPU overhead on 40k+ connections
         if now_ms - *last_timeout_ms >= CONN_TIMEOUT_THROTTLE_MS {
-            for (_, conn, _) in self.transport.connections.values_mut() {
-                conn.on_timeout();
+            self.transport.on_timeouts();
+
+            let (wheel, cooldown) = (&mut self.timing_wheel, &mut self.cooldown_master);
+            let (tokens, bandwidth) = (&mut self.pixel_tokens, &mut self.bandwidth);
+            let mut on_free = |user_id| {
+                wheel.release(cooldown, user_id);
+                tokens.forget(user_id);
+                bandwidth.forget(user_id);
+            };
+            self.transport.cleanup_connections(&mut on_free);
+            self.transport
+                .reap_stale_handshakes(now_ms as u64, &mut on_free);
+            if self.transport.memory.over_hard_limit() {
+                self.transport.reap_handshakes(&mut on_free);
+            }
+            self.metrics
+                .connections
+                .store(self.transport.connections.len() as u64, Ordering::Relaxed);
+            self.metrics
+                .tier1_connections
+                .store(self.transport.slots.tier1_count() as u64, Ordering::Relaxed);
+            if now_ms as u64 >= self.next_transport_stats_ms {
+                self.transport.collect_stats().publish(self.metrics);
+                self.next_transport_stats_ms = now_ms as u64 + TRANSPORT_STATS_INTERVAL_MS;
             }
-
-            self.transport.cleanup_connections();
 
             *last_timeout_ms = now_ms;
         }
     }
 
+    /// Records one loop iteration's busy time for the master's health controller.
     #[cfg(target_os = "linux")]
-    fn process_pending_cqes(
-        &mut self,
-        ring: &mut IoUring,
-        fd_types: types::Fd,
-        pending_cqes: &[(u64, i32, u32)],
-    ) {
+    fn record_loop_time(&self, loop_start_ms: u64) {
+        self.metrics.loop_iterations.fetch_add(1, Ordering::Relaxed);
+        if crate::time::CLOCK.now_ms().wrapping_sub(loop_start_ms) >= WORKER_LOOP_BUDGET_MS {
+            self.metrics.loop_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn process_pending_cqes(&mut self, ring: &mut IoUring, pending_cqes: &[(u64, i32, u32)]) {
         for &(user_data, result, flags) in pending_cqes {
             if user_data & 0xFF == TAG_OUTGOING_UDP {
-                let idx = (user_data >> 8) as usize;
-                self.tx_free_indices.push(idx);
-            } else if user_data == TAG_INCOMING_UDP {
+                self.tx.complete(user_data);
+            } else if user_data == TAG_INCOMING_UDP || user_data == TAG_INCOMING_HANDSHAKE {
                 // result is the OP specific code
                 // for RecvMsgMulti it is equivalent to the return value of the read(2)
                 if result >= 0 {
-                    self.handle_incoming_cqe(ring, flags, fd_types);
+                    self.handle_incoming_cqe(user_data, flags);
                 } else {
                     #[cfg(feature = "debug-logs")]
                     println!("CQE error in RecvMsgMulti: {}", result);
 
-                    if !io_uring::cqueue::more(flags) {
-                        let recv = opcode::RecvMsgMulti::new(
-                            fd_types,
-                            self.msghdr.as_ref() as *const _,
-                            IO_URING_BGID,
-                        )
-                        .build()
-                        .user_data(TAG_INCOMING_UDP);
-                        unsafe {
-                            if ring.submission().push(&recv).is_err() {
-                                ring.submit().unwrap();
-                                ring.submission().push(&recv).unwrap();
+                    let metrics = self.metrics;
+                    if !io_uring::cqueue::more(flags)
+                        && let Some(lane) = self.lane_mut(user_data)
+                    {
+                        lane.armed = false;
+                        // Every buffer is taken: from here to the re-arm
+                        // the socket buffer is all there is (recv_lane.rs).
+                        if result == -libc::ENOBUFS {
+                            metrics.recv_exhaustions.fetch_add(1, Ordering::Relaxed);
+                            if let Some(drops) = recv_lane::kernel_drops(&lane.socket) {
+                                lane.drops.open(drops);
                             }
                         }
                     }
                 }
             }
         }
+        self.replenish_buffers(ring);
     }
 
     #[cfg(target_os = "linux")]
-    fn run_linux(&mut self) {
+    fn run_linux(&mut self, ready: Sender<()>) {
         let mut ring = self.setup_io_uring();
-        let socket = self.setup_socket();
+        let socket = &self.rx.socket;
         let fd = socket.as_raw_fd();
 
-        self.provide_initial_buffers(&mut ring);
+        // What the kernel granted, for /config.json.
+        let facts = self.facts;
+        let granted = |size: std::io::Result<usize>| size.unwrap_or(0);
+        facts
+            .socket_recv_buf
+            .store(granted(socket.recv_buffer_size()), Ordering::Relaxed);
+        facts
+            .socket_send_buf
+            .store(granted(socket.send_buffer_size()), Ordering::Relaxed);
+        facts
+            .sq_entries
+            .store(ring.params().sq_entries(), Ordering::Relaxed);
+        facts
+            .cq_entries
+            .store(ring.params().cq_entries(), Ordering::Relaxed);
+
+        Self::provide_initial_buffers(&mut self.rx, &mut ring);
+        if let Some(lane) = self.handshake.as_mut() {
+            Self::provide_initial_buffers(lane, &mut ring);
+        }
 
         let fd_types = types::Fd(fd);
-        // Initial socket receive sqe
-        let recv =
-            opcode::RecvMsgMulti::new(fd_types, self.msghdr.as_ref() as *const _, IO_URING_BGID)
-                .build()
-                .user_data(TAG_INCOMING_UDP);
-
-        unsafe {
-            ring.submission().push(&recv).unwrap();
-        }
+        // Nothing to replenish yet: this arms the first RecvMsgMulti.
+        self.replenish_buffers(&mut ring);
         ring.submit().unwrap();
+        let _ = ready.send(());
 
         let mut last_tick_sec = crate::time::CLOCK.now_sec();
         let mut last_timeout_ms = crate::time::CLOCK.now_ms() as u128;
 
-        self.last_broadcast_index =
-            crate::canvas::ACTIVE_INDEX.load(std::sync::atomic::Ordering::Acquire);
+        self.last_sent_seq = crate::canvas::PUBLISH_SEQ.load(Ordering::Acquire);
+        self.full_schedule.start(crate::time::CLOCK.now_ms());
+        self.transport.stats.next_full_due_ms = self.full_schedule.next_due_ms();
 
         // Allocate once outside the loop — avoids a ~1 MB stack frame (Box::new
         // builds the array on the stack before moving it to the heap) every tick.
         let mut pending_cqes: Vec<(u64, i32, u32)> = Vec::with_capacity(u16::MAX as usize);
 
         loop {
-            ring.submit_and_wait(1).unwrap();
+            // A datagram backlog is read this iteration, whether or not
+            // another packet comes in.
+            let wait = if self.transport.has_backlog() { 0 } else { 1 };
+            self.phase.enter(Phase::Wait, 0);
+            ring.submit_and_wait(wait).unwrap();
+            let loop_start_ms = crate::time::CLOCK.now_ms();
 
             // NOTE: handle evicting users from cooldown and cleans up current cooldown array
+            self.phase.enter(Phase::Tick, loop_start_ms);
             self.handle_tick(&mut last_tick_sec);
+            self.phase.enter(Phase::Broadcast, loop_start_ms);
             self.handle_broadcast();
 
+            self.phase.enter(Phase::Receive, loop_start_ms);
+
             let mut cqes_processed = 0;
             pending_cqes.clear();
 
             let mut completion = ring.completion();
-            while let Some(cqe) = completion.next() {
+            for cqe in &mut completion {
                 cqes_processed += 1;
                 if pending_cqes.len() < u16::MAX as usize {
                     pending_cqes.push((cqe.user_data(), cqe.result(), cqe.flags()));
@@ -582,19 +1278,60 @@ impl WorkerCore {
             }
             drop(completion);
 
-            self.process_pending_cqes(&mut ring, fd_types, &pending_cqes);
+            self.process_pending_cqes(&mut ring, &pending_cqes);
+            self.phase.enter(Phase::Backlog, loop_start_ms);
+            self.drain_datagram_backlog();
 
             // orer important here.
             // we first broadcast to all *established* connections, then we flush the pending sqes.
             // new connections accepted (but not yet established) will not receive the broadcast.
             // We accept them in process_pending_cqes and send ACK from server here
+            self.phase.enter(Phase::Flush, loop_start_ms);
             let sqes_added = self.flush_outgoing(&mut ring, fd_types);
+            self.tx.audit();
 
             if cqes_processed > 0 || sqes_added > 0 {
                 ring.submission().sync(); // Wake up kernel if SQEs pending
             }
 
+            self.phase.enter(Phase::Maintain, loop_start_ms);
             self.maintain_connections(&mut last_timeout_ms);
+            self.record_loop_time(loop_start_ms);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dest_cache_follows_migration() {
+        let mut dest = CachedDest::new();
+        let first: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let migrated: SocketAddr = "10.0.0.2:6000".parse().unwrap();
+
+        let sa = *dest.get(first).unwrap();
+        assert_eq!(sa.sin_family, libc::AF_INET as u16);
+        assert_eq!(u16::from_be(sa.sin_port), 5000);
+        assert_eq!(
+            Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)),
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+
+        // Same peer: served from the cache unchanged.
+        let again = *dest.get(first).unwrap();
+        assert_eq!(again.sin_addr.s_addr, sa.sin_addr.s_addr);
+        assert_eq!(again.sin_port, sa.sin_port);
+
+        // Peer migrates mid-connection: the cache must be rebuilt.
+        let sa = *dest.get(migrated).unwrap();
+        assert_eq!(u16::from_be(sa.sin_port), 6000);
+        assert_eq!(
+            Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)),
+            Ipv4Addr::new(10, 0, 0, 2)
+        );
+
+        assert!(dest.get("[::1]:4433".parse().unwrap()).is_none());
+    }
+}